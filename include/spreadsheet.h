@@ -0,0 +1,78 @@
+#ifndef RUST_LAB_SPREADSHEET_H
+#define RUST_LAB_SPREADSHEET_H
+
+/* Generated by cbindgen from src/ffi.rs; do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The call succeeded.
+#define SHEET_OK 0
+
+// The cell holds `ERR` (or the expression divides by zero); the value was not written.
+#define SHEET_ERR 1
+
+// A null pointer, a label outside the sheet, text that is not UTF-8 or an invalid expression.
+#define SHEET_INVALID -1
+
+// The expression would make a cell depend on itself.
+#define SHEET_LOOP -4
+
+// A spreadsheet owned by C code, created with `sheet_new` and released with `sheet_free`.
+//
+// Several sheets can be used, and a sheet can be handed to another thread, but one sheet must not
+// be used from two threads at once. `data` has one extra row below the sheet: `sheet_evaluate`
+// evaluates there, so an expression can refer to any cell of the sheet without being taken for a
+// loop.
+typedef struct Sheet Sheet;
+
+// Creates an empty sheet with all cells set to `0`.
+//
+// # Arguments
+// * `rows` - Number of rows (1 ≤ rows ≤ 999).
+// * `cols` - Number of columns (1 ≤ cols ≤ 18278).
+//
+// # Returns
+// The new sheet, or null if the size is out of range. Release it with `sheet_free`.
+Sheet *sheet_new(size_t rows, size_t cols);
+
+// Releases a sheet created with `sheet_new`. Passing null does nothing.
+//
+// # Safety
+// `sheet` must be null or a pointer returned by `sheet_new` that was not freed yet.
+void sheet_free(Sheet *sheet);
+
+// Assigns an expression to a cell (e.g. `"A1+10"` to `"B2"`) and recalculates the cells depending
+// on it.
+//
+// # Returns
+// `SHEET_OK`, `SHEET_ERR` if the cell was set but divides by zero, `SHEET_LOOP` or `SHEET_INVALID`.
+//
+// # Safety
+// `sheet` must come from `sheet_new`; `addr` and `expression` must be NUL-terminated strings.
+int sheet_set_cell(Sheet *sheet, const char *addr, const char *expression);
+
+// Reads the value of a cell into `value`.
+//
+// # Returns
+// `SHEET_OK`, `SHEET_ERR` if the cell holds `ERR`, or `SHEET_INVALID`.
+//
+// # Safety
+// `sheet` must come from `sheet_new`, `addr` must be a NUL-terminated string and `value` must point
+// to writable memory for an `int`.
+int sheet_get_value(const Sheet *sheet, const char *addr, int *value);
+
+// Evaluates an expression against the sheet (e.g. `"SUM(A1:B3)"`) without assigning it to a cell.
+//
+// # Returns
+// `SHEET_OK` with the result in `value`, `SHEET_ERR` on a division by zero, or `SHEET_INVALID`.
+//
+// # Safety
+// `sheet` must come from `sheet_new`, `expression` must be a NUL-terminated string and `value` must
+// point to writable memory for an `int`.
+int sheet_evaluate(Sheet *sheet, const char *expression, int *value);
+
+#endif  /* RUST_LAB_SPREADSHEET_H */
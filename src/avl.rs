@@ -3,17 +3,17 @@
 //! The AVL tree is a self-balancing binary search tree, which ensures that the heights of the two child subtrees of any node differ by at most one.
 //! This property makes AVL trees more efficient for lookups, insertions, and deletions compared to unbalanced binary search trees.
 
-use std::cell::RefCell;
 use std::cmp::max;
-use std::rc::Rc;
+use std::sync::Arc;
 use crate::cell::*;
 /// Type alias for a reference to an AVL node
 /// The `Link` type is an `Option` that can either be `Some` containing a reference to an `AvlNode` or `None`.
-pub type Link = Option<Rc<RefCell<AvlNode>>>;
+pub type Link = Option<Arc<Shared<AvlNode>>>;
 /// Represents the entire spreadsheet data structure.
 ///
-/// `SheetData` stores a 2D grid of cells (`sheet`) and a flat 1D vector of all cells (`flat`)
-/// to simplify certain operations like calculating the (row, col) of a specific cell reference.
+/// `SheetData` stores a 2D grid of cells (`sheet`) and a flat 1D vector of all cells (`flat`).
+/// `flat` is the arena owning every cell, and each cell's `id` is its index there, so the
+/// (row, col) of a cell reference is known without searching for it.
 /// 
 /// This struct is used primarily by the AVL (dependency tracking / evaluation) system and
 /// avoids importing higher-level logic from the `sheet` module to prevent circular dependencies.
@@ -23,6 +23,9 @@ pub struct SheetData {
      /// Flattened 1D vector of all cells in row-major order.
     /// Used for efficient indexing and lookups by position.
     pub flat: Vec<CellRef>,
+    /// Per-column prefix sums for range aggregates: `col_prefix[col][k]` holds the sum of the values
+    /// of rows `0..k` and how many of them are `ERR`. Only rows above the first edited row are kept.
+    col_prefix: Vec<Vec<(i64, u32)>>,
 }
 
 impl SheetData {
@@ -39,8 +42,15 @@ impl SheetData {
     /// A new `SheetData` instance with pre-allocated and linked cells.
     pub fn new(rows: usize, cols: usize) -> Self {
         let mut flat: Vec<CellRef> = Vec::with_capacity(rows * cols);
-        for _ in 0..(rows * cols) {
-            flat.push(Cell::new(0, "", 0));
+        for id in 0..(rows * cols) {
+            let cell = Cell::new(0, "", 0);
+            {
+                let mut c = cell.borrow_mut();
+                c.id = id;
+                c.row = id / cols;
+                c.col = id % cols;
+            }
+            flat.push(cell);
         }
 
         let mut sheet: Vec<Vec<CellRef>> = Vec::with_capacity(rows);
@@ -50,7 +60,35 @@ impl SheetData {
             sheet.push(flat[start..end].to_vec());
         }
 
-        SheetData { sheet, flat }
+        SheetData { sheet, flat, col_prefix: vec![vec![(0, 0)]; cols] }
+    }
+
+    /// Drops the prefix sums of a column from `row` down, after the cell at `(row, col)` changed.
+    ///
+    /// Every change to a cell's `val` or `status` must be followed by this call, or `column_sum`
+    /// keeps returning the old value.
+    pub fn invalidate(&mut self, row: usize, col: usize) {
+        if let Some(prefix) = self.col_prefix.get_mut(col) {
+            prefix.truncate(row + 1);
+        }
+    }
+
+    /// Sums the values of rows `first..=last` in a column using the prefix sums, extending them
+    /// only over the rows not summed since they were last invalidated.
+    ///
+    /// # Returns
+    /// The sum and the number of `ERR` cells in the rows.
+    pub fn column_sum(&mut self, col: usize, first: usize, last: usize) -> (i64, u32) {
+        let prefix = &mut self.col_prefix[col];
+        while prefix.len() <= last + 1 {
+            let row = prefix.len() - 1;
+            let (sum, errors) = prefix[row];
+            let cell = self.sheet[row][col].borrow();
+            prefix.push((sum + cell.val as i64, errors + (cell.status == 1) as u32));
+        }
+        let (end_sum, end_errors) = prefix[last + 1];
+        let (start_sum, start_errors) = prefix[first];
+        (end_sum - start_sum, end_errors - start_errors)
     }
 
        /// Returns a reference to a cell at a specific `(row, col)` in the sheet.
@@ -60,7 +98,7 @@ impl SheetData {
     /// * `col` - Zero-based column index.
     ///
     /// # Returns
-    /// A `CellRef` (i.e., `Rc<RefCell<Cell>>`) pointing to the requested cell.
+    /// A `CellRef` (i.e., `Arc<Shared<Cell>>`) pointing to the requested cell.
     ///
     /// # Panics
     /// Panics if the given `row` or `col` is out of bounds.
@@ -69,8 +107,8 @@ impl SheetData {
     }
       /// Calculates the (row, col) position of a cell reference within the sheet.
     ///
-    /// Searches through the flat list to find the index of the cell using `Rc::ptr_eq`,
-    /// and maps that index back into a 2D `(row, col)` tuple.
+    /// Reads the cell's id (its index in the flat list) and maps it back into a 2D `(row, col)`
+    /// tuple, in constant time.
     ///
     /// # Arguments
     /// * `target` - A reference to the cell whose position you want to find.
@@ -81,13 +119,15 @@ impl SheetData {
     ///
     /// # Example
     /// ```
+    /// # use Rust_lab::SheetData;
     /// let data = SheetData::new(3, 3);
     /// let cell = data.get(1, 2);
     /// assert_eq!(data.calculate_row_col(&cell), Some((1, 2)));
     /// ```
     pub fn calculate_row_col(&self, target: &CellRef) -> Option<(usize, usize)> {
-        self.flat.iter().position(|c| Rc::ptr_eq(c, target))
-            .map(|i| (i / self.sheet[0].len(), i % self.sheet[0].len()))
+        let id = target.borrow().id;
+        // A cell of another sheet can share an id, but is not the one stored under it here
+        Arc::ptr_eq(self.flat.get(id)?, target).then(|| (id / self.sheet[0].len(), id % self.sheet[0].len()))
     }
 }
 
@@ -98,7 +138,7 @@ impl SheetData {
 /// properties to ensure efficient insertions, deletions, and lookups.
 pub struct AvlNode {
      /// A reference-counted, mutable reference to the cell associated with this node.   
-    pub cell: Rc<RefCell<Cell>>,
+    pub cell: Arc<Shared<Cell>>,
     /// The left child in the AVL tree.
     pub left: Link,
      /// The right child in the AVL tree.
@@ -118,9 +158,9 @@ impl AvlNode {
     /// * `cell` - A reference-counted pointer to the `Cell` this node represents.
     ///
     /// # Returns
-    /// A `Rc<RefCell<AvlNode>>`, allowing shared ownership and interior mutability of the node.
-    pub fn new(cell: Rc<RefCell<Cell>>) -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(Self {
+    /// A `Arc<Shared<AvlNode>>`, allowing shared ownership and interior mutability of the node.
+    pub fn new(cell: Arc<Shared<Cell>>) -> Arc<Shared<Self>> {
+        Arc::new(Shared::new(Self {
             cell,
             left: None,
             right: None,
@@ -129,17 +169,6 @@ impl AvlNode {
     }
 }
 
-// fn calculate_row_col(cell: &Rc<RefCell<Cell>>, sheet: &Sheet) -> Option<(usize, usize)> {
-//     for (i, row) in sheet.iter().enumerate() {
-//         for (j, c) in row.iter().enumerate() {
-//             if Rc::ptr_eq(cell, c) {
-//                 return Some((i, j));
-//             }
-//         }
-//     }
-//     None
-// }
-
 /// Compares two `CellRef`s based on their positions (row and column) in the spreadsheet.
 ///
 /// This function is used for ordering cells in the AVL tree. Cells are compared first
@@ -148,17 +177,15 @@ impl AvlNode {
 /// # Arguments
 /// * `a` - A reference to the first `CellRef`.
 /// * `b` - A reference to the second `CellRef`.
-/// * `sheet_data` - A reference to the `SheetData`, which is used to resolve the
-///   row and column indices of the cells.
 ///
 /// # Returns
 /// An [`Ordering`](std::cmp::Ordering):  
 /// - `Ordering::Less` if `a` comes before `b`,  
 /// - `Ordering::Greater` if `a` comes after `b`,  
 /// - `Ordering::Equal` if they are at the same position.
-fn compare_cells(a: &Rc<RefCell<Cell>>, b: &Rc<RefCell<Cell>>, sheet_data: &SheetData) -> std::cmp::Ordering {
-    let (a_row, a_col) = sheet_data.calculate_row_col(a).unwrap();
-    let (b_row, b_col) = sheet_data.calculate_row_col(b).unwrap();
+fn compare_cells(a: &Arc<Shared<Cell>>, b: &Arc<Shared<Cell>>) -> std::cmp::Ordering {
+    let (a_row, a_col) = a.borrow().position();
+    let (b_row, b_col) = b.borrow().position();
 
     match a_row.cmp(&b_row) {
         std::cmp::Ordering::Equal => a_col.cmp(&b_col),
@@ -186,8 +213,8 @@ fn height(node: &Link) -> i32 {
 /// needs to be rebalanced.
 ///
 /// # Arguments
-/// * `node` - A reference to an `Rc<RefCell<AvlNode>>` representing the AVL node.
-fn get_balance(node: &Rc<RefCell<AvlNode>>) -> i32 {
+/// * `node` - A reference to an `Arc<Shared<AvlNode>>` representing the AVL node.
+fn get_balance(node: &Arc<Shared<AvlNode>>) -> i32 {
     height(&node.borrow().left) - height(&node.borrow().right)
 }
 /// Performs a right rotation on an AVL node.
@@ -198,12 +225,12 @@ fn get_balance(node: &Rc<RefCell<AvlNode>>) -> i32 {
 /// of the node is greater than 1).
 ///
 /// # Arguments
-/// * `y` - The `Rc<RefCell<AvlNode>>` representing the node to be rotated right.
+/// * `y` - The `Arc<Shared<AvlNode>>` representing the node to be rotated right.
 ///
 /// # Returns
-/// A new `Rc<RefCell<AvlNode>>` that represents the new root of the subtree
+/// A new `Arc<Shared<AvlNode>>` that represents the new root of the subtree
 /// after the rotation.
-fn rotate_right(y: Rc<RefCell<AvlNode>>) -> Rc<RefCell<AvlNode>> {
+fn rotate_right(y: Arc<Shared<AvlNode>>) -> Arc<Shared<AvlNode>> {
     let x = {
         let mut y_borrow = y.borrow_mut();
         y_borrow.left.take().unwrap()
@@ -239,12 +266,12 @@ fn rotate_right(y: Rc<RefCell<AvlNode>>) -> Rc<RefCell<AvlNode>> {
 /// of the node is less than -1).
 ///
 /// # Arguments
-/// * `x` - The `Rc<RefCell<AvlNode>>` representing the node to be rotated left.
+/// * `x` - The `Arc<Shared<AvlNode>>` representing the node to be rotated left.
 ///
 /// # Returns
-/// A new `Rc<RefCell<AvlNode>>` that represents the new root of the subtree
+/// A new `Arc<Shared<AvlNode>>` that represents the new root of the subtree
 /// after the rotation.
-fn rotate_left(x: Rc<RefCell<AvlNode>>) -> Rc<RefCell<AvlNode>> {
+fn rotate_left(x: Arc<Shared<AvlNode>>) -> Arc<Shared<AvlNode>> {
     let y = {
         let mut x_borrow = x.borrow_mut();
         x_borrow.right.take().unwrap()
@@ -273,6 +300,56 @@ fn rotate_left(x: Rc<RefCell<AvlNode>>) -> Rc<RefCell<AvlNode>> {
     y
 }
 
+/// Restores the height and the balance of a node whose subtrees have just changed.
+///
+/// Both subtrees must already be balanced. The node is rotated when one side is more than one level
+/// taller than the other:
+/// - **LL / RR:** a single right (left) rotation.
+/// - **LR / RL:** a left (right) rotation of the child, followed by a right (left) rotation.
+///
+/// # Returns
+/// The new root of the subtree.
+fn rebalance(node: Arc<Shared<AvlNode>>) -> Arc<Shared<AvlNode>> {
+    {
+        let mut node_borrow = node.borrow_mut();
+        node_borrow.height = 1 + max(height(&node_borrow.left), height(&node_borrow.right));
+    }
+    let balance = get_balance(&node);
+    if balance > 1 {
+        let left = node.borrow().left.clone().unwrap();
+        if get_balance(&left) < 0 {
+            node.borrow_mut().left = Some(rotate_left(left));
+        }
+        return rotate_right(node);
+    }
+    if balance < -1 {
+        let right = node.borrow().right.clone().unwrap();
+        if get_balance(&right) > 0 {
+            node.borrow_mut().right = Some(rotate_right(right));
+        }
+        return rotate_left(node);
+    }
+    node
+}
+
+/// Attaches `child` on one side of `node` and rebalances it.
+///
+/// # Arguments
+/// * `node` - The parent node.
+/// * `side` - `Ordering::Less` to attach on the left, anything else on the right.
+/// * `child` - The new subtree on that side.
+///
+/// # Returns
+/// The new root of the subtree that `node` was the root of.
+fn reattach(node: Arc<Shared<AvlNode>>, side: std::cmp::Ordering, child: Link) -> Arc<Shared<AvlNode>> {
+    if side == std::cmp::Ordering::Less {
+        node.borrow_mut().left = child;
+    } else {
+        node.borrow_mut().right = child;
+    }
+    rebalance(node)
+}
+
 /// Inserts a new `cell` into the AVL tree.
 ///
 /// This function inserts a `cell` into the AVL tree while maintaining the balance of the tree. 
@@ -281,9 +358,8 @@ fn rotate_left(x: Rc<RefCell<AvlNode>>) -> Rc<RefCell<AvlNode>> {
 ///
 /// # Arguments
 /// * `node` - The root node of the AVL subtree to which the new `cell` should be inserted. This is a 
-///            `Link` (i.e., an `Option<Rc<RefCell<AvlNode>>>`).
-/// * `cell` - The `Rc<RefCell<Cell>>` representing the new cell to be inserted.
-/// * `sheet_data` - A reference to the `SheetData`, which provides context for comparing cells.
+///   `Link` (i.e., an `Option<Arc<Shared<AvlNode>>>`).
+/// * `cell` - The `Arc<Shared<Cell>>` representing the new cell to be inserted.
 ///
 /// # Returns
 /// * `Link` - The updated root node of the AVL subtree after insertion and rebalancing. If the
@@ -291,138 +367,110 @@ fn rotate_left(x: Rc<RefCell<AvlNode>>) -> Rc<RefCell<AvlNode>> {
 ///
 /// # Description                           
 /// The function performs the following steps:
-/// 1. It compares the `cell` with the `node`'s `cell` using the `compare_cells` function.
-/// 2. It recursively traverses the AVL tree and inserts the `cell` in the correct position based on
-///    the comparison result (less than or greater than).
-/// 3. After insertion, it checks if the AVL tree needs rebalancing. If so, it applies the necessary
-///    rotations (single or double rotations) to restore the balance.
-/// 4. It updates the `height` of the nodes in the path of the inserted `cell` to ensure accurate height
-///    calculations for subsequent balance checks.
-///
-/// The function considers the following balance cases for rebalancing:
-/// - **LL (Left-Left Case):** A single right rotation is performed.
-/// - **RR (Right-Right Case):** A single left rotation is performed.
-/// - **LR (Left-Right Case):** A left rotation is followed by a right rotation.
-/// - **RL (Right-Left Case):** A right rotation is followed by a left rotation.
-///
-pub fn insert(node: Link, cell: Rc<RefCell<Cell>>, sheet_data: &SheetData) -> Link {
-    if let Some(n) = node {
-        let cmp;
-        {
-            let n_borrow = n.borrow_mut();
-            cmp = compare_cells(&cell, &n_borrow.cell, sheet_data);
-        }
-        {
-            let mut n_borrow = n.borrow_mut();
-            if cmp == std::cmp::Ordering::Less {
-                n_borrow.left = insert(n_borrow.left.clone(), cell.clone(), sheet_data);
-            } else if cmp == std::cmp::Ordering::Greater {
-                n_borrow.right = insert(n_borrow.right.clone(), cell.clone(), sheet_data);
-            } else {
-                return Some(n.clone()); // Duplicate
-            }
-
-            n_borrow.height = 1 + max(height(&n_borrow.left), height(&n_borrow.right));
-        }
-
-        let balance = get_balance(&n);
-        // Clone once and reuse for comparisons
-        let left = n.borrow().left.clone();
-        let right = n.borrow().right.clone();
+/// 1. It walks down from the root, comparing the `cell` with each node's `cell` using the
+///    `compare_cells` function, and records the path it takes.
+/// 2. It creates a leaf for the `cell` where the walk ended.
+/// 3. It walks the recorded path back up, reattaching each subtree to its parent, updating the
+///    `height` and applying the rotations of `rebalance` where a node became unbalanced.
+///
+/// The path is kept in a `Vec` instead of on the call stack, so a tree with many dependencies
+/// cannot overflow the stack.
+pub fn insert(node: Link, cell: Arc<Shared<Cell>>) -> Link {
+    let Some(root) = node else {
+        return Some(AvlNode::new(cell));
+    };
 
-        // LL Case
-        if balance > 1 && compare_cells(&cell, &left.as_ref().unwrap().borrow().cell, sheet_data) == std::cmp::Ordering::Less {
-            return Some(rotate_right(n));
-        }
-        // RR Case
-        if balance < -1 && compare_cells(&cell, &right.as_ref().unwrap().borrow().cell, sheet_data) == std::cmp::Ordering::Greater {
-            return Some(rotate_left(n));
-        }
-        // LR Case
-        if balance > 1 && compare_cells(&cell, &left.as_ref().unwrap().borrow().cell, sheet_data) == std::cmp::Ordering::Greater {
-            let left_rotated = rotate_left(left.unwrap());
-            n.borrow_mut().left = Some(left_rotated);
-            return Some(rotate_right(n));
-        }
-        // RL Case
-        if balance < -1 && compare_cells(&cell, &right.as_ref().unwrap().borrow().cell, sheet_data) == std::cmp::Ordering::Less {
-            let right_rotated = rotate_right(right.unwrap());
-            n.borrow_mut().right = Some(right_rotated);
-            return Some(rotate_left(n));
+    let mut path = Vec::new();
+    let mut current = root.clone();
+    loop {
+        let side = compare_cells(&cell, &current.borrow().cell);
+        let next = match side {
+            std::cmp::Ordering::Less => current.borrow().left.clone(),
+            std::cmp::Ordering::Greater => current.borrow().right.clone(),
+            std::cmp::Ordering::Equal => return Some(root), // Duplicate
+        };
+        path.push((current, side));
+        match next {
+            Some(next) => current = next,
+            None => break,
         }
+    }
 
-        Some(n)
-    } else {
-        Some(AvlNode::new(cell))
+    let mut subtree = AvlNode::new(cell);
+    while let Some((node, side)) = path.pop() {
+        subtree = reattach(node, side, Some(subtree));
     }
+    Some(subtree)
 }
 
 /// Finds a node in the AVL tree corresponding to the given `row` and `col`.
 ///
 /// This function searches the AVL tree for a specific `cell` based on its `row` and `col` values.
-/// It compares the target `row` and `col` with the nodes' values using the `calculate_row_col` method from
-/// the `SheetData` structure. If a match is found, it returns a reference to the corresponding `AvlNode`
+/// It compares the target `row` and `col` with the `row` and `col` stored in each node's cell. If a match is found, it returns a reference to the corresponding `AvlNode`
 /// wrapped in a `Some`, otherwise, it returns `None`.
 ///
 /// # Arguments
-/// * `node` - The root node of the AVL subtree in which to search. This is a `Link` (i.e., an `Option<Rc<RefCell<AvlNode>>>`).
+/// * `node` - The root node of the AVL subtree in which to search. This is a `Link` (i.e., an `Option<Arc<Shared<AvlNode>>>`).
 /// * `row` - The row index of the `cell` to search for.
 /// * `col` - The column index of the `cell` to search for.
-/// * `sheet_data` - A reference to the `SheetData` structure, which is used to calculate the row and column indices of each `cell`.
 ///
 /// # Returns
-/// * `Link` - The `Link` (i.e., `Option<Rc<RefCell<AvlNode>>>`) of the node that corresponds to the given `row` and `col`.
+/// * `Link` - The `Link` (i.e., `Option<Arc<Shared<AvlNode>>>`) of the node that corresponds to the given `row` and `col`.
 ///   If no such node exists, `None` is returned.
 /// # Description
-/// The function recursively traverses the AVL tree to locate the node that matches the given `row` and `col`:
+/// The function walks down the AVL tree to locate the node that matches the given `row` and `col`:
 /// 1. It compares the `row` and `col` of the target node with the current node's `row` and `col`.
 /// 2. If a match is found, it returns the current node.
-/// 3. If the target `row` and `col` are smaller than the current node's `row` and `col`, it recursively searches
-///    the left subtree.
-/// 4. If the target `row` and `col` are larger, it recursively searches the right subtree.
+/// 3. If the target `row` and `col` are smaller than the current node's `row` and `col`, it continues
+///    in the left subtree.
+/// 4. If the target `row` and `col` are larger, it continues in the right subtree.
 ///
 /// If the node does not exist in the tree, `None` is returned.
-pub fn find(node: &Link, row: usize, col: usize, sheet_data: &SheetData) -> Link {
-    if let Some(n) = node {
-        let (n_row, n_col) = sheet_data.calculate_row_col(&n.borrow().cell).unwrap();
+pub fn find(node: &Link, row: usize, col: usize) -> Link {
+    let mut current = node.clone();
+    while let Some(n) = current {
+        let (n_row, n_col) = n.borrow().cell.borrow().position();
         if (row, col) == (n_row, n_col) {
-            Some(n.clone())
-        } else if (row, col) < (n_row, n_col) {
-            find(&n.borrow().left, row, col, sheet_data)
-        } else {
-            find(&n.borrow().right, row, col, sheet_data)
+            return Some(n);
         }
-    } else {
-        None
+        current = if (row, col) < (n_row, n_col) { n.borrow().left.clone() } else { n.borrow().right.clone() };
     }
+    None
 }
 
-/// Finds the node with the minimum value in the AVL subtree rooted at the given `node`.
+/// Removes the node with the minimum value from the AVL subtree rooted at the given `node`.
 ///
-/// This function traverses the leftmost path in the AVL subtree, returning the node with the smallest
-/// value (i.e., the leftmost node). It is typically used during the node deletion process in an AVL tree,
+/// This function walks the leftmost path of the subtree to its last node, which holds the smallest
+/// value, unlinks it and rebalances the path back up. It is used during the node deletion process,
 /// where the minimum node in the right subtree replaces the deleted node.
 ///
 /// # Arguments
-/// * `node` - The root node of the AVL subtree to search within. This is an `Rc<RefCell<AvlNode>>`.
+/// * `node` - The root node of the AVL subtree to remove the minimum from.
 ///
 /// # Returns
-/// * `Rc<RefCell<AvlNode>>` - A reference-counted, mutable, borrowable `AvlNode` that contains the smallest
-///   value in the subtree. This node is the leftmost node in the AVL tree.
-///
-/// # Description
-/// The function iteratively traverses the left child of each node in the AVL subtree until it reaches
-/// a node with no left child, which is the node with the smallest value. It then returns this node.
-fn min_value_node(node: Rc<RefCell<AvlNode>>) -> Rc<RefCell<AvlNode>> {
+/// * `(Link, CellRef)` - The new root of the subtree and the cell of the removed node.
+fn remove_min(node: Arc<Shared<AvlNode>>) -> (Link, CellRef) {
+    let mut path = Vec::new();
     let mut current = node;
-    while let Some(left) = {
-        let current_borrow = current.borrow(); // This borrow ends at the end of the block
-        current_borrow.left.clone()
-    } {
-        current = left;
+    loop {
+        let left = current.borrow().left.clone();
+        match left {
+            Some(left) => {
+                path.push(current);
+                current = left;
+            }
+            None => break,
+        }
     }
-    current
+
+    let cell = current.borrow().cell.clone();
+    let mut subtree = current.borrow_mut().right.take();
+    while let Some(node) = path.pop() {
+        subtree = Some(reattach(node, std::cmp::Ordering::Less, subtree));
+    }
+    (subtree, cell)
 }
+
 /// Deletes a node with the given `row` and `col` from the AVL tree.
 ///
 /// This function deletes the node with the specified `row` and `col` from the AVL tree. It performs the
@@ -431,10 +479,9 @@ fn min_value_node(node: Rc<RefCell<AvlNode>>) -> Rc<RefCell<AvlNode>> {
 /// has one or no children, it is removed directly.
 ///
 /// # Arguments
-/// * `root` - The root of the AVL tree to delete the node from. This is an `Option<Rc<RefCell<AvlNode>>>` (i.e., a `Link`).
+/// * `root` - The root of the AVL tree to delete the node from. This is an `Option<Arc<Shared<AvlNode>>>` (i.e., a `Link`).
 /// * `row` - The row index of the node to delete.
 /// * `col` - The column index of the node to delete.
-/// * `sheet_data` - A reference to the `SheetData` structure used for calculating row and column indices of nodes.
 ///
 /// # Returns
 /// * `Link` - The new root of the AVL subtree after deletion. This is either a reference-counted pointer to the root node
@@ -446,60 +493,60 @@ fn min_value_node(node: Rc<RefCell<AvlNode>>) -> Rc<RefCell<AvlNode>> {
 /// 2. If the node is found, it deletes it using the standard AVL deletion procedure:
 ///    - If the node has only one child or no children, it is removed directly.
 ///    - If the node has two children, it is replaced by its in-order successor (the smallest node in its right subtree).
-/// 3. After the node is deleted, the tree is rebalanced if necessary by performing rotations.
+/// 3. After the node is deleted, the nodes on the path from the root are rebalanced bottom-up if necessary
+///    by performing rotations. The path is kept in a `Vec`, so deep trees do not grow the call stack.
 ///
 /// The function uses left and right rotations as necessary to restore the AVL tree's balance factor after deletion.
-pub fn delete_node(root: Link, row: usize, col: usize, sheet_data: &SheetData) -> Link {
-    if let Some(node) = root {
-        let mut node_borrow = node.borrow_mut();
-        // let (n_row, n_col) = calculate_row_col(&node_borrow.cell, sheet).unwrap();
-        let (n_row, n_col) = sheet_data.calculate_row_col(&node_borrow.cell).unwrap();
-        if (row, col) < (n_row, n_col) {
-            node_borrow.left = delete_node(node_borrow.left.clone(), row, col, sheet_data);
-        } else if (row, col) > (n_row, n_col) {
-            node_borrow.right = delete_node(node_borrow.right.clone(), row, col, sheet_data);
-        } else {
-            // Node found
-            if node_borrow.left.is_none() || node_borrow.right.is_none() {
-                return node_borrow.left.clone().or(node_borrow.right.clone());
-            } else {
-                let temp = min_value_node(node_borrow.right.clone().unwrap());
-                node_borrow.cell = temp.borrow().cell.clone();
-                // let (t_row, t_col) = calculate_row_col(&temp.borrow().cell, sheet).unwrap();
-                let (t_row, t_col) = sheet_data.calculate_row_col(&temp.borrow().cell).unwrap();
-                node_borrow.right = delete_node(node_borrow.right.clone(), t_row, t_col, sheet_data);
-            }
-        }
-
-        node_borrow.height = 1 + max(height(&node_borrow.left), height(&node_borrow.right));
-        drop(node_borrow);
-
-        let balance = get_balance(&node);
-        let left = node.borrow().left.clone();
-        let right = node.borrow().right.clone();
-
-        if balance > 1 && get_balance(&left.as_ref().unwrap()) >= 0 {
-            return Some(rotate_right(node));
+pub fn delete_node(root: Link, row: usize, col: usize) -> Link {
+    let mut path = Vec::new();
+    let mut current = root.clone();
+    let found = loop {
+        let Some(node) = current else {
+            return root; // Not in the tree
+        };
+        let side = (row, col).cmp(&node.borrow().cell.borrow().position());
+        if side == std::cmp::Ordering::Equal {
+            break node;
         }
+        current = if side == std::cmp::Ordering::Less { node.borrow().left.clone() } else { node.borrow().right.clone() };
+        path.push((node, side));
+    };
 
-        if balance > 1 && get_balance(&left.as_ref().unwrap()) < 0 {
-            let left_rotated = rotate_left(left.unwrap());
-            node.borrow_mut().left = Some(left_rotated);
-            return Some(rotate_right(node));
+    let (left, right) = {
+        let mut found_borrow = found.borrow_mut();
+        (found_borrow.left.take(), found_borrow.right.take())
+    };
+    let mut subtree = match (left, right) {
+        (Some(left), Some(right)) => {
+            let (right, successor) = remove_min(right);
+            {
+                let mut found_borrow = found.borrow_mut();
+                found_borrow.cell = successor;
+                found_borrow.left = Some(left);
+                found_borrow.right = right;
+            }
+            Some(rebalance(found))
         }
+        (left, right) => left.or(right),
+    };
 
-        if balance < -1 && get_balance(&right.as_ref().unwrap()) <= 0 {
-            return Some(rotate_left(node));
-        }
+    while let Some((node, side)) = path.pop() {
+        subtree = Some(reattach(node, side, subtree));
+    }
+    subtree
+}
 
-        if balance < -1 && get_balance(&right.as_ref().unwrap()) > 0 {
-            let right_rotated = rotate_right(right.unwrap());
-            node.borrow_mut().right = Some(right_rotated);
-            return Some(rotate_left(node));
+impl Drop for AvlNode {
+    /// Drops the subtree below this node one node at a time, so a large tree does not recurse
+    /// once per level. Subtrees still shared with another link are left to their other owner.
+    fn drop(&mut self) {
+        let mut pending: Vec<Arc<Shared<AvlNode>>> = self.left.take().into_iter().chain(self.right.take()).collect();
+        while let Some(mut node) = pending.pop() {
+            if let Some(node) = Arc::get_mut(&mut node) {
+                let node = node.get_mut();
+                pending.extend(node.left.take());
+                pending.extend(node.right.take());
+            }
         }
-
-        Some(node)
-    } else {
-        None
     }
-}
\ No newline at end of file
+}
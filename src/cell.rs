@@ -2,15 +2,58 @@
 //!
 //! This module defines the `Cell` struct, which represents a single cell in a spreadsheet.
 
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
 
 /// Maximum length allowed for an input expression
 pub const MAX_INPUT_LEN_CELL: usize = 35;
 /// A reference-counted, mutable reference to a `Cell`.
 ///
-/// Used throughout the sheet to share ownership and allow internal mutability.
-pub type CellRef = Rc<RefCell<Cell>>;
+/// Used throughout the sheet to share ownership and allow internal mutability. It is `Send`, so a
+/// whole `SheetData` can be moved to a worker thread and recalculated there.
+pub type CellRef = Arc<Shared<Cell>>;
+
+/// A value with shared interior mutability that can move between threads, borrowed like a `RefCell`.
+///
+/// A sheet is only used by one thread at a time, so a borrow never has to wait for another thread.
+/// As with a `RefCell`, borrowing a value in a way that conflicts with a borrow still alive panics
+/// instead of blocking forever.
+pub struct Shared<T>(RwLock<T>);
+
+impl<T> Shared<T> {
+    /// Wraps a value so it can be shared.
+    pub fn new(value: T) -> Self {
+        Shared(RwLock::new(value))
+    }
+
+    /// Borrows the value for reading.
+    ///
+    /// # Panics
+    /// Panics if the value is currently borrowed mutably.
+    pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+        match self.0.try_read() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => panic!("already mutably borrowed"),
+        }
+    }
+
+    /// Borrows the value for writing.
+    ///
+    /// # Panics
+    /// Panics if the value is currently borrowed.
+    pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+        match self.0.try_write() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => panic!("already borrowed"),
+        }
+    }
+
+    /// Returns the value of a `Shared` that is not shared, without borrowing it.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
 
 
 use crate::avl::{Link as AvlLink}; // Assuming `avl.rs` defines AVL tree
@@ -44,6 +87,14 @@ pub struct Cell {
     ///
     /// Useful for quick updates and recalculations.
     pub dependents: StackLink,              // Stack of dependents
+    /// Stable id of the cell: its index in `SheetData::flat`, the arena owning every cell of a sheet.
+    ///
+    /// Set by `SheetData::new`; `0` for a cell created on its own.
+    pub id: usize,
+    /// Zero-based row of the cell in its sheet, set by `SheetData::new` (`0` for a cell created on its own).
+    pub row: usize,
+    /// Zero-based column of the cell in its sheet, set by `SheetData::new` (`0` for a cell created on its own).
+    pub col: usize,
 }
 
 impl Cell {
@@ -58,14 +109,22 @@ impl Cell {
     /// * `status` - The initial status of the cell (0 = OK, 1 = ERR).
     ///
     /// # Returns
-    /// A `CellRef`, i.e., `Rc<RefCell<Cell>>`, allowing shared mutable access.
+    /// A `CellRef`, i.e., `Arc<Shared<Cell>>`, allowing shared mutable access.
     pub fn new(val: i32, expression: &str, status: i32) -> CellRef {
-        Rc::new(RefCell::new(Self {
+        Arc::new(Shared::new(Self {
             val,
             expression: expression.chars().take(MAX_INPUT_LEN_CELL).collect(),
             status,
             dependencies: None,
             dependents: None,
+            id: 0,
+            row: 0,
+            col: 0,
         }))
     }
+
+    /// Returns the `(row, col)` of the cell in its sheet, as set by `SheetData::new`.
+    pub fn position(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
 }
@@ -5,19 +5,43 @@
 //! terminal users. The extension aims to enhance the usability and functionality 
 //! of the original spreadsheet program, allowing for a keyboard-driven, privacy-focused 
 //! experience with remote editing capabilities.
+//!
+//! The model can also be used without a terminal: `Spreadsheet`, `Cell`, `CellAddress`, `Alignment`
+//! and `Mode` are public, and the public methods of `Spreadsheet` edit, search, sort, save and load
+//! a sheet directly. `Spreadsheet::builder()` makes a sheet with cells already entered, and
+//! `evaluate` computes an expression against a sheet without entering it. `on_change` registers a
+//! callback told about every cell whose value changes.
+//!
+//! ```ignore
+//! let mut sheet = Spreadsheet::new(20, 10);
+//! sheet.on_change(|addr, old, new| println!("{}: {} -> {}", addr.to_string(), old, new));
+//! sheet.update_cell(&CellAddress::new(0, 0), "4", false);
+//! sheet.update_cell(&CellAddress::new(1, 0), "=SUM(A1:A3)", false);
+//! assert_eq!(sheet.get_cell(&CellAddress::new(1, 0)).unwrap().display_value, "4");
+//! assert_eq!(evaluate(&sheet, "B1*2+1"), Ok(9.0));
+//! sheet.run_command("sort A1:A3 desc");
+//! println!("{}", sheet.status_message());
+//! let b2: CellAddress = "B2".parse()?;
+//! sheet.save_json(Path::new("out.json"), false)?;
+//! ```
 use std::env;
+use clap::Parser;
+use chrono::NaiveDate;
+use calamine::{open_workbook_auto, Data, Reader};
+use zip::write::SimpleFileOptions;
+use chrono::format::{Item, StrftimeItems};
 use printpdf::{PdfDocument,  BuiltinFont, Mm};
 use crossterm::{
     cursor::{MoveTo,Show,Hide,position},
-    event::{self, Event, KeyCode},
+    event::{self, Event, KeyCode, KeyModifiers},
     style::{self, Color, SetForegroundColor},
     terminal::{self,Clear, ClearType},
-    ExecutableCommand,
+    ExecutableCommand, QueueableCommand,
 };
 use std::collections::{HashMap, VecDeque, HashSet};
 use std::fs::File;
 use std::io::{self, stdout, BufReader, BufWriter, Write, Result};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::process::{ Stdio};
@@ -28,15 +52,196 @@ use std::thread;
 
 use rodio::{OutputStream, Sink};
 use std::time::{Duration, Instant};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use crate::sheet::{MAX_COLS, MAX_ROWS};
 
-/// A static mutable variable to store the starting row for displaying the spreadsheet. 
-static mut START_ROW: usize = 0;
-/// A static mutable variable to store the starting column for displaying the spreadsheet.
-static mut START_COL: usize = 0;
-/// A static mutable variable to store the number of rows in the spreadsheet.
-static mut R :usize = 0;
-/// A static mutable variable to store the number of columns in the spreadsheet.
-static mut C :usize = 0;
+mod cells;
+pub use cells::{Cell, CellAddress};
+mod diff;
+use diff::union_of_addresses;
+pub use diff::{diff_files, diff_sheets, merge_files, merge_sheets, CellDiff, MergeConflict};
+mod pivot;
+use pivot::Pivot;
+#[cfg(feature = "websocket")]
+mod serve;
+#[cfg(feature = "websocket")]
+use serve::Dashboard;
+mod share;
+use share::Session;
+#[cfg(feature = "sqlite")]
+mod store;
+#[cfg(feature = "sqlite")]
+use store::{CellStore, SWAP_MIN_CELLS};
+
+/// Width and alignment of new cells, set once from the user config at startup (5 and centered otherwise).
+static CELL_DEFAULTS: std::sync::OnceLock<(usize, Alignment)> = std::sync::OnceLock::new();
+/// Sound played when Haunt Mode starts, unless `haunt.door_sound` is set in the user config.
+const DEFAULT_DOOR_SOUND: &str = r#"C:\Users\hp\OneDrive - IIT Delhi\Desktop\Academics\prisha_rust_lab\creaking_door.wav"#;
+/// Sound played by the jump scare, unless `haunt.scream_sound` is set in the user config.
+const DEFAULT_SCREAM_SOUND: &str = r#"C:\Users\hp\OneDrive - IIT Delhi\Desktop\Academics\prisha_rust_lab\scary-scream.wav"#;
+/// Terminal lines drawn around the grid: the column header, the blank line and cell line below the grid,
+/// the sheet tabs, and the alert, command and status lines at the bottom.
+const SCREEN_RESERVED_LINES: usize = 7;
+/// Width of the row number column to the left of the grid.
+const ROW_LABEL_WIDTH: usize = 5;
+/// Letters in the longest column label ("ZZZ", column 18278, the widest sheet the engine supports).
+const MAX_COL_LETTERS: usize = 3;
+/// Default time budget (in milliseconds) for evaluating one cell's formula before it shows `#TIMEOUT!`.
+const DEFAULT_EVAL_TIMEOUT_MS: u64 = 2000;
+/// Display value given to a cell whose evaluation ran past the timeout.
+const TIMEOUT_ERROR: &str = "#TIMEOUT!";
+/// Error reason of a cell whose evaluation ran past the timeout.
+const TIMEOUT_REASON: &str = "EVALUATION TIMEOUT";
+/// Default idle tick interval (in milliseconds) of the event loop.
+const DEFAULT_TICK_MS: u64 = 100;
+/// Shortest time (in milliseconds) between two frames, which caps the frame rate at about 60 per second.
+/// Idle ticks never come more often than this either.
+const MIN_FRAME_MS: u64 = 16;
+/// Operations touching more cells than this show a progress bar in the status line.
+const PROGRESS_MIN_CELLS: usize = 10_000;
+/// Minimum time (in milliseconds) between two progress bar updates.
+const PROGRESS_INTERVAL_MS: u64 = 100;
+/// Number of formula cells a chunked recalculation evaluates before checking for input again.
+const RECALC_BATCH_CELLS: usize = 2_000;
+/// Maximum number of `:` commands kept in the command history.
+const MAX_COMMAND_HISTORY: usize = 500;
+/// Every `:` command as `(name, arguments, description)`. Tab completion offers the names and `:help`
+/// lists all three, so a command registered here shows up in both.
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("q", "", "Quit"),
+    ("help", "", "Show this help"),
+    ("i", "[cell]", "Insert a value or formula at a cell (default: the cursor)"),
+    ("j", "<cell>", "Jump to a cell"),
+    ("undo", "", "Undo the last action"),
+    ("redo", "", "Redo the last undone action"),
+    ("find", "[-v|-f|-a] <text|/regex/>", "Search values, formulas or addresses"),
+    ("replace", "<old> <new> [range] [-f] [-c]", "Replace text in values (-f also formulas, -c confirm each)"),
+    ("matches set", "<value>", "Write a value into every cell matched by the last find"),
+    ("matches clear", "", "Clear every cell matched by the last find"),
+    ("mi", "<range> <value>", "Insert a value or formula into every cell of a range"),
+    ("lock", "[cell]", "Lock a cell against edits"),
+    ("unlock", "[cell]", "Unlock a cell"),
+    ("protect", "[range|sheet] [password]", "Refuse edits, sorts and fills in a range or the whole sheet"),
+    ("unprotect", "<range|sheet|all> [password]", "Lift a protection"),
+    ("group", "[<first>:<last>]", "Group rows so they can be collapsed, or list the groups"),
+    ("ungroup", "[<first>:<last>|all]", "Remove a row group (default: the one at the cursor)"),
+    ("collapse", "[<first>:<last>|all]", "Fold away the rows of a group (also zc, zM)"),
+    ("expand", "[<first>:<last>|all]", "Show the rows of a collapsed group again (also zo, zR)"),
+    ("watch", "[cell]", "Pin a cell to the watch panel"),
+    ("unwatch", "[cell]", "Remove a cell from the watch panel"),
+    ("validate", "[range] list <a,b,...>|off", "Limit cells to a list of values, picked in insert mode"),
+    ("align", "[cell] <l|r|c>", "Align a cell left, right or center"),
+    ("fmt", "[range] date [pattern] | clear", "Show cells as dates, or remove the format"),
+    ("dim", "[cell] (height,width)", "Set the height and width of a cell"),
+    ("color", "[range] <fg|none> [on <bg|none>] | clear", "Color the text and background of cells"),
+    ("bold", "[range]", "Toggle bold text"),
+    ("italic", "[range]", "Toggle italic text"),
+    ("wrap", "[range]", "Toggle wrapping long values over the cell height"),
+    ("sort", "<range> <1|0>", "Sort a range of cells ascending (1) or descending (0)"),
+    ("freeze", "<rows> [cols]", "Keep leading rows and columns on screen"),
+    ("unfreeze", "", "Scroll the whole grid again"),
+    ("chart", "<bar|line> <range>", "Chart a range in a popup (closed by any key)"),
+    ("eval", "<expression>", "Compute an expression without entering it, e.g. SUM(A1:B2)*2"),
+    ("grid", "[on|off]", "Toggle lines between cells"),
+    ("zebra", "[on|off]", "Toggle shading every other row"),
+    ("heatmap", "[on|off]", "Toggle coloring numbers from lowest to highest"),
+    ("sheet", "[name|list|new|rename|copy|delete|next|prev]", "Switch between and manage sheets"),
+    ("saveas_json", "[range] <file> [-u]", "Save as JSON (-u keeps the undo history)"),
+    ("saveas_bin", "[range] <file> [-u]", "Save as compact binary (MessagePack)"),
+    ("saveas_text", "[range] <file>", "Save as line-per-cell text that diffs well"),
+    ("saveas_log", "<file>", "Append the changes since the last save to a change log"),
+    ("saveas_db", "<file>", "Save to a SQLite database (sqlite feature)"),
+    ("saveas_csv", "[range] <file>", "Export the display values as CSV"),
+    ("saveas_pdf", "[range] <file> [landscape] [wrap] [size=N] [title=\"...\"]", "Export a printable PDF"),
+    ("saveas_html", "[range] <file>", "Export a styled HTML table"),
+    ("saveas_tex", "[range] <file>", "Export a LaTeX booktabs table"),
+    ("saveas_ods", "[range] <file>", "Export an OpenDocument spreadsheet"),
+    ("saveas_depcsv", "[range] <file>", "Export the dependency graph as CSV"),
+    ("load", "<file> [sheet]", "Load a save, or import a CSV file or workbook"),
+    ("load_bin", "<file>", "Load a binary save"),
+    ("load_log", "<file> [revision]", "Load a revision of a change log"),
+    ("compact_log", "<file>", "Fold a change log into one snapshot"),
+    ("open_db", "<file>", "Open a SQLite-backed sheet (sqlite feature)"),
+    ("hh", "", "Go to the leftmost cell of the row"),
+    ("ll", "", "Go to the rightmost cell of the row"),
+    ("jj", "", "Go to the bottom cell of the column"),
+    ("kk", "", "Go to the top cell of the column"),
+    ("haunt", "", "Haunt the sheet"),
+    ("haunt when", "<cell|ERRORS> <op> <value> [weight N]", "Haunt the sheet while a condition holds"),
+    ("dehaunt", "", "End the haunting"),
+    ("undolimit", "[steps]", "Limit the undo history (0 keeps everything)"),
+    ("timeout", "[ms]", "Set the time budget of a single formula evaluation"),
+    ("calc", "[auto|lazy]", "Update dependents at once, or only when shown or read"),
+    ("locked", "[skip|fail]", "Leave locked cells out of mi and sort, or refuse the whole edit"),
+    ("sortmode", "[rows|range]", "Sort whole rows, or only the cells inside the range"),
+    ("share", "<file> [name]", "Edit the sheet together with other clients sharing the file"),
+    ("unshare", "", "Leave the shared editing session"),
+    ("who", "", "List the other clients of the shared session and their cells"),
+    ("serve", "[address|off]", "Mirror the sheet to browser dashboards over WebSocket (websocket feature)"),
+    ("history", "[cell]", "Show who changed a cell's input and when"),
+    ("audit", "[file|off]", "Append every cell change to an audit file"),
+    ("tick", "[ms]", "Set how often the idle screen refreshes"),
+    ("alert", "<cell> <op> <value> [\"message\"] [bell]", "Notify when a cell's value meets a condition"),
+    ("alerts", "", "List the alert and haunt rules"),
+    ("alert clear", "", "Remove every alert and haunt rule"),
+    ("marks", "", "List the marks"),
+    ("yank", "[range] [reg]", "Copy cells into a register"),
+    ("put", "[reg]", "Paste a register at the cursor"),
+    ("pivot", "[<range> <cell> rows=<col> [cols=<col>] values=<fn>(<col>)|refresh]", "Write a pivot table, or recompute them all"),
+    ("registers", "", "List the filled registers"),
+    ("refresh links", "", "Re-read LINK sources and recalculate"),
+    ("demo", "", "Toggle the key overlay and recording"),
+    ("demo on", "", "Show the key overlay and record keys"),
+    ("demo off", "", "Hide the key overlay"),
+    ("demo save", "<file>", "Save the recorded keys as a script"),
+    ("demo play", "<file> [delay_ms]", "Replay a key script"),
+];
+/// Normal-mode keys as `(keys, description)`, listed by `:help`.
+const KEY_BINDINGS: &[(&str, &str)] = &[
+    ("h j k l", "Move the cursor left, down, up, right"),
+    ("w a s d", "Scroll the view up, left, down, right by a page"),
+    (":", "Enter a command"),
+    ("q<reg> ... q", "Record a macro into a register"),
+    ("@<reg>, @@", "Replay a macro, or the last one"),
+    (".", "Repeat the last change at the cursor"),
+    ("m<reg>, '<reg>, ''", "Set a mark, jump to it, jump back"),
+    ("y, p", "Yank the current cell, paste a register"),
+    ("\"<reg>", "Use a register for the next y or p"),
+    ("[, ]", "Highlight the cursor cell's precedents, dependents"),
+    ("n, p, t", "Find mode: next match, previous match, search scope"),
+    ("y, n, a, q", "Replace mode: replace, skip, replace all, stop"),
+    ("Tab, Up, Down", "Command mode: complete, recall history"),
+    ("Esc", "Back to Normal mode"),
+];
+/// Formula functions as `(syntax, description)`, listed by `:help`.
+const FORMULA_FUNCTIONS: &[(&str, &str)] = &[
+    ("=SUM(A1:B5)", "Sum of a range"),
+    ("=MIN(A1:B5)", "Smallest value of a range"),
+    ("=MAX(A1:B5)", "Largest value of a range"),
+    ("=STDEV(A1:B5)", "Standard deviation of a range"),
+    ("=sqrt(A1)", "Square root of a cell or number"),
+    ("=log(A1)", "Natural logarithm of a cell or number"),
+    ("=(A1/B1)", "Arithmetic on cells and numbers: + - * /"),
+    ("=LINK(path,B3)", "Value of a cell in another saved sheet"),
+    ("=SLEEP(2)", "Wait a number of seconds"),
+    ("=SPARK(A1:A12)", "Sparkline of a range, e.g. ▁▃▅█"),
+];
+/// Commands whose argument is a file path, for Tab completion.
+const FILE_COMMANDS: &[&str] = &["share", "audit", "load", "load_bin", "load_log", "compact_log", "open_db", "saveas_json", "saveas_bin", "saveas_text", "saveas_log", "saveas_db", "saveas_csv", "saveas_pdf", "saveas_html", "saveas_tex", "saveas_ods", "saveas_depcsv", "demo save", "demo play"];
+/// Maximum nesting of macro replays (a macro calling itself stops here).
+const MAX_MACRO_DEPTH: usize = 20;
+/// Number of recent key presses shown in the demo overlay.
+const DEMO_OVERLAY_KEYS: usize = 12;
+/// Default pause (in milliseconds) between keys when replaying a demo script.
+const DEFAULT_DEMO_DELAY_MS: u64 = 150;
+/// Version of the save file format written by this build; see `SAVE_MIGRATIONS`.
+const SAVE_FORMAT_VERSION: u32 = 2;
+/// Upgrade steps for save files, where entry `n` turns a version `n` file into version `n + 1`.
+///
+/// - 0: the bare map of cells written by the first releases.
+/// - 1: `{cells, undo, redo}` written by `saveas_json <file> -u`, without a version number.
+/// - 2: every save carries `version`; cells missing newer fields (such as `format`) get their defaults.
+const SAVE_MIGRATIONS: &[fn(&mut serde_json::Value)] = &[migrate_save_v0, migrate_save_v1];
 
 
 /// Plays a sound synchronously using Windows PowerShell.
@@ -57,7 +262,8 @@ static mut C :usize = 0;
 /// Panics if PowerShell fails to launch or execute the command.
 ///
 /// # Example
-/// ```
+/// ```no_run
+/// # use Rust_lab::extended::play_sound;
 /// play_sound("C:/path/to/sound.wav");
 /// ```
 
@@ -84,16 +290,18 @@ pub fn play_sound(path: &str) {
 /// The ASCII art is centered based on the current terminal size to maximize visual impact.
 /// Intended for brief use during themed or playful modes of the application.
 ///
+/// # Arguments
+/// * `scream_path` - The sound file to play (`haunt.scream_sound` in the user config).
+///
 /// # Notes
 /// - Sound playback depends on the availability and correctness of the `play_sound` function and audio file path.
 /// - The function requires terminal control capabilities provided by the `crossterm` crate.
 
-fn trigger_jump_scare() {
+fn trigger_jump_scare(scream_path: &str) {
 
     let mut stdout = stdout();
 
     // 🧨 Play scream sound
-    let scream_path = r#"C:\Users\hp\OneDrive - IIT Delhi\Desktop\Academics\prisha_rust_lab\scary-scream.wav"#; // Path to your sound file
     play_sound(scream_path);
     let scare_art = r#"
     ████████████████████████████████████████
@@ -125,805 +333,3511 @@ fn trigger_jump_scare() {
     thread::sleep(Duration::from_secs(2));
 }
 
-// Cell struct to store data and metadata
-/// Represents a single cell in the spreadsheet.
+/// Returns the directory holding the editor's per-user files (command history, ...).
 ///
-/// The `Cell` struct holds both the raw input value (as entered by the user) and the 
-/// value to be displayed in the spreadsheet. It also supports formulas, text alignment, 
-/// and cell dimensions (width and height). The cell can be locked to prevent editing.
+/// Uses `$XDG_CONFIG_HOME/hackersheet` when set, otherwise `$HOME/.config/hackersheet`
+/// (`%APPDATA%\hackersheet` on Windows). Returns `None` if none of these variables is set.
+fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir).join("hackersheet"));
+    }
+    if let Some(home) = env::var_os("HOME").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(home).join(".config").join("hackersheet"));
+    }
+    env::var_os("APPDATA").map(|dir| PathBuf::from(dir).join("hackersheet"))
+}
+
+/// Returns the path of the user config file, `~/.hackersheetrc` (`%USERPROFILE%\.hackersheetrc` on Windows).
+fn config_file() -> Option<PathBuf> {
+    env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))
+        .filter(|d| !d.is_empty())
+        .map(|home| PathBuf::from(home).join(".hackersheetrc"))
+}
+
+/// Startup defaults read from `~/.hackersheetrc` (TOML). Every key is optional; command-line
+/// options take precedence over the file.
 ///
-/// # Fields:
-/// - `raw_value`: The raw input string (e.g., numbers, text, or formulas).
-/// - `display_value`: The value that will be shown to the user, possibly altered by formulas.
-/// - `formula`: An optional string containing a formula that is applied to compute the value.
-/// - `is_locked`: A boolean indicating whether the cell is locked and cannot be edited.
-/// - `alignment`: The alignment of the text inside the cell (e.g., left, right, or center).
-/// - `width`: The width of the cell (in characters).
-/// - `height`: The height of the cell (in rows).
-/// # Methods:
-/// - `new`: Creates a new `Cell` with default values.
-/// - `display`: Returns the content of the cell formatted according to its alignment and width.
-/// - `default`: Creates a new, default `Cell` with empty values for `raw_value` and `display_value`.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct Cell {
-    raw_value: String,       // Raw input
-    display_value: String,   // Value as displayed
-    formula: Option<String>, // Formula if any
-    is_locked: bool,         // Whether cell is locked
-    alignment: Alignment,    // Text alignment
-    width: usize,            // Cell width
-    height: usize,           // Cell height
+/// ```toml
+/// rows = 20
+/// cols = 12
+/// width = 8              # width of new cells
+/// alignment = "left"     # left, right or center
+/// theme = "light"        # default, light or mono
+/// undo_limit = 200       # 0 keeps unlimited history
+/// autosave = 60          # seconds between autosaves, 0 (the default) turns autosave off
+/// grid_lines = true      # box-drawing lines between cells (see `:grid`)
+/// zebra = true           # shade every other row (see `:zebra`)
+/// heatmap = true         # color numbers from lowest to highest (see `:heatmap`)
+/// tick = 50              # milliseconds between refreshes while idle, e.g. for haunt effects (see `:tick`)
+/// locked = "fail"        # refuse bulk edits of ranges holding locked cells instead of skipping them (see `:locked`)
+/// sort = "range"         # sort only the cells inside the range instead of whole rows (see `:sortmode`)
+///
+/// [haunt]
+/// door_sound = "/home/me/sounds/door.wav"
+/// scream_sound = "/home/me/sounds/scream.wav"
+/// ```
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    rows: Option<usize>,
+    cols: Option<usize>,
+    width: Option<usize>,
+    alignment: Option<String>,
+    theme: Option<String>,
+    undo_limit: Option<usize>,
+    autosave: Option<u64>,
+    grid_lines: Option<bool>,
+    zebra: Option<bool>,
+    heatmap: Option<bool>,
+    tick: Option<u64>,
+    locked: Option<String>,
+    sort: Option<String>,
+    haunt: HauntConfig,
 }
 
-impl Cell {
-    fn new() -> Self {
-        Cell {
-            raw_value: String::from("0"),
-            display_value: String::from("0"),
-            formula: None,
-            is_locked: false,
-            alignment: Alignment::Center,
-            width: 5,  // Default width
-            height: 1, // Default height
-        }
-    }
+/// The `[haunt]` table of the user config: sound files used by Haunt Mode.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct HauntConfig {
+    door_sound: Option<String>,
+    scream_sound: Option<String>,
+}
 
-    fn default() -> Self {
-        Cell {
-            raw_value: String::new(),
-            display_value: String::new(),
-            formula: None,
-            alignment: Alignment::Center,
-            is_locked: false,
-            width: 5, // or whatever default width you use
-            height: 1,
+impl Config {
+/// Reads the user config file. A missing file gives the built-in defaults.
+///
+/// # Returns
+///
+/// The config, or a message naming the file and the problem if it cannot be read, is not valid
+/// TOML, or holds a value out of range.
+    fn load() -> std::result::Result<Config, String> {
+        let Some(path) = config_file().filter(|path| path.exists()) else {
+            return Ok(Config::default());
+        };
+        let fail = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let text = std::fs::read_to_string(&path).map_err(|e| fail(&e))?;
+        let config: Config = toml::from_str(&text).map_err(|e| fail(&e))?;
+        if !config.rows.is_none_or(|rows| (1..=MAX_ROWS).contains(&rows)) || !config.cols.is_none_or(|cols| (1..=MAX_COLS).contains(&cols)) {
+            return Err(fail(&format!("rows must be 1 to {} and cols 1 to {}", MAX_ROWS, MAX_COLS)));
+        }
+        if config.width == Some(0) {
+            return Err(fail(&"width must be at least 1"));
+        }
+        if config.alignment.as_deref().is_some_and(|a| parse_alignment(a).is_none()) {
+            return Err(fail(&"alignment must be left, right or center"));
+        }
+        if config.theme.as_deref().is_some_and(|t| Theme::named(t).is_none()) {
+            return Err(fail(&"theme must be default, light or mono"));
         }
+        Ok(config)
     }
 }
-/// Represents the alignment of text within a cell.
+
+/// Checks that a sheet of `rows` x `cols` fits the largest grid (`MAX_ROWS` x `MAX_COLS`).
 ///
-/// The `Alignment` enum defines the available text alignments for a cell:
-/// - `Left`: Aligns text to the left side of the cell.
-/// - `Right`: Aligns text to the right side of the cell.
-/// - `Center`: Centers the text in the middle of the cell.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
-enum Alignment {
-    Left,
-    Right,
-    Center,
+/// # Returns
+/// An error such as `"SHEET SIZE 1200x10 OUT OF RANGE (1x1 TO 999x18278)"` if either is 0 or too large.
+fn check_sheet_size(rows: usize, cols: usize) -> std::result::Result<(), String> {
+    if (1..=MAX_ROWS).contains(&rows) && (1..=MAX_COLS).contains(&cols) {
+        Ok(())
+    } else {
+        Err(format!("SHEET SIZE {}x{} OUT OF RANGE (1x1 TO {}x{})", rows, cols, MAX_ROWS, MAX_COLS))
+    }
 }
-/// Represents different modes the spreadsheet can be in.
-///
-/// The `Mode` enum defines the available modes for the spreadsheet editor:
-/// - `Normal`: Default mode for interacting with the spreadsheet.
-/// - `Insert`: Mode for inserting new data or formulas into cells.
-/// - `Command`: Mode for executing commands.
-/// - `Find`: Mode for searching within the spreadsheet.
-#[derive(Clone, Debug, PartialEq)]
-enum Mode {
-    Normal,
-    Insert,
-    Command,
-    Find,
+
+/// Parses an alignment name (`left`, `right` or `center`/`centre`), ignoring case.
+fn parse_alignment(name: &str) -> Option<Alignment> {
+    match name.to_ascii_lowercase().as_str() {
+        "left" => Some(Alignment::Left),
+        "right" => Some(Alignment::Right),
+        "center" | "centre" => Some(Alignment::Center),
+        _ => None,
+    }
 }
-/// Represents a cell's address in the spreadsheet using column and row indices.
-///
-/// The `CellAddress` struct holds the `col` (column index) and `row` (row index) for a specific
-/// cell, and provides methods for converting between string representations of cell addresses
-/// (e.g., "A1", "B2") and the internal column/row index format.
+
+/// Parses a color name for cell colors: one of the 16 terminal colors (`red`, `dark_red`, `grey`, ...)
+/// or a `#rrggbb` hex value, ignoring case.
+fn parse_color(name: &str) -> Option<Color> {
+    let name = name.to_ascii_lowercase().replace('-', "_");
+    if let Some(hex) = name.strip_prefix('#').filter(|hex| hex.len() == 6) {
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        return Some(Color::Rgb { r: channel(0)?, g: channel(2)?, b: channel(4)? });
+    }
+    Some(match name.as_str() {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "white" => Color::White,
+        "grey" | "gray" => Color::Grey,
+        "dark_grey" | "dark_gray" => Color::DarkGrey,
+        "dark_red" => Color::DarkRed,
+        "dark_green" => Color::DarkGreen,
+        "dark_yellow" => Color::DarkYellow,
+        "dark_blue" => Color::DarkBlue,
+        "dark_magenta" => Color::DarkMagenta,
+        "dark_cyan" => Color::DarkCyan,
+        _ => return None,
+    })
+}
+
+/// Colors used to draw the grid, chosen with `theme` in the user config.
 ///
-/// # Methods:
-/// - `new`: Creates a new `CellAddress` from a column and row index.
-/// - `from_str`: Parses a string (e.g., "A1", "B2") into a `CellAddress` if valid.
-/// - `col_to_letters`: Converts a column index to the corresponding Excel-style column label (e.g., 0 -> "A", 1 -> "B", 26 -> "AA").
-#[derive(Clone, Debug)]
-struct CellAddress {
-    col: usize,
-    row: usize,
+/// # Fields:
+/// - `headers`: Foreground of the column letters and row numbers.
+/// - `cursor_fg`, `cursor_bg`: Colors of the selected cell.
+/// - `match_fg`, `match_bg`: Colors of cells matched by the active search.
+/// - `precedent_bg`, `dependent_bg`: Backgrounds of the cells the cursor cell depends on and of the
+///   cells depending on it, when shown with `[` and `]`.
+/// - `stripe_bg`: Background of every other row when zebra striping is on.
+/// - `peer_bg`: Background of the cells the other clients of a shared session are on.
+/// - `heat_low`, `heat_high`: RGB backgrounds of the lowest and highest numbers in heatmap mode; the
+///   numbers in between get a blend of the two.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Theme {
+    headers: Color,
+    cursor_fg: Color,
+    cursor_bg: Color,
+    match_fg: Color,
+    match_bg: Color,
+    precedent_bg: Color,
+    dependent_bg: Color,
+    stripe_bg: Color,
+    peer_bg: Color,
+    heat_low: (u8, u8, u8),
+    heat_high: (u8, u8, u8),
 }
 
-impl CellAddress {
-    /// Creates a new `CellAddress` from a column and row index.
-    ///
-    /// # Arguments:
-    /// - `col`: The zero-based column index (0 for 'A').
-    /// - `row`: The zero-based row index (0 for row 1).
-    ///
-    /// # Returns:
-    /// A `CellAddress` struct representing the cell at the specified position.
-    fn new(col: usize, row: usize) -> Self {
-        CellAddress { col, row }
-    }
-    /// Parses a string (e.g., "A1", "B2") into a `CellAddress`.
-    ///
-    /// The string must be in the format of a letter (column) followed by a number (row),
-    /// such as "A1" or "B2". The column is case-insensitive.
-    ///
-    /// # Arguments:
-    /// - `addr`: A string representing the cell address, e.g., "A1", "B2".
-    ///
-    /// # Returns:
-    /// An `Option<CellAddress>`, which is `Some(CellAddress)` if the string is valid,
-    /// or `None` if the string is invalid.
-    fn from_str(addr: &str) -> Option<Self> {
-        if addr.len() < 2 {
-            return None;
-        }
-        
-        let col_char = addr.chars().next().unwrap();
-        let col = match col_char {
-            'A'..='Z' => (col_char as usize) - ('A' as usize),
-            'a'..='z' => (col_char as usize) - ('a' as usize),
-            _ => return None,
-        };
-        
-        let row_str = &addr[1..];
-        match row_str.parse::<usize>() {
-            Ok(row) if row > 0 => Some(CellAddress::new(col, row - 1)),
+impl Theme {
+/// Returns the theme with the given name (`default`, `light` or `mono`), ignoring case.
+    fn named(name: &str) -> Option<Theme> {
+        match name.to_ascii_lowercase().as_str() {
+            "default" | "dark" => Some(Theme {
+                headers: Color::Cyan, cursor_fg: Color::Black, cursor_bg: Color::White,
+                match_fg: Color::Black, match_bg: Color::Yellow,
+                precedent_bg: Color::DarkCyan, dependent_bg: Color::DarkMagenta, stripe_bg: Color::AnsiValue(236),
+                peer_bg: Color::DarkGreen, heat_low: (40, 70, 160), heat_high: (190, 40, 40),
+            }),
+            "light" => Some(Theme {
+                headers: Color::DarkBlue, cursor_fg: Color::White, cursor_bg: Color::DarkBlue,
+                match_fg: Color::Black, match_bg: Color::DarkYellow,
+                precedent_bg: Color::Cyan, dependent_bg: Color::Magenta, stripe_bg: Color::AnsiValue(254),
+                peer_bg: Color::Green, heat_low: (150, 190, 255), heat_high: (255, 140, 120),
+            }),
+            "mono" => Some(Theme {
+                headers: Color::Reset, cursor_fg: Color::Black, cursor_bg: Color::Grey,
+                match_fg: Color::Black, match_bg: Color::DarkGrey,
+                precedent_bg: Color::DarkGrey, dependent_bg: Color::DarkGrey, stripe_bg: Color::AnsiValue(236),
+                peer_bg: Color::Grey, heat_low: (40, 40, 40), heat_high: (200, 200, 200),
+            }),
             _ => None,
         }
     }
-    /// Converts a column index to an Excel-style column label (e.g., 0 -> "A", 1 -> "B", 26 -> "AA").
-    ///
-    /// # Arguments:
-    /// - `col`: The zero-based column index.
-    ///
-    /// # Returns:
-    /// A string representing the Excel-style column label.
-    fn col_to_letters(mut col: usize) -> String {
-        let mut label = String::new();
-        col += 1; // shift to 1-based
-        while col > 0 {
-            col -= 1;
-            label.insert(0, (b'A' + (col % 26) as u8) as char);
-            col /= 26;
-        }
-        label
-    }
-      /// Converts the `CellAddress` to a string representation (e.g., "A1", "B2").
-    ///
-    /// # Returns:
-    /// A string representing the cell address in the format "A1", "B2", etc.
-    fn to_string(&self) -> String {
-       format!("{}{}", Self::col_to_letters(self.col), self.row + 1)
+}
+
+/// Returns a short, printable label for a key, used by the demo overlay and in recorded scripts.
+///
+/// Printable characters are returned as-is; special keys use a bracketed name such as `<Enter>`,
+/// `<Esc>` or `<BS>`. Keys without a label return `None`.
+fn key_label(key: &KeyCode) -> Option<String> {
+    let label = match key {
+        KeyCode::Char('<') => "<lt>".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::Enter => "<Enter>".to_string(),
+        KeyCode::Esc => "<Esc>".to_string(),
+        KeyCode::Backspace => "<BS>".to_string(),
+        KeyCode::Tab => "<Tab>".to_string(),
+        KeyCode::Up => "<Up>".to_string(),
+        KeyCode::Down => "<Down>".to_string(),
+        KeyCode::Left => "<Left>".to_string(),
+        KeyCode::Right => "<Right>".to_string(),
+        _ => return None,
+    };
+    Some(label)
+}
+
+/// Parses a demo script into the key presses it describes.
+///
+/// The script uses the same notation as [`key_label`]: plain characters are typed literally and
+/// bracketed names (`<Enter>`, `<Esc>`, `<BS>`, `<lt>`, ...) stand for special keys. Line breaks are
+/// ignored, and lines starting with `#` are comments.
+///
+/// # Returns
+/// The keys in the order they should be replayed.
+fn parse_key_script(script: &str) -> Vec<KeyCode> {
+    let mut keys = Vec::new();
+    for line in script.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            if let Some(end) = rest.find('>').filter(|_| c == '<') {
+                let key = match &rest[..=end] {
+                    "<lt>" => Some(KeyCode::Char('<')),
+                    "<Enter>" => Some(KeyCode::Enter),
+                    "<Esc>" => Some(KeyCode::Esc),
+                    "<BS>" => Some(KeyCode::Backspace),
+                    "<Tab>" => Some(KeyCode::Tab),
+                    "<Up>" => Some(KeyCode::Up),
+                    "<Down>" => Some(KeyCode::Down),
+                    "<Left>" => Some(KeyCode::Left),
+                    "<Right>" => Some(KeyCode::Right),
+                    _ => None,
+                };
+                if let Some(key) = key {
+                    keys.push(key);
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+            keys.push(KeyCode::Char(c));
+            rest = &rest[c.len_utf8()..];
+        }
     }
+    keys
 }
 
-// Represents an undo action in the spreadsheet, storing the state of a cell before an edit.
+/// Suspends the TUI and hands the terminal back to the shell, like Ctrl-Z in Vim.
 ///
-/// The `UndoAction` struct holds information about a cell's address and its previous state (the `old_cell`),
-/// allowing for the undoing of a specific change made to a cell. This can be useful for implementing 
-/// undo functionality in the spreadsheet editor.
+/// The terminal is restored (raw mode off, cursor shown, screen cleared) before suspending. On Unix
+/// the process stops itself with `SIGTSTP` and continues here after `fg`; on Windows, where job
+/// control does not exist, an interactive shell is started instead and the editor resumes when it exits.
+/// Raw mode and the hidden cursor are re-entered afterwards; the caller is expected to redraw.
 ///
-/// # Fields:
-/// - `cell_address`: The address of the cell that was modified.
-/// - `old_cell`: The previous state of the cell before the edit was made, including its value, formula, and other properties.
+/// # Arguments
+/// * `stdout` - The terminal output stream.
+///
+/// # Returns
+/// Returns an `io::Result<()>` indicating whether the terminal could be restored and re-entered.
+fn suspend_to_shell(stdout: &mut io::Stdout) -> io::Result<()> {
+    terminal::disable_raw_mode()?;
+    stdout.execute(Show)?;
+    stdout.execute(terminal::Clear(ClearType::All))?;
+    stdout.execute(MoveTo(0, 0))?;
+    stdout.flush()?;
 
-#[derive(Clone, Debug)]
-struct UndoAction {
-    cell_address: CellAddress,
-    old_cell: Cell,
+    if cfg!(windows) {
+        let shell = env::var("COMSPEC").unwrap_or_else(|_| "cmd.exe".to_string());
+        println!("Type 'exit' to return to the spreadsheet.");
+        std::process::Command::new(shell).status()?;
+    } else {
+        // Blocks until the shell resumes us with `fg`
+        std::process::Command::new("kill")
+            .args(["-TSTP", &std::process::id().to_string()])
+            .status()?;
+    }
+
+    terminal::enable_raw_mode()?;
+    stdout.execute(Hide)?;
+    stdout.execute(terminal::Clear(ClearType::All))?;
+    Ok(())
 }
 
-// Represents a collection of cell changes in a single action that can be undone or redone.
-//
-// The `SheetAction` struct groups multiple `UndoAction` instances that represent the changes made to cells
-// during a particular operation. This structure is useful for tracking the state of a spreadsheet during edits
-// and facilitates undo and redo functionality.
-//
-// # Fields:
-// - `cells`: A collection of all `UndoAction` instances, representing the changes made to individual cells
-//   in the current action.
-// struct SheetAction {
-//     cells: Vec<UndoAction>,  // Collection of all cell changes in this action
-// }
+/// Day zero of date serial numbers (the spreadsheet convention where 1900-01-01 is day 2).
+fn date_epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1899, 12, 30).unwrap()
+}
 
+/// Converts a date serial number (days since 1899-12-30) back into a date.
+fn serial_to_date(serial: f64) -> Option<NaiveDate> {
+    if !serial.is_finite() {
+        return None;
+    }
+    date_epoch().checked_add_signed(chrono::Duration::try_days(serial.floor() as i64)?)
+}
 
-/// Represents the state of the entire spreadsheet, including cell data, user interaction, and tracking of undo/redo actions.
+/// Parses a date typed by the user and returns its serial number.
 ///
-/// The `Spreadsheet` struct encapsulates the entire state of a spreadsheet, including the data of each cell,
-/// the current cursor position, the mode of operation (e.g., normal, insert), and additional attributes to manage
-/// user actions such as undo, redo, and search. It also manages dependencies between cells and tracks changes
-/// in real-time to ensure consistent updates across the spreadsheet.
+/// The cell's own display pattern is tried first, so `03/04/2024` follows the order of the format
+/// chosen for the cell; then several common input formats are accepted (ISO `2024-04-03`,
+/// `03.04.2024`, `03/04/2024`, `04/03/2024`, `3 Apr 2024`, `Apr 3, 2024`, ...).
 ///
-/// # Fields:
-/// - `data`: A `HashMap` storing the actual data (cells) of the spreadsheet, where the key is the cell address.
-/// - `cursor`: The current position of the cursor (cell address).
-/// - `mode`: The current mode of the spreadsheet (e.g., Normal, Insert, Command, Find).
-/// - `max_cols`: The maximum number of columns in the spreadsheet.
-/// - `max_rows`: The maximum number of rows in the spreadsheet.
-/// - `command_buffer`: A string buffer for storing the current command being entered by the user.
-/// - `status_message`: A message that displays the current status or feedback for the user.
-/// - `undo_stack`: A stack (using `VecDeque`) that tracks the history of actions that can be undone.
-/// - `redo_stack`: A stack (using `VecDeque`) that tracks the history of undone actions that can be redone.
-/// - `find_matches`: A list of `CellAddress` instances that match the current search query.
-/// - `current_find_match`: The index of the current match in the `find_matches` list.
-/// - `find_query`: The current search query being used to find matches in the spreadsheet.
-/// - `dependents`: A `HashMap` mapping a cell address to the set of cells that depend on it.
-/// - `dependencies`: A `HashMap` mapping a cell address to the set of cells it depends on.
-/// - `currently_updating`: A set of cell addresses currently being updated, used to avoid cycles in dependency resolution.
-/// ### Haunt Mode & Visual Effects:
-/// - `haunted`: Indicates whether Haunt Mode is active.
-/// - `haunt_sink`: Optional `Sink` for playing haunted audio effects.
-/// - `haunt_stream`: Optional `OutputStream` tied to the haunted audio.
-/// - `flicker_on`: Enables screen flicker effects when Haunt Mode is active.
-/// - `last_flicker`: Timestamp of the last flicker event, used to control flicker intervals.
-/// - `corruption_level`: Represents the current level of screen corruption (0–3).
-/// - `last_corruption_tick`: Timestamp of the last corruption update.
-/// - `haunted_start`: Records when Haunt Mode was activated.
-/// - `jump_scare_triggered`: Tracks whether a jump scare has already occurred during Haunt Mode.
-struct Spreadsheet {
-    data: HashMap<String, Cell>,
-    cursor: CellAddress,
-    mode: Mode,
-    max_cols: usize,
-    max_rows: usize,
-    command_buffer: String,
-    status_message: String,
-    undo_stack: VecDeque<UndoAction>,
-    redo_stack: VecDeque<UndoAction>,
-    find_matches: Vec<CellAddress>,
-    current_find_match: usize,
-    find_query: String,
-    dependents: HashMap<String, HashSet<String>>,  // Maps cell address to cells that depend on it
-    dependencies: HashMap<String, HashSet<String>>,
-    currently_updating: HashSet<String>, // Tracks cells being updated to prevent cycles
-    haunted : bool,
-    haunt_sink : Option<Sink>,
-    haunt_stream : Option<OutputStream>,
-    flicker_on: bool,
-    last_flicker: Instant,
-    corruption_level: u8,       // 0 = calm, 3 = full chaos
-    last_corruption_tick: Instant,
-    haunted_start: Option<Instant>,
-    jump_scare_triggered: bool,
-
+/// # Arguments
+/// * `value` - The text entered into the cell.
+/// * `pattern` - The `strftime`-style display pattern of the cell.
+///
+/// # Returns
+/// `Some(serial)` if the text is a date, otherwise `None`.
+fn parse_date_input(value: &str, pattern: &str) -> Option<i64> {
+    const INPUT_FORMATS: [&str; 8] = ["%Y-%m-%d", "%d.%m.%Y", "%d/%m/%Y", "%m/%d/%Y", "%Y/%m/%d", "%d-%m-%Y", "%d %b %Y", "%b %d, %Y"];
+    let value = value.trim();
+    std::iter::once(pattern)
+        .chain(INPUT_FORMATS)
+        .find_map(|fmt| NaiveDate::parse_from_str(value, fmt).ok())
+        .map(|date| (date - date_epoch()).num_days())
+}
 
+/// Returns the date pattern matching the user's locale (from `LC_ALL`, `LC_TIME` or `LANG`).
+///
+/// US English uses `%m/%d/%Y`, British English `%d/%m/%Y`, most other European locales
+/// `%d.%m.%Y`, and everything else (including no locale) ISO `%Y-%m-%d`.
+fn locale_date_pattern() -> &'static str {
+    let locale = ["LC_ALL", "LC_TIME", "LANG"].iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|value| !value.is_empty())
+        .unwrap_or_default();
+    match locale.split(['.', '@']).next().unwrap_or("") {
+        "en_US" => "%m/%d/%Y",
+        "en_GB" | "en_IE" | "en_AU" | "en_NZ" | "en_IN" => "%d/%m/%Y",
+        l if ["de", "fr", "it", "es", "nl", "pl", "ru", "cs", "fi", "nb", "da", "sv"].iter().any(|p| l.starts_with(p)) => "%d.%m.%Y",
+        _ => "%Y-%m-%d",
+    }
 }
 
-impl Spreadsheet {
-    /// Creates a new `Spreadsheet` instance with the given number of rows and columns.
-    ///
-    /// This method initializes a spreadsheet with the specified dimensions, creating
-    /// a grid of cells. It sets up the initial state for the spreadsheet, including the
-    /// cursor position, mode, undo and redo stacks, and other related fields.
-    ///
-    /// # Arguments:
-    /// - `rows`: The number of rows in the spreadsheet.
-    /// - `cols`: The number of columns in the spreadsheet
-    ///
-    /// # Returns:
-    /// A new `Spreadsheet` instance with the given number of rows and columns.
-    fn new(rows: usize, cols: usize) -> Self {
-        let mut sheet = Spreadsheet {
-            data: HashMap::new(),
-            cursor: CellAddress::new(0, 0),
-            mode: Mode::Normal,
-            max_cols: cols,
-            max_rows: rows,
-            command_buffer: String::new(),
-            status_message: String::new(),
-            undo_stack: VecDeque::with_capacity(3),
-            redo_stack: VecDeque::with_capacity(3),
-            find_matches: Vec::new(),
-            current_find_match: 0,
-            find_query: String::new(),
-            dependents: HashMap::new(),
-            dependencies: HashMap::new(),
-            currently_updating: HashSet::new(),
-            haunted: false,
-            haunt_sink: None,
-            haunt_stream: None,
-            flicker_on: false,
-            last_flicker: Instant::now(),
-            corruption_level: 0,
-            last_corruption_tick: Instant::now(),
-            haunted_start: None,
-            jump_scare_triggered: false,
-        };
-        
-        // Initialize cells
-        for col in 0..cols {
-            for row in 0..rows {
-                let addr = CellAddress::new(col, row).to_string();
-                sheet.data.insert(addr, Cell::new());
-            }
-        }
-        
-        sheet
+/// Resolves the pattern argument of `:fmt ... date`: a named preset (`iso`, `us`, `eu`, `uk`,
+/// `locale`), an explicit `strftime`-style pattern, or the locale's pattern when omitted.
+///
+/// # Returns
+/// `Some(pattern)` if the pattern is valid, or `None` if it contains unknown `%` specifiers.
+fn resolve_date_pattern(arg: Option<&str>) -> Option<String> {
+    let pattern = match arg.map(|a| a.trim()) {
+        None | Some("") | Some("locale") => locale_date_pattern(),
+        Some("iso") => "%Y-%m-%d",
+        Some("us") => "%m/%d/%Y",
+        Some("eu") => "%d.%m.%Y",
+        Some("uk") => "%d/%m/%Y",
+        Some(p) => p,
+    };
+    if StrftimeItems::new(pattern).any(|item| matches!(item, Item::Error)) {
+        return None;
     }
+    Some(pattern.to_string())
+}
 
-    /// Retrieves a reference to a cell at the given address.
-    ///
-    /// This method looks up a cell in the spreadsheet based on the provided address.
-    ///
-    /// # Arguments:
-    /// - `addr`: A reference to the `CellAddress` of the cell to retrieve.
-    ///
-    /// # Returns:
-    /// An `Option` containing a reference to the `Cell` if it exists, or `None` if the address is invalid.
-    fn get_cell(&self, addr: &CellAddress) -> Option<&Cell> {
-        self.data.get(&addr.to_string())
+/// An intermediate result while translating an imported Excel formula.
+///
+/// - `Number`: A constant, already folded (e.g. `ROUND(2.5,0)` becomes `3`).
+/// - `Cell`: A single cell reference.
+/// - `Range`: A range such as `A1:B5`, only valid as a function argument.
+/// - `Formula`: A complete formula for the internal engine (without `=`) that cannot be combined further.
+#[derive(Clone, Debug)]
+enum Translated {
+    Number(f64),
+    Cell(String),
+    Range(String),
+    Formula(String),
+}
+
+/// A small recursive-descent parser that translates Excel formula syntax into the formulas the
+/// internal engine understands (`SUM(A1:B5)`, `sqrt(A1)`, `(A1+B1)`, ...).
+///
+/// # Fields:
+/// - `chars`: The characters of the formula, with `$` markers and whitespace removed.
+/// - `pos`: The position of the next unread character.
+struct ExcelTranslator {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl ExcelTranslator {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
     }
 
-     /// Retrieves a mutable reference to a cell at the given address.
-    ///
-    /// This method allows for modifying the cell at the specified address.
-    ///
-    /// # Arguments:
-    /// - `addr`: A reference to the `CellAddress` of the cell to retrieve.
-    ///
-    /// # Returns:
-    /// An `Option` containing a mutable reference to the `Cell` if it exists, or `None` if the address is invalid.
-    fn get_cell_mut(&mut self, addr: &CellAddress) -> Option<&mut Cell> {
-        self.data.get_mut(&addr.to_string())
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> std::result::Result<Translated, String> {
+        let mut left = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let right = self.term()?;
+            left = combine(left, op, right)?;
+        }
+        Ok(left)
     }
 
-    /// Moves the cursor by the given number of columns and rows.
-    ///
-    /// This method updates the position of the cursor within the bounds of the spreadsheet.
-    ///
-    /// # Arguments:
-    /// - `dx`: The number of columns to move the cursor. Positive values move to the right, negative values to the left.
-    /// - `dy`: The number of rows to move the cursor. Positive values move down, negative values move up.
-    ///
-    /// # Notes:
-    /// The cursor will not move outside the bounds of the spreadsheet (i.e., the number of columns and rows).
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> std::result::Result<Translated, String> {
+        let mut left = self.factor()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.pos += 1;
+            let right = self.factor()?;
+            left = combine(left, op, right)?;
+        }
+        Ok(left)
+    }
 
-    fn move_cursor(&mut self, dx: isize, dy: isize) {
-        let new_col = self.cursor.col as isize + dx;
-        let new_row = self.cursor.row as isize + dy;
-        
-        // Ensure within bounds
-        if new_col >= 0 && new_col < self.max_cols as isize &&
-           new_row >= 0 && new_row < self.max_rows as isize {
-            self.cursor.col = new_col as usize;
-            self.cursor.row = new_row as usize;
+    // factor := '-' factor | '(' expr ')' | number | cell | range | name '(' args ')'
+    fn factor(&mut self) -> std::result::Result<Translated, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                match self.factor()? {
+                    Translated::Number(n) => Ok(Translated::Number(-n)),
+                    _ => Err("NEGATED CELL".to_string()),
+                }
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.factor()
+            }
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.expr()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.' || c == 'E' || c == 'e') {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                text.parse::<f64>().map(Translated::Number).map_err(|_| format!("BAD NUMBER {}", text))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect::<String>().to_uppercase();
+                if self.peek() == Some('(') {
+                    self.pos += 1;
+                    let mut args = Vec::new();
+                    if self.peek() != Some(')') {
+                        args.push(self.expr()?);
+                        while self.peek() == Some(',') {
+                            self.pos += 1;
+                            args.push(self.expr()?);
+                        }
+                    }
+                    self.expect(')')?;
+                    return translate_function(&name, args);
+                }
+                if CellAddress::from_str(&name).is_none() {
+                    return Err(format!("UNKNOWN NAME {}", name));
+                }
+                if self.peek() == Some(':') {
+                    self.pos += 1;
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+                        self.pos += 1;
+                    }
+                    let end: String = self.chars[start..self.pos].iter().collect::<String>().to_uppercase();
+                    if CellAddress::from_str(&end).is_none() {
+                        return Err(format!("BAD RANGE {}:{}", name, end));
+                    }
+                    return Ok(Translated::Range(format!("{}:{}", name, end)));
+                }
+                Ok(Translated::Cell(name))
+            }
+            Some('"') => Err("TEXT VALUES".to_string()),
+            Some(c) => Err(format!("UNEXPECTED '{}'", c)),
+            None => Err("UNEXPECTED END".to_string()),
         }
     }
 
-    /// Moves the cursor to the specified cell address.
-    ///
-    /// This method attempts to move the cursor to a given cell address, specified as a string (e.g., "A1").
-    /// If the address is valid and within the bounds of the spreadsheet, the cursor will be moved to that cell.
-    ///
-    /// # Arguments:
-    /// - `addr`: A string representing the cell address to jump to (e.g., "A1", "B2").
-    ///
-    /// # Returns:
-    /// `true` if the cell address is valid and the cursor is successfully moved, otherwise `false`.
-    fn jump_to_cell(&mut self, addr: &str) -> bool {
-        if let Some(cell_addr) = CellAddress::from_str(addr) {
-            if cell_addr.col < self.max_cols && cell_addr.row < self.max_rows {
-                self.cursor = cell_addr;
-                return true;
+    fn expect(&mut self, c: char) -> std::result::Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("EXPECTED '{}'", c))
+        }
+    }
+}
+
+/// Combines two operands with an arithmetic operator. Constants are folded; the engine itself only
+/// evaluates a single `+`, `-` or `*` between two cells or non-negative numbers.
+fn combine(left: Translated, op: char, right: Translated) -> std::result::Result<Translated, String> {
+    let operand = |t: &Translated| match t {
+        Translated::Cell(c) => Some(c.clone()),
+        Translated::Number(n) if *n >= 0.0 => Some(n.to_string()),
+        _ => None,
+    };
+    match (&left, op, &right) {
+        (Translated::Number(a), _, Translated::Number(b)) => Ok(Translated::Number(match op {
+            '+' => a + b,
+            '-' => a - b,
+            '*' => a * b,
+            _ if *b == 0.0 => return Err("DIVISION BY ZERO".to_string()),
+            _ => a / b,
+        })),
+        // a + (-n) and a - (-n) keep the right operand non-negative
+        (_, '+' | '-', Translated::Number(b)) if *b < 0.0 => {
+            combine(left, if op == '+' { '-' } else { '+' }, Translated::Number(-b))
+        }
+        _ => match (operand(&left), operand(&right)) {
+            (Some(a), Some(b)) => Ok(Translated::Formula(format!("({}{}{})", a, op, b))),
+            _ => Err("NESTED EXPRESSION".to_string()),
+        },
+    }
+}
+
+/// Rounds like Excel: halves go away from zero and negative digit counts round to tens, hundreds, ...
+fn excel_round(value: f64, digits: f64, mode: fn(f64) -> f64) -> f64 {
+    let factor = 10f64.powi(digits.trunc() as i32);
+    mode(value * factor) / factor
+}
+
+/// Translates one Excel function call with already translated arguments.
+fn translate_function(name: &str, args: Vec<Translated>) -> std::result::Result<Translated, String> {
+    let numbers: Option<Vec<f64>> = args.iter()
+        .map(|a| if let Translated::Number(n) = a { Some(*n) } else { None })
+        .collect();
+    match (name, args.as_slice()) {
+        // Range aggregates supported natively
+        ("SUM" | "MIN" | "MAX" | "STDEV" | "STDEV.S", [Translated::Range(range)]) => {
+            let engine_name = if name == "STDEV.S" { "STDEV" } else { name };
+            Ok(Translated::Formula(format!("{}({})", engine_name, range)))
+        }
+        ("SUM" | "MIN" | "MAX", [Translated::Cell(cell)]) => Ok(Translated::Cell(cell.clone())),
+        ("SUM", [first, rest @ ..]) if !rest.is_empty() => {
+            rest.iter().try_fold(first.clone(), |acc, arg| combine(acc, '+', arg.clone()))
+        }
+        ("MIN" | "MAX" | "AVERAGE", _) if numbers.as_ref().is_some_and(|n| !n.is_empty()) => {
+            let n = numbers.unwrap();
+            Ok(Translated::Number(match name {
+                "MIN" => n.iter().copied().fold(f64::INFINITY, f64::min),
+                "MAX" => n.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                _ => n.iter().sum::<f64>() / n.len() as f64,
+            }))
+        }
+        // Single-argument functions: cells go to the engine, constants are folded
+        ("SQRT" | "LN", [Translated::Cell(cell)]) => {
+            Ok(Translated::Formula(format!("{}({})", if name == "SQRT" { "sqrt" } else { "log" }, cell)))
+        }
+        ("SQRT", [Translated::Number(n)]) if *n >= 0.0 => Ok(Translated::Number(n.sqrt())),
+        ("LN", [Translated::Number(n)]) if *n > 0.0 => Ok(Translated::Number(n.ln())),
+        ("LOG10" | "LOG", [Translated::Number(n)]) if *n > 0.0 => Ok(Translated::Number(n.log10())),
+        ("LOG", [Translated::Number(n), Translated::Number(base)]) if *n > 0.0 && *base > 0.0 => {
+            Ok(Translated::Number(n.log(*base)))
+        }
+        ("ABS", [Translated::Number(n)]) => Ok(Translated::Number(n.abs())),
+        ("INT", [Translated::Number(n)]) => Ok(Translated::Number(n.floor())),
+        ("PI", []) => Ok(Translated::Number(std::f64::consts::PI)),
+        ("ROUND", [Translated::Number(n), Translated::Number(d)]) => Ok(Translated::Number(excel_round(*n, *d, f64::round))),
+        ("ROUNDUP", [Translated::Number(n), Translated::Number(d)]) => {
+            Ok(Translated::Number(excel_round(*n, *d, |x| x.abs().ceil().copysign(x))))
+        }
+        ("ROUNDDOWN", [Translated::Number(n), Translated::Number(d)]) => Ok(Translated::Number(excel_round(*n, *d, f64::trunc))),
+        ("ROUND" | "ROUNDUP" | "ROUNDDOWN", _) => Err(format!("{} OF A CELL", name)),
+        // The engine has no error values to catch, so the guarded expression is used as is
+        ("IFERROR", [value, _fallback]) => Ok(value.clone()),
+        _ => Err(format!("UNSUPPORTED FUNCTION {}", name)),
+    }
+}
+
+/// Translates a formula written for Excel (or LibreOffice) into the internal formula syntax.
+///
+/// Handles `$` absolute markers, OpenFormula references (`[.A1:.B2]`), `;` argument separators, lower-case function names, operator
+/// precedence, `IFERROR` and Excel's `ROUND` semantics (halves away from zero), and folds constant
+/// sub-expressions. Anything the engine cannot evaluate is reported instead of being imported wrongly.
+///
+/// # Arguments
+/// * `formula` - The Excel formula, with or without the leading `=`.
+///
+/// # Returns
+/// `Ok(value)` with the text to enter into the cell (a formula starting with `=`, or a plain number
+/// if the formula was constant), or `Err(reason)` if the formula cannot be translated.
+fn translate_excel_formula(formula: &str) -> std::result::Result<String, String> {
+    // OpenFormula (from .ods files) writes references as [.A1] and ranges as [.A1:.B2]
+    let formula = formula.trim().trim_start_matches('=').trim_start_matches("of:");
+    let open_refs = regex::Regex::new(r"\[\.(\$?[A-Za-z]+\$?[0-9]+)(?::\.(\$?[A-Za-z]+\$?[0-9]+))?\]").unwrap();
+    let formula = open_refs.replace_all(formula, |caps: &regex::Captures| match caps.get(2) {
+        Some(end) => format!("{}:{}", &caps[1], end.as_str()),
+        None => caps[1].to_string(),
+    });
+    let chars: Vec<char> = formula.trim_start_matches('=').chars()
+        .filter(|c| *c != '$' && !c.is_whitespace())
+        .map(|c| if c == ';' { ',' } else { c })
+        .collect();
+    let mut translator = ExcelTranslator { chars, pos: 0 };
+    let result = translator.expr()?;
+    if let Some(c) = translator.peek() {
+        return Err(format!("UNEXPECTED '{}'", c));
+    }
+    match result {
+        Translated::Number(n) if n.is_finite() => Ok(n.to_string()),
+        Translated::Number(_) => Err("NOT A NUMBER".to_string()),
+        Translated::Cell(cell) => Ok(format!("=({})", cell)),
+        Translated::Formula(f) => Ok(format!("={}", f)),
+        Translated::Range(range) => Err(format!("BARE RANGE {}", range)),
+    }
+}
+
+/// Converts an internal formula (without `=`) to OpenFormula as stored in `.ods` files, e.g.
+/// `SUM(A1:B2)` becomes `of:=SUM([.A1:.B2])` and `(A1+B1)` becomes `of:=([.A1]+[.B1])`.
+///
+/// # Returns
+/// `Some(formula)`, or `None` for formulas with no OpenDocument equivalent (`LINK`, `SLEEP`).
+fn to_open_formula(formula: &str) -> Option<String> {
+    if formula.starts_with("LINK(") || formula.starts_with("SLEEP(") {
+        return None;
+    }
+    let renamed = if let Some(arg) = formula.strip_prefix("sqrt(") {
+        format!("SQRT({}", arg)
+    } else if let Some(arg) = formula.strip_prefix("log(") {
+        format!("LN({}", arg)
+    } else {
+        formula.to_string()
+    };
+    let refs = regex::Regex::new(r"([A-Z]+[0-9]+)(?::([A-Z]+[0-9]+))?").unwrap();
+    let converted = refs.replace_all(&renamed, |caps: &regex::Captures| match caps.get(2) {
+        Some(end) => format!("[.{}:.{}]", &caps[1], end.as_str()),
+        None => format!("[.{}]", &caps[1]),
+    });
+    Some(format!("of:={}", converted))
+}
+
+/// Escapes text for use inside HTML element content and attribute values.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Escapes the characters LaTeX treats specially so cell text is typeset literally.
+fn escape_latex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                escaped.push('\\');
+                escaped.push(c);
             }
+            c => escaped.push(c),
         }
-        false
     }
+    escaped
+}
 
-    /// Adds a dependency between two cells.
-    ///
-    /// This method records that one cell (the dependent) depends on the value of another cell (the dependency).
-    /// It updates the `dependencies` and `dependents` mappings accordingly.
-    ///
-    /// # Arguments:
-    /// - `dependent`: The address of the cell that depends on another cell.
-    /// - `dependency`: The address of the cell that is being depended on.
-    ///
-    /// # Notes:
-    /// This method will ensure that both the `dependencies` and `dependents` mappings are updated for both
-    /// the dependent and the dependency cells.
-    fn add_dependency(&mut self, dependent: &str, dependency: &str) {
-        // Record that 'dependent' depends on 'dependency'
-        self.dependencies.entry(dependent.to_string())
-            .or_insert_with(HashSet::new)
-            .insert(dependency.to_string());
-        
-        // Record that 'dependency' is depended upon by 'dependent'
-        self.dependents.entry(dependency.to_string())
-            .or_insert_with(HashSet::new)
-            .insert(dependent.to_string());
+/// Returns how many terminal columns `text` takes up: two for wide characters such as CJK and most
+/// emoji, none for combining marks.
+fn display_width(text: &str) -> usize {
+    UnicodeWidthStr::width(text)
+}
+
+/// Returns the longest start of `text` that takes up at most `width` terminal columns.
+fn take_width(text: &str, width: usize) -> String {
+    let mut used = 0;
+    text.chars()
+        .take_while(|c| {
+            used += c.width().unwrap_or(0);
+            used <= width
+        })
+        .collect()
+}
 
-        println!("DEBUG: Added dependency: {} -> {}", dependent, dependency);
+/// Cuts `text` down to at most `width` terminal columns, marking a cut with a trailing `~`.
+fn truncate_to_width(text: &str, width: usize) -> String {
+    if display_width(text) <= width {
+        return text.to_string();
+    }
+    let mut cut = take_width(text, width.saturating_sub(1));
+    if width > 0 {
+        cut.push('~');
     }
+    cut
+}
 
-    /// Removes all dependencies related to the given cell address.
-    ///
-    /// This method removes both the cell's dependencies and the cell from the list of dependents of each of its
-    /// dependencies. It is useful when clearing dependencies when a cell's formula is changed or removed.
-    ///
-    /// # Arguments:
-    /// - `cell_addr`: The address of the cell for which to remove dependencies.
-    fn remove_dependencies(&mut self, cell_addr: &str) {
-        // Remove all dependencies for this cell
-        if let Some(deps) = self.dependencies.remove(cell_addr) {
-            // For each dependency, remove this cell from its dependents
-            for dep in deps {
-                if let Some(dependents) = self.dependents.get_mut(&dep) {
-                    dependents.remove(cell_addr);
-                }
+/// Block characters from one to eight eighths high, used by sparklines and charts.
+const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Returns a sparkline of `values`: one block character per value, scaled between their minimum and
+/// maximum.
+fn sparkline(values: &[f64]) -> String {
+    let lo = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let hi = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    values.iter().map(|v| {
+        let level = if hi > lo { ((v - lo) / (hi - lo) * 7.0).round() as usize } else { 0 };
+        BLOCKS[level.min(7)]
+    }).collect()
+}
+
+/// Returns the heatmap background of `value`: `low` at `lo`, `high` at `hi`, and a blend in between.
+pub fn heat_color(value: f64, lo: f64, hi: f64, low: (u8, u8, u8), high: (u8, u8, u8)) -> Color {
+    let t = if hi > lo { ((value - lo) / (hi - lo)).clamp(0.0, 1.0) } else { 0.5 };
+    let blend = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    Color::Rgb { r: blend(low.0, high.0), g: blend(low.1, high.1), b: blend(low.2, high.2) }
+}
+
+/// Returns the lines (top to bottom) of a chart of `values` that is `height` lines tall, each value
+/// `bar_width` characters wide. Bars start from zero (or the minimum, if it is below zero); a line
+/// chart is scaled between the minimum and maximum and only marks the top of each bar.
+pub fn chart_lines(kind: ChartKind, values: &[f64], height: usize, bar_width: usize) -> Vec<String> {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let lo = if kind == ChartKind::Bar { min.min(0.0) } else { min };
+    let span = if max > lo { max - lo } else { 1.0 };
+    // Each line holds eight levels, one per block character
+    let levels: Vec<usize> = values.iter().map(|v| ((v - lo) / span * (height * 8) as f64).round() as usize).collect();
+    (0..height).rev().map(|line| {
+        let bottom = line * 8;
+        levels.iter().map(|&level| {
+            let block = match kind {
+                ChartKind::Bar if level > bottom => Some(BLOCKS[(level - bottom).min(8) - 1]),
+                ChartKind::Line if level > bottom && level <= bottom + 8 => Some(BLOCKS[level - bottom - 1]),
+                ChartKind::Line if level == 0 && line == 0 => Some(BLOCKS[0]),
+                _ => None,
+            };
+            block.unwrap_or(' ').to_string().repeat(bar_width)
+        }).collect()
+    }).collect()
+}
+
+/// Returns the status line shown by `report_progress`: a spinner, the label, a bar filled in
+/// proportion to `done` and the percentage, e.g. `/ SORTING [#####...............] 25%`.
+///
+/// # Arguments
+///
+/// * `label` - What is being done.
+/// * `done` - How many cells have been processed (more than `total` counts as all of them).
+/// * `total` - How many cells the operation processes; must not be 0.
+/// * `frame` - How many times the bar was drawn before, which turns the spinner.
+pub fn progress_bar(label: &str, done: usize, total: usize, frame: usize) -> String {
+    const BAR_WIDTH: usize = 20;
+    let filled = (done.min(total) * BAR_WIDTH) / total;
+    let spinner = ['|', '/', '-', '\\'][frame % 4];
+    format!("{} {} [{}{}] {}%", spinner, label, "#".repeat(filled), ".".repeat(BAR_WIDTH - filled), done.min(total) * 100 / total)
+}
+
+/// Returns the text of the `:help` screen, built from `KEY_BINDINGS`, `COMMANDS` and `FORMULA_FUNCTIONS`.
+/// Descriptions start in a common column; an entry too long for it has its description on the next line.
+pub fn help_lines() -> Vec<String> {
+    const COLUMN: usize = 34;
+    let entry = |lines: &mut Vec<String>, usage: String, about: &str| {
+        if usage.chars().count() < COLUMN {
+            lines.push(format!("  {:<2$}{}", usage, about, COLUMN));
+        } else {
+            lines.push(format!("  {}", usage));
+            lines.push(format!("  {:<2$}{}", "", about, COLUMN));
+        }
+    };
+    let mut lines = vec!["KEYS".to_string()];
+    for (keys, about) in KEY_BINDINGS {
+        entry(&mut lines, keys.to_string(), about);
+    }
+    lines.push(String::new());
+    lines.push("COMMANDS".to_string());
+    for (name, args, about) in COMMANDS {
+        entry(&mut lines, format!(":{} {}", name, args).trim_end().to_string(), about);
+    }
+    lines.push(String::new());
+    lines.push("FORMULA FUNCTIONS".to_string());
+    for (syntax, about) in FORMULA_FUNCTIONS {
+        entry(&mut lines, syntax.to_string(), about);
+    }
+    lines
+}
+
+/// Pads `value` to `width` terminal columns according to the alignment.
+fn align_to_width(value: &str, width: usize, alignment: &Alignment) -> String {
+    let padding = width.saturating_sub(display_width(value));
+    let left = match alignment {
+        Alignment::Left => 0,
+        Alignment::Right => padding,
+        Alignment::Center => padding / 2,
+    };
+    format!("{}{}{}", " ".repeat(left), value, " ".repeat(padding - left))
+}
+
+/// Breaks `text` into lines of at most `width` terminal columns, at spaces where possible; words
+/// longer than a line are split.
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let used = display_width(&line);
+        if used > 0 && used + 1 + display_width(word) <= width {
+            line.push(' ');
+            line.push_str(word);
+            continue;
+        }
+        if used > 0 {
+            lines.push(std::mem::take(&mut line));
+        }
+        let mut rest = word;
+        while display_width(rest) > width {
+            // A character wider than the whole line still goes on a line of its own
+            let mut head = take_width(rest, width);
+            if head.is_empty() {
+                head = rest.chars().take(1).collect();
             }
+            rest = &rest[head.len()..];
+            lines.push(head);
         }
+        line = rest.to_string();
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
     }
+    lines
+}
 
-    /// Updates the dependencies for a cell based on its formula.
-    ///
-    /// This method analyzes a cell's formula and updates its dependencies accordingly. The formula can refer to
-    /// other cells directly (e.g., `A1`), ranges of cells (e.g., `SUM(A1:B2)`), or even functions with cell
-    /// references (e.g., `=SUM(A1:B1)`).
-    ///
-    /// # Arguments:
-    /// - `cell_addr`: The address of the cell whose dependencies need to be updated.
-    /// - `formula`: The formula string that defines the dependencies.
-    fn update_dependencies(&mut self, cell_addr: &str, formula: &str) {
-        println!("DEBUG: Removing dependencies for cell {}", cell_addr);
-        // First, remove any existing dependencies
-        self.remove_dependencies(cell_addr);
-        if formula.starts_with('=') {
-
-            let formula = &formula[1..]; // Skip the '=' character
-            println!("DEBUG: Updating dependencies for formula {}", formula);
-            // Handle range formulas like SUM(A1:B2)
-            if formula.contains('(') && formula.contains(')') && formula.contains(':') {
-                println!("DEBUG: Found range in formula");
-                let range_start = formula.find('(').unwrap() + 1;
-                let range_end = formula.find(')').unwrap();
-                if range_start < range_end {
-                    let range_str = &formula[range_start..range_end];
-                    if let Some((start, end)) = self.parse_range(range_str) {
-                        // Add all cells in the range as dependencies
-                        for col in start.col..=end.col {
-                            for row in start.row..=end.row {
-                                let addr = CellAddress::new(col, row).to_string();
-                                // dependencies.push(addr);
-                                self.add_dependency(cell_addr, &addr);
-                            }
-                        }
-                    }
-                }
-            } else if formula.contains('(') && formula.contains(')') {
-                println!("DEBUG: Found function in formula");
-                let func_start = formula.find('(').unwrap() + 1;
-                let func_end = formula.find(')').unwrap();
-                if func_start < func_end {
-                    let cell_ref = &formula[func_start..func_end];
-                    if let Some(addr) = CellAddress::from_str(cell_ref) {
-                        // dependencies.push(addr.to_string());
-                        self.add_dependency(cell_addr, &(addr.to_string()));
-                    }
-                }
+/// A token of a formula or expression, as read by `tokenize_formula`.
+#[derive(Clone, Debug, PartialEq)]
+enum FormulaToken {
+    /// A number such as `2` or `0.5`.
+    Number(f64),
+    /// A function name or cell label, upper-cased: `SUM`, `SQRT`, `AB12`.
+    Name(String),
+    /// One of `+ - * / ( ) , :`.
+    Symbol(char),
+}
+
+/// Shows a token as it is written in a formula, for error messages.
+impl std::fmt::Display for FormulaToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormulaToken::Number(value) => write!(f, "{}", value),
+            FormulaToken::Name(name) => write!(f, "{}", name),
+            FormulaToken::Symbol(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+/// Splits a formula (without its leading `=`) into tokens, skipping whitespace. A name runs over all
+/// the letters and digits that follow it, so `LOG10` or `AB12` is read as one name.
+///
+/// # Returns
+/// The tokens, or `Err(reason)` for a malformed number or a character that cannot start a token.
+fn tokenize_formula(formula: &str) -> std::result::Result<Vec<FormulaToken>, String> {
+    let chars: Vec<char> = formula.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while let Some(&c) = chars.get(pos) {
+        let start = pos;
+        pos += 1;
+        if c.is_whitespace() {
+            continue;
+        }
+        if c.is_ascii_digit() || c == '.' {
+            while chars.get(pos).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                pos += 1;
             }
-            // Handle simple cell references
-            else {
-                // Simple regex-like pattern for cell references (e.g., A1, B2)
-                for c in formula.chars() {
-                    if c.is_ascii_alphabetic() {
-                        let col_char = c;
-                        let mut remaining = formula.chars().skip_while(|&ch| ch != col_char).skip(1);
-                        let mut row_str = String::new();
-                        
-                        while let Some(c) = remaining.next() {
-                            if c.is_ascii_digit() {
-                                row_str.push(c);
-                            } else {
-                                break;
-                            }
-                        }
-                        
-                        if !row_str.is_empty() {
-                            if let Some(addr) = CellAddress::from_str(&format!("{}{}", col_char, row_str)) {
-                                // dependencies.push(addr.to_string());
-                                self.add_dependency(cell_addr, &(addr.to_string()));
-                            }
+            let text: String = chars[start..pos].iter().collect();
+            tokens.push(FormulaToken::Number(text.parse::<f64>().map_err(|_| format!("BAD NUMBER {}", text))?));
+        } else if c.is_ascii_alphabetic() {
+            while chars.get(pos).is_some_and(|c| c.is_ascii_alphanumeric()) {
+                pos += 1;
+            }
+            tokens.push(FormulaToken::Name(chars[start..pos].iter().collect::<String>().to_uppercase()));
+        } else if "+-*/(),:".contains(c) {
+            tokens.push(FormulaToken::Symbol(c));
+        } else {
+            return Err(format!("UNEXPECTED '{}'", c));
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive-descent evaluator for `evaluate`, reading cell values from a sheet.
+///
+/// # Fields:
+/// - `sheet`: The sheet that cell references are read from.
+/// - `tokens`: The tokens of the expression, from `tokenize_formula`.
+/// - `pos`: The position of the next unread token.
+struct ExprEvaluator<'a> {
+    sheet: &'a Spreadsheet,
+    tokens: Vec<FormulaToken>,
+    pos: usize,
+}
+
+impl ExprEvaluator<'_> {
+    fn peek(&self) -> Option<&FormulaToken> {
+        self.tokens.get(self.pos)
+    }
+
+    /// Returns whether the next token is the symbol `c`.
+    fn at(&self, c: char) -> bool {
+        self.peek() == Some(&FormulaToken::Symbol(c))
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> std::result::Result<f64, String> {
+        let mut left = self.term()?;
+        while let Some(&FormulaToken::Symbol(op @ ('+' | '-'))) = self.peek() {
+            self.pos += 1;
+            let right = self.term()?;
+            left = if op == '+' { left + right } else { left - right };
+        }
+        Ok(left)
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> std::result::Result<f64, String> {
+        let mut left = self.factor()?;
+        while let Some(&FormulaToken::Symbol(op @ ('*' | '/'))) = self.peek() {
+            self.pos += 1;
+            let right = self.factor()?;
+            left = match op {
+                '*' => left * right,
+                _ if right == 0.0 => return Err("DIVISION BY ZERO".to_string()),
+                _ => left / right,
+            };
+        }
+        Ok(left)
+    }
+
+    // factor := ('-' | '+') factor | '(' expr ')' | number | cell | name '(' args ')'
+    fn factor(&mut self) -> std::result::Result<f64, String> {
+        let Some(token) = self.peek().cloned() else {
+            return Err("UNEXPECTED END".to_string());
+        };
+        self.pos += 1;
+        match token {
+            FormulaToken::Symbol('-') => Ok(-self.factor()?),
+            FormulaToken::Symbol('+') => self.factor(),
+            FormulaToken::Symbol('(') => {
+                let inner = self.expr()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            FormulaToken::Number(value) => Ok(value),
+            FormulaToken::Name(name) => {
+                if self.at('(') {
+                    self.pos += 1;
+                    let mut values = Vec::new();
+                    if !self.at(')') {
+                        values.extend(self.arg()?);
+                        while self.at(',') {
+                            self.pos += 1;
+                            values.extend(self.arg()?);
                         }
                     }
+                    self.expect(')')?;
+                    return apply_function(&name, &values);
                 }
+                let addr = CellAddress::from_str(&name).ok_or_else(|| format!("UNKNOWN NAME {}", name))?;
+                self.cell_value(&addr)
             }
+            token => Err(format!("UNEXPECTED '{}'", token)),
         }
     }
-    /// Propagates changes through the spreadsheet based on cell dependencies.
-    ///
-    /// This method updates all the cells that depend on a given cell. If a cell's value changes, this method
-    /// ensures that all dependent cells are recalculated. It also checks for circular dependencies and avoids
-    /// infinite loops by tracking cells that are currently being updated.
-    ///
-    /// # Arguments:
-    /// - `cell_addr`: A string representing the address of the cell whose changes need to be propagated.
-    ///
-    /// # Notes:
-    /// - If a circular dependency is detected, an error message is shown, and the operation is undone.
-    /// - This method processes each dependent cell recursively to ensure that the entire dependency chain is handled.
-    fn propagate_changes(&mut self, cell_addr: &str) {
-        // Get all cells that depend on this cell
-        let mut dependents_to_process = Vec::new();
-        
-        // First, collect all the dependents without holding a reference to self
-        if let Some(deps) = self.dependents.get(cell_addr) {
-            for dep in deps {
-                dependents_to_process.push(dep.clone());
-            }
-        } else {
-            return;
+
+    // arg := cell ':' cell | expr
+    fn arg(&mut self) -> std::result::Result<Vec<f64>, String> {
+        if let [FormulaToken::Name(first), FormulaToken::Symbol(':'), rest @ ..] = &self.tokens[self.pos..] {
+            let last = match rest.first() {
+                Some(FormulaToken::Name(last)) => last.as_str(),
+                _ => "",
+            };
+            let range = format!("{}:{}", first, last);
+            let (from, to) = self.sheet.parse_range(&range).ok_or_else(|| format!("INVALID RANGE {}", range))?;
+            self.pos += 3;
+            return self.range_values(&from, &to);
         }
-        println!("DEBUG: Dependents to process: {:?}", dependents_to_process);
-        // Now process each dependent
-        for dependent in dependents_to_process {
-            // Check if the dependent is already being updated to avoid circular dependencies
-            if self.currently_updating.contains(&dependent) {
-                self.status_message = format!("ERROR: CIRCULAR DEPENDENCY DETECTED WITH {}", dependent);
-                println!("DEBUG: Undo stack: {:?}", self.undo_stack);
-                self.undo();
-                self.status_message = format!("ERROR: CIRCULAR DEPENDENCY DETECTED WITH {}", dependent);
-                return;
+        Ok(vec![self.expr()?])
+    }
+
+    /// Returns the number in a cell; an empty cell counts as `0`.
+    fn cell_value(&self, addr: &CellAddress) -> std::result::Result<f64, String> {
+        let cell = self.sheet.get_cell(addr).ok_or_else(|| format!("CELL {} OUTSIDE THE SHEET", addr))?;
+        if cell.error.is_some() {
+            return Err(format!("{} HAS AN ERROR", addr));
+        }
+        if cell.display_value.is_empty() {
+            return Ok(0.0);
+        }
+        cell.display_value.parse::<f64>().map_err(|_| format!("{} IS NOT A NUMBER", addr))
+    }
+
+    /// Returns the numbers in a range, skipping text and empty cells like the range formulas do.
+    fn range_values(&self, start: &CellAddress, end: &CellAddress) -> std::result::Result<Vec<f64>, String> {
+        for addr in [start, end] {
+            if self.sheet.get_cell(addr).is_none() {
+                return Err(format!("CELL {} OUTSIDE THE SHEET", addr));
             }
-            let formula_opt = if let Some(cell) = self.data.get(&dependent) {
-                cell.formula.clone()
-            } else {
-                None
-            };
-            if let Some(formula) = formula_opt {
-                let formula_with_eq = format!("={}", formula);
-                
-                if let Some(addr) = CellAddress::from_str(&dependent) {
-                    // Update the cell with its formula to recalculate
-                    self.update_cell(&addr, &formula_with_eq, true);
+        }
+        for row in start.row.min(end.row)..=start.row.max(end.row) {
+            for col in start.col.min(end.col)..=start.col.max(end.col) {
+                let addr = CellAddress::new(col, row);
+                if self.sheet.get_cell(&addr).is_some_and(|cell| cell.error.is_some()) {
+                    return Err(format!("{} HAS AN ERROR", addr));
                 }
             }
         }
+        Ok(self.sheet.range_numbers(start, end))
     }
-    /// Updates a cell's value in the spreadsheet, recalculates it if necessary, and propagates changes
-/// to dependent cells. This function supports both simple values and complex formulas (such as 
-/// `SUM`, `MIN`, `MAX`, `sqrt`, and `log`). It also checks for circular dependencies and invalid 
-/// formulas, ensuring that the integrity of the spreadsheet is maintained.
+
+    fn expect(&mut self, c: char) -> std::result::Result<(), String> {
+        if self.at(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("EXPECTED '{}'", c))
+        }
+    }
+}
+
+/// Applies a formula function to its arguments, with ranges already expanded into their numbers.
+fn apply_function(name: &str, values: &[f64]) -> std::result::Result<f64, String> {
+    match (name, values) {
+        ("SUM", _) => Ok(values.iter().sum()),
+        ("MIN" | "MAX" | "STDEV", []) => Err(format!("NO NUMBERS FOR {}", name)),
+        ("MIN", _) => Ok(values.iter().copied().fold(f64::INFINITY, f64::min)),
+        ("MAX", _) => Ok(values.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+        ("STDEV", _) => {
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            Ok((values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64).sqrt())
+        }
+        ("SQRT", [value]) => Ok(value.sqrt()),
+        ("LOG", [value]) => Ok(value.ln()),
+        ("SQRT" | "LOG", _) => Err(format!("{} TAKES ONE ARGUMENT", name.to_lowercase())),
+        _ => Err(format!("UNSUPPORTED FUNCTION {}", name)),
+    }
+}
+
+/// Computes an expression against the current values of a sheet without entering it into a cell,
+/// e.g. `evaluate(&sheet, "SUM(A1:B2)*2")`. Unlike a cell formula, the expression can nest freely:
+/// `+ - * /` with the usual precedence, parentheses, numbers, cell references and the `SUM`, `MIN`,
+/// `MAX`, `STDEV` (of ranges or values), `sqrt` and `log` functions.
 ///
 /// # Arguments
-///
-/// * `addr` - A reference to the `CellAddress` of the cell to be updated. This indicates which 
-///   cell in the spreadsheet should be modified.
-/// * `value` - A string representing the new value or formula for the cell. If the value starts 
-///   with `=`, it is considered a formula; otherwise, it's treated as a constant value.
-/// * `multi` - A boolean flag indicating whether this update is part of a multi-cell operation. 
-///   If `multi` is `false`, the function will push the current state to the undo stack to allow 
-///   for future undo operations. If `multi` is `true`, undo history will not be updated.
+/// * `sheet` - The sheet whose cells are referenced.
+/// * `expression` - The expression, with or without a leading `=`.
 ///
 /// # Returns
+/// `Ok(value)`, or `Err(reason)` if the expression is malformed, refers to a cell outside the sheet,
+/// to text or to a cell with an error, or has no finite result.
+pub fn evaluate(sheet: &Spreadsheet, expression: &str) -> std::result::Result<f64, String> {
+    let tokens = tokenize_formula(expression.trim().trim_start_matches('='))?;
+    let mut evaluator = ExprEvaluator { sheet, tokens, pos: 0 };
+    let value = evaluator.expr()?;
+    if let Some(token) = evaluator.peek() {
+        return Err(format!("UNEXPECTED '{}'", token));
+    }
+    if !value.is_finite() {
+        return Err(if value.is_nan() { "UNDEFINED RESULT" } else { "INFINITE RESULT" }.to_string());
+    }
+    Ok(value)
+}
+
+/// Reads a script line of the form `A1=5` into the cell and the input to enter there: numbers are
+/// entered as values, text in double quotes as text, and anything else as a formula
+/// (`B1=SUM(A1:A3)` enters `=SUM(A1:A3)`).
+fn parse_assignment(line: &str) -> Option<(CellAddress, String)> {
+    let (target, input) = line.split_once('=')?;
+    let addr = CellAddress::from_str(target.trim())?;
+    let input = input.trim();
+    let value = if input.parse::<f64>().is_ok() {
+        input.to_string()
+    } else if let Some(text) = input.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+        text.to_string()
+    } else {
+        format!("={}", input)
+    };
+    Some((addr, value))
+}
+
+/// Returns whether a status message reports a failed command rather than its result.
+fn is_error_message(message: &str) -> bool {
+    message.contains("ERROR") || ["INVALID", "USAGE", "CANNOT", "UNSUPPORTED"].iter().any(|prefix| message.starts_with(prefix))
+}
+
+/// Quotes a CSV field if it contains the delimiter, a quote or a line break, doubling inner quotes.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Returns whether every cell reference in a formula lies inside the range `start..=end`.
+fn refs_within(formula: &str, start: &CellAddress, end: &CellAddress) -> bool {
+    let refs = regex::Regex::new(r"[A-Z]+[0-9]+").unwrap();
+    refs.find_iter(formula).filter_map(|m| CellAddress::from_str(m.as_str())).all(|addr| {
+        (start.col..=end.col).contains(&addr.col) && (start.row..=end.row).contains(&addr.row)
+    })
+}
+
+/// Writes a save file as pretty JSON or, if `binary` is set, as MessagePack, compressed if the
+/// path ends in `.gz` or `.zst`.
+fn write_save(path: &Path, saved: &SaveFileRef, binary: bool) -> io::Result<()> {
+    let mut writer = SaveWriter::create(path, Compression::for_path(path), false)?;
+    if binary {
+        rmp_serde::encode::write_named(&mut writer, saved).map_err(|e| io::Error::other(e.to_string()))?;
+    } else {
+        serde_json::to_writer_pretty(&mut writer, saved)?;
+    }
+    writer.finish()
+}
+
+/// The compression of a save file, chosen from its extension.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+/// Returns `Gzip` for paths ending in `.gz`, `Zstd` for `.zst` and `None` otherwise.
+    fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_ascii_lowercase()).as_deref() {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// A save file being written, compressing on the fly. `finish` must be called to complete the
+/// compressed stream; dropping the writer may leave a truncated file.
+enum SaveWriter {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<File>>),
+}
+
+impl SaveWriter {
+/// Creates the file at `path`, or appends to it if `append` is set. Appending to a compressed file
+/// adds a new gzip member or zstd frame, which `open_save` reads back as one continuous stream.
+    fn create(path: &Path, compression: Compression, append: bool) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(path)?;
+        let file = BufWriter::new(file);
+        Ok(match compression {
+            Compression::None => SaveWriter::Plain(file),
+            Compression::Gzip => SaveWriter::Gzip(flate2::write::GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => SaveWriter::Zstd(zstd::stream::write::Encoder::new(file, 0)?),
+        })
+    }
+
+/// Completes the compressed stream and flushes the file.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            SaveWriter::Plain(mut file) => file.flush(),
+            SaveWriter::Gzip(encoder) => encoder.finish()?.flush(),
+            SaveWriter::Zstd(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for SaveWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SaveWriter::Plain(file) => file.write(buf),
+            SaveWriter::Gzip(encoder) => encoder.write(buf),
+            SaveWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SaveWriter::Plain(file) => file.flush(),
+            SaveWriter::Gzip(encoder) => encoder.flush(),
+            SaveWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Opens a save file for reading, decompressing it if the path ends in `.gz` or `.zst`.
+fn open_save(path: &Path) -> io::Result<Box<dyn io::Read>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(match Compression::for_path(path) {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(file)?),
+    })
+}
+
+/// Splits one CSV line into fields, honouring double-quoted fields with `""` escapes.
+fn split_csv_line(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            c if c == delimiter && !in_quotes => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+// Cell struct to store data and metadata
+
+/// The kind of chart drawn by `:chart`: filled bars, or a line tracing the top of each bar.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChartKind {
+    Bar,
+    Line,
+}
+/// Represents the alignment of text within a cell.
 ///
-/// Returns `true` if the cell was updated successfully, and `false` if an error occurred (e.g., 
-/// invalid formula, circular dependency, or locked cell).
-///
-/// # Error Handling
+/// The `Alignment` enum defines the available text alignments for a cell:
+/// - `Left`: Aligns text to the left side of the cell.
+/// - `Right`: Aligns text to the right side of the cell.
+/// - `Center`: Centers the text in the middle of the cell.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Alignment {
+    Left,
+    Right,
+    Center,
+}
+/// Represents different modes the spreadsheet can be in.
 ///
-/// This function performs several checks and sets the `status_message` with an appropriate error 
-/// message if any of the following conditions are met:
-/// 
-/// - The cell doesn't exist (`ERROR: CELL {addr} NOT FOUND`)
-/// - The cell is locked (`ERROR: CELL {addr} LOCKED`)
-/// - A circular dependency is detected (`ERROR: CIRCULAR DEPENDENCY DETECTED EARLY WITH {addr}`)
-/// - An invalid formula is provided, such as an incorrectly formatted range (`ERROR: INVALID RANGE {range}`)
-/// - An invalid arithmetic expression (`ERROR: INVALID ARITHMETIC EXPRESSION {expression}`)
-/// - An invalid function argument (`ERROR: INVALID ARGUMENT {function}`)
-/// - A general invalid formula error (`ERROR: INVALID FORMULA {value}`)
-    fn update_cell(&mut self, addr: &CellAddress, value: &str, multi:bool) -> bool {
-        // First, check if cell exists and if it's locked
-        let cell_exists = self.get_cell(addr).is_some();
-        let is_locked = self.get_cell(addr).map_or(false, |cell| cell.is_locked);
-        
-        if !cell_exists {
-            self.status_message = format!("ERROR: CELL {} NOT FOUND", addr.to_string());
-            return false;
+/// The `Mode` enum defines the available modes for the spreadsheet editor:
+/// - `Normal`: Default mode for interacting with the spreadsheet.
+/// - `Insert`: Mode for inserting new data or formulas into cells.
+/// - `Command`: Mode for executing commands.
+/// - `Find`: Mode for searching within the spreadsheet.
+/// - `Replace`: Mode for confirming each replacement of a `:replace ... -c` command.
+/// - `Help`: Mode for reading the `:help` screen.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Command,
+    Find,
+    Replace,
+    Help,
+}
+/// Represents what a search looks at in each cell.
+///
+/// The `SearchScope` enum defines the parts of a cell `find` can match against:
+/// - `Values`: The displayed value of the cell (default).
+/// - `Formulas`: The raw input of the cell, i.e. the formula with its leading `=` or the plain value.
+/// - `Addresses`: The cell's address (e.g., "B7").
+#[derive(Clone, Debug, PartialEq)]
+enum SearchScope {
+    Values,
+    Formulas,
+    Addresses,
+}
+
+impl SearchScope {
+    /// Returns the scope that follows this one when cycling with `t` in Find mode.
+    fn next(&self) -> Self {
+        match self {
+            SearchScope::Values => SearchScope::Formulas,
+            SearchScope::Formulas => SearchScope::Addresses,
+            SearchScope::Addresses => SearchScope::Values,
         }
-        
-        if is_locked {
-            self.status_message = format!("ERROR: CELL {} LOCKED", addr.to_string());
-            return false;
+    }
+    /// Returns the name of the scope as shown in status messages.
+    fn name(&self) -> &'static str {
+        match self {
+            SearchScope::Values => "VALUES",
+            SearchScope::Formulas => "FORMULAS",
+            SearchScope::Addresses => "ADDRESSES",
         }
+    }
+}
 
-        let cell_addr_str = addr.to_string();
-        println!("DEBUG: Updating cell {} with value {}", cell_addr_str, value);
-        println!("DEBUG: Currently updating: {:?}", self.currently_updating);
-        // Check for circular dependency
-        if self.currently_updating.contains(&cell_addr_str) {
-            self.status_message = format!("ERROR: CIRCULAR DEPENDENCY DETECTED EARLY WITH {}", cell_addr_str);
-            return false;
+// Represents an undo action in the spreadsheet, storing the state of a cell before an edit.
+///
+/// The `UndoAction` struct holds information about a cell's address and its previous state (the `old_cell`),
+/// allowing for the undoing of a specific change made to a cell. This can be useful for implementing 
+/// undo functionality in the spreadsheet editor.
+///
+/// # Fields:
+/// - `cell_address`: The address of the cell that was modified.
+/// - `old_cell`: The previous state of the cell before the edit was made, including its value, formula, and other properties.
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct UndoAction {
+    cell_address: CellAddress,
+    old_cell: Cell,
+}
+
+/// A callback registered with `Spreadsheet::on_change`, given a cell's address, old value and new value.
+type ChangeHook = Box<dyn FnMut(&CellAddress, &str, &str)>;
+
+/// One change of a cell's input in the audit log, shown by `:history` and written to the file opened
+/// with `:audit` as a line of JSON.
+///
+/// # Fields:
+/// - `time`: When the change was made, in local time (`YYYY-MM-DD HH:MM:SS`).
+/// - `cell`: The address of the changed cell, e.g. `"B3"`.
+/// - `old`: The cell's input before the change, empty if the cell was empty.
+/// - `new`: The cell's input after the change, empty if the cell was cleared.
+/// - `author`: Who made the change: the client of the shared session or the dashboard it came from;
+///   `None` for edits made in the editor while not sharing the sheet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: String,
+    pub cell: String,
+    pub old: String,
+    pub new: String,
+    #[serde(default)]
+    pub author: Option<String>,
+}
+
+/// A comparison operator used by rules that test a cell's numeric value against a threshold.
+///
+/// The `Comparison` enum supports the usual relational operators:
+/// - `Greater` (`>`), `GreaterEq` (`>=`), `Less` (`<`), `LessEq` (`<=`), `Equal` (`==`) and `NotEqual` (`!=`).
+#[derive(Clone, Debug, PartialEq)]
+enum Comparison {
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    /// Parses an operator string such as `">"` or `"<="` into a `Comparison`.
+    ///
+    /// # Arguments:
+    /// - `op`: The operator as typed by the user.
+    ///
+    /// # Returns:
+    /// `Some(Comparison)` for a known operator, or `None` otherwise.
+    fn from_str(op: &str) -> Option<Self> {
+        match op {
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterEq),
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessEq),
+            "==" | "=" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            _ => None,
         }
-        
-        // Mark this cell as being updated
-        self.currently_updating.insert(cell_addr_str.clone());
-        if let Some(_old_cell) = self.get_cell(addr).cloned() {
+    }
+    /// Evaluates `lhs <op> rhs`.
+    fn holds(&self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            Comparison::Greater => lhs > rhs,
+            Comparison::GreaterEq => lhs >= rhs,
+            Comparison::Less => lhs < rhs,
+            Comparison::LessEq => lhs <= rhs,
+            Comparison::Equal => lhs == rhs,
+            Comparison::NotEqual => lhs != rhs,
+        }
+    }
+    /// Returns the operator symbol, used when listing rules.
+    fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Greater => ">",
+            Comparison::GreaterEq => ">=",
+            Comparison::Less => "<",
+            Comparison::LessEq => "<=",
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+        }
+    }
+}
 
-            let is_valid_formula: bool;
-            if value.starts_with("=") {
-                // Validate formula
-                let formula = &value[1..];
-                is_valid_formula = if formula.starts_with("SUM(") || formula.starts_with("MIN(") || formula.starts_with("MAX(") || formula.starts_with("STDEV(") {
-                    if let Some(range_str) = formula.strip_prefix("SUM(").or_else(|| formula.strip_prefix("MIN("))
-                        .or_else(|| formula.strip_prefix("MAX(")).or_else(|| formula.strip_prefix("STDEV("))
-                        .and_then(|s| s.strip_suffix(')')) {
-                        if let Some((start, end)) = self.parse_range(range_str) {
-                            
-                            let start_exists = self.get_cell(&start).is_some();
-                            // println!("Debug: Start cell {} exists: {}", start.to_string(), start_exists);
-                            let end_exists = self.get_cell(&end).is_some();
-                            if !(start_exists && end_exists) {
-                                self.status_message = format!("ERROR: INVALID RANGE {}", range_str);
-                            }
-                            start_exists && end_exists
-                        } else {
-                            self.status_message = format!("ERROR: INVALID RANGE {}", range_str);
+/// The pseudo-cell an alert rule can test instead of a real cell: the number of cells holding an
+/// error value (e.g. `#TIMEOUT!`).
+const ERROR_COUNT_SUBJECT: &str = "ERRORS";
 
-                            false
-                        }
-                    } else {
-                        self.status_message = format!("ERROR: INVALID RANGE {}", formula);
-                        false
-                    }
-                } else if formula.starts_with("sqrt(") || formula.starts_with("log(") {
-                    if let Some(arg) = formula.strip_prefix("sqrt(").or_else(|| formula.strip_prefix("log("))
-                        .and_then(|s| s.strip_suffix(')')) {
-                        CellAddress::from_str(arg).map_or(false, |addr| self.get_cell(&addr).is_some()) || arg.parse::<f64>().is_ok()
-                    } else {
-                        self.status_message = format!("ERROR: INVALID ARGUMENT {}", formula);
-                        false
-                    }
-                } 
-                else if formula.starts_with("(") && formula.ends_with(")") {
-                    let cell_ref = &formula[1..formula.len() - 1];
-                    if let Some(addr) = CellAddress::from_str(cell_ref) {
-                        self.get_cell(&addr).is_some()
-                    }
-                    else if cell_ref.contains('+') || cell_ref.contains('-') || cell_ref.contains('*') {
-                        // Arithmetic expression like =(A1+B1)
-                        let re = regex::Regex::new(r"([+\-*])").unwrap();
-                        let parts: Vec<&str> = re.split(cell_ref).collect();
-                        
-                        // Check if all parts are valid (either cell references or numbers)
-                        let all_valid = parts.iter().all(|part| {
-                            let trimmed = part.trim();
-                            if trimmed.is_empty() {
-                                return false;
-                            }
-                            
-                            // Check if it's a valid cell reference
-                            if let Some(addr) = CellAddress::from_str(trimmed) {
-                                self.get_cell(&addr).is_some()
-                            } else {
-                                // Check if it's a valid number
-                                trimmed.parse::<f64>().is_ok()
-                            }
-                        });
-                        
-                        if !all_valid {
-                            self.status_message = format!("ERROR: INVALID ARITHMETIC EXPRESSION {}", cell_ref);
-                            false
-                        } else {
-                            true
-                        }
-                    } else {
-                        self.status_message = format!("ERROR: INVALID CELL REFERENCE {}", cell_ref);
-                        false
-                    }
-        
-                }
-                
-                else {
-                    self.status_message = format!("ERROR: INVALID FORMULA {}", value);
-                    false
-                };
-            }
-            else {
-                if !multi{
-                    println!("DEBUG: Pushing undo for cell {}", addr.to_string());
-                    self.push_undo_sheet();
-                    self.redo_stack.clear(); 
-                }
-                // self.push_undo_sheet();
-                // self.redo_stack.clear(); 
+/// What happens while an alert rule's condition holds.
+///
+/// - `Notify`: Show `message` as a highlighted notice when the condition becomes true, ringing the bell if `bell` is set.
+/// - `Haunt`: Add `weight` to the haunt intensity for as long as the condition holds.
+#[derive(Clone, Debug)]
+enum RuleAction {
+    Notify { message: String, bell: bool },
+    Haunt { weight: u8 },
+}
 
-                self.update_dependencies(&addr.to_string(), value);
+/// An alert rule attached to a cell, checked after every recalculation pass.
+///
+/// # Fields:
+/// - `cell`: The address of the watched cell (e.g., "B14"), or `ERRORS` for the number of error cells.
+/// - `comparison`: The operator used to compare the cell's value to `threshold`.
+/// - `threshold`: The value the cell is compared against.
+/// - `action`: What the rule does when its condition holds.
+/// - `triggered`: Whether the condition held after the previous pass, so an alert only fires when it becomes true.
+#[derive(Clone, Debug)]
+struct AlertRule {
+    cell: String,
+    comparison: Comparison,
+    threshold: f64,
+    action: RuleAction,
+    triggered: bool,
+}
 
-                if let Some(cell) = self.get_cell_mut(addr) {
-                    cell.formula = None;
-                    cell.raw_value = value.to_string();
-                    cell.display_value = value.to_string();
-                }
-                println!("DEBUG: propagating starting on {}", addr.to_string());
+/// State of an in-progress confirm-each replacement.
+///
+/// # Fields:
+/// - `from`: The text being replaced.
+/// - `to`: The replacement text.
+/// - `include_formulas`: Whether the replacement also applies inside formulas.
+/// - `matches`: The cells that contained `from` when the command was issued.
+/// - `index`: The match currently awaiting confirmation.
+/// - `replaced`: The number of cells replaced so far.
+/// - `action_open`: Whether the undo action for this replacement session has been started.
+#[derive(Clone, Debug)]
+struct ReplaceState {
+    from: String,
+    to: String,
+    include_formulas: bool,
+    matches: Vec<CellAddress>,
+    index: usize,
+    replaced: usize,
+    action_open: bool,
+}
 
-                self.propagate_changes(&addr.to_string());
-                self.currently_updating.remove(&cell_addr_str);
-        println!("DEBUG: Finished updating cell {}", cell_addr_str);
-                return true;
-            }
-            if is_valid_formula {
-                // Save the old cell for undo (clone it before modifying)
-                if !multi{
-                    println!("DEBUG: Pushing undo for cell {}", addr.to_string());
-                    self.push_undo_sheet();
-                    self.redo_stack.clear(); 
-                }
+/// State of Tab completion in Command mode, kept so repeated Tab presses cycle through the candidates.
+///
+/// # Fields:
+/// - `stem`: The part of the command buffer before the completed word.
+/// - `candidates`: The possible completions of the word.
+/// - `index`: The candidate currently shown in the command buffer.
+#[derive(Clone, Debug)]
+struct Completion {
+    stem: String,
+    candidates: Vec<String>,
+    index: usize,
+}
 
-                let formula = &value[1..];
-                // self.remove_dependencies(&addr.to_string());
-                println!("DEBUG: Updating dependencies for cell {}", addr.to_string());
-                self.update_dependencies(&addr.to_string(), value);
-                // Compute the formula result
-                let result = if formula.starts_with("SUM(") {
-                    let range_str = formula.strip_prefix("SUM(").unwrap().strip_suffix(')').unwrap();
-                    if let Some((start, end)) = self.parse_range(range_str) {
-                        let mut sum = 0.0;
-                        for col in start.col..=end.col {
-                            for row in start.row..=end.row {
-                                let addr = CellAddress::new(col, row);
-                                if let Some(cell) = self.get_cell(&addr) {
-                                    if let Ok(value) = cell.display_value.parse::<f64>() {
-                                        sum += value;
-                                    }
-                                }
+/// The contents of a copy/paste register: a rectangular block of cells in row-major order.
+///
+/// # Fields:
+/// - `source`: The range the cells were yanked from (e.g., "A1:B3"), shown by `:registers`.
+/// - `rows`: The number of rows in the block.
+/// - `cols`: The number of columns in the block.
+/// - `cells`: The yanked cells, `rows * cols` of them.
+#[derive(Clone, Debug)]
+struct Register {
+    source: String,
+    rows: usize,
+    cols: usize,
+    cells: Vec<Cell>,
+}
+
+/// What `draw` last put on the terminal, kept so the next frame only rewrites what changed.
+///
+/// # Fields:
+/// - `size`: The terminal size, as columns and rows, the frame was drawn for.
+/// - `lines`: The output of each line of the grid, headers and panels below it, from the top row.
+/// - `overlay`: The output drawn over the lines: the chart, the alert, the demo overlay and the command buffer.
+/// - `status`: The output of the status bar on the bottom row.
+struct DrawnFrame {
+    size: (u16, u16),
+    lines: Vec<Vec<u8>>,
+    overlay: Vec<u8>,
+    status: Vec<u8>,
+}
+
+/// Returns the terminal rows a new frame has to rewrite: the rows whose output differs from the last
+/// frame, or every row if there is no last frame to compare with. Rows missing from a frame are blank,
+/// and the bottom row is left out as it belongs to the status bar.
+///
+/// # Arguments
+///
+/// * `last` - The output of each row of the last frame, if it can be kept.
+/// * `next` - The output of each row of the new frame.
+/// * `height` - The number of terminal rows.
+pub fn changed_lines(last: Option<&[Vec<u8>]>, next: &[Vec<u8>], height: u16) -> Vec<usize> {
+    (0..height.saturating_sub(1) as usize)
+        .filter(|&row| last.is_none_or(|last| last.get(row) != next.get(row)))
+        .collect()
+}
+
+/// Options of the PDF export, given after the file name of `saveas_pdf`.
+///
+/// # Fields:
+/// - `landscape`: Whether to print on landscape A4 pages.
+/// - `title`: A title printed at the top of every page.
+/// - `font_size`: The font size of the table, in points.
+/// - `wrap`: Whether long values wrap onto extra lines instead of being cut off.
+#[derive(Clone, Debug)]
+struct PdfOptions {
+    landscape: bool,
+    title: Option<String>,
+    font_size: f32,
+    wrap: bool,
+}
+
+impl PdfOptions {
+    /// Splits the options off the end of a `saveas_pdf` argument, returning the file name and the options.
+    fn parse(arg: &str) -> (&str, PdfOptions) {
+        let mut options = PdfOptions { landscape: false, title: None, font_size: 10.0, wrap: false };
+        let mut rest = arg.trim();
+        loop {
+            if let Some(start) = rest.strip_suffix('"').and_then(|r| r.rfind(" title=\"")) {
+                options.title = Some(rest[start + 8..rest.len() - 1].to_string());
+                rest = rest[..start].trim_end();
+                continue;
+            }
+            let Some((head, last)) = rest.rsplit_once(' ') else {
+                break;
+            };
+            match last {
+                "landscape" => options.landscape = true,
+                "wrap" => options.wrap = true,
+                size if size.starts_with("size=") => match size[5..].parse::<f32>() {
+                    Ok(points) => options.font_size = points.clamp(6.0, 24.0),
+                    Err(_) => break,
+                },
+                _ => break,
+            }
+            rest = head.trim_end();
+        }
+        (rest, options)
+    }
+}
+
+/// The register used by `y`/`p` when no `"<reg>` prefix is given.
+const UNNAMED_REGISTER: char = '"';
+
+/// A cell-mutating action that `.` can repeat at the cursor.
+///
+/// The `Change` enum records the last edit in a cursor-relative form:
+/// - `Insert`: A value or formula typed in Insert mode.
+/// - `MultiInsert`: A `:mi` over a range of `rows` x `cols` cells, repeated with the cursor as top-left corner.
+/// - `Align`: An alignment change (`"l"`, `"r"` or `"c"`).
+/// - `Dimension`: A height and/or width change.
+/// - `Lock` / `Unlock`: Locking or unlocking a cell.
+#[derive(Clone, Debug, PartialEq)]
+enum Change {
+    Insert(String),
+    MultiInsert { rows: usize, cols: usize, value: String },
+    Align(String),
+    Dimension(Option<usize>, Option<usize>),
+    Lock,
+    Unlock,
+}
+
+/// Represents a collection of cell changes in a single action that can be undone or redone.
+///
+/// The `SheetAction` struct groups the `UndoAction` instances for every cell touched by one user
+/// operation (including recalculated dependents), so only the changed cells are stored rather than a
+/// copy of the whole sheet.
+///
+/// # Fields:
+/// - `cells`: The state of each changed cell before the action, at most one entry per cell.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct SheetAction {
+    cells: Vec<UndoAction>,  // Collection of all cell changes in this action
+}
+
+
+/// Sheet names reserved for the subcommands of `sheet`.
+const SHEET_SUBCOMMANDS: &[&str] = &["new", "rename", "copy", "delete", "next", "prev", "list"];
+
+/// One sheet of the workbook. The active sheet lives in the `Spreadsheet` fields themselves; its
+/// slot in `Spreadsheet::sheets` only keeps its name, and switching sheets swaps the state in and out.
+///
+/// # Fields:
+/// - `name`: The name shown on the sheet's tab.
+/// - `data`, `dependents`, `dependencies`: The cells and dependency graph of the sheet.
+/// - `undo_stack`, `redo_stack`: The sheet's own undo and redo history.
+/// - `cursor`: The selected cell when the sheet was last active.
+/// - `start_row`, `start_col`, `followed_cursor`: Where the sheet was scrolled to when it was last active.
+/// - `max_rows`, `max_cols`: The size of the sheet.
+/// - `watch_list`: The cells of this sheet pinned to the watch panel.
+/// - `frozen_rows`, `frozen_cols`: The panes frozen with `freeze` on this sheet.
+/// - `protection`: The ranges of this sheet guarded with `protect`.
+/// - `pivots`: The pivot tables written into this sheet.
+/// - `row_groups`: The row groups of this sheet and whether they are collapsed.
+struct SheetState {
+    name: String,
+    data: HashMap<String, Cell>,
+    dependents: HashMap<String, HashSet<String>>,
+    dependencies: HashMap<String, HashSet<String>>,
+    undo_stack: VecDeque<SheetAction>,
+    redo_stack: VecDeque<SheetAction>,
+    cursor: CellAddress,
+    start_row: usize,
+    start_col: usize,
+    followed_cursor: Option<(usize, usize)>,
+    max_rows: usize,
+    max_cols: usize,
+    watch_list: Vec<String>,
+    frozen_rows: usize,
+    frozen_cols: usize,
+    protection: Protection,
+    pivots: Vec<Pivot>,
+    row_groups: Vec<RowGroup>,
+}
+
+impl SheetState {
+    /// Creates an empty sheet of a `rows` x `cols` grid; no cell is stored until it is edited.
+    fn blank(name: &str, rows: usize, cols: usize) -> Self {
+        SheetState {
+            name: name.to_string(),
+            data: HashMap::new(),
+            dependents: HashMap::new(),
+            dependencies: HashMap::new(),
+            undo_stack: VecDeque::new(),
+            redo_stack: VecDeque::new(),
+            cursor: CellAddress::new(0, 0),
+            start_row: 0,
+            start_col: 0,
+            followed_cursor: None,
+            max_rows: rows,
+            max_cols: cols,
+            watch_list: Vec::new(),
+            frozen_rows: 0,
+            frozen_cols: 0,
+            protection: Protection::default(),
+            pivots: Vec::new(),
+            row_groups: Vec::new(),
+        }
+    }
+}
+
+/// The parts of a sheet guarded against edits, sorts and fills with `protect`, saved with the sheet.
+///
+/// # Fields:
+/// - `sheet`: Whether the whole sheet is protected.
+/// - `ranges`: The protected ranges, e.g. `A1:D100`, or single cells.
+/// - `password`: The salt and SHA-256 hash (`<salt>$<hash>`, in hex) of the password needed to
+///   `unprotect`, if one was given.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Protection {
+    #[serde(default)]
+    sheet: bool,
+    #[serde(default)]
+    ranges: Vec<String>,
+    #[serde(default)]
+    password: Option<String>,
+}
+
+impl Protection {
+    /// Returns whether nothing is protected.
+    fn is_empty(&self) -> bool {
+        !self.sheet && self.ranges.is_empty()
+    }
+
+    /// Returns the protected area the rectangle `start..=end` reaches into, as `SHEET` or `RANGE A1:D100`.
+    fn covering(&self, start: &CellAddress, end: &CellAddress) -> Option<String> {
+        if self.sheet {
+            return Some("SHEET".to_string());
+        }
+        self.ranges.iter().find(|range| {
+            let (from, to) = range.split_once(':').unwrap_or((range, range));
+            let (Some(from), Some(to)) = (CellAddress::from_str(from), CellAddress::from_str(to)) else {
+                return false;
+            };
+            from.col <= end.col && start.col <= to.col && from.row <= end.row && start.row <= to.row
+        }).map(|range| format!("RANGE {}", range))
+    }
+
+    /// Stores a new password as a random salt and the hash of the salted password.
+    fn set_password(&mut self, password: &str) {
+        let salt: String = (0..16).map(|_| format!("{:02x}", rand::random::<u8>())).collect();
+        self.password = Some(format!("{}${}", salt, password_hash(&salt, password)));
+    }
+
+    /// Returns whether `password` unlocks the protection; anything does if no password was set.
+    fn accepts(&self, password: Option<&str>) -> bool {
+        match (self.password.as_deref().and_then(|stored| stored.split_once('$')), password) {
+            (None, _) => true,
+            (Some((salt, hash)), Some(password)) => password_hash(salt, password) == hash,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// A block of rows grouped with `group`. A collapsed group is folded out of the grid, leaving the rows
+/// around it (such as a subtotal row below) in view.
+///
+/// # Fields:
+/// - `first`, `last`: The first and last row of the group (zero-based, inclusive).
+/// - `collapsed`: Whether the rows are folded away.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct RowGroup {
+    first: usize,
+    last: usize,
+    #[serde(default)]
+    collapsed: bool,
+}
+
+impl RowGroup {
+    /// Returns whether the group holds `row`.
+    fn contains(&self, row: usize) -> bool {
+        (self.first..=self.last).contains(&row)
+    }
+
+    /// Returns the rows of the group as they are written in commands, e.g. `10:40`.
+    fn describe(&self) -> String {
+        format!("{}:{}", self.first + 1, self.last + 1)
+    }
+}
+
+/// The hex SHA-256 hash of a password prefixed with its salt.
+fn password_hash(salt: &str, password: &str) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(format!("{}{}", salt, password)).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The contents of a save file, at the current `SAVE_FORMAT_VERSION`.
+///
+/// Saves made with `saveas_json <file> -u` also store the undo and redo history so editing can continue
+/// where it left off; plain saves leave both empty. Older files are upgraded by `read_save_file`.
+/// Only cells that are not blank are saved, so the size of the sheet is stored as `rows` and `cols`;
+/// files without them get the smallest size holding every saved cell. The parts guarded with `protect`
+/// are saved as `protection`, with the password hash if one was set, pivot tables as `pivots` and
+/// row groups as `row_groups`.
+#[derive(Serialize, Deserialize)]
+struct SaveFile {
+    version: u32,
+    #[serde(default)]
+    rows: Option<usize>,
+    #[serde(default)]
+    cols: Option<usize>,
+    cells: HashMap<String, Cell>,
+    #[serde(default)]
+    undo: VecDeque<SheetAction>,
+    #[serde(default)]
+    redo: VecDeque<SheetAction>,
+    #[serde(default)]
+    protection: Option<Protection>,
+    #[serde(default)]
+    pivots: Vec<Pivot>,
+    #[serde(default)]
+    row_groups: Vec<RowGroup>,
+}
+
+/// The borrowed form of `SaveFile` written by `save_json` and `save_bin`; the history is left out
+/// unless it was asked for. The cells of a range export are an owned copy.
+#[derive(Serialize)]
+struct SaveFileRef<'a> {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rows: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cols: Option<usize>,
+    cells: std::borrow::Cow<'a, HashMap<String, Cell>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    undo: Option<&'a VecDeque<SheetAction>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redo: Option<&'a VecDeque<SheetAction>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protection: Option<&'a Protection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pivots: Option<&'a Vec<Pivot>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row_groups: Option<&'a Vec<RowGroup>>,
+}
+
+/// Reads a JSON save file of any known version, upgrading it to the current format.
+///
+/// # Arguments
+///
+/// * `reader` - The source of the JSON document.
+///
+/// # Returns
+///
+/// The upgraded save file, or an error if the JSON is malformed or was written by a newer version.
+fn read_save_file<R: io::Read>(reader: R) -> io::Result<SaveFile> {
+    let text = io::read_to_string(reader)?;
+    // Text saves are told apart by their first line, so they open wherever a JSON save does
+    if text.lines().next().map(str::trim_end) == Some(TEXT_SAVE_HEADER) {
+        return read_text_save(&text);
+    }
+    let value = serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+        format!("INVALID JSON AT LINE {} COLUMN {}: {}", e.line(), e.column(), e)))?;
+    upgrade_save(value, Some(&text))
+}
+
+/// Reads a binary (MessagePack) save file, upgrading it to the current format like `read_save_file`.
+///
+/// # Arguments
+///
+/// * `reader` - The source of the MessagePack document.
+///
+/// # Returns
+///
+/// The upgraded save file, or an error if the data is not a valid save or was written by a newer version.
+fn read_bin_save_file<R: io::Read>(reader: R) -> io::Result<SaveFile> {
+    let value = rmp_serde::from_read(reader)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("INVALID BINARY SAVE: {}", e)))?;
+    upgrade_save(value, None)
+}
+
+/// The first line of a file written by `saveas_text`.
+const TEXT_SAVE_HEADER: &str = "# sheet text save, format 1";
+
+/// The cell fields a text save leaves out: they are rebuilt from the cell's input when it is loaded.
+const TEXT_SAVE_COMPUTED: &[&str] = &["raw_value", "display_value", "formula", "error"];
+
+/// Writes a save file in the text format of `saveas_text`, compressed if the path ends in `.gz` or
+/// `.zst`: the header, a `size <rows>x<cols>` line when the size is known, a `protection <json>` line if
+/// parts of the sheet are protected, a `pivot <json>` line per pivot table and a `group <json>` line per
+/// row group, then one `<address> <fields>`
+/// line per cell in row-major order. The fields are a JSON object of the cell's `input` and of the
+/// styles that differ from a default cell, with sorted keys, so saving the same sheet twice gives the
+/// same file and an edit only changes the lines of the cells it touched.
+fn write_text_save(path: &Path, saved: &SaveFileRef) -> io::Result<()> {
+    let defaults = serde_json::to_value(Cell::default())?;
+    let mut writer = SaveWriter::create(path, Compression::for_path(path), false)?;
+    writeln!(writer, "{}", TEXT_SAVE_HEADER)?;
+    if let (Some(rows), Some(cols)) = (saved.rows, saved.cols) {
+        writeln!(writer, "size {}x{}", rows, cols)?;
+    }
+    if let Some(protection) = saved.protection {
+        writeln!(writer, "protection {}", serde_json::to_string(protection)?)?;
+    }
+    for pivot in saved.pivots.into_iter().flatten() {
+        writeln!(writer, "pivot {}", serde_json::to_string(pivot)?)?;
+    }
+    for group in saved.row_groups.into_iter().flatten() {
+        writeln!(writer, "group {}", serde_json::to_string(group)?)?;
+    }
+    for addr in union_of_addresses(&[&saved.cells]) {
+        let cell = &saved.cells[&addr];
+        let mut fields: std::collections::BTreeMap<String, serde_json::Value> = std::collections::BTreeMap::new();
+        if let serde_json::Value::Object(values) = serde_json::to_value(cell)? {
+            fields.extend(values.into_iter().filter(|(field, value)| {
+                !TEXT_SAVE_COMPUTED.contains(&field.as_str()) && defaults.get(field) != Some(value)
+            }));
+        }
+        let input = cell.input();
+        if !input.is_empty() {
+            fields.insert("input".to_string(), serde_json::json!(input));
+        }
+        writeln!(writer, "{} {}", addr, serde_json::to_string(&fields)?)?;
+    }
+    writer.finish()
+}
+
+/// Reads a save file written by `saveas_text`. Fields a line leaves out take their default, and the
+/// computed values are filled in when the sheet is recalculated after loading.
+///
+/// # Arguments
+///
+/// * `text` - The contents of the file, starting with `TEXT_SAVE_HEADER`.
+///
+/// # Returns
+///
+/// The save file, or an error naming the first line that cannot be read or the cells that are invalid.
+fn read_text_save(text: &str) -> io::Result<SaveFile> {
+    let invalid = |line: usize, e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, format!("LINE {}: {}", line, e));
+    let defaults = serde_json::to_value(Cell::default())?;
+    let mut value = serde_json::json!({ "version": SAVE_FORMAT_VERSION, "cells": {} });
+    let (mut pivots, mut row_groups) = (Vec::new(), Vec::new());
+    for (index, line) in text.lines().enumerate().skip(1) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(size) = line.strip_prefix("size ") {
+            let size = size.split_once('x').and_then(|(rows, cols)| Some((rows.trim().parse::<usize>().ok()?, cols.trim().parse::<usize>().ok()?)));
+            let (rows, cols) = size.ok_or_else(|| invalid(index + 1, &"SIZE MUST BE WRITTEN AS <ROWS>x<COLS>"))?;
+            value["rows"] = serde_json::json!(rows);
+            value["cols"] = serde_json::json!(cols);
+            continue;
+        }
+        if let Some(protection) = line.strip_prefix("protection ") {
+            value["protection"] = serde_json::from_str(protection).map_err(|e| invalid(index + 1, &e))?;
+            continue;
+        }
+        if let Some(pivot) = line.strip_prefix("pivot ") {
+            pivots.push(serde_json::from_str::<serde_json::Value>(pivot).map_err(|e| invalid(index + 1, &e))?);
+            continue;
+        }
+        if let Some(group) = line.strip_prefix("group ") {
+            row_groups.push(serde_json::from_str::<serde_json::Value>(group).map_err(|e| invalid(index + 1, &e))?);
+            continue;
+        }
+        let (addr, fields) = line.split_once(' ').ok_or_else(|| invalid(index + 1, &"EXPECTED <CELL> <FIELDS>"))?;
+        let mut fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(fields).map_err(|e| invalid(index + 1, &e))?;
+        let input = match fields.remove("input") {
+            Some(serde_json::Value::String(input)) => input,
+            Some(_) => return Err(invalid(index + 1, &"input MUST BE TEXT")),
+            None => String::new(),
+        };
+        let mut cell = defaults.clone();
+        for (field, field_value) in fields {
+            cell[field] = field_value;
+        }
+        match input.strip_prefix('=') {
+            Some(formula) => cell["formula"] = serde_json::json!(formula),
+            None => {
+                cell["raw_value"] = serde_json::json!(input);
+                cell["display_value"] = serde_json::json!(input);
+            }
+        }
+        value["cells"][addr] = cell;
+    }
+    value["pivots"] = serde_json::Value::Array(pivots);
+    value["row_groups"] = serde_json::Value::Array(row_groups);
+    upgrade_save(value, None)
+}
+
+/// Returns whether the file at `path` is a save written by `saveas_text`.
+fn is_text_save(path: &Path) -> bool {
+    open_save(path).and_then(|reader| {
+        let mut first = String::new();
+        io::BufRead::read_line(&mut BufReader::new(reader), &mut first)?;
+        Ok(first.trim_end() == TEXT_SAVE_HEADER)
+    }).unwrap_or(false)
+}
+
+/// Runs the `SAVE_MIGRATIONS` a decoded save file needs, checks it with `validate_save` and converts
+/// it into a `SaveFile`.
+///
+/// # Arguments
+///
+/// * `value` - The decoded save file.
+/// * `text` - The JSON text it was read from, used to give line numbers in errors.
+fn upgrade_save(mut value: serde_json::Value, text: Option<&str>) -> io::Result<SaveFile> {
+    if !value.is_object() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "NOT A SAVED SHEET: EXPECTED AN OBJECT OF CELLS"));
+    }
+    let mut version = match value.get("version") {
+        Some(v) => v.as_u64().and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "INVALID SAVE FORMAT VERSION"))?,
+        None if value.get("cells").is_some_and(|c| c.is_object()) => 1,
+        None => 0,
+    };
+    if version > SAVE_FORMAT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("SAVE FORMAT VERSION {} IS NEWER THAN SUPPORTED ({})", version, SAVE_FORMAT_VERSION)));
+    }
+    while version < SAVE_FORMAT_VERSION {
+        SAVE_MIGRATIONS[version as usize](&mut value);
+        version += 1;
+    }
+    value["version"] = serde_json::json!(SAVE_FORMAT_VERSION);
+    let problems = validate_save(&value, text);
+    if !problems.is_empty() {
+        // The status line only has room for the first few
+        let shown = problems.iter().take(3).cloned().collect::<Vec<_>>().join("; ");
+        let more = match problems.len() {
+            n if n > 3 => format!(" (AND {} MORE)", n - 3),
+            _ => String::new(),
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}{}", shown, more)));
+    }
+    serde_json::from_value(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Checks every cell of an upgraded save file: the address must be a valid cell within
+/// `MAX_ROWS`, written the way the sheet writes it (e.g. `B7`), and every field must have the
+/// right type and a sensible value. Cells in the undo and redo history are checked the same way.
+///
+/// # Arguments
+///
+/// * `value` - The save file, already upgraded to the current format.
+/// * `text` - The JSON text it was read from, used to find the line of each problem.
+///
+/// # Returns
+///
+/// One message per problem, such as `"B7 (LINE 40): width MUST BE A POSITIVE INTEGER"`; empty if the file is valid.
+fn validate_save(value: &serde_json::Value, text: Option<&str>) -> Vec<String> {
+    // The line of the first `"key":` in the text, if the file was JSON
+    let line_of = |key: &str| -> String {
+        text.and_then(|text| {
+            let pos = text.find(&format!("\"{}\":", key)).or_else(|| text.find(&format!("\"{}\" :", key)))?;
+            Some(format!(" (LINE {})", text[..pos].matches('\n').count() + 1))
+        }).unwrap_or_default()
+    };
+    let mut problems = Vec::new();
+    let Some(cells) = value.get("cells").and_then(|c| c.as_object()) else {
+        return vec!["cells MUST BE AN OBJECT OF CELLS".to_string()];
+    };
+    for (key, cell) in cells {
+        match CellAddress::from_str(key) {
+            Some(addr) if addr.to_string() != *key => {
+                problems.push(format!("{}{}: ADDRESS MUST BE WRITTEN AS {}", key, line_of(key), addr));
+            }
+            Some(addr) if addr.row >= MAX_ROWS => {
+                problems.push(format!("{}{}: ROW IS BEYOND THE LAST ROW ({})", key, line_of(key), MAX_ROWS));
+            }
+            Some(_) => problems.extend(validate_cell(cell).into_iter().map(|p| format!("{}{}: {}", key, line_of(key), p))),
+            None => problems.push(format!("{}{}: NOT A CELL ADDRESS", key, line_of(key))),
+        }
+    }
+    for stack in ["undo", "redo"] {
+        let Some(actions) = value.get(stack) else {
+            continue;
+        };
+        let Some(actions) = actions.as_array() else {
+            problems.push(format!("{}{}: MUST BE A LIST OF ACTIONS", stack, line_of(stack)));
+            continue;
+        };
+        for (step, action) in actions.iter().enumerate() {
+            let changes = action.get("cells").and_then(|c| c.as_array());
+            let Some(changes) = changes else {
+                problems.push(format!("{} STEP {}: MUST HOLD A LIST OF CELLS", stack, step + 1));
+                continue;
+            };
+            for change in changes {
+                let position = ["col", "row"].map(|field| change.get("cell_address").and_then(|a| a.get(field)).and_then(|v| v.as_u64()));
+                if position.iter().any(Option::is_none) {
+                    problems.push(format!("{} STEP {}: cell_address MUST HOLD col AND row NUMBERS", stack, step + 1));
+                }
+                match change.get("old_cell") {
+                    Some(cell) => problems.extend(validate_cell(cell).into_iter().map(|p| format!("{} STEP {}: {}", stack, step + 1, p))),
+                    None => problems.push(format!("{} STEP {}: MISSING old_cell", stack, step + 1)),
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// One entry appended to a change log by `saveas_log`: the cells that changed since the previous entry.
+///
+/// # Fields:
+/// - `time`: When the entry was saved (RFC 3339, local time).
+/// - `changes`: The new state of every changed cell.
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    time: String,
+    changes: HashMap<String, Cell>,
+}
+
+/// A change log replayed up to some revision.
+///
+/// # Fields:
+/// - `rows`, `cols`: The size of the sheet saved in the snapshot, if it has one.
+/// - `cells`: The sheet as of that revision.
+/// - `times`: The save time of every revision in the log, the snapshot on the first line being revision 1.
+struct ReplayedLog {
+    rows: Option<usize>,
+    cols: Option<usize>,
+    cells: HashMap<String, Cell>,
+    times: Vec<String>,
+}
+
+/// Reads a change log written by `saveas_log`. The first line is a full save (with a `time`), every
+/// further line a `LogEntry`.
+///
+/// # Arguments
+///
+/// * `path` - The path of the change log.
+/// * `revision` - Stop after this revision (1 is the snapshot), or `None` to replay the whole log.
+///
+/// # Returns
+///
+/// The replayed sheet and the times of all revisions, or an error naming the line that could not be read.
+fn replay_change_log(path: &Path, revision: Option<usize>) -> io::Result<ReplayedLog> {
+    let contents = io::read_to_string(open_save(path)?)?;
+    let invalid = |line: usize, e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, format!("CHANGE LOG LINE {}: {}", line, e));
+    let mut lines = contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let (_, first) = lines.next().ok_or_else(|| invalid(1, &"EMPTY CHANGE LOG"))?;
+    let snapshot: serde_json::Value = serde_json::from_str(first).map_err(|e| invalid(1, &e))?;
+    let mut times = vec![snapshot.get("time").and_then(|t| t.as_str()).unwrap_or("").to_string()];
+    let SaveFile { rows, cols, mut cells, .. } = upgrade_save(snapshot, None).map_err(|e| invalid(1, &e))?;
+    for (index, line) in lines {
+        let entry: serde_json::Value = serde_json::from_str(line).map_err(|e| invalid(index + 1, &e))?;
+        let problems: Vec<String> = entry.get("changes").and_then(|c| c.as_object()).map_or_else(
+            || vec!["changes MUST BE AN OBJECT OF CELLS".to_string()],
+            |changes| changes.iter().flat_map(|(addr, cell)| {
+                let addr_problem = CellAddress::from_str(addr).is_none().then(|| format!("{}: NOT A CELL ADDRESS", addr));
+                addr_problem.into_iter().chain(validate_cell(cell).into_iter().map(move |p| format!("{}: {}", addr, p)))
+            }).collect());
+        if let Some(problem) = problems.first() {
+            return Err(invalid(index + 1, problem));
+        }
+        let entry: LogEntry = serde_json::from_value(entry).map_err(|e| invalid(index + 1, &e))?;
+        times.push(entry.time);
+        if revision.is_none_or(|last| times.len() <= last) {
+            cells.extend(entry.changes);
+        }
+    }
+    Ok(ReplayedLog { rows, cols, cells, times })
+}
+
+/// Checks the fields of one serialized cell, returning a message for each problem.
+fn validate_cell(cell: &serde_json::Value) -> Vec<String> {
+    let Some(fields) = cell.as_object() else {
+        return vec!["CELL MUST BE AN OBJECT".to_string()];
+    };
+    let mut problems = Vec::new();
+    for (field, value) in fields {
+        let problem = match field.as_str() {
+            "raw_value" | "display_value" => (!value.is_string()).then_some("MUST BE TEXT"),
+            "formula" => (!value.is_string() && !value.is_null()).then_some("MUST BE TEXT OR null"),
+            "is_locked" => (!value.is_boolean()).then_some("MUST BE true OR false"),
+            "alignment" => (!matches!(value.as_str(), Some("Left" | "Right" | "Center"))).then_some("MUST BE \"Left\", \"Right\" OR \"Center\""),
+            "width" | "height" => value.as_u64().is_none_or(|n| n == 0).then_some("MUST BE A POSITIVE INTEGER"),
+            "format" => match value {
+                serde_json::Value::Null => None,
+                serde_json::Value::String(format) => match format.strip_prefix("date:") {
+                    Some(pattern) if resolve_date_pattern(Some(pattern)).is_some() => None,
+                    Some(_) => Some("HAS AN INVALID DATE PATTERN"),
+                    None => Some("MUST START WITH \"date:\""),
+                },
+                _ => Some("MUST BE TEXT OR null"),
+            },
+            "fg" | "bg" => match value {
+                serde_json::Value::Null => None,
+                serde_json::Value::String(color) if parse_color(color).is_some() => None,
+                _ => Some("MUST BE A COLOR NAME, \"#rrggbb\" OR null"),
+            },
+            "bold" | "italic" | "wrap" => (!value.is_boolean()).then_some("MUST BE true OR false"),
+            "error" => (!value.is_string() && !value.is_null()).then_some("MUST BE TEXT OR null"),
+            "choices" => match value {
+                serde_json::Value::Null => None,
+                serde_json::Value::Array(items) if !items.is_empty() && items.iter().all(|item| item.is_string()) => None,
+                _ => Some("MUST BE A LIST OF TEXT OR null"),
+            },
+            _ => Some("IS NOT A CELL FIELD"),
+        };
+        if let Some(problem) = problem {
+            problems.push(format!("{} {}", field, problem));
+        }
+    }
+    for required in ["raw_value", "display_value"] {
+        if !fields.contains_key(required) {
+            problems.push(format!("MISSING {}", required));
+        }
+    }
+    problems
+}
+
+/// Upgrades a version 0 save (a bare map of cells) by wrapping it with an empty history.
+fn migrate_save_v0(value: &mut serde_json::Value) {
+    let cells = value.take();
+    *value = serde_json::json!({ "cells": cells, "undo": [], "redo": [] });
+}
+
+/// Upgrades a version 1 save by filling fields a cell did not have yet with those of an empty cell,
+/// both in the sheet and in the saved history.
+fn migrate_save_v1(value: &mut serde_json::Value) {
+    let fill = fill_cell_defaults;
+    if let Some(cells) = value.get_mut("cells").and_then(|c| c.as_object_mut()) {
+        cells.values_mut().for_each(fill);
+    }
+    for stack in ["undo", "redo"] {
+        if let Some(actions) = value.get_mut(stack).and_then(|s| s.as_array_mut()) {
+            for action in actions {
+                if let Some(changes) = action.get_mut("cells").and_then(|c| c.as_array_mut()) {
+                    changes.iter_mut().filter_map(|change| change.get_mut("old_cell")).for_each(fill);
+                }
+            }
+        }
+    }
+}
+
+/// Gives a serialized cell the fields of an empty cell that it is missing.
+fn fill_cell_defaults(cell: &mut serde_json::Value) {
+    let defaults = serde_json::to_value(Cell::new()).unwrap_or_default();
+    if let (Some(cell), Some(defaults)) = (cell.as_object_mut(), defaults.as_object()) {
+        for (field, default) in defaults {
+            cell.entry(field.clone()).or_insert_with(|| default.clone());
+        }
+    }
+}
+
+/// Builds a populated `Spreadsheet` without going through commands or key events, e.g.
+/// `Spreadsheet::builder().rows(100).cols(26).cell("B1", "4").cell("A1", "=SUM(B1:B5)").build()`.
+///
+/// # Fields:
+/// - `rows`, `cols`: The size of the sheet (10 by 10 unless set).
+/// - `cells`: The values and formulas to enter, by address, in the order they were given.
+#[derive(Clone, Debug)]
+pub struct SpreadsheetBuilder {
+    rows: usize,
+    cols: usize,
+    cells: Vec<(String, String)>,
+}
+
+impl SpreadsheetBuilder {
+    /// Sets the number of rows.
+    pub fn rows(mut self, rows: usize) -> Self {
+        self.rows = rows;
+        self
+    }
+
+    /// Sets the number of columns.
+    pub fn cols(mut self, cols: usize) -> Self {
+        self.cols = cols;
+        self
+    }
+
+    /// Enters a value or formula into a cell, as if typed there; formulas may use cells entered later.
+    ///
+    /// # Arguments:
+    /// - `addr`: The address of the cell, e.g. "A1".
+    /// - `value`: The value, or a formula starting with `=`.
+    pub fn cell(mut self, addr: &str, value: &str) -> Self {
+        self.cells.push((addr.to_string(), value.to_string()));
+        self
+    }
+
+    /// Builds the sheet.
+    ///
+    /// # Returns:
+    /// The sheet, with no undo history and nothing to save, or the error of the first cell that was
+    /// refused (e.g. `"A1: ERROR: INVALID FORMULA =FOO()"`). A size larger than 999 rows or 18278
+    /// columns is refused first.
+    pub fn try_build(self) -> std::result::Result<Spreadsheet, String> {
+        let (sheet, errors) = self.populate();
+        match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(sheet),
+        }
+    }
+
+    /// Builds the sheet like `try_build`, leaving out cells that cannot be entered.
+    pub fn build(self) -> Spreadsheet {
+        self.populate().0
+    }
+
+    /// Creates the sheet and enters the cells, returning it with the errors of the refused cells.
+    fn populate(self) -> (Spreadsheet, Vec<String>) {
+        let mut sheet = Spreadsheet::new(self.rows, self.cols);
+        let mut errors: Vec<String> = check_sheet_size(self.rows, self.cols).err().into_iter().collect();
+        for (addr, value) in &self.cells {
+            match CellAddress::from_str(addr).filter(|a| a.row < sheet.max_rows && a.col < sheet.max_cols) {
+                Some(cell_addr) if sheet.update_cell(&cell_addr, value, false) => {}
+                Some(_) => errors.push(format!("{}: {}", addr, sheet.status_message)),
+                None => errors.push(format!("{}: INVALID CELL ADDRESS", addr)),
+            }
+        }
+        sheet.undo_stack.clear();
+        sheet.redo_stack.clear();
+        sheet.unsaved_changes = false;
+        sheet.modified = false;
+        sheet.status_message.clear();
+        (sheet, errors)
+    }
+}
+
+/// Represents the state of the entire spreadsheet, including cell data, user interaction, and tracking of undo/redo actions.
+///
+/// The `Spreadsheet` struct encapsulates the entire state of a spreadsheet, including the data of each cell,
+/// the current cursor position, the mode of operation (e.g., normal, insert), and additional attributes to manage
+/// user actions such as undo, redo, and search. It also manages dependencies between cells and tracks changes
+/// in real-time to ensure consistent updates across the spreadsheet.
+///
+/// # Fields:
+/// - `data`: A `HashMap` storing the cells of the spreadsheet that differ from a blank cell, where the key is the
+///   cell address. Other cells within `max_rows` x `max_cols` read as `blank_cell`.
+/// - `blank_cell`: The cell returned for addresses with nothing stored (a `Cell::new()`).
+/// - `cursor`: The current position of the cursor (cell address).
+/// - `mode`: The current mode of the spreadsheet (e.g., Normal, Insert, Command, Find).
+/// - `help_scroll`: The first line of the `:help` screen shown.
+/// - `max_cols`: The maximum number of columns in the spreadsheet.
+/// - `max_rows`: The maximum number of rows in the spreadsheet.
+/// - `command_buffer`: A string buffer for storing the current command being entered by the user.
+/// - `status_message`: A message that displays the current status or feedback for the user.
+/// - `undo_stack`: A stack (using `VecDeque`) of per-action cell deltas that can be undone.
+/// - `redo_stack`: A stack (using `VecDeque`) of per-action cell deltas of undone actions that can be redone.
+/// - `action_depth`: How many `begin_action` calls are still waiting for their `commit_action`; cell
+///   changes are recorded into the newest undo action while this is non-zero.
+/// - `undo_limit`: The maximum number of undo steps kept (`0` means unlimited).
+/// - `readonly`: Whether user edits are refused (set by the `--readonly` command-line flag).
+/// - `theme`: The colors used to draw the grid.
+/// - `frozen_rows`, `frozen_cols`: How many leading rows and columns stay on screen while scrolling (`freeze`).
+/// - `protection`: The ranges (or the whole sheet) where edits, sorts and fills are refused (`protect`).
+/// - `pivots`: The pivot tables written with `pivot`, recomputed by `pivot refresh`.
+/// - `row_groups`: The groups of rows made with `group`; the rows of collapsed ones are not drawn.
+/// - `start_row`, `start_col`: The first scrolling row and column shown, after the frozen ones.
+/// - `view_rows`, `view_cols`: How many rows and columns the grid shows, fitted to the terminal on every full
+///   redraw (10 x 10 until the first one, e.g. in batch mode).
+/// - `terminal_size`: The terminal size (columns, rows) of the last resize event; until one arrives the
+///   terminal is asked on every full redraw.
+/// - `followed_cursor`: The cursor position (column, row) the view was last scrolled to follow; none at
+///   first, so a new sheet scrolls to its cursor when it is first drawn.
+/// - `autosave`: Where and how often the sheet is saved automatically, if autosave is on.
+/// - `last_autosave`: When the sheet was last autosaved (or autosave was turned on).
+/// - `unsaved_changes`: Whether the sheet changed since the last autosave.
+/// - `file_name`: The file the sheet was last opened from or saved to, shown in the status bar.
+/// - `modified`: Whether the sheet changed since it was last opened or saved.
+/// - `last_error`: The most recent error message, kept in the status bar after newer messages replace it.
+/// - `sheets`: Every sheet of the workbook in tab order; the entry of the active sheet only holds its name.
+/// - `active_sheet`: The index in `sheets` of the sheet currently shown.
+/// - `store`: The SQLite database the sheet is backed by after `saveas_db` or `open_db`, or the swap database
+///   a large sheet was moved to (only with the `sqlite` feature); `data` then holds the formula cells and the
+///   row pages read so far.
+/// - `swap_threshold`: How many cells may be in memory before the sheet is moved to a swap database
+///   (`SWAP_MIN_CELLS` unless changed with `set_swap_threshold`; only with the `sqlite` feature).
+/// - `find_matches`: A list of `CellAddress` instances that match the current search query.
+/// - `current_find_match`: The index of the current match in the `find_matches` list.
+/// - `find_query`: The current search query being used to find matches in the spreadsheet.
+/// - `find_scope`: Which part of each cell (value, formula or address) the search matches against.
+/// - `replace_state`: The pending confirm-each replacement, if one is in progress.
+/// - `dependents`: A `HashMap` mapping a cell address to the set of cells that depend on it.
+/// - `dependencies`: A `HashMap` mapping a cell address to the set of cells it depends on.
+/// - `propagating`: Whether `propagate_changes` or `evaluate_stale` is re-evaluating dependents, so the cells it updates do not start their own pass.
+/// - `watch_list`: Addresses of cells pinned to the watch panel, shown regardless of scroll position.
+/// - `alerts`: Alert rules checked after each recalculation pass.
+/// - `alert_notice`: The message of the most recently fired alert, shown highlighted until the next key press.
+/// - `chart`: The chart opened with `:chart` and the range it shows, drawn over the grid until the next key press.
+/// - `ring_bell`: Set when a fired alert asks for the terminal bell on the next redraw.
+/// - `link_cache`: Display values of externally linked sheets, keyed by source path and then cell address.
+/// - `eval_timeout`: The time budget for evaluating a single cell's formula; a formula that takes longer shows `#TIMEOUT!`.
+/// - `tick_rate`: How long the event loop waits for input before running an idle tick (animations, watch panel).
+/// - `needs_full_redraw`: Whether the next frame must redraw the whole grid, or only the command and status lines.
+/// - `frame_drawn`: When the last frame was drawn, to keep frames at least `MIN_FRAME_MS` apart while keys are waiting.
+/// - `drawn`: The last frame drawn, compared with the next one so only changed lines are written; `None` when
+///   the screen was drawn over since, so the next frame rewrites every line.
+/// - `show_progress`: Whether long operations draw a progress bar (only in the interactive editor).
+/// - `progress_drawn`: When the progress bar was last drawn, and how many times during the current operation.
+/// - `chunked_recalc`: Whether long recalculations are left to the event loop, which evaluates them in batches
+///   between key presses, instead of being finished at once (set by the editor).
+/// - `recalc_queue`: Formula cells still to be evaluated by the current recalculation, in dependency order.
+/// - `recalc_total`: The number of formula cells in the current recalculation, for its progress.
+/// - `lazy_calc`: Whether dependents of an edited cell are only marked stale instead of evaluated (`:calc lazy`).
+/// - `stale`: Formula cells whose inputs changed under lazy calculation and that have not been evaluated since.
+/// - `strict_locks`: Whether `mi` and `sort` refuse a range holding locked cells instead of leaving those cells
+///   (and for `sort`, their rows) in place (`:locked fail`).
+/// - `sort_in_range`: Whether `sort` moves only the cells inside the range instead of whole rows (`:sortmode range`).
+/// - `command_history`: Previously executed `:` commands, oldest first, shared across sessions.
+/// - `history_index`: The history entry currently recalled into the command buffer, if any.
+/// - `completion`: The active Tab completion, if the last key in Command mode was Tab.
+/// - `last_change`: The last cell-mutating action, repeated at the cursor by `.`.
+/// ### Macros:
+/// - `pending_key`: The first key of a two-key Normal mode sequence (e.g., `q`, `@`, `m` or `'`) awaiting its register.
+/// - `macro_recording`: The register currently being recorded into, if any.
+/// - `macro_buffer`: The keys recorded so far for `macro_recording`.
+/// - `macros`: Recorded key sequences by register.
+/// - `last_macro`: The register replayed most recently, used by `@@`.
+/// - `macro_depth`: How many macro replays are currently nested, used to stop runaway recursion.
+/// - `marks`: Cells remembered with `m<reg>`; `'` holds the position before the last mark jump.
+/// - `registers`: Yanked blocks of cells by register (`a`-`z`, plus `"` for the unnamed register).
+/// - `active_register`: The register chosen with a `"<reg>` prefix for the next `y` or `p`.
+/// - `show_precedents`, `show_dependents`: Whether the cells the cursor cell depends on, and the cells
+///   depending on it, are highlighted (toggled with `[` and `]`).
+/// - `grid_lines`: Whether cells are separated by box-drawing lines (`grid`).
+/// - `zebra`: Whether every other row is shaded (`zebra`).
+/// - `heatmap`: Whether numeric cells are colored by value (`heatmap`).
+/// ### Demo Mode:
+/// - `demo_mode`: Whether the key overlay is shown and key presses are recorded.
+/// - `demo_keys`: The most recent key labels, shown in the overlay.
+/// - `demo_last_command`: The last executed `:` command, shown in the overlay.
+/// - `demo_recording`: Every key pressed since demo mode was turned on.
+/// - `demo_queue`: Keys of a script being replayed, consumed by the main loop.
+/// - `demo_delay`: The pause between replayed keys.
+/// ### Haunt Mode & Visual Effects:
+/// - `haunted`: Indicates whether Haunt Mode is active.
+/// - `door_sound`, `scream_sound`: Sound files played when Haunt Mode starts and by the jump scare.
+/// - `haunt_sink`: Optional `Sink` for playing haunted audio effects.
+/// - `haunt_stream`: Optional `OutputStream` tied to the haunted audio.
+/// - `flicker_on`: Enables screen flicker effects when Haunt Mode is active.
+/// - `last_flicker`: Timestamp of the last flicker event, used to control flicker intervals.
+/// - `corruption_level`: Represents the current level of screen corruption (0–3).
+/// - `last_corruption_tick`: Timestamp of the last corruption update.
+/// - `haunted_start`: Records when Haunt Mode was activated with `:haunt` (drives the timed jump scare).
+/// - `haunt_by_rules`: Whether Haunt Mode was started by a haunt rule (and so is ended by the rules too).
+/// - `haunt_cells`: Cells with bad values behind the haunt rules that currently hold, named in the whispers.
+/// - `jump_scare_triggered`: Tracks whether a jump scare has already occurred during Haunt Mode.
+/// - `change_hooks`: Callbacks registered with `on_change`, called for every cell whose value changed.
+/// - `session`: The collaborative editing session joined with `share`, if any.
+/// - `dashboard`: The WebSocket server started with `serve` and the dashboards connected to it (only with
+///   the `websocket` feature).
+/// - `audit_log`: Every change of a cell's input since the sheet was created, oldest first, including the
+///   entries read from the audit file.
+/// - `audit_file`: The file opened with `audit` that new audit entries are appended to, if any.
+/// - `author`: Who the edits being made come from, while running a command sent by a dashboard.
+pub struct Spreadsheet {
+    data: HashMap<String, Cell>,
+    blank_cell: Cell,
+    cursor: CellAddress,
+    mode: Mode,
+    help_scroll: usize,
+    max_cols: usize,
+    max_rows: usize,
+    command_buffer: String,
+    status_message: String,
+    undo_stack: VecDeque<SheetAction>,
+    redo_stack: VecDeque<SheetAction>,
+    action_depth: usize,
+    undo_limit: usize,
+    readonly: bool,
+    theme: Theme,
+    frozen_rows: usize,
+    frozen_cols: usize,
+    protection: Protection,
+    pivots: Vec<Pivot>,
+    row_groups: Vec<RowGroup>,
+    start_row: usize,
+    start_col: usize,
+    view_rows: usize,
+    view_cols: usize,
+    terminal_size: Option<(u16, u16)>,
+    followed_cursor: Option<(usize, usize)>,
+    autosave: Option<(PathBuf, Duration)>,
+    last_autosave: Instant,
+    unsaved_changes: bool,
+    file_name: Option<PathBuf>,
+    modified: bool,
+    last_error: Option<String>,
+    sheets: Vec<SheetState>,
+    active_sheet: usize,
+    #[cfg(feature = "sqlite")]
+    store: Option<CellStore>,
+    #[cfg(feature = "sqlite")]
+    swap_threshold: usize,
+    find_matches: Vec<CellAddress>,
+    current_find_match: usize,
+    find_query: String,
+    find_scope: SearchScope,
+    replace_state: Option<ReplaceState>,
+    dependents: HashMap<String, HashSet<String>>,  // Maps cell address to cells that depend on it
+    dependencies: HashMap<String, HashSet<String>>,
+    propagating: bool,
+    watch_list: Vec<String>,
+    alerts: Vec<AlertRule>,
+    alert_notice: Option<String>,
+    chart: Option<(ChartKind, CellAddress, CellAddress)>,
+    ring_bell: bool,
+    link_cache: HashMap<String, HashMap<String, String>>,
+    eval_timeout: Duration,
+    tick_rate: Duration,
+    command_history: Vec<String>,
+    history_index: Option<usize>,
+    completion: Option<Completion>,
+    last_change: Option<Change>,
+    pending_key: Option<char>,
+    macro_recording: Option<char>,
+    macro_buffer: Vec<KeyCode>,
+    macros: HashMap<char, Vec<KeyCode>>,
+    last_macro: Option<char>,
+    macro_depth: usize,
+    marks: HashMap<char, CellAddress>,
+    registers: HashMap<char, Register>,
+    active_register: Option<char>,
+    needs_full_redraw: bool,
+    frame_drawn: Instant,
+    drawn: Option<DrawnFrame>,
+    show_progress: bool,
+    progress_drawn: (Instant, usize),
+    chunked_recalc: bool,
+    recalc_queue: VecDeque<String>,
+    recalc_total: usize,
+    lazy_calc: bool,
+    stale: HashSet<String>,
+    strict_locks: bool,
+    sort_in_range: bool,
+    show_precedents: bool,
+    show_dependents: bool,
+    grid_lines: bool,
+    zebra: bool,
+    heatmap: bool,
+    demo_mode: bool,
+    demo_keys: VecDeque<String>,
+    demo_last_command: Option<String>,
+    demo_recording: Vec<KeyCode>,
+    demo_queue: VecDeque<KeyCode>,
+    demo_delay: Duration,
+    haunted : bool,
+    door_sound: String,
+    scream_sound: String,
+    haunt_sink : Option<Sink>,
+    haunt_stream : Option<OutputStream>,
+    flicker_on: bool,
+    last_flicker: Instant,
+    corruption_level: u8,       // 0 = calm, 3 = full chaos
+    last_corruption_tick: Instant,
+    haunted_start: Option<Instant>,
+    haunt_by_rules: bool,
+    haunt_cells: Vec<String>,
+    jump_scare_triggered: bool,
+    change_hooks: Vec<ChangeHook>,
+    session: Option<Session>,
+    #[cfg(feature = "websocket")]
+    dashboard: Option<Dashboard>,
+    audit_log: Vec<AuditEntry>,
+    audit_file: Option<PathBuf>,
+    author: Option<String>,
+}
+
+impl Spreadsheet {
+    /// Returns a `SpreadsheetBuilder` for a 10 by 10 sheet with no cells entered.
+    pub fn builder() -> SpreadsheetBuilder {
+        SpreadsheetBuilder { rows: 10, cols: 10, cells: Vec::new() }
+    }
+
+    /// Creates a new `Spreadsheet` instance with the given number of rows and columns.
+    ///
+    /// This method initializes a spreadsheet with the specified dimensions. No cells are
+    /// allocated up front: the size only bounds the grid, and a cell is stored once it is edited.
+    /// The size is kept between 1x1 and 999x18278, the largest sheet the editor can show and address.
+    /// It sets up the initial state for the spreadsheet, including the cursor position, mode,
+    /// undo and redo stacks, and other related fields.
+    ///
+    /// # Arguments:
+    /// - `rows`: The number of rows in the spreadsheet.
+    /// - `cols`: The number of columns in the spreadsheet
+    ///
+    /// # Returns:
+    /// A new `Spreadsheet` instance with the given number of rows and columns.
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Spreadsheet {
+            data: HashMap::new(),
+            blank_cell: Cell::new(),
+            cursor: CellAddress::new(0, 0),
+            mode: Mode::Normal,
+            help_scroll: 0,
+            max_cols: cols.clamp(1, MAX_COLS),
+            max_rows: rows.clamp(1, MAX_ROWS),
+            command_buffer: String::new(),
+            status_message: String::new(),
+            undo_stack: VecDeque::new(),
+            redo_stack: VecDeque::new(),
+            action_depth: 0,
+            undo_limit: 0,
+            readonly: false,
+            theme: Theme::named("default").unwrap(),
+            frozen_rows: 0,
+            frozen_cols: 0,
+            protection: Protection::default(),
+            pivots: Vec::new(),
+            row_groups: Vec::new(),
+            start_row: 0,
+            start_col: 0,
+            view_rows: 10,
+            view_cols: 10,
+            terminal_size: None,
+            followed_cursor: None,
+            autosave: None,
+            last_autosave: Instant::now(),
+            unsaved_changes: false,
+            file_name: None,
+            modified: false,
+            last_error: None,
+            sheets: vec![SheetState::blank("Sheet1", 0, 0)],
+            active_sheet: 0,
+            #[cfg(feature = "sqlite")]
+            store: None,
+            #[cfg(feature = "sqlite")]
+            swap_threshold: SWAP_MIN_CELLS,
+            find_matches: Vec::new(),
+            current_find_match: 0,
+            find_query: String::new(),
+            find_scope: SearchScope::Values,
+            replace_state: None,
+            dependents: HashMap::new(),
+            dependencies: HashMap::new(),
+            propagating: false,
+            watch_list: Vec::new(),
+            alerts: Vec::new(),
+            alert_notice: None,
+            chart: None,
+            ring_bell: false,
+            link_cache: HashMap::new(),
+            eval_timeout: Duration::from_millis(DEFAULT_EVAL_TIMEOUT_MS),
+            tick_rate: Duration::from_millis(DEFAULT_TICK_MS),
+            command_history: Vec::new(),
+            history_index: None,
+            completion: None,
+            last_change: None,
+            pending_key: None,
+            macro_recording: None,
+            macro_buffer: Vec::new(),
+            macros: HashMap::new(),
+            last_macro: None,
+            macro_depth: 0,
+            marks: HashMap::new(),
+            registers: HashMap::new(),
+            active_register: None,
+            needs_full_redraw: true,
+            frame_drawn: Instant::now(),
+            drawn: None,
+            show_progress: false,
+            chunked_recalc: false,
+            recalc_queue: VecDeque::new(),
+            recalc_total: 0,
+            lazy_calc: false,
+            stale: HashSet::new(),
+            strict_locks: false,
+            sort_in_range: false,
+            progress_drawn: (Instant::now(), 0),
+            show_precedents: false,
+            show_dependents: false,
+            grid_lines: false,
+            zebra: false,
+            heatmap: false,
+            demo_mode: false,
+            demo_keys: VecDeque::new(),
+            demo_last_command: None,
+            demo_recording: Vec::new(),
+            demo_queue: VecDeque::new(),
+            demo_delay: Duration::from_millis(DEFAULT_DEMO_DELAY_MS),
+            haunted: false,
+            door_sound: DEFAULT_DOOR_SOUND.to_string(),
+            scream_sound: DEFAULT_SCREAM_SOUND.to_string(),
+            haunt_sink: None,
+            haunt_stream: None,
+            flicker_on: false,
+            last_flicker: Instant::now(),
+            corruption_level: 0,
+            last_corruption_tick: Instant::now(),
+            haunted_start: None,
+            haunt_by_rules: false,
+            haunt_cells: Vec::new(),
+            jump_scare_triggered: false,
+            change_hooks: Vec::new(),
+            session: None,
+            #[cfg(feature = "websocket")]
+            dashboard: None,
+            audit_log: Vec::new(),
+            audit_file: None,
+            author: None,
+        }
+    }
+
+    /// Checks whether edits are blocked because the sheet was opened with `--readonly`.
+    ///
+    /// # Returns:
+    /// `true` (and an explanatory status message) if the sheet is read-only, otherwise `false`.
+    fn is_read_only(&mut self) -> bool {
+        if self.readonly {
+            self.status_message = "ERROR: SHEET IS READ-ONLY".to_string();
+        }
+        self.readonly
+    }
+
+    /// Applies the theme, undo limit, autosave interval, tick rate and haunt sounds of the user config.
+    ///
+    /// # Arguments:
+    /// - `config`: The config read from `~/.hackersheetrc`.
+    /// - `autosave_path`: Where autosaves are written if the config turns autosave on.
+    fn apply_config(&mut self, config: &Config, autosave_path: PathBuf) {
+        if let Some(theme) = config.theme.as_deref().and_then(Theme::named) {
+            self.theme = theme;
+        }
+        if let Some(limit) = config.undo_limit {
+            self.undo_limit = limit;
+        }
+        self.autosave = config.autosave.filter(|&secs| secs > 0)
+            .map(|secs| (autosave_path, Duration::from_secs(secs)));
+        self.grid_lines = config.grid_lines.unwrap_or(self.grid_lines);
+        self.zebra = config.zebra.unwrap_or(self.zebra);
+        self.heatmap = config.heatmap.unwrap_or(self.heatmap);
+        if let Some(ms) = config.tick.filter(|&ms| ms > 0) {
+            self.tick_rate = Duration::from_millis(ms);
+        }
+        match config.locked.as_deref() {
+            Some("skip") => self.strict_locks = false,
+            Some("fail") => self.strict_locks = true,
+            _ => {}
+        }
+        match config.sort.as_deref() {
+            Some("rows") => self.sort_in_range = false,
+            Some("range") => self.sort_in_range = true,
+            _ => {}
+        }
+        if let Some(path) = &config.haunt.door_sound {
+            self.door_sound = path.clone();
+        }
+        if let Some(path) = &config.haunt.scream_sound {
+            self.scream_sound = path.clone();
+        }
+    }
+
+    /// Records the file the sheet was just opened from or saved to, clearing the modified flag.
+    ///
+    /// # Arguments:
+    /// - `path`: The path of the file.
+    fn set_current_file(&mut self, path: &Path) {
+        self.file_name = Some(path.to_path_buf());
+        self.modified = false;
+    }
+
+    /// Shows how far a long operation has got as a spinner and bar in the status line, redrawn at most
+    /// every `PROGRESS_INTERVAL_MS`. Operations on `PROGRESS_MIN_CELLS` cells or fewer show nothing,
+    /// and neither does batch mode. The operation's own result message replaces the bar when it ends.
+    ///
+    /// # Arguments:
+    /// - `label`: What is being done, e.g. `SORTING`.
+    /// - `done`: How many cells have been processed.
+    /// - `total`: How many cells the operation processes; `done == 0` starts a new operation.
+    fn report_progress(&mut self, label: &str, done: usize, total: usize) {
+        if !self.show_progress || total <= PROGRESS_MIN_CELLS {
+            return;
+        }
+        let (last, frame) = self.progress_drawn;
+        if done == 0 {
+            self.progress_drawn = (Instant::now(), 0);
+        } else if last.elapsed() < Duration::from_millis(PROGRESS_INTERVAL_MS) {
+            return;
+        }
+        self.status_message = progress_bar(label, done, total, frame);
+        self.progress_drawn = (Instant::now(), frame + 1);
+        let mut stdout = io::stdout();
+        if self.draw_status_line(&mut stdout).is_ok() {
+            let _ = stdout.flush();
+        }
+        // The next frame writes its own status bar over the progress bar
+        if let Some(drawn) = &mut self.drawn {
+            drawn.status.clear();
+        }
+    }
+
+    /// Saves the sheet (with its history) to the autosave file if autosave is on, the interval has
+    /// passed and the sheet changed since the last autosave.
+    ///
+    /// # Returns:
+    /// `true` if an autosave was attempted, so the status line needs redrawing.
+    fn autosave_if_due(&mut self) -> bool {
+        let Some((path, interval)) = self.autosave.clone() else {
+            return false;
+        };
+        if !self.unsaved_changes || self.last_autosave.elapsed() < interval {
+            return false;
+        }
+        self.last_autosave = Instant::now();
+        let saved = path.parent().map_or(Ok(()), std::fs::create_dir_all).and_then(|_| self.save_json(&path, true));
+        match saved {
+            Ok(()) => {
+                self.unsaved_changes = false;
+                self.status_message = format!("AUTOSAVED TO {}", path.display());
+            }
+            Err(e) => self.status_message = format!("AUTOSAVE ERROR: {}", e),
+        }
+        true
+    }
+
+    /// Returns the message left by the last command or edit, e.g. `"ERROR: CELL A1 LOCKED"`.
+    pub fn status_message(&self) -> &str {
+        &self.status_message
+    }
+
+    /// Returns the address of the cell under the cursor, which `find` and the jumps move.
+    pub fn cursor(&self) -> &CellAddress {
+        &self.cursor
+    }
+
+    /// Returns the current editing mode.
+    pub fn mode(&self) -> &Mode {
+        &self.mode
+    }
+
+    /// Returns the size of the sheet as `(rows, cols)`.
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.max_rows, self.max_cols)
+    }
+
+    /// Returns the cells behind the haunt rules that currently hold, sorted (empty when none holds).
+    pub fn haunt_cells(&self) -> &[String] {
+        &self.haunt_cells
+    }
+
+    /// Returns the highlighted notice of the last alert that fired, until the next key press clears it.
+    pub fn alert_notice(&self) -> Option<&str> {
+        self.alert_notice.as_deref()
+    }
+
+    /// Returns whether the next frame redraws the whole grid, rather than only the input and status lines.
+    pub fn needs_full_redraw(&self) -> bool {
+        self.needs_full_redraw
+    }
+
+    /// Returns the rows and columns the next frame shows, frozen ones first, as `(rows, cols)`. The
+    /// view is fitted to the terminal and scrolled to the cursor first, as it is before every frame.
+    pub fn viewport(&mut self) -> (Vec<usize>, Vec<usize>) {
+        self.fit_view();
+        (self.visible_rows(), self.visible_cols())
+    }
+
+    /// Fits the grid to a terminal of `cols` by `rows` characters, as on a resize event; later frames
+    /// keep this size until the next resize.
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        self.terminal_size = Some((cols, rows));
+        self.needs_full_redraw = true;
+        self.fit_view();
+    }
+
+    /// Forgets what is on the screen, so the next frame repaints all of it; needed after another
+    /// program (e.g. the shell on Ctrl-Z) has used the terminal.
+    pub fn redraw(&mut self) {
+        self.needs_full_redraw = true;
+        self.drawn = None;
+    }
+
+    /// Moves the cursor by the given number of columns and rows.
+    ///
+    /// This method updates the position of the cursor within the bounds of the spreadsheet.
+    ///
+    /// # Arguments:
+    /// - `dx`: The number of columns to move the cursor. Positive values move to the right, negative values to the left.
+    /// - `dy`: The number of rows to move the cursor. Positive values move down, negative values move up.
+    ///
+    /// # Notes:
+    /// The cursor will not move outside the bounds of the spreadsheet (i.e., the number of columns and rows).
+    /// Moving up or down steps over rows folded away in collapsed groups.
+    fn move_cursor(&mut self, dx: isize, dy: isize) {
+        let new_col = self.cursor.col as isize + dx;
+        let mut new_row = self.cursor.row as isize + dy;
+        while dy != 0 && new_row >= 0 && new_row < self.max_rows as isize && self.is_row_hidden(new_row as usize) {
+            new_row += dy.signum();
+        }
+        
+        // Ensure within bounds
+        if new_col >= 0 && new_col < self.max_cols as isize &&
+           new_row >= 0 && new_row < self.max_rows as isize {
+            self.cursor.col = new_col as usize;
+            self.cursor.row = new_row as usize;
+        }
+    }
+
+    /// Moves the cursor to the specified cell address.
+    ///
+    /// This method attempts to move the cursor to a given cell address, specified as a string (e.g., "A1").
+    /// If the address is valid and within the bounds of the spreadsheet, the cursor will be moved to that cell.
+    ///
+    /// # Arguments:
+    /// - `addr`: A string representing the cell address to jump to (e.g., "A1", "B2").
+    ///
+    /// # Returns:
+    /// `true` if the cell address is valid and the cursor is successfully moved, otherwise `false`.
+    fn jump_to_cell(&mut self, addr: &str) -> bool {
+        if let Some(cell_addr) = CellAddress::from_str(addr) {
+            if cell_addr.col < self.max_cols && cell_addr.row < self.max_rows {
+                self.set_cursor(cell_addr);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Puts the cursor on a cell, expanding the collapsed row groups that hide its row. Every jump
+    /// (`:j`, marks, find and replace matches) goes through here, so the cursor is never on a row
+    /// that is not drawn.
+    ///
+    /// # Arguments:
+    /// - `addr`: The cell to put the cursor on.
+    fn set_cursor(&mut self, addr: CellAddress) {
+        for group in self.row_groups.iter_mut().filter(|group| group.collapsed && group.contains(addr.row)) {
+            group.collapsed = false;
+            self.modified = true;
+        }
+        self.cursor = addr;
+    }
+
+    /// Moves the cursor off a row folded away in a collapsed group, to the next row shown below it,
+    /// or above if there is none.
+    fn reveal_cursor(&mut self) {
+        let row = self.cursor.row;
+        if let Some(shown) = (row..self.max_rows).chain((0..row).rev()).find(|&row| !self.is_row_hidden(row)) {
+            self.cursor.row = shown;
+        }
+    }
+
+    /// Adds a dependency between two cells.
+    ///
+    /// This method records that one cell (the dependent) depends on the value of another cell (the dependency).
+    /// It updates the `dependencies` and `dependents` mappings accordingly.
+    ///
+    /// # Arguments:
+    /// - `dependent`: The address of the cell that depends on another cell.
+    /// - `dependency`: The address of the cell that is being depended on.
+    ///
+    /// # Notes:
+    /// This method will ensure that both the `dependencies` and `dependents` mappings are updated for both
+    /// the dependent and the dependency cells.
+    fn add_dependency(&mut self, dependent: &str, dependency: &str) {
+        // Record that 'dependent' depends on 'dependency'
+        self.dependencies.entry(dependent.to_string())
+            .or_insert_with(HashSet::new)
+            .insert(dependency.to_string());
+        
+        // Record that 'dependency' is depended upon by 'dependent'
+        self.dependents.entry(dependency.to_string())
+            .or_insert_with(HashSet::new)
+            .insert(dependent.to_string());
+
+    }
+
+    /// Returns every cell reachable from a cell through a dependency map, directly or through other
+    /// cells: all its precedents when given `dependencies`, all its dependents when given `dependents`.
+    ///
+    /// # Arguments:
+    /// - `links`: The dependency map to follow.
+    /// - `cell_addr`: The address of the cell to start from (not included in the result).
+    fn linked_cells(links: &HashMap<String, HashSet<String>>, cell_addr: &str) -> HashSet<String> {
+        let mut found = HashSet::new();
+        let mut pending = vec![cell_addr.to_string()];
+        while let Some(addr) = pending.pop() {
+            for next in links.get(&addr).into_iter().flatten() {
+                if next != cell_addr && found.insert(next.clone()) {
+                    pending.push(next.clone());
+                }
+            }
+        }
+        found
+    }
+
+    /// Returns the cells highlighted as the precedents and as the dependents of the cursor cell, each
+    /// empty unless turned on with `[` or `]`.
+    pub fn formula_links(&self) -> (HashSet<String>, HashSet<String>) {
+        let cursor_addr = self.cursor.to_string();
+        let precedents = if self.show_precedents { Self::linked_cells(&self.dependencies, &cursor_addr) } else { HashSet::new() };
+        let dependents = if self.show_dependents { Self::linked_cells(&self.dependents, &cursor_addr) } else { HashSet::new() };
+        (precedents, dependents)
+    }
+
+    /// Returns the numeric values of a range in reading order (row by row), skipping cells that do not
+    /// hold a number.
+    ///
+    /// # Arguments:
+    /// - `start`, `end`: Opposite corners of the range.
+    fn range_numbers(&self, start: &CellAddress, end: &CellAddress) -> Vec<f64> {
+        self.range_numbers_until(start, end, None).unwrap_or_default()
+    }
+
+    /// Like `range_numbers`, but gives up with `None` once `deadline` has passed.
+    fn range_numbers_until(&self, start: &CellAddress, end: &CellAddress, deadline: Option<Instant>) -> Option<Vec<f64>> {
+        let mut values = Vec::new();
+        for row in start.row.min(end.row)..=start.row.max(end.row) {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            for col in start.col.min(end.col)..=start.col.max(end.col) {
+                if let Some(value) = self.get_cell(&CellAddress::new(col, row)).and_then(|cell| cell.display_value.parse::<f64>().ok()) {
+                    values.push(value);
+                }
+            }
+        }
+        Some(values)
+    }
+
+    /// Removes all dependencies related to the given cell address.
+    ///
+    /// This method removes both the cell's dependencies and the cell from the list of dependents of each of its
+    /// dependencies. It is useful when clearing dependencies when a cell's formula is changed or removed.
+    /// Entries left empty are dropped, so the maps only ever hold cells that are linked.
+    ///
+    /// # Arguments:
+    /// - `cell_addr`: The address of the cell for which to remove dependencies.
+    fn remove_dependencies(&mut self, cell_addr: &str) {
+        // Remove all dependencies for this cell
+        if let Some(deps) = self.dependencies.remove(cell_addr) {
+            // For each dependency, remove this cell from its dependents, and the entry once no cell is left
+            for dep in deps {
+                if let Some(dependents) = self.dependents.get_mut(&dep) {
+                    dependents.remove(cell_addr);
+                    if dependents.is_empty() {
+                        self.dependents.remove(&dep);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Links a cell into the dependency graph according to the formula it now holds, after the cell
+    /// was replaced without going through `update_cell` (undo, redo, sort). A cell without a formula
+    /// is only unlinked from the cells it read.
+    ///
+    /// # Arguments:
+    /// - `cell_addr`: The address of the replaced cell.
+    fn relink_cell(&mut self, cell_addr: &str) {
+        match self.data.get(cell_addr).and_then(|cell| cell.formula.clone()) {
+            Some(formula) => self.update_dependencies(cell_addr, &format!("={}", formula)),
+            None => self.remove_dependencies(cell_addr),
+        }
+    }
+
+    /// Updates the dependencies for a cell based on its formula.
+    ///
+    /// This method analyzes a cell's formula and updates its dependencies accordingly. The formula can refer to
+    /// other cells directly (e.g., `A1`), ranges of cells (e.g., `SUM(A1:B2)`), or even functions with cell
+    /// references (e.g., `=SUM(A1:B1)`).
+    ///
+    /// # Arguments:
+    /// - `cell_addr`: The address of the cell whose dependencies need to be updated.
+    /// - `formula`: The formula string that defines the dependencies.
+    fn update_dependencies(&mut self, cell_addr: &str, formula: &str) {
+        // First, remove any existing dependencies
+        self.remove_dependencies(cell_addr);
+        for addr in self.formula_references(formula) {
+            self.add_dependency(cell_addr, &addr);
+        }
+    }
+
+    /// Returns the addresses of the cells a formula reads (see `update_dependencies`), without
+    /// changing the dependency graph. Values that are not formulas read no cells.
+    ///
+    /// The formula is split with the same tokenizer `evaluate` uses: a name followed by `(` is a
+    /// function, `A1:B2` is every cell of the range and any other name that is a cell label is a
+    /// single cell. `LINK` reads a cell of another file, not of this sheet.
+    ///
+    /// # Arguments:
+    /// - `formula`: The value entered into a cell, e.g. `=SUM(A1:B2)`.
+    fn formula_references(&self, formula: &str) -> Vec<String> {
+        let mut refs = Vec::new();
+        let Some(formula) = formula.strip_prefix('=') else {
+            return refs;
+        };
+        if formula.starts_with("LINK(") {
+            return refs;
+        }
+        let Ok(tokens) = tokenize_formula(formula) else {
+            return refs;
+        };
+        let mut pos = 0;
+        while pos < tokens.len() {
+            match &tokens[pos..] {
+                [FormulaToken::Name(_), FormulaToken::Symbol('('), ..] => pos += 2,
+                [FormulaToken::Name(first), FormulaToken::Symbol(':'), FormulaToken::Name(last), ..] => {
+                    // Add all cells in the range as dependencies
+                    if let Some((start, end)) = self.parse_range(&format!("{}:{}", first, last)) {
+                        for col in start.col..=end.col {
+                            for row in start.row..=end.row {
+                                refs.push(CellAddress::new(col, row).to_string());
                             }
                         }
-                        sum
+                    }
+                    pos += 3;
+                }
+                [FormulaToken::Name(name), ..] => {
+                    if let Some(addr) = CellAddress::from_str(name) {
+                        refs.push(addr.to_string());
+                    }
+                    pos += 1;
+                }
+                _ => pos += 1,
+            }
+        }
+        refs
+    }
+
+    /// Returns whether giving a cell the formula would make it depend on itself, directly or through
+    /// other cells: that is when the formula reads the cell or one of the cells depending on it.
+    ///
+    /// # Arguments:
+    /// - `cell_addr`: The address of the cell about to get the formula.
+    /// - `formula`: The value about to be entered, e.g. `=B1+1`.
+    fn creates_cycle(&self, cell_addr: &str, formula: &str) -> bool {
+        let refs = self.formula_references(formula);
+        if refs.is_empty() {
+            return false;
+        }
+        let dependents = Self::linked_cells(&self.dependents, cell_addr);
+        refs.iter().any(|addr| addr == cell_addr || dependents.contains(addr))
+    }
+    /// Propagates changes through the spreadsheet based on cell dependencies.
+    ///
+    /// Only the transitive dependents of the changed cell are re-evaluated, each once and in
+    /// topological order (Kahn's algorithm over the dependents), so a cell is evaluated after every
+    /// cell it reads. A dependent whose inputs all kept their value and error is not evaluated again,
+    /// and neither are the cells past it unless another path reaches them.
+    ///
+    /// # Arguments:
+    /// - `cell_addr`: A string representing the address of the cell whose changes need to be propagated.
+    ///
+    /// # Notes:
+    /// - Formulas closing a cycle are refused by `apply_cell_update` before they are entered. If a cycle is
+    ///   still found (e.g. in a loaded file), an error message is shown, and the open transaction is rolled back.
+    fn propagate_changes(&mut self, cell_addr: &str) {
+        // Dependents are evaluated by the outermost pass only
+        if self.propagating || !self.dependents.contains_key(cell_addr) {
+            return;
+        }
+        let affected = Self::linked_cells(&self.dependents, cell_addr);
+        // A cell depending on the changed cell again, or any cycle among its dependents, never becomes ready
+        let looping = self.dependents.get(cell_addr).is_some_and(|deps| deps.contains(cell_addr))
+            || affected.iter().any(|addr| self.dependents.get(addr).is_some_and(|deps| deps.contains(cell_addr)));
+        let order = match self.dependency_order(&affected) {
+            Ok(_) if looping => Err(cell_addr.to_string()),
+            order => order,
+        };
+        let order = match order {
+            Ok(order) => order,
+            Err(dependent) => {
+                self.status_message = format!("ERROR: CIRCULAR DEPENDENCY DETECTED WITH {}", dependent);
+                self.rollback_action();
+                self.status_message = format!("ERROR: CIRCULAR DEPENDENCY DETECTED WITH {}", dependent);
+                return;
+            }
+        };
+        if self.lazy_calc {
+            // Evaluated by `evaluate_stale` once they are shown or read
+            self.stale.extend(order);
+            return;
+        }
+
+        let mut changed = HashSet::from([cell_addr.to_string()]);
+        self.propagating = true;
+        for dependent in order {
+            let stale = self.dependencies.get(&dependent).is_some_and(|deps| deps.iter().any(|d| changed.contains(d)));
+            let (Some(addr), Some(cell)) = (CellAddress::from_str(&dependent), self.data.get(&dependent)) else {
+                continue;
+            };
+            let Some(formula) = cell.formula.clone().filter(|_| stale) else {
+                continue;
+            };
+            let before = (cell.display_value.clone(), cell.error.clone());
+            self.apply_cell_update(&addr, &format!("={}", formula));
+            if self.data.get(&dependent).is_some_and(|cell| (&cell.display_value, &cell.error) != (&before.0, &before.1)) {
+                changed.insert(dependent);
+            }
+        }
+        self.propagating = false;
+    }
+    /// Orders cells so each comes after the cells it depends on among them (Kahn's algorithm),
+    /// ties broken by address.
+    ///
+    /// # Arguments:
+    /// - `cells`: The cells to order.
+    ///
+    /// # Returns:
+    /// The ordered cells, or the first address (by address) of a cell caught in a cycle.
+    fn dependency_order(&self, cells: &HashSet<String>) -> std::result::Result<Vec<String>, String> {
+        let mut pending: HashMap<&String, usize> = cells.iter().map(|addr| {
+            let count = self.dependencies.get(addr).map_or(0, |deps| deps.iter().filter(|d| cells.contains(*d)).count());
+            (addr, count)
+        }).collect();
+        let mut ready: Vec<&String> = pending.iter().filter(|(_, n)| **n == 0).map(|(a, _)| *a).collect();
+        ready.sort_by(|a, b| b.cmp(a));
+        let mut order = Vec::with_capacity(cells.len());
+        while let Some(addr) = ready.pop() {
+            for dep in self.dependents.get(addr).into_iter().flatten() {
+                if let Some(count) = pending.get_mut(dep) {
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push(dep);
+                    }
+                }
+            }
+            order.push(addr.clone());
+        }
+        match pending.iter().filter(|(_, n)| **n > 0).map(|(a, _)| (*a).clone()).min() {
+            Some(stuck) => Err(stuck),
+            None => Ok(order),
+        }
+    }
+    /// Evaluates stale formulas left by lazy calculation (see `lazy_calc`): the given cells if they
+    /// are stale, after the stale cells they read, or every stale cell when `cells` is `None`.
+    /// Dependents of the evaluated cells are already stale themselves and are not touched.
+    ///
+    /// # Arguments:
+    /// - `cells`: The cells whose values are needed, or `None` for all.
+    /// - `deadline`: When to stop, leaving the cells not evaluated yet stale, if ever.
+    fn evaluate_stale(&mut self, cells: Option<&[String]>, deadline: Option<Instant>) {
+        if self.stale.is_empty() {
+            return;
+        }
+        let needed: HashSet<String> = match cells {
+            None => std::mem::take(&mut self.stale),
+            Some(cells) => cells.iter().filter(|addr| self.stale.contains(*addr)).flat_map(|addr| {
+                let mut inputs = Self::linked_cells(&self.dependencies, addr);
+                inputs.retain(|input| self.stale.contains(input));
+                inputs.insert(addr.clone());
+                inputs
+            }).collect(),
+        };
+        if needed.is_empty() {
+            return;
+        }
+        self.stale.retain(|addr| !needed.contains(addr));
+        let order = self.dependency_order(&needed).unwrap_or_else(|_| needed.into_iter().collect());
+        let propagating = std::mem::replace(&mut self.propagating, true);
+        let mut order = order.into_iter();
+        while let Some(addr_str) = order.next() {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.stale.insert(addr_str);
+                self.stale.extend(order);
+                break;
+            }
+            let (Some(addr), Some(formula)) = (CellAddress::from_str(&addr_str), self.data.get(&addr_str).and_then(|cell| cell.formula.clone())) else {
+                continue;
+            };
+            self.apply_cell_update(&addr, &format!("={}", formula));
+        }
+        self.propagating = propagating;
+    }
+    /// Marks every dependent of the given cells stale under lazy calculation, e.g. after an undo
+    /// restored them, as the dependents may have been evaluated since.
+    fn mark_dependents_stale(&mut self, changes: &[UndoAction]) {
+        if !self.lazy_calc {
+            return;
+        }
+        for change in changes {
+            let dependents = Self::linked_cells(&self.dependents, &change.cell_address.to_string());
+            self.stale.extend(dependents);
+        }
+    }
+    /// Switches between updating dependents as soon as a cell changes and lazy calculation, where
+    /// dependents are only marked stale and evaluated when they are shown, exported or read by
+    /// another formula. Switching lazy calculation off evaluates every stale cell.
+    ///
+    /// # Arguments:
+    /// - `lazy`: Whether to calculate lazily.
+    pub fn set_lazy_calc(&mut self, lazy: bool) {
+        self.lazy_calc = lazy;
+        if !lazy {
+            self.evaluate_stale(None, None);
+        }
+    }
+    /// Returns how long the event loop waits for a key before its next tick: the tick rate, but at least
+    /// `MIN_FRAME_MS`, and not at all while a chunked recalculation has cells left to evaluate.
+    pub fn poll_timeout(&self) -> Duration {
+        if self.recalc_queue.is_empty() {
+            self.tick_rate.max(Duration::from_millis(MIN_FRAME_MS))
+        } else {
+            Duration::ZERO
+        }
+    }
+    /// Leaves recalculations of more than `RECALC_BATCH_CELLS` formulas (e.g. after loading a large
+    /// file) to the caller, which evaluates them a batch at a time with `recalculate_batch` and stays
+    /// responsive in between. Switching it off finishes a recalculation still pending.
+    ///
+    /// # Arguments:
+    /// - `chunked`: Whether to recalculate in batches.
+    pub fn set_chunked_recalc(&mut self, chunked: bool) {
+        self.chunked_recalc = chunked;
+        if !chunked {
+            self.finish_recalculation();
+        }
+    }
+    /// Updates a cell's value in the spreadsheet, recalculates it if necessary, and propagates changes
+/// to dependent cells. This function supports both simple values and complex formulas (such as 
+/// `SUM`, `MIN`, `MAX`, `sqrt`, and `log`). It also checks for circular dependencies and invalid 
+/// formulas, ensuring that the integrity of the spreadsheet is maintained.
+///
+/// # Arguments
+///
+/// * `addr` - A reference to the `CellAddress` of the cell to be updated. This indicates which 
+///   cell in the spreadsheet should be modified.
+/// * `value` - A string representing the new value or formula for the cell. If the value starts 
+///   with `=`, it is considered a formula; otherwise, it's treated as a constant value.
+/// * `multi` - A boolean flag indicating whether this update is part of a multi-cell operation. 
+///   If `multi` is `false`, the update (including recalculated dependents) is its own undo step and
+///   clears the status message left by an earlier edit. If `multi` is `true`, it joins the transaction
+///   opened by the caller with `begin_action`, whose messages are left alone.
+///
+/// # Returns
+///
+/// Returns `true` if the cell was updated successfully, and `false` if an error occurred (e.g., 
+/// invalid formula, circular dependency, or locked cell).
+///
+/// # Error Handling
+///
+/// This function performs several checks and sets the `status_message` with an appropriate error 
+/// message if any of the following conditions are met:
+/// 
+/// - The cell doesn't exist (`ERROR: CELL {addr} NOT FOUND`)
+/// - The cell is locked (`ERROR: CELL {addr} LOCKED`)
+/// - The cell lies in a protected range or sheet (`ERROR: RANGE {range} PROTECTED`, `ERROR: SHEET PROTECTED`)
+/// - The formula reads the cell or a cell depending on it (`ERROR: CIRCULAR DEPENDENCY DETECTED WITH {addr}`);
+///   this is checked before anything changes, so the sheet and its undo and redo history are left as they were
+/// - A cycle among existing formulas (e.g., from a loaded file) is found while recalculating the dependents
+///   (`ERROR: CIRCULAR DEPENDENCY DETECTED WITH {addr}`); the edit is rolled back
+/// - An invalid formula is provided, such as an incorrectly formatted range (`ERROR: INVALID RANGE {range}`)
+/// - An invalid arithmetic expression (`ERROR: INVALID ARITHMETIC EXPRESSION {expression}`)
+/// - An invalid function argument (`ERROR: INVALID ARGUMENT {function}`)
+/// - A general invalid formula error (`ERROR: INVALID FORMULA {value}`)
+/// - The formula took longer than the evaluation timeout (`ERROR: EVALUATION TIMEOUT IN {addr}`); the cell shows `#TIMEOUT!`
+    pub fn update_cell(&mut self, addr: &CellAddress, value: &str, multi:bool) -> bool {
+        if !multi {
+            // An error of an earlier edit, e.g. a refused list value, is not about this one
+            self.status_message.clear();
+        }
+        if let Some(area) = self.protection.covering(addr, addr) {
+            self.status_message = format!("ERROR: {} PROTECTED", area);
+            return false;
+        }
+        if !multi {
+            self.begin_action();
+        }
+        let updated = self.apply_cell_update(addr, value);
+        if !multi {
+            self.commit_action();
+        }
+        updated
+    }
+/// Does the work of `update_cell`, recording changed cells into the caller's undo transaction.
+    fn apply_cell_update(&mut self, addr: &CellAddress, value: &str) -> bool {
+        // Rows of a database-backed sheet are read in before the cell or its formula's inputs are used
+        if self.is_store_backed() {
+            self.page_in_rows(addr.row, addr.row);
+            self.page_in_refs(value);
+        }
+        // First, check if cell exists and if it's locked
+        let cell_exists = self.get_cell(addr).is_some();
+        let is_locked = self.get_cell(addr).map_or(false, |cell| cell.is_locked);
+        
+        if !cell_exists {
+            self.status_message = format!("ERROR: CELL {} NOT FOUND", addr);
+            return false;
+        }
+        
+        if is_locked {
+            self.status_message = format!("ERROR: CELL {} LOCKED", addr);
+            return false;
+        }
+        // A list-validated cell takes one of its values, a formula, or nothing
+        let refused = self.get_cell(addr).and_then(|cell| cell.choices.as_ref())
+            .filter(|choices| !value.is_empty() && !value.starts_with('=') && !choices.iter().any(|choice| choice == value));
+        if let Some(choices) = refused {
+            self.status_message = format!("ERROR: {} MUST BE ONE OF {}", addr, choices.join(", "));
+            return false;
+        }
+
+        let cell_addr_str = addr.to_string();
+        // A new formula closing a cycle is refused before anything is changed; re-evaluating a cell's
+        // own formula leaves the graph as it is
+        let same_formula = self.get_cell(addr).and_then(|cell| cell.formula.as_deref()) == value.strip_prefix('=');
+        if !same_formula && self.creates_cycle(&cell_addr_str, value) {
+            self.status_message = format!("ERROR: CIRCULAR DEPENDENCY DETECTED WITH {}", cell_addr_str);
+            return false;
+        }
+        // The dependents `propagate_changes` and `evaluate_stale` evaluate belong to the update of the cell that changed
+        let top_level = !self.propagating;
+
+        self.stale.remove(&cell_addr_str);
+        if let Some(_old_cell) = self.get_cell(addr).cloned() {
+
+            let is_valid_formula: bool;
+            if value.starts_with("=") {
+                // Validate formula
+                let formula = &value[1..];
+                is_valid_formula = if ["SUM(", "MIN(", "MAX(", "STDEV(", "SPARK("].iter().any(|f| formula.starts_with(f)) {
+                    if let Some(range_str) = formula.strip_prefix("SUM(").or_else(|| formula.strip_prefix("MIN("))
+                        .or_else(|| formula.strip_prefix("MAX(")).or_else(|| formula.strip_prefix("STDEV("))
+                        .or_else(|| formula.strip_prefix("SPARK("))
+                        .and_then(|s| s.strip_suffix(')')) {
+                        if let Some((start, end)) = self.parse_range(range_str) {
+                            
+                            let start_exists = self.get_cell(&start).is_some();
+                            // println!("Debug: Start cell {} exists: {}", start.to_string(), start_exists);
+                            let end_exists = self.get_cell(&end).is_some();
+                            if !(start_exists && end_exists) {
+                                self.status_message = format!("ERROR: INVALID RANGE {}", range_str);
+                            }
+                            start_exists && end_exists
+                        } else {
+                            self.status_message = format!("ERROR: INVALID RANGE {}", range_str);
+
+                            false
+                        }
                     } else {
-                        0.0
+                        self.status_message = format!("ERROR: INVALID RANGE {}", formula);
+                        false
                     }
-                } else if formula.starts_with("MIN(") {
-                    let range_str = formula.strip_prefix("MIN(").unwrap().strip_suffix(')').unwrap();
-                    if let Some((start, end)) = self.parse_range(range_str) {
-                        let mut min = f64::INFINITY;
-                        for col in start.col..=end.col {
-                            for row in start.row..=end.row {
-                                let addr = CellAddress::new(col, row);
-                                if let Some(cell) = self.get_cell(&addr) {
-                                    if let Ok(value) = cell.display_value.parse::<f64>() {
-                                        if value < min {
-                                            min = value;
-                                        }
-                                    }
-                                }
+                } else if formula.starts_with("LINK(") {
+                    // External reference: =LINK(path,B3)
+                    let valid = formula.strip_prefix("LINK(").and_then(|s| s.strip_suffix(')'))
+                        .and_then(|args| args.rsplit_once(','))
+                        .is_some_and(|(path, cell_ref)| !path.trim().is_empty() && CellAddress::from_str(cell_ref.trim()).is_some());
+                    if !valid {
+                        self.status_message = format!("ERROR: INVALID LINK {}", formula);
+                    }
+                    valid
+                } else if formula.starts_with("sqrt(") || formula.starts_with("log(") || formula.starts_with("SLEEP(") {
+                    if let Some(arg) = formula.strip_prefix("sqrt(").or_else(|| formula.strip_prefix("log("))
+                        .or_else(|| formula.strip_prefix("SLEEP("))
+                        .and_then(|s| s.strip_suffix(')')) {
+                        CellAddress::from_str(arg).map_or(false, |addr| self.get_cell(&addr).is_some()) || arg.parse::<f64>().is_ok()
+                    } else {
+                        self.status_message = format!("ERROR: INVALID ARGUMENT {}", formula);
+                        false
+                    }
+                } 
+                else if formula.starts_with("(") && formula.ends_with(")") {
+                    let cell_ref = &formula[1..formula.len() - 1];
+                    if let Some(addr) = CellAddress::from_str(cell_ref) {
+                        self.get_cell(&addr).is_some()
+                    }
+                    else if cell_ref.contains(['+', '-', '*', '/']) {
+                        // Arithmetic expression like =(A1+B1)
+                        let re = regex::Regex::new(r"([+\-*/])").unwrap();
+                        let parts: Vec<&str> = re.split(cell_ref).collect();
+                        
+                        // Check if all parts are valid (either cell references or numbers)
+                        let all_valid = parts.iter().all(|part| {
+                            let trimmed = part.trim();
+                            if trimmed.is_empty() {
+                                return false;
+                            }
+                            
+                            // Check if it's a valid cell reference
+                            if let Some(addr) = CellAddress::from_str(trimmed) {
+                                self.get_cell(&addr).is_some()
+                            } else {
+                                // Check if it's a valid number
+                                trimmed.parse::<f64>().is_ok()
                             }
+                        });
+                        
+                        if !all_valid {
+                            self.status_message = format!("ERROR: INVALID ARITHMETIC EXPRESSION {}", cell_ref);
+                            false
+                        } else {
+                            true
                         }
-                        min
                     } else {
-                        0.0
+                        self.status_message = format!("ERROR: INVALID CELL REFERENCE {}", cell_ref);
+                        false
                     }
-                } else if formula.starts_with("MAX(") {
-                    let range_str = formula.strip_prefix("MAX(").unwrap().strip_suffix(')').unwrap();
+        
+                }
+                
+                else {
+                    self.status_message = format!("ERROR: INVALID FORMULA {}", value);
+                    false
+                };
+            }
+            else {
+                // self.push_undo_sheet();
+                // self.redo_stack.clear(); 
+
+                self.update_dependencies(&addr.to_string(), value);
+
+                // Dates typed into a date-formatted cell are stored as serial numbers
+                let stored = self.get_cell(addr)
+                    .and_then(|cell| cell.date_pattern())
+                    .and_then(|pattern| parse_date_input(value, pattern))
+                    .map_or_else(|| value.to_string(), |serial| serial.to_string());
+                if let Some(cell) = self.get_cell_mut(addr) {
+                    cell.formula = None;
+                    cell.raw_value = stored.clone();
+                    cell.display_value = stored;
+                    cell.error = None;
+                }
+
+                self.propagate_changes(&addr.to_string());
+                if top_level {
+                    self.check_alerts();
+                }
+                return true;
+            }
+            if is_valid_formula {
+                let formula = &value[1..];
+                // self.remove_dependencies(&addr.to_string());
+                self.update_dependencies(&addr.to_string(), value);
+                // Every formula gets the whole time budget, however long the cells before it took;
+                // evaluating its stale inputs counts towards it
+                let deadline = Instant::now() + self.eval_timeout;
+                // Under lazy calculation the inputs may be stale themselves
+                if !self.stale.is_empty() {
+                    let inputs: Vec<String> = self.dependencies.get(&cell_addr_str).map_or_else(Vec::new, |deps| deps.iter().cloned().collect());
+                    self.evaluate_stale(Some(&inputs), Some(deadline));
+                }
+                let mut timed_out = false;
+                let mut error = None;
+                // Compute the formula result
+                let result = if formula.starts_with("SUM(") {
+                    let range_str = formula.strip_prefix("SUM(").unwrap().strip_suffix(')').unwrap();
                     if let Some((start, end)) = self.parse_range(range_str) {
-                        let mut max = f64::NEG_INFINITY;
-                        for col in start.col..=end.col {
+                        let mut sum = 0.0;
+                        'columns: for col in start.col..=end.col {
+                            if Instant::now() >= deadline {
+                                timed_out = true;
+                                break 'columns;
+                            }
                             for row in start.row..=end.row {
                                 let addr = CellAddress::new(col, row);
                                 if let Some(cell) = self.get_cell(&addr) {
                                     if let Ok(value) = cell.display_value.parse::<f64>() {
-                                        if value > max {
-                                            max = value;
-                                        }
+                                        sum += value;
                                     }
                                 }
                             }
                         }
-                        max
+                        sum
                     } else {
                         0.0
                     }
-                } else if formula.starts_with("STDEV(") {
-                    let range_str = formula.strip_prefix("STDEV(").unwrap().strip_suffix(')').unwrap();
-                    if let Some((start, end)) = self.parse_range(range_str) {
-                        let mut values = Vec::new();
-                        for col in start.col..=end.col {
-                            for row in start.row..=end.row {
-                                let addr = CellAddress::new(col, row);
-                                if let Some(cell) = self.get_cell(&addr) {
-                                    if let Ok(value) = cell.display_value.parse::<f64>() {
-                                        values.push(value);
-                                    }
-                                }
-                            }
+                } else if let Some((name, args)) = ["MIN(", "MAX(", "STDEV("].iter().find_map(|f| formula.strip_prefix(f).map(|args| (&f[..f.len() - 1], args))) {
+                    // A range without numbers has no minimum, maximum or deviation
+                    let range_str = args.strip_suffix(')').unwrap();
+                    match self.parse_range(range_str).map(|(start, end)| self.range_numbers_until(&start, &end, Some(deadline)).map(|values| apply_function(name, &values))) {
+                        Some(Some(Ok(value))) => value,
+                        Some(None) => {
+                            timed_out = true;
+                            0.0
                         }
-                        let mean = values.iter().sum::<f64>() / values.len() as f64;
-                        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
-                        variance.sqrt()
-                    } else {
-                        0.0
+                        Some(Some(Err(reason))) => {
+                            error = Some(reason);
+                            0.0
+                        }
+                        None => 0.0,
                     }
                 } else if formula.starts_with("sqrt(") {
                     let arg = formula.strip_prefix("sqrt(").unwrap().strip_suffix(')').unwrap();
@@ -939,638 +3853,2784 @@ impl Spreadsheet {
                         } else {
                             0.0
                         }
-                    } else {
-                        0.0
+                    } else {
+                        0.0
+                    }
+                } else if formula.starts_with("log(") {
+                    let arg = formula.strip_prefix("log(").unwrap().strip_suffix(')').unwrap();
+                    if let Ok(value) = arg.parse::<f64>() {
+                        value.ln()
+                    } else if let Some(addr) = CellAddress::from_str(arg) {
+                        if let Some(cell) = self.get_cell(&addr) {
+                            if let Ok(value) = cell.display_value.parse::<f64>() {
+                                value.ln()
+                            } else {
+                                0.0
+                            }
+                        } else {
+                            0.0
+                        }
+                    } else {
+                        0.0
+                    }
+                } else if formula.starts_with("LINK(") {
+                    let args = formula.strip_prefix("LINK(").unwrap().strip_suffix(')').unwrap();
+                    let (path, cell_ref) = args.rsplit_once(',').unwrap();
+                    match self.linked_value(path.trim(), cell_ref.trim()).and_then(|v| v.parse::<f64>().ok()) {
+                        Some(value) => value,
+                        None => {
+                            error = Some(format!("NO NUMBER AT {} IN {}", cell_ref.trim(), path.trim()));
+                            0.0
+                        }
+                    }
+                } else if formula.starts_with("SLEEP(") {
+                    let arg = formula.strip_prefix("SLEEP(").unwrap().strip_suffix(')').unwrap();
+                    let secs = if let Some(addr) = CellAddress::from_str(arg) {
+                        self.get_cell(&addr).and_then(|cell| cell.display_value.parse::<f64>().ok()).unwrap_or(0.0)
+                    } else {
+                        arg.parse::<f64>().unwrap_or(0.0)
+                    };
+                    // Never sleep past the remaining budget of this cell
+                    let wanted = Duration::try_from_secs_f64(secs.max(0.0)).unwrap_or(Duration::MAX);
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if wanted > remaining {
+                        thread::sleep(remaining);
+                        timed_out = true;
+                        0.0
+                    } else {
+                        thread::sleep(wanted);
+                        secs
+                    }
+                } else if formula.starts_with("(") && formula.ends_with(")") {
+                    let inside_brackets = &formula[1..formula.len() - 1];
+                    
+                    if let Some(addr) = CellAddress::from_str(inside_brackets) {
+                        // Simple cell reference like =(A1)
+                        if let Some(cell) = self.get_cell(&addr) {
+                            if let Ok(value) = cell.display_value.parse::<f64>() {
+                                value
+                            } else {
+                                0.0
+                            }
+                        } else {
+                            0.0
+                        }
+                    } else if inside_brackets.contains(['+', '-', '*', '/']) {
+                        // Arithmetic expression like =(A1+B1) or =(A1*2-B1/4), with the usual precedence
+                        match evaluate(self, inside_brackets) {
+                            Ok(value) => value,
+                            Err(reason) => {
+                                error = Some(reason);
+                                0.0
+                            }
+                        }
+                    } else {
+                        0.0
+                    }
+                }
+                else {
+                    0.0
+                };
+                let timed_out = timed_out || Instant::now() >= deadline;
+                // Update the cell's display value with the computed result; SPARK shows a sparkline instead
+                let spark = formula.strip_prefix("SPARK(").and_then(|f| f.strip_suffix(')')).and_then(|r| self.parse_range(r));
+                let shown = if timed_out {
+                    TIMEOUT_ERROR.to_string()
+                } else if let Some((start, end)) = &spark {
+                    sparkline(&self.range_numbers_until(start, end, Some(deadline)).unwrap_or_default())
+                } else {
+                    result.to_string()
+                };
+                // Like ERR in the basic sheet, a failure spreads to every formula reading the failed cell
+                let failed_input = self.dependencies.get(&cell_addr_str).and_then(|inputs| {
+                    inputs.iter().filter(|input| self.data.get(*input).is_some_and(|cell| cell.error.is_some())).min()
+                });
+                let error = if timed_out {
+                    Some(TIMEOUT_REASON.to_string())
+                } else if let Some(input) = failed_input {
+                    Some(format!("{} HAS AN ERROR", input))
+                } else if spark.is_none() && !result.is_finite() {
+                    Some(if result.is_nan() { "UNDEFINED RESULT" } else { "INFINITE RESULT" }.to_string())
+                } else {
+                    error
+                };
+                let divided_by_zero = error.as_deref() == Some("DIVISION BY ZERO");
+                if let Some(cell) = self.get_cell_mut(addr) {
+                    cell.display_value = shown.clone();
+                    cell.raw_value = shown;
+                    cell.formula = Some(value[1..].to_string());
+                    cell.error = error;
+
+                }
+                if timed_out {
+                    self.status_message = format!("ERROR: EVALUATION TIMEOUT IN {}", cell_addr_str);
+                } else if divided_by_zero {
+                    self.status_message = format!("ERROR: DIVISION BY ZERO IN {}", cell_addr_str);
+                }
+                self.propagate_changes(&addr.to_string());
+                if top_level {
+                    self.check_alerts();
+                }
+                return true;
+            }
+            else {
+
+                self.status_message = format!("ERROR: INVALID FORMULA {}", value);
+                return false;
+            }
+        }
+        return true;
+    }
+
+    // Pushes a single undo action to the undo stack for a specific cell update. This action stores
+// the previous state of the cell so that it can be reverted during an undo operation.
+//
+// The undo stack is capped at 3 actions, and older actions are discarded when this limit is exceeded.
+//
+// # Arguments
+//
+// * `addr` - The `CellAddress` of the cell that was updated.
+// * `old_cell` - A `Cell` representing the state of the cell before the update.
+//
+// # Notes
+//
+// The undo stack is maintained in a way that only a limited number of undo actions are stored
+// at any given time. If the stack reaches its limit, the oldest action is discarded to make room
+// for new actions.
+    // fn push_undo(&mut self, addr: CellAddress, old_cell: Cell) {
+    //     // Maintain max 3 undo steps
+    //     if self.undo_stack.len() >= 3 {
+    //         self.undo_stack.pop_front();
+    //     }
+    //     self.undo_stack.push_back(UndoAction {
+    //         cell_address: addr,
+    //         old_cell
+    //     });
+    // }
+
+    /// Begins an undo transaction. Every cell changed until the matching `commit_action` (through
+/// `get_cell_mut`) has its previous state recorded, so a whole user operation such as a multi-insert
+/// or a sort is undone in one step. Transactions nest: only the outermost one creates an undo action.
+///
+/// Only the changed cells are stored. The history is unlimited unless `undo_limit` is set, in which
+/// case the oldest actions are discarded once the limit is exceeded.
+    fn begin_action(&mut self) {
+        if self.action_depth == 0 {
+            self.finish_recalculation();
+            self.undo_stack.push_back(SheetAction::default());
+            if self.undo_limit > 0 {
+                while self.undo_stack.len() > self.undo_limit {
+                    self.undo_stack.pop_front();
+                }
+            }
+        }
+        self.action_depth += 1;
+    }
+    /// Ends an undo transaction started by `begin_action`. When the outermost transaction ends, the
+/// action is kept if it changed any cells (which also invalidates the redo history) and dropped otherwise.
+    fn commit_action(&mut self) {
+        self.action_depth = self.action_depth.saturating_sub(1);
+        if self.action_depth > 0 {
+            return;
+        }
+        if self.undo_stack.back().is_some_and(|action| action.cells.is_empty()) {
+            self.undo_stack.pop_back();
+        } else {
+            if !self.change_hooks.is_empty() {
+                let changes = self.undo_stack.back().map_or_else(Vec::new, |action| action.cells.clone());
+                self.notify_changes(&changes);
+            }
+            if self.session.is_some() {
+                let changes = self.undo_stack.back().map_or_else(Vec::new, |action| action.cells.clone());
+                self.share_changes(&changes);
+            }
+            let changes = self.undo_stack.back().map_or_else(Vec::new, |action| action.cells.clone());
+            self.record_audit(&changes, None);
+            self.redo_stack.clear();
+            self.unsaved_changes = true;
+            self.modified = true;
+            let changed = self.undo_stack.back().map_or_else(Vec::new, |action| {
+                action.cells.iter().map(|change| change.cell_address.clone()).collect::<Vec<_>>()
+            });
+            // Cells edited back to blank are not kept
+            for addr in &changed {
+                let addr_str = addr.to_string();
+                if self.data.get(&addr_str).is_some_and(|cell| cell.is_blank()) {
+                    self.data.remove(&addr_str);
+                }
+            }
+            if self.is_store_backed() {
+                self.sync_store(changed);
+            }
+            self.spill_to_swap();
+        }
+    }
+    /// Registers a callback that is called after every update, recalculation, undo or redo, once for
+/// each cell whose value changed, with the cell's address and its old and new displayed values.
+///
+/// # Arguments
+///
+/// * `hook` - The callback, e.g. `|addr, old, new| println!("{} {} -> {}", addr.to_string(), old, new)`.
+    pub fn on_change(&mut self, hook: impl FnMut(&CellAddress, &str, &str) + 'static) {
+        self.change_hooks.push(Box::new(hook));
+    }
+    /// Calls the `on_change` callbacks for every recorded cell whose value differs from its recorded state.
+    fn notify_changes(&mut self, changes: &[UndoAction]) {
+        if self.change_hooks.is_empty() {
+            return;
+        }
+        let mut hooks = std::mem::take(&mut self.change_hooks);
+        for change in changes {
+            let Some(cell) = self.get_cell(&change.cell_address) else {
+                continue;
+            };
+            if cell.display_value != change.old_cell.display_value {
+                for hook in hooks.iter_mut() {
+                    hook(&change.cell_address, &change.old_cell.display_value, &cell.display_value);
+                }
+            }
+        }
+        self.change_hooks = hooks;
+    }
+    /// Appends the cells whose input changed to the audit log, and to the audit file if one is open.
+/// Cells that were only recalculated or restyled are left out.
+///
+/// # Arguments
+///
+/// * `changes` - The changed cells, holding their states from before the change.
+/// * `author` - Who made the change, if not the dashboard running a command or this client of a shared session.
+    fn record_audit(&mut self, changes: &[UndoAction], author: Option<&str>) {
+        let author = author.map(str::to_string)
+            .or_else(|| self.author.clone())
+            .or_else(|| self.session.as_ref().map(|session| session.client.clone()));
+        let time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        // Cells never entered (or cleared) have no input, whatever value they show
+        let input = |cell: &Cell| if cell.is_blank() { String::new() } else { cell.input() };
+        let entries: Vec<AuditEntry> = changes.iter().filter_map(|change| {
+            let new = self.get_cell(&change.cell_address).map_or_else(String::new, input);
+            let old = input(&change.old_cell);
+            (new != old).then(|| AuditEntry {
+                time: time.clone(),
+                cell: change.cell_address.to_string(),
+                old,
+                new,
+                author: author.clone(),
+            })
+        }).collect();
+        if let Some(path) = self.audit_file.as_ref().filter(|_| !entries.is_empty()) {
+            let lines: String = entries.iter()
+                .filter_map(|entry| serde_json::to_string(entry).ok())
+                .map(|line| line + "\n")
+                .collect();
+            let written = std::fs::OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| file.write_all(lines.as_bytes()));
+            if let Err(e) = written {
+                self.status_message = format!("AUDIT ERROR: {}", e);
+            }
+        }
+        self.audit_log.extend(entries);
+    }
+    /// Returns the edit trail of a cell: every change of its input in the audit log, oldest first.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The cell, e.g. `"A1".parse().unwrap()`.
+    pub fn history(&self, addr: &CellAddress) -> Vec<&AuditEntry> {
+        let cell = addr.to_string();
+        self.audit_log.iter().filter(|entry| entry.cell == cell).collect()
+    }
+    /// Opens the audit file that the audit log is appended to from now on, reading the entries already
+/// in it so `history` also covers earlier sessions. The file is only ever appended to: the changes
+/// recorded before any audit file was opened are added to its end. Switching from another audit file
+/// leaves that file's entries out of the log.
+///
+/// # Arguments
+///
+/// * `path` - The audit file, created if it does not exist.
+///
+/// # Returns
+///
+/// The number of entries read from the file, or an error if it cannot be read or written or holds
+/// something other than audit entries.
+    pub fn open_audit(&mut self, path: &Path) -> io::Result<usize> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut log = text.lines().filter(|line| !line.trim().is_empty()).enumerate()
+            .map(|(i, line)| serde_json::from_str::<AuditEntry>(line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("LINE {}: {}", i + 1, e))))
+            .collect::<io::Result<Vec<_>>>()?;
+        let read = log.len();
+        if self.audit_file.is_none() && !self.audit_log.is_empty() {
+            let mut lines = String::new();
+            for entry in &self.audit_log {
+                lines += &serde_json::to_string(entry)?;
+                lines.push('\n');
+            }
+            std::fs::OpenOptions::new().create(true).append(true).open(path)?.write_all(lines.as_bytes())?;
+            log.append(&mut self.audit_log);
+        }
+        self.audit_log = log;
+        self.audit_file = Some(path.to_path_buf());
+        Ok(read)
+    }
+    /// Reverts every change recorded so far in the open transaction, e.g. when a circular dependency
+/// is found halfway through a recalculation. The transaction stays open and is committed as usual.
+    fn rollback_action(&mut self) {
+        if self.action_depth == 0 {
+            return;
+        }
+        if let Some(action) = self.undo_stack.back_mut() {
+            let cells = std::mem::take(&mut action.cells);
+            self.apply_sheet_action(SheetAction { cells });
+        }
+    }
+    /// Records the current state of a cell into the open undo action, if it is not already part of it.
+///
+/// # Arguments
+///
+/// * `addr_str` - The address of the cell about to be changed.
+    fn record_undo(&mut self, addr_str: &str) {
+        if self.action_depth == 0 {
+            return;
+        }
+        let Some(cell_address) = CellAddress::from_str(addr_str) else {
+            return;
+        };
+        if self.get_cell(&cell_address).is_none() {
+            return;
+        }
+        let cell = self.data.get(addr_str).unwrap_or(&self.blank_cell);
+        let recorded = |c: &UndoAction| c.cell_address.col == cell_address.col && c.cell_address.row == cell_address.row;
+        if let Some(action) = self.undo_stack.back_mut().filter(|action| !action.cells.iter().any(recorded)) {
+            action.cells.push(UndoAction { cell_address, old_cell: cell.clone() });
+        }
+    }
+    /// Restores the cells recorded in an action and returns the inverse action, holding the states
+/// the cells had just before being restored.
+///
+/// # Arguments
+///
+/// * `action` - The action to apply.
+///
+/// # Returns
+///
+/// The `SheetAction` that reverts this one.
+    fn apply_sheet_action(&mut self, action: SheetAction) -> SheetAction {
+        let mut inverse = SheetAction::default();
+        self.unsaved_changes = true;
+        self.modified = true;
+        if self.is_store_backed() {
+            for change in &action.cells {
+                self.page_in_rows(change.cell_address.row, change.cell_address.row);
+            }
+        }
+        for change in action.cells {
+            if self.get_cell(&change.cell_address).is_none() {
+                continue;
+            }
+            let addr_str = change.cell_address.to_string();
+            let restored_blank = change.old_cell.is_blank();
+            let old_cell = if restored_blank {
+                self.data.remove(&addr_str).unwrap_or_else(Cell::new)
+            } else {
+                self.data.insert(addr_str.clone(), change.old_cell).unwrap_or_else(Cell::new)
+            };
+            self.relink_cell(&addr_str);
+            inverse.cells.push(UndoAction { cell_address: change.cell_address, old_cell });
+        }
+        if self.is_store_backed() {
+            self.sync_store(inverse.cells.iter().map(|change| change.cell_address.clone()).collect());
+        }
+        inverse
+    }
+    /// Returns the protected area one of the cells restored by `action` lies in, as `SHEET` or `RANGE A1:D100`.
+    fn protected_area(&self, action: &SheetAction) -> Option<String> {
+        action.cells.iter().find_map(|change| self.protection.covering(&change.cell_address, &change.cell_address))
+    }
+    /// Undoes the last action applied to the sheet. If the undo stack is empty, a message is set
+/// indicating that there is nothing to undo.
+///
+/// The cells changed by the last action are reverted to their earlier states. The states they had
+/// before the undo are moved to the redo stack, allowing the action to be reapplied later using the redo function.
+///
+/// # Returns
+///
+/// Returns `true` if the undo operation was successfully applied, or `false` if there was nothing to undo
+/// or the action changed a cell that is now protected (`ERROR: RANGE {range} PROTECTED`); the action then
+/// stays on the undo stack.
+    pub fn undo(&mut self) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        self.finish_recalculation();
+        // Check if we have any actions to undo
+        let Some(action) = self.undo_stack.pop_back() else {
+            self.status_message = "NOTHING TO UNDO".to_string();
+            return false;
+        };
+        if let Some(area) = self.protected_area(&action) {
+            self.status_message = format!("ERROR: {} PROTECTED", area);
+            self.undo_stack.push_back(action);
+            return false;
+        }
+        let inverse = self.apply_sheet_action(action);
+        self.mark_dependents_stale(&inverse.cells);
+        self.notify_changes(&inverse.cells);
+        self.share_changes(&inverse.cells);
+        self.record_audit(&inverse.cells, None);
+        self.redo_stack.push_back(inverse);
+        
+        self.status_message = "UNDO APPLIED".to_string();
+        true
+    }
+    /// Redoes the last undone action. If the redo stack is empty, a message is set indicating that
+/// there is nothing to redo.
+///
+/// The cells reverted by the last undo are set back to the states they had before it. Their
+/// reverted states are moved back to the undo stack, allowing the action to be undone again if needed.
+///
+/// # Returns
+///
+/// Returns `true` if the redo operation was successfully applied, or `false` if there was nothing to redo
+/// or the action changed a cell that is now protected; the action then stays on the redo stack.
+    pub fn redo(&mut self) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        self.finish_recalculation();
+        // Check if we have any actions to redo
+        let Some(action) = self.redo_stack.pop_back() else {
+            self.status_message = "NOTHING TO REDO".to_string();
+            return false;
+        };
+        if let Some(area) = self.protected_area(&action) {
+            self.status_message = format!("ERROR: {} PROTECTED", area);
+            self.redo_stack.push_back(action);
+            return false;
+        }
+        let inverse = self.apply_sheet_action(action);
+        self.mark_dependents_stale(&inverse.cells);
+        self.notify_changes(&inverse.cells);
+        self.share_changes(&inverse.cells);
+        self.record_audit(&inverse.cells, None);
+        self.undo_stack.push_back(inverse);
+        
+        self.status_message = "REDO APPLIED".to_string();
+        true
+    }
+
+    /// Returns the values the cursor cell is limited to, if it has a list validation.
+    fn cursor_choices(&self) -> Option<Vec<String>> {
+        self.get_cell(&self.cursor).and_then(|cell| cell.choices.clone())
+    }
+
+    /// Moves the insert mode picker of a list-validated cell to another of its values: `Tab`, `Down` and
+    /// `Right` pick the next value, `BackTab`, `Up` and `Left` the previous one (both wrapping around), and
+    /// a character the next value starting with it. Other keys are ignored, as free typing is not allowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `choices` - The values of the cursor cell.
+    /// * `key` - The key pressed.
+    fn pick_choice(&mut self, choices: &[String], key: KeyCode) {
+        // Until a value is picked the cell's own value is the current one
+        let current = match self.command_buffer.as_str() {
+            "" => self.get_cell(&self.cursor).map(|cell| cell.raw_value.clone()).unwrap_or_default(),
+            picked => picked.to_string(),
+        };
+        let position = choices.iter().position(|choice| *choice == current);
+        let count = choices.len();
+        let picked = match key {
+            KeyCode::Tab | KeyCode::Down | KeyCode::Right => Some(position.map_or(0, |i| (i + 1) % count)),
+            KeyCode::BackTab | KeyCode::Up | KeyCode::Left => Some(position.map_or(count - 1, |i| (i + count - 1) % count)),
+            KeyCode::Char(c) => {
+                let start = position.map_or(0, |i| i + 1);
+                (0..count).map(|offset| (start + offset) % count).find(|&i| {
+                    choices[i].chars().next().is_some_and(|first| first.to_lowercase().eq(c.to_lowercase()))
+                })
+            }
+            _ => None,
+        };
+        if let Some(i) = picked {
+            self.command_buffer = choices[i].clone();
+            self.status_message = format!("PICK {}/{}: {}", i + 1, count, choices.iter().enumerate().map(|(j, choice)| {
+                if j == i { format!("[{}]", choice) } else { choice.clone() }
+            }).collect::<Vec<_>>().join(" "));
+        }
+    }
+
+    /// Protects a range or the whole sheet against edits, sorts and fills, or lifts that protection.
+    /// Once a password is set it is needed to unprotect anything, and to protect with a password again.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - `<range|sheet> [password]`, or `all [password]` to lift every protection; empty to list
+    ///   what is protected.
+    /// * `protect` - Whether to protect (`protect`) or lift the protection (`unprotect`).
+    fn protect(&mut self, args: &str, protect: bool) {
+        let mut words = args.split_whitespace();
+        let (Some(target), password) = (words.next(), words.next()) else {
+            let mut areas: Vec<String> = self.protection.ranges.clone();
+            if self.protection.sheet {
+                areas.insert(0, "SHEET".to_string());
+            }
+            self.status_message = match (areas.is_empty(), self.protection.password.is_some()) {
+                (true, _) => "NOTHING IS PROTECTED".to_string(),
+                (false, false) => format!("PROTECTED: {}", areas.join(", ")),
+                (false, true) => format!("PROTECTED: {} (WITH PASSWORD)", areas.join(", ")),
+            };
+            return;
+        };
+        if self.is_read_only() {
+            return;
+        }
+        let area = match target {
+            "sheet" => None,
+            "all" if !protect => None,
+            range => {
+                let parsed = self.parse_range(range).or_else(|| CellAddress::from_str(range).map(|addr| (addr.clone(), addr)));
+                let Some((start, end)) = parsed else {
+                    self.status_message = self.range_error(range);
+                    return;
+                };
+                let (from, to) = (CellAddress::new(start.col.min(end.col), start.row.min(end.row)), CellAddress::new(start.col.max(end.col), start.row.max(end.row)));
+                Some(if (from.col, from.row) == (to.col, to.row) { from.to_string() } else { format!("{}:{}", from, to) })
+            }
+        };
+        // Protecting without a password needs none; everything else must match the one set
+        if (!protect || password.is_some()) && !self.protection.accepts(password) {
+            self.status_message = match password {
+                Some(_) => "ERROR: WRONG PASSWORD".to_string(),
+                None => "ERROR: PASSWORD REQUIRED".to_string(),
+            };
+            return;
+        }
+        let name = area.clone().unwrap_or_else(|| target.to_uppercase());
+        let changed = match (protect, area) {
+            (true, None) => !std::mem::replace(&mut self.protection.sheet, true),
+            (true, Some(range)) if !self.protection.ranges.contains(&range) => {
+                self.protection.ranges.push(range);
+                true
+            }
+            (true, Some(_)) => false,
+            (false, None) if target == "all" => {
+                self.protection = Protection::default();
+                true
+            }
+            (false, None) => std::mem::replace(&mut self.protection.sheet, false),
+            (false, Some(range)) => {
+                let count = self.protection.ranges.len();
+                self.protection.ranges.retain(|protected| *protected != range);
+                self.protection.ranges.len() < count
+            }
+        };
+        if let Some(password) = password.filter(|_| protect && self.protection.password.is_none()) {
+            self.protection.set_password(password);
+        }
+        if self.protection.is_empty() {
+            self.protection.password = None;
+        }
+        self.modified = true;
+        self.status_message = match (protect, changed) {
+            (true, _) => format!("PROTECTED {}", name),
+            (false, true) => format!("UNPROTECTED {}", name),
+            (false, false) => format!("{} IS NOT PROTECTED", name),
+        };
+    }
+
+    /// Returns whether a row is folded away inside a collapsed row group.
+    pub fn is_row_hidden(&self, row: usize) -> bool {
+        self.row_groups.iter().any(|group| group.collapsed && group.contains(row))
+    }
+
+    /// Parses the rows of a row group as written in commands: `10:40`, or `10` for a single row.
+    ///
+    /// # Returns
+    ///
+    /// The first and last row (zero-based), or `None` if the text is not a span of rows on the sheet.
+    fn parse_row_span(&self, text: &str) -> Option<(usize, usize)> {
+        let (first, last) = text.split_once(':').unwrap_or((text, text));
+        let (first, last) = (first.trim().parse::<usize>().ok()?, last.trim().parse::<usize>().ok()?);
+        let (first, last) = (first.min(last), first.max(last));
+        (first >= 1 && last <= self.max_rows).then(|| (first - 1, last - 1))
+    }
+
+    /// Finds the row groups a `ungroup`, `collapse` or `expand` command is about: every group for `all`,
+    /// the group with exactly the given rows, or with no rows the innermost group at the cursor. Expanding
+    /// at the cursor looks for a collapsed group just above or below it instead, since its rows cannot
+    /// hold the cursor.
+    ///
+    /// # Returns
+    ///
+    /// The indices of the groups, or an error message.
+    fn find_row_groups(&self, args: &str, expanding: bool) -> std::result::Result<Vec<usize>, String> {
+        let row = self.cursor.row;
+        let innermost = |found: &mut dyn Iterator<Item = (usize, &RowGroup)>| {
+            found.min_by_key(|(_, group)| group.last - group.first).map(|(i, _)| vec![i])
+        };
+        match args {
+            "all" => Ok((0..self.row_groups.len()).collect()),
+            "" if expanding => innermost(&mut self.row_groups.iter().enumerate()
+                .filter(|(_, group)| group.collapsed && (group.last + 1 == row || group.first == row + 1)))
+                .ok_or_else(|| format!("NO COLLAPSED ROW GROUP NEXT TO ROW {}", row + 1)),
+            "" => innermost(&mut self.row_groups.iter().enumerate().filter(|(_, group)| group.contains(row)))
+                .ok_or_else(|| format!("NO ROW GROUP AT ROW {}", row + 1)),
+            span => {
+                let (first, last) = self.parse_row_span(span).ok_or_else(|| format!("INVALID ROWS: {}", span))?;
+                self.row_groups.iter().position(|group| (group.first, group.last) == (first, last))
+                    .map(|i| vec![i])
+                    .ok_or_else(|| format!("NO ROW GROUP {}:{}", first + 1, last + 1))
+            }
+        }
+    }
+
+    /// Groups a span of rows so they can be collapsed, or lists the groups. Groups may nest but not
+    /// partly overlap.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The rows to group (`10:40`), or empty to list the groups.
+    fn group_rows(&mut self, args: &str) {
+        if args.is_empty() {
+            self.status_message = if self.row_groups.is_empty() {
+                "NO ROW GROUPS".to_string()
+            } else {
+                format!("ROW GROUPS: {}", self.row_groups.iter().map(|group| {
+                    if group.collapsed { format!("{} (COLLAPSED)", group.describe()) } else { group.describe() }
+                }).collect::<Vec<_>>().join(", "))
+            };
+            return;
+        }
+        if self.is_read_only() {
+            return;
+        }
+        let Some((first, last)) = self.parse_row_span(args) else {
+            self.status_message = format!("INVALID ROWS: {}", args);
+            return;
+        };
+        let group = RowGroup { first, last, collapsed: false };
+        if self.row_groups.iter().any(|other| (other.first, other.last) == (first, last)) {
+            self.status_message = format!("ROWS {} ARE ALREADY GROUPED", group.describe());
+            return;
+        }
+        if let Some(other) = self.row_groups.iter().find(|other| {
+            let nested = (other.first <= first && last <= other.last) || (first <= other.first && other.last <= last);
+            !nested && first <= other.last && other.first <= last
+        }) {
+            self.status_message = format!("ERROR: ROWS {} OVERLAP THE GROUP {}", group.describe(), other.describe());
+            return;
+        }
+        self.status_message = format!("GROUPED ROWS {}", group.describe());
+        // Outer groups come before the groups nested in them
+        let at = self.row_groups.partition_point(|other| (other.first, std::cmp::Reverse(other.last)) < (first, std::cmp::Reverse(last)));
+        self.row_groups.insert(at, group);
+        self.modified = true;
+    }
+
+    /// Removes row groups, showing their rows again.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The rows of the group (`10:40`), `all`, or empty for the innermost group at the cursor.
+    fn ungroup_rows(&mut self, args: &str) {
+        if self.is_read_only() {
+            return;
+        }
+        match self.find_row_groups(args, false) {
+            Ok(found) => {
+                for i in found.into_iter().rev() {
+                    self.row_groups.remove(i);
+                }
+                self.status_message = match args {
+                    "all" => "ALL ROW GROUPS REMOVED".to_string(),
+                    _ => "ROW GROUP REMOVED".to_string(),
+                };
+                self.modified = true;
+            }
+            Err(message) => self.status_message = message,
+        }
+    }
+
+    /// Collapses, expands or toggles row groups. If the cursor ends up on a folded row it moves to
+    /// the next row shown below, or above if there is none.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The rows of the group (`10:40`), `all`, or empty for the group at the cursor.
+    /// * `collapse` - `Some(true)` to collapse, `Some(false)` to expand, `None` to expand a collapsed group
+    ///   next to the cursor or else collapse the group at the cursor.
+    fn fold_rows(&mut self, args: &str, collapse: Option<bool>) {
+        let (found, collapse) = match collapse {
+            Some(collapse) => (self.find_row_groups(args, !collapse), collapse),
+            None => match self.find_row_groups(args, true) {
+                Ok(found) => (Ok(found), false),
+                Err(_) => (self.find_row_groups(args, false), true),
+            },
+        };
+        let found = match found {
+            Ok(found) => found,
+            Err(message) => {
+                self.status_message = message;
+                return;
+            }
+        };
+        for &i in &found {
+            self.row_groups[i].collapsed = collapse;
+        }
+        self.modified = true;
+        self.status_message = match (found.as_slice(), collapse) {
+            ([i], true) => format!("COLLAPSED ROWS {}", self.row_groups[*i].describe()),
+            ([i], false) => format!("EXPANDED ROWS {}", self.row_groups[*i].describe()),
+            (_, true) => format!("{} ROW GROUPS COLLAPSED", found.len()),
+            (_, false) => format!("{} ROW GROUPS EXPANDED", found.len()),
+        };
+        self.reveal_cursor();
+    }
+
+    /// Locks a specific cell, preventing its value from being modified until it is unlocked.
+/// If no address is provided, the currently selected cell (cursor) will be locked.
+///
+/// # Arguments
+///
+/// * `addr` - An optional string slice representing the cell's address to be locked. If not provided,
+///   the currently selected cell is locked.
+///
+/// # Returns
+///
+/// Returns `true` if the cell was successfully locked, or `false` if the cell could not be locked 
+/// (e.g., invalid address).
+    fn lock_cell(&mut self, addr: Option<&str>) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        let addr = if let Some(a) = addr {
+            if let Some(cell_addr) = CellAddress::from_str(a) {
+                cell_addr
+            } else {
+                return false;
+            }
+        } else {
+            self.cursor.clone()
+        };
+        
+        self.begin_action();
+        let changed = if let Some(cell) = self.get_cell_mut(&addr) {
+            cell.is_locked = true;
+            self.status_message = "CELL LOCKED".to_string();
+            true
+        } else {
+            false
+        };
+        self.commit_action();
+        changed
+    }
+/// Unlocks a specific cell, allowing its value to be modified. If no address is provided, 
+/// the currently selected cell (cursor) will be unlocked.
+///
+/// # Arguments
+///
+/// * `addr` - An optional string slice representing the cell's address to be unlocked. If not provided,
+///   the currently selected cell is unlocked.
+///
+/// # Returns
+///
+/// Returns `true` if the cell was successfully unlocked, or `false` if the cell could not be unlocked 
+/// (e.g., invalid address).
+    fn unlock_cell(&mut self, addr: Option<&str>) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        let addr = if let Some(a) = addr {
+            if let Some(cell_addr) = CellAddress::from_str(a) {
+                cell_addr
+            } else {
+                return false;
+            }
+        } else {
+            self.cursor.clone()
+        };
+        
+        self.begin_action();
+        let changed = if let Some(cell) = self.get_cell_mut(&addr) {
+            cell.is_locked = false;
+            self.status_message = "CELL UNLOCKED".to_string();
+            true
+        } else {
+            false
+        };
+        self.commit_action();
+        changed
+    }
+/// Registers an alert rule on a cell. The rule is checked after every recalculation pass and
+/// fires a highlighted notification when its condition becomes true.
+///
+/// # Arguments
+///
+/// * `addr` - The address of the cell to watch (e.g., "B14").
+/// * `op` - The comparison operator (`>`, `>=`, `<`, `<=`, `==`, `!=`).
+/// * `threshold` - The value to compare the cell against.
+/// * `message` - The notification text shown when the alert fires.
+/// * `bell` - Whether to ring the terminal bell when the alert fires.
+///
+/// # Returns
+///
+/// Returns `true` if the rule was added, or `false` if the address or operator is invalid.
+    fn add_alert(&mut self, addr: &str, op: &str, threshold: f64, message: &str, bell: bool) -> bool {
+        let cell = match CellAddress::from_str(addr) {
+            Some(cell_addr) if self.get_cell(&cell_addr).is_some() => cell_addr.to_string(),
+            _ => return false,
+        };
+        let comparison = match Comparison::from_str(op) {
+            Some(c) => c,
+            None => return false,
+        };
+        let message = if message.is_empty() {
+            format!("{} {} {}", cell, comparison.symbol(), threshold)
+        } else {
+            message.to_string()
+        };
+        self.alerts.push(AlertRule {
+            cell,
+            comparison,
+            threshold,
+            action: RuleAction::Notify { message, bell },
+            triggered: false,
+        });
+        // Evaluate once so a rule that already holds is reported immediately
+        self.check_alerts();
+        if self.alert_notice.is_none() {
+            self.status_message = "ALERT ADDED".to_string();
+        }
+        true
+    }
+/// Adds a haunt rule: while its condition holds, haunt mode is on and `weight` is added to the
+/// haunt intensity, so the sheet gets spookier the more rules hold. Uses the same rules engine as
+/// `add_alert`; `subject` may also be `ERRORS`, the number of cells holding an error value.
+///
+/// # Arguments
+///
+/// * `subject` - The cell to test (e.g., "B14") or `ERRORS`.
+/// * `op` - The comparison operator (`>`, `>=`, `<`, `<=`, `==`, `!=`).
+/// * `threshold` - The value to compare against.
+/// * `weight` - How much the rule adds to the intensity (levels 1 to 3).
+///
+/// # Returns
+///
+/// Returns `true` if the rule was added, or `false` if the subject or operator is invalid.
+    fn add_haunt_rule(&mut self, subject: &str, op: &str, threshold: f64, weight: u8) -> bool {
+        let cell = if subject.eq_ignore_ascii_case(ERROR_COUNT_SUBJECT) {
+            ERROR_COUNT_SUBJECT.to_string()
+        } else {
+            match CellAddress::from_str(subject) {
+                Some(cell_addr) if self.get_cell(&cell_addr).is_some() => cell_addr.to_string(),
+                _ => return false,
+            }
+        };
+        let comparison = match Comparison::from_str(op) {
+            Some(c) => c,
+            None => return false,
+        };
+        self.alerts.push(AlertRule {
+            cell,
+            comparison,
+            threshold,
+            action: RuleAction::Haunt { weight },
+            triggered: false,
+        });
+        self.status_message = "HAUNT RULE ADDED".to_string();
+        self.check_alerts();
+        true
+    }
+/// Returns the addresses of all cells holding an error, either a recorded formula error (shown as `ERR`)
+/// or an error value such as `#TIMEOUT!`, sorted.
+    fn error_cells(&self) -> Vec<String> {
+        let mut addrs: Vec<CellAddress> = self.data.iter()
+            .filter(|(_, cell)| cell.error.is_some() || cell.display_value.starts_with('#'))
+            .filter_map(|(addr, _)| CellAddress::from_str(addr))
+            .collect();
+        addrs.sort_by_key(|addr| (addr.col, addr.row));
+        addrs.iter().map(|addr| addr.to_string()).collect()
+    }
+/// Checks every alert rule against the current cell values. A notify rule fires when its condition
+/// changes from false to true; the newest fired message becomes the highlighted alert notice.
+/// Haunt rules that hold add up to the haunt intensity and name the offending cells for the whispers.
+///
+/// Cells whose value is not numeric never satisfy an alert condition.
+    fn check_alerts(&mut self) {
+        let error_cells = self.error_cells();
+        let mut fired = None;
+        let mut haunt_weight: u32 = 0;
+        let mut haunt_cells = Vec::new();
+        let mut has_haunt_rules = false;
+        for rule in self.alerts.iter_mut() {
+            let value = if rule.cell == ERROR_COUNT_SUBJECT {
+                Some(error_cells.len() as f64)
+            } else {
+                self.data.get(&rule.cell).unwrap_or(&self.blank_cell).display_value.parse::<f64>().ok()
+            };
+            let holds = value.is_some_and(|value| rule.comparison.holds(value, rule.threshold));
+            match &rule.action {
+                RuleAction::Notify { message, bell } => {
+                    if holds && !rule.triggered {
+                        fired = Some((rule.cell.clone(), message.clone()));
+                        if *bell {
+                            self.ring_bell = true;
+                        }
                     }
-                } else if formula.starts_with("log(") {
-                    let arg = formula.strip_prefix("log(").unwrap().strip_suffix(')').unwrap();
-                    if let Ok(value) = arg.parse::<f64>() {
-                        value.ln()
-                    } else if let Some(addr) = CellAddress::from_str(arg) {
-                        if let Some(cell) = self.get_cell(&addr) {
-                            if let Ok(value) = cell.display_value.parse::<f64>() {
-                                value.ln()
-                            } else {
-                                0.0
-                            }
+                }
+                RuleAction::Haunt { weight } => {
+                    has_haunt_rules = true;
+                    if holds {
+                        haunt_weight += *weight as u32;
+                        if rule.cell == ERROR_COUNT_SUBJECT {
+                            haunt_cells.extend(error_cells.iter().cloned());
                         } else {
-                            0.0
+                            haunt_cells.push(rule.cell.clone());
                         }
-                    } else {
-                        0.0
                     }
-                } else if formula.starts_with("(") && formula.ends_with(")") {
-                    let inside_brackets = &formula[1..formula.len() - 1];
-                    
-                    if let Some(addr) = CellAddress::from_str(inside_brackets) {
-                        // Simple cell reference like =(A1)
-                        println!("DEBUG: Found simple cell reference in formula");
-                        if let Some(cell) = self.get_cell(&addr) {
-                            if let Ok(value) = cell.display_value.parse::<f64>() {
-                                value
-                            } else {
-                                0.0
-                            }
-                        } else {
-                            0.0
-                        }
-                    } else if inside_brackets.contains('+') || inside_brackets.contains('-') || inside_brackets.contains('*') {
-                        // Arithmetic expression like =(A1+B1) or =(A1+1)
-                        println!("DEBUG: Found arithmetic expression in formula: {}", inside_brackets);
-                        
-                        // Find the operator and its position
-                        let mut operator = '+';  // Default
-                        let mut operator_pos = 0;
-                        
-                        for (i, c) in inside_brackets.chars().enumerate() {
-                            if c == '+' || c == '-' || c == '*' {
-                                operator = c;
-                                operator_pos = i;
-                                break;
-                            }
+                }
+            }
+            rule.triggered = holds;
+        }
+        if let Some((cell, message)) = fired {
+            self.alert_notice = Some(format!("ALERT {}: {}", cell, message));
+        }
+        if has_haunt_rules || self.haunt_by_rules {
+            self.apply_haunt_rules(haunt_weight, haunt_cells);
+        }
+    }
+/// Drives haunt mode from the haunt rules: it starts (silently, without the timed jump scare) when
+/// the first rule holds, follows the summed rule weight as its corruption level, and ends again when
+/// no rule holds. A haunting started with `:haunt` is never ended by the rules.
+///
+/// # Arguments
+///
+/// * `weight` - The summed weight of all haunt rules that currently hold.
+/// * `cells` - The cells behind those rules, referenced by the whispers.
+    fn apply_haunt_rules(&mut self, weight: u32, mut cells: Vec<String>) {
+        cells.sort();
+        cells.dedup();
+        self.haunt_cells = cells;
+        if weight > 0 {
+            if !self.haunted {
+                self.haunted = true;
+                self.haunt_by_rules = true;
+            }
+            if self.haunt_by_rules {
+                self.corruption_level = weight.min(3) as u8;
+            }
+        } else if self.haunt_by_rules {
+            self.haunted = false;
+            self.haunt_by_rules = false;
+            self.corruption_level = 0;
+        }
+    }
+/// Swaps the state of the active sheet with the sheet stored at `index` in `sheets`.
+    fn swap_sheet_state(&mut self, index: usize) {
+        let state = &mut self.sheets[index];
+        std::mem::swap(&mut self.data, &mut state.data);
+        std::mem::swap(&mut self.dependents, &mut state.dependents);
+        std::mem::swap(&mut self.dependencies, &mut state.dependencies);
+        std::mem::swap(&mut self.undo_stack, &mut state.undo_stack);
+        std::mem::swap(&mut self.redo_stack, &mut state.redo_stack);
+        std::mem::swap(&mut self.cursor, &mut state.cursor);
+        std::mem::swap(&mut self.start_row, &mut state.start_row);
+        std::mem::swap(&mut self.start_col, &mut state.start_col);
+        std::mem::swap(&mut self.followed_cursor, &mut state.followed_cursor);
+        std::mem::swap(&mut self.max_rows, &mut state.max_rows);
+        std::mem::swap(&mut self.max_cols, &mut state.max_cols);
+        std::mem::swap(&mut self.watch_list, &mut state.watch_list);
+        std::mem::swap(&mut self.frozen_rows, &mut state.frozen_rows);
+        std::mem::swap(&mut self.frozen_cols, &mut state.frozen_cols);
+        std::mem::swap(&mut self.protection, &mut state.protection);
+        std::mem::swap(&mut self.pivots, &mut state.pivots);
+        std::mem::swap(&mut self.row_groups, &mut state.row_groups);
+    }
+/// Makes the sheet at `index` the active one, storing the state of the current sheet in its slot.
+/// Find matches and a pending replacement belong to the old sheet and are dropped.
+    fn switch_sheet(&mut self, index: usize) {
+        if index == self.active_sheet {
+            return;
+        }
+        // The queued and stale cells belong to the current sheet
+        self.finish_recalculation();
+        self.evaluate_stale(None, None);
+        self.swap_sheet_state(self.active_sheet);
+        self.swap_sheet_state(index);
+        self.active_sheet = index;
+        self.find_matches.clear();
+        self.current_find_match = 0;
+        self.replace_state = None;
+        self.needs_full_redraw = true;
+    }
+/// Returns the index of the sheet with the given name, ignoring case.
+    fn sheet_index(&self, name: &str) -> Option<usize> {
+        self.sheets.iter().position(|sheet| sheet.name.eq_ignore_ascii_case(name))
+    }
+/// Checks that `name` can be used for a new or renamed sheet: it must be a single word that is not a
+/// `sheet` subcommand and not already taken by another sheet.
+///
+/// # Arguments
+///
+/// * `name` - The proposed name.
+/// * `renaming` - The index of the sheet being renamed, which may keep its name in a different case.
+///
+/// # Returns
+///
+/// `Ok(())` if the name is free, or the status message explaining why it is not.
+    fn check_sheet_name(&self, name: &str, renaming: Option<usize>) -> std::result::Result<(), String> {
+        if name.is_empty() || name.chars().any(char::is_whitespace) {
+            Err("SHEET NAMES MUST BE A SINGLE WORD".to_string())
+        } else if SHEET_SUBCOMMANDS.iter().any(|sub| sub.eq_ignore_ascii_case(name)) {
+            Err(format!("'{}' IS RESERVED FOR A SHEET COMMAND", name))
+        } else if self.sheet_index(name).is_some_and(|index| Some(index) != renaming) {
+            Err(format!("SHEET '{}' ALREADY EXISTS", name))
+        } else {
+            Ok(())
+        }
+    }
+/// Returns the first free name of the form `<base><n>`, counting up from `n = start`.
+    fn unused_sheet_name(&self, base: &str, start: usize) -> String {
+        (start..).map(|n| format!("{}{}", base, n)).find(|name| self.sheet_index(name).is_none()).unwrap()
+    }
+/// Runs a `sheet` command, which manages the sheets shown as tabs below the grid.
+///
+/// # Arguments
+///
+/// * `args` - Everything after `sheet`: empty or `list` to list the sheets, `new [name]`, `rename [old] <new>`,
+///   `copy [name]`, `delete [name]`, `next`, `prev`, or the name of the sheet to switch to.
+    fn sheet_command(&mut self, args: &str) {
+        if self.is_store_backed() {
+            self.status_message = "SHEETS ARE NOT AVAILABLE FOR DATABASE-BACKED SHEETS".to_string();
+            return;
+        }
+        let words: Vec<&str> = args.split_whitespace().collect();
+        // The session edits the sheet that was shared; the others cannot be switched to until it is left
+        if self.session.is_some() && !matches!(words.as_slice(), [] | ["list"]) {
+            self.status_message = "ERROR: LEAVE THE SHARED SESSION (unshare) BEFORE CHANGING SHEETS".to_string();
+            return;
+        }
+        let changes_workbook = matches!(words.first(), Some(&"new" | &"rename" | &"copy" | &"delete"));
+        if changes_workbook && self.is_read_only() {
+            return;
+        }
+        let count = self.sheets.len();
+        match words.as_slice() {
+            [] | ["list"] => {
+                let names: Vec<String> = self.sheets.iter().enumerate()
+                    .map(|(i, sheet)| if i == self.active_sheet { format!("[{}]", sheet.name) } else { sheet.name.clone() })
+                    .collect();
+                self.status_message = format!("SHEETS: {}", names.join(" "));
+            }
+            ["next"] | ["prev"] => {
+                let step = if words[0] == "next" { 1 } else { count - 1 };
+                self.switch_sheet((self.active_sheet + step) % count);
+                self.status_message = format!("SHEET '{}'", self.sheets[self.active_sheet].name);
+            }
+            ["new"] | ["new", _] => {
+                let name = words.get(1).map_or_else(|| self.unused_sheet_name("Sheet", count + 1), |name| name.to_string());
+                if let Err(message) = self.check_sheet_name(&name, None) {
+                    self.status_message = message;
+                    return;
+                }
+                self.sheets.insert(self.active_sheet + 1, SheetState::blank(&name, self.max_rows, self.max_cols));
+                self.switch_sheet(self.active_sheet + 1);
+                self.status_message = format!("CREATED SHEET '{}'", name);
+            }
+            ["rename", new] | ["rename", _, new] => {
+                let index = match (words.len() == 3).then(|| words[1]) {
+                    Some(old) => match self.sheet_index(old) {
+                        Some(index) => index,
+                        None => {
+                            self.status_message = format!("NO SHEET NAMED '{}'", old);
+                            return;
                         }
-                        
-                        let left_part = &inside_brackets[0..operator_pos].trim();
-                        let right_part = &inside_brackets[operator_pos+1..].trim();
-                        
-                        // Evaluate left operand
-                        let left_value = if let Some(addr) = CellAddress::from_str(left_part) {
-                            if let Some(cell) = self.get_cell(&addr) {
-                                cell.display_value.parse::<f64>().unwrap_or(0.0)
-                            } else {
-                                0.0
-                            }
-                        } else {
-                            left_part.parse::<f64>().unwrap_or(0.0)
-                        };
-                        
-                        // Evaluate right operand
-                        let right_value = if let Some(addr) = CellAddress::from_str(right_part) {
-                            if let Some(cell) = self.get_cell(&addr) {
-                                cell.display_value.parse::<f64>().unwrap_or(0.0)
-                            } else {
-                                0.0
-                            }
-                        } else {
-                            right_part.parse::<f64>().unwrap_or(0.0)
-                        };
-                        
-                        // Perform the operation
-                        match operator {
-                            '+' => left_value + right_value,
-                            '-' => left_value - right_value,
-                            '*' => left_value * right_value,
-                            _ => 0.0  // Should not reach here due to validation
+                    },
+                    None => self.active_sheet,
+                };
+                if let Err(message) = self.check_sheet_name(new, Some(index)) {
+                    self.status_message = message;
+                    return;
+                }
+                let old = std::mem::replace(&mut self.sheets[index].name, new.to_string());
+                self.status_message = format!("RENAMED SHEET '{}' TO '{}'", old, new);
+            }
+            ["copy"] | ["copy", _] => {
+                let name = words.get(1).map_or_else(|| self.unused_sheet_name(&format!("{}_", self.sheets[self.active_sheet].name), 2), |name| name.to_string());
+                if let Err(message) = self.check_sheet_name(&name, None) {
+                    self.status_message = message;
+                    return;
+                }
+                // The copy starts with its own, empty undo history
+                let copy = SheetState {
+                    name: name.clone(),
+                    data: self.data.clone(),
+                    dependents: self.dependents.clone(),
+                    dependencies: self.dependencies.clone(),
+                    undo_stack: VecDeque::new(),
+                    redo_stack: VecDeque::new(),
+                    cursor: self.cursor.clone(),
+                    start_row: self.start_row,
+                    start_col: self.start_col,
+                    followed_cursor: self.followed_cursor,
+                    max_rows: self.max_rows,
+                    max_cols: self.max_cols,
+                    watch_list: self.watch_list.clone(),
+                    frozen_rows: self.frozen_rows,
+                    frozen_cols: self.frozen_cols,
+                    protection: self.protection.clone(),
+                    pivots: self.pivots.clone(),
+                    row_groups: self.row_groups.clone(),
+                };
+                self.sheets.insert(self.active_sheet + 1, copy);
+                self.switch_sheet(self.active_sheet + 1);
+                self.status_message = format!("COPIED SHEET TO '{}'", name);
+            }
+            ["delete"] | ["delete", _] => {
+                let index = match words.get(1) {
+                    Some(name) => match self.sheet_index(name) {
+                        Some(index) => index,
+                        None => {
+                            self.status_message = format!("NO SHEET NAMED '{}'", name);
+                            return;
                         }
-                    } else {
-                        println!("DEBUG: Invalid content in brackets: {}", inside_brackets);
-                        0.0
+                    },
+                    None => self.active_sheet,
+                };
+                if count == 1 {
+                    self.status_message = "CANNOT DELETE THE ONLY SHEET".to_string();
+                    return;
+                }
+                if index == self.active_sheet {
+                    self.switch_sheet(if index + 1 < count { index + 1 } else { index - 1 });
+                }
+                let removed = self.sheets.remove(index);
+                if index < self.active_sheet {
+                    self.active_sheet -= 1;
+                }
+                self.status_message = format!("DELETED SHEET '{}'", removed.name);
+            }
+            [name] => match self.sheet_index(name) {
+                Some(index) => {
+                    self.switch_sheet(index);
+                    self.status_message = format!("SHEET '{}'", self.sheets[index].name);
+                }
+                None => self.status_message = format!("NO SHEET NAMED '{}'", name),
+            },
+            _ => self.status_message = "USAGE: sheet [list|new [name]|rename [old] <new>|copy [name]|delete [name]|next|prev|<name>]".to_string(),
+        }
+    }
+/// Returns the rows shown in the grid: the frozen rows, then as many rows from the scroll position as
+/// fit in the remaining `view_rows`. Rows in collapsed groups are skipped.
+    fn visible_rows(&self) -> Vec<usize> {
+        let start = self.start_row.max(self.frozen_rows);
+        (0..self.frozen_rows).chain(start..self.max_rows).filter(|&row| !self.is_row_hidden(row)).take(self.view_rows).collect()
+    }
+/// Returns the columns shown in the grid: the frozen columns, then as many columns from the scroll
+/// position as fit in the remaining `view_cols`.
+    fn visible_cols(&self) -> Vec<usize> {
+        let start = self.start_col.max(self.frozen_cols);
+        (0..self.frozen_cols).chain(start..).take(self.view_cols).take_while(|&col| col < self.max_cols).collect()
+    }
+/// Returns the width a column is drawn with: wide enough for its letters and for the widest of the
+/// given rows' cells, and at least 5 characters.
+    fn column_width(&self, col: usize, rows: &[usize]) -> usize {
+        rows.iter()
+            .filter_map(|&row| self.get_cell(&CellAddress::new(col, row)))
+            .map(|cell| cell.width)
+            .fold(CellAddress::col_to_letters(col).len().max(5), usize::max)
+    }
+/// Returns how many terminal lines a row is drawn with: the height of its tallest cell among the
+/// given columns, and at least 1.
+    fn row_height(&self, row: usize, cols: &[usize]) -> usize {
+        cols.iter()
+            .filter_map(|&col| self.get_cell(&CellAddress::new(col, row)))
+            .map(|cell| cell.height)
+            .fold(1, usize::max)
+    }
+/// Returns a horizontal line of the `grid` display: blank under the row labels, then `fill` across each
+/// column, with `junctions` at the borders (left end, inner border, border after the frozen columns,
+/// right end).
+    fn grid_rule(&self, col_widths: &[usize], fill: char, junctions: [char; 4]) -> String {
+        let mut rule = " ".repeat(ROW_LABEL_WIDTH);
+        for (col_idx, width) in col_widths.iter().enumerate() {
+            rule.push(match col_idx {
+                0 => junctions[0],
+                i if i == self.frozen_cols => junctions[2],
+                _ => junctions[1],
+            });
+            rule.push_str(&fill.to_string().repeat(*width));
+        }
+        rule.push(junctions[3]);
+        rule
+    }
+/// Fits `view_rows` and `view_cols` to the terminal: rows are added while their heights fit the lines
+/// not needed around the grid, and columns are added while their drawn widths fit the terminal width. At least one scrolling
+/// row and column is always shown, even on a tiny terminal.
+    fn fit_view_to_terminal(&mut self) {
+        let Some((term_cols, term_rows)) = self.terminal_size.or_else(|| terminal::size().ok()) else {
+            return;
+        };
+        // Grid lines draw the frozen row divider in place of a row separator, not on an extra line
+        let frozen_divider = self.frozen_rows > 0 && !self.grid_lines;
+        let reserved = SCREEN_RESERVED_LINES + frozen_divider as usize + (!self.watch_list.is_empty()) as usize;
+        let lines = (term_rows as usize).saturating_sub(reserved);
+        self.fit_view_rows(lines);
+
+        let rows = self.visible_rows();
+        let start = self.start_col.max(self.frozen_cols);
+        // The frozen column divider, or the right border of the grid lines
+        let mut used = ROW_LABEL_WIDTH + (self.frozen_cols > 0 || self.grid_lines) as usize;
+        let mut count = 0;
+        for col in (0..self.frozen_cols).chain(start..self.max_cols) {
+            let width = self.column_width(col, &rows) + 1;
+            if count > self.frozen_cols && used + width > term_cols as usize {
+                break;
+            }
+            used += width;
+            count += 1;
+        }
+        self.view_cols = count.max(self.frozen_cols + 1);
+        // Row heights depend on which columns are shown
+        self.fit_view_rows(lines);
+    }
+/// Fits the view to the terminal and scrolls it to the cursor. Column widths depend on the scroll
+/// position, so the view is fitted again after following the cursor.
+    fn fit_view(&mut self) {
+        self.fit_view_to_terminal();
+        self.follow_cursor();
+        self.fit_view_to_terminal();
+    }
+/// Sets `view_rows` to the number of shown rows, starting with the frozen ones, whose heights fit in
+/// `lines` terminal lines (at least one scrolling row). With grid lines every row also takes the
+/// line above it, and one more line closes the grid.
+    fn fit_view_rows(&mut self, lines: usize) {
+        let rule = self.grid_lines as usize;
+        let lines = lines.saturating_sub(rule);
+        let start = self.start_row.max(self.frozen_rows);
+        let cols = self.visible_cols();
+        let mut used = 0;
+        let mut count = 0;
+        let mut filled = false;
+        for row in (0..self.frozen_rows).chain(start..self.max_rows).filter(|&row| !self.is_row_hidden(row)) {
+            let height = self.row_height(row, &cols) + rule;
+            if count > self.frozen_rows && used + height > lines {
+                filled = true;
+                break;
+            }
+            used += height;
+            count += 1;
+        }
+        // Lines left below the last row count as rows of height 1, so scrolling works as before
+        if !filled {
+            count += lines.saturating_sub(used) / (1 + rule);
+        }
+        self.view_rows = count.max(self.frozen_rows + 1);
+    }
+/// Scrolls the view just far enough that the cursor is on screen, if it moved since the last call.
+/// Scrolling with `wasd` leaves the cursor where it is, so the view only follows actual cursor moves
+/// (keys, jumps, searches, marks) and does not snap back after a manual scroll.
+    fn follow_cursor(&mut self) {
+        let cursor = (self.cursor.col, self.cursor.row);
+        if Some(cursor) == self.followed_cursor {
+            return;
+        }
+        self.followed_cursor = Some(cursor);
+        // Frozen rows and columns are always visible; the rest scroll within a page
+        let scrolled = |start: usize, pos: usize, frozen: usize, page: usize| {
+            if pos < frozen {
+                start
+            } else if pos < start.max(frozen) {
+                pos
+            } else if pos >= start.max(frozen) + page {
+                pos + 1 - page
+            } else {
+                start
+            }
+        };
+        self.start_row = scrolled(self.start_row, self.cursor.row, self.frozen_rows, self.view_rows - self.frozen_rows);
+        self.start_col = scrolled(self.start_col, self.cursor.col, self.frozen_cols, self.view_cols - self.frozen_cols);
+        // Tall rows below the cursor can leave fewer rows on screen than the page assumed
+        self.fit_view_to_terminal();
+        while self.cursor.row >= self.frozen_rows && !self.visible_rows().contains(&self.cursor.row)
+            && self.start_row < self.cursor.row {
+            self.start_row = self.start_row.max(self.frozen_rows) + 1;
+            self.fit_view_to_terminal();
+        }
+    }
+/// Keeps the first `rows` rows and `cols` columns on screen while the rest of the grid scrolls.
+/// At least one row and column of the view is left to scroll; `0 0` unfreezes.
+///
+/// # Returns
+///
+/// `true` if the panes were changed, or `false` (with a status message) if they would not leave a
+/// row and column to scroll.
+    fn freeze_panes(&mut self, rows: usize, cols: usize) -> bool {
+        if rows >= self.view_rows || cols >= self.view_cols || rows >= self.max_rows || cols >= self.max_cols {
+            self.status_message = "FROZEN PANES MUST LEAVE A ROW AND COLUMN TO SCROLL".to_string();
+            return false;
+        }
+        self.frozen_rows = rows;
+        self.frozen_cols = cols;
+        self.needs_full_redraw = true;
+        self.status_message = if rows == 0 && cols == 0 {
+            "PANES UNFROZEN".to_string()
+        } else {
+            format!("FROZE {} ROWS AND {} COLUMNS", rows, cols)
+        };
+        true
+    }
+/// Adds a cell to the watch panel so its live value stays visible while scrolling.
+/// If no address is provided, the currently selected cell (cursor) is watched.
+///
+/// # Arguments
+///
+/// * `addr` - An optional string slice representing the cell's address to watch. If not provided,
+///   the currently selected cell is used.
+///
+/// # Returns
+///
+/// Returns `true` if the cell is now on the watch list, or `false` if the address is invalid.
+    fn watch_cell(&mut self, addr: Option<&str>) -> bool {
+        let addr = if let Some(a) = addr {
+            if let Some(cell_addr) = CellAddress::from_str(a) {
+                cell_addr
+            } else {
+                return false;
+            }
+        } else {
+            self.cursor.clone()
+        };
+
+        if self.get_cell(&addr).is_none() {
+            return false;
+        }
+        let addr_str = addr.to_string();
+        if !self.watch_list.contains(&addr_str) {
+            self.watch_list.push(addr_str.clone());
+        }
+        self.status_message = format!("WATCHING {}", addr_str);
+        true
+    }
+/// Removes a cell from the watch panel. If no address is provided, the currently selected
+/// cell (cursor) is removed.
+///
+/// # Arguments
+///
+/// * `addr` - An optional string slice representing the cell's address. If not provided,
+///   the currently selected cell is used.
+///
+/// # Returns
+///
+/// Returns `true` if the cell was on the watch list and has been removed, otherwise `false`.
+    fn unwatch_cell(&mut self, addr: Option<&str>) -> bool {
+        let addr_str = if let Some(a) = addr {
+            if let Some(cell_addr) = CellAddress::from_str(a) {
+                cell_addr.to_string()
+            } else {
+                return false;
+            }
+        } else {
+            self.cursor.to_string()
+        };
+
+        let before = self.watch_list.len();
+        self.watch_list.retain(|w| *w != addr_str);
+        if self.watch_list.len() == before {
+            return false;
+        }
+        self.status_message = format!("STOPPED WATCHING {}", addr_str);
+        true
+    }
+/// Sets the alignment of a specific cell. The alignment can be set to left, right, or center.
+/// If no address is provided, the currently selected cell (cursor) will be modified.
+///
+/// # Arguments
+///
+/// * `addr` - An optional string slice representing the cell's address. If not provided,
+///   the currently selected cell is used.
+/// * `align` - A string that specifies the alignment. Possible values are:
+///   - `"l"` for left alignment
+///   - `"r"` for right alignment
+///   - `"c"` for center alignment
+///
+/// # Returns
+///
+/// Returns `true` if the alignment was successfully changed, or `false` if the address is invalid,
+/// the cell is locked, or the alignment value is invalid.
+    fn set_alignment(&mut self, addr: Option<&str>, align: &str) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        let addr = if let Some(a) = addr {
+            if let Some(cell_addr) = CellAddress::from_str(a) {
+                cell_addr
+            } else {
+                return false;
+            }
+        } else {
+            self.cursor.clone()
+        };
+        
+        let alignment = match align {
+            "l" => Alignment::Left,
+            "r" => Alignment::Right,
+            "c" => Alignment::Center,
+            _ => return false,
+        };
+        
+        if self.get_cell(&addr).is_some_and(|cell| cell.is_locked) {
+            self.status_message = format!("ERROR: CELL {} LOCKED", addr);
+            return false;
+        }
+        self.begin_action();
+        let changed = if let Some(cell) = self.get_cell_mut(&addr) {
+            cell.alignment = alignment;
+            self.status_message = "ALIGNMENT CHANGED".to_string();
+            true
+        } else {
+            false
+        };
+        self.commit_action();
+        changed
+    }
+/// Changes the colors or text style of every cell in a range as one undoable action; locked cells
+/// are skipped.
+///
+/// # Arguments
+///
+/// * `range_str` - A range (e.g., "A1:C1") or a single cell address, or `None` for the current cell.
+/// * `apply` - The change to make to each cell.
+///
+/// # Returns
+///
+/// Returns `true` if the style was applied, or `false` if the range is invalid or the sheet is read-only.
+    fn set_style(&mut self, range_str: Option<&str>, apply: impl Fn(&mut Cell)) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        let (start, end) = match range_str.map(|r| r.trim_start_matches('[').trim_end_matches(']')) {
+            None => (self.cursor.clone(), self.cursor.clone()),
+            Some(range_str) => match self.parse_range(range_str) {
+                Some(range) => range,
+                None => match CellAddress::from_str(range_str) {
+                    Some(addr) => (addr.clone(), addr),
+                    None => return false,
+                },
+            },
+        };
+        self.begin_action();
+        for col in start.col.min(end.col)..=start.col.max(end.col) {
+            for row in start.row.min(end.row)..=start.row.max(end.row) {
+                let addr = CellAddress::new(col, row);
+                if self.get_cell(&addr).is_none_or(|cell| cell.is_locked) {
+                    continue;
+                }
+                if let Some(cell) = self.get_cell_mut(&addr) {
+                    apply(cell);
+                }
+            }
+        }
+        self.commit_action();
+        self.status_message = "STYLE CHANGED".to_string();
+        true
+    }
+/// Sets (or clears) the display format of every cell in a range. When a date format is applied,
+/// cells that already contain date text are converted to date serial numbers so they can be
+/// formatted and used in calculations. The change is recorded as one undoable action; locked
+/// cells are skipped.
+///
+/// # Arguments
+///
+/// * `range_str` - A range (e.g., "D2:D100") or a single cell address.
+/// * `format` - The new format (e.g., `date:%d.%m.%Y`), or `None` to show plain values again.
+///
+/// # Returns
+///
+/// Returns `true` if the format was applied, or `false` if the range is invalid.
+    fn set_format(&mut self, range_str: &str, format: Option<String>) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        let range_str = range_str.trim_start_matches('[').trim_end_matches(']');
+        let (start, end) = match self.parse_range(range_str) {
+            Some(range) => range,
+            None => match CellAddress::from_str(range_str) {
+                Some(addr) => (addr.clone(), addr),
+                None => return false,
+            },
+        };
+        self.begin_action();
+
+        let mut converted = Vec::new();
+        for col in start.col.min(end.col)..=start.col.max(end.col) {
+            for row in start.row.min(end.row)..=start.row.max(end.row) {
+                let addr = CellAddress::new(col, row);
+                if let Some(cell) = self.get_cell_mut(&addr) {
+                    if cell.is_locked {
+                        continue;
+                    }
+                    cell.format = format.clone();
+                    let typed = cell.formula.is_none() && cell.raw_value.parse::<f64>().is_err();
+                    if let Some(serial) = cell.date_pattern().filter(|_| typed).and_then(|p| parse_date_input(&cell.raw_value, p)) {
+                        converted.push((addr, serial.to_string()));
+                    }
+                }
+            }
+        }
+        // Re-enter converted dates so dependent formulas see the serial numbers
+        for (addr, serial) in converted {
+            self.update_cell(&addr, &serial, true);
+        }
+        self.commit_action();
+        self.status_message = "FORMAT CHANGED".to_string();
+        true
+    }
+/// Sets the height and width for a specific cell. If no address is provided, the currently selected 
+/// cell (cursor) will be modified. The height and width can be adjusted independently.
+///
+/// # Arguments
+///
+/// * `addr` - An optional string slice representing the cell's address. If not provided,
+///   the currently selected cell is used.
+/// * `height` - An optional `usize` representing the height of the cell. If not provided, the height
+///   will not be changed.
+/// * `width` - An optional `usize` representing the width of the cell. If not provided, the width
+///   will not be changed.
+///
+/// # Returns
+///
+/// Returns `true` if the dimension was successfully changed, or `false` if the address is invalid,
+/// the cell is locked, or invalid dimensions were provided.
+    fn set_dimension(&mut self, addr: Option<&str>, height: Option<usize>, width: Option<usize>) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        let addr = if let Some(a) = addr {
+            if let Some(cell_addr) = CellAddress::from_str(a) {
+                cell_addr
+            } else {
+                return false;
+            }
+        } else {
+            self.cursor.clone()
+        };
+        if self.get_cell(&addr).is_some_and(|cell| cell.is_locked) {
+            self.status_message = format!("ERROR: CELL {} LOCKED", addr);
+            return false;
+        }
+        self.begin_action();
+        let changed = if let Some(cell) = self.get_cell_mut(&addr) {
+            if let Some(h) = height {
+                cell.height = h;
+            }
+            
+            if let Some(w) = width {
+                cell.width = w;
+            }
+            
+            self.status_message = "DIMENSION CHANGED".to_string();
+            true
+        } else {
+            false
+        };
+        self.commit_action();
+        changed
+    }
+/// Searches for a query string within all cells in the spreadsheet. If any cells contain the query,
+/// their addresses will be stored as matches.
+///
+/// A query wrapped in slashes (e.g., `/^4[0-9]+$/`) is treated as a regular expression; anything else
+/// is matched as a literal substring. The text each cell is matched against depends on `find_scope`:
+/// its displayed value, its raw formula (e.g., `=SUM(B7:B9)`), or its address. When searching
+/// addresses, a query that is itself an address (e.g., `ab12`) finds only that cell, not `AB120`.
+///
+/// # Arguments
+///
+/// * `query` - The string (or `/regex/`) to search for in the cell values.
+///
+/// # Returns
+///
+/// Returns `true` if one or more matches are found, and sets the cursor to the first match. 
+/// Returns `false` if no matches are found or the regular expression is invalid.
+    pub fn find(&mut self, query: &str) -> bool {
+        self.find_matches.clear();
+        self.find_query = query.to_string();
+
+        let pattern = if query.len() >= 2 && query.starts_with('/') && query.ends_with('/') {
+            match regex::Regex::new(&query[1..query.len() - 1]) {
+                Ok(re) => Some(re),
+                Err(_) => {
+                    self.status_message = "INVALID REGEX".to_string();
+                    return false;
+                }
+            }
+        } else {
+            None
+        };
+        let address = match self.find_scope {
+            SearchScope::Addresses => CellAddress::from_str(query).map(|addr| addr.to_string()),
+            _ => None,
+        };
+        
+        // Search for matches
+        for col in 0..self.max_cols {
+            for row in 0..self.max_rows {
+                let addr = CellAddress::new(col, row);
+                if let Some(cell) = self.get_cell(&addr) {
+                    let text = match self.find_scope {
+                        SearchScope::Values => cell.display_value.clone(),
+                        SearchScope::Formulas => match &cell.formula {
+                            Some(formula) => format!("={}", formula),
+                            None => cell.raw_value.clone(),
+                        },
+                        SearchScope::Addresses => addr.to_string(),
+                    };
+                    let is_match = match (&pattern, &address) {
+                        (Some(re), _) => re.is_match(&text),
+                        (None, Some(address)) => text == *address,
+                        (None, None) => text.contains(query),
+                    };
+                    if is_match {
+                        self.find_matches.push(addr);
                     }
                 }
-                else {
-                    0.0
-                };
-                // Update the cell's display value with the computed result
-                if let Some(cell) = self.get_cell_mut(addr) {
-                    cell.display_value = result.to_string();
-                    cell.raw_value = result.to_string();
-                    cell.formula = Some(value[1..].to_string());
+            }
+        }
+        
+        if !self.find_matches.is_empty() {
+            self.current_find_match = 0;
+            self.set_cursor(self.find_matches[0].clone());
+            self.status_message = format!("{} MATCHES FOUND", self.find_matches.len());
+            true
+        } else {
+            self.status_message = "NO MATCHES FOUND".to_string();
+            false
+        }
+    }
+/// Navigates to the next matching cell in the find results. The cursor will be updated to the next
+/// match in the list of search results.
+///
+/// # Returns
+///
+/// Returns `true` if a match is found and the cursor is updated. Returns `false` if no matches have been found.
+    pub fn find_next(&mut self) -> bool {
+        if self.find_matches.is_empty() {
+            return false;
+        }
+        
+        self.current_find_match = (self.current_find_match + 1) % self.find_matches.len();
+        self.set_cursor(self.find_matches[self.current_find_match].clone());
+        true
+    }
+/// Navigates to the previous matching cell in the find results. The cursor will be updated to the previous
+/// match in the list of search results.
+///
+/// # Returns
+///
+/// Returns `true` if a match is found and the cursor is updated. Returns `false` if no matches have been found.
+    pub fn find_prev(&mut self) -> bool {
+        if self.find_matches.is_empty() {
+            return false;
+        }
+        
+        if self.current_find_match == 0 {
+            self.current_find_match = self.find_matches.len() - 1;
+        } else {
+            self.current_find_match -= 1;
+        }
+        
+        self.set_cursor(self.find_matches[self.current_find_match].clone());
+        true
+    }
 
+/// Returns the text of a cell that a replacement operates on: the formula (with its leading `=`)
+/// for formula cells when `include_formulas` is set, the raw value for plain cells, and `None`
+/// for formula cells otherwise.
+    fn replace_target(&self, addr: &CellAddress, include_formulas: bool) -> Option<String> {
+        let cell = self.get_cell(addr)?;
+        match &cell.formula {
+            Some(formula) if include_formulas => Some(format!("={}", formula)),
+            Some(_) => None,
+            None => Some(cell.raw_value.clone()),
+        }
+    }
+/// Replaces every occurrence of `from` with `to` in one cell and recalculates it.
+///
+/// # Returns
+///
+/// Returns `true` if the cell contained `from` and was updated, or `false` if there was nothing to
+/// replace or the cell rejected the new value (e.g., it is locked).
+    fn replace_in_cell(&mut self, addr: &CellAddress, from: &str, to: &str, include_formulas: bool) -> bool {
+        match self.replace_target(addr, include_formulas) {
+            Some(text) if text.contains(from) => {
+                let new_text = text.replace(from, to);
+                self.update_cell(addr, &new_text, true)
+            }
+            _ => false,
+        }
+    }
+/// Replaces `from` with `to` in cell values (and optionally inside formulas) across a range or the
+/// whole sheet. In replace-all mode every match is replaced at once; in confirm-each mode the editor
+/// switches to `Replace` mode and asks for each match. Either way the whole replacement is recorded as
+/// a single undoable action.
+///
+/// # Arguments
+///
+/// * `from` - The text to search for.
+/// * `to` - The replacement text.
+/// * `range_str` - An optional range (e.g., "A1:C10") limiting the replacement. Defaults to the whole sheet.
+/// * `include_formulas` - Whether formulas are searched as well as plain values.
+/// * `confirm` - Whether to ask for confirmation before each replacement.
+///
+/// # Returns
+///
+/// Returns `true` if at least one match was found, or `false` if the range is invalid or nothing matched.
+    fn replace(&mut self, from: &str, to: &str, range_str: Option<&str>, include_formulas: bool, confirm: bool) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        let (start, end) = match range_str {
+            Some(r) => {
+                let r = r.trim_start_matches('[').trim_end_matches(']');
+                match self.parse_range(r) {
+                    Some(range) => range,
+                    None => {
+                        self.status_message = self.range_error(r);
+                        return false;
+                    }
                 }
-                println!("DEBUG: propagating starting on {}", addr.to_string());
-                self.propagate_changes(&addr.to_string());
-                self.currently_updating.remove(&cell_addr_str);
-        println!("DEBUG: Finished updating cell {}", cell_addr_str);
-                return true;
             }
-            else {
+            None => (CellAddress::new(0, 0), CellAddress::new(self.max_cols.saturating_sub(1), self.max_rows.saturating_sub(1))),
+        };
 
-                self.status_message = format!("ERROR: INVALID FORMULA {}", value);
-                return false;
+        let mut matches = Vec::new();
+        for col in start.col.min(end.col)..=start.col.max(end.col) {
+            for row in start.row.min(end.row)..=start.row.max(end.row) {
+                let addr = CellAddress::new(col, row);
+                if self.replace_target(&addr, include_formulas).is_some_and(|text| text.contains(from)) {
+                    matches.push(addr);
+                }
+            }
+        }
+        if matches.is_empty() {
+            self.status_message = "NO MATCHES FOUND".to_string();
+            return false;
+        }
+
+        if confirm {
+            self.set_cursor(matches[0].clone());
+            self.status_message = format!("REPLACE IN {}? (y/n/a/q)", matches[0]);
+            self.replace_state = Some(ReplaceState {
+                from: from.to_string(),
+                to: to.to_string(),
+                include_formulas,
+                matches,
+                index: 0,
+                replaced: 0,
+                action_open: false,
+            });
+            self.mode = Mode::Replace;
+            return true;
+        }
+
+        self.begin_action();
+        let mut replaced = 0;
+        for addr in &matches {
+            if self.replace_in_cell(addr, from, to, include_formulas) {
+                replaced += 1;
+            }
+        }
+        self.commit_action();
+        self.status_message = format!("{} CELLS REPLACED", replaced);
+        true
+    }
+/// Answers the confirmation prompt of a confirm-each replacement.
+///
+/// # Arguments
+///
+/// * `replace_current` - Whether to replace the match under the cursor.
+/// * `replace_rest` - Whether to replace the current and all remaining matches without asking.
+///
+/// The undo transaction begins with the first accepted replacement and is committed when the session
+/// ends, so the whole session is undone as one action. When no matches are left the editor returns to `Normal` mode.
+    fn answer_replace(&mut self, replace_current: bool, replace_rest: bool) {
+        let mut state = match self.replace_state.take() {
+            Some(state) => state,
+            None => {
+                self.mode = Mode::Normal;
+                return;
+            }
+        };
+
+        while state.index < state.matches.len() {
+            if replace_current || replace_rest {
+                if !state.action_open {
+                    self.begin_action();
+                    state.action_open = true;
+                }
+                let addr = state.matches[state.index].clone();
+                if self.replace_in_cell(&addr, &state.from, &state.to, state.include_formulas) {
+                    state.replaced += 1;
+                }
+            }
+            state.index += 1;
+            if !replace_rest {
+                break;
+            }
+        }
+
+        if state.index < state.matches.len() {
+            let next = state.matches[state.index].clone();
+            self.status_message = format!("REPLACE IN {}? (y/n/a/q)", next);
+            self.set_cursor(next);
+            self.replace_state = Some(state);
+        } else {
+            self.finish_replace(state);
+        }
+    }
+/// Ends a confirm-each replacement session, committing its undo transaction if anything was replaced.
+///
+/// # Arguments
+///
+/// * `state` - The state of the session being ended.
+    fn finish_replace(&mut self, state: ReplaceState) {
+        if state.action_open {
+            self.commit_action();
+        }
+        self.status_message = format!("{} CELLS REPLACED", state.replaced);
+        self.mode = Mode::Normal;
+    }
+
+/// Applies the same value (or formula) to every cell matched by the last `find`, recorded as a
+/// single undoable action. Locked cells are skipped.
+///
+/// # Arguments
+///
+/// * `value` - The value or formula to write into each match; an empty string clears the cells.
+///
+/// # Returns
+///
+/// Returns `true` if there were matches to update, or `false` if no search results are active.
+    fn apply_to_matches(&mut self, value: &str) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        if self.find_matches.is_empty() {
+            self.status_message = "NO FIND MATCHES".to_string();
+            return false;
+        }
+        self.begin_action();
+        let matches = self.find_matches.clone();
+        let mut updated = 0;
+        for addr in &matches {
+            if self.update_cell(addr, value, true) {
+                updated += 1;
             }
         }
-        // Ensure removal from currently_updating set in all cases
+        self.commit_action();
+        self.status_message = format!("{} OF {} MATCHES UPDATED", updated, matches.len());
+        true
+    }
+
+    /// Parses a range string in the format "A1:B5" into two `CellAddress` objects representing
+/// the starting and ending cell addresses. If the format is invalid, returns `None`.
+///
+/// # Arguments
+///
+/// * `range_str` - A string representing the range to parse (e.g., "A1:B5").
+///
+/// # Returns
+///
+/// Returns an `Option` containing a tuple of `CellAddress` objects for the start and end cells if valid,
+/// or `None` if the format is invalid, the cell addresses cannot be parsed, or either lies outside the
+/// sheet (so commands never walk a range larger than the grid).
+    pub fn parse_range(&self, range_str: &str) -> Option<(CellAddress, CellAddress)> {
+        let parts: Vec<&str> = range_str.split(':').collect();
+        if parts.len() != 2 {
+            return None;
+        }
         
-        return true;
+        let in_sheet = |addr: &CellAddress| addr.row < self.max_rows && addr.col < self.max_cols;
+        let start = CellAddress::from_str(parts[0]).filter(in_sheet)?;
+        let end = CellAddress::from_str(parts[1]).filter(in_sheet)?;
+        
+        Some((start, end))
     }
 
-    // Pushes a single undo action to the undo stack for a specific cell update. This action stores
-// the previous state of the cell so that it can be reverted during an undo operation.
-//
-// The undo stack is capped at 3 actions, and older actions are discarded when this limit is exceeded.
-//
-// # Arguments
-//
-// * `addr` - The `CellAddress` of the cell that was updated.
-// * `old_cell` - A `Cell` representing the state of the cell before the update.
-//
-// # Notes
-//
-// The undo stack is maintained in a way that only a limited number of undo actions are stored
-// at any given time. If the stack reaches its limit, the oldest action is discarded to make room
-// for new actions.
-    // fn push_undo(&mut self, addr: CellAddress, old_cell: Cell) {
-    //     // Maintain max 3 undo steps
-    //     if self.undo_stack.len() >= 3 {
-    //         self.undo_stack.pop_front();
-    //     }
-    //     self.undo_stack.push_back(UndoAction {
-    //         cell_address: addr,
-    //         old_cell
-    //     });
-    // }
+    /// Returns the status message for a range `parse_range` refused: `ERROR: RANGE {range} OUTSIDE
+    /// THE SHEET (A1:{last cell})` when both addresses are well formed, and `INVALID RANGE` otherwise.
+    fn range_error(&self, range_str: &str) -> String {
+        match range_str.split_once(':') {
+            Some((start, end)) if CellAddress::from_str(start).is_some() && CellAddress::from_str(end).is_some() => {
+                let last = CellAddress::new(self.max_cols - 1, self.max_rows - 1);
+                format!("ERROR: RANGE {} OUTSIDE THE SHEET (A1:{})", range_str, last)
+            }
+            _ => "INVALID RANGE".to_string(),
+        }
+    }
 
-    /// Pushes the entire sheet's state to the undo stack. This operation adds all current cells in
-/// the sheet to the undo stack so that the entire sheet can be reverted in a single undo operation.
+    /// Returns the locked cells inside the rectangle `start..=end`, ordered by row and then column.
+    /// Blank cells are never locked, so only the stored cells are looked at.
+    fn locked_cells(&mut self, start: &CellAddress, end: &CellAddress) -> Vec<CellAddress> {
+        self.page_in_rows(start.row, end.row);
+        let mut locked: Vec<CellAddress> = self.data.iter()
+            .filter(|(_, cell)| cell.is_locked)
+            .filter_map(|(addr, _)| CellAddress::from_str(addr))
+            .filter(|addr| (start.col..=end.col).contains(&addr.col) && (start.row..=end.row).contains(&addr.row))
+            .collect();
+        locked.sort_by_key(|addr| (addr.row, addr.col));
+        locked
+    }
+/// Inserts a specified value into a range of cells. The range is parsed from the `range_str`
+/// argument (e.g., "A1:B3"), and the value is inserted into all cells within that range. 
+/// The undo stack is updated before any changes are made.
 ///
-/// The undo stack is capped at 3 actions, and older actions are discarded when this limit is exceeded.
-/// If the undo stack already contains 3 actions, it is cleared before adding a new action.
+/// # Arguments
 ///
-/// # Example
+/// * `range_str` - A string representing the range to insert the value into (e.g., "A1:B3").
+/// * `value` - The value to insert into the specified range of cells.
 ///
-/// # Notes
+/// # Returns
 ///
-/// This operation clears the undo stack when adding the first action if the cell at address `A1`
-/// is present in the data and the undo stack already has 3 actions.
-    fn push_undo_sheet(&mut self) {
-        // Add all cells to the undo stack
-        for (addr_str, cell) in &self.data {
-            if let Some(addr) = CellAddress::from_str(addr_str) {
-                // Maintain max 3 undo steps - only check on the first cell
-                if addr_str == "A1" && self.undo_stack.len() >= 3 {
-                    self.undo_stack.clear();
+/// Returns `true` if the value was successfully inserted into the specified range, or `false` if:
+/// - The range is invalid.
+/// - The range reaches into a protected range or sheet; nothing is inserted then.
+/// - The range holds locked cells and `strict_locks` is set; nothing is inserted then. Otherwise the
+///   locked cells are skipped and counted in the status message.
+    fn multi_insert(&mut self, range_str: &str, value: &str) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        // Remove brackets if present
+        let range_str = range_str.trim_start_matches('[').trim_end_matches(']');
+        
+        if let Some((start, end)) = self.parse_range(range_str) {
+            let start_col = start.col.min(end.col);
+            let end_col = start.col.max(end.col);
+            let start_row = start.row.min(end.row);
+            let end_row = start.row.max(end.row);
+            if let Some(area) = self.protection.covering(&CellAddress::new(start_col, start_row), &CellAddress::new(end_col, end_row)) {
+                self.status_message = format!("ERROR: {} PROTECTED", area);
+                return false;
+            }
+            let locked = self.locked_cells(&CellAddress::new(start_col, start_row), &CellAddress::new(end_col, end_row));
+            if self.strict_locks && !locked.is_empty() {
+                self.status_message = format!("ERROR: {} LOCKED CELLS IN {}, FIRST {}", locked.len(), range_str, locked[0]);
+                return false;
+            }
+            let skipped: HashSet<(usize, usize)> = locked.iter().map(|addr| (addr.col, addr.row)).collect();
+            self.begin_action();
+            let total = (end_col - start_col + 1) * (end_row - start_row + 1);
+            let mut done = 0;
+            for col in start_col..=end_col {
+                for row in start_row..=end_row {
+                    self.report_progress("INSERTING", done, total);
+                    done += 1;
+                    let addr = CellAddress::new(col, row);
+                    if skipped.contains(&(col, row)) {
+                        continue;
+                    }
+                    if !self.update_cell(&addr, value,true) {
+                        // If any cell fails (e.g., an invalid formula), continue with the rest
+                        continue;
+                    }
+                }
+            }
+            self.commit_action();
+            
+            self.status_message = match locked.len() {
+                0 => "MULTIPLE INSERTS".to_string(),
+                skipped => format!("MULTIPLE INSERTS, {} LOCKED CELLS SKIPPED", skipped),
+            };
+            true
+        } else {
+            self.status_message = self.range_error(range_str);
+            false
+        }
+    }
+/// Reads an external link source (a sheet saved with `saveas_json`) and returns the display value of
+/// every cell in it, keyed by address.
+///
+/// # Arguments
+///
+/// * `source` - The path of the linked sheet.
+///
+/// # Returns
+///
+/// Returns `io::Result<HashMap<String, String>>` with the linked cell values, or an error if the source
+/// cannot be read, is not a saved sheet, or is a URL (remote sources are not supported).
+    fn read_link_source(source: &str) -> io::Result<HashMap<String, String>> {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "URL LINK SOURCES ARE NOT SUPPORTED"));
+        }
+        let cells = read_save_file(open_save(Path::new(source))?)?.cells;
+        Ok(cells.into_iter().map(|(addr, cell)| (addr, cell.display_value)).collect())
+    }
+/// Returns the value of a cell in an external sheet, reading and caching the source on first use.
+///
+/// # Arguments
+///
+/// * `source` - The path of the linked sheet.
+/// * `cell_ref` - The address of the cell inside the linked sheet.
+///
+/// # Returns
+///
+/// Returns `Some(value)` if the source could be read and contains the cell, otherwise `None`.
+    fn linked_value(&mut self, source: &str, cell_ref: &str) -> Option<String> {
+        if !self.link_cache.contains_key(source) {
+            match Self::read_link_source(source) {
+                Ok(values) => {
+                    self.link_cache.insert(source.to_string(), values);
+                }
+                Err(e) => {
+                    self.status_message = format!("LINK ERROR: {}: {}", source, e);
+                    return None;
                 }
-                
-                self.undo_stack.push_back(UndoAction {
-                    cell_address: addr,
-                    old_cell: cell.clone(),
-                });
             }
         }
+        let addr = CellAddress::from_str(cell_ref)?.to_string();
+        self.link_cache.get(source)?.get(&addr).cloned()
     }
-    /// Undoes the last action applied to the sheet. If the undo stack is empty, a message is set
-/// indicating that there is nothing to undo.
-///
-/// The state of the sheet is reverted to the state it was in before the last action. The undone
-/// actions are then moved to the redo stack, allowing them to be reapplied later using the redo function.
+/// Re-reads every external source referenced by a `LINK(...)` formula, reports which sources changed,
+/// and recalculates the cells that read from them in one pass.
 ///
 /// # Returns
 ///
-/// Returns `true` if the undo operation was successfully applied, or `false` if there was nothing to undo.
-    fn undo(&mut self) -> bool {
-        // Check if we have any actions to undo
-        if self.undo_stack.is_empty() {
-            self.status_message = "NOTHING TO UNDO".to_string();
-            return false;
-        }
-        
-        // Store all current cell states for redo before undoing
+/// Returns the number of sources whose contents changed since they were last read.
+    fn refresh_links(&mut self) -> usize {
+        // Group linking cells by the source they read from
+        let mut linked: HashMap<String, Vec<String>> = HashMap::new();
         for (addr_str, cell) in &self.data {
-            if let Some(addr) = CellAddress::from_str(addr_str) {
-                self.redo_stack.push_back(UndoAction {
-                    cell_address: addr,
-                    old_cell: cell.clone(),
-                });
+            if let Some((source, _)) = cell.formula.as_deref()
+                .and_then(|f| f.strip_prefix("LINK("))
+                .and_then(|f| f.strip_suffix(')'))
+                .and_then(|args| args.rsplit_once(',')) {
+                linked.entry(source.trim().to_string()).or_default().push(addr_str.clone());
             }
         }
-        
-        // Now restore all cells from the undo stack
-        let mut restored_cells = HashMap::new();
-        
-        while let Some(action) = self.undo_stack.pop_back() {
-            // Store the restored cell
-            restored_cells.insert(action.cell_address.to_string(), action.old_cell);
-            
-            // Stop when we've restored all cells
-            if restored_cells.len() == self.data.len() {
-                break;
+        if linked.is_empty() {
+            self.status_message = "NO LINKS TO REFRESH".to_string();
+            return 0;
+        }
+
+        let mut changed = Vec::new();
+        let mut failed = Vec::new();
+        let mut to_recalc = Vec::new();
+        let mut sources: Vec<&String> = linked.keys().collect();
+        sources.sort();
+        for source in sources {
+            match Self::read_link_source(source) {
+                Ok(values) => {
+                    if self.link_cache.get(source.as_str()) != Some(&values) {
+                        changed.push(source.clone());
+                        to_recalc.extend(linked[source].iter().cloned());
+                    }
+                    self.link_cache.insert(source.clone(), values);
+                }
+                Err(_) => failed.push(source.clone()),
             }
         }
-        
-        // Apply all restored cells to the sheet
-        for (addr_str, cell) in restored_cells {
-            if let Some(target_cell) = self.data.get_mut(&addr_str) {
-                *target_cell = cell;
+
+        for addr_str in to_recalc {
+            let formula = self.data.get(&addr_str).and_then(|cell| cell.formula.clone());
+            if let (Some(addr), Some(formula)) = (CellAddress::from_str(&addr_str), formula) {
+                self.update_cell(&addr, &format!("={}", formula), true);
             }
         }
-        
-        self.status_message = "UNDO APPLIED".to_string();
-        true
+
+        self.status_message = if changed.is_empty() {
+            "LINKS REFRESHED: NO CHANGES".to_string()
+        } else {
+            format!("LINKS REFRESHED: {} CHANGED ({})", changed.len(), changed.join(", "))
+        };
+        if !failed.is_empty() {
+            self.status_message.push_str(&format!(" | UNREADABLE: {}", failed.join(", ")));
+        }
+        changed.len()
     }
-    /// Redoes the last undone action. If the redo stack is empty, a message is set indicating that
-/// there is nothing to redo.
+/// Writes the keys recorded in demo mode to a script file that `demo play` can replay.
+/// A line break follows every `<Enter>` so each command ends up on its own line.
+///
+/// # Arguments
 ///
-/// The state of the sheet is restored to the state it was in before the undo operation. The redone
-/// actions are then moved back to the undo stack, allowing them to be undone again if needed.
+/// * `path` - The path where the script should be saved.
+/// * `skip_last` - The number of most recent keys to leave out (the keys of the save command itself).
 ///
 /// # Returns
 ///
-/// Returns `true` if the redo operation was successfully applied, or `false` if there was nothing to redo.
-    fn redo(&mut self) -> bool {
-        // Check if we have any actions to redo
-        if self.redo_stack.is_empty() {
-            self.status_message = "NOTHING TO REDO".to_string();
-            return false;
-        }
-        
-        // Store all current cell states for undo before redoing
-        for (addr_str, cell) in &self.data {
-            if let Some(addr) = CellAddress::from_str(addr_str) {
-                self.undo_stack.push_back(UndoAction {
-                    cell_address: addr,
-                    old_cell: cell.clone(),
-                });
+/// Returns `io::Result<()>`, which will be `Ok` if the file is written successfully.
+    fn save_demo_script(&self, path: &Path, skip_last: usize) -> io::Result<()> {
+        let keep = self.demo_recording.len().saturating_sub(skip_last);
+        let mut writer = BufWriter::new(File::create(path)?);
+        for key in &self.demo_recording[..keep] {
+            if let Some(label) = key_label(key) {
+                write!(writer, "{}", label)?;
+                if *key == KeyCode::Enter {
+                    writeln!(writer)?;
+                }
             }
         }
-        
-        // Now restore all cells from the redo stack
-        let mut restored_cells = HashMap::new();
-        
-        while let Some(action) = self.redo_stack.pop_back() {
-            // Store the restored cell
-            restored_cells.insert(action.cell_address.to_string(), action.old_cell);
-            
-            // Stop when we've restored all cells
-            if restored_cells.len() == self.data.len() {
-                break;
-            }
+        writer.flush()?;
+        Ok(())
+    }
+/// Loads a demo script and queues its keys for replay by the main loop. Demo mode is switched on so
+/// the overlay shows each key as it is replayed.
+///
+/// # Arguments
+///
+/// * `path` - The path of the script to replay.
+/// * `delay` - An optional pause between keys; the current delay is kept when `None`.
+///
+/// # Returns
+///
+/// Returns `io::Result<usize>` with the number of queued keys, or an error if the script cannot be read.
+    fn play_demo_script(&mut self, path: &Path, delay: Option<Duration>) -> io::Result<usize> {
+        let script = std::fs::read_to_string(path)?;
+        let keys = parse_key_script(&script);
+        if let Some(delay) = delay {
+            self.demo_delay = delay;
         }
-        
-        // Apply all restored cells to the sheet
-        for (addr_str, cell) in restored_cells {
-            if let Some(target_cell) = self.data.get_mut(&addr_str) {
-                *target_cell = cell;
-            }
+        self.demo_mode = true;
+        self.demo_queue = keys.into_iter().collect();
+        Ok(self.demo_queue.len())
+    }
+/// Saves the current spreadsheet data as a JSON file to the specified path.
+///
+/// # Arguments
+///
+/// * `path` - The path where the JSON file should be saved.
+/// * `with_history` - Whether to store the undo and redo history alongside the cells, so it can be
+///   continued after reopening the file.
+///
+/// # Returns
+///
+/// Returns `io::Result<()>`, which will be `Ok` if the file is written successfully, or an error if
+/// there is an issue with creating or writing to the file.
+    pub fn save_json(&self, path: &Path, with_history: bool) -> io::Result<()> {
+        write_save(path, &self.save_file_ref(with_history, None), false)
+    }
+/// Saves the sheet with its undo history to `recovery.json` in `dir` after a crash, creating `dir`
+/// if needed.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to write the recovery file to, normally the config directory.
+///
+/// # Returns
+///
+/// Returns `io::Result<PathBuf>` with the path of the recovery file, or an error if it cannot be written.
+    pub fn save_recovery(&self, dir: &Path) -> io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let recovery = dir.join("recovery.json");
+        self.save_json(&recovery, true)?;
+        Ok(recovery)
+    }
+/// Saves the spreadsheet in the text format of `saveas_text`: one line per cell, in row-major order,
+/// holding only its input and the styles that differ from a default cell.
+///
+/// # Arguments
+///
+/// * `path` - The path where the text file should be saved.
+///
+/// # Returns
+///
+/// Returns `io::Result<()>`, which will be `Ok` if the file is written successfully, or an error if
+/// there is an issue with creating or writing to the file.
+    pub fn save_text(&self, path: &Path) -> io::Result<()> {
+        write_text_save(path, &self.save_file_ref(false, None))
+    }
+/// Saves the sheet to a change log. A new log starts with a full snapshot; after that each save appends
+/// one line holding only the cells changed since the state the log already records, so the log keeps
+/// every saved revision. `compact_log` folds the history back into a single snapshot.
+///
+/// # Arguments
+///
+/// * `path` - The path of the change log.
+///
+/// # Returns
+///
+/// The revision just written and the number of cells it holds (the revision is not written, and
+/// the count is `0`, when nothing changed), or an error if the log cannot be read or written.
+    pub fn save_log(&self, path: &Path) -> io::Result<(usize, usize)> {
+        let time = chrono::Local::now().to_rfc3339();
+        if !path.exists() {
+            let mut snapshot = serde_json::to_value(self.save_file_ref(false, None))?;
+            snapshot["time"] = serde_json::json!(time);
+            let mut writer = SaveWriter::create(path, Compression::for_path(path), false)?;
+            writeln!(writer, "{}", snapshot)?;
+            writer.finish()?;
+            return Ok((1, self.data.len()));
         }
-        
-        self.status_message = "REDO APPLIED".to_string();
-        true
+        let logged = replay_change_log(path, None)?;
+        // Cells cleared since the last revision are no longer stored and are logged as blank
+        let cleared = logged.cells.iter()
+            .filter(|(addr, old)| !self.data.contains_key(*addr) && !old.is_blank())
+            .map(|(addr, _)| (addr, &self.blank_cell));
+        let changes: HashMap<String, Cell> = self.data.iter()
+            .filter(|(addr, cell)| logged.cells.get(*addr)
+                .is_none_or(|old| serde_json::to_value(old).ok() != serde_json::to_value(cell).ok()))
+            .chain(cleared)
+            .map(|(addr, cell)| (addr.clone(), cell.clone()))
+            .collect();
+        if changes.is_empty() {
+            return Ok((logged.times.len(), 0));
+        }
+        let count = changes.len();
+        let mut writer = SaveWriter::create(path, Compression::for_path(path), true)?;
+        writeln!(writer, "{}", serde_json::to_string(&LogEntry { time, changes })?)?;
+        writer.finish()?;
+        Ok((logged.times.len() + 1, count))
     }
-
-    /// Locks a specific cell, preventing its value from being modified until it is unlocked.
-/// If no address is provided, the currently selected cell (cursor) will be locked.
+/// Loads the sheet as it was at a revision of a change log written by `saveas_log`.
 ///
 /// # Arguments
 ///
-/// * `addr` - An optional string slice representing the cell's address to be locked. If not provided,
-///   the currently selected cell is locked.
+/// * `path` - The path of the change log.
+/// * `revision` - The revision to load (1 is the first snapshot), or `None` for the latest.
 ///
 /// # Returns
 ///
-/// Returns `true` if the cell was successfully locked, or `false` if the cell could not be locked 
-/// (e.g., invalid address).
-    fn lock_cell(&mut self, addr: Option<&str>) -> bool {
-        let addr = if let Some(a) = addr {
-            if let Some(cell_addr) = CellAddress::from_str(a) {
-                cell_addr
-            } else {
-                return false;
-            }
-        } else {
-            self.cursor.clone()
-        };
-        
-        if let Some(cell) = self.get_cell_mut(&addr) {
-            cell.is_locked = true;
-            self.status_message = "CELL LOCKED".to_string();
-            true
-        } else {
-            false
+/// The loaded revision and the times of all revisions, or an error if the log cannot be read or the
+/// revision does not exist.
+    pub fn load_log(&mut self, path: &Path, revision: Option<usize>) -> io::Result<(usize, Vec<String>)> {
+        let replayed = replay_change_log(path, revision)?;
+        let loaded = revision.unwrap_or(replayed.times.len());
+        if loaded == 0 || loaded > replayed.times.len() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("NO REVISION {} (THE LOG HAS {})", loaded, replayed.times.len())));
         }
+        self.load_save_file(SaveFile { version: SAVE_FORMAT_VERSION, rows: replayed.rows, cols: replayed.cols, cells: replayed.cells, undo: VecDeque::new(), redo: VecDeque::new(), protection: None, pivots: Vec::new(), row_groups: Vec::new() })?;
+        Ok((loaded, replayed.times))
     }
-/// Unlocks a specific cell, allowing its value to be modified. If no address is provided, 
-/// the currently selected cell (cursor) will be unlocked.
+/// Rewrites a change log as a single snapshot of its latest revision, dropping the history.
 ///
 /// # Arguments
 ///
-/// * `addr` - An optional string slice representing the cell's address to be unlocked. If not provided,
-///   the currently selected cell is unlocked.
+/// * `path` - The path of the change log.
 ///
 /// # Returns
 ///
-/// Returns `true` if the cell was successfully unlocked, or `false` if the cell could not be unlocked 
-/// (e.g., invalid address).
-    fn unlock_cell(&mut self, addr: Option<&str>) -> bool {
-        let addr = if let Some(a) = addr {
-            if let Some(cell_addr) = CellAddress::from_str(a) {
-                cell_addr
-            } else {
-                return false;
-            }
-        } else {
-            self.cursor.clone()
+/// The number of revisions that were folded together, or an error if the log cannot be read or written.
+    fn compact_log(path: &Path) -> io::Result<usize> {
+        let replayed = replay_change_log(path, None)?;
+        let mut snapshot = serde_json::json!({ "version": SAVE_FORMAT_VERSION, "rows": replayed.rows, "cols": replayed.cols, "cells": replayed.cells });
+        snapshot["time"] = serde_json::json!(replayed.times.last().cloned().unwrap_or_default());
+        // Written next to the log and renamed over it, so a failed write leaves the log intact
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let mut writer = SaveWriter::create(Path::new(&temp), Compression::for_path(path), false)?;
+        writeln!(writer, "{}", snapshot)?;
+        writer.finish()?;
+        std::fs::rename(&temp, path)?;
+        Ok(replayed.times.len())
+    }
+/// Saves the spreadsheet in the compact binary (MessagePack) format, which holds the same data as
+/// `save_json` but is much smaller and faster to read and write for large sheets.
+///
+/// # Arguments
+///
+/// * `path` - The path where the binary file should be saved.
+/// * `with_history` - Whether to store the undo and redo history alongside the cells.
+///
+/// # Returns
+///
+/// Returns `io::Result<()>`, which will be `Ok` if the file is written successfully, or an error if
+/// there is an issue with creating or writing to the file.
+    pub fn save_bin(&self, path: &Path, with_history: bool) -> io::Result<()> {
+        write_save(path, &self.save_file_ref(with_history, None), true)
+    }
+/// Borrows the sheet as a `SaveFileRef` at the current format version.
+///
+/// # Arguments
+///
+/// * `with_history` - Whether to include the undo and redo history.
+/// * `range` - Only save the cells in this range (keeping their addresses); the history is never
+///   included then, as it may refer to cells outside the range.
+    fn save_file_ref(&self, with_history: bool, range: Option<(CellAddress, CellAddress)>) -> SaveFileRef<'_> {
+        let whole = range.is_none();
+        let with_history = with_history && whole;
+        let cells = match range {
+            Some((start, end)) => std::borrow::Cow::Owned(self.data.iter()
+                .filter(|(addr, _)| CellAddress::from_str(addr).is_some_and(|addr| {
+                    (start.col..=end.col).contains(&addr.col) && (start.row..=end.row).contains(&addr.row)
+                }))
+                .map(|(addr, cell)| (addr.clone(), cell.clone()))
+                .collect()),
+            None => std::borrow::Cow::Borrowed(&self.data),
         };
-        
-        if let Some(cell) = self.get_cell_mut(&addr) {
-            cell.is_locked = false;
-            self.status_message = "CELL UNLOCKED".to_string();
-            true
-        } else {
-            false
+        SaveFileRef {
+            version: SAVE_FORMAT_VERSION,
+            rows: whole.then_some(self.max_rows),
+            cols: whole.then_some(self.max_cols),
+            cells,
+            undo: with_history.then_some(&self.undo_stack),
+            redo: with_history.then_some(&self.redo_stack),
+            protection: (!self.protection.is_empty()).then_some(&self.protection),
+            pivots: (whole && !self.pivots.is_empty()).then_some(&self.pivots),
+            row_groups: (whole && !self.row_groups.is_empty()).then_some(&self.row_groups),
         }
     }
-/// Sets the alignment of a specific cell. The alignment can be set to left, right, or center.
-/// If no address is provided, the currently selected cell (cursor) will be modified.
+/// Writes the formula dependency structure as a CSV adjacency list with one `cell,depends_on`
+/// row per dependency edge, sorted by cell address.
 ///
 /// # Arguments
 ///
-/// * `addr` - An optional string slice representing the cell's address. If not provided,
-///   the currently selected cell is used.
-/// * `align` - A string that specifies the alignment. Possible values are:
-///   - `"l"` for left alignment
-///   - `"r"` for right alignment
-///   - `"c"` for center alignment
+/// * `path` - The path where the CSV file should be saved.
+/// * `range` - Only write the dependencies of formula cells in this range, or `None` for all of them.
 ///
 /// # Returns
 ///
-/// Returns `true` if the alignment was successfully changed, or `false` if the address is invalid,
-/// the cell is locked, or the alignment value is invalid.
-    fn set_alignment(&mut self, addr: Option<&str>, align: &str) -> bool {
-        let addr = if let Some(a) = addr {
-            if let Some(cell_addr) = CellAddress::from_str(a) {
-                cell_addr
-            } else {
-                return false;
-            }
-        } else {
-            self.cursor.clone()
-        };
-        
-        let alignment = match align {
-            "l" => Alignment::Left,
-            "r" => Alignment::Right,
-            "c" => Alignment::Center,
-            _ => return false,
-        };
-        
-        if let Some(cell) = self.get_cell_mut(&addr) {
-            if cell.is_locked {
-                self.status_message = format!("ERROR: CELL {} LOCKED", addr.to_string());
-                return false;
+/// Returns `io::Result<()>`, which will be `Ok` if the file is written successfully, or an error if
+/// there is an issue with creating or writing to the file.
+    fn save_dependency_csv(&self, path: &Path, range: Option<(CellAddress, CellAddress)>) -> io::Result<()> {
+        let mut edges: Vec<(CellAddress, CellAddress)> = Vec::new();
+        for (cell, deps) in &self.dependencies {
+            for dep in deps {
+                if let (Some(from), Some(to)) = (CellAddress::from_str(cell), CellAddress::from_str(dep)) {
+                    let in_range = range.as_ref().is_none_or(|(start, end)| {
+                        (start.col..=end.col).contains(&from.col) && (start.row..=end.row).contains(&from.row)
+                    });
+                    if in_range {
+                        edges.push((from, to));
+                    }
+                }
             }
-            
-            cell.alignment = alignment;
-            self.status_message = "ALIGNMENT CHANGED".to_string();
-            true
-        } else {
-            false
         }
+        // Order by (col, row) so the output is stable between runs
+        edges.sort_by_key(|(from, to)| (from.col, from.row, to.col, to.row));
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "cell,depends_on")?;
+        for (from, to) in edges {
+            writeln!(writer, "{},{}", from, to)?;
+        }
+        writer.flush()?;
+        Ok(())
     }
-/// Sets the height and width for a specific cell. If no address is provided, the currently selected 
-/// cell (cursor) will be modified. The height and width can be adjusted independently.
+/// Loads spreadsheet data from a JSON file at the specified path. If the file was saved together with
+/// its undo history, the history is restored as well.
 ///
 /// # Arguments
 ///
-/// * `addr` - An optional string slice representing the cell's address. If not provided,
-///   the currently selected cell is used.
-/// * `height` - An optional `usize` representing the height of the cell. If not provided, the height
-///   will not be changed.
-/// * `width` - An optional `usize` representing the width of the cell. If not provided, the width
-///   will not be changed.
+/// * `path` - The path to the JSON file containing the spreadsheet data.
 ///
 /// # Returns
 ///
-/// Returns `true` if the dimension was successfully changed, or `false` if the address is invalid,
-/// the cell is locked, or invalid dimensions were provided.
-    fn set_dimension(&mut self, addr: Option<&str>, height: Option<usize>, width: Option<usize>) -> bool {
-        println!("Debug: Setting dimension for cell {:?}", addr);
-        let addr = if let Some(a) = addr {
-            if let Some(cell_addr) = CellAddress::from_str(a) {
-                cell_addr
-            } else {
-                return false;
-            }
-        } else {
-            self.cursor.clone()
+/// Returns `io::Result<()>`, which will be `Ok` if the file is read and the data is successfully loaded,
+/// or an error if the file cannot be opened or the data cannot be parsed.
+    pub fn load_json(&mut self, path: &Path) -> io::Result<()> {
+        let saved = read_save_file(open_save(path)?)?;
+        self.load_save_file(saved)
+    }
+/// Loads spreadsheet data from a binary file written by `save_bin`, restoring the saved history if any.
+///
+/// # Arguments
+///
+/// * `path` - The path to the binary file.
+///
+/// # Returns
+///
+/// Returns `io::Result<()>`, which will be `Ok` if the file is read and the data is successfully loaded,
+/// or an error if the file cannot be opened or is not a valid binary save.
+    pub fn load_bin(&mut self, path: &Path) -> io::Result<()> {
+        let saved = read_bin_save_file(open_save(path)?)?;
+        self.load_save_file(saved)
+    }
+/// Replaces the sheet with the contents of a save file, then resizes the grid and recalculates.
+///
+/// A file whose size or cells reach past 999 rows or 18278 columns is refused with an
+/// `InvalidData` error, leaving the sheet as it was.
+    fn load_save_file(&mut self, saved: SaveFile) -> io::Result<()> {
+        // Scan through all cell addresses to find the last row and column used
+        let (mut last_row, mut last_col) = (0, 0);
+        for addr in saved.cells.keys().filter_map(|addr_str| CellAddress::from_str(addr_str)) {
+            last_row = last_row.max(addr.row);
+            last_col = last_col.max(addr.col);
+        }
+        let (rows, cols) = match (saved.rows, saved.cols) {
+            // Files that store their size keep it even when the last rows or columns are blank
+            (Some(rows), Some(cols)) => (rows.max(last_row + 1), cols.max(last_col + 1)),
+            // Otherwise the grid reaches one past the last cell, or is 11 by 11 if only the first is used
+            _ => (if last_row == 0 { 10 } else { last_row } + 1, if last_col == 0 { 10 } else { last_col } + 1),
         };
-        println!("Debug: Address after parsing: {:?}", addr);
-        if let Some(cell) = self.get_cell_mut(&addr) {
-            if cell.is_locked {
-                self.status_message = format!("ERROR: CELL {} LOCKED", addr.to_string());
-                return false;
-            }
-            println!("Debug: Cell found: {:?}", cell);
-            if let Some(h) = height {
-                println!("Debug: Setting height to {}", h);
-                cell.height = h;
-            }
-            
-            if let Some(w) = width {
-                println!("Debug: Setting width to {}", w);
-                cell.width = w;
-            }
-            
-            self.status_message = "DIMENSION CHANGED".to_string();
-            true
-        } else {
+        check_sheet_size(rows, cols).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // The loaded file replaces a database-backed sheet too
+        #[cfg(feature = "sqlite")]
+        {
+            self.store = None;
+        }
+        // Saved history continues where the file left off; otherwise it starts empty
+        self.data = saved.cells;
+        self.undo_stack = saved.undo;
+        self.redo_stack = saved.redo;
+        self.protection = saved.protection.unwrap_or_default();
+        self.pivots = saved.pivots;
+        self.row_groups = saved.row_groups;
+        self.reveal_cursor();
+        self.action_depth = 0;
+        self.max_rows = rows;
+        self.max_cols = cols;
+        // Older files list every cell; blank ones are not kept
+        self.data.retain(|_, cell| !cell.is_blank());
+
+        // Saved files only hold cell contents, so the graph and formula results are rebuilt from scratch
+        self.recalculate_all();
+        self.spill_to_swap();
+        Ok(())
+    }
+    #[cfg(not(feature = "sqlite"))]
+    fn page_in_rows(&mut self, _first: usize, _last: usize) {}
+    #[cfg(not(feature = "sqlite"))]
+    fn spill_to_swap(&mut self) {}
+/// Reads every row of a database-backed sheet into memory, for commands that go through all of its
+/// cells such as exports. `page_in_rows` drops them again as other rows are read.
+    fn page_in_all_rows(&mut self) {
+        if self.is_store_backed() {
+            self.page_in_rows(0, self.max_rows.saturating_sub(1));
+        }
+    }
+/// Reads in the rows of every cell or range mentioned in a command or formula (see `page_in_rows`).
+///
+/// # Arguments
+///
+/// * `text` - The command or formula to scan for references such as `B7` or `A1:C20`.
+    fn page_in_refs(&mut self, text: &str) {
+        if !self.is_store_backed() {
+            return;
+        }
+        let refs = regex::Regex::new(r"([A-Z]+[0-9]+)(?::([A-Z]+[0-9]+))?").unwrap();
+        let spans: Vec<(usize, usize)> = refs.captures_iter(text).filter_map(|caps| {
+            let start = CellAddress::from_str(&caps[1])?;
+            let end = caps.get(2).and_then(|end| CellAddress::from_str(end.as_str())).unwrap_or_else(|| start.clone());
+            Some((start.row.min(end.row), start.row.max(end.row)))
+        }).collect();
+        for (first, last) in spans {
+            self.page_in_rows(first, last);
+        }
+    }
+    #[cfg(all(feature = "websocket", not(feature = "sqlite")))]
+    fn row_in_memory(&self, _row: usize) -> bool {
+        true
+    }
+/// Returns whether the sheet is backed by a SQLite database.
+    fn is_store_backed(&self) -> bool {
+        #[cfg(feature = "sqlite")]
+        {
+            self.store.is_some()
+        }
+        #[cfg(not(feature = "sqlite"))]
+        {
             false
         }
     }
-/// Searches for a query string within all cells in the spreadsheet. If any cells contain the query,
-/// their addresses will be stored as matches.
-///
-/// # Arguments
+    #[cfg(not(feature = "sqlite"))]
+    fn sync_store(&mut self, _addrs: Vec<CellAddress>) {}
+/// Rebuilds the `dependencies` and `dependents` mappings from the formulas stored in every cell.
+    fn rebuild_dependencies(&mut self) {
+        self.dependencies.clear();
+        self.dependents.clear();
+        let formulas: Vec<(String, String)> = self.data.iter()
+            .filter_map(|(addr, cell)| cell.formula.as_ref().map(|f| (addr.clone(), format!("={}", f))))
+            .collect();
+        for (addr, formula) in formulas {
+            self.update_dependencies(&addr, &formula);
+        }
+    }
+/// Re-evaluates every formula cell in dependency order and rebuilds the dependency graph.
 ///
-/// * `query` - The string to search for in the cell values.
+/// Each formula is evaluated once, after all the formula cells it refers to. Cells that are part of a
+/// circular reference are not evaluated and keep their stored values; a formula that no longer
+/// evaluates is marked with its error. Locked cells are recalculated as well, and nothing is recorded
+/// in the undo history. With `chunked_recalc` set, more than `RECALC_BATCH_CELLS` formulas are only
+/// queued here and evaluated by `recalculate_batch` from the event loop.
 ///
 /// # Returns
 ///
-/// Returns `true` if one or more matches are found, and sets the cursor to the first match. 
-/// Returns `false` if no matches are found.
-    fn find(&mut self, query: &str) -> bool {
-        self.find_matches.clear();
-        self.find_query = query.to_string();
-        
-        // Search for matches
-        for col in 0..self.max_cols {
-            for row in 0..self.max_rows {
-                let addr = CellAddress::new(col, row);
-                if let Some(cell) = self.get_cell(&addr) {
-                    if cell.display_value.contains(query) {
-                        self.find_matches.push(addr);
+/// The number of formula cells to re-evaluate.
+    fn recalculate_all(&mut self) -> usize {
+        self.stale.clear();
+        self.rebuild_dependencies();
+
+        // Kahn's algorithm over the formula cells only; plain values never need evaluating
+        let mut pending: HashMap<String, usize> = HashMap::new();
+        for (addr, cell) in &self.data {
+            if cell.formula.is_some() {
+                let count = self.dependencies.get(addr).map_or(0, |deps| {
+                    deps.iter().filter(|d| self.data.get(*d).is_some_and(|c| c.formula.is_some())).count()
+                });
+                pending.insert(addr.clone(), count);
+            }
+        }
+        let mut ready: Vec<String> = pending.iter().filter(|(_, n)| **n == 0).map(|(a, _)| a.clone()).collect();
+        ready.sort();
+        let mut order = Vec::new();
+        while let Some(addr) = ready.pop() {
+            if let Some(deps) = self.dependents.get(&addr) {
+                for dep in deps {
+                    if let Some(count) = pending.get_mut(dep) {
+                        *count -= 1;
+                        if *count == 0 {
+                            ready.push(dep.clone());
+                        }
                     }
                 }
             }
+            order.push(addr);
         }
-        
-        if !self.find_matches.is_empty() {
-            self.current_find_match = 0;
-            self.cursor = self.find_matches[0].clone();
-            self.status_message = format!("{} MATCHES FOUND", self.find_matches.len());
-            true
-        } else {
-            self.status_message = "NO MATCHES FOUND".to_string();
-            false
+
+        // With an empty graph, evaluating a cell cannot trigger propagation into cells not yet in order
+        self.dependencies.clear();
+        self.dependents.clear();
+        self.recalc_total = order.len();
+        self.recalc_queue = order.into();
+        if !self.chunked_recalc || self.recalc_total <= RECALC_BATCH_CELLS {
+            self.finish_recalculation();
         }
+        self.recalc_total
     }
-/// Navigates to the next matching cell in the find results. The cursor will be updated to the next
-/// match in the list of search results.
+/// Evaluates the next queued formula cells of a recalculation started by `recalculate_all`, and
+/// rebuilds the dependency graph once the queue is empty. The status message is left as it was.
 ///
-/// # Returns
+/// # Arguments
 ///
-/// Returns `true` if a match is found and the cursor is updated. Returns `false` if no matches have been found.
-    fn find_next(&mut self) -> bool {
-        if self.find_matches.is_empty() {
-            return false;
-        }
-        
-        self.current_find_match = (self.current_find_match + 1) % self.find_matches.len();
-        self.cursor = self.find_matches[self.current_find_match].clone();
-        true
-    }
-/// Navigates to the previous matching cell in the find results. The cursor will be updated to the previous
-/// match in the list of search results.
+/// * `limit` - The most formula cells to evaluate.
 ///
 /// # Returns
 ///
-/// Returns `true` if a match is found and the cursor is updated. Returns `false` if no matches have been found.
-    fn find_prev(&mut self) -> bool {
-        if self.find_matches.is_empty() {
+/// `true` if formula cells are still waiting to be evaluated.
+    pub fn recalculate_batch(&mut self, limit: usize) -> bool {
+        if self.recalc_queue.is_empty() {
             return false;
         }
-        
-        if self.current_find_match == 0 {
-            self.current_find_match = self.find_matches.len() - 1;
-        } else {
-            self.current_find_match -= 1;
+        let status = self.status_message.clone();
+        for _ in 0..limit {
+            let Some(addr_str) = self.recalc_queue.pop_front() else {
+                break;
+            };
+            self.report_progress("RECALCULATING", self.recalc_total - self.recalc_queue.len() - 1, self.recalc_total);
+            let (Some(addr), Some(cell)) = (CellAddress::from_str(&addr_str), self.data.get_mut(&addr_str)) else {
+                continue;
+            };
+            let locked = std::mem::replace(&mut cell.is_locked, false);
+            let formula = format!("={}", cell.formula.clone().unwrap_or_default());
+            let evaluated = self.apply_cell_update(&addr, &formula);
+            // A rejected formula leaves its text in place
+            let reason = self.status_message.trim_start_matches("ERROR: ").to_string();
+            if let Some(cell) = self.data.get_mut(&addr_str) {
+                cell.is_locked = locked;
+                if !evaluated {
+                    cell.error = Some(reason);
+                }
+            }
+        }
+        self.status_message = status;
+        if self.recalc_queue.is_empty() {
+            self.rebuild_dependencies();
+            return false;
         }
-        
-        self.cursor = self.find_matches[self.current_find_match].clone();
         true
     }
-
-    /// Parses a range string in the format "A1:B5" into two `CellAddress` objects representing
-/// the starting and ending cell addresses. If the format is invalid, returns `None`.
+/// Evaluates everything left of a chunked recalculation, so the cells are up to date before they
+/// are edited, saved or searched.
+    fn finish_recalculation(&mut self) {
+        while self.recalculate_batch(usize::MAX) {}
+    }
+/// Imports a CSV file (as exported by Excel or LibreOffice) into the sheet, starting at `A1`.
+///
+/// Fields separated by `,` or `;` (whichever the first line uses more) are entered cell by cell.
+/// Formulas are passed through `translate_excel_formula`; those that cannot be translated are kept as
+/// plain text and listed in the summary. Constant values are entered first so formulas see them.
+/// The whole import is recorded as one undoable action.
 ///
 /// # Arguments
 ///
-/// * `range_str` - A string representing the range to parse (e.g., "A1:B5").
+/// * `path` - The path to the CSV file.
 ///
 /// # Returns
 ///
-/// Returns an `Option` containing a tuple of `CellAddress` objects for the start and end cells if valid,
-/// or `None` if the format is invalid or the cell addresses cannot be parsed.
-    fn parse_range(&self, range_str: &str) -> Option<(CellAddress, CellAddress)> {
-        let parts: Vec<&str> = range_str.split(':').collect();
-        if parts.len() != 2 {
-            return None;
+/// The number of imported cells and a list of untranslated formulas (`"B4: UNSUPPORTED FUNCTION VLOOKUP"`),
+/// or an error if the file cannot be read.
+    pub fn import_csv(&mut self, path: &Path) -> io::Result<(usize, Vec<String>)> {
+        let contents = std::fs::read_to_string(path)?;
+        let first_line = contents.lines().next().unwrap_or("");
+        let delimiter = if first_line.matches(';').count() > first_line.matches(',').count() { ';' } else { ',' };
+
+        let mut values = Vec::new();
+        let mut formulas = Vec::new();
+        for (row, line) in contents.lines().enumerate().filter(|(row, _)| *row < self.max_rows) {
+            for (col, field) in split_csv_line(line, delimiter).into_iter().enumerate().take(self.max_cols) {
+                let field = field.trim().to_string();
+                if field.is_empty() {
+                    continue;
+                }
+                let addr = CellAddress::new(col, row);
+                if field.starts_with('=') {
+                    formulas.push((addr, field));
+                } else {
+                    values.push((addr, field));
+                }
+            }
         }
-        
-        let start = CellAddress::from_str(parts[0])?;
-        let end = CellAddress::from_str(parts[1])?;
-        
-        Some((start, end))
+        Ok(self.import_entries(values, formulas))
     }
-/// Inserts a specified value into a range of cells. The range is parsed from the `range_str`
-/// argument (e.g., "A1:B3"), and the value is inserted into all cells within that range. 
-/// The undo stack is updated before any changes are made.
+/// Enters imported values and formulas as one undoable action. Values go first so formulas see
+/// them; formulas are passed through `translate_excel_formula`, and those that cannot be translated
+/// are kept as plain text and reported. In the editor, more than `RECALC_BATCH_CELLS` formulas are
+/// entered without being evaluated and left to a chunked `recalculate_all`; a formula the engine then
+/// rejects shows as an error instead of being reported here.
 ///
 /// # Arguments
 ///
-/// * `range_str` - A string representing the range to insert the value into (e.g., "A1:B3").
-/// * `value` - The value to insert into the specified range of cells.
+/// * `values` - Constant cell contents by address.
+/// * `formulas` - Excel formulas (starting with `=`) by address.
 ///
 /// # Returns
 ///
-/// Returns `true` if the value was successfully inserted into the specified range, or `false` if:
-/// - The range is invalid.
-/// - Any of the cells in the range are locked (the update will skip locked cells).
-/// - An error occurs while processing the range.
-    fn multi_insert(&mut self, range_str: &str, value: &str) -> bool {
-        // Remove brackets if present
-        let range_str = range_str.trim_start_matches('[').trim_end_matches(']');
-        
-        if let Some((start, end)) = self.parse_range(range_str) {
-            let start_col = start.col.min(end.col);
-            let end_col = start.col.max(end.col);
-            let start_row = start.row.min(end.row);
-            let end_row = start.row.max(end.row);
-            self.push_undo_sheet();
-            self.redo_stack.clear(); 
-            for col in start_col..=end_col {
-                for row in start_row..=end_row {
-                    let addr = CellAddress::new(col, row);
-                    if !self.update_cell(&addr, value,true) {
-                        // If any cell fails (e.g., is locked), continue with the rest
-                        continue;
+/// The number of imported cells and a list of untranslated formulas (`"B4: UNSUPPORTED FUNCTION VLOOKUP"`).
+    fn import_entries(&mut self, values: Vec<(CellAddress, String)>, formulas: Vec<(CellAddress, String)>) -> (usize, Vec<String>) {
+        self.begin_action();
+        let mut imported = 0;
+        let mut untranslated = Vec::new();
+        let total = values.len() + formulas.len();
+        let value_count = values.len();
+        for (done, (addr, value)) in values.into_iter().enumerate() {
+            self.report_progress("IMPORTING", done, total);
+            if self.update_cell(&addr, &value, true) {
+                imported += 1;
+            }
+        }
+        let deferred = self.chunked_recalc && formulas.len() > RECALC_BATCH_CELLS;
+        for (done, (addr, formula)) in formulas.into_iter().enumerate() {
+            self.report_progress("IMPORTING", value_count + done, total);
+            match translate_excel_formula(&formula) {
+                Ok(value) if deferred && value.starts_with('=') => {
+                    if let Some(cell) = self.get_cell_mut(&addr).filter(|cell| !cell.is_locked) {
+                        cell.formula = Some(value[1..].to_string());
+                        cell.raw_value.clear();
+                        cell.display_value.clear();
+                        imported += 1;
+                    }
+                }
+                Ok(value) if self.update_cell(&addr, &value, true) => imported += 1,
+                Ok(value) => untranslated.push(format!("{}: {}", addr, value)),
+                Err(reason) => {
+                    // Keep the original text visible so it can be fixed by hand
+                    if let Some(cell) = self.get_cell_mut(&addr).filter(|cell| !cell.is_locked) {
+                        cell.formula = None;
+                        cell.raw_value = formula.clone();
+                        cell.display_value = formula;
                     }
+                    untranslated.push(format!("{}: {}", addr, reason));
                 }
             }
-            
-            self.status_message = "MULTIPLE INSERTS".to_string();
-            true
-        } else {
-            self.status_message = "INVALID RANGE".to_string();
-            false
         }
+        self.commit_action();
+        if deferred {
+            self.recalculate_all();
+        }
+        (imported, untranslated)
     }
-/// Saves the current spreadsheet data as a JSON file to the specified path.
-///
-/// # Arguments
+/// Imports the first (or a named) sheet of an `.xlsx`, `.xlsm`, `.xlsb`, `.xls` or `.ods` workbook,
+/// starting at `A1`.
 ///
-/// * `path` - The path where the JSON file should be saved.
-///
-/// # Returns
-///
-/// Returns `io::Result<()>`, which will be `Ok` if the file is written successfully, or an error if
-/// there is an issue with creating or writing to the file.
-    fn save_json(&self, path: &Path) -> io::Result<()> {
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(writer, &self.data)?;
-        Ok(())
-    }
-/// Loads spreadsheet data from a JSON file at the specified path.
+/// Numbers and text are entered as they are, booleans as `TRUE`/`FALSE`, error values as their
+/// Excel code (e.g. `#DIV/0!`) and dates as date serials shown with the locale's date format.
+/// Formulas go through the same translation as CSV imports. The import is one undoable action.
 ///
 /// # Arguments
 ///
-/// * `path` - The path to the JSON file containing the spreadsheet data.
+/// * `path` - The path to the workbook.
+/// * `sheet` - The name of the sheet to import, or `None` for the first sheet.
 ///
 /// # Returns
 ///
-/// Returns `io::Result<()>`, which will be `Ok` if the file is read and the data is successfully loaded,
-/// or an error if the file cannot be opened or the data cannot be parsed.
-    fn load_json(&mut self, path: &Path) -> io::Result<()> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        self.data = serde_json::from_reader(reader)?;
-        
-        // Reset max rows and columns
-        self.max_rows = 0;
-        self.max_cols = 0;
-        
-        // Scan through all cell addresses to find the maximum row and column
-        for addr_str in self.data.keys() {
-            if let Some(addr) = CellAddress::from_str(addr_str) {
-                // Update max_rows if this cell's row is larger
-                if addr.row > self.max_rows {
-                    self.max_rows = addr.row;
+/// The number of imported cells and a list of problems (untranslated formulas and cells outside
+/// the sheet), or an error if the workbook cannot be read or has no such sheet.
+    fn import_workbook(&mut self, path: &Path, sheet: Option<&str>) -> io::Result<(usize, Vec<String>)> {
+        let to_io = |e: calamine::Error| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let mut workbook = open_workbook_auto(path).map_err(to_io)?;
+        let names = workbook.sheet_names();
+        let name = match sheet {
+            Some(sheet) => names.iter().find(|n| n.eq_ignore_ascii_case(sheet)).cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("NO SHEET NAMED {} (SHEETS: {})", sheet, names.join(", ")))
+            })?,
+            None => names.first().cloned().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "WORKBOOK HAS NO SHEETS"))?,
+        };
+        let range = workbook.worksheet_range(&name).map_err(to_io)?;
+        // Not every format stores formulas (e.g. some older .xls files); values still import
+        let formula_range = workbook.worksheet_formula(&name).ok();
+
+        let mut problems = Vec::new();
+        let mut outside = 0;
+        let mut in_sheet = |row: usize, col: usize| {
+            let fits = row < self.max_rows && col < self.max_cols;
+            if !fits {
+                outside += 1;
+            }
+            fits.then(|| CellAddress::new(col, row))
+        };
+
+        let mut formulas = Vec::new();
+        let mut formula_cells = HashSet::new();
+        if let Some(formula_range) = &formula_range {
+            let (top, left) = formula_range.start().unwrap_or((0, 0));
+            for (row, col, formula) in formula_range.used_cells() {
+                if formula.is_empty() {
+                    continue;
                 }
-                
-                // Update max_cols if this cell's column is larger
-                if addr.col > self.max_cols {
-                    self.max_cols = addr.col;
+                if let Some(addr) = in_sheet(top as usize + row, left as usize + col) {
+                    formula_cells.insert(addr.to_string());
+                    formulas.push((addr, format!("={}", formula.trim_start_matches('='))));
                 }
             }
         }
-        
-        // If no cells were found, set defaults
-        if self.max_rows == 0 {
-            self.max_rows = 10; // Default number of rows
+
+        let mut values = Vec::new();
+        let mut dates = Vec::new();
+        let (top, left) = range.start().unwrap_or((0, 0));
+        for (row, col, data) in range.used_cells() {
+            let Some(addr) = in_sheet(top as usize + row, left as usize + col) else {
+                continue;
+            };
+            // Cached results of formula cells are recalculated by the engine instead
+            if formula_cells.contains(&addr.to_string()) {
+                continue;
+            }
+            let value = match data {
+                Data::Int(n) => n.to_string(),
+                Data::Float(f) => f.to_string(),
+                Data::String(text) => text.clone(),
+                Data::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+                Data::DateTime(date) => {
+                    dates.push(addr.clone());
+                    date.as_f64().floor().to_string()
+                }
+                Data::DateTimeIso(text) | Data::DurationIso(text) => text.clone(),
+                Data::Error(e) => e.to_string(),
+                Data::Empty => continue,
+            };
+            values.push((addr, value));
         }
-        
-        if self.max_cols == 0 {
-            self.max_cols = 10; // Default number of columns
+        if outside > 0 {
+            problems.push(format!("{} CELLS OUTSIDE THE {}x{} SHEET SKIPPED", outside, self.max_rows, self.max_cols));
         }
-        self.max_rows += 1; // Adjust for 0-based indexing
-        self.max_cols += 1; // Adjust for 0-based indexing
-        // println!("DEBUG: Max rows: {}, Max cols: {}", self.max_rows, self.max_cols);
-        unsafe {
-            C = self.max_cols;
-            R = self.max_rows;
+
+        self.begin_action();
+        let (imported, untranslated) = self.import_entries(values, formulas);
+        let date_format = format!("date:{}", locale_date_pattern());
+        for addr in dates {
+            if let Some(cell) = self.get_cell_mut(&addr).filter(|cell| !cell.is_locked) {
+                cell.format = Some(date_format.clone());
+            }
         }
-        
-        Ok(())
+        self.commit_action();
+        problems.extend(untranslated);
+        Ok((imported, problems))
     }
 /// Sorts the rows within a specified range of cells based on the values in a given column. The rows
 /// can be sorted in either ascending or descending order.
@@ -1595,8 +6655,14 @@ impl Spreadsheet {
 /// 3. Applies the sorted rows back to the sheet.
 /// 4. The undo stack is updated before sorting, and the redo stack is cleared.
 ///
-/// If a cell is locked, it will not be modified during the sorting operation.
-    fn sort_range(&mut self, range_str: &str, ascending: bool) -> bool {
+/// Whole rows move, so a row holding a locked cell stays where it is and the other rows are sorted
+/// into the remaining positions around it. With `strict_locks` set, the sort is refused instead, as it
+/// always is when the rows reach into a protected range or sheet. With `sort_in_range` set
+/// (`:sortmode range`), only the cells of the range's columns move and the rest of each row stays put.
+    pub fn sort_range(&mut self, range_str: &str, ascending: bool) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
         // Remove brackets if present
         let range_str = range_str.trim_start_matches('[').trim_end_matches(']');
     
@@ -1604,17 +6670,38 @@ impl Spreadsheet {
             let col = start.col;
             let start_row = start.row;
             let end_row = end.row;
+            // The columns that move: the whole rows, or only the range's own columns
+            let (first_col, last_col) = if self.sort_in_range {
+                (start.col.min(end.col), start.col.max(end.col))
+            } else {
+                (0, self.max_cols.saturating_sub(1))
+            };
+            let width = last_col - first_col + 1;
+            // Any protected cell among the moving ones stops the sort
+            if let Some(area) = self.protection.covering(&CellAddress::new(first_col, start_row), &CellAddress::new(last_col, end_row)) {
+                self.status_message = format!("ERROR: {} PROTECTED", area);
+                return false;
+            }
+            let locked = self.locked_cells(&CellAddress::new(first_col, start_row), &CellAddress::new(last_col, end_row));
+            if self.strict_locks && !locked.is_empty() {
+                self.status_message = format!("ERROR: {} LOCKED CELLS IN ROWS {}-{}, FIRST {}", locked.len(), start_row + 1, end_row + 1, locked[0]);
+                return false;
+            }
+            let locked_rows: HashSet<usize> = locked.iter().map(|addr| addr.row).collect();
+            let free_rows: Vec<usize> = (start_row..=end_row).filter(|row| !locked_rows.contains(row)).collect();
     
-            // Save the current state for undo before sorting
-            self.push_undo_sheet();
-            self.redo_stack.clear();
+            // Record the sort as one undoable action
+            self.begin_action();
     
-            // Collect full rows with the value in the sort column
+            // Collect the moving part of each row with the value in the sort column
             let mut rows: Vec<(usize, Vec<Cell>)> = Vec::new();
+            // Every cell is read once and written once
+            let total = 2 * free_rows.len() * width;
     
-            for row in start_row..=end_row {
+            for (i, &row) in free_rows.iter().enumerate() {
+                self.report_progress("SORTING", i * width, total);
                 let mut row_cells = Vec::new();
-                for c in 0..self.max_cols {
+                for c in first_col..=last_col {
                     let addr = CellAddress::new(c, row);
                     if let Some(cell) = self.get_cell(&addr).cloned() {
                         row_cells.push(cell);
@@ -1627,8 +6714,8 @@ impl Spreadsheet {
     
             // Sort rows based on value in the specified column
             rows.sort_by(|a, b| {
-                let val_a = &a.1.get(col).map_or("", |cell| &cell.display_value);
-                let val_b = &b.1.get(col).map_or("", |cell| &cell.display_value);
+                let val_a = &a.1.get(col - first_col).map_or("", |cell| &cell.display_value);
+                let val_b = &b.1.get(col - first_col).map_or("", |cell| &cell.display_value);
                 
                 // Try to compare as numbers first
                 if let (Ok(num_a), Ok(num_b)) = (val_a.parse::<f64>(), val_b.parse::<f64>()) {
@@ -1642,211 +6729,547 @@ impl Spreadsheet {
             });
     
             // Apply sorted rows back
+            let collected = total / 2;
             for (i, (_, row_cells)) in rows.into_iter().enumerate() {
-                let new_row = start_row + i;
+                self.report_progress("SORTING", collected + i * width, total);
+                let new_row = free_rows[i];
                 for (c, cell) in row_cells.into_iter().enumerate() {
-                    let addr = CellAddress::new(c, new_row);
+                    let addr = CellAddress::new(first_col + c, new_row);
                     if let Some(target) = self.get_cell_mut(&addr) {
-                        if !target.is_locked {
-                            *target = cell;
-                        }
+                        *target = cell;
                     } else {
                         // Insert new cell if it doesn't exist
                         let addr_str = addr.to_string();
                         self.data.insert(addr_str, cell);
                     }
+                    // A formula moved to another row reads the same cells from its new address
+                    self.relink_cell(&addr.to_string());
+                }
+            }
+    
+            self.commit_action();
+            let sorted = if self.sort_in_range { "RANGE SORT APPLIED" } else { "ROW SORT APPLIED" };
+            self.status_message = match locked_rows.len() {
+                0 => sorted.to_string(),
+                kept => format!("{}, {} LOCKED ROWS KEPT IN PLACE", sorted, kept),
+            };
+            true
+        } else {
+            self.status_message = self.range_error(range_str);
+            false
+        }
+    }
+/// Formats the value of a cell for display, taking into account its width and alignment.
+///
+/// # Arguments
+///
+/// * `addr` - A reference to the `CellAddress` of the cell whose value is to be formatted.
+///
+/// # Returns
+///
+/// Returns a `String` containing the formatted cell value, which may be truncated to fit the cell's width
+/// and padded according to the specified alignment (left, right, or center).
+/// # Notes
+///
+/// This function formats the value of the cell to fit within the defined width:
+/// - If the cell's value exceeds its width, it will be truncated with an ellipsis (`..`) if there's enough space.
+/// - The cell's value will be padded with spaces based on its alignment (left, right, or center).
+///
+/// If the width is too small to display any part of the value, the cell will display a series of periods (`"."`).
+    fn format_cell_value(&self, addr: &CellAddress) -> String {
+        let cell = self.get_cell(addr).clone().unwrap(); 
+        let width = cell.width;
+        let mut value = cell.formatted_value();
+        if display_width(&value) > width {
+            if width >= 3 {
+                value = format!("{}..", take_width(&value, width - 2));
+            } else {
+                value = ".".repeat(width); // Not enough space for any content
+            }
+        }
+        align_to_width(&value, width, &cell.alignment)
+    }
+/// Returns the lines a cell is drawn with, one for each row of its height. A wrapping cell fills
+/// them with its value broken at spaces (cut off with `..` on the last line if it still does not
+/// fit); otherwise the formatted value is on the first line and the lines below it are blank.
+    pub fn cell_lines(&self, addr: &CellAddress) -> Vec<String> {
+        let Some(cell) = self.get_cell(addr) else {
+            return Vec::new();
+        };
+        let height = cell.height.max(1);
+        let mut lines = if cell.wrap {
+            let mut wrapped = wrap_text(&cell.formatted_value(), cell.width);
+            if wrapped.len() > height {
+                wrapped.truncate(height);
+                let last = &mut wrapped[height - 1];
+                *last = if cell.width >= 3 {
+                    format!("{}..", take_width(last, cell.width - 2))
+                } else {
+                    ".".repeat(cell.width)
+                };
+            }
+            wrapped.iter().map(|line| align_to_width(line, cell.width, &cell.alignment)).collect()
+        } else {
+            vec![self.format_cell_value(addr)]
+        };
+        lines.resize(height, " ".repeat(cell.width));
+        lines
+    }
+/// Exports the spreadsheet data to a PDF file with formatted content including rows, columns, and cell values.
+///
+/// The export includes the following features:
+/// - Data from the spreadsheet is formatted in a table with bold row numbers and column headers and grid lines.
+/// - Each column is as wide as its widest cell (the cell widths set with `dim`), and values are aligned
+///   like the cells. Values too long for their column are cut off with `...`, or wrapped onto extra
+///   lines of a taller row when `wrap` is set.
+/// - Content is split into multiple pages if there are more rows than can fit on one page, and across
+///   pages horizontally if the columns do not fit the page width.
+/// - An optional title heads every page, and page numbers are included in the footer (e.g., "Page 1 of 3").
+///
+/// # Arguments
+///
+/// * `filename` - The name of the output PDF file. This is where the PDF will be saved.
+/// * `range` - The range to print, or `None` for the used part of the sheet (from `A1` to the last cell with content).
+/// * `options` - Page orientation, title, font size and wrapping.
+///
+/// # Returns
+///
+/// Returns a `Result<(), io::Error>`. On success, it returns `Ok(())`. On failure, it returns an `Err`
+/// with the error details.
+///
+/// # Notes
+///
+/// This function does the following:
+/// 1. Creates a new PDF document with A4 page dimensions.
+/// 2. Works out the column widths and groups the columns into page-wide bands.
+/// 3. For each band, splits the rows across pages (all rows of a band are printed before the next band).
+/// 4. Draws the title, column headers, row numbers, cell values and the grid on each page.
+/// 5. Adds page numbers to the bottom of each page (e.g., "Page X of Y").
+/// 6. Saves the PDF document to the provided file path.
+    fn export_to_pdf(&self, filename: &str, range: Option<(CellAddress, CellAddress)>, options: &PdfOptions) -> Result<()> {
+        use printpdf::{Line, PdfLayerReference, Point};
+
+        // Set page dimensions and layout parameters
+        let (page_width, page_height) = if options.landscape { (Mm(297.0), Mm(210.0)) } else { (Mm(210.0), Mm(297.0)) };
+        let margin_top = Mm(20.0);
+        let margin_bottom = Mm(20.0);
+        let margin_left = Mm(10.0);
+        let margin_right = Mm(10.0);
+        let font_size = options.font_size;
+        let line_height = Mm(font_size * 0.5); // Distance between wrapped lines
+        let row_padding = Mm(font_size * 0.1);
+        let char_width = font_size * 0.2; // Average width of a Helvetica character, in mm
+        let padding = Mm(1.5);
+        let title_height = if options.title.is_some() { Mm(font_size * 1.2) } else { Mm(0.0) };
+
+        // Create a new PDF document
+        let (doc, page1, layer1) = PdfDocument::new(options.title.as_deref().unwrap_or("Spreadsheet Export"), page_width, page_height, "Layer 1");
+
+        // Add the built-in Helvetica fonts
+        let to_io = |e: printpdf::Error| io::Error::other(format!("Error adding font: {}", e));
+        let font = doc.add_builtin_font(BuiltinFont::Helvetica).map_err(to_io)?;
+        let bold = doc.add_builtin_font(BuiltinFont::HelveticaBold).map_err(to_io)?;
+
+        // Columns are as wide as their widest cell, like on screen
+        let (start, end) = range.unwrap_or_else(|| self.used_range());
+        let col_chars: Vec<usize> = (start.col..=end.col).map(|col| {
+            (start.row..=end.row)
+                .filter_map(|row| self.get_cell(&CellAddress::new(col, row)))
+                .map(|cell| cell.width)
+                .fold(CellAddress::col_to_letters(col).len().max(3), usize::max)
+        }).collect();
+        let col_widths: Vec<Mm> = col_chars.iter().map(|&chars| Mm(chars as f32 * char_width) + padding + padding).collect();
+        let label_width = Mm((end.row + 1).to_string().len().max(3) as f32 * char_width) + padding + padding;
+
+        // Group the columns into bands that fit across one page
+        let available = page_width - margin_left - margin_right - label_width;
+        let mut bands: Vec<std::ops::Range<usize>> = Vec::new();
+        let mut band_start = 0;
+        let mut band_width = Mm(0.0);
+        for (i, width) in col_widths.iter().enumerate() {
+            if i > band_start && band_width + *width > available {
+                bands.push(band_start..i);
+                band_start = i;
+                band_width = Mm(0.0);
+            }
+            band_width += *width;
+        }
+        bands.push(band_start..col_widths.len());
+
+        // Splits a value into the lines printed in a column `chars` characters wide
+        let fit = |text: &str, chars: usize| -> Vec<String> {
+            let chars = chars.max(1);
+            let letters: Vec<char> = text.chars().collect();
+            if letters.len() <= chars {
+                vec![text.to_string()]
+            } else if options.wrap {
+                letters.chunks(chars).map(|chunk| chunk.iter().collect()).collect()
+            } else {
+                let kept: String = letters[..chars.saturating_sub(3)].iter().collect();
+                vec![format!("{}...", kept)]
+            }
+        };
+        let row_height = |lines: usize| Mm(line_height.0 * lines.max(1) as f32) + row_padding + row_padding;
+        let line = |layer: &PdfLayerReference, x1: Mm, y1: Mm, x2: Mm, y2: Mm| {
+            layer.add_line(Line { points: vec![(Point::new(x1, y1), false), (Point::new(x2, y2), false)], is_closed: false });
+        };
+
+        // A row number with the alignment and printed lines of each of its cells in the band
+        type LaidOutRow = (usize, Vec<(Alignment, Vec<String>)>);
+
+        // Store page indices for adding page numbers later
+        let mut pages = Vec::new();
+        let content_height = page_height - margin_top - margin_bottom - title_height - row_height(1);
+        for band in &bands {
+            // Lay out every row of the band, then split the rows into pages by their heights
+            let laid_out: Vec<LaidOutRow> = (start.row..=end.row).map(|row| {
+                let cells = band.clone().map(|col_idx| {
+                    let cell = self.get_cell(&CellAddress::new(start.col + col_idx, row)).cloned().unwrap_or_else(Cell::default);
+                    (cell.alignment.clone(), fit(&cell.formatted_value(), col_chars[col_idx]))
+                }).collect();
+                (row, cells)
+            }).collect();
+            let mut chunks: Vec<&[LaidOutRow]> = Vec::new();
+            let (mut chunk_start, mut used) = (0, Mm(0.0));
+            for (i, (_, cells)) in laid_out.iter().enumerate() {
+                let height = row_height(cells.iter().map(|(_, lines)| lines.len()).max().unwrap_or(1));
+                if i > chunk_start && used + height > content_height {
+                    chunks.push(&laid_out[chunk_start..i]);
+                    chunk_start = i;
+                    used = Mm(0.0);
+                }
+                used += height;
+            }
+            chunks.push(&laid_out[chunk_start..]);
+
+            for rows in chunks {
+                let (page, layer) = if pages.is_empty() {
+                    (page1, layer1)
+                } else {
+                    doc.add_page(page_width, page_height, format!("Page {}", pages.len() + 1))
+                };
+                pages.push((page, layer));
+                let current_layer = doc.get_page(page).get_layer(layer);
+                current_layer.set_outline_thickness(0.3);
+                current_layer.set_outline_color(printpdf::Color::Greyscale(printpdf::Greyscale::new(0.6, None)));
+
+                if let Some(title) = &options.title {
+                    current_layer.use_text(title, font_size * 1.4, margin_left, page_height - margin_top, &bold);
+                }
+                let top = page_height - margin_top - title_height;
+                let baseline = row_padding + Mm(line_height.0 * 0.75); // From the top of a row to its first baseline
+                let mut x_edges = vec![margin_left, margin_left + label_width];
+                for i in band.clone() {
+                    x_edges.push(*x_edges.last().unwrap() + col_widths[i]);
+                }
+                let right = *x_edges.last().unwrap();
+
+                // Draw column headers (A, B, C, etc.)
+                let mut y_edges = vec![top, top - row_height(1)];
+                for (i, col_idx) in band.clone().enumerate() {
+                    let col_label = CellAddress::col_to_letters(start.col + col_idx);
+                    let text_width = Mm(col_label.len() as f32 * char_width);
+                    let x = x_edges[i + 1] + Mm((col_widths[col_idx].0 - text_width.0) / 2.0);
+                    current_layer.use_text(col_label, font_size, x, top - baseline, &bold);
+                }
+
+                // Draw rows with row numbers for this page
+                for (row, cells) in rows {
+                    let row_top = *y_edges.last().unwrap();
+                    let lines_in_row = cells.iter().map(|(_, lines)| lines.len()).max().unwrap_or(1);
+                    let row_label = format!("{}", row + 1); // +1 because row numbers start at 1
+                    current_layer.use_text(&row_label, font_size, margin_left + padding, row_top - baseline, &bold);
+                    for (i, (alignment, lines)) in cells.iter().enumerate() {
+                        let (left, width) = (x_edges[i + 1], col_widths[band.start + i]);
+                        for (n, text) in lines.iter().enumerate() {
+                            let text_width = Mm(text.chars().count() as f32 * char_width);
+                            let x = match alignment {
+                                Alignment::Left => left + padding,
+                                Alignment::Right => left + width - padding - text_width,
+                                Alignment::Center => left + Mm((width.0 - text_width.0) / 2.0),
+                            };
+                            let y = row_top - baseline - Mm(line_height.0 * n as f32);
+                            current_layer.use_text(text, font_size, x, y, &font);
+                        }
+                    }
+                    y_edges.push(row_top - row_height(lines_in_row));
+                }
+
+                // Grid lines around every cell, including the header row and the row numbers
+                let bottom = *y_edges.last().unwrap();
+                for &y in &y_edges {
+                    line(&current_layer, margin_left, y, right, y);
                 }
+                for &x in &x_edges {
+                    line(&current_layer, x, top, x, bottom);
+                }
+            }
+        }
+
+        // Add page numbers
+        let page_count = pages.len();
+        for (i, (page, layer)) in pages.iter().enumerate() {
+            let layer_ref = doc.get_page(*page).get_layer(*layer);
+
+            // Add page number at bottom center
+            let page_text = format!("Page {} of {}", i + 1, page_count);
+            layer_ref.use_text(&page_text, 10.0, page_width / 2.0 - Mm(15.0), margin_bottom / 2.0, &font);
+        }
+
+        // Save the document
+        doc.save(&mut BufWriter::new(File::create(filename)?)).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("Error saving PDF: {}", e))
+        })?;
+
+        Ok(())
+    }
+/// Returns the smallest range starting at `A1` that contains every cell with content (a formula,
+/// a lock, or a value other than the initial `0`), used as the default area for exports.
+    fn used_range(&self) -> (CellAddress, CellAddress) {
+        let (mut last_col, mut last_row) = (0, 0);
+        for (addr_str, cell) in &self.data {
+            let used = cell.formula.is_some() || cell.is_locked || !(cell.raw_value.is_empty() || cell.raw_value == "0");
+            if let Some(addr) = CellAddress::from_str(addr_str).filter(|_| used) {
+                last_col = last_col.max(addr.col);
+                last_row = last_row.max(addr.row);
             }
-    
-            self.status_message = "ROW SORT APPLIED".to_string();
-            true
-        } else {
-            self.status_message = "INVALID RANGE".to_string();
-            false
         }
+        (CellAddress::new(0, 0), CellAddress::new(last_col, last_row))
     }
-/// Formats the value of a cell for display, taking into account its width and alignment.
+/// Exports a range as a LaTeX `tabular` using the `booktabs` rules (`\\toprule`, `\\midrule`,
+/// `\\bottomrule`), ready to `\\input` into a report. The first row of the range is set as the
+/// header. Each column is aligned like most of its cells; cells aligned differently get their own
+/// `\\multicolumn`. Special characters are escaped.
 ///
 /// # Arguments
 ///
-/// * `addr` - A reference to the `CellAddress` of the cell whose value is to be formatted.
+/// * `path` - The path where the `.tex` file should be saved.
+/// * `range` - The range to export, or `None` for the used part of the sheet.
 ///
 /// # Returns
 ///
-/// Returns a `String` containing the formatted cell value, which may be truncated to fit the cell's width
-/// and padded according to the specified alignment (left, right, or center).
-/// # Notes
+/// Returns `io::Result<()>`, which will be `Ok` if the file is written successfully, or an error if
+/// there is an issue with creating or writing to the file.
+    fn export_latex(&self, path: &Path, range: Option<(CellAddress, CellAddress)>) -> io::Result<()> {
+        let (start, end) = range.unwrap_or_else(|| self.used_range());
+        let (top, bottom) = (start.row.min(end.row), start.row.max(end.row));
+        let (left, right) = (start.col.min(end.col), start.col.max(end.col));
+        let spec = |alignment: &Alignment| match alignment {
+            Alignment::Left => 'l',
+            Alignment::Right => 'r',
+            Alignment::Center => 'c',
+        };
+        let cell_at = |col, row| self.get_cell(&CellAddress::new(col, row)).cloned().unwrap_or_else(Cell::default);
+
+        // The most common alignment of each column becomes its column spec
+        let column_specs: Vec<char> = (left..=right).map(|col| {
+            let mut counts = [('l', 0), ('c', 0), ('r', 0)];
+            for row in top..=bottom {
+                let align = spec(&cell_at(col, row).alignment);
+                if let Some(entry) = counts.iter_mut().find(|(a, _)| *a == align) {
+                    entry.1 += 1;
+                }
+            }
+            counts.iter().max_by_key(|(_, n)| *n).map_or('c', |(a, _)| *a)
+        }).collect();
+
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "% Requires \\usepackage{{booktabs}}")?;
+        writeln!(out, "\\begin{{tabular}}{{{}}}", column_specs.iter().collect::<String>())?;
+        writeln!(out, "\\toprule")?;
+        for row in top..=bottom {
+            let fields: Vec<String> = (left..=right).zip(&column_specs).map(|(col, column_spec)| {
+                let cell = cell_at(col, row);
+                let text = escape_latex(&cell.formatted_value());
+                let align = spec(&cell.alignment);
+                if align == *column_spec {
+                    text
+                } else {
+                    format!("\\multicolumn{{1}}{{{}}}{{{}}}", align, text)
+                }
+            }).collect();
+            writeln!(out, "{} \\\\", fields.join(" & "))?;
+            if row == top && bottom > top {
+                writeln!(out, "\\midrule")?;
+            }
+        }
+        writeln!(out, "\\bottomrule")?;
+        writeln!(out, "\\end{{tabular}}")?;
+        out.flush()
+    }
+/// Exports the used part of the sheet as an OpenDocument Spreadsheet (`.ods`) that LibreOffice
+/// opens natively. Numbers are stored as numbers, date-formatted cells as dates, and formulas as
+/// OpenFormula together with their current result. Formulas without an OpenDocument equivalent
+/// (`LINK`, `SLEEP`), or that refer to cells outside the exported range, are stored as their value only.
 ///
-/// This function formats the value of the cell to fit within the defined width:
-/// - If the cell's value exceeds its width, it will be truncated with an ellipsis (`..`) if there's enough space.
-/// - The cell's value will be padded with spaces based on its alignment (left, right, or center).
+/// # Arguments
 ///
-/// If the width is too small to display any part of the value, the cell will display a series of periods (`"."`).
-    fn format_cell_value(&self, addr: &CellAddress) -> String {
-        let cell = self.get_cell(addr).clone().unwrap(); 
-        let width = cell.width;
-        let mut value = cell.display_value.clone();
-        if value.len() > width {
-            if width >= 3 {
-                value = format!("{}..", &value[..width - 2]);
-            } else {
-                value = ".".repeat(width); // Not enough space for any content
-            }
+/// * `path` - The path where the `.ods` file should be saved.
+/// * `range` - The range to export, or `None` for the used part of the sheet. Cells keep their addresses.
+///
+/// # Returns
+///
+/// The number of formulas that had to be stored as plain values, or an error if the file cannot
+/// be written.
+    fn export_ods(&self, path: &Path, range: Option<(CellAddress, CellAddress)>) -> io::Result<usize> {
+        let (start, end) = range.unwrap_or_else(|| self.used_range());
+        let mut values_only = 0;
+        let mut content = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<office:document-content xmlns:office=\"urn:oasis:names:tc:opendocument:xmlns:office:1.0\" ",
+            "xmlns:table=\"urn:oasis:names:tc:opendocument:xmlns:table:1.0\" ",
+            "xmlns:text=\"urn:oasis:names:tc:opendocument:xmlns:text:1.0\" ",
+            "xmlns:of=\"urn:oasis:names:tc:opendocument:xmlns:of:1.2\" office:version=\"1.2\">\n",
+            "<office:body><office:spreadsheet><table:table table:name=\"Sheet1\">\n",
+        ));
+        // Cells keep their positions, so a range not starting at A1 is preceded by empty rows and columns
+        if start.row > 0 {
+            content.push_str(&format!("<table:table-row table:number-rows-repeated=\"{}\"><table:table-cell/></table:table-row>\n", start.row));
         }
-        let padding = width.saturating_sub(value.len());
-        
-    
-        match cell.alignment {
-            Alignment::Left => format!("{:<width$}", value, width = width),
-            Alignment::Right => format!("{:>width$}", value, width = width),
-            Alignment::Center => {
-                let left = padding / 2;
-                let right = padding - left;
-                format!(
-                    "{}{}{}",
-                    " ".repeat(left),
-                    value,
-                    " ".repeat(right)
-                )
+        for row in start.row..=end.row {
+            content.push_str("<table:table-row>");
+            if start.col > 0 {
+                content.push_str(&format!("<table:table-cell table:number-columns-repeated=\"{}\"/>", start.col));
             }
+            for col in start.col..=end.col {
+                let cell = self.get_cell(&CellAddress::new(col, row)).cloned().unwrap_or_else(Cell::default);
+                // Formulas referring to cells left out of the export are saved as values
+                let formula = match cell.formula.as_ref().filter(|f| refs_within(f, &start, &end)) {
+                    Some(f) => match to_open_formula(f) {
+                        Some(of) => format!(" table:formula=\"{}\"", escape_html(&of)),
+                        None => {
+                            values_only += 1;
+                            String::new()
+                        }
+                    },
+                    None if cell.formula.is_some() => {
+                        values_only += 1;
+                        String::new()
+                    }
+                    None => String::new(),
+                };
+                let shown = escape_html(&cell.formatted_value());
+                let date = cell.date_pattern()
+                    .and_then(|_| cell.display_value.parse::<f64>().ok())
+                    .and_then(serial_to_date);
+                if let Some(date) = date {
+                    content.push_str(&format!("<table:table-cell office:value-type=\"date\" office:date-value=\"{}\"{}><text:p>{}</text:p></table:table-cell>",
+                        date.format("%Y-%m-%d"), formula, shown));
+                } else if let Ok(number) = cell.display_value.parse::<f64>() {
+                    content.push_str(&format!("<table:table-cell office:value-type=\"float\" office:value=\"{}\"{}><text:p>{}</text:p></table:table-cell>",
+                        number, formula, shown));
+                } else if cell.display_value.is_empty() && formula.is_empty() {
+                    content.push_str("<table:table-cell/>");
+                } else {
+                    content.push_str(&format!("<table:table-cell office:value-type=\"string\"{}><text:p>{}</text:p></table:table-cell>",
+                        formula, shown));
+                }
+            }
+            content.push_str("</table:table-row>\n");
         }
+        content.push_str("</table:table></office:spreadsheet></office:body></office:document-content>\n");
+
+        let manifest = concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<manifest:manifest xmlns:manifest=\"urn:oasis:names:tc:opendocument:xmlns:manifest:1.0\" manifest:version=\"1.2\">\n",
+            " <manifest:file-entry manifest:full-path=\"/\" manifest:media-type=\"application/vnd.oasis.opendocument.spreadsheet\"/>\n",
+            " <manifest:file-entry manifest:full-path=\"content.xml\" manifest:media-type=\"text/xml\"/>\n",
+            "</manifest:manifest>\n",
+        );
+        let to_io = |e: zip::result::ZipError| io::Error::other(e.to_string());
+        let mut zip = zip::ZipWriter::new(File::create(path)?);
+        // The mimetype entry must come first and be stored uncompressed
+        zip.start_file("mimetype", SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored)).map_err(to_io)?;
+        zip.write_all(b"application/vnd.oasis.opendocument.spreadsheet")?;
+        zip.start_file("META-INF/manifest.xml", SimpleFileOptions::default()).map_err(to_io)?;
+        zip.write_all(manifest.as_bytes())?;
+        zip.start_file("content.xml", SimpleFileOptions::default()).map_err(to_io)?;
+        zip.write_all(content.as_bytes())?;
+        zip.finish().map_err(to_io)?;
+        Ok(values_only)
     }
-/// Exports the spreadsheet data to a PDF file with formatted content including rows, columns, and cell values.
-///
-/// The export includes the following features:
-/// - Data from the spreadsheet is formatted in a table-like structure with row numbers and column headers.
-/// - Content is split into multiple pages if there are more rows than can fit on one page.
-/// - Page numbers are included in the footer (e.g., "Page 1 of 3").
+/// Writes the display values of a range as comma-separated values, one line per row. Fields holding
+/// a comma, quote or line break are quoted.
 ///
 /// # Arguments
 ///
-/// * `filename` - The name of the output PDF file. This is where the PDF will be saved.
+/// * `path` - The path where the CSV file should be saved.
+/// * `range` - The range to export, or `None` for the used part of the sheet.
 ///
 /// # Returns
 ///
-/// Returns a `Result<(), io::Error>`. On success, it returns `Ok(())`. On failure, it returns an `Err`
-/// with the error details.
+/// Returns `io::Result<()>`, which will be `Ok` if the file is written successfully, or an error if
+/// there is an issue with creating or writing to the file.
+    pub fn export_csv(&self, path: &Path, range: Option<(CellAddress, CellAddress)>) -> io::Result<()> {
+        let (start, end) = range.unwrap_or_else(|| self.used_range());
+        let mut out = BufWriter::new(File::create(path)?);
+        for row in start.row..=end.row {
+            let fields: Vec<String> = (start.col..=end.col)
+                .map(|col| self.get_cell(&CellAddress::new(col, row)).map_or_else(String::new, |cell| csv_field(&cell.formatted_value())))
+                .collect();
+            writeln!(out, "{}", fields.join(","))?;
+        }
+        out.flush()
+    }
+/// Exports the used part of the sheet as a standalone HTML page holding one styled table.
 ///
-/// # Notes
+/// Styles are inline so the table survives being pasted into e-mails: alignment follows each
+/// cell's alignment, formula results are blue (with the formula as a tooltip), error values red,
+/// and locked cells have a grey background and a lock symbol.
 ///
-/// This function does the following:
-/// 1. Creates a new PDF document with A4 page dimensions.
-/// 2. Iterates through the spreadsheet data and splits it across pages if needed.
-/// 3. Draws the column headers and row numbers on each page.
-/// 4. Writes the cell values within the table format, considering cell width and row height.
-/// 5. Adds page numbers to the bottom of each page (e.g., "Page X of Y").
-/// 6. Saves the PDF document to the provided file path.
+/// # Arguments
 ///
-/// The resulting PDF will have the following layout:
-/// - Each page shows a part of the table with row numbers on the left, followed by columns A to J.
-/// - The table content will be truncated if the width of the columns exceeds the page width.
-/// - The rows will be adjusted to fit within the available content height on each page.
-    fn export_to_pdf(&self, filename: &str) -> Result<()> {
-        // Create a new PDF document
-        let ( doc, page1, layer1) = PdfDocument::new("Spreadsheet Export", Mm(210.0), Mm(297.0), "Layer 1");
-        let mut current_page = page1;
-        let mut current_layer = doc.get_page(current_page).get_layer(layer1);
-        
-        // Add the built-in Helvetica font
-        let font = doc.add_builtin_font(BuiltinFont::Helvetica).map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, format!("Error adding font: {}", e))
-        })?;
-        
-        // Set page dimensions and layout parameters
-        let page_width = Mm(210.0);  // A4 width
-        let page_height = Mm(297.0); // A4 height
-        let margin_top = Mm(20.0);
-        let margin_bottom = Mm(20.0);
-        let margin_left = Mm(10.0);
-        let cell_width = Mm(19.0);   // Adjusted to fit 10 columns (A-J) plus row numbers
-        let row_height = Mm(10.0);
-        
-        // Maximum rows per page calculation
-        let content_height = page_height - margin_top - margin_bottom;
-        let max_rows_per_page = (content_height.0 / row_height.0).floor() as i32 - 1; // -1 for header row
-        
-        // Calculate dimensions
-        let row_count = unsafe { R };
-        let col_count = unsafe { C };
-        let max_cols = 10; // Limit to 10 columns (A-J)
-        
-        // Store page indices for adding page numbers later
-        let mut page_indices = vec![page1];
-        
-        // Process the data in page chunks
-        let mut processed_rows = 0;
-        
-        while processed_rows < row_count {
-            // Calculate rows for current page
-            let rows_in_this_page = std::cmp::min(max_rows_per_page,(row_count - processed_rows) as i32);
-            let mut y_position = page_height - margin_top;
-            
-            // Draw column headers (A, B, C, etc.)
-            let mut x_position = margin_left + cell_width; // Starting after row numbers column
-            current_layer.use_text("", 10.0, margin_left, y_position, &font); // Empty top-left cell
-            
-            // Draw column headers A through J (limited to max_cols)
-            for col in 0..std::cmp::min(col_count, max_cols) {
-                let col_label = format!("{}", char::from(b'A' + col as u8));
-                current_layer.use_text(&col_label, 10.0, x_position, y_position, &font);
-                x_position += cell_width;
-            }
-            
-            y_position -= row_height;
-            
-            // Draw rows with row numbers for this page
-            for page_row in 0..rows_in_this_page {
-                let actual_row = processed_rows + page_row as usize;
-                
-                // Draw row number
-                let row_label = format!("{}", actual_row + 1); // +1 because row numbers start at 1
-                current_layer.use_text(&row_label, 10.0, margin_left, y_position, &font);
-                
-                // Draw cells for this row
-                x_position = margin_left + cell_width;
-                for col in 0..std::cmp::min(col_count, max_cols) {
-                    let addr = CellAddress::new(col, actual_row);
-                    let text = if let Some(cell) = self.get_cell(&addr) {
-                        cell.display_value.clone()
-                    } else {
-                        "".to_string()
-                    };
-                    
-                    current_layer.use_text(&text, 10.0, x_position, y_position, &font);
-                    x_position += cell_width;
+/// * `path` - The path where the HTML file should be saved.
+/// * `range` - The range to export, or `None` for the used part of the sheet.
+///
+/// # Returns
+///
+/// Returns `io::Result<()>`, which will be `Ok` if the file is written successfully, or an error if
+/// there is an issue with creating or writing to the file.
+    fn export_html(&self, path: &Path, range: Option<(CellAddress, CellAddress)>) -> io::Result<()> {
+        let (start, end) = range.unwrap_or_else(|| self.used_range());
+        let mut out = BufWriter::new(File::create(path)?);
+        let header_style = "background:#f0f3f7;color:#555;font-weight:bold;border:1px solid #c8ccd2;padding:4px 8px";
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, "<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Spreadsheet Export</title>\n</head>\n<body>")?;
+        writeln!(out, "<table style=\"border-collapse:collapse;font-family:Helvetica,Arial,sans-serif;font-size:13px\">")?;
+        write!(out, "<thead><tr><th style=\"{}\"></th>", header_style)?;
+        for col in start.col..=end.col {
+            write!(out, "<th style=\"{}\">{}</th>", header_style, CellAddress::col_to_letters(col))?;
+        }
+        writeln!(out, "</tr></thead>\n<tbody>")?;
+        for row in start.row..=end.row {
+            write!(out, "<tr><th style=\"{}\">{}</th>", header_style, row + 1)?;
+            for col in start.col..=end.col {
+                let cell = self.get_cell(&CellAddress::new(col, row)).cloned().unwrap_or_else(Cell::default);
+                let value = cell.formatted_value();
+                let align = match cell.alignment {
+                    Alignment::Left => "left",
+                    Alignment::Right => "right",
+                    Alignment::Center => "center",
+                };
+                let mut style = format!("border:1px solid #c8ccd2;padding:4px 8px;min-width:{}ch;text-align:{}", cell.width, align);
+                if value.starts_with('#') {
+                    style.push_str(";color:#b00020;background:#fdecea");
+                } else if cell.formula.is_some() {
+                    style.push_str(";color:#1a4f8b");
                 }
-                
-                y_position -= row_height;
-            }
-            
-            processed_rows += rows_in_this_page as usize ;
-            
-            // Create a new page if there are more rows to process
-            if processed_rows < row_count {
-                let (new_page, new_layer) = doc.add_page(page_width, page_height, format!("Page {}", processed_rows / (max_rows_per_page as usize) + 2));
-                current_page = new_page;
-                current_layer = doc.get_page(current_page).get_layer(new_layer);
-                page_indices.push(current_page); // Store the new page index
+                if cell.is_locked {
+                    style.push_str(";background:#e4e4e4");
+                }
+                let title = match (&cell.formula, cell.is_locked) {
+                    (Some(formula), true) => format!(" title=\"={} (locked)\"", escape_html(formula)),
+                    (Some(formula), false) => format!(" title=\"={}\"", escape_html(formula)),
+                    (None, true) => " title=\"locked\"".to_string(),
+                    (None, false) => String::new(),
+                };
+                let lock = if cell.is_locked { "&#128274; " } else { "" };
+                write!(out, "<td style=\"{}\"{}>{}{}</td>", style, title, lock, escape_html(&value))?;
             }
+            writeln!(out, "</tr>")?;
         }
-        
-        // Add page numbers
-        let page_count = page_indices.len();
-        for (i, page_index) in page_indices.iter().enumerate() {
-            let page_num = i + 1;
-            let layer_ref = doc.get_page(*page_index).get_layer(layer1); // Reuse layer1 or create new layers
-            
-            // Add page number at bottom center
-            let page_text = format!("Page {} of {}", page_num, page_count);
-            layer_ref.use_text(&page_text, 10.0, page_width / 2.0 - Mm(15.0), margin_bottom / 2.0, &font);
-        }
-        
-        // Save the document
-        doc.save(&mut BufWriter::new(File::create(filename)?)).map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, format!("Error saving PDF: {}", e))
-        })?;
-        
-        Ok(())
+        writeln!(out, "</tbody>\n</table>\n</body>\n</html>")?;
+        out.flush()
     }
 /// Processes and executes a command entered by the user.
 ///
@@ -1861,25 +7284,119 @@ impl Spreadsheet {
 ///
 /// # Command List
 /// - `"q"`: Quit the application.
+/// - `"help"`: Show the keys, commands and formula functions (see `COMMANDS`) on a scrollable screen.
 /// - `"i [cell]"`: Enter insert mode at the specified cell (or current cell if no cell specified).
 /// - `"j [cell]"`: Jump to the specified cell.
+/// - `"tick [ms]"`: Set how often the screen refreshes while idle (haunt effects, watch panel); at most every 16 ms.
+/// - `"undolimit [steps]"`: Limit how many actions can be undone; `0` (the default) keeps unlimited history.
+/// - `"timeout [ms]"`: Set the time budget for evaluating a single formula; a formula taking longer shows `#TIMEOUT!`.
+/// - `"calc [auto|lazy]"`: Update the dependents of an edited cell at once, or only mark them stale and evaluate
+///   them when they are shown, exported or read by another formula; without an argument, show the current mode.
+/// - `"locked [skip|fail]"`: Choose what `mi` and `sort` do with locked cells in their range: leave them (and the
+///   rows holding them) in place and report how many, or refuse the whole edit; without an argument, show the policy.
+/// - `"sortmode [rows|range]"`: Choose whether `sort` reorders whole rows (the default) or only the cells inside the
+///   sorted range, leaving the columns outside it alone; without an argument, show the mode.
+/// - `"share [file] [name]"`: Edit the sheet together with other clients opening the same session file (see
+///   `Session`); the first client's sheet is shared, later ones take it over. `name` is shown at this client's
+///   cursor on the others' screens (default: the user name and process id). `"unshare"` leaves the session and
+///   `"who"` lists the other clients and the cells they are on.
+/// - `"serve [address|off]"`: Start a WebSocket endpoint (default `127.0.0.1:8765`) that browser dashboards connect
+///   to: they receive the sheet, then every changed cell and cursor move as JSON, and can send `:` commands as
+///   `{"command": "..."}`, which run at the dashboard's own cursor (see `Dashboard`). A dashboard whose connection
+///   drops can reconnect to its session with the token it was given (see `RemoteSession`). `"serve off"` stops the
+///   endpoint. Needs the `websocket` feature.
+/// - `"history [cell]"`: Show the edit trail of a cell (default: the cursor): when its input changed, from what to
+///   what, and by whom in a shared session or from a dashboard (see `AuditEntry`).
+/// - `"audit [file|off]"`: Append every cell change to an audit file from now on, reading the changes already in
+///   it into the history; `"audit off"` stops writing to it.
 /// - `"undo"`: Undo the last operation.
 /// - `"redo"`: Redo the last undone operation.
-/// - `"find [search_term]"`: Enter find mode with the specified search term.
+/// - `"find [-v|-f|-a] [search_term]"`: Enter find mode with the specified search term; `find /regex/` searches by
+///   regular expression. `-v` searches values, `-f` raw formulas and `-a` cell addresses (the last choice is remembered).
+/// - `"replace [old] [new] [range] [-f] [-c]"`: Replace text in cell values (`-f` also inside formulas),
+///   either all at once or confirming each match (`-c`), as a single undoable action.
+/// - `"matches set [value]"` / `"matches clear"`: Write a value into (or clear) every cell matched by the last find,
+///   as one undoable action. Press `:` in find mode to enter these without losing the matches.
 /// - `"mi [start] [end]"`: Multi-insert command for a range of values.
 /// - `"lock [cell]"`: Lock the specified cell, or lock the current cell if no cell is specified.
 /// - `"unlock [cell]"`: Unlock the specified cell, or unlock the current cell if no cell is specified.
+/// - `"protect <range|sheet> [password]"`: Refuse edits, sorts and fills that reach into the range (or anywhere on
+///   the sheet). The first password given is saved as a salted hash with the sheet and is then needed by
+///   `"unprotect <range|sheet|all> [password]"`. `"protect"` alone lists what is protected.
+/// - `"group <first>:<last>"`: Group rows (e.g. `group 10:40`) so they can be folded away with `"collapse"` (or `zc`)
+///   and shown again with `"expand"` (or `zo`), keeping the rows around them, such as subtotals, in view. Groups may
+///   nest. `"ungroup"`, `"collapse"` and `"expand"` take the rows of a group or `all`, and default to the group at
+///   the cursor; `"group"` alone lists the groups.
+/// - `"validate [range] list <value>,<value>,..."`: Only accept these values in the range (default: the current
+///   cell); insert mode then offers them as a picker. `"validate [range] off"` removes the list.
+/// - `"demo [on|off]"`: Toggle demo mode, which shows pressed keys and commands in an overlay and records them.
+/// - `"demo save [filename]"`: Save the keys recorded in demo mode as a replayable script.
+/// - `"demo play [filename] [delay_ms]"`: Replay a script at human speed; any key press stops the replay.
+/// - `"refresh links"`: Re-read all external `LINK(path,cell)` sources and recalculate the cells using them.
+/// - `"alert [cell] [op] [value] ["message"] [bell]"`: Notify (and optionally ring the bell) when a cell's value satisfies a condition.
+/// - `"yank [range] [reg]"`: Copy a cell or range into register `a`-`z` (default: the unnamed register).
+/// - `"put [reg]"`: Paste a register at the cursor.
+/// - `"pivot <range> <cell> rows=<col> [cols=<col>] values=<function>(<col>)"`: Group the rows of a range (whose
+///   first row holds the headers) by the values in one or two of its columns and write a table of the combined
+///   `values` (`SUM`, `COUNT`, `AVG`, `MIN` or `MAX`) from `cell` on, e.g. `pivot A1:C50 E1 rows=A cols=B values=SUM(C)`.
+///   The table is not updated as the data changes; `"pivot refresh"` recomputes every table and `"pivot"` lists them.
+/// - `"registers"`: List the filled registers with their source range, size and first value.
+/// - `"marks"`: List the marks set with `m<reg>` and the cells they point to.
+/// - `"alerts"`: List the active alert and haunt rules; `"alert clear"` removes them all.
+/// - `"watch [cell]"`: Pin the specified cell (or the current cell) to the watch panel.
+/// - `"unwatch [cell]"`: Remove the specified cell (or the current cell) from the watch panel.
 /// - `"align [alignment]"`: Set alignment for the current cell or a specified cell.
+/// - `"fmt [range] date [pattern]"`: Show the cells as dates using a `strftime` pattern (e.g. `%d.%m.%Y`) or a
+///   preset (`iso`, `us`, `eu`, `uk`, `locale`; default is the locale's). `"fmt [range] clear"` removes the format.
+/// - `"color [range] [fg] [on bg]"`: Color the text and/or background of the current cell or a range (e.g. `color A1 red`,
+///   `color A1:C1 white on dark_blue`, `color on #ffcc00`); `none` resets one color, `"color [range] clear"` both.
+/// - `"bold [range]"` / `"italic [range]"`: Toggle bold or italic text for the current cell or a range.
+/// - `"wrap [range]"`: Toggle wrapping long values onto the lines of the cell's height.
 /// - `"dim [cell] (height,width)"`: Set dimensions (height and width) for a cell.
 /// - `"sort [range] [ascending_flag]"`: Sort a range of cells in ascending or descending order.
+/// - `"freeze [rows] [cols]"`: Keep the first rows and columns on screen while scrolling (e.g. `freeze 1 2` for a
+///   header row and two label columns); `"unfreeze"` (or `freeze 0 0`) scrolls the whole grid again.
+/// - `"chart [bar|line] [range]"`: Show a bar or line chart of the numbers in a range over the grid until the next key.
+/// - `"eval [expression]"`: Show the value of an expression such as `SUM(A1:B2)*2` without entering it into a cell.
+/// - `"grid [on|off]"` / `"zebra [on|off]"` / `"heatmap [on|off]"`: Turn box-drawing lines between cells, shading of
+///   every other row, or coloring numeric cells on a gradient from the lowest to the highest number,
+///   on or off (toggled without an argument).
+/// - `"sheet [name]"`: Switch to the named sheet; `sheet` alone lists the sheets. `"sheet new [name]"` adds a blank
+///   sheet, `"sheet rename [old] <new>"` renames one (default: the active sheet), `"sheet copy [name]"` duplicates the
+///   active sheet, `"sheet delete [name]"` removes one, and `"sheet next"` / `"sheet prev"` cycle through the tabs.
+///   Saving and loading work on the active sheet only.
 /// - `"saveas_<format> [filename]"`: Save the spreadsheet as the specified format (e.g., JSON or PDF).
-/// - `"load [filename]"`: Load a spreadsheet from a file.
+///   `"saveas_<format> [range] [filename]"` saves only that range (e.g. `saveas_csv A1:F200 out.csv`).
+///   `saveas_csv` writes the display values as comma-separated values.
+///   `saveas_pdf [filename] [landscape] [wrap] [size=N] [title="..."]` prints on landscape pages, wraps long
+///   values instead of cutting them off, sets the font size (6-24, default 10) or adds a title.
+///   `saveas_html` writes a styled HTML table of the used cells.
+///   `saveas_ods` writes an OpenDocument spreadsheet, keeping formulas where LibreOffice has an equivalent.
+///   `saveas_tex [filename] [range]` writes a booktabs `tabular` of the range (default: the used cells).
+///   `saveas_depcsv` writes the dependency graph as a `cell,depends_on` CSV adjacency list.
+///   `saveas_json [filename] -u` also saves the undo/redo history so it can be continued after reopening.
+///   `saveas_bin [filename] [-u]` saves the same data in a compact binary (MessagePack) file.
+///   `saveas_text [filename]` saves one line per cell, sorted by address and holding only what differs from
+///   a default cell, so a sheet kept in version control shows small diffs; `load` opens it like a JSON save.
+///   `saveas_log [filename]` appends the cells changed since the last save to a change log (one JSON line per save).
+///   `saveas_db [filename]` saves to a SQLite database that later edits are written to (`sqlite` feature).
+///   `saveas_json`, `saveas_bin`, `saveas_text` and `saveas_log` compress the file with gzip or zstd when its name ends in
+///   `.gz` or `.zst`; `load`, `load_bin` and `load_log` decompress such files the same way.
+/// - `"load [filename]"`: Load a spreadsheet from a file. A `.csv` file is imported instead, translating
+///   Excel formulas and listing the ones that could not be translated. `.xlsx`/`.xlsm`/`.xlsb`/`.xls`/`.ods`
+///   workbooks are imported the same way; `"load [workbook] [sheet]"` picks a sheet other than the first.
+/// - `"load_bin [filename]"`: Load a spreadsheet saved with `saveas_bin`.
+/// - `"load_log [filename] [revision]"`: Load the latest (or the given) revision of a change log.
+/// - `"compact_log [filename]"`: Fold a change log into a single snapshot of its latest revision.
+/// - `"open_db [filename]"`: Open a sheet saved with `saveas_db`, reading rows from the database as they are needed.
 /// - `"hh"`: Go to the leftmost cell in the current row.
 /// - `"ll"`: Go to the rightmost cell in the current row.
 /// - `"jj"`: Go to the bottommost cell in the current column.
 /// - `"kk"`: Go to the topmost cell in the current column.
 /// - `"haunt"`: Enable haunting mode, play a sound, and display a haunting message.
 /// - `"dehaunt"`: Disable haunting mode and stop the sound if it's playing.
+/// - `"haunt when [cell|ERRORS] [op] [value] [weight N]"`: Haunt the sheet while a condition holds, e.g.
+///   `haunt when ERRORS > 2 weight 2`; the intensity is the summed weight of the rules that hold.
 ///
 /// # Arguments
 ///
@@ -1894,10 +7411,53 @@ impl Spreadsheet {
     fn process_command(&mut self) -> bool {
         // First, copy the command buffer to a local String to avoid borrowing issues
         let cmd = self.command_buffer.trim().to_string();
+        if self.demo_mode {
+            self.demo_last_command = Some(cmd.clone());
+        }
+        self.finish_recalculation();
+        // Commands see every value up to date, whatever they read or export
+        self.evaluate_stale(None, None);
+        // A database-backed sheet reads in the rows a command works on; exports and searches need them all
+        if self.is_store_backed() {
+            let whole_sheet = ["saveas_", "find", "replace", "matches", "yank"].iter().any(|c| cmd.starts_with(c))
+                && !cmd.starts_with("saveas_db");
+            if whole_sheet {
+                self.page_in_rows(0, self.max_rows);
+            } else {
+                self.page_in_rows(self.cursor.row, self.cursor.row);
+                self.page_in_refs(&cmd);
+            }
+        }
         
         // Command parsing
         if cmd == "q" {
             return false; // Quit
+        } else if ["bold", "italic", "wrap"].iter().any(|style| cmd == *style || cmd.starts_with(&format!("{} ", style))) {
+            // Format: :bold [range] / :italic [range] / :wrap [range]; toggles the style, following the first
+            // cell of the range. Checked before `i`, which would otherwise take `italic` for an insert.
+            let mut words = cmd.split_whitespace();
+            let style = words.next().unwrap_or_default();
+            let range = words.next();
+            let first = match range {
+                Some(range) => self.parse_range(range).map(|(start, _)| start).or_else(|| CellAddress::from_str(range)),
+                None => Some(self.cursor.clone()),
+            };
+            let on = !first.and_then(|addr| self.get_cell(&addr)).is_some_and(|cell| match style {
+                "bold" => cell.bold,
+                "italic" => cell.italic,
+                _ => cell.wrap,
+            });
+            let applied = self.set_style(range, |cell| match style {
+                "bold" => cell.bold = on,
+                "italic" => cell.italic = on,
+                _ => cell.wrap = on,
+            });
+            if !applied && !self.readonly {
+                self.status_message = "INVALID STYLE RANGE".to_string();
+            }
+        } else if cmd == "help" {
+            self.mode = Mode::Help;
+            self.help_scroll = 0;
         } else if cmd.starts_with("i") {
             // Enter insert mode
             self.mode = Mode::Insert;
@@ -1913,35 +7473,270 @@ impl Spreadsheet {
                     self.status_message = "INVALID CELL".to_string();
                 }
             }
+            // A list-validated cell is filled from a picker instead of typed into
+            if let Some(choices) = self.cursor_choices().filter(|_| self.status_message == "INSERTING") {
+                self.status_message = format!("PICK WITH TAB OR ARROWS: {}", choices.join(" "));
+            }
             self.command_buffer.clear(); // Clear command buffer before entering new value
-        } else if cmd.starts_with("j") {
-            // Jump to cell
+        } else if cmd == "j" || cmd.starts_with("j ") {
+            // Jump to cell (not `jj`, which has its own branch)
             let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
             if parts.len() > 1 {
                 if !self.jump_to_cell(parts[1]) {
                     self.status_message = "INVALID CELL".to_string();
                 }
             }
+        } else if cmd.starts_with("tick") {
+            // Set the idle tick interval in milliseconds
+            let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
+            match parts.get(1).and_then(|ms| ms.trim().parse::<u64>().ok()) {
+                Some(ms) if parts[0] == "tick" && ms > 0 => {
+                    self.tick_rate = Duration::from_millis(ms);
+                    self.status_message = format!("TICK RATE SET TO {}MS", ms);
+                }
+                _ => {
+                    self.status_message = "USAGE: tick <milliseconds>".to_string();
+                }
+            }
+        } else if cmd.starts_with("timeout") {
+            // Set the recalculation time budget in milliseconds
+            let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
+            match parts.get(1).and_then(|ms| ms.trim().parse::<u64>().ok()) {
+                Some(ms) if parts[0] == "timeout" => {
+                    self.eval_timeout = Duration::from_millis(ms);
+                    self.status_message = format!("EVALUATION TIMEOUT SET TO {}MS", ms);
+                }
+                _ => {
+                    self.status_message = "USAGE: timeout <milliseconds>".to_string();
+                }
+            }
+        } else if cmd == "calc" || cmd.starts_with("calc ") {
+            // Format: :calc [auto|lazy]
+            let lazy = match cmd.strip_prefix("calc").unwrap_or("").trim() {
+                "" => Some(self.lazy_calc),
+                "auto" => Some(false),
+                "lazy" => Some(true),
+                _ => None,
+            };
+            match lazy {
+                Some(lazy) => {
+                    self.set_lazy_calc(lazy);
+                    self.status_message = format!("CALCULATION: {}", if lazy { "LAZY" } else { "AUTO" });
+                }
+                None => self.status_message = "USAGE: calc [auto|lazy]".to_string(),
+            }
+        } else if cmd == "locked" || cmd.starts_with("locked ") {
+            // Format: :locked [skip|fail]
+            let strict = match cmd.strip_prefix("locked").unwrap_or("").trim() {
+                "" => Some(self.strict_locks),
+                "skip" => Some(false),
+                "fail" => Some(true),
+                _ => None,
+            };
+            match strict {
+                Some(strict) => {
+                    self.strict_locks = strict;
+                    self.status_message = format!("LOCKED CELLS: {}", if strict { "FAIL" } else { "SKIP" });
+                }
+                None => self.status_message = "USAGE: locked [skip|fail]".to_string(),
+            }
+        } else if cmd == "sortmode" || cmd.starts_with("sortmode ") {
+            // Format: :sortmode [rows|range]
+            let in_range = match cmd.strip_prefix("sortmode").unwrap_or("").trim() {
+                "" => Some(self.sort_in_range),
+                "rows" => Some(false),
+                "range" => Some(true),
+                _ => None,
+            };
+            match in_range {
+                Some(in_range) => {
+                    self.sort_in_range = in_range;
+                    self.status_message = format!("SORT MOVES: {}", if in_range { "RANGE" } else { "ROWS" });
+                }
+                None => self.status_message = "USAGE: sortmode [rows|range]".to_string(),
+            }
+        } else if cmd == "share" || cmd.starts_with("share ") {
+            // Format: :share <file> [name]
+            let args: Vec<&str> = cmd["share".len()..].split_whitespace().collect();
+            let user = env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_else(|_| "user".to_string());
+            let client = args.get(1).map_or_else(|| format!("{}-{}", user, std::process::id()), |name| name.to_string());
+            match args.as_slice() {
+                [file] | [file, _] => match self.share(Path::new(file), &client) {
+                    Ok(true) => self.status_message = format!("JOINED SESSION {} AS {}", file, client),
+                    Ok(false) => self.status_message = format!("STARTED SESSION {} AS {}", file, client),
+                    Err(e) => self.status_message = format!("SESSION ERROR: {}", e),
+                },
+                _ => self.status_message = "USAGE: share <file> [name]".to_string(),
+            }
+        } else if cmd == "unshare" {
+            self.status_message = if self.unshare() { "LEFT THE SHARED SESSION" } else { "THE SHEET IS NOT SHARED" }.to_string();
+        } else if cmd == "who" {
+            self.status_message = match &self.session {
+                None => "THE SHEET IS NOT SHARED".to_string(),
+                Some(session) if session.peers.is_empty() => format!("NO OTHER CLIENTS IN {}", session.path.display()),
+                Some(session) => {
+                    let mut peers: Vec<String> = session.peers.iter().map(|(name, addr)| format!("{} AT {}", name, addr)).collect();
+                    peers.sort();
+                    format!("CLIENTS: {}", peers.join(", "))
+                }
+            };
+        } else if cmd == "serve" || cmd.starts_with("serve ") {
+            // Format: :serve [address|off]
+            let arg = cmd["serve".len()..].trim();
+            #[cfg(feature = "websocket")]
+            {
+                self.status_message = if arg == "off" {
+                    if self.stop_serving() { "STOPPED THE DASHBOARD SERVER" } else { "THE DASHBOARD SERVER IS NOT RUNNING" }.to_string()
+                } else {
+                    let addr = if arg.is_empty() { "127.0.0.1:8765" } else { arg };
+                    match self.serve(addr) {
+                        Ok(local) => format!("SERVING DASHBOARDS AT ws://{}", local),
+                        Err(e) => format!("SERVER ERROR: {}", e),
+                    }
+                };
+            }
+            #[cfg(not(feature = "websocket"))]
+            {
+                let _ = arg;
+                self.status_message = "WEBSOCKET SUPPORT NOT ENABLED (BUILD WITH --features websocket)".to_string();
+            }
+        } else if cmd == "history" || cmd.starts_with("history ") {
+            // Format: :history [cell]
+            let arg = cmd["history".len()..].trim();
+            let addr = if arg.is_empty() { Some(self.cursor.clone()) } else { CellAddress::from_str(arg) };
+            self.status_message = match addr.filter(|addr| addr.row < self.max_rows && addr.col < self.max_cols) {
+                None => "INVALID CELL".to_string(),
+                Some(addr) => {
+                    let shown = |input: &str| if input.is_empty() { "(EMPTY)".to_string() } else { input.to_string() };
+                    let trail: Vec<String> = self.history(&addr).iter().map(|entry| {
+                        let author = entry.author.as_ref().map_or(String::new(), |author| format!(" BY {}", author));
+                        format!("{} {} -> {}{}", entry.time, shown(&entry.old), shown(&entry.new), author)
+                    }).collect();
+                    if trail.is_empty() {
+                        format!("NO CHANGES OF {}", addr)
+                    } else {
+                        format!("HISTORY OF {}: {}", addr, trail.join("; "))
+                    }
+                }
+            };
+        } else if cmd == "audit" || cmd.starts_with("audit ") {
+            // Format: :audit <file|off>
+            let arg = cmd["audit".len()..].trim();
+            self.status_message = match arg {
+                "" => match &self.audit_file {
+                    Some(path) => format!("AUDITING TO {}", path.display()),
+                    None => "USAGE: audit <file|off>".to_string(),
+                },
+                "off" => match self.audit_file.take() {
+                    Some(path) => format!("STOPPED AUDITING TO {}", path.display()),
+                    None => "NO AUDIT FILE OPEN".to_string(),
+                },
+                path => match self.open_audit(Path::new(path)) {
+                    Ok(read) => format!("AUDITING TO {} ({} EARLIER CHANGES)", path, read),
+                    Err(e) => format!("AUDIT ERROR: {}", e),
+                },
+            };
+        } else if cmd == "validate" || cmd.starts_with("validate ") {
+            // Format: :validate [range] list <value>,<value>,... or :validate [range] off
+            let args = cmd["validate".len()..].trim();
+            let (range, rule) = match args.split_once(' ') {
+                Some((range, rule)) if range != "list" => (Some(range), rule.trim()),
+                _ => (None, args),
+            };
+            let choices = rule.strip_prefix("list ")
+                .map(|list| list.split(',').map(str::trim).filter(|choice| !choice.is_empty()).map(String::from).collect::<Vec<_>>());
+            if rule != "off" && choices.as_ref().is_none_or(|choices| choices.is_empty()) {
+                self.status_message = "USAGE: validate [range] list <value>,<value>,... | validate [range] off".to_string();
+            } else if self.set_style(range, |cell| cell.choices = choices.clone()) {
+                self.status_message = match &choices {
+                    Some(choices) => format!("VALUES LIMITED TO {}", choices.join(", ")),
+                    None => "VALIDATION REMOVED".to_string(),
+                };
+            } else if !self.readonly {
+                self.status_message = "INVALID VALIDATION RANGE".to_string();
+            }
+        } else if cmd == "protect" || cmd.starts_with("protect ") {
+            // Format: :protect <range|sheet> [password], or :protect to list the protected areas
+            self.protect(&cmd["protect".len()..], true);
+        } else if cmd == "unprotect" || cmd.starts_with("unprotect ") {
+            // Format: :unprotect <range|sheet|all> [password]
+            self.protect(&cmd["unprotect".len()..], false);
+        } else if cmd == "group" || cmd.starts_with("group ") {
+            // Format: :group <first>:<last>, or :group to list the row groups
+            self.group_rows(cmd["group".len()..].trim());
+        } else if cmd == "ungroup" || cmd.starts_with("ungroup ") {
+            // Format: :ungroup [<first>:<last>|all]
+            self.ungroup_rows(cmd["ungroup".len()..].trim());
+        } else if cmd == "collapse" || cmd.starts_with("collapse ") {
+            // Format: :collapse [<first>:<last>|all]
+            self.fold_rows(cmd["collapse".len()..].trim(), Some(true));
+        } else if cmd == "expand" || cmd.starts_with("expand ") {
+            // Format: :expand [<first>:<last>|all]
+            self.fold_rows(cmd["expand".len()..].trim(), Some(false));
+        } else if cmd.starts_with("undolimit") {
+            // Set how many undo steps are kept (0 = unlimited)
+            let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
+            match parts.get(1).and_then(|n| n.trim().parse::<usize>().ok()) {
+                Some(limit) if parts[0] == "undolimit" => {
+                    self.undo_limit = limit;
+                    if limit > 0 {
+                        while self.undo_stack.len() > limit {
+                            self.undo_stack.pop_front();
+                        }
+                        self.status_message = format!("UNDO LIMIT SET TO {}", limit);
+                    } else {
+                        self.status_message = "UNDO HISTORY UNLIMITED".to_string();
+                    }
+                }
+                _ => {
+                    self.status_message = "USAGE: undolimit <steps> (0 = unlimited)".to_string();
+                }
+            }
         } else if cmd == "undo" {
             self.undo();
         } else if cmd == "redo" {
             self.redo();
         } else if cmd.starts_with("find") {
             // Enter find mode
+            // Format: :find [-v|-f|-a] query
             let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
             if parts.len() > 1 {
-                if self.find(parts[1]) {
+                let (scope, query) = match parts[1].split_once(' ') {
+                    Some(("-v", q)) => (Some(SearchScope::Values), q),
+                    Some(("-f", q)) => (Some(SearchScope::Formulas), q),
+                    Some(("-a", q)) => (Some(SearchScope::Addresses), q),
+                    _ => (None, parts[1]),
+                };
+                if let Some(scope) = scope {
+                    self.find_scope = scope;
+                }
+                if self.find(query.trim()) {
                     self.mode = Mode::Find;
                 }
             } else {
                 self.status_message = "INVALID FIND COMMAND".to_string();
             }
+        } else if cmd == "matches clear" {
+            self.apply_to_matches("");
+        } else if let Some(value) = cmd.strip_prefix("matches set ") {
+            let value = value.trim().to_string();
+            self.apply_to_matches(&value);
         } else if cmd.starts_with("mi") {
             // Multi-insert
             let parts: Vec<&str> = cmd.splitn(3, ' ').collect();
             if parts.len() == 3 {
                 if !self.multi_insert(parts[1], parts[2]) {
-                    self.status_message = "INVALID MULTI-INSERT".to_string();
+                    // Locked cells refused under `:locked fail` and ranges outside the sheet are reported
+                    // by `multi_insert` itself
+                    if !self.status_message.starts_with("ERROR:") {
+                        self.status_message = "INVALID MULTI-INSERT".to_string();
+                    }
+                } else if let Some((start, end)) = self.parse_range(parts[1].trim_start_matches('[').trim_end_matches(']')) {
+                    self.last_change = Some(Change::MultiInsert {
+                        rows: start.row.abs_diff(end.row) + 1,
+                        cols: start.col.abs_diff(end.col) + 1,
+                        value: parts[2].to_string(),
+                    });
                 }
             } else {
                 self.status_message = "INVALID MULTI-INSERT COMMAND".to_string();
@@ -1953,8 +7748,8 @@ impl Spreadsheet {
                 if !self.lock_cell(Some(parts[1])) {
                     self.status_message = "INVALID LOCK COMMAND".to_string();
                 }
-            } else {
-                self.lock_cell(None);
+            } else if self.lock_cell(None) {
+                self.last_change = Some(Change::Lock);
             }
         } else if cmd.starts_with("unlock") {
             // Unlock cell
@@ -1963,8 +7758,201 @@ impl Spreadsheet {
                 if !self.unlock_cell(Some(parts[1])) {
                     self.status_message = "INVALID UNLOCK COMMAND".to_string();
                 }
+            } else if self.unlock_cell(None) {
+                self.last_change = Some(Change::Unlock);
+            }
+        } else if cmd.starts_with("replace") {
+            // Format: :replace <old> <new> [range] [-f] [-c]
+            let parts: Vec<&str> = cmd.split_whitespace().collect();
+            if parts[0] == "replace" && parts.len() >= 3 {
+                let mut range = None;
+                let mut include_formulas = false;
+                let mut confirm = false;
+                let mut valid = true;
+                for arg in &parts[3..] {
+                    match *arg {
+                        "-f" => include_formulas = true,
+                        "-c" => confirm = true,
+                        r if range.is_none() => range = Some(r),
+                        _ => valid = false,
+                    }
+                }
+                if valid {
+                    self.replace(parts[1], parts[2], range, include_formulas, confirm);
+                } else {
+                    self.status_message = "INVALID REPLACE COMMAND".to_string();
+                }
+            } else {
+                self.status_message = "USAGE: replace <old> <new> [range] [-f] [-c]".to_string();
+            }
+        } else if cmd == "demo" || cmd == "demo on" || cmd == "demo off" {
+            self.demo_mode = match cmd.as_str() {
+                "demo on" => true,
+                "demo off" => false,
+                _ => !self.demo_mode,
+            };
+            self.demo_keys.clear();
+            self.demo_last_command = None;
+            if self.demo_mode {
+                self.demo_recording.clear();
+                self.status_message = "DEMO MODE ON".to_string();
+            } else {
+                self.demo_queue.clear();
+                self.status_message = "DEMO MODE OFF".to_string();
+            }
+        } else if cmd.starts_with("demo save") {
+            let parts: Vec<&str> = cmd.splitn(3, ' ').collect();
+            if parts.len() == 3 {
+                // Leave out ':' + the command itself + <Enter>
+                let skip = self.command_buffer.chars().count() + 2;
+                if let Err(e) = self.save_demo_script(Path::new(parts[2].trim()), skip) {
+                    self.status_message = format!("SAVE ERROR: {}", e);
+                } else {
+                    self.status_message = format!("DEMO SCRIPT SAVED TO {}", parts[2].trim());
+                }
+            } else {
+                self.status_message = "USAGE: demo save <filename>".to_string();
+            }
+        } else if cmd.starts_with("demo play") {
+            // Format: :demo play <script> [delay_ms]
+            let parts: Vec<&str> = cmd.split_whitespace().collect();
+            if parts.len() == 3 || parts.len() == 4 {
+                let delay = parts.get(3).and_then(|ms| ms.parse::<u64>().ok()).map(Duration::from_millis);
+                match self.play_demo_script(Path::new(parts[2]), delay) {
+                    Ok(n) => self.status_message = format!("REPLAYING {} KEYS", n),
+                    Err(e) => self.status_message = format!("LOAD ERROR: {}", e),
+                }
             } else {
-                self.unlock_cell(None);
+                self.status_message = "USAGE: demo play <filename> [delay_ms]".to_string();
+            }
+        } else if cmd == "refresh links" {
+            self.refresh_links();
+        } else if cmd == "alert clear" {
+            self.alerts.clear();
+            self.alert_notice = None;
+            self.check_alerts();
+            self.status_message = "ALERTS CLEARED".to_string();
+        } else if cmd.starts_with("yank ") {
+            // Format: :yank <range> [reg]
+            let parts: Vec<&str> = cmd.split_whitespace().collect();
+            match parts.as_slice() {
+                [_, range] => {
+                    self.yank(range, UNNAMED_REGISTER);
+                }
+                [_, range, reg] if reg.len() == 1 && reg.chars().all(|c| c.is_ascii_lowercase()) => {
+                    self.yank(range, reg.chars().next().unwrap());
+                }
+                _ => self.status_message = "USAGE: yank <range> [register]".to_string(),
+            }
+        } else if cmd == "pivot" || cmd.starts_with("pivot ") {
+            // Format: :pivot <range> <cell> rows=<col> [cols=<col>] values=<function>(<col>), :pivot refresh, or
+            // :pivot to list the pivot tables
+            let args = cmd["pivot".len()..].trim();
+            if args.is_empty() {
+                self.status_message = if self.pivots.is_empty() {
+                    "NO PIVOT TABLES".to_string()
+                } else {
+                    format!("PIVOTS: {}", self.pivots.iter().map(Pivot::describe).collect::<Vec<_>>().join("; "))
+                };
+            } else if !self.is_read_only() && args == "refresh" {
+                let mut pivots = std::mem::take(&mut self.pivots);
+                let failed: Vec<String> = pivots.iter_mut().filter_map(|pivot| self.write_pivot(pivot).err()).collect();
+                self.pivots = pivots;
+                self.status_message = match failed.first() {
+                    Some(error) => error.clone(),
+                    None => format!("{} PIVOT TABLES REFRESHED", self.pivots.len()),
+                };
+            } else if !self.readonly {
+                match Pivot::parse(args) {
+                    Ok(mut pivot) => {
+                        // A new table at the same cell replaces the old one, starting from the cells it held
+                        let old = self.pivots.iter().position(|old| old.dest == pivot.dest);
+                        if let Some(old) = old {
+                            pivot.size = self.pivots[old].size;
+                        }
+                        match self.write_pivot(&mut pivot) {
+                            Ok((rows, cols)) => {
+                                self.status_message = format!("PIVOT TABLE WRITTEN TO {} ({}x{})", pivot.dest, rows, cols);
+                                match old {
+                                    Some(old) => self.pivots[old] = pivot,
+                                    None => self.pivots.push(pivot),
+                                }
+                            }
+                            Err(error) => self.status_message = error,
+                        }
+                    }
+                    Err(error) => self.status_message = error,
+                }
+            }
+        } else if cmd == "put" || cmd.starts_with("put ") {
+            // Format: :put [reg]  (pastes at the cursor)
+            match cmd[3..].trim() {
+                "" => {
+                    self.put(UNNAMED_REGISTER);
+                }
+                reg if reg.len() == 1 && reg.chars().all(|c| c.is_ascii_lowercase()) => {
+                    self.put(reg.chars().next().unwrap());
+                }
+                _ => self.status_message = "USAGE: put [register]".to_string(),
+            }
+        } else if cmd == "registers" {
+            let mut regs: Vec<(&char, &Register)> = self.registers.iter().collect();
+            regs.sort_by_key(|(reg, _)| **reg);
+            if regs.is_empty() {
+                self.status_message = "NO REGISTERS".to_string();
+            } else {
+                let listed: Vec<String> = regs.iter()
+                    .map(|(reg, r)| format!("\"{} {} ({}x{}) {}", reg, r.source, r.rows, r.cols,
+                        r.cells.first().map_or("", |cell| cell.display_value.as_str())))
+                    .collect();
+                self.status_message = format!("REGISTERS: {}", listed.join(", "));
+            }
+        } else if cmd == "marks" {
+            let mut marks: Vec<(&char, &CellAddress)> = self.marks.iter().collect();
+            marks.sort_by_key(|(reg, _)| **reg);
+            if marks.is_empty() {
+                self.status_message = "NO MARKS".to_string();
+            } else {
+                let listed: Vec<String> = marks.iter()
+                    .map(|(reg, addr)| format!("{}={}", reg, addr))
+                    .collect();
+                self.status_message = format!("MARKS: {}", listed.join(", "));
+            }
+        } else if cmd == "alerts" {
+            if self.alerts.is_empty() {
+                self.status_message = "NO ALERTS".to_string();
+            } else {
+                let rules: Vec<String> = self.alerts.iter()
+                    .map(|r| match r.action {
+                        RuleAction::Notify { .. } => format!("{} {} {}", r.cell, r.comparison.symbol(), r.threshold),
+                        RuleAction::Haunt { weight } => format!("{} {} {} (HAUNT x{})", r.cell, r.comparison.symbol(), r.threshold, weight),
+                    })
+                    .collect();
+                self.status_message = format!("ALERTS: {}", rules.join(", "));
+            }
+        } else if cmd.starts_with("alert ") {
+            // Format: :alert <cell> <op> <value> ["message"] [bell]
+            let re = regex::Regex::new(r#"^alert\s+([A-Za-z]+\d+)\s*(>=|<=|==|!=|>|<|=)\s*(-?\d+(?:\.\d+)?)\s*(?:"([^"]*)")?\s*(bell)?$"#).unwrap();
+            if let Some(caps) = re.captures(&cmd) {
+                let threshold = caps[3].parse::<f64>().unwrap_or(0.0);
+                let message = caps.get(4).map_or("", |m| m.as_str());
+                if !self.add_alert(&caps[1], &caps[2], threshold, message, caps.get(5).is_some()) {
+                    self.status_message = "INVALID ALERT CELL".to_string();
+                }
+            } else {
+                self.status_message = "USAGE: alert <cell> <op> <value> [\"message\"] [bell]".to_string();
+            }
+        } else if cmd.starts_with("unwatch") {
+            // Remove a cell from the watch panel
+            let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
+            if !self.unwatch_cell(parts.get(1).map(|a| a.trim())) {
+                self.status_message = "CELL NOT WATCHED".to_string();
+            }
+        } else if cmd.starts_with("watch") {
+            // Pin a cell to the watch panel
+            let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
+            if !self.watch_cell(parts.get(1).map(|a| a.trim())) {
+                self.status_message = "INVALID WATCH COMMAND".to_string();
             }
         } else if cmd.starts_with("align") || cmd.starts_with("allign") {
             // Set alignment
@@ -1973,15 +7961,72 @@ impl Spreadsheet {
                 // Just alignment for current cell
                 if !self.set_alignment(None, parts[1]) {
                     self.status_message = "INVALID ALIGNMENT".to_string();
+                } else {
+                    self.last_change = Some(Change::Align(parts[1].to_string()));
                 }
             } else if parts.len() == 3 {
                 // Cell and alignment
                 if !self.set_alignment(Some(parts[1]), parts[2]) {
                     self.status_message = "INVALID ALIGNMENT COMMAND".to_string();
+                } else {
+                    self.last_change = Some(Change::Align(parts[2].to_string()));
                 }
             } else {
                 self.status_message = "INVALID ALIGNMENT COMMAND".to_string();
             }
+        } else if cmd.starts_with("color ") {
+            // Format: :color [range] <fg|none> [on <bg|none>]  or  :color [range] clear
+            let words: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+            let is_color = |word: &str| parse_color(word).is_some() || word == "none";
+            let (range, rest) = match words.split_first() {
+                Some((first, rest)) if !is_color(first) && *first != "clear" && *first != "on" => (Some(*first), rest),
+                _ => (None, &words[..]),
+            };
+            let color = |word: &str| (word != "none").then(|| word.to_ascii_lowercase());
+            // The new foreground and background: `None` leaves a color as it is, `Some(None)` clears it
+            type ColorChange = (Option<Option<String>>, Option<Option<String>>);
+            let change: Option<ColorChange> = match rest {
+                ["clear"] => Some((Some(None), Some(None))),
+                [fg] if is_color(fg) => Some((Some(color(fg)), None)),
+                ["on", bg] if is_color(bg) => Some((None, Some(color(bg)))),
+                [fg, "on", bg] if is_color(fg) && is_color(bg) => Some((Some(color(fg)), Some(color(bg)))),
+                _ => None,
+            };
+            match change {
+                Some((fg, bg)) => {
+                    let applied = self.set_style(range, |cell| {
+                        if let Some(fg) = &fg {
+                            cell.fg = fg.clone();
+                        }
+                        if let Some(bg) = &bg {
+                            cell.bg = bg.clone();
+                        }
+                    });
+                    if !applied && !self.readonly {
+                        self.status_message = "INVALID COLOR RANGE".to_string();
+                    }
+                }
+                None => self.status_message = "USAGE: color [range] <color|none> [on <color|none>] | color [range] clear".to_string(),
+            }
+        } else if cmd.starts_with("fmt ") {
+            // Format: :fmt <range> date [pattern|iso|us|eu|uk|locale]  or  :fmt <range> clear
+            let parts: Vec<&str> = cmd.splitn(4, ' ').collect();
+            match (parts.get(1), parts.get(2)) {
+                (Some(range), Some(&"clear")) => {
+                    if !self.set_format(range, None) {
+                        self.status_message = "INVALID FORMAT RANGE".to_string();
+                    }
+                }
+                (Some(range), Some(&"date")) => match resolve_date_pattern(parts.get(3).copied()) {
+                    Some(pattern) => {
+                        if !self.set_format(range, Some(format!("date:{}", pattern))) {
+                            self.status_message = "INVALID FORMAT RANGE".to_string();
+                        }
+                    }
+                    None => self.status_message = "INVALID DATE PATTERN".to_string(),
+                },
+                _ => self.status_message = "USAGE: fmt <range> date [pattern] | fmt <range> clear".to_string(),
+            }
         } else if cmd.starts_with("dim") {
             // Set dimension
             // Format: :dim [cell] ((h,w))
@@ -2005,16 +8050,19 @@ impl Spreadsheet {
                 } else {
                     None
                 };
-                println!("Debug: Height: {:?}, Width: {:?}", height, width);
                 if parts.len() > 1 {
                     // Cell specified
                     if !self.set_dimension(Some(parts[1]), height, width) {
                         self.status_message = "INVALID DIMENSION COMMAND".to_string();
+                    } else {
+                        self.last_change = Some(Change::Dimension(height, width));
                     }
                 } else {
                     // Current cell
                     if !self.set_dimension(None, height, width) {
                         self.status_message = "INVALID DIMENSION COMMAND".to_string();
+                    } else {
+                        self.last_change = Some(Change::Dimension(height, width));
                     }
                 }
             } else {
@@ -2026,47 +8074,315 @@ impl Spreadsheet {
             let parts: Vec<&str> = cmd.splitn(3, ' ').collect();
             if parts.len() == 3 {
                 let ascending = parts[2] == "1";
-                if !self.sort_range(parts[1], ascending) {
+                // Locked cells refused under `:locked fail` and ranges outside the sheet are reported by
+                // `sort_range` itself
+                if !self.sort_range(parts[1], ascending) && !self.status_message.starts_with("ERROR:") {
                     self.status_message = "INVALID SORT COMMAND".to_string();
                 }
             } else {
                 self.status_message = "INVALID SORT COMMAND".to_string();
             }
+        } else if cmd == "unfreeze" {
+            self.freeze_panes(0, 0);
+        } else if cmd == "chart" || cmd.starts_with("chart ") {
+            // Format: :chart <bar|line> <range>
+            let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+            let kind = match args.first() {
+                Some(&"bar") => Some(ChartKind::Bar),
+                Some(&"line") => Some(ChartKind::Line),
+                _ => None,
+            };
+            match (kind, args.get(1).and_then(|range| self.parse_range(range))) {
+                (Some(kind), Some((start, end))) if args.len() == 2 => {
+                    if self.range_numbers(&start, &end).is_empty() {
+                        self.status_message = "NO NUMBERS TO CHART".to_string();
+                    } else {
+                        self.chart = Some((kind, start, end));
+                    }
+                }
+                _ => self.status_message = "USAGE: chart <bar|line> <range>".to_string(),
+            }
+        } else if cmd == "eval" || cmd.starts_with("eval ") {
+            // Format: :eval <expression>
+            let expression = cmd["eval".len()..].trim();
+            self.status_message = if expression.is_empty() {
+                "USAGE: eval <expression>".to_string()
+            } else {
+                match evaluate(self, expression) {
+                    Ok(value) => format!("{} = {}", expression, value),
+                    Err(reason) => format!("ERROR: {}", reason),
+                }
+            };
+        } else if ["grid", "zebra", "heatmap"].iter().any(|name| cmd == *name || cmd.starts_with(&format!("{} ", name))) {
+            // Format: :grid [on|off] / :zebra [on|off] / :heatmap [on|off]; toggles without an argument
+            let (name, arg) = cmd.split_once(' ').map_or((cmd.as_str(), ""), |(name, arg)| (name, arg.trim()));
+            let setting = match name {
+                "grid" => &mut self.grid_lines,
+                "zebra" => &mut self.zebra,
+                _ => &mut self.heatmap,
+            };
+            let current = *setting;
+            let on = match arg {
+                "" => Some(!current),
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => None,
+            };
+            match on {
+                Some(on) => {
+                    *setting = on;
+                    let label = match name {
+                        "grid" => "GRID LINES",
+                        "zebra" => "ZEBRA STRIPES",
+                        _ => "HEATMAP",
+                    };
+                    self.status_message = format!("{} {}", label, if on { "ON" } else { "OFF" });
+                }
+                None => self.status_message = format!("USAGE: {} [on|off]", name),
+            }
+        } else if cmd.starts_with("freeze") {
+            // Format: :freeze <rows> [cols]
+            let counts: Vec<Option<usize>> = cmd.split_whitespace().skip(1).map(|n| n.parse().ok()).collect();
+            match counts.as_slice() {
+                [Some(rows)] => { self.freeze_panes(*rows, 0); }
+                [Some(rows), Some(cols)] => { self.freeze_panes(*rows, *cols); }
+                _ => self.status_message = "USAGE: freeze <rows> [cols]".to_string(),
+            }
+        } else if cmd == "sheet" || cmd.starts_with("sheet ") {
+            self.sheet_command(cmd["sheet".len()..].trim());
         } else if cmd.starts_with("saveas_") {
             let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
             if parts.len() == 2 {
                 let filetype = &cmd[7..cmd.find(' ').unwrap_or(cmd.len())];
                 let filepath = parts[1].trim();
+                // An optional range before the file name limits the export: :saveas_csv A1:F200 out.csv
+                // A range past the sheet is refused rather than taken for part of the file name
+                let (first, _) = filepath.split_once(' ').unwrap_or_default();
+                let outside = Some(self.range_error(first)).filter(|e| self.parse_range(first).is_none() && e.starts_with("ERROR:"));
+                let (range, filepath) = match filepath.split_once(' ').and_then(|(first, rest)| Some((self.parse_range(first)?, rest))) {
+                    Some(((start, end), rest)) => {
+                        let top_left = CellAddress::new(start.col.min(end.col), start.row.min(end.row));
+                        let bottom_right = CellAddress::new(start.col.max(end.col), start.row.max(end.row));
+                        (Some((top_left, bottom_right)), rest.trim())
+                    }
+                    None => (None, filepath),
+                };
+                // Exports go through every cell, including the rows of a database-backed sheet not read yet
+                self.page_in_all_rows();
         
                 match filetype {
-                    "json" => {
-                        if let Err(e) = self.save_json(Path::new(filepath)) {
+                    _ if outside.is_some() => self.status_message = outside.unwrap_or_default(),
+                    "json" | "bin" => {
+                        // A trailing -u also saves the undo history (not for a range)
+                        let (filepath, with_history) = match filepath.strip_suffix(" -u") {
+                            Some(path) => (path.trim(), true),
+                            None => (filepath, false),
+                        };
+                        let whole_sheet = range.is_none();
+                        let saved = self.save_file_ref(with_history, range);
+                        if let Err(e) = write_save(Path::new(filepath), &saved, filetype == "bin") {
+                            self.status_message = format!("SAVE ERROR: {}", e);
+                        } else {
+                            // Exporting a range leaves the sheet's own file alone
+                            if whole_sheet {
+                                self.set_current_file(Path::new(filepath));
+                            }
+                            self.status_message = format!("FILE SAVED TO {}", filepath);
+                        }
+                    }
+                    "text" => {
+                        let whole_sheet = range.is_none();
+                        if let Err(e) = write_text_save(Path::new(filepath), &self.save_file_ref(false, range)) {
                             self.status_message = format!("SAVE ERROR: {}", e);
                         } else {
+                            if whole_sheet {
+                                self.set_current_file(Path::new(filepath));
+                            }
                             self.status_message = format!("FILE SAVED TO {}", filepath);
                         }
                     }
+                    "log" => {
+                        match self.save_log(Path::new(filepath)) {
+                            Ok((revision, changed)) => {
+                                self.set_current_file(Path::new(filepath));
+                                self.status_message = match changed {
+                                    0 => format!("NO CHANGES SINCE REVISION {} OF {}", revision, filepath),
+                                    _ => format!("REVISION {} SAVED TO {} ({} CELLS)", revision, filepath, changed),
+                                };
+                            }
+                            Err(e) => self.status_message = format!("SAVE ERROR: {}", e),
+                        }
+                    }
+                    "csv" => {
+                        if let Err(e) = self.export_csv(Path::new(filepath), range) {
+                            self.status_message = format!("CSV EXPORT ERROR: {}", e);
+                        } else {
+                            self.status_message = format!("CSV SAVED TO {}", filepath);
+                        }
+                    }
+                    "depcsv" => {
+                        if let Err(e) = self.save_dependency_csv(Path::new(filepath), range) {
+                            self.status_message = format!("SAVE ERROR: {}", e);
+                        } else {
+                            self.status_message = format!("DEPENDENCIES SAVED TO {}", filepath);
+                        }
+                    }
+                    "tex" => {
+                        // Format: :saveas_tex [range] <file>, or :saveas_tex <file> [range]
+                        let (file, range_arg) = match filepath.rsplit_once(' ') {
+                            Some((file, range)) if range.contains(':') => (file.trim(), Some(range)),
+                            _ => (filepath, None),
+                        };
+                        let trailing = range_arg.map(|r| self.parse_range(r.trim_start_matches('[').trim_end_matches(']')));
+                        if let Some(None) = trailing {
+                            self.status_message = self.range_error(range_arg.unwrap_or_default().trim_start_matches('[').trim_end_matches(']'));
+                        } else if let Err(e) = self.export_latex(Path::new(file), trailing.flatten().or(range)) {
+                            self.status_message = format!("LATEX EXPORT ERROR: {}", e);
+                        } else {
+                            self.status_message = format!("LATEX TABLE SAVED TO {}", file);
+                        }
+                    }
+                    "ods" => {
+                        match self.export_ods(Path::new(filepath), range) {
+                            Ok(0) => self.status_message = format!("ODS SAVED TO {}", filepath),
+                            Ok(n) => self.status_message = format!("ODS SAVED TO {} ({} FORMULAS SAVED AS VALUES)", filepath, n),
+                            Err(e) => self.status_message = format!("ODS EXPORT ERROR: {}", e),
+                        }
+                    }
+                    "db" => {
+                        #[cfg(feature = "sqlite")]
+                        match self.save_db(Path::new(filepath)) {
+                            Ok(stored) => {
+                                self.set_current_file(Path::new(filepath));
+                                self.status_message = format!("DATABASE SAVED TO {} ({} CELLS)", filepath, stored);
+                            }
+                            Err(e) => self.status_message = format!("DATABASE ERROR: {}", e),
+                        }
+                        #[cfg(not(feature = "sqlite"))]
+                        {
+                            self.status_message = "SQLITE SUPPORT NOT ENABLED (BUILD WITH --features sqlite)".to_string();
+                        }
+                    }
+                    "html" => {
+                        if let Err(e) = self.export_html(Path::new(filepath), range) {
+                            self.status_message = format!("HTML EXPORT ERROR: {}", e);
+                        } else {
+                            self.status_message = format!("HTML SAVED TO {}", filepath);
+                        }
+                    }
                     "pdf" => {
-                        if let Err(e) = self.export_to_pdf(filepath) {
+                        // Format: :saveas_pdf [range] <file> [landscape] [wrap] [size=N] [title="..."]
+                        let (filepath, options) = PdfOptions::parse(filepath);
+                        if let Err(e) = self.export_to_pdf(filepath, range, &options) {
                             self.status_message = format!("PDF EXPORT ERROR: {}", e);
                         } else {
                             self.status_message = format!("PDF SAVED TO {}", filepath);
                         }
                     }
                     _ => {
-                        self.status_message = "UNSUPPORTED FORMAT. Use saveas_json or saveas_pdf.".to_string();
+                        self.status_message = "UNSUPPORTED FORMAT. Use saveas_json, saveas_bin, saveas_text, saveas_log, saveas_db, saveas_csv, saveas_pdf, saveas_html, saveas_tex, saveas_ods or saveas_depcsv.".to_string();
                     }
                 }
             } else {
-                self.status_message = "USAGE: saveas_<format> <filename>".to_string();
+                self.status_message = "USAGE: saveas_<format> [range] <filename>".to_string();
+            }
+        } else if cmd.starts_with("open_db") {
+            // Format: :open_db <file>
+            match cmd.split_once(' ').map(|(_, path)| path.trim()).filter(|path| !path.is_empty()) {
+                #[cfg(feature = "sqlite")]
+                Some(path) => match self.open_db(Path::new(path)) {
+                    Ok(()) => {
+                        self.set_current_file(Path::new(path));
+                        self.status_message = format!("OPENED DATABASE {}", path);
+                    }
+                    Err(e) => self.status_message = format!("DATABASE ERROR: {}", e),
+                },
+                #[cfg(not(feature = "sqlite"))]
+                Some(_) => self.status_message = "SQLITE SUPPORT NOT ENABLED (BUILD WITH --features sqlite)".to_string(),
+                None => self.status_message = "USAGE: open_db <file>".to_string(),
+            }
+        } else if cmd.starts_with("load_log") {
+            // Format: :load_log <file> [revision]
+            let args = cmd.split_once(' ').map_or("", |(_, args)| args.trim());
+            let (path, revision) = match args.rsplit_once(' ').and_then(|(path, rev)| Some((path, rev.parse::<usize>().ok()?))) {
+                Some((path, revision)) => (path.trim(), Some(revision)),
+                None => (args, None),
+            };
+            if path.is_empty() {
+                self.status_message = "USAGE: load_log <file> [revision]".to_string();
+            } else {
+                match self.load_log(Path::new(path), revision) {
+                    Ok((loaded, times)) => {
+                        self.set_current_file(Path::new(path));
+                        self.status_message = format!("LOADED REVISION {} OF {} (SAVED {})", loaded, times.len(), times[loaded - 1]);
+                    }
+                    Err(e) => self.status_message = format!("LOAD ERROR: {}", e),
+                }
+            }
+        } else if cmd.starts_with("compact_log") {
+            // Format: :compact_log <file>
+            match cmd.split_once(' ').map(|(_, path)| path.trim()).filter(|path| !path.is_empty()) {
+                Some(path) => match Self::compact_log(Path::new(path)) {
+                    Ok(revisions) => self.status_message = format!("COMPACTED {} REVISIONS OF {}", revisions, path),
+                    Err(e) => self.status_message = format!("COMPACT ERROR: {}", e),
+                },
+                None => self.status_message = "USAGE: compact_log <file>".to_string(),
+            }
+        } else if cmd.starts_with("load_bin") {
+            // Format: :load_bin <file>
+            match cmd.split_once(' ').map(|(_, path)| path.trim()).filter(|path| !path.is_empty()) {
+                Some(path) => match self.load_bin(Path::new(path)) {
+                    Ok(()) => {
+                        self.set_current_file(Path::new(path));
+                        self.status_message = "FILE LOADED".to_string();
+                    }
+                    Err(e) => self.status_message = format!("LOAD ERROR: {}", e),
+                },
+                None => self.status_message = "INVALID LOAD COMMAND".to_string(),
             }
         } else if cmd.starts_with("load") {
             // Load
             let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
-            if parts.len() == 2 {
+            // Workbooks take an optional sheet name after the path: :load book.xlsx Sheet2
+            let workbook = parts.get(1).and_then(|arg| {
+                let lower = arg.to_lowercase();
+                [".xlsx", ".xlsm", ".xlsb", ".xls", ".ods"].iter().find_map(|ext| {
+                    lower.find(ext).filter(|i| {
+                        let end = i + ext.len();
+                        end == arg.len() || arg[end..].starts_with(' ')
+                    }).map(|i| {
+                        let (path, sheet) = arg.split_at(i + ext.len());
+                        (path.to_string(), Some(sheet.trim()).filter(|s| !s.is_empty()).map(str::to_string))
+                    })
+                })
+            });
+            if let Some((path, sheet)) = workbook {
+                match self.import_workbook(Path::new(&path), sheet.as_deref()) {
+                    Ok((imported, problems)) if problems.is_empty() => {
+                        self.status_message = format!("IMPORTED {} CELLS", imported);
+                    }
+                    Ok((imported, problems)) => {
+                        self.status_message = format!("IMPORTED {} CELLS, {} PROBLEMS: {}",
+                            imported, problems.len(), problems.join("; "));
+                    }
+                    Err(e) => self.status_message = format!("LOAD ERROR: {}", e),
+                }
+            } else if parts.len() == 2 && parts[1].to_lowercase().ends_with(".csv") {
+                match self.import_csv(Path::new(parts[1])) {
+                    Ok((imported, untranslated)) if untranslated.is_empty() => {
+                        self.status_message = format!("IMPORTED {} CELLS", imported);
+                    }
+                    Ok((imported, untranslated)) => {
+                        self.status_message = format!("IMPORTED {} CELLS, {} FORMULAS NOT TRANSLATED: {}",
+                            imported, untranslated.len(), untranslated.join("; "));
+                    }
+                    Err(e) => self.status_message = format!("LOAD ERROR: {}", e),
+                }
+            } else if parts.len() == 2 {
                 if let Err(e) = self.load_json(Path::new(parts[1])) {
                     self.status_message = format!("LOAD ERROR: {}", e);
                 } else {
+                    self.set_current_file(Path::new(parts[1]));
                     self.status_message = "FILE LOADED".to_string();
                 }
             } else {
@@ -2081,21 +8397,36 @@ impl Spreadsheet {
         } else if cmd == "jj" {
             // Go to bottom cell in column
             self.cursor.row = self.max_rows - 1;
+            self.reveal_cursor();
         } else if cmd == "kk" {
             // Go to top cell in column
             self.cursor.row = 0;
+            self.reveal_cursor();
+        } else if cmd.starts_with("haunt when") {
+            // Format: :haunt when <cell|ERRORS> <op> <value> [weight N]
+            let re = regex::Regex::new(r"^haunt when\s+([A-Za-z]+\d*)\s*(>=|<=|==|!=|>|<|=)\s*(-?\d+(?:\.\d+)?)\s*(?:weight\s+(\d+))?$").unwrap();
+            if let Some(caps) = re.captures(&cmd) {
+                let threshold = caps[3].parse::<f64>().unwrap_or(0.0);
+                let weight = caps.get(4).and_then(|w| w.as_str().parse::<u8>().ok()).unwrap_or(1);
+                if !self.add_haunt_rule(&caps[1], &caps[2], threshold, weight) {
+                    self.status_message = "INVALID HAUNT RULE".to_string();
+                }
+            } else {
+                self.status_message = "USAGE: haunt when <cell|ERRORS> <op> <value> [weight N]".to_string();
+            }
         }  else if cmd == "haunt" {
             self.haunted = true;
             self.haunted_start = Some(Instant::now());
             self.jump_scare_triggered = false;
         
             // WSL-friendly sound playback
-            let windows_path = r#"C:\Users\hp\OneDrive - IIT Delhi\Desktop\Academics\prisha_rust_lab\creaking_door.wav"#; 
-            play_sound(windows_path);
+            play_sound(&self.door_sound);
         
             self.status_message = "👻 You are being haunted...".to_string();
         } else if cmd == "dehaunt" {
             self.haunted = false;
+            self.haunt_by_rules = false;
+            self.corruption_level = 0;
             self.haunted_start = None;
             self.jump_scare_triggered = false;
         
@@ -2109,9 +8440,462 @@ impl Spreadsheet {
         } else {
             self.status_message = "INVALID COMMAND".to_string();
         }
+        if is_error_message(&self.status_message) {
+            self.last_error = Some(self.status_message.clone());
+        }
         
         true // Continue running
     }
+/// Loads the command history saved by previous sessions from the config directory.
+/// A missing history file simply leaves the history empty.
+    fn load_command_history(&mut self) {
+        let path = match config_dir() {
+            Some(dir) => dir.join("history"),
+            None => return,
+        };
+        if let Ok(contents) = std::fs::read_to_string(path) {
+            self.command_history = contents.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| line.to_string())
+                .collect();
+            let excess = self.command_history.len().saturating_sub(MAX_COMMAND_HISTORY);
+            self.command_history.drain(..excess);
+        }
+    }
+/// Adds an executed command to the history and writes the history back to the config directory.
+/// Consecutive duplicates are not stored twice. Failing to write the file is not an error; the
+/// history then only lasts for the current session.
+///
+/// # Arguments
+///
+/// * `cmd` - The command as typed, without the leading `:`.
+    fn record_command(&mut self, cmd: &str) {
+        self.history_index = None;
+        if cmd.is_empty() || self.command_history.last().map(|c| c.as_str()) == Some(cmd) {
+            return;
+        }
+        self.command_history.push(cmd.to_string());
+        if self.command_history.len() > MAX_COMMAND_HISTORY {
+            self.command_history.remove(0);
+        }
+        if let Some(dir) = config_dir().filter(|dir| std::fs::create_dir_all(dir).is_ok()) {
+            let _ = std::fs::write(dir.join("history"), self.command_history.join("\n") + "\n");
+        }
+    }
+/// Moves through the command history and places the selected entry in the command buffer.
+///
+/// # Arguments
+///
+/// * `older` - `true` to step back to an older command (Up), `false` to step forward (Down). Stepping
+///   forward past the newest entry clears the buffer.
+    fn recall_history(&mut self, older: bool) {
+        if self.command_history.is_empty() {
+            return;
+        }
+        let last = self.command_history.len() - 1;
+        self.history_index = match (self.history_index, older) {
+            (None, true) => Some(last),
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i < last => Some(i + 1),
+            (_, false) => None,
+        };
+        self.command_buffer = match self.history_index {
+            Some(i) => self.command_history[i].clone(),
+            None => String::new(),
+        };
+    }
+/// Returns the completions for the word at the end of the command buffer.
+///
+/// While the command name is still being typed, command names are completed. For the argument of a
+/// file command (`load`, `saveas_*`, ...) file paths are completed; for any other argument, cell
+/// addresses (the cursor, watched cells and cells with content) are completed.
+///
+/// # Arguments
+///
+/// * `buffer` - The current command buffer.
+///
+/// # Returns
+///
+/// A tuple of the text before the completed word and the sorted list of candidates.
+    fn completion_candidates(&self, buffer: &str) -> (String, Vec<String>) {
+        let name_matches: Vec<String> = COMMANDS.iter()
+            .map(|(name, _, _)| name)
+            .filter(|name| name.starts_with(buffer) && **name != buffer)
+            .map(|name| name.to_string())
+            .collect();
+        if !buffer.contains(' ') || !name_matches.is_empty() {
+            return (String::new(), name_matches);
+        }
+
+        let split = buffer.rfind(' ').map_or(0, |i| i + 1);
+        let (stem, word) = buffer.split_at(split);
+        let command = stem.trim_end();
+        let mut candidates = Vec::new();
+        if FILE_COMMANDS.contains(&command) {
+            let (dir, prefix) = match word.rfind('/') {
+                Some(i) => (&word[..=i], &word[i + 1..]),
+                None => ("", word),
+            };
+            let read_dir = if dir.is_empty() { Path::new(".") } else { Path::new(dir) };
+            if let Ok(entries) = std::fs::read_dir(read_dir) {
+                for entry in entries.flatten() {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if name.starts_with(prefix) && !(prefix.is_empty() && name.starts_with('.')) {
+                        let suffix = if entry.path().is_dir() { "/" } else { "" };
+                        candidates.push(format!("{}{}{}", dir, name, suffix));
+                    }
+                }
+            }
+            candidates.sort();
+        } else {
+            let upper = word.to_ascii_uppercase();
+            let mut cells: Vec<CellAddress> = vec![self.cursor.clone()];
+            cells.extend(self.watch_list.iter().filter_map(|a| CellAddress::from_str(a)));
+            cells.extend(self.data.iter()
+                .filter(|(_, cell)| cell.formula.is_some() || !(cell.raw_value.is_empty() || cell.raw_value == "0"))
+                .filter_map(|(addr, _)| CellAddress::from_str(addr)));
+            cells.sort_by_key(|addr| (addr.col, addr.row));
+            for addr in cells {
+                let addr_str = addr.to_string();
+                if addr_str.starts_with(&upper) && !candidates.contains(&addr_str) {
+                    candidates.push(addr_str);
+                }
+            }
+        }
+        (stem.to_string(), candidates)
+    }
+/// Completes the word at the end of the command buffer. The first Tab inserts the first candidate;
+/// further presses cycle through the others. The candidates are listed in the status message.
+    fn complete_command(&mut self) {
+        if let Some(completion) = &mut self.completion {
+            completion.index = (completion.index + 1) % completion.candidates.len();
+            self.command_buffer = format!("{}{}", completion.stem, completion.candidates[completion.index]);
+            return;
+        }
+        let (stem, candidates) = self.completion_candidates(&self.command_buffer);
+        if candidates.is_empty() {
+            self.status_message = "NO COMPLETIONS".to_string();
+            return;
+        }
+        self.command_buffer = format!("{}{}", stem, candidates[0]);
+        if candidates.len() > 1 {
+            self.status_message = candidates.join("  ");
+            self.completion = Some(Completion { stem, candidates, index: 0 });
+        }
+    }
+/// Repeats the last cell-mutating action (`.` in Normal mode) at the current cursor position.
+///
+/// # Returns
+///
+/// Returns `true` if the action was re-applied, or `false` if there is nothing to repeat or the
+/// action failed at the cursor (e.g., the cell is locked).
+    fn repeat_last_change(&mut self) -> bool {
+        let change = match self.last_change.clone() {
+            Some(change) => change,
+            None => {
+                self.status_message = "NOTHING TO REPEAT".to_string();
+                return false;
+            }
+        };
+        let cursor = self.cursor.clone();
+        match change {
+            Change::Insert(value) => !self.is_read_only() && self.update_cell(&cursor, &value, false),
+            Change::MultiInsert { rows, cols, value } => {
+                let end = CellAddress::new(
+                    (cursor.col + cols - 1).min(self.max_cols.saturating_sub(1)),
+                    (cursor.row + rows - 1).min(self.max_rows.saturating_sub(1)),
+                );
+                let range = format!("{}:{}", cursor, end);
+                self.multi_insert(&range, &value)
+            }
+            Change::Align(align) => self.set_alignment(None, &align),
+            Change::Dimension(height, width) => self.set_dimension(None, height, width),
+            Change::Lock => self.lock_cell(None),
+            Change::Unlock => self.unlock_cell(None),
+        }
+    }
+/// Runs one `:` command (a leading `:` is optional) as if it was typed in Command mode. Its result
+/// is left in `status_message`.
+///
+/// # Arguments
+///
+/// * `cmd` - The command, e.g. `"sort A1:A10 desc"`.
+///
+/// # Returns
+///
+/// Returns `false` if the command asked to quit, otherwise `true`.
+    pub fn run_command(&mut self, cmd: &str) -> bool {
+        self.status_message.clear();
+        self.command_buffer = cmd.trim_start_matches(':').to_string();
+        let keep_running = self.process_command();
+        self.command_buffer.clear();
+        keep_running
+    }
+/// Presses keys as if they were typed, in the notation of demo scripts: plain characters are typed
+/// literally and bracketed names (`<Enter>`, `<Esc>`, `<Tab>`, `<Up>`, ...) stand for special keys
+/// (see `parse_key_script`).
+///
+/// # Arguments
+///
+/// * `keys` - The keys to press, e.g. `"jjl"` or `":j B4<Enter>"`.
+///
+/// # Returns
+///
+/// Returns `false` if a key asked to quit (the remaining keys are not pressed), otherwise `true`.
+    pub fn send_keys(&mut self, keys: &str) -> bool {
+        parse_key_script(keys).into_iter().all(|key| self.handle_key_event(key))
+    }
+/// Runs the `:` commands in a script file without the terminal UI, printing each command's status
+/// message (errors to stderr). Blank lines and lines starting with `#` are skipped; a leading `:` is
+/// optional. Lines like `A1=5` or `B1=SUM(A1:A3)` enter a value or formula into a cell (see
+/// `parse_assignment`).
+///
+/// # Arguments
+///
+/// * `script` - The path of the script to run, or `-` to read it from stdin.
+///
+/// # Returns
+///
+/// Returns the number of lines that failed, or an error if the script cannot be read.
+    pub fn run_batch(&mut self, script: &Path) -> io::Result<usize> {
+        let contents = if script == Path::new("-") {
+            io::read_to_string(io::stdin())?
+        } else {
+            std::fs::read_to_string(script)?
+        };
+        let mut failed = 0;
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let keep_running = match parse_assignment(line) {
+                Some((addr, value)) => {
+                    self.status_message.clear();
+                    if addr.row >= self.max_rows || addr.col >= self.max_cols {
+                        self.status_message = format!("ERROR: CELL {} OUTSIDE THE SHEET", addr);
+                    } else if !self.is_read_only() {
+                        self.update_cell(&addr, &value, false);
+                    }
+                    true
+                }
+                None => self.run_command(line),
+            };
+            if is_error_message(&self.status_message) {
+                failed += 1;
+                eprintln!("{}: {}", line_no + 1, self.status_message);
+            } else if !self.status_message.is_empty() {
+                println!("{}: {}", line_no + 1, self.status_message);
+            }
+            if !keep_running {
+                break;
+            }
+        }
+        Ok(failed)
+    }
+/// Completes a two-key Normal mode sequence such as `qa` (record into register `a`), `@a`
+/// (replay register `a`), `ma` (set mark `a`), `'a` (jump to mark `a`) or `"a` (use copy register `a`
+/// for the next `y` or `p`), or a `z` fold key for row groups (`zc`, `zo`, `za`, `zM`, `zR`).
+///
+/// # Arguments
+///
+/// * `pending` - The first key of the sequence.
+/// * `key` - The key that followed it.
+///
+/// # Returns
+///
+/// Returns `false` if a replayed macro asked to quit, otherwise `true`.
+    fn handle_pending_key(&mut self, pending: char, key: KeyCode) -> bool {
+        match (pending, key) {
+            ('q', KeyCode::Char(reg)) if reg.is_ascii_lowercase() => {
+                self.macro_recording = Some(reg);
+                self.macro_buffer.clear();
+                self.status_message = format!("RECORDING @{}", reg);
+            }
+            ('@', KeyCode::Char('@')) => {
+                if let Some(reg) = self.last_macro {
+                    return self.replay_macro(reg);
+                }
+                self.status_message = "NO PREVIOUS MACRO".to_string();
+            }
+            ('@', KeyCode::Char(reg)) if reg.is_ascii_lowercase() => {
+                return self.replay_macro(reg);
+            }
+            ('"', KeyCode::Char(reg)) if reg.is_ascii_lowercase() || reg == UNNAMED_REGISTER => {
+                self.active_register = Some(reg);
+                self.status_message = format!("\"{}", reg);
+            }
+            ('m', KeyCode::Char(reg)) if reg.is_ascii_alphabetic() => {
+                self.marks.insert(reg, self.cursor.clone());
+                self.status_message = format!("MARK {} SET AT {}", reg, self.cursor);
+            }
+            ('\'', KeyCode::Char(reg)) if reg.is_ascii_alphabetic() || reg == '\'' => {
+                self.jump_to_mark(reg);
+            }
+            ('z', KeyCode::Char('c')) => self.fold_rows("", Some(true)),
+            ('z', KeyCode::Char('o')) => self.fold_rows("", Some(false)),
+            ('z', KeyCode::Char('a')) => self.fold_rows("", None),
+            ('z', KeyCode::Char('M')) => self.fold_rows("all", Some(true)),
+            ('z', KeyCode::Char('R')) => self.fold_rows("all", Some(false)),
+            (_, KeyCode::Esc) => {}
+            ('z', _) => {
+                self.status_message = "USE zc, zo, za, zM OR zR".to_string();
+            }
+            _ => {
+                self.status_message = "INVALID REGISTER".to_string();
+            }
+        }
+        true
+    }
+/// Copies a cell or range into a register. Yanking into a named register also fills the unnamed
+/// register, so a plain `p` pastes whatever was yanked last.
+///
+/// # Arguments
+///
+/// * `range_str` - A range (e.g., "A1:B3") or a single cell address.
+/// * `reg` - The register to yank into (`a`-`z` or `"`).
+///
+/// # Returns
+///
+/// Returns `true` if the cells were yanked, or `false` if the range is invalid.
+    fn yank(&mut self, range_str: &str, reg: char) -> bool {
+        let range_str = range_str.trim_start_matches('[').trim_end_matches(']');
+        let (start, end) = match self.parse_range(range_str) {
+            Some(range) => range,
+            None => match CellAddress::from_str(range_str) {
+                Some(addr) => (addr.clone(), addr),
+                None => {
+                    self.status_message = self.range_error(range_str);
+                    return false;
+                }
+            },
+        };
+        let (top, bottom) = (start.row.min(end.row), start.row.max(end.row));
+        let (left, right) = (start.col.min(end.col), start.col.max(end.col));
+        let mut cells = Vec::new();
+        for row in top..=bottom {
+            for col in left..=right {
+                cells.push(self.get_cell(&CellAddress::new(col, row)).cloned().unwrap_or_else(Cell::default));
+            }
+        }
+        let register = Register {
+            source: range_str.to_uppercase(),
+            rows: bottom - top + 1,
+            cols: right - left + 1,
+            cells,
+        };
+        self.status_message = format!("{} CELLS YANKED INTO \"{}", register.cells.len(), reg);
+        if reg != UNNAMED_REGISTER {
+            self.registers.insert(UNNAMED_REGISTER, register.clone());
+        }
+        self.registers.insert(reg, register);
+        true
+    }
+/// Pastes a register at the cursor, its top-left cell landing on the cursor. Values and formulas
+/// are re-entered (so formulas are recalculated in their new place) and alignment and display
+/// format are copied; cells beyond the sheet edge and locked cells are skipped. The paste is one
+/// undoable action.
+///
+/// # Arguments
+///
+/// * `reg` - The register to paste (`a`-`z` or `"`).
+///
+/// # Returns
+///
+/// Returns `true` if the register was pasted, or `false` if it is empty.
+    fn put(&mut self, reg: char) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        let register = match self.registers.get(&reg) {
+            Some(register) => register.clone(),
+            None => {
+                self.status_message = format!("REGISTER \"{} IS EMPTY", reg);
+                return false;
+            }
+        };
+        self.begin_action();
+        let mut pasted = 0;
+        for (i, cell) in register.cells.iter().enumerate() {
+            let addr = CellAddress::new(self.cursor.col + i % register.cols, self.cursor.row + i / register.cols);
+            if addr.col >= self.max_cols || addr.row >= self.max_rows {
+                continue;
+            }
+            let value = cell.formula.as_ref().map_or_else(|| cell.raw_value.clone(), |f| format!("={}", f));
+            if self.update_cell(&addr, &value, true) {
+                if let Some(target) = self.get_cell_mut(&addr) {
+                    target.alignment = cell.alignment.clone();
+                    target.format = cell.format.clone();
+                }
+                pasted += 1;
+            }
+        }
+        self.commit_action();
+        self.status_message = format!("{} CELLS PASTED FROM \"{}", pasted, reg);
+        true
+    }
+/// Moves the cursor to a mark and scrolls the view so the cell is visible. The position before the
+/// jump is remembered as the `'` mark, so `''` jumps back.
+///
+/// # Arguments
+///
+/// * `reg` - The mark to jump to (`a`-`z`, `A`-`Z` or `'`).
+///
+/// # Returns
+///
+/// Returns `true` if the cursor moved, or `false` if the mark is not set or lies outside the sheet.
+    fn jump_to_mark(&mut self, reg: char) -> bool {
+        let target = match self.marks.get(&reg) {
+            Some(addr) if addr.col < self.max_cols && addr.row < self.max_rows => addr.clone(),
+            Some(_) => {
+                self.status_message = format!("MARK {} IS OUTSIDE THE SHEET", reg);
+                return false;
+            }
+            None => {
+                self.status_message = format!("MARK {} NOT SET", reg);
+                return false;
+            }
+        };
+        self.marks.insert('\'', self.cursor.clone());
+        // Marks are cell addresses, so the view follows however far it has been scrolled since
+        self.set_cursor(target.clone());
+        self.status_message = format!("JUMPED TO MARK {} ({})", reg, target);
+        true
+    }
+/// Replays the keys recorded in a macro register as if they were typed, including any
+/// command-mode operations they contain.
+///
+/// # Arguments
+///
+/// * `reg` - The register to replay (`a`-`z`).
+///
+/// # Returns
+///
+/// Returns `false` if the macro asked to quit the application, otherwise `true`.
+    fn replay_macro(&mut self, reg: char) -> bool {
+        let keys = match self.macros.get(&reg) {
+            Some(keys) => keys.clone(),
+            None => {
+                self.status_message = format!("REGISTER @{} IS EMPTY", reg);
+                return true;
+            }
+        };
+        if self.macro_depth >= MAX_MACRO_DEPTH {
+            self.status_message = "ERROR: MACRO RECURSION TOO DEEP".to_string();
+            return true;
+        }
+        self.last_macro = Some(reg);
+        self.macro_depth += 1;
+        let mut keep_running = true;
+        for key in keys {
+            if !self.handle_key_event(key) {
+                keep_running = false;
+                break;
+            }
+        }
+        self.macro_depth -= 1;
+        keep_running
+    }
 /// Handles key events based on the current mode of the application.
 ///
 /// This function processes the key presses based on the current mode of the application 
@@ -2123,21 +8907,45 @@ impl Spreadsheet {
 ///     - `h`, `j`, `k`, `l` to move the cursor left, down, up, and right respectively.
 ///     - `w`, `a`, `s`, `d` to scroll the view.
 ///     - `:` to switch to Command Mode.
-///     - `q` to quit the application.
+///     - `q<reg>` to start recording a macro into register `a`-`z`, `q` again to stop.
+///     - `@<reg>` to replay a macro, `@@` to replay the last one.
+///     - `.` to repeat the last change (insert, multi-insert, align, dim, lock/unlock) at the cursor.
+///     - `m<reg>` to set a mark at the cursor, `'<reg>` to jump to it, `''` to jump back.
+///     - `y` to yank the current cell, `p` to paste the unnamed register at the cursor; prefix with
+///       `"<reg>` (e.g. `"ay`, `"ap`) to use register `a`-`z` instead.
+///     - `[` to highlight the cells the cursor cell depends on, `]` the cells that depend on it
+///       (press again to turn off); the highlights follow the cursor.
+///     - `zc` / `zo` to collapse / expand the row group at the cursor, `za` to toggle it, `zM` / `zR` to
+///       collapse / expand every row group.
 /// - **Insert Mode**: 
 ///     - `Esc` to switch back to Normal Mode.
 ///     - `Enter` to apply the changes to the cell and return to Normal Mode.
 ///     - `Backspace` to remove the last character from the command buffer.
 ///     - Any character is inserted into the command buffer.
+///     - In a cell with a list validation, `Tab` / `Down` / `Right` pick the next allowed value and
+///       `Shift-Tab` / `Up` / `Left` the previous one, a character picks the next value starting with it,
+///       and `Enter` without a pick keeps the cell as it was.
 /// - **Command Mode**: 
 ///     - `Esc` to return to Normal Mode.
 ///     - `Enter` to execute the command from the buffer and return to Normal Mode.
+///     - `Up` / `Down` to recall older / newer commands from the history.
+///     - `Tab` to complete command names, cell addresses and file paths (press again to cycle).
 ///     - `Backspace` to remove the last character from the command buffer.
 ///     - Any character is added to the command buffer.
 /// - **Find Mode**: 
 ///     - `Esc` to return to Normal Mode and clear the find matches.
 ///     - `n` to find the next match.
 ///     - `p` to find the previous match.
+///     - `t` to cycle the search scope (values, formulas, addresses) and search again.
+///     - `:` to enter Command Mode with the matches kept (for `:matches set` / `:matches clear`).
+/// - **Replace Mode** (confirm-each `:replace`):
+///     - `y` to replace the current match, `n` to skip it.
+///     - `a` to replace all remaining matches.
+///     - `q` or `Esc` to stop replacing.
+/// - **Help Mode** (`:help`):
+///     - `j` / `k` (or the arrow keys) to scroll a line, `Space` / `PageUp` / `PageDown` a page.
+///     - `g` / `G` to go to the top / bottom.
+///     - `q` or `Esc` to close the help.
 ///
 /// # Arguments
 /// 
@@ -2147,42 +8955,102 @@ impl Spreadsheet {
 /// 
 /// Returns a boolean value:
 /// - `true` to continue running the application.
-/// - `false` if the user pressed `q` in Normal Mode (to quit the application).
+/// - `false` if the user quit the application (e.g., with `:q`).
     fn handle_key_event(&mut self, key: KeyCode) -> bool {
+        // Alert notices and charts stay up only until the user reacts
+        self.alert_notice = None;
+        self.chart = None;
+        // Typing into the command buffer only needs the input line redrawn (see below)
+        self.needs_full_redraw = true;
+        if self.demo_mode {
+            if let Some(label) = key_label(&key) {
+                if self.demo_keys.len() >= DEMO_OVERLAY_KEYS {
+                    self.demo_keys.pop_front();
+                }
+                self.demo_keys.push_back(label);
+            }
+            self.demo_recording.push(key);
+        }
+        // Only keys typed by the user are recorded, not keys replayed from a macro
+        if self.macro_recording.is_some() && self.macro_depth == 0 {
+            self.macro_buffer.push(key);
+        }
+        if let Some(pending) = self.pending_key.take_if(|_| self.mode == Mode::Normal) {
+            return self.handle_pending_key(pending, key);
+        }
         match self.mode {
             Mode::Normal => {
                 match key {
-                    KeyCode::Char('q') => return false, // Quit
+                    KeyCode::Char('q') => {
+                        if let Some(reg) = self.macro_recording.take() {
+                            // Stop recording; drop the closing 'q' itself
+                            self.macro_buffer.pop();
+                            self.macros.insert(reg, std::mem::take(&mut self.macro_buffer));
+                            self.status_message = format!("RECORDED @{}", reg);
+                        } else {
+                            self.pending_key = Some('q');
+                        }
+                    },
+                    KeyCode::Char('@') => self.pending_key = Some('@'),
+                    KeyCode::Char('m') => self.pending_key = Some('m'),
+                    KeyCode::Char('"') => self.pending_key = Some('"'),
+                    KeyCode::Char('y') => {
+                        let reg = self.active_register.take().unwrap_or(UNNAMED_REGISTER);
+                        let cell = self.cursor.to_string();
+                        self.yank(&cell, reg);
+                    },
+                    KeyCode::Char('p') => {
+                        let reg = self.active_register.take().unwrap_or(UNNAMED_REGISTER);
+                        self.put(reg);
+                    },
+                    KeyCode::Char('\'') => self.pending_key = Some('\''),
+                    KeyCode::Char('z') => self.pending_key = Some('z'),
+                    KeyCode::Char('.') => {
+                        self.repeat_last_change();
+                    },
+                    KeyCode::Char('[') => {
+                        self.show_precedents = !self.show_precedents;
+                        self.status_message = if self.show_precedents { "SHOWING PRECEDENTS" } else { "PRECEDENTS HIDDEN" }.to_string();
+                    },
+                    KeyCode::Char(']') => {
+                        self.show_dependents = !self.show_dependents;
+                        self.status_message = if self.show_dependents { "SHOWING DEPENDENTS" } else { "DEPENDENTS HIDDEN" }.to_string();
+                    },
                     KeyCode::Char('h') => self.move_cursor(-1, 0),
                     KeyCode::Char('j') => self.move_cursor(0, 1),
                     KeyCode::Char('k') => self.move_cursor(0, -1),
                     KeyCode::Char('l') => self.move_cursor(1, 0),
-                    KeyCode::Char('w') => unsafe {
-                        if START_ROW >= 10 {
-                            START_ROW -= 10;
+                    // Scroll by a page: the rows or columns of the view that are not frozen
+                    KeyCode::Char('w') => {
+                        let page = self.view_rows - self.frozen_rows;
+                        if self.start_row >= page {
+                            self.start_row -= page;
                         } else {
-                            START_ROW = 0;
+                            self.start_row = 0;
                         }
                     },
-                    KeyCode::Char('d') => unsafe {
-                        if START_COL + 20 <= C - 1 {
-                            START_COL += 10;
+                    KeyCode::Char('d') => {
+                        let page = self.view_cols - self.frozen_cols;
+                        if self.start_col + 2 * page < self.max_cols {
+                            self.start_col += page;
                         } else {
-                            START_COL =  C.saturating_sub(10);
+                            self.start_col = self.max_cols.saturating_sub(page);
                         }
                     },
-                    KeyCode::Char('a') => unsafe {
-                        if START_COL >= 10 {
-                            START_COL -= 10;
+                    KeyCode::Char('a') => {
+                        let page = self.view_cols - self.frozen_cols;
+                        if self.start_col >= page {
+                            self.start_col -= page;
                         } else {
-                            START_COL = 0;
+                            self.start_col = 0;
                         }
                     },
-                    KeyCode::Char('s') => unsafe {
-                        if START_ROW + 20 <= R - 1 {
-                            START_ROW += 10;
+                    KeyCode::Char('s') => {
+                        let page = self.view_rows - self.frozen_rows;
+                        if self.start_row + 2 * page < self.max_rows {
+                            self.start_row += page;
                         } else {
-                            START_ROW = R.saturating_sub(10);
+                            self.start_row = self.max_rows.saturating_sub(page);
                         }
                     },
                     KeyCode::Char(':') => {
@@ -2198,6 +9066,18 @@ impl Spreadsheet {
                         self.mode = Mode::Normal;
                         self.status_message.clear();
                     },
+                    KeyCode::Enter if self.command_buffer.is_empty() && self.cursor_choices().is_some() => {
+                        // Nothing was picked, so the cell keeps its value
+                        self.mode = Mode::Normal;
+                        self.status_message.clear();
+                    },
+                    KeyCode::Tab | KeyCode::BackTab | KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
+                        | KeyCode::Char(_) | KeyCode::Backspace if self.cursor_choices().is_some() => {
+                        if let Some(choices) = self.cursor_choices() {
+                            self.pick_choice(&choices, key);
+                        }
+                        self.needs_full_redraw = false;
+                    },
                     KeyCode::Enter => {
                         // Apply changes and exit insert mode
                         // Clone the values to avoid borrowing issues
@@ -2206,28 +9086,50 @@ impl Spreadsheet {
                         // println!("Debug: Inserting value {} at {}", command_buffer_clone, cursor_clone.to_string());
                         // Now we can safely call update_cell with the cloned values
                         self.status_message.clear();
-                        self.update_cell(&cursor_clone, &command_buffer_clone, false);
+                        if !self.is_read_only() && self.update_cell(&cursor_clone, &command_buffer_clone, false) {
+                            self.last_change = Some(Change::Insert(command_buffer_clone));
+                        }
                         self.mode = Mode::Normal;
                         self.command_buffer.clear();
                         
                     },
                     KeyCode::Backspace => {
                         self.command_buffer.pop();
+                        self.needs_full_redraw = false;
                     },
                     KeyCode::Char(c) => {
                         self.command_buffer.push(c);
+                        self.needs_full_redraw = false;
                     },
                     _ => {}
                 }
             },
             Mode::Command => {
+                if key != KeyCode::Tab {
+                    self.completion = None;
+                }
                 match key {
+                    KeyCode::Tab => {
+                        self.complete_command();
+                        self.needs_full_redraw = false;
+                    },
                     KeyCode::Esc => {
                         self.mode = Mode::Normal;
                         self.command_buffer.clear();
+                        self.history_index = None;
+                    },
+                    KeyCode::Up => {
+                        self.recall_history(true);
+                        self.needs_full_redraw = false;
+                    },
+                    KeyCode::Down => {
+                        self.recall_history(false);
+                        self.needs_full_redraw = false;
                     },
                     KeyCode::Enter => {
                         self.mode = Mode::Normal;
+                        let cmd = self.command_buffer.trim().to_string();
+                        self.record_command(&cmd);
                         let continue_running = self.process_command();
                         self.command_buffer.clear();
                         if !continue_running {
@@ -2236,9 +9138,11 @@ impl Spreadsheet {
                     },
                     KeyCode::Backspace => {
                         self.command_buffer.pop();
+                        self.needs_full_redraw = false;
                     },
                     KeyCode::Char(c) => {
                         self.command_buffer.push(c);
+                        self.needs_full_redraw = false;
                     },
                     _ => {}
                 }
@@ -2256,6 +9160,49 @@ impl Spreadsheet {
                     KeyCode::Char('p') => {
                         self.find_prev();
                     },
+                    KeyCode::Char(':') => {
+                        // Keep the matches so `:matches ...` can act on them
+                        self.mode = Mode::Command;
+                        self.command_buffer.clear();
+                    },
+                    KeyCode::Char('t') => {
+                        // Cycle the search scope and rerun the query
+                        self.find_scope = self.find_scope.next();
+                        let query = self.find_query.clone();
+                        let found = self.find(&query);
+                        self.status_message = format!("SEARCHING {}: {}", self.find_scope.name(), self.status_message);
+                        if !found {
+                            self.mode = Mode::Normal;
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            Mode::Replace => {
+                match key {
+                    KeyCode::Char('y') => self.answer_replace(true, false),
+                    KeyCode::Char('n') => self.answer_replace(false, false),
+                    KeyCode::Char('a') => self.answer_replace(true, true),
+                    KeyCode::Esc | KeyCode::Char('q') => {
+                        match self.replace_state.take() {
+                            Some(state) => self.finish_replace(state),
+                            None => self.mode = Mode::Normal,
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            Mode::Help => {
+                // Scrolling past the end is clamped when the screen is drawn
+                let page = self.view_rows.max(1);
+                match key {
+                    KeyCode::Char('j') | KeyCode::Down => self.help_scroll += 1,
+                    KeyCode::Char('k') | KeyCode::Up => self.help_scroll = self.help_scroll.saturating_sub(1),
+                    KeyCode::Char(' ') | KeyCode::PageDown => self.help_scroll += page,
+                    KeyCode::PageUp => self.help_scroll = self.help_scroll.saturating_sub(page),
+                    KeyCode::Char('g') | KeyCode::Home => self.help_scroll = 0,
+                    KeyCode::Char('G') | KeyCode::End => self.help_scroll = usize::MAX,
+                    KeyCode::Esc | KeyCode::Char('q') => self.mode = Mode::Normal,
                     _ => {}
                 }
             }
@@ -2263,23 +9210,199 @@ impl Spreadsheet {
         
         true // Continue running
     }
+    /// Redraws only the command buffer and status message lines.
+///
+/// Used while typing in Insert or Command mode, where the grid itself has not changed, so each
+/// keystroke costs two short lines of output instead of a full-screen redraw. The next full frame
+/// rewrites every line, as the lines drawn here are not part of the last frame.
+///
+/// # Arguments
+///
+/// * `stdout` - The output stream for writing terminal content.
+///
+/// # Returns
+///
+/// Returns an `io::Result<()>` indicating whether the lines were written successfully.
+fn draw_input_line(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
+    self.drawn = None;
+    let (_, rows) = terminal::size()?;
+    stdout.execute(MoveTo(0, rows.saturating_sub(2)))?;
+    stdout.execute(Clear(ClearType::CurrentLine))?;
+    write!(stdout, "{}", self.command_buffer)?;
+
+    self.draw_status_line(stdout)?;
+    stdout.flush()?;
+    Ok(())
+}
+/// Draws the `:help` screen over the grid: a title line, as many lines of `help_lines` as fit from
+/// `help_scroll` on, and the status bar.
+///
+/// # Arguments
+///
+/// * `stdout` - The output stream for writing terminal content.
+///
+/// # Returns
+///
+/// Returns an `io::Result<()>` indicating whether the screen was written successfully.
+fn draw_help(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
+    self.drawn = None;
+    let (cols, rows) = terminal::size()?;
+    let lines = help_lines();
+    let height = (rows as usize).saturating_sub(2).max(1);
+    self.help_scroll = self.help_scroll.min(lines.len().saturating_sub(height));
+
+    stdout.execute(terminal::Clear(ClearType::All))?;
+    stdout.execute(MoveTo(0, 0))?;
+    let last = (self.help_scroll + height).min(lines.len());
+    let title = format!(" HELP  lines {}-{} of {}  |  j/k scroll, space next page, q close", self.help_scroll + 1, last, lines.len());
+    stdout.execute(style::SetAttribute(style::Attribute::Reverse))?;
+    write!(stdout, "{:<1$}", truncate_to_width(&title, cols as usize), cols as usize)?;
+    stdout.execute(style::SetAttribute(style::Attribute::NoReverse))?;
+    for (i, line) in lines[self.help_scroll..last].iter().enumerate() {
+        stdout.execute(MoveTo(0, i as u16 + 1))?;
+        write!(stdout, "{}", truncate_to_width(line, cols as usize))?;
+    }
+    self.draw_status_line(stdout)?;
+    stdout.flush()
+}
+/// Draws a `:chart` panel over the grid, below the column headers: a title with the range and its
+/// minimum and maximum, and the chart in a box. Values that do not fit the terminal width are left out.
+///
+/// # Arguments
+///
+/// * `stdout` - The output stream for writing terminal content.
+/// * `kind` - Whether to draw bars or a line.
+/// * `start`, `end` - Opposite corners of the charted range.
+///
+/// # Returns
+///
+/// Returns an `io::Result<()>` indicating whether the panel was written successfully.
+fn draw_chart(&self, stdout: &mut impl Write, kind: ChartKind, start: &CellAddress, end: &CellAddress) -> io::Result<()> {
+    const CHART_HEIGHT: usize = 8;
+    let (cols, _) = terminal::size()?;
+    let mut values = self.range_numbers(start, end);
+    let count = values.len();
+    let room = (cols as usize).saturating_sub(ROW_LABEL_WIDTH + 4).max(1);
+    values.truncate(room);
+    let bar_width = (room / values.len()).clamp(1, 3);
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let mut title = format!("{} {}:{}  MIN {}  MAX {}", if kind == ChartKind::Bar { "BAR" } else { "LINE" },
+        start, end, min, max);
+    if values.len() < count {
+        title.push_str(&format!("  (FIRST {} OF {})", values.len(), count));
+    }
+    let inner = (values.len() * bar_width).max(title.chars().count());
+    let mut lines = vec![format!("┌{}┐", "─".repeat(inner)), format!("│{:<1$}│", title, inner)];
+    for line in chart_lines(kind, &values, CHART_HEIGHT, bar_width) {
+        lines.push(format!("│{:<1$}│", line, inner));
+    }
+    lines.push(format!("└{}┘", "─".repeat(inner)));
+
+    stdout.execute(SetForegroundColor(self.theme.headers))?;
+    for (i, line) in lines.iter().enumerate() {
+        stdout.execute(MoveTo(ROW_LABEL_WIDTH as u16 + 1, i as u16 + 1))?;
+        write!(stdout, "{}", truncate_to_width(line, (cols as usize).saturating_sub(ROW_LABEL_WIDTH + 1)))?;
+    }
+    stdout.execute(SetForegroundColor(Color::Reset))?;
+    Ok(())
+}
+/// Returns the text of the status bar for a terminal `width` columns wide, as the mode, the
+/// information and the last error, which together fill the width exactly.
+///
+/// The bar always shows the mode, the current file (with `[+]` when it changed since it was opened or
+/// saved), the number of cells in use and the cursor address, then the other clients of a shared
+/// session with their cells, the latest status message and, on the right, the last error.
+pub fn status_bar(&self, width: usize) -> (String, String, String) {
+    let mode = match self.mode {
+        Mode::Normal => "NORMAL",
+        Mode::Insert => "INSERT",
+        Mode::Command => "COMMAND",
+        Mode::Find => "FIND",
+        Mode::Replace => "REPLACE",
+        Mode::Help => "HELP",
+    };
+    let file = self.file_name.as_ref().map_or("[NO FILE]".to_string(), |path| path.display().to_string());
+    let cells = self.data.values().filter(|cell| !cell.is_blank()).count();
+    let mode = format!(" {} ", mode);
+    let mut info = format!(" {}{} | {} CELLS | {}", file, if self.modified { " [+]" } else { "" }, cells, self.cursor);
+    if let Some(error) = self.get_cell(&self.cursor).and_then(|cell| cell.error.as_ref()) {
+        info.push_str(&format!(" ERR: {}", error));
+    }
+    // The other clients of a shared session and the cells they are on
+    if let Some(session) = self.session.as_ref().filter(|session| !session.peers.is_empty()) {
+        let mut peers: Vec<String> = session.peers.iter().map(|(name, addr)| format!("{} {}", name, addr)).collect();
+        peers.sort();
+        info.push_str(&format!(" | WITH {}", peers.join(", ")));
+    }
+    // The current message is only shown when it is not already the error on the right
+    if !self.status_message.is_empty() && self.last_error.as_ref() != Some(&self.status_message) {
+        info.push_str(" | ");
+        info.push_str(&self.status_message);
+    }
+    let error = self.last_error.as_ref().map(|e| format!(" ERR: {} ", e)).unwrap_or_default();
+    let mode = truncate_to_width(&mode, width);
+    let rest_width = width.saturating_sub(display_width(&mode));
+    let error = truncate_to_width(&error, rest_width);
+    let info_width = rest_width - display_width(&error);
+    let info = align_to_width(&truncate_to_width(&info, info_width), info_width, &Alignment::Left);
+    (mode, info, error)
+}
+/// Draws the status bar (see `status_bar`) on the bottom line of the terminal, the mode in its own colour
+/// and the last error in red.
+///
+/// # Arguments
+///
+/// * `stdout` - The output stream for writing terminal content.
+///
+/// # Returns
+///
+/// Returns an `io::Result<()>` indicating whether the line was written successfully.
+fn draw_status_line(&self, stdout: &mut impl Write) -> io::Result<()> {
+    let (cols, rows) = terminal::size()?;
+    let mode_color = match self.mode {
+        Mode::Normal => Color::Blue,
+        Mode::Insert => Color::Green,
+        Mode::Command => Color::Yellow,
+        Mode::Find => Color::Magenta,
+        Mode::Replace => Color::Red,
+        Mode::Help => Color::Cyan,
+    };
+    let (mode, info, error) = self.status_bar(cols as usize);
+
+    stdout.execute(MoveTo(0, rows.saturating_sub(1)))?;
+    stdout.execute(Clear(ClearType::CurrentLine))?;
+    stdout.execute(style::SetAttribute(style::Attribute::Bold))?;
+    stdout.execute(SetForegroundColor(Color::Black))?;
+    stdout.execute(style::SetBackgroundColor(mode_color))?;
+    write!(stdout, "{}", mode)?;
+    stdout.execute(style::SetAttribute(style::Attribute::NormalIntensity))?;
+    stdout.execute(SetForegroundColor(Color::White))?;
+    stdout.execute(style::SetBackgroundColor(Color::DarkGrey))?;
+    write!(stdout, "{}", info)?;
+    stdout.execute(style::SetBackgroundColor(Color::Red))?;
+    write!(stdout, "{}", error)?;
+    stdout.execute(SetForegroundColor(Color::Reset))?;
+    stdout.execute(style::SetBackgroundColor(Color::Reset))?;
+    Ok(())
+}
     /// Draws the spreadsheet grid and related UI elements to the terminal.
 ///
 /// This function is responsible for rendering the spreadsheet's grid, including:
 /// - Row and column headers
-/// - The cells' contents, with appropriate formatting and spacing
-/// - The cursor position (highlighted)
+/// - The cells' contents, with appropriate formatting and spacing; a row is as many lines tall as its tallest cell
+/// - The cursor position (highlighted), and the cells the other clients of a shared session are on
 /// - A status bar that shows information about the current cell
-/// - A status message and command buffer, if available
+/// - The status bar and the command buffer, if available
 ///
-/// The screen is cleared at the beginning, and the entire grid is redrawn, ensuring
-/// that any changes (like cursor movement, updated cell content, or mode transitions) 
-/// are reflected in the UI. This function works with a terminal-based UI and uses 
-/// `terminal` and `cursor` functionalities to move the cursor and clear the screen.
+/// The whole frame is built in memory and handed to `present_frame`, which only writes the lines
+/// that differ from the last frame (such as the old and new cursor cells or an updated value) and
+/// the status bar if it changed. The screen is never cleared, so redrawing does not flicker, even
+/// over a slow connection.
 ///
 /// # Arguments
 /// 
-/// * `stdout` - The output stream for writing terminal content, typically the terminal's standard output.
+/// * `out` - The output stream for writing terminal content, typically the terminal's standard output.
 /// 
 /// # Returns
 /// 
@@ -2288,7 +9411,7 @@ impl Spreadsheet {
 /// - `Err(e)` if an I/O error occurred during the process.
 
 
-fn draw(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
+fn draw(&mut self, out: &mut io::Stdout) -> io::Result<()> {
     use rand::Rng;
 
     // Flicker toggle every 300ms
@@ -2297,39 +9420,52 @@ fn draw(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
         self.last_flicker = Instant::now();
     }
     // Corruption increases every 5 seconds while haunted
-    if self.haunted && self.last_corruption_tick.elapsed() > Duration::from_secs(7) {
+    // (a haunting started by haunt rules follows the rule weights instead)
+    if self.haunted && !self.haunt_by_rules && self.last_corruption_tick.elapsed() > Duration::from_secs(7) {
         self.corruption_level = self.corruption_level.saturating_add(1).min(3);
         self.last_corruption_tick = Instant::now();
     }
+    if self.mode == Mode::Help {
+        return self.draw_help(out);
+    }
+    // Rows about to be shown must be in memory for a database-backed sheet
+    self.fit_view();
+    let visible_rows = self.visible_rows();
+    let visible_cols = self.visible_cols();
+    if self.is_store_backed() {
+        let first_row = self.start_row;
+        self.page_in_rows(first_row, first_row + self.view_rows);
+        self.page_in_rows(0, self.frozen_rows);
+        for addr in self.watch_list.clone() {
+            self.page_in_refs(&addr);
+        }
+    }
+    // Stale formulas about to be shown are evaluated now; the rest wait until they scroll into view
+    if !self.stale.is_empty() {
+        let shown: Vec<String> = visible_rows.iter()
+            .flat_map(|&row| visible_cols.iter().map(move |&col| CellAddress::new(col, row).to_string()))
+            .chain(self.watch_list.iter().cloned())
+            .chain(std::iter::once(self.cursor.to_string()))
+            .collect();
+        self.evaluate_stale(Some(&shown), None);
+    }
 
-
-    // Clear screen
-    stdout.execute(terminal::Clear(ClearType::All))?;
-    stdout.execute(MoveTo(0, 0))?;
+    // The lines are written to a buffer and compared with the last frame before any reach the terminal
+    let mut lines = Vec::new();
+    let stdout = &mut lines;
     
-    let row_label_width = 5;
+    let row_label_width = ROW_LABEL_WIDTH;
     let cell_padding = 1;
-    let default_cell_width = 5;
-    let mut col_widths = vec![default_cell_width; 10];
-
-    for col in unsafe { START_COL..(START_COL + 10) } {
-        let col_idx = (col - unsafe { START_COL }) as usize;
-        let col_letter = CellAddress::col_to_letters(col);
-        col_widths[col_idx] = col_widths[col_idx].max(col_letter.len());
-        for row in unsafe { START_ROW..(START_ROW + 10).min(R) } {
-            let addr = CellAddress::new(col, row);
-            if let Some(cell) = self.get_cell(&addr) {
-                col_widths[col_idx] = col_widths[col_idx].max(cell.width);
-            }
-        }
-        col_widths[col_idx] = col_widths[col_idx].max(3);
-    }
+    let col_widths: Vec<usize> = visible_cols.iter().map(|&col| self.column_width(col, &visible_rows)).collect();
 
-    stdout.execute(SetForegroundColor(Color::Cyan))?;
+    stdout.execute(SetForegroundColor(self.theme.headers))?;
     write!(stdout, "{:<width$}", "", width = row_label_width + 1)?;
 
-    for col in unsafe { START_COL..(START_COL + 10).min(C) } {
-        let col_idx = (col - unsafe { START_COL }) as usize;
+    for (col_idx, &col) in visible_cols.iter().enumerate() {
+        // Frozen columns are set off from the scrolling ones by a divider (part of the grid lines if shown)
+        if col_idx > 0 && col_idx == self.frozen_cols && !self.grid_lines {
+            write!(stdout, "|")?;
+        }
         let col_letter = CellAddress::col_to_letters(col);
         let total_cell_width = col_widths[col_idx] + cell_padding;
         write!(stdout, "{:^width$}", col_letter, width = total_cell_width)?;
@@ -2344,124 +9480,256 @@ fn draw(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
     }
 
     let mut rng = rand::thread_rng();
+    // Cells matched by the active search are highlighted while in Find mode
+    let highlighted: HashSet<String> = if self.mode == Mode::Find {
+        self.find_matches.iter().map(|addr| addr.to_string()).collect()
+    } else {
+        HashSet::new()
+    };
+    // Cells the other clients of a shared session are on
+    let peer_cells: HashSet<String> = self.session.as_ref()
+        .map_or_else(HashSet::new, |session| session.peers.values().map(|addr| addr.to_string()).collect());
+    let (precedents, dependents) = self.formula_links();
+    // The heatmap spreads its gradient over every number in the sheet, not only the visible ones
+    let heat_range = if self.heatmap {
+        // Cells that are not stored are blank and hold 0
+        let blanks = self.data.len() < self.max_rows * self.max_cols;
+        self.data.values().filter_map(|cell| cell.display_value.parse::<f64>().ok())
+            .chain(blanks.then(|| self.blank_cell.display_value.parse::<f64>().ok()).flatten())
+            .fold(None, |range: Option<(f64, f64)>, v| Some(range.map_or((v, v), |(lo, hi)| (lo.min(v), hi.max(v)))))
+    } else {
+        None
+    };
 
-    for row in unsafe { START_ROW..(START_ROW + 10).min(R) } {
-        stdout.execute(SetForegroundColor(Color::Cyan))?;
-        write!(stdout, "{:>width$}", row + 1, width = row_label_width)?;
-        stdout.execute(SetForegroundColor(Color::Reset))?;
+    for (row_idx, &row) in visible_rows.iter().enumerate() {
+        if self.grid_lines {
+            // A rule above every row; the one below the frozen rows is doubled
+            let (fill, junctions) = if row_idx == 0 {
+                ('─', ['┌', '┬', '╥', '┐'])
+            } else if row_idx == self.frozen_rows {
+                ('═', ['╞', '╪', '╬', '╡'])
+            } else {
+                ('─', ['├', '┼', '╫', '┤'])
+            };
+            stdout.execute(SetForegroundColor(self.theme.headers))?;
+            write!(stdout, "{}\r\n", self.grid_rule(&col_widths, fill, junctions))?;
+            stdout.execute(SetForegroundColor(Color::Reset))?;
+        } else if row_idx > 0 && row_idx == self.frozen_rows {
+            // Frozen rows are set off from the scrolling ones by a divider line
+            let grid_width: usize = col_widths.iter().take(visible_cols.len()).map(|w| w + cell_padding).sum();
+            let divider = (self.frozen_cols > 0) as usize;
+            stdout.execute(SetForegroundColor(self.theme.headers))?;
+            write!(stdout, "{}\r\n", "-".repeat(row_label_width + grid_width + divider))?;
+            stdout.execute(SetForegroundColor(Color::Reset))?;
+        }
+        // Every other row is shaded when zebra striping is on
+        let stripe = (self.zebra && row % 2 == 1).then_some(self.theme.stripe_bg);
+        // Grid lines take the place of the space before each cell
+        let pad = if self.grid_lines { "" } else { " " };
+        // A row is as tall as its tallest cell; the label goes on its first line
+        for line in 0..self.row_height(row, &visible_cols) {
+            stdout.execute(SetForegroundColor(self.theme.headers))?;
+            if line == 0 {
+                // A `+` marks the row below a collapsed group, such as its subtotal row
+                let folded = row > 0 && self.is_row_hidden(row - 1);
+                let label = format!("{}{}", if folded { "+" } else { "" }, row + 1);
+                write!(stdout, "{:>width$}", label, width = row_label_width)?;
+            } else {
+                write!(stdout, "{:width$}", "", width = row_label_width)?;
+            }
+            stdout.execute(SetForegroundColor(Color::Reset))?;
 
-        for col in unsafe { START_COL..(START_COL + 10).min(C) } {
-            let col_idx = (col - unsafe { START_COL }) as usize;
-            let addr = CellAddress::new(col, row);
-            let is_cursor_cell = col == self.cursor.col && row == self.cursor.row;
+            for (col_idx, &col) in visible_cols.iter().enumerate() {
+                let frozen_border = col_idx > 0 && col_idx == self.frozen_cols;
+                if self.grid_lines || frozen_border {
+                    stdout.execute(SetForegroundColor(self.theme.headers))?;
+                    write!(stdout, "{}", match (self.grid_lines, frozen_border) {
+                        (true, true) => '║',
+                        (true, false) => '│',
+                        _ => '|',
+                    })?;
+                    stdout.execute(SetForegroundColor(Color::Reset))?;
+                }
+                let addr = CellAddress::new(col, row);
+                let is_cursor_cell = col == self.cursor.col && row == self.cursor.row;
+                let cell_lines = self.cell_lines(&addr);
+                // Below a cell shorter than its row, the column is left blank
+                if line >= cell_lines.len() {
+                    if let Some(stripe) = stripe {
+                        stdout.execute(style::SetBackgroundColor(stripe))?;
+                    }
+                    write!(stdout, "{}{:width$}", pad, "", width = col_widths[col_idx])?;
+                    if stripe.is_some() {
+                        stdout.execute(style::SetBackgroundColor(Color::Reset))?;
+                    }
+                    continue;
+                }
 
-            // Haunted flicker logic
-            let mut flicker_effect = None;
+                // Haunted flicker logic
+                let mut flicker_effect = None;
 
-            if self.haunted && self.flicker_on {
-                let chance: f32 = rng.r#gen();
+                if line == 0 && self.haunted && self.flicker_on {
+                    let chance: f32 = rng.r#gen();
 
-                match self.corruption_level {
-                    0 => {
-                        if chance < 0.05 {
+                    match self.corruption_level {
+                        0 if chance < 0.05 => {
                             flicker_effect = Some("👻");
                         }
-                    }
-                    1 => {
-                        if chance < 0.05 {
-                            flicker_effect = Some("👻");
-                        } else if chance < 0.10 {
-                            flicker_effect = Some("~");
+                        1 => {
+                            if chance < 0.05 {
+                                flicker_effect = Some("👻");
+                            } else if chance < 0.10 {
+                                flicker_effect = Some("~");
+                            }
                         }
-                    }
-                    2 => {
-                        if chance < 0.05 {
-                            flicker_effect = Some("👻");
-                        } else if chance < 0.10 {
-                            flicker_effect = Some(["~", "#", "X", "%", "!!"].choose(&mut rng).unwrap());
-                        } else if chance < 0.12 {
-                            flicker_effect = Some("💥");
+                        2 => {
+                            if chance < 0.05 {
+                                flicker_effect = Some("👻");
+                            } else if chance < 0.10 {
+                                flicker_effect = Some(["~", "#", "X", "%", "!!"].choose(&mut rng).unwrap());
+                            } else if chance < 0.12 {
+                                flicker_effect = Some("💥");
+                            }
                         }
-                    }
-                    3 => {
-                        if chance < 0.05 {
-                            flicker_effect = Some("👻");
-                        } else if chance < 0.10 {
-                            flicker_effect = Some(["~", "#", "X", "%", "!!", "???"].choose(&mut rng).unwrap());
-                        } else if chance < 0.15 {
-                            flicker_effect = Some("💥");
+                        3 => {
+                            if chance < 0.05 {
+                                flicker_effect = Some("👻");
+                            } else if chance < 0.10 {
+                                flicker_effect = Some(["~", "#", "X", "%", "!!", "???"].choose(&mut rng).unwrap());
+                            } else if chance < 0.15 {
+                                flicker_effect = Some("💥");
+                            }
                         }
+                        _ => {}
                     }
-                    _ => {}
                 }
-            }
 
-            if self.haunted && self.corruption_level >= 2 && rng.r#gen::<f32>() < 0.02 {
-                let whispers = [
-                    "get out",
-                    "it sees you",
-                    "run",
-                    "don't trust it",
-                    "they're watching",
-                    "help me",
-                    "leave now",
-                ];
-                self.status_message = whispers.choose(&mut rng).unwrap().to_string();
-            }
+                if line == 0 && self.haunted && self.corruption_level >= 2 && rng.r#gen::<f32>() < 0.02 {
+                    let whispers = [
+                        "get out",
+                        "it sees you",
+                        "run",
+                        "don't trust it",
+                        "they're watching",
+                        "help me",
+                        "leave now",
+                    ];
+                    // Whispers name the cells the haunt rules are unhappy about
+                    self.status_message = match self.haunt_cells.choose(&mut rng) {
+                        Some(cell) if rng.r#gen::<bool>() => {
+                            let cell_whispers = ["{} is wrong", "look at {}", "{} knows", "fix {}", "something lives in {}"];
+                            cell_whispers.choose(&mut rng).unwrap().replace("{}", cell)
+                        }
+                        _ => whispers.choose(&mut rng).unwrap().to_string(),
+                    };
+                }
             
 
 
 
-            // Handle flicker color
-            // if flicker_dim {
-            //     stdout.execute(SetForegroundColor(Color::DarkGrey))?;
-            // }
+                // Handle flicker color
+                // if flicker_dim {
+                //     stdout.execute(SetForegroundColor(Color::DarkGrey))?;
+                // }
 
-            // Cursor highlight
-            if is_cursor_cell {
-                stdout.execute(SetForegroundColor(Color::Black))?;
-                stdout.execute(style::SetBackgroundColor(Color::White))?;
-            }
+                // Cursor highlight
+                let is_match_cell = !is_cursor_cell && highlighted.contains(&addr.to_string());
+                let (fg, bg, bold, italic) = self.get_cell(&addr).map_or((None, None, false, false), |cell| {
+                    // Failed formulas show a red ERR whatever the cell's own color
+                    let fg = if cell.error.is_some() { Some(Color::Red) } else { cell.fg.as_deref().and_then(parse_color) };
+                    (fg, cell.bg.as_deref().and_then(parse_color), cell.bold, cell.italic)
+                });
+                let link_bg = if dependents.contains(&addr.to_string()) {
+                    Some(self.theme.dependent_bg)
+                } else if precedents.contains(&addr.to_string()) {
+                    Some(self.theme.precedent_bg)
+                } else {
+                    None
+                };
+                let heat_bg = heat_range.zip(self.get_cell(&addr).and_then(|cell| cell.display_value.parse::<f64>().ok()))
+                    .map(|((lo, hi), value)| heat_color(value, lo, hi, self.theme.heat_low, self.theme.heat_high));
+                let is_peer_cell = !is_cursor_cell && peer_cells.contains(&addr.to_string());
+                if is_cursor_cell {
+                    stdout.execute(SetForegroundColor(self.theme.cursor_fg))?;
+                    stdout.execute(style::SetBackgroundColor(self.theme.cursor_bg))?;
+                } else if is_match_cell {
+                    stdout.execute(SetForegroundColor(self.theme.match_fg))?;
+                    stdout.execute(style::SetBackgroundColor(self.theme.match_bg))?;
+                } else if is_peer_cell {
+                    stdout.execute(SetForegroundColor(self.theme.cursor_fg))?;
+                    stdout.execute(style::SetBackgroundColor(self.theme.peer_bg))?;
+                } else {
+                    // The cell's own colors; the cursor, search and link highlights take precedence over them
+                    if let Some(fg) = fg {
+                        stdout.execute(SetForegroundColor(fg))?;
+                    }
+                    if let Some(bg) = link_bg.or(heat_bg).or(bg).or(stripe) {
+                        stdout.execute(style::SetBackgroundColor(bg))?;
+                    }
+                }
+                // Text styles apply under the highlights too
+                if bold {
+                    stdout.execute(style::SetAttribute(style::Attribute::Bold))?;
+                }
+                if italic {
+                    stdout.execute(style::SetAttribute(style::Attribute::Italic))?;
+                }
+                let styled = is_cursor_cell || is_match_cell || is_peer_cell || fg.is_some() || bg.is_some() || link_bg.is_some() || heat_bg.is_some() || stripe.is_some();
 
-            let _cell_content = if let Some(cell) = self.get_cell(&addr) {
-                cell.display_value.clone()
-            } else {
-                "0".to_string()
-            };
+                let _cell_content = if let Some(cell) = self.get_cell(&addr) {
+                    cell.display_value.clone()
+                } else {
+                    "0".to_string()
+                };
 
-            let _available_width = col_widths[col_idx];
-            // if cell_content.len() > available_width {
-            //     cell_content = format!("{}..", &cell_content[0..available_width.saturating_sub(2)]);
-            // }
+                let _available_width = col_widths[col_idx];
+                // if cell_content.len() > available_width {
+                //     cell_content = format!("{}..", &cell_content[0..available_width.saturating_sub(2)]);
+                // }
 
-            // Draw or skip content based on flicker
-            if let Some(effect) = flicker_effect {
-                // Extra chaos: highlight 💥 in red
-                if effect == "💥" {
-                    stdout.execute(SetForegroundColor(Color::Red))?;
-                    stdout.execute(style::SetBackgroundColor(Color::Black))?;
+                // Draw or skip content based on flicker
+                if let Some(effect) = flicker_effect {
+                    // Extra chaos: highlight 💥 in red
+                    if effect == "💥" {
+                        stdout.execute(SetForegroundColor(Color::Red))?;
+                        stdout.execute(style::SetBackgroundColor(Color::Black))?;
+                    }
+                    write!(stdout, "{}{}", pad, align_to_width(effect, col_widths[col_idx], &Alignment::Center))?;
+                    stdout.execute(SetForegroundColor(Color::Reset))?;
+                    stdout.execute(style::SetBackgroundColor(Color::Reset))?;
+                } else {
+                    write!(stdout, "{}{}", pad, align_to_width(&cell_lines[line], col_widths[col_idx], &Alignment::Center))?;
                 }
-                write!(stdout, " {:^width$}", effect, width = col_widths[col_idx])?;
-                stdout.execute(SetForegroundColor(Color::Reset))?;
-                stdout.execute(style::SetBackgroundColor(Color::Reset))?;
-            } else {
-                write!(stdout, " {:^width$}", self.format_cell_value(&addr), width = col_widths[col_idx])?;
-            }
             
             
 
-            // Reset styles
-            if is_cursor_cell {
+                // Reset styles
+                if styled {
+                    stdout.execute(SetForegroundColor(Color::Reset))?;
+                    stdout.execute(style::SetBackgroundColor(Color::Reset))?;
+                }
+                if bold || italic {
+                    stdout.execute(style::SetAttribute(style::Attribute::NormalIntensity))?;
+                    stdout.execute(style::SetAttribute(style::Attribute::NoItalic))?;
+                }
+
+                // if flicker_dim {
+                //     stdout.execute(SetForegroundColor(Color::Reset))?;
+                // }
+            }
+            if self.grid_lines {
+                stdout.execute(SetForegroundColor(self.theme.headers))?;
+                write!(stdout, "│")?;
                 stdout.execute(SetForegroundColor(Color::Reset))?;
-                stdout.execute(style::SetBackgroundColor(Color::Reset))?;
             }
 
-            // if flicker_dim {
-            //     stdout.execute(SetForegroundColor(Color::Reset))?;
-            // }
+            write!(stdout, "\r\n")?;
         }
-
-        write!(stdout, "\r\n")?;
+    }
+    if self.grid_lines && !visible_rows.is_empty() {
+        stdout.execute(SetForegroundColor(self.theme.headers))?;
+        write!(stdout, "{}\r\n", self.grid_rule(&col_widths, '─', ['└', '┴', '╨', '┘']))?;
+        stdout.execute(SetForegroundColor(Color::Reset))?;
     }
 
     writeln!(stdout)?;
@@ -2473,18 +9741,72 @@ fn draw(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
         };
         let lock_status = if cell.is_locked { "Locked" } else { "Unlocked" };
         write!(stdout, "{} : {} | {} | {} ",
-            self.cursor.to_string(),
+            self.cursor,
             cell.display_value,
             formula_text,
             lock_status
         )?;
     }
 
+    // Tab strip: every sheet of the workbook, the active one highlighted
+    write!(stdout, "\r\n")?;
+    for (i, sheet) in self.sheets.iter().enumerate() {
+        if i == self.active_sheet {
+            stdout.execute(style::SetAttribute(style::Attribute::Reverse))?;
+            write!(stdout, " {} ", sheet.name)?;
+            stdout.execute(style::SetAttribute(style::Attribute::NoReverse))?;
+        } else {
+            write!(stdout, " {} ", sheet.name)?;
+        }
+        write!(stdout, "|")?;
+    }
+
+    // Watch panel: live values of pinned cells, independent of the scroll position
+    if !self.watch_list.is_empty() {
+        write!(stdout, "\r\n")?;
+        stdout.execute(SetForegroundColor(Color::Yellow))?;
+        write!(stdout, "WATCH")?;
+        for addr_str in &self.watch_list {
+            let value = &self.data.get(addr_str).unwrap_or(&self.blank_cell).display_value;
+            write!(stdout, " | {} = {}", addr_str, value)?;
+        }
+        stdout.execute(SetForegroundColor(Color::Reset))?;
+    }
+
     let (cols, rows) = terminal::size()?;
-    let status_message = &self.status_message;
-    if !status_message.is_empty() {
-        stdout.execute(MoveTo(cols.saturating_sub(status_message.len() as u16), rows.saturating_sub(1)))?;
-        write!(stdout, "{}", status_message)?;
+    let mut overlay = Vec::new();
+    let stdout = &mut overlay;
+    if let Some((kind, start, end)) = &self.chart {
+        self.draw_chart(stdout, *kind, start, end)?;
+    }
+    if let Some(notice) = &self.alert_notice {
+        stdout.execute(MoveTo(0, rows.saturating_sub(3)))?;
+        stdout.execute(SetForegroundColor(Color::White))?;
+        stdout.execute(style::SetBackgroundColor(Color::Red))?;
+        write!(stdout, "{}", notice)?;
+        stdout.execute(SetForegroundColor(Color::Reset))?;
+        stdout.execute(style::SetBackgroundColor(Color::Reset))?;
+    }
+    // Demo overlay: recent keys and the last command in the top-right corner
+    if self.demo_mode {
+        let keys_line = self.demo_keys.iter().cloned().collect::<Vec<_>>().join(" ");
+        let command_line = self.demo_last_command.as_ref().map(|c| format!(":{}", c)).unwrap_or_default();
+        stdout.execute(SetForegroundColor(Color::Black))?;
+        stdout.execute(style::SetBackgroundColor(Color::Green))?;
+        for (i, line) in [keys_line, command_line].iter().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let text = format!(" {} ", line);
+            stdout.execute(MoveTo(cols.saturating_sub(display_width(&text) as u16), i as u16))?;
+            write!(stdout, "{}", text)?;
+        }
+        stdout.execute(SetForegroundColor(Color::Reset))?;
+        stdout.execute(style::SetBackgroundColor(Color::Reset))?;
+    }
+    if self.ring_bell {
+        write!(out, "\x07")?;
+        self.ring_bell = false;
     }
 
     if !self.command_buffer.is_empty() {
@@ -2493,88 +9815,355 @@ fn draw(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
         write!(stdout, "{}", command_buffer)?;
     }
 
+    let mut status = Vec::new();
+    self.draw_status_line(&mut status)?;
+    let lines = lines.split(|&byte| byte == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line).to_vec()).collect();
+    self.present_frame(out, DrawnFrame { size: (cols, rows), lines, overlay, status })
+}
+/// Writes a frame built by `draw` to the terminal, leaving out what is already on the screen.
+///
+/// A line is only rewritten if it differs from the same line of the last frame; it is written over
+/// the old one and cleared to its end, so nothing is erased beforehand. Every line is rewritten when
+/// there is no last frame, the terminal was resized or the overlay changed, since the lines under a
+/// closed chart or command buffer must come back. The overlay is written again over rewritten lines.
+///
+/// # Arguments
+///
+/// * `stdout` - The output stream for writing terminal content.
+/// * `frame` - The new frame, kept as the last frame afterwards.
+///
+/// # Returns
+///
+/// Returns an `io::Result<()>` indicating whether the frame was written successfully.
+fn present_frame(&mut self, stdout: &mut io::Stdout, frame: DrawnFrame) -> io::Result<()> {
+    let last = self.drawn.take().filter(|last| last.size == frame.size);
+    let repaint = last.as_ref().is_none_or(|last| last.overlay != frame.overlay);
+    let mut rewritten = false;
+    let last_lines = last.as_ref().filter(|_| !repaint).map(|last| last.lines.as_slice());
+    for row in changed_lines(last_lines, &frame.lines, frame.size.1) {
+        let line = frame.lines.get(row);
+        stdout.queue(MoveTo(0, row as u16))?;
+        if let Some(line) = line {
+            stdout.write_all(line)?;
+        }
+        stdout.queue(style::ResetColor)?;
+        stdout.queue(style::SetAttribute(style::Attribute::Reset))?;
+        stdout.queue(Clear(ClearType::UntilNewLine))?;
+        rewritten = true;
+    }
+    if repaint || (rewritten && !frame.overlay.is_empty()) {
+        stdout.write_all(&frame.overlay)?;
+    }
+    if last.is_none_or(|last| last.status != frame.status) {
+        stdout.write_all(&frame.status)?;
+    }
     stdout.flush()?;
+    self.drawn = Some(frame);
+    Ok(())
+}
+}
+
+/// Returns whether a changed screen is drawn now: unless keys are already waiting and the last frame
+/// is less than `MIN_FRAME_MS` old, in which case the keys are handled first and drawn together.
+///
+/// # Arguments
+/// * `key_waiting` - Whether a key press is waiting to be read.
+/// * `since_last_frame` - How long ago the last frame was drawn.
+pub fn frame_due(key_waiting: bool, since_last_frame: Duration) -> bool {
+    !key_waiting || since_last_frame >= Duration::from_millis(MIN_FRAME_MS)
+}
+
+/// Runs the interactive event loop until the user quits.
+///
+/// Each iteration draws the sheet (or only the input line while typing) if something changed,
+/// replays any queued demo keys, and waits up to one tick for input so time-based effects keep
+/// running while idle. Keys that are already waiting are handled before the next frame, so a held
+/// key or pasted text is drawn about every `MIN_FRAME_MS` rather than once per key.
+///
+/// # Arguments
+/// * `sheet` - The spreadsheet being edited.
+/// * `stdout` - The terminal output stream.
+///
+/// # Returns
+/// Returns `Ok(())` when the user quits, or the first terminal I/O error.
+fn run_event_loop(sheet: &mut Spreadsheet, stdout: &mut io::Stdout) -> Result<()> {
+    let mut redraw = true;
+    loop {
+        // Draw the current state
+        if sheet.haunted {
+            if let Some(start_time) = sheet.haunted_start {
+                if !sheet.jump_scare_triggered && start_time.elapsed() > Duration::from_secs(15) {
+                    trigger_jump_scare(&sheet.scream_sound);
+                    sheet.drawn = None;
+                    sheet.jump_scare_triggered = true;
+                    std::thread::sleep(std::time::Duration::from_secs(2));
+                    sheet.jump_scare_triggered = true;
+                }
+            }
+        }
+        
+        // Skip the grid while typing unless an effect needs every frame
+        if redraw && frame_due(event::poll(Duration::ZERO)?, sheet.frame_drawn.elapsed()) {
+            if sheet.needs_full_redraw || sheet.haunted || sheet.demo_mode {
+                sheet.draw(stdout)?;
+            } else {
+                sheet.draw_input_line(stdout)?;
+            }
+            sheet.frame_drawn = Instant::now();
+            redraw = false;
+        }
+
+        // Replay queued demo keys at human speed; any real key press stops the replay
+        if let Some(key) = sheet.demo_queue.pop_front() {
+            if event::poll(sheet.demo_delay)? {
+                let _ = event::read()?;
+                sheet.demo_queue.clear();
+                sheet.status_message = "DEMO REPLAY STOPPED".to_string();
+            } else if !sheet.handle_key_event(key) {
+                break;
+            }
+            redraw = true;
+            continue;
+        }
+
+        // Handle input, waking up every tick so time-based effects run while idle; a pending
+        // recalculation runs a batch whenever no key is waiting
+        let recalculating = !sheet.recalc_queue.is_empty();
+        if event::poll(sheet.poll_timeout())? {
+            match event::read()? {
+                Event::Key(key_event) => {
+                    if key_event.code == KeyCode::Char('z') && key_event.modifiers.contains(KeyModifiers::CONTROL) {
+                        suspend_to_shell(stdout)?;
+                        sheet.redraw();
+                        redraw = true;
+                        continue;
+                    }
+                    if !sheet.handle_key_event(key_event.code) {
+                        break; // Exit if handler returns false
+                    }
+                    redraw = true;
+                }
+                Event::Resize(cols, rows) => {
+                    sheet.resize(cols, rows);
+                    redraw = true;
+                }
+                _ => {}
+            }
+        } else if recalculating {
+            let total = sheet.recalc_total;
+            sheet.status_message = if sheet.recalculate_batch(RECALC_BATCH_CELLS) {
+                format!("RECALCULATING {}/{}", total - sheet.recalc_queue.len(), total)
+            } else {
+                format!("RECALCULATED {} CELLS", total)
+            };
+            sheet.needs_full_redraw = true;
+            redraw = true;
+        } else {
+            // Idle tick: only animated or live content needs a new frame
+            let animated = sheet.haunted || !sheet.watch_list.is_empty();
+            if !redraw {
+                sheet.needs_full_redraw = animated;
+            }
+            redraw |= animated;
+            redraw |= sheet.autosave_if_due();
+            if sheet.sync_session() {
+                sheet.needs_full_redraw = true;
+                redraw = true;
+            }
+            #[cfg(feature = "websocket")]
+            if sheet.sync_dashboard() {
+                sheet.needs_full_redraw = true;
+                redraw = true;
+            }
+        }
+    }
+
 
     Ok(())
 }
+
+/// Installs a panic hook that puts the terminal back into a usable state (raw mode off, cursor
+/// shown) before the panic message is printed, so the message is readable and the shell is not
+/// left in raw mode.
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let mut stdout = stdout();
+        let _ = terminal::disable_raw_mode();
+        let _ = stdout.execute(SetForegroundColor(Color::Reset));
+        let _ = stdout.execute(Show);
+        let _ = stdout.execute(terminal::Clear(ClearType::All));
+        let _ = stdout.execute(MoveTo(0, 0));
+        default_hook(info);
+    }));
+}
+
+/// Command-line interface of the spreadsheet.
+///
+/// `spreadsheet [FILE] [--rows N --cols M] [--vim] [--readonly] [--script SCRIPT] [--diff OLD NEW]
+/// [--merge BASE OURS THEIRS]`
+#[derive(Parser, Debug)]
+#[command(name = "spreadsheet", about = "A Vim-style spreadsheet for the terminal", long_about = None)]
+struct Cli {
+    /// Spreadsheet to open (a file written by `saveas_json` or `saveas_text`); loaded immediately if it exists
+    file: Option<PathBuf>,
+    /// Number of rows of a new sheet, 1 to 999 [default: 10, or `rows` in ~/.hackersheetrc]
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=MAX_ROWS as u64))]
+    rows: Option<usize>,
+    /// Number of columns of a new sheet, 1 to 18278 [default: 10, or `cols` in ~/.hackersheetrc]
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=MAX_COLS as u64))]
+    cols: Option<usize>,
+    /// Start the Vim-style terminal editor (the default unless --script is given)
+    #[arg(long)]
+    vim: bool,
+    /// Refuse all edits; the sheet can still be viewed, searched and exported
+    #[arg(long)]
+    readonly: bool,
+    /// Run the `:` commands and `A1=5` assignments in SCRIPT (one per line, `-` for stdin) without
+    /// the terminal UI, then exit with status 1 if any of them failed
+    #[arg(long, alias = "batch", value_name = "SCRIPT", conflicts_with = "vim")]
+    script: Option<PathBuf>,
+    /// Print the cells that differ between two saved sheets, then exit with status 1 if any differ
+    #[arg(long, num_args = 2, value_names = ["OLD", "NEW"], conflicts_with_all = ["vim", "script", "merge"])]
+    diff: Option<Vec<PathBuf>>,
+    /// Merge the changes THEIRS made since BASE into OURS (written back to OURS, like a git merge
+    /// driver), then exit with status 1 if a cell was changed on both sides; those keep OURS and are printed
+    #[arg(long, num_args = 3, value_names = ["BASE", "OURS", "THEIRS"], conflicts_with_all = ["vim", "script"])]
+    merge: Option<Vec<PathBuf>>,
 }
 
 /// Main function to initialize and run the extended spreadsheet application.
 ///
-/// This function sets up the terminal in raw mode and creates a spreadsheet with a configurable
-/// size (determined by command-line arguments). It runs a main event loop where the current state
-/// of the spreadsheet is drawn and user input is handled to manipulate the spreadsheet. The loop
-/// continues until the user decides to quit. Once the program ends, the terminal is cleaned up, 
+/// This function parses the command line, creates a spreadsheet of the requested size (or loads the
+/// given file), and then either runs a batch script or sets up the terminal in raw mode and runs the
+/// interactive event loop until the user quits. Once the program ends, the terminal is cleaned up,
 /// and the cursor is restored.
 ///
 /// # Command-Line Arguments
-/// 
-/// The program expects two command-line arguments:
-/// - `<rows>`: The number of rows in the spreadsheet. Defaults to `10` if not provided.
-/// - `<cols>`: The number of columns in the spreadsheet. Defaults to `10` if not provided.
-/// 
-/// If the number of arguments provided is incorrect, the program will display an error message and
-/// default to a 10x10 grid.
+///
+/// `spreadsheet [FILE] [--rows N --cols M] [--vim] [--readonly] [--script SCRIPT] [--diff OLD NEW]
+/// [--merge BASE OURS THEIRS]`
+/// - `FILE`: A saved sheet to open. If it does not exist yet, an empty sheet is created.
+/// - `--rows`, `--cols`: The size of a new sheet, at most 999 rows and 18278 columns (larger values are
+///   refused with a usage error). Both default to `10`, or to `rows` and `cols` in the user config.
+/// - `--vim`: Start the interactive Vim-style editor (default).
+/// - `--readonly`: Open the sheet without allowing edits.
+/// - `--script SCRIPT` (or `--batch`): Execute the commands in `SCRIPT` (`-` reads them from stdin) and print
+///   their status instead of starting the editor. The exit status is 1 if any command failed.
+/// - `--diff OLD NEW`: Print one line per cell that differs between two saved sheets (see `CellDiff::describe`)
+///   instead of starting the editor. Like `diff`, the exit status is 0 if none differ, 1 if some do and 2 if a
+///   file cannot be read.
+/// - `--merge BASE OURS THEIRS`: Merge the cells THEIRS changed since BASE into OURS (see `merge_sheets`) and
+///   print the conflicts. The exit status is 1 if there were any, and 2 if a file cannot be read or written.
+///
+/// # User Config
+/// Defaults for the grid size, new cells, theme, undo limit, autosave and haunt sounds are read from
+/// `~/.hackersheetrc` (see `Config`). A broken config is reported and the built-in defaults are used.
+/// Autosaves go to `FILE.autosave` next to the opened file, or to `autosave.json` in the config directory.
 ///
 /// # Behavior
 /// - The terminal is cleared, raw mode is enabled, and the cursor is hidden to allow custom rendering.
 /// - The event loop waits for key events to handle user input (e.g., navigating the spreadsheet or editing cells).
 /// - The loop continues until the user exits (via the `handle_key_event` method returning `false`).
+/// - `Ctrl-Z` suspends the editor to the shell; the terminal is restored and fully redrawn on resume.
+/// - If the program panics, the terminal is restored, the panic message is printed, and the sheet is
+///   written to `recovery.json` in the config directory (or the current directory) before exiting.
 /// - Upon exit, the terminal is restored, the cursor is shown again, and the screen is cleared.
 ///
 /// # Terminal Settings
 /// - Raw mode is enabled with `terminal::enable_raw_mode()`, which allows direct control over input and output.
 /// - The cursor is hidden initially and shown again upon exit to maintain the custom UI.
 pub fn main() -> Result<()> {
-    // Setup terminal
+    let cli = Cli::parse();
+    if let Some([old, new]) = cli.diff.as_deref() {
+        match diff_files(old, new) {
+            Ok(diffs) => {
+                for diff in &diffs {
+                    println!("{}", diff.describe());
+                }
+                std::process::exit(if diffs.is_empty() { 0 } else { 1 });
+            }
+            Err(e) => {
+                eprintln!("Could not compare the sheets: {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Some([base, ours, theirs]) = cli.merge.as_deref() {
+        match merge_files(base, ours, theirs, ours) {
+            Ok(conflicts) => {
+                for conflict in &conflicts {
+                    println!("{}", conflict.describe());
+                }
+                std::process::exit(if conflicts.is_empty() { 0 } else { 1 });
+            }
+            Err(e) => {
+                eprintln!("Could not merge the sheets: {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+    let (config, config_error) = match Config::load() {
+        Ok(config) => (config, None),
+        Err(e) => (Config::default(), Some(e)),
+    };
+    let width = config.width.unwrap_or(5);
+    let alignment = config.alignment.as_deref().and_then(parse_alignment).unwrap_or(Alignment::Center);
+    let _ = CELL_DEFAULTS.set((width, alignment));
+    let rows = cli.rows.or(config.rows).unwrap_or(10);
+    let cols = cli.cols.or(config.cols).unwrap_or(10);
 
-    let args: Vec<String> = env::args().collect();
-    let (rows, cols) = if args.len() == 3 {
-        let r = args[1].parse::<usize>().unwrap_or(10);
-        let c = args[2].parse::<usize>().unwrap_or(10);
-        (r, c)
-    } else {
-        eprintln!("Usage: {} <rows> <cols>. Defaulting to 10x10.", args[0]);
-        (10, 10)
+    // Create spreadsheet (10x10 grid unless sized on the command line)
+    let mut sheet = Spreadsheet::new(rows, cols);
+    // Long recalculations (e.g. of the file opened below) run between key presses
+    sheet.set_chunked_recalc(true);
+    if let Some(file) = &cli.file {
+        if file.exists() {
+            if let Err(e) = sheet.load_json(file) {
+                eprintln!("Could not open {}: {}", file.display(), e);
+                std::process::exit(1);
+            }
+            sheet.status_message = format!("OPENED {}", file.display());
+        } else {
+            sheet.status_message = format!("NEW FILE {}", file.display());
+        }
+        sheet.set_current_file(file);
+    }
+    sheet.readonly = cli.readonly;
+    let autosave_path = match &cli.file {
+        Some(file) => {
+            let mut path = file.as_os_str().to_owned();
+            path.push(".autosave");
+            PathBuf::from(path)
+        }
+        None => config_dir().unwrap_or_else(|| PathBuf::from(".")).join("autosave.json"),
     };
+    sheet.apply_config(&config, autosave_path);
+    if let Some(e) = config_error {
+        if cli.script.is_some() {
+            eprintln!("Ignoring config file {}", e);
+        } else {
+            sheet.status_message = format!("CONFIG ERROR: {}", e);
+        }
+    }
 
-    unsafe {
-        R = rows;
-        C = cols;
+    if let Some(script) = &cli.script {
+        if sheet.run_batch(script)? > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
     }
+
     let mut stdout = stdout();
     terminal::enable_raw_mode()?;
     stdout.execute(terminal::Clear(ClearType::All))?;
     stdout.execute(Hide)?; // Hide cursor for custom rendering
+    sheet.load_command_history();
+    sheet.show_progress = true;
 
-    // Create spreadsheet (10x10 grid)
-    let mut sheet = Spreadsheet::new(rows, cols);
-
-    // Main event loop
-    loop {
-        // Draw the current state
-        if sheet.haunted {
-            if let Some(start_time) = sheet.haunted_start {
-                if !sheet.jump_scare_triggered && start_time.elapsed() > Duration::from_secs(15) {
-                    trigger_jump_scare();
-                    sheet.jump_scare_triggered = true;
-                    std::thread::sleep(std::time::Duration::from_secs(2));
-                    sheet.jump_scare_triggered = true;
-                }
-            }
-        }
-        
-        sheet.draw(&mut stdout)?;
-
-        // Handle input
-            // if event::poll(std::time::Duration::from_millis(100))? {
-                if let Event::Key(key_event) = event::read()? {
-                    if !sheet.handle_key_event(key_event.code) {
-                        break; // Exit if handler returns false
-                    }
-                // }
-            }
-    }
+    // Main event loop; a panic is caught so the sheet can be saved to a recovery file
+    install_panic_hook();
+    let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run_event_loop(&mut sheet, &mut stdout)));
 
     // Clean up
     terminal::disable_raw_mode()?;
@@ -2582,5 +10171,14 @@ pub fn main() -> Result<()> {
     stdout.execute(terminal::Clear(ClearType::All))?;
     stdout.execute(MoveTo(0, 0))?;
 
-    Ok(())
+    match outcome {
+        Ok(result) => result,
+        Err(_) => {
+            match sheet.save_recovery(&config_dir().unwrap_or_else(|| PathBuf::from("."))) {
+                Ok(recovery) => eprintln!("The spreadsheet crashed. Your data was saved to {}", recovery.display()),
+                Err(e) => eprintln!("The spreadsheet crashed and the recovery file could not be written: {}", e),
+            }
+            std::process::exit(101);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,271 @@
+//! The cells of the extended sheet and their addresses. A sheet only stores the cells that differ
+//! from a blank one; every other address inside the sheet reads as a shared blank cell.
+
+use super::*;
+
+/// Represents a single cell in the spreadsheet.
+///
+/// The `Cell` struct holds both the raw input value (as entered by the user) and the 
+/// value to be displayed in the spreadsheet. It also supports formulas, text alignment, 
+/// and cell dimensions (width and height). The cell can be locked to prevent editing.
+///
+/// # Fields:
+/// - `raw_value`: The raw input string (e.g., numbers, text, or formulas).
+/// - `display_value`: The value that will be shown to the user, possibly altered by formulas.
+/// - `formula`: An optional string containing a formula that is applied to compute the value.
+/// - `is_locked`: A boolean indicating whether the cell is locked and cannot be edited.
+/// - `alignment`: The alignment of the text inside the cell (e.g., left, right, or center).
+/// - `width`: The width of the cell (in characters).
+/// - `height`: The height of the cell (in rows).
+/// - `format`: An optional display format, e.g. `date:%d.%m.%Y` to show a date serial number as a date.
+/// - `fg`, `bg`: Optional text and background color names (see `parse_color`), set with `color`.
+/// - `bold`, `italic`: Text styles, toggled with `bold` and `italic`.
+/// - `wrap`: Whether a long value wraps onto the lines of the cell's height instead of being cut off,
+///   toggled with `wrap`.
+/// - `error`: Why the cell's formula failed, if it did; the cell then shows `ERR`.
+/// - `choices`: The only values the cell accepts, if it has a list validation; insert mode offers them
+///   as a picker.
+/// # Methods:
+/// - `new`: Creates a new `Cell` with default values.
+/// - `display`: Returns the content of the cell formatted according to its alignment and width.
+/// - `default`: Creates a new, default `Cell` with empty values for `raw_value` and `display_value`.
+/// - `formatted_value`: Returns the display value with the cell's display format applied, or `ERR`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cell {
+    pub raw_value: String,       // Raw input
+    pub display_value: String,   // Value as displayed
+    pub formula: Option<String>, // Formula if any
+    pub is_locked: bool,         // Whether cell is locked
+    pub alignment: Alignment,    // Text alignment
+    pub width: usize,            // Cell width
+    pub height: usize,           // Cell height
+    #[serde(default)]
+    pub format: Option<String>,  // Display format, e.g. "date:%d.%m.%Y"
+    #[serde(default)]
+    pub fg: Option<String>,      // Text color, e.g. "red"
+    #[serde(default)]
+    pub bg: Option<String>,      // Background color
+    #[serde(default)]
+    pub bold: bool,
+    #[serde(default)]
+    pub italic: bool,
+    #[serde(default)]
+    pub wrap: bool,
+    #[serde(default)]
+    pub error: Option<String>,   // Why the formula failed, if it did
+    #[serde(default)]
+    pub choices: Option<Vec<String>>, // The only values allowed, set with `validate ... list`
+}
+
+impl Cell {
+    /// Returns whether the cell is indistinguishable from a freshly created one.
+    pub fn is_blank(&self) -> bool {
+        let empty = Cell::new();
+        self.raw_value == empty.raw_value && self.display_value == empty.display_value && self.formula.is_none()
+            && !self.is_locked && self.alignment == empty.alignment && self.width == empty.width
+            && self.height == empty.height && self.format.is_none()
+            && self.fg.is_none() && self.bg.is_none() && !self.bold && !self.italic && !self.wrap
+            && self.error.is_none() && self.choices.is_none()
+    }
+
+    pub(super) fn new() -> Self {
+        Cell {
+            raw_value: String::from("0"),
+            display_value: String::from("0"),
+            formula: None,
+            is_locked: false,
+            alignment: CELL_DEFAULTS.get().map_or(Alignment::Center, |(_, alignment)| alignment.clone()),
+            width: CELL_DEFAULTS.get().map_or(5, |(width, _)| *width),  // Default width
+            height: 1, // Default height
+            format: None,
+            fg: None,
+            bg: None,
+            bold: false,
+            italic: false,
+            wrap: false,
+            error: None,
+            choices: None,
+        }
+    }
+
+    pub(super) fn default() -> Self {
+        Cell {
+            raw_value: String::new(),
+            display_value: String::new(),
+            formula: None,
+            alignment: Alignment::Center,
+            is_locked: false,
+            width: 5, // or whatever default width you use
+            height: 1,
+            format: None,
+            fg: None,
+            bg: None,
+            bold: false,
+            italic: false,
+            wrap: false,
+            error: None,
+            choices: None,
+        }
+    }
+
+    /// Returns the value as it should be shown to the user: date-formatted cells holding a serial
+    /// number are rendered with their pattern, everything else is the plain display value.
+    pub fn formatted_value(&self) -> String {
+        if self.error.is_some() {
+            return "ERR".to_string();
+        }
+        if let Some(date) = self.format.as_deref().and_then(|f| f.strip_prefix("date:")).and_then(|pattern| {
+            self.display_value.parse::<f64>().ok().and_then(serial_to_date).map(|date| date.format(pattern).to_string())
+        }) {
+            return date;
+        }
+        self.display_value.clone()
+    }
+
+    /// Returns whether two cells hold the same input and styles, whatever values they computed (a formula
+    /// cell keeps its value in `raw_value` too).
+    pub(super) fn same_content(&self, other: &Cell) -> bool {
+        let content = |cell: &Cell| Cell {
+            raw_value: if cell.formula.is_some() { String::new() } else { cell.raw_value.clone() },
+            display_value: String::new(),
+            error: None,
+            ..cell.clone()
+        };
+        content(self) == content(other)
+    }
+
+    /// Returns what was entered into the cell: its formula with a leading `=`, or its value.
+    pub(super) fn input(&self) -> String {
+        self.formula.as_ref().map_or_else(|| self.raw_value.clone(), |f| format!("={}", f))
+    }
+
+    /// Returns the date pattern of the cell, if it has a date display format.
+    pub(super) fn date_pattern(&self) -> Option<&str> {
+        self.format.as_deref().and_then(|f| f.strip_prefix("date:"))
+    }
+}
+
+/// Represents a cell's address in the spreadsheet using column and row indices.
+///
+/// The `CellAddress` struct holds the `col` (column index) and `row` (row index) for a specific
+/// cell, and provides methods for converting between string representations of cell addresses
+/// (e.g., "A1", "B2") and the internal column/row index format.
+///
+/// # Methods:
+/// - `new`: Creates a new `CellAddress` from a column and row index.
+/// - `from_str`: Parses a string (e.g., "A1", "B2", "AA10") into a `CellAddress` if valid.
+/// - `col_to_letters`: Converts a column index to the corresponding Excel-style column label (e.g., 0 -> "A", 1 -> "B", 26 -> "AA").
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CellAddress {
+    pub col: usize,
+    pub row: usize,
+}
+
+impl CellAddress {
+    /// Creates a new `CellAddress` from a column and row index.
+    ///
+    /// # Arguments:
+    /// - `col`: The zero-based column index (0 for 'A').
+    /// - `row`: The zero-based row index (0 for row 1).
+    ///
+    /// # Returns:
+    /// A `CellAddress` struct representing the cell at the specified position.
+    pub fn new(col: usize, row: usize) -> Self {
+        CellAddress { col, row }
+    }
+    /// Parses a string (e.g., "A1", "B2", "AA10") into a `CellAddress`.
+    ///
+    /// The string must be in the format of one to three letters (column, up to "ZZZ") followed by a
+    /// number (row), such as "A1", "B2" or "AB12". The column is case-insensitive.
+    ///
+    /// # Arguments:
+    /// - `addr`: A string representing the cell address, e.g., "A1", "B2".
+    ///
+    /// # Returns:
+    /// An `Option<CellAddress>`, which is `Some(CellAddress)` if the string is valid,
+    /// or `None` if the string is invalid.
+    pub(super) fn from_str(addr: &str) -> Option<Self> {
+        let letters = addr.chars().take_while(|c| c.is_ascii_alphabetic()).count();
+        if !(1..=MAX_COL_LETTERS).contains(&letters) {
+            return None;
+        }
+        let (col_str, row_str) = addr.split_at(letters);
+        if row_str.is_empty() || !row_str.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        // Columns are bijective base 26: A = 1, Z = 26, AA = 27
+        let col = col_str.bytes().fold(0, |col, b| col * 26 + (b.to_ascii_uppercase() - b'A') as usize + 1);
+        match row_str.parse::<usize>() {
+            Ok(row) if row > 0 => Some(CellAddress::new(col - 1, row - 1)),
+            _ => None,
+        }
+    }
+    /// Converts a column index to an Excel-style column label (e.g., 0 -> "A", 1 -> "B", 26 -> "AA").
+    ///
+    /// # Arguments:
+    /// - `col`: The zero-based column index.
+    ///
+    /// # Returns:
+    /// A string representing the Excel-style column label.
+    pub fn col_to_letters(mut col: usize) -> String {
+        let mut label = String::new();
+        col += 1; // shift to 1-based
+        while col > 0 {
+            col -= 1;
+            label.insert(0, (b'A' + (col % 26) as u8) as char);
+            col /= 26;
+        }
+        label
+    }
+}
+
+/// Shows the address as a label, e.g. "A1" or "B2", so `to_string()` gives it too.
+impl std::fmt::Display for CellAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", Self::col_to_letters(self.col), self.row + 1)
+    }
+}
+
+/// Lets library users parse addresses with `"B7".parse::<CellAddress>()`.
+impl std::str::FromStr for CellAddress {
+    type Err = String;
+
+    fn from_str(addr: &str) -> std::result::Result<Self, String> {
+        CellAddress::from_str(addr).ok_or_else(|| format!("INVALID CELL ADDRESS {}", addr))
+    }
+}
+
+impl Spreadsheet {
+    /// Retrieves a reference to a cell at the given address.
+    ///
+    /// This method looks up a cell in the spreadsheet based on the provided address.
+    ///
+    /// # Arguments:
+    /// - `addr`: A reference to the `CellAddress` of the cell to retrieve.
+    ///
+    /// # Returns:
+    /// An `Option` containing a reference to the `Cell` if it exists, or `None` if the address is invalid.
+    /// Cells that were never edited all share one blank cell.
+    pub fn get_cell(&self, addr: &CellAddress) -> Option<&Cell> {
+        self.data.get(&addr.to_string())
+            .or_else(|| (addr.row < self.max_rows && addr.col < self.max_cols).then_some(&self.blank_cell))
+    }
+
+    /// Retrieves a mutable reference to a cell at the given address.
+    ///
+    /// This method allows for modifying the cell at the specified address.
+    ///
+    /// # Arguments:
+    /// - `addr`: A reference to the `CellAddress` of the cell to retrieve.
+    ///
+    /// # Returns:
+    /// An `Option` containing a mutable reference to the `Cell` if it exists, or `None` if the address is invalid.
+    /// A blank cell is stored from here on, until `commit_action` finds it blank again.
+    pub(super) fn get_cell_mut(&mut self, addr: &CellAddress) -> Option<&mut Cell> {
+        self.get_cell(addr)?;
+        let addr_str = addr.to_string();
+        self.record_undo(&addr_str);
+        Some(self.data.entry(addr_str).or_insert_with(Cell::new))
+    }
+}
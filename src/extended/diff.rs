@@ -0,0 +1,171 @@
+//! Cell-by-cell diffs and three-way merges of saved sheets, used by `--diff` and `--merge`.
+
+use super::*;
+
+/// A cell that differs between two versions of a sheet (see `diff_sheets`).
+///
+/// # Fields:
+/// - `cell`: The address of the cell, e.g. `"B3"`.
+/// - `old`: The cell in the first version, `None` if it is empty there.
+/// - `new`: The cell in the second version, `None` if it is empty there.
+#[derive(Clone, Debug)]
+pub struct CellDiff {
+    pub cell: String,
+    pub old: Option<Cell>,
+    pub new: Option<Cell>,
+}
+
+impl CellDiff {
+    /// Returns one line describing the difference, like a line of a text diff: `+ B2: =SUM(A1:A3)` for
+    /// a cell filled in, `- C3: hello` for a cell cleared, `~ A1: 5 -> 7` for a changed input and
+    /// `~ D4: style changed` for a cell whose input stayed the same.
+    pub fn describe(&self) -> String {
+        match (&self.old, &self.new) {
+            (None, Some(new)) => format!("+ {}: {}", self.cell, new.input()),
+            (Some(old), None) => format!("- {}: {}", self.cell, old.input()),
+            (Some(old), Some(new)) if old.input() == new.input() => format!("~ {}: style changed", self.cell),
+            (old, new) => format!("~ {}: {} -> {}", self.cell, old.as_ref().map_or_else(String::new, Cell::input), new.as_ref().map_or_else(String::new, Cell::input)),
+        }
+    }
+}
+
+/// A cell both sides of a three-way merge changed in different ways since their common base (see
+/// `merge_sheets`). The merge keeps `ours`.
+///
+/// # Fields:
+/// - `cell`: The address of the cell, e.g. `"B3"`.
+/// - `base`, `ours`, `theirs`: The cell in each version, `None` where it is empty.
+#[derive(Clone, Debug)]
+pub struct MergeConflict {
+    pub cell: String,
+    pub base: Option<Cell>,
+    pub ours: Option<Cell>,
+    pub theirs: Option<Cell>,
+}
+
+impl MergeConflict {
+    /// Returns one line describing the conflict, e.g. `! A1: 5 -> 6 (ours), 5 -> 7 (theirs)`.
+    pub fn describe(&self) -> String {
+        let input = |cell: &Option<Cell>| cell.as_ref().map_or_else(String::new, Cell::input);
+        format!("! {}: {} -> {} (ours), {} -> {} (theirs)", self.cell, input(&self.base), input(&self.ours), input(&self.base), input(&self.theirs))
+    }
+}
+
+/// Returns the non-blank cell at an address of a sheet's cells.
+fn saved_cell<'a>(cells: &'a HashMap<String, Cell>, addr: &str) -> Option<&'a Cell> {
+    cells.get(addr).filter(|cell| !cell.is_blank())
+}
+
+/// Returns whether two versions of a cell have the same input and styles; values are recalculated, so
+/// they are left out. An empty cell is the same as a blank one.
+fn same_saved_cell(a: Option<&Cell>, b: Option<&Cell>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.same_content(b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+/// Returns every address used in any of the given sheets, in row-major order.
+pub(super) fn union_of_addresses(sheets: &[&HashMap<String, Cell>]) -> Vec<String> {
+    let mut addrs: Vec<String> = sheets.iter().flat_map(|cells| cells.keys().cloned()).collect::<HashSet<_>>().into_iter().collect();
+    addrs.sort_by_key(|addr| (CellAddress::from_str(addr).map(|addr| (addr.row, addr.col)), addr.clone()));
+    addrs
+}
+
+/// Compares two versions of a sheet cell by cell.
+///
+/// # Arguments
+/// - `old`, `new`: The cells of each version by address, as saved by `saveas_json`.
+///
+/// # Returns
+/// Every cell whose input or styles differ, in row-major order. Differences in computed values alone
+/// are left out, since they follow from the inputs.
+pub fn diff_sheets(old: &HashMap<String, Cell>, new: &HashMap<String, Cell>) -> Vec<CellDiff> {
+    union_of_addresses(&[old, new]).into_iter().filter_map(|addr| {
+        let (before, after) = (saved_cell(old, &addr), saved_cell(new, &addr));
+        (!same_saved_cell(before, after)).then(|| CellDiff { old: before.cloned(), new: after.cloned(), cell: addr })
+    }).collect()
+}
+
+/// Merges two versions of a sheet that both started from a common base: each cell takes the change
+/// made on either side, or keeps `ours` and is reported as a conflict if both sides changed it in
+/// different ways.
+///
+/// # Arguments
+/// - `base`: The cells of the version both sides started from.
+/// - `ours`, `theirs`: The cells of the two changed versions.
+///
+/// # Returns
+/// The merged cells and the conflicts, in row-major order.
+pub fn merge_sheets(base: &HashMap<String, Cell>, ours: &HashMap<String, Cell>, theirs: &HashMap<String, Cell>)
+    -> (HashMap<String, Cell>, Vec<MergeConflict>) {
+    let mut merged = HashMap::new();
+    let mut conflicts = Vec::new();
+    for addr in union_of_addresses(&[base, ours, theirs]) {
+        let (b, o, t) = (saved_cell(base, &addr), saved_cell(ours, &addr), saved_cell(theirs, &addr));
+        let kept = if same_saved_cell(o, t) || same_saved_cell(b, t) {
+            o
+        } else if same_saved_cell(b, o) {
+            t
+        } else {
+            conflicts.push(MergeConflict { cell: addr.clone(), base: b.cloned(), ours: o.cloned(), theirs: t.cloned() });
+            o
+        };
+        if let Some(cell) = kept {
+            merged.insert(addr, cell.clone());
+        }
+    }
+    (merged, conflicts)
+}
+
+/// Reads a sheet saved with `saveas_json` or `saveas_text` (compressed if its name ends in `.gz` or `.zst`).
+fn read_saved_sheet(path: &Path) -> io::Result<SaveFile> {
+    read_save_file(open_save(path)?).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Compares two sheets saved with `saveas_json` or `saveas_text` cell by cell (see `diff_sheets`).
+///
+/// # Returns
+/// The cells that differ, or an error if either file cannot be read.
+pub fn diff_files(old: &Path, new: &Path) -> io::Result<Vec<CellDiff>> {
+    Ok(diff_sheets(&read_saved_sheet(old)?.cells, &read_saved_sheet(new)?.cells))
+}
+
+/// Merges two sheets saved with `saveas_json` or `saveas_text` that both started from a common base
+/// (see `merge_sheets`) and saves the result, large enough for the cells of both sides, in the format
+/// `out` already has.
+///
+/// # Arguments
+/// - `base`, `ours`, `theirs`: The saved versions.
+/// - `out`: Where the merged sheet is saved; it may be one of the inputs.
+///
+/// # Returns
+/// The conflicts, whose cells were kept as in `ours`, or an error if a file cannot be read or written.
+pub fn merge_files(base: &Path, ours: &Path, theirs: &Path, out: &Path) -> io::Result<Vec<MergeConflict>> {
+    // A text save being merged into stays a text save, keeping its history readable
+    let as_text = is_text_save(out);
+    let (base, ours, theirs) = (read_saved_sheet(base)?, read_saved_sheet(ours)?, read_saved_sheet(theirs)?);
+    let (cells, conflicts) = merge_sheets(&base.cells, &ours.cells, &theirs.cells);
+    let size = |saved: &SaveFile| saved.rows.zip(saved.cols);
+    let (rows, cols) = match (size(&ours), size(&theirs)) {
+        (Some((r1, c1)), Some((r2, c2))) => (Some(r1.max(r2)), Some(c1.max(c2))),
+        (size, other) => size.or(other).unzip(),
+    };
+    let merged = SaveFileRef {
+        version: SAVE_FORMAT_VERSION,
+        rows,
+        cols,
+        cells: std::borrow::Cow::Owned(cells),
+        undo: None,
+        redo: None,
+        protection: ours.protection.as_ref(),
+        pivots: Some(&ours.pivots).filter(|pivots| !pivots.is_empty()),
+        row_groups: Some(&ours.row_groups).filter(|groups| !groups.is_empty()),
+    };
+    if as_text {
+        write_text_save(out, &merged)?;
+    } else {
+        write_save(out, &merged, false)?;
+    }
+    Ok(conflicts)
+}
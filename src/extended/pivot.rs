@@ -0,0 +1,189 @@
+//! Pivot tables: `pivot` groups the rows of a range by one or two columns and writes a table
+//! combining the values of each group, which `pivot refresh` recomputes from the current values.
+
+use super::*;
+
+/// A pivot table written into the sheet by `pivot`, kept so `pivot refresh` can recompute it.
+///
+/// # Fields:
+/// - `source`: The data range, e.g. `A1:C100`; its first row holds the column headers.
+/// - `dest`: The top-left cell of the table.
+/// - `rows`, `cols`: The columns (by letter) whose values label the table's rows and, optionally, columns.
+/// - `function`: How the values of each group are combined: `SUM`, `COUNT`, `AVG`, `MIN` or `MAX`.
+/// - `values`: The column (by letter) holding the values.
+/// - `size`: The rows and columns the table took up when it was last written, cleared before it is rewritten.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(super) struct Pivot {
+    source: String,
+    pub(super) dest: String,
+    rows: String,
+    #[serde(default)]
+    cols: Option<String>,
+    function: String,
+    values: String,
+    #[serde(default)]
+    pub(super) size: (usize, usize),
+}
+
+impl Pivot {
+    /// Reads the arguments of `pivot`: `<source> <dest> rows=A [cols=B] values=SUM(C)`.
+    pub(super) fn parse(args: &str) -> std::result::Result<Pivot, String> {
+        let usage = "USAGE: pivot <range> <cell> rows=<col> [cols=<col>] values=<SUM|COUNT|AVG|MIN|MAX>(<col>)";
+        let words: Vec<&str> = args.split_whitespace().collect();
+        let [source, dest, options @ ..] = words.as_slice() else {
+            return Err(usage.to_string());
+        };
+        let (mut rows, mut cols, mut values) = (None, None, None);
+        for option in options {
+            match option.split_once('=') {
+                Some(("rows", col)) => rows = Some(col.to_uppercase()),
+                Some(("cols", col)) => cols = Some(col.to_uppercase()),
+                Some(("values", spec)) => values = Some(spec.to_uppercase()),
+                _ => return Err(usage.to_string()),
+            }
+        }
+        let (Some(rows), Some(values)) = (rows, values) else {
+            return Err(usage.to_string());
+        };
+        let (function, column) = values.strip_suffix(')').and_then(|spec| spec.split_once('(')).ok_or_else(|| usage.to_string())?;
+        let function = match function {
+            "AVERAGE" => "AVG",
+            "SUM" | "COUNT" | "AVG" | "MIN" | "MAX" => function,
+            other => return Err(format!("UNSUPPORTED PIVOT FUNCTION {}", other)),
+        };
+        Ok(Pivot {
+            source: source.to_uppercase(),
+            dest: dest.to_uppercase(),
+            rows,
+            cols,
+            function: function.to_string(),
+            values: column.to_string(),
+            size: (0, 0),
+        })
+    }
+
+    /// Describes the pivot for the `pivot` listing, e.g. `E1: SUM(C) BY A, B FROM A1:C9`.
+    pub(super) fn describe(&self) -> String {
+        let by = match &self.cols {
+            Some(cols) => format!("{}, {}", self.rows, cols),
+            None => self.rows.clone(),
+        };
+        format!("{}: {}({}) BY {} FROM {}", self.dest, self.function, self.values, by, self.source)
+    }
+}
+
+/// Combines the values of one pivot group, or returns `None` if there is nothing to combine. `COUNT`
+/// counts every non-empty value; the other functions only look at numbers.
+fn pivot_aggregate(function: &str, values: &[String]) -> Option<f64> {
+    let numbers: Vec<f64> = values.iter().filter_map(|value| value.parse::<f64>().ok()).collect();
+    match function {
+        "COUNT" => Some(values.iter().filter(|value| !value.is_empty()).count() as f64),
+        _ if numbers.is_empty() => None,
+        "AVG" => Some(numbers.iter().sum::<f64>() / numbers.len() as f64),
+        name => apply_function(name, &numbers).ok(),
+    }
+}
+
+impl Spreadsheet {
+/// Builds the cells of a pivot table from the current values of its source range. Every distinct value
+/// of the `rows` column (and the `cols` column, if given) becomes a row (column) of the table, in sorted
+/// order with numbers compared as numbers; each cell combines the `values` of the source rows in its
+/// group. The top-left cell names the function and the values' header.
+///
+/// # Returns
+///
+/// The table, row by row, or an error if the source range or a column is invalid.
+    pub(super) fn pivot_table(&self, pivot: &Pivot) -> std::result::Result<Vec<Vec<String>>, String> {
+        let (start, end) = self.parse_range(&pivot.source).ok_or_else(|| self.range_error(&pivot.source))?;
+        let (first, last) = (start.col.min(end.col), start.col.max(end.col));
+        let column = |letters: &str| CellAddress::from_str(&format!("{}1", letters)).map(|addr| addr.col)
+            .filter(|col| (first..=last).contains(col))
+            .ok_or_else(|| format!("ERROR: COLUMN {} IS NOT IN {}", letters, pivot.source));
+        let (row_col, value_col) = (column(&pivot.rows)?, column(&pivot.values)?);
+        let col_col = pivot.cols.as_deref().map(column).transpose()?;
+        let value = |col: usize, row: usize| self.get_cell(&CellAddress::new(col, row)).map(|cell| cell.formatted_value()).unwrap_or_default();
+
+        let (header, data) = (start.row.min(end.row), start.row.min(end.row) + 1..=start.row.max(end.row));
+        let mut groups: HashMap<(String, String), Vec<String>> = HashMap::new();
+        let (mut row_keys, mut col_keys) = (Vec::new(), Vec::new());
+        for row in data {
+            let row_key = value(row_col, row);
+            let col_key = col_col.map(|col| value(col, row)).unwrap_or_default();
+            if !row_keys.contains(&row_key) {
+                row_keys.push(row_key.clone());
+            }
+            if !col_keys.contains(&col_key) {
+                col_keys.push(col_key.clone());
+            }
+            groups.entry((row_key, col_key)).or_default().push(value(value_col, row));
+        }
+        let order = |a: &String, b: &String| match (a.parse::<f64>(), b.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+            _ => a.cmp(b),
+        };
+        row_keys.sort_by(order);
+        col_keys.sort_by(order);
+
+        let label = format!("{} OF {}", pivot.function, Some(value(value_col, header)).filter(|h| !h.is_empty()).unwrap_or_else(|| pivot.values.clone()));
+        let mut table = vec![match col_col {
+            Some(_) => std::iter::once(label).chain(col_keys.iter().cloned()).collect(),
+            None => vec![value(row_col, header), label],
+        }];
+        for row_key in row_keys {
+            let mut line = vec![row_key.clone()];
+            for col_key in &col_keys {
+                let combined = groups.get(&(row_key.clone(), col_key.clone())).and_then(|values| pivot_aggregate(&pivot.function, values));
+                line.push(combined.map(|n| n.to_string()).unwrap_or_default());
+            }
+            table.push(line);
+        }
+        Ok(table)
+    }
+
+/// Writes a pivot table into the sheet as one undoable action, first clearing what the table held
+/// when it was last written. The table must fit in the sheet, stay clear of its source range and of
+/// protected cells.
+///
+/// # Returns
+///
+/// Returns `Ok` with the size of the table written, or an error message if it cannot be written.
+    pub(super) fn write_pivot(&mut self, pivot: &mut Pivot) -> std::result::Result<(usize, usize), String> {
+        let table = self.pivot_table(pivot)?;
+        let dest = CellAddress::from_str(&pivot.dest).ok_or_else(|| format!("ERROR: INVALID CELL {}", pivot.dest))?;
+        let size = (table.len(), table.iter().map(Vec::len).max().unwrap_or(0));
+        if dest.row + size.0 > self.max_rows || dest.col + size.1 > self.max_cols {
+            return Err(format!("ERROR: PIVOT NEEDS {}x{} CELLS FROM {}", size.0, size.1, pivot.dest));
+        }
+        let bottom_right = CellAddress::new(dest.col + size.1 - 1, dest.row + size.0 - 1);
+        if let Some((start, end)) = self.parse_range(&pivot.source) {
+            let overlaps = start.col.min(end.col) <= bottom_right.col && dest.col <= start.col.max(end.col)
+                && start.row.min(end.row) <= bottom_right.row && dest.row <= start.row.max(end.row);
+            if overlaps {
+                return Err(format!("ERROR: PIVOT AT {} WOULD OVERWRITE {}", pivot.dest, pivot.source));
+            }
+        }
+        if let Some(area) = self.protection.covering(&dest, &bottom_right) {
+            return Err(format!("ERROR: {} PROTECTED", area));
+        }
+
+        self.begin_action();
+        let (old_rows, old_cols) = pivot.size;
+        for row in dest.row..(dest.row + old_rows).min(self.max_rows) {
+            for col in dest.col..(dest.col + old_cols).min(self.max_cols) {
+                let addr = CellAddress::new(col, row);
+                let written = row - dest.row < size.0 && col - dest.col < size.1;
+                if !written && self.get_cell(&addr).is_some_and(|cell| !cell.is_blank()) {
+                    self.update_cell(&addr, "", true);
+                }
+            }
+        }
+        for (i, line) in table.iter().enumerate() {
+            for (j, text) in line.iter().enumerate() {
+                self.update_cell(&CellAddress::new(dest.col + j, dest.row + i), text, true);
+            }
+        }
+        self.commit_action();
+        pivot.size = size;
+        Ok(size)
+    }
+}
@@ -0,0 +1,397 @@
+//! The `serve` command: a WebSocket endpoint browser dashboards connect to in order to mirror the
+//! sheet and send commands to it, each with a session it can reconnect to after a dropped connection.
+
+use super::*;
+
+/// How long (in seconds) the server keeps the session of a dashboard whose connection dropped, for it to
+/// reconnect to (see `RemoteSession`).
+const REMOTE_SESSION_TTL_SECS: u64 = 600;
+/// Number of command statuses each dashboard session keeps, to answer commands resent after a reconnect.
+const REMOTE_REPLIES_KEPT: usize = 100;
+/// A message sent to the dashboards connected to `serve`, as one JSON text frame.
+///
+/// - `Snapshot`: The whole sheet, sent when a dashboard connects or reconnects and when the sheet is
+///   resized, with the dashboard's session: the `token` to reconnect with, and its own cursor and view.
+/// - `Changes`: The new state of the cells that changed since the last message, `null` for cells
+///   that were cleared.
+/// - `Cursor`: Where the editor's cursor moved to.
+/// - `Status`: The status message left by a command a dashboard sent, whether it failed, the `id` the
+///   command was sent with, and where the dashboard's cursor is after it.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum DashboardEvent<'a> {
+    Snapshot {
+        token: &'a str,
+        rows: usize,
+        cols: usize,
+        cursor: String,
+        client_cursor: String,
+        view: String,
+        cells: &'a HashMap<String, Cell>,
+    },
+    Changes { cells: HashMap<String, Option<Cell>> },
+    Cursor { addr: String },
+    Status { command: String, message: String, error: bool, id: Option<u64>, cursor: String },
+}
+
+/// A message sent by a dashboard. `command` runs as if it was typed after `:` in the editor, but at the
+/// dashboard's own cursor and view, e.g. `{"command": "sort A1:A10 0", "id": 7}`. `view` moves the
+/// top-left cell of the part of the sheet the dashboard shows, e.g. `{"view": "A20"}`.
+///
+/// A command sent with an `id` the session already ran is not run again; its status is sent again
+/// instead, so a dashboard can resend the commands it got no status for after reconnecting.
+#[derive(Deserialize, Debug)]
+struct DashboardMessage {
+    #[serde(default)]
+    command: Option<String>,
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(default)]
+    view: Option<String>,
+}
+
+/// What the server keeps of a dashboard between connections. A dashboard reconnecting with the
+/// session's token (`ws://host:port/?token=...`) within `REMOTE_SESSION_TTL_SECS` of losing its
+/// connection gets it back, so a dropped connection costs neither its place in the sheet nor the
+/// commands it had sent.
+///
+/// # Fields:
+/// - `token`: The random token the dashboard was given in its first snapshot.
+/// - `name`: The author of the dashboard's edits in the audit log, given when it first connects
+///   (`?name=...`) or numbered in connection order (`dashboard 1`, ...).
+/// - `socket`: The connection, `None` while the dashboard is disconnected.
+/// - `cursor`: The dashboard's own cursor, where the commands it sends run.
+/// - `view`: The first row and column the dashboard shows, moved by `view` messages and by commands that scroll.
+/// - `replies`: The statuses of the last `REMOTE_REPLIES_KEPT` commands sent with an `id`, by id.
+/// - `dropped`: When the connection was lost.
+struct RemoteSession {
+    token: String,
+    name: String,
+    socket: Option<tungstenite::WebSocket<std::net::TcpStream>>,
+    cursor: CellAddress,
+    view: (usize, usize),
+    replies: VecDeque<(u64, String)>,
+    dropped: Option<Instant>,
+}
+
+impl RemoteSession {
+    /// Forgets the connection after it failed, keeping the session for a reconnect.
+    fn disconnect(&mut self) {
+        self.socket = None;
+        self.dropped = Some(Instant::now());
+    }
+}
+
+/// Reads the parameters of a dashboard's WebSocket request (`?token=...&name=...`) during the handshake.
+struct RequestQuery<'a>(&'a mut HashMap<String, String>);
+
+impl tungstenite::handshake::server::Callback for RequestQuery<'_> {
+    fn on_request(
+        self,
+        request: &tungstenite::handshake::server::Request,
+        response: tungstenite::handshake::server::Response,
+    ) -> std::result::Result<tungstenite::handshake::server::Response, tungstenite::handshake::server::ErrorResponse> {
+        let query = request.uri().query().unwrap_or_default();
+        self.0.extend(query.split('&').filter_map(|pair| pair.split_once('=')).map(|(key, value)| (key.to_string(), value.to_string())));
+        Ok(response)
+    }
+}
+
+/// The WebSocket endpoint started with `serve`. Browser dashboards connect to it to mirror the sheet
+/// kept by the editor: each gets a snapshot of the sheet, then the cells that change and the cursor
+/// moves, and can send commands back (see `DashboardEvent` and `DashboardMessage`). Every dashboard
+/// has a `RemoteSession` it can reconnect to.
+///
+/// Nothing runs in the background: the editor accepts connections, runs the commands received and
+/// sends the changes on every idle tick (see `Spreadsheet::sync_dashboard`).
+///
+/// # Fields:
+/// - `listener`: The socket dashboards connect to, polled without blocking.
+/// - `sessions`: The sessions of the dashboards, connected or waiting for a reconnect.
+/// - `sent`: Every cell as the dashboards were last told it is, to find the cells that changed since.
+/// - `size`: The size of the sheet the dashboards were last sent.
+/// - `cursor`: The editor's cursor position the dashboards were last sent.
+/// - `opened`: How many sessions were opened, to number the dashboards that connect without a name.
+pub(super) struct Dashboard {
+    listener: std::net::TcpListener,
+    sessions: Vec<RemoteSession>,
+    sent: HashMap<String, Cell>,
+    size: (usize, usize),
+    cursor: (usize, usize),
+    opened: usize,
+}
+
+impl Dashboard {
+    /// Sends one event to a dashboard. Frames that do not fit into the socket yet stay queued until
+    /// the next flush.
+    ///
+    /// # Returns
+    /// `false` if the dashboard disconnected.
+    fn send(socket: &mut tungstenite::WebSocket<std::net::TcpStream>, event: &DashboardEvent) -> bool {
+        match serde_json::to_string(event) {
+            Ok(text) => Self::alive(socket.send(tungstenite::Message::Text(text))),
+            Err(_) => true,
+        }
+    }
+
+    /// Returns whether a dashboard is still connected after a write, which may only have been queued
+    /// because its socket was full.
+    fn alive(result: tungstenite::Result<()>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(tungstenite::Error::Io(e)) => e.kind() == io::ErrorKind::WouldBlock,
+            Err(_) => false,
+        }
+    }
+
+    /// Sends one event to every connected dashboard.
+    fn broadcast(&mut self, event: &DashboardEvent) {
+        for session in &mut self.sessions {
+            if session.socket.as_mut().is_some_and(|socket| !Self::send(socket, event)) {
+                session.disconnect();
+            }
+        }
+    }
+
+    /// Accepts the dashboards waiting to connect, with the parameters of each one's request (see
+    /// `RemoteSession`). The WebSocket handshake blocks for at most a second, so a connection that never
+    /// sends it cannot hang the editor.
+    fn accept(&mut self) -> Vec<(tungstenite::WebSocket<std::net::TcpStream>, HashMap<String, String>)> {
+        let mut accepted = Vec::new();
+        while let Ok((stream, _)) = self.listener.accept() {
+            let mut query = HashMap::new();
+            let handshake = stream.set_nonblocking(false)
+                .and_then(|_| stream.set_read_timeout(Some(Duration::from_secs(1))))
+                .ok()
+                .and_then(|_| tungstenite::accept_hdr(stream, RequestQuery(&mut query)).ok());
+            if let Some(socket) = handshake.filter(|socket| socket.get_ref().set_nonblocking(true).is_ok()) {
+                accepted.push((socket, query));
+            }
+        }
+        accepted
+    }
+}
+
+impl Spreadsheet {
+    /// Starts the WebSocket endpoint browser dashboards connect to in order to mirror the sheet and send
+    /// commands to it (see `Dashboard`), replacing the endpoint already running.
+    ///
+    /// # Arguments
+    /// - `addr`: The address to listen on, e.g. `127.0.0.1:8765`; port 0 picks a free port.
+    ///
+    /// # Returns
+    /// The address the endpoint listens on, or an error if it cannot be bound.
+    pub fn serve(&mut self, addr: &str) -> io::Result<std::net::SocketAddr> {
+        self.stop_serving();
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local = listener.local_addr()?;
+        self.dashboard = Some(Dashboard {
+            listener,
+            sessions: Vec::new(),
+            sent: HashMap::new(),
+            size: (self.max_rows, self.max_cols),
+            cursor: (self.cursor.col, self.cursor.row),
+            opened: 0,
+        });
+        Ok(local)
+    }
+
+    /// Stops the WebSocket endpoint started with `serve`, closing the connections of its dashboards and
+    /// forgetting their sessions.
+    ///
+    /// # Returns
+    /// `false` if no endpoint was running.
+    pub fn stop_serving(&mut self) -> bool {
+        let Some(dashboard) = self.dashboard.take() else {
+            return false;
+        };
+        for mut socket in dashboard.sessions.into_iter().filter_map(|session| session.socket) {
+            let _ = socket.close(None);
+            let _ = socket.flush();
+        }
+        true
+    }
+
+    /// Exchanges messages with the dashboards connected to `serve`, if it is running: runs the commands
+    /// they sent and answers each with its status, sends the cells that changed since the last call (or
+    /// the whole sheet if it was resized) and the cursor if it moved, then accepts new dashboards and
+    /// sends them the whole sheet. Sessions disconnected for longer than `REMOTE_SESSION_TTL_SECS` are
+    /// forgotten. The editor calls this on every idle tick.
+    ///
+    /// # Returns
+    /// `true` if a dashboard ran a command, so the screen needs redrawing.
+    pub fn sync_dashboard(&mut self) -> bool {
+        let Some(mut dashboard) = self.dashboard.take() else {
+            return false;
+        };
+        let ttl = Duration::from_secs(REMOTE_SESSION_TTL_SECS);
+        dashboard.sessions.retain(|session| session.dropped.is_none_or(|dropped| dropped.elapsed() < ttl));
+        let mut ran = false;
+        for session in &mut dashboard.sessions {
+            while let Some(socket) = session.socket.as_mut() {
+                match socket.read() {
+                    Ok(tungstenite::Message::Text(text)) => {
+                        ran = true;
+                        let Some(reply) = self.run_dashboard_message(session, &text) else {
+                            continue;
+                        };
+                        let sent = session.socket.as_mut().is_some_and(|socket| Dashboard::alive(socket.send(tungstenite::Message::Text(reply))));
+                        if !sent {
+                            session.disconnect();
+                        }
+                    }
+                    // Pings are answered by the WebSocket itself
+                    Ok(_) => {}
+                    Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => session.disconnect(),
+                }
+            }
+        }
+
+        if dashboard.sessions.iter().any(|session| session.socket.is_some()) {
+            if dashboard.size != (self.max_rows, self.max_cols) {
+                self.page_in_all_rows();
+                for session in &mut dashboard.sessions {
+                    let snapshot = self.dashboard_snapshot(session);
+                    if session.socket.as_mut().is_some_and(|socket| !Dashboard::alive(socket.send(tungstenite::Message::Text(snapshot)))) {
+                        session.disconnect();
+                    }
+                }
+                dashboard.sent = self.data.clone();
+            } else {
+                let mut changes: HashMap<String, Option<Cell>> = self.data.iter()
+                    .filter(|(addr, cell)| dashboard.sent.get(*addr) != Some(*cell))
+                    .map(|(addr, cell)| (addr.clone(), Some(cell.clone())))
+                    .collect();
+                // Cells of rows paged out of a database-backed sheet are still there, only not in memory
+                dashboard.sent.retain(|addr, _| {
+                    let kept = self.data.contains_key(addr)
+                        || CellAddress::from_str(addr).is_some_and(|addr| !self.row_in_memory(addr.row));
+                    if !kept {
+                        changes.insert(addr.clone(), None);
+                    }
+                    kept
+                });
+                if !changes.is_empty() {
+                    for (addr, cell) in &changes {
+                        if let Some(cell) = cell {
+                            dashboard.sent.insert(addr.clone(), cell.clone());
+                        }
+                    }
+                    dashboard.broadcast(&DashboardEvent::Changes { cells: changes });
+                }
+            }
+            if dashboard.cursor != (self.cursor.col, self.cursor.row) {
+                dashboard.broadcast(&DashboardEvent::Cursor { addr: self.cursor.to_string() });
+            }
+            // Frames that did not fit into a socket before go out now
+            for session in &mut dashboard.sessions {
+                if session.socket.as_mut().is_some_and(|socket| !Dashboard::alive(socket.flush())) {
+                    session.disconnect();
+                }
+            }
+        }
+        dashboard.size = (self.max_rows, self.max_cols);
+        dashboard.cursor = (self.cursor.col, self.cursor.row);
+
+        let accepted = dashboard.accept();
+        if !accepted.is_empty() {
+            self.page_in_all_rows();
+            dashboard.sent = self.data.clone();
+        }
+        for (mut socket, mut query) in accepted {
+            let resumed = query.remove("token").and_then(|token| dashboard.sessions.iter().position(|session| session.token == token));
+            let index = resumed.unwrap_or_else(|| {
+                dashboard.opened += 1;
+                dashboard.sessions.push(RemoteSession {
+                    token: format!("{:016x}", rand::random::<u64>()),
+                    name: query.remove("name").unwrap_or_else(|| format!("dashboard {}", dashboard.opened)),
+                    socket: None,
+                    cursor: CellAddress::new(0, 0),
+                    view: (0, 0),
+                    replies: VecDeque::new(),
+                    dropped: None,
+                });
+                dashboard.sessions.len() - 1
+            });
+            let session = &mut dashboard.sessions[index];
+            // A dashboard reconnecting before its old connection was noticed to drop takes its place
+            if let Some(mut old) = session.socket.take() {
+                let _ = old.close(None);
+                let _ = old.flush();
+            }
+            if Dashboard::alive(socket.send(tungstenite::Message::Text(self.dashboard_snapshot(session)))) {
+                session.socket = Some(socket);
+                session.dropped = None;
+            } else {
+                session.disconnect();
+            }
+        }
+        self.dashboard = Some(dashboard);
+        ran
+    }
+
+    /// Returns the snapshot of the whole sheet sent to the dashboard of a session, as JSON.
+    fn dashboard_snapshot(&self, session: &RemoteSession) -> String {
+        let snapshot = DashboardEvent::Snapshot {
+            token: &session.token,
+            rows: self.max_rows,
+            cols: self.max_cols,
+            cursor: self.cursor.to_string(),
+            client_cursor: session.cursor.to_string(),
+            view: CellAddress::new(session.view.1, session.view.0).to_string(),
+            cells: &self.data,
+        };
+        serde_json::to_string(&snapshot).unwrap_or_default()
+    }
+
+    /// Takes in a message a dashboard sent (see `DashboardMessage`): moves its view and runs its command
+    /// at its own cursor and view, leaving the editor's where they are, with the dashboard's name as the
+    /// author of its edits. Quitting and starting or stopping
+    /// the endpoint are left to the editor.
+    ///
+    /// # Returns
+    /// The status to answer with, or `None` for a message without a command.
+    fn run_dashboard_message(&mut self, session: &mut RemoteSession, text: &str) -> Option<String> {
+        let status = |command: String, message: String, id: Option<u64>, session: &RemoteSession| {
+            let error = is_error_message(&message);
+            serde_json::to_string(&DashboardEvent::Status { command, message, error, id, cursor: session.cursor.to_string() }).unwrap_or_default()
+        };
+        let message = match serde_json::from_str::<DashboardMessage>(text) {
+            Ok(message) => message,
+            Err(e) => return Some(status(String::new(), format!("INVALID MESSAGE: {}", e), None, session)),
+        };
+        if let Some(view) = &message.view {
+            match CellAddress::from_str(view).filter(|addr| addr.row < self.max_rows && addr.col < self.max_cols) {
+                Some(addr) => session.view = (addr.row, addr.col),
+                None => return Some(status(message.command.unwrap_or_default(), format!("INVALID VIEW {}", view), message.id, session)),
+            }
+        }
+        let command = message.command?;
+        if let Some((_, reply)) = message.id.and_then(|id| session.replies.iter().find(|(done, _)| *done == id)) {
+            return Some(reply.clone());
+        }
+        let name = command.trim().trim_start_matches(':').split_whitespace().next().unwrap_or_default();
+        let result = if name == "q" || name == "serve" {
+            format!("ERROR: {} CAN ONLY BE RUN IN THE EDITOR", name)
+        } else {
+            let editor_cursor = std::mem::replace(&mut self.cursor, session.cursor.clone());
+            let editor_view = (std::mem::replace(&mut self.start_row, session.view.0), std::mem::replace(&mut self.start_col, session.view.1));
+            self.author = Some(session.name.clone());
+            self.run_command(&command);
+            self.author = None;
+            session.cursor = std::mem::replace(&mut self.cursor, editor_cursor);
+            session.view = (std::mem::replace(&mut self.start_row, editor_view.0), std::mem::replace(&mut self.start_col, editor_view.1));
+            self.status_message.clone()
+        };
+        let reply = status(command, result, message.id, session);
+        if let Some(id) = message.id {
+            session.replies.push_back((id, reply.clone()));
+            if session.replies.len() > REMOTE_REPLIES_KEPT {
+                session.replies.pop_front();
+            }
+        }
+        Some(reply)
+    }
+}
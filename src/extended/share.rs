@@ -0,0 +1,281 @@
+//! The `share` command: collaborative editing sessions where several editors append their edits and
+//! cursor moves to one file and read back each other's, the last writer of a cell winning.
+
+use super::*;
+
+/// One line of a shared session file (see `Session`).
+///
+/// - `Cells`: The new content of cells edited by `client`, stamped with its clock at the time.
+/// - `Cursor`: Where `client`'s cursor moved to.
+/// - `Leave`: `client` left the session.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum SessionOp {
+    Cells { client: String, clock: u64, cells: HashMap<String, Cell> },
+    Cursor { client: String, addr: String },
+    Leave { client: String },
+}
+
+/// A collaborative editing session started with `share`. Every client of the session appends its
+/// edits and cursor moves to the same file, one `SessionOp` per line, and reads the lines the others
+/// appended since it last looked.
+///
+/// Concurrent edits of a cell are settled by the last writer: each edit is stamped with a Lamport
+/// clock (one more than any stamp the client has seen) and the client name, and a cell only takes an
+/// edit whose stamp is higher than that of the edit it shows. Every client compares the same stamps,
+/// so all of them end up with the same content whatever order they read the edits in.
+///
+/// # Fields:
+/// - `path`: The session file.
+/// - `client`: This client's name, shown to the others at its cursor.
+/// - `read`: How many bytes of the session file have been read.
+/// - `clock`: The highest clock value sent or seen.
+/// - `stamps`: The `(clock, client)` stamp of the edit every cell edited in the session shows.
+/// - `peers`: The cursors of the other clients, by name.
+/// - `cursor_sent`: The cursor position last sent, so a cursor is only sent when it moves.
+pub(super) struct Session {
+    pub(super) path: PathBuf,
+    pub(super) client: String,
+    read: u64,
+    clock: u64,
+    stamps: HashMap<String, (u64, String)>,
+    pub(super) peers: HashMap<String, CellAddress>,
+    cursor_sent: Option<(usize, usize)>,
+}
+
+impl SessionOp {
+    /// Returns the name of the client that sent the operation.
+    fn client(&self) -> &str {
+        match self {
+            SessionOp::Cells { client, .. } | SessionOp::Cursor { client, .. } | SessionOp::Leave { client } => client,
+        }
+    }
+}
+
+impl Session {
+    /// Appends one operation to the session file, creating it if needed.
+    fn append(&self, op: &SessionOp) -> io::Result<()> {
+        let mut line = serde_json::to_string(op)?;
+        line.push('\n');
+        // The whole line goes out in one append, so lines of clients writing at once do not interleave
+        std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(line.as_bytes())
+    }
+
+    /// Stamps the cells with a new clock value and sends them to the other clients.
+    fn send_cells(&mut self, cells: HashMap<String, Cell>) -> io::Result<()> {
+        self.clock += 1;
+        for addr in cells.keys() {
+            self.stamps.insert(addr.clone(), (self.clock, self.client.clone()));
+        }
+        self.append(&SessionOp::Cells { client: self.client.clone(), clock: self.clock, cells })
+    }
+
+    /// Sends the cursor position if it moved since it was last sent.
+    fn send_cursor(&mut self, cursor: &CellAddress) -> io::Result<()> {
+        if self.cursor_sent == Some((cursor.col, cursor.row)) {
+            return Ok(());
+        }
+        self.cursor_sent = Some((cursor.col, cursor.row));
+        self.append(&SessionOp::Cursor { client: self.client.clone(), addr: cursor.to_string() })
+    }
+
+    /// Reads the operations appended since the last call. A line still being written is left for the
+    /// next call; a missing file has no operations yet.
+    fn read_new(&mut self) -> io::Result<Vec<SessionOp>> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        file.seek(SeekFrom::Start(self.read))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        let complete = text.rfind('\n').map_or(0, |end| end + 1);
+        self.read += complete as u64;
+        text[..complete].lines().filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("SESSION FILE: {}", e))))
+            .collect()
+    }
+
+    /// Takes in an operation read from the session file: cursors and departures of other clients update
+    /// `peers`, and the cells of an edit are returned if they win over the edits the cells show. Edits
+    /// this client sent are already stamped on their cells, so they never win again.
+    fn receive(&mut self, op: SessionOp) -> Vec<(String, Cell)> {
+        match op {
+            SessionOp::Cells { client, clock, cells } => {
+                self.clock = self.clock.max(clock);
+                let won: Vec<(String, Cell)> = cells.into_iter()
+                    .filter(|(addr, _)| self.stamps.get(addr).is_none_or(|(shown, by)| (clock, &client) > (*shown, by)))
+                    .collect();
+                for (addr, _) in &won {
+                    self.stamps.insert(addr.clone(), (clock, client.clone()));
+                }
+                won
+            }
+            SessionOp::Cursor { client, addr } => {
+                if let Some(addr) = CellAddress::from_str(&addr).filter(|_| client != self.client) {
+                    self.peers.insert(client, addr);
+                }
+                Vec::new()
+            }
+            SessionOp::Leave { client } => {
+                self.peers.remove(&client);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl Spreadsheet {
+    /// Joins the collaborative editing session kept in a file, or starts it if the file holds no edits
+    /// yet. Starting a session sends the whole sheet to it; joining one replaces the sheet with the
+    /// shared content, keeping the size (grown to fit the shared cells) and dropping the undo history.
+    ///
+    /// # Arguments
+    /// - `path`: The session file every client opens, e.g. on a shared drive.
+    /// - `client`: The name the other clients see at this client's cursor; it must differ from theirs.
+    ///
+    /// # Returns
+    /// `true` if an existing session was joined and `false` if a new one was started, or an error if
+    /// the file cannot be read or written.
+    pub fn share(&mut self, path: &Path, client: &str) -> io::Result<bool> {
+        self.unshare();
+        let mut session = Session {
+            path: path.to_path_buf(),
+            client: client.to_string(),
+            read: 0,
+            clock: 0,
+            stamps: HashMap::new(),
+            peers: HashMap::new(),
+            cursor_sent: None,
+        };
+        let ops = session.read_new()?;
+        let joined = ops.iter().any(|op| matches!(op, SessionOp::Cells { .. }));
+        let shared: HashMap<String, Cell> = ops.into_iter().flat_map(|op| session.receive(op)).collect();
+        if joined {
+            self.load_save_file(SaveFile {
+                version: SAVE_FORMAT_VERSION,
+                rows: Some(self.max_rows),
+                cols: Some(self.max_cols),
+                cells: shared,
+                undo: VecDeque::new(),
+                redo: VecDeque::new(),
+                protection: Some(self.protection.clone()),
+                pivots: self.pivots.clone(),
+                row_groups: self.row_groups.clone(),
+            })?;
+        } else {
+            self.page_in_all_rows();
+            session.send_cells(self.data.clone())?;
+        }
+        session.send_cursor(&self.cursor)?;
+        self.session = Some(session);
+        Ok(joined)
+    }
+
+    /// Leaves the collaborative editing session, telling the other clients.
+    ///
+    /// # Returns
+    /// `false` if the sheet was not shared.
+    pub fn unshare(&mut self) -> bool {
+        let Some(session) = self.session.take() else {
+            return false;
+        };
+        // The others drop this client's cursor; if the file is gone there is no one left to tell
+        let _ = session.append(&SessionOp::Leave { client: session.client.clone() });
+        true
+    }
+
+    /// Exchanges changes with the collaborative editing session, if the sheet is shared: sends the
+    /// cursor if it moved, then applies the edits other clients made since the last call and takes in
+    /// their cursors. The editor calls this on every idle tick.
+    ///
+    /// # Returns
+    /// `true` if anything arrived (or the session file could not be read), so the screen needs redrawing.
+    pub fn sync_session(&mut self) -> bool {
+        let Some(mut session) = self.session.take() else {
+            return false;
+        };
+        let ops = match session.send_cursor(&self.cursor).and_then(|_| session.read_new()) {
+            Ok(ops) => ops,
+            Err(e) => {
+                self.status_message = format!("SESSION ERROR: {}", e);
+                self.session = Some(session);
+                return true;
+            }
+        };
+        let arrived = !ops.is_empty();
+        let edits: Vec<(String, Vec<(String, Cell)>)> = ops.into_iter()
+            .map(|op| (op.client().to_string(), session.receive(op)))
+            .collect();
+        self.session = Some(session);
+        for (client, cells) in edits {
+            self.apply_shared_cells(cells, &client);
+        }
+        arrived
+    }
+
+    /// Sends the new content of the cells changed by an edit, undo or redo to the collaborative
+    /// session. Cells whose input and styles stayed the same (dependents that were only recalculated)
+    /// are left out, as every client recalculates them itself.
+    ///
+    /// # Arguments
+    /// - `changes`: The changed cells, holding their states from before the change.
+    pub(super) fn share_changes(&mut self, changes: &[UndoAction]) {
+        let Some(mut session) = self.session.take() else {
+            return;
+        };
+        let cells: HashMap<String, Cell> = changes.iter().filter_map(|change| {
+            let cell = self.get_cell(&change.cell_address)?;
+            (!cell.same_content(&change.old_cell)).then(|| (change.cell_address.to_string(), cell.clone()))
+        }).collect();
+        let sent = if cells.is_empty() { Ok(()) } else { session.send_cells(cells) };
+        if let Err(e) = sent {
+            self.status_message = format!("SESSION ERROR: {}", e);
+        }
+        self.session = Some(session);
+    }
+
+    /// Puts cells edited by another client into the sheet, outside the undo history but in the audit
+    /// log, then recalculates the formulas among them and every cell depending on them (or marks them
+    /// stale under lazy calculation).
+    fn apply_shared_cells(&mut self, cells: Vec<(String, Cell)>, client: &str) {
+        if cells.is_empty() {
+            return;
+        }
+        let mut changed = Vec::new();
+        let mut edits = Vec::new();
+        for (addr_str, cell) in cells {
+            let Some(addr) = CellAddress::from_str(&addr_str).filter(|addr| addr.row < self.max_rows && addr.col < self.max_cols) else {
+                continue;
+            };
+            if self.is_store_backed() {
+                self.page_in_rows(addr.row, addr.row);
+            }
+            if cell.formula.is_some() {
+                self.stale.insert(addr_str.clone());
+            }
+            let old_cell = self.data.get(&addr_str).cloned().unwrap_or_else(Cell::new);
+            edits.push(UndoAction { cell_address: addr.clone(), old_cell });
+            if cell.is_blank() {
+                self.data.remove(&addr_str);
+            } else {
+                self.data.insert(addr_str.clone(), cell);
+            }
+            self.relink_cell(&addr_str);
+            let dependents = Self::linked_cells(&self.dependents, &addr_str);
+            self.stale.extend(dependents);
+            changed.push(addr);
+        }
+        if self.is_store_backed() {
+            self.sync_store(changed);
+        }
+        self.record_audit(&edits, Some(client));
+        self.unsaved_changes = true;
+        self.modified = true;
+        if !self.lazy_calc {
+            self.evaluate_stale(None, None);
+        }
+    }
+}
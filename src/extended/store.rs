@@ -0,0 +1,275 @@
+//! The SQLite backing store of the extended sheet: sheets saved with `saveas_db` or opened with
+//! `open_db`, and sheets grown too large for memory, which move to a swap database. Their rows are
+//! read back in pages as they are shown or referred to.
+
+use super::*;
+
+/// Number of rows read from a SQLite-backed sheet at a time.
+const STORE_PAGE_ROWS: usize = 64;
+/// Number of row pages of a SQLite-backed sheet kept in memory before the least recently used are dropped.
+const STORE_MAX_PAGES: usize = 16;
+/// Number of cells in memory beyond which a sheet not backed by a database is moved to a swap database.
+pub(super) const SWAP_MIN_CELLS: usize = 1_000_000;
+/// Number of swap databases created so far, so each sheet of the process gets its own file.
+static SWAP_FILES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// A SQLite database holding the cells of a sheet, for sheets too large to keep in memory.
+///
+/// Only non-empty cells are stored, one row per cell with its value, display value, formula and the
+/// remaining fields as JSON metadata. Edits are written through when each undo action is committed, so
+/// cells in memory never differ from the database and rows can be dropped and read back at any time.
+/// Formula cells are always kept in memory so the dependency graph stays complete.
+///
+/// # Fields:
+/// - `conn`: The open database connection.
+/// - `pages`: The row pages currently in memory (`row / STORE_PAGE_ROWS`), least recently used first.
+/// - `swap`: The path of the database if it is a swap file made by `spill_to_swap`, deleted with the store.
+pub(super) struct CellStore {
+    conn: rusqlite::Connection,
+    pages: VecDeque<usize>,
+    swap: Option<PathBuf>,
+}
+
+impl CellStore {
+    /// Creates (or overwrites) a database with the given cells and sheet size.
+    fn create(path: &Path, cells: &HashMap<String, Cell>, rows: usize, cols: usize) -> rusqlite::Result<Self> {
+        let mut conn = rusqlite::Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sheet (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS cells (
+                 col INTEGER NOT NULL, row INTEGER NOT NULL,
+                 value TEXT NOT NULL, display TEXT NOT NULL, formula TEXT, metadata TEXT NOT NULL,
+                 PRIMARY KEY (row, col));
+             DELETE FROM sheet; DELETE FROM cells;")?;
+        let tx = conn.transaction()?;
+        for (key, value) in [("version", SAVE_FORMAT_VERSION as usize), ("rows", rows), ("cols", cols)] {
+            tx.execute("INSERT INTO sheet (key, value) VALUES (?1, ?2)", rusqlite::params![key, value.to_string()])?;
+        }
+        for (addr, cell) in cells {
+            if let Some(addr) = CellAddress::from_str(addr) {
+                Self::write_cell(&tx, &addr, cell)?;
+            }
+        }
+        tx.commit()?;
+        Ok(CellStore { conn, pages: VecDeque::new(), swap: None })
+    }
+
+    /// Opens an existing database, returning it with the saved number of rows and columns. A size
+    /// past 999 rows or 18278 columns is refused.
+    fn open(path: &Path) -> rusqlite::Result<(Self, usize, usize)> {
+        let conn = rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        let setting = |key: &str| -> rusqlite::Result<usize> {
+            conn.query_row("SELECT value FROM sheet WHERE key = ?1", [key], |row| row.get::<_, String>(0))
+                .map(|value| value.parse().unwrap_or(0))
+        };
+        let (rows, cols) = (setting("rows")?.max(1), setting("cols")?.max(1));
+        check_sheet_size(rows, cols).map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into()))?;
+        Ok((CellStore { conn, pages: VecDeque::new(), swap: None }, rows, cols))
+    }
+
+    /// Stores one cell, deleting its row instead when the cell is empty.
+    fn write_cell(conn: &rusqlite::Connection, addr: &CellAddress, cell: &Cell) -> rusqlite::Result<()> {
+        if cell.is_blank() {
+            conn.execute("DELETE FROM cells WHERE row = ?1 AND col = ?2", rusqlite::params![addr.row, addr.col])?;
+            return Ok(());
+        }
+        let mut metadata = serde_json::to_value(cell).unwrap_or_default();
+        if let Some(fields) = metadata.as_object_mut() {
+            for column in ["raw_value", "display_value", "formula"] {
+                fields.remove(column);
+            }
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO cells (col, row, value, display, formula, metadata) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![addr.col, addr.row, cell.raw_value, cell.display_value, cell.formula, metadata.to_string()])?;
+        Ok(())
+    }
+
+    /// Stores the given cells in one transaction.
+    fn write_cells<'a>(&mut self, cells: impl Iterator<Item = (CellAddress, &'a Cell)>) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        for (addr, cell) in cells {
+            Self::write_cell(&tx, &addr, cell)?;
+        }
+        tx.commit()
+    }
+
+    /// Reads the stored cells matching an SQL condition on `row`, `col` and `formula`.
+    fn read_cells(&self, condition: &str, params: impl rusqlite::Params) -> rusqlite::Result<Vec<(CellAddress, Cell)>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT col, row, value, display, formula, metadata FROM cells WHERE {}", condition))?;
+        let rows = stmt.query_map(params, |row| {
+            let addr = CellAddress::new(row.get(0)?, row.get(1)?);
+            let mut cell: serde_json::Value = serde_json::from_str(&row.get::<_, String>(5)?).unwrap_or_default();
+            if !cell.is_object() {
+                cell = serde_json::json!({});
+            }
+            cell["raw_value"] = serde_json::json!(row.get::<_, String>(2)?);
+            cell["display_value"] = serde_json::json!(row.get::<_, String>(3)?);
+            cell["formula"] = serde_json::json!(row.get::<_, Option<String>>(4)?);
+            fill_cell_defaults(&mut cell);
+            Ok((addr, serde_json::from_value(cell).unwrap_or_else(|_| Cell::new())))
+        })?;
+        rows.collect()
+    }
+}
+
+impl Drop for CellStore {
+    /// Deletes a swap database once the sheet no longer uses it, closing the connection first.
+    fn drop(&mut self) {
+        if let Some(path) = self.swap.take() {
+            if let Ok(conn) = rusqlite::Connection::open_in_memory() {
+                drop(std::mem::replace(&mut self.conn, conn));
+            }
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Spreadsheet {
+    /// Sets how many cells a sheet may hold in memory before the next edit or load moves it to a swap
+    /// database (`SWAP_MIN_CELLS` by default).
+    ///
+    /// # Arguments:
+    /// - `cells`: The number of cells to keep in memory.
+    pub fn set_swap_threshold(&mut self, cells: usize) {
+        self.swap_threshold = cells;
+    }
+/// Saves the sheet to a SQLite database and keeps the sheet backed by it, so later edits are written
+/// to the database as they are made.
+///
+/// # Arguments
+///
+/// * `path` - The path of the database file; an existing sheet database there is overwritten.
+///
+/// # Returns
+///
+/// The number of non-empty cells stored, or an error if the database cannot be written.
+    pub(super) fn save_db(&mut self, path: &Path) -> rusqlite::Result<usize> {
+        let mut store = CellStore::create(path, &self.data, self.max_rows, self.max_cols)?;
+        // Every row is in memory now, and can be dropped again like any page read later
+        store.pages = (0..self.max_rows.div_ceil(STORE_PAGE_ROWS)).collect();
+        self.store = Some(store);
+        Ok(self.data.values().filter(|cell| !cell.is_blank()).count())
+    }
+/// Opens a sheet saved with `save_db`. Only the formula cells and the rows around the cursor are read
+/// up front; other rows are read from the database when they are shown or referred to.
+///
+/// # Arguments
+///
+/// * `path` - The path of the database file.
+///
+/// # Returns
+///
+/// Returns `rusqlite::Result<()>`, which is an error if the file is not a sheet database.
+    pub(super) fn open_db(&mut self, path: &Path) -> rusqlite::Result<()> {
+        let (store, rows, cols) = CellStore::open(path)?;
+        let formulas = store.read_cells("formula IS NOT NULL", [])?;
+        self.data = formulas.into_iter().map(|(addr, cell)| (addr.to_string(), cell)).collect();
+        self.store = Some(store);
+        self.max_rows = rows;
+        self.max_cols = cols;
+        self.cursor = CellAddress::new(0, 0);
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.action_depth = 0;
+        self.start_row = 0;
+        self.start_col = 0;
+        self.page_in_rows(0, 10);
+        self.recalculate_all();
+        Ok(())
+    }
+/// Makes sure the rows `first..=last` of a database-backed sheet are in memory, reading the missing
+/// row pages from the database. Least recently used pages beyond `STORE_MAX_PAGES` are dropped again
+/// (except for formula cells) unless an undo action is still open.
+///
+/// # Arguments
+///
+/// * `first` - The first row needed.
+/// * `last` - The last row needed.
+    pub(super) fn page_in_rows(&mut self, first: usize, last: usize) {
+        let Some(store) = self.store.as_mut() else {
+            return;
+        };
+        let last = last.min(self.max_rows.saturating_sub(1));
+        let wanted: Vec<usize> = (first / STORE_PAGE_ROWS..=last / STORE_PAGE_ROWS).collect();
+        for &page in &wanted {
+            if let Some(i) = store.pages.iter().position(|&p| p == page) {
+                store.pages.remove(i);
+                store.pages.push_back(page);
+                continue;
+            }
+            let rows = page * STORE_PAGE_ROWS..((page + 1) * STORE_PAGE_ROWS).min(self.max_rows);
+            match store.read_cells("row >= ?1 AND row < ?2", rusqlite::params![rows.start, rows.end]) {
+                Ok(cells) => {
+                    for (addr, cell) in cells {
+                        self.data.insert(addr.to_string(), cell);
+                    }
+                    store.pages.push_back(page);
+                }
+                Err(e) => self.status_message = format!("DATABASE ERROR: {}", e),
+            }
+        }
+        if self.action_depth > 0 {
+            return;
+        }
+        while store.pages.len() > STORE_MAX_PAGES.max(wanted.len()) {
+            let Some(page) = store.pages.pop_front() else {
+                break;
+            };
+            for row in page * STORE_PAGE_ROWS..((page + 1) * STORE_PAGE_ROWS).min(self.max_rows) {
+                for col in 0..self.max_cols {
+                    let addr = CellAddress::new(col, row).to_string();
+                    if self.data.get(&addr).is_some_and(|cell| cell.formula.is_none()) {
+                        self.data.remove(&addr);
+                    }
+                }
+            }
+        }
+    }
+/// Moves a sheet that is not backed by a database to a swap database in the temporary directory once
+/// more than `swap_threshold` cells are in memory. Only the formula cells and the rows in view stay in
+/// memory; other rows are read back in pages when they are shown or referred to, like a sheet opened
+/// with `open_db`. Workbooks with several sheets are left in memory, as sheets cannot be switched then.
+    pub(super) fn spill_to_swap(&mut self) {
+        if self.store.is_some() || self.data.len() <= self.swap_threshold || self.action_depth > 0 || self.sheets.len() > 1 {
+            return;
+        }
+        let number = SWAP_FILES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("hackersheet-{}-{}.swap.db", std::process::id(), number));
+        match CellStore::create(&path, &self.data, self.max_rows, self.max_cols) {
+            Ok(mut store) => {
+                store.swap = Some(path);
+                self.store = Some(store);
+                self.data.retain(|_, cell| cell.formula.is_some());
+                let first = self.start_row;
+                self.page_in_rows(first, first + self.view_rows);
+                self.page_in_rows(self.cursor.row, self.cursor.row);
+            }
+            Err(e) => self.status_message = format!("DATABASE ERROR: {}", e),
+        }
+    }
+/// Writes the current state of the given cells to the database backing the sheet, if there is one.
+///
+/// # Arguments
+///
+/// * `addrs` - The cells that changed.
+    pub(super) fn sync_store(&mut self, addrs: Vec<CellAddress>) {
+        let Some(store) = self.store.as_mut() else {
+            return;
+        };
+        let cells = addrs.into_iter().map(|addr| {
+            let cell = self.data.get(&addr.to_string()).unwrap_or(&self.blank_cell);
+            (addr, cell)
+        });
+        if let Err(e) = store.write_cells(cells) {
+            self.status_message = format!("DATABASE ERROR: {}", e);
+        }
+    }
+/// Returns whether the cells of a row are in memory: always, unless the sheet is backed by a database
+/// and the page holding the row was not read in yet or was dropped again (see `page_in_rows`).
+    #[cfg(feature = "websocket")]
+    pub(super) fn row_in_memory(&self, row: usize) -> bool {
+        self.store.as_ref().is_none_or(|store| store.pages.contains(&(row / STORE_PAGE_ROWS)))
+    }
+}
@@ -0,0 +1,160 @@
+//! # C interface to the spreadsheet engine
+//!
+//! This module exposes the evaluation engine (`SheetData`, `execute_command` and
+//! `evaluate_expression`) through `extern "C"` functions so programs in other languages can embed
+//! it. It is only built with the `ffi` feature; the matching header is `include/spreadsheet.h`,
+//! generated with `make header` (cbindgen).
+//!
+//! ```c
+//! Sheet *sheet = sheet_new(10, 10);
+//! sheet_set_cell(sheet, "A1", "5");
+//! sheet_set_cell(sheet, "B1", "A1*2");
+//! int value;
+//! if (sheet_get_value(sheet, "B1", &value) == SHEET_OK) { printf("%d\n", value); } // 10
+//! sheet_free(sheet);
+//! ```
+use crate::avl::SheetData;
+use crate::sheet::{evaluate_expression, execute_command, label_to_index, MAX_COLS, MAX_ROWS};
+use std::ffi::{c_char, c_int, CStr};
+
+/// The call succeeded.
+pub const SHEET_OK: c_int = 0;
+/// The cell holds `ERR` (or the expression divides by zero); the value was not written.
+pub const SHEET_ERR: c_int = 1;
+/// A null pointer, a label outside the sheet, text that is not UTF-8 or an invalid expression.
+pub const SHEET_INVALID: c_int = -1;
+/// The expression would make a cell depend on itself.
+pub const SHEET_LOOP: c_int = -4;
+
+/// A spreadsheet owned by C code, created with `sheet_new` and released with `sheet_free`.
+///
+/// Several sheets can be used, and a sheet can be handed to another thread, but one sheet must not
+/// be used from two threads at once. `data` has one extra row below the sheet: `sheet_evaluate`
+/// evaluates there, so an expression can refer to any cell of the sheet without being taken for a
+/// loop.
+pub struct Sheet {
+    rows: usize,
+    cols: usize,
+    data: SheetData,
+}
+
+impl Sheet {
+    /// Converts a cell label into a `(row, col)` inside this sheet.
+    fn position(&self, addr: &str) -> Option<(usize, usize)> {
+        label_to_index(addr).filter(|&(row, col)| row < self.rows && col < self.cols)
+    }
+}
+
+/// Reads a C string argument, or `None` if it is null or not UTF-8.
+///
+/// # Safety
+/// `text` must be null or point to a NUL-terminated string.
+unsafe fn read_str<'a>(text: *const c_char) -> Option<&'a str> {
+    if text.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(text) }.to_str().ok()
+}
+
+/// Creates an empty sheet with all cells set to `0`.
+///
+/// # Arguments
+/// * `rows` - Number of rows (1 ≤ rows ≤ 999).
+/// * `cols` - Number of columns (1 ≤ cols ≤ 18278).
+///
+/// # Returns
+/// The new sheet, or null if the size is out of range. Release it with `sheet_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn sheet_new(rows: usize, cols: usize) -> *mut Sheet {
+    if !(1..=MAX_ROWS).contains(&rows) || !(1..=MAX_COLS).contains(&cols) {
+        return std::ptr::null_mut();
+    }
+    Box::into_raw(Box::new(Sheet { rows, cols, data: SheetData::new(rows + 1, cols) }))
+}
+
+/// Releases a sheet created with `sheet_new`. Passing null does nothing.
+///
+/// # Safety
+/// `sheet` must be null or a pointer returned by `sheet_new` that was not freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sheet_free(sheet: *mut Sheet) {
+    if !sheet.is_null() {
+        drop(unsafe { Box::from_raw(sheet) });
+    }
+}
+
+/// Assigns an expression to a cell (e.g. `"A1+10"` to `"B2"`) and recalculates the cells depending
+/// on it.
+///
+/// # Returns
+/// `SHEET_OK`, `SHEET_ERR` if the cell was set but divides by zero, `SHEET_LOOP` or `SHEET_INVALID`.
+///
+/// # Safety
+/// `sheet` must come from `sheet_new`; `addr` and `expression` must be NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sheet_set_cell(sheet: *mut Sheet, addr: *const c_char, expression: *const c_char) -> c_int {
+    let (Some(sheet), Some(addr), Some(expression)) = (unsafe { sheet.as_mut() }, unsafe { read_str(addr) }, unsafe { read_str(expression) }) else {
+        return SHEET_INVALID;
+    };
+    match execute_command(&format!("{}={}", addr, expression), sheet.rows, sheet.cols, &mut sheet.data) {
+        0 => SHEET_OK,
+        -2 => SHEET_ERR,
+        -4 => SHEET_LOOP,
+        _ => SHEET_INVALID,
+    }
+}
+
+/// Reads the value of a cell into `value`.
+///
+/// # Returns
+/// `SHEET_OK`, `SHEET_ERR` if the cell holds `ERR`, or `SHEET_INVALID`.
+///
+/// # Safety
+/// `sheet` must come from `sheet_new`, `addr` must be a NUL-terminated string and `value` must point
+/// to writable memory for an `int`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sheet_get_value(sheet: *const Sheet, addr: *const c_char, value: *mut c_int) -> c_int {
+    let (Some(sheet), Some(addr)) = (unsafe { sheet.as_ref() }, unsafe { read_str(addr) }) else {
+        return SHEET_INVALID;
+    };
+    if value.is_null() {
+        return SHEET_INVALID;
+    }
+    let Some((row, col)) = sheet.position(addr) else {
+        return SHEET_INVALID;
+    };
+    let cell = sheet.data.sheet[row][col].borrow();
+    if cell.status != 0 {
+        return SHEET_ERR;
+    }
+    unsafe { *value = cell.val };
+    SHEET_OK
+}
+
+/// Evaluates an expression against the sheet (e.g. `"SUM(A1:B3)"`) without assigning it to a cell.
+///
+/// # Returns
+/// `SHEET_OK` with the result in `value`, `SHEET_ERR` on a division by zero, or `SHEET_INVALID`.
+///
+/// # Safety
+/// `sheet` must come from `sheet_new`, `expression` must be a NUL-terminated string and `value` must
+/// point to writable memory for an `int`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sheet_evaluate(sheet: *mut Sheet, expression: *const c_char, value: *mut c_int) -> c_int {
+    let (Some(sheet), Some(expression)) = (unsafe { sheet.as_mut() }, unsafe { read_str(expression) }) else {
+        return SHEET_INVALID;
+    };
+    if value.is_null() {
+        return SHEET_INVALID;
+    }
+    let mut result = 0;
+    // Evaluating from the spare row without recording dependencies leaves every cell as it was
+    match evaluate_expression(expression, sheet.rows, sheet.cols, &mut sheet.data, &mut result, &sheet.rows, &0, 0) {
+        0 => {
+            unsafe { *value = result };
+            SHEET_OK
+        }
+        -2 => SHEET_ERR,
+        _ => SHEET_INVALID,
+    }
+}
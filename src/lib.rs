@@ -1,13 +1,21 @@
 // First declare all your modules
 pub mod avl;
 pub mod cell;
+#[cfg(feature = "tui")]
 pub mod extended;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod sheet;
 pub mod stack;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 // If you want to re-export items from these modules to be available directly from the crate root:
 pub use crate::avl::*;
 pub use crate::cell::*;
+#[cfg(feature = "tui")]
 pub use crate::extended::*;
 pub use crate::sheet::*;
 pub use crate::stack::*;
+// Both sheets have a `Cell`; at the crate root it is the basic one, the other is `extended::Cell`
+pub use crate::cell::Cell;
@@ -11,13 +11,13 @@
 use crate::avl::*;
 use crate::cell::*;
 use crate::stack::*;
-use crate::extended::*;
 use regex::Regex;
 use std::time::Instant;
 use std::env;
-use std::io::{self, Write};
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::io::{self, IsTerminal, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::mpsc;
 use std::time::SystemTime;
 
 use std::thread;
@@ -26,16 +26,16 @@ use std::time::Duration;
 /// A static mutable variable to control the spreadsheet's output state.
 /// When set to 1, output is enabled; otherwise, it is disabled.
 pub static mut FLAG: i32 = 1;
-/// A static mutable variable to store the number of rows in the spreadsheet.
-pub static mut R: usize = 0;
-/// A static mutable variable to store the number of columns in the spreadsheet.
-pub static mut C: usize = 0;
 /// A static mutable variable to store the starting row for displaying the spreadsheet.
 pub static mut START_ROW: usize = 0;
 /// A static mutable variable to store the starting column for displaying the spreadsheet.
 pub static mut START_COL: usize = 0;
 /// A static mutable variable to store the maximum length of input strings.
 pub const MAX_INPUT_LEN: usize = 1000;
+/// The largest number of rows a sheet can have; row labels have at most three digits.
+pub const MAX_ROWS: usize = 999;
+/// The largest number of columns a sheet can have; column labels have at most three letters ("ZZZ").
+pub const MAX_COLS: usize = 18278;
 
 use lazy_static::lazy_static;
 
@@ -51,55 +51,41 @@ lazy_static! {
 /// # Arguments
 /// * `dep` - The cell that depends on `c`
 /// * `c` - The dependency cell
-/// * `sheet_data` - The spreadsheet data structure
-pub fn add_dependency(c: &CellRef, dep: &CellRef, sheet_data: &mut SheetData) {
+pub fn add_dependency(c: &CellRef, dep: &CellRef) {
     let existing_deps = {
         let cell = c.borrow();
         cell.dependencies.clone()
     };
 
-    let new_deps = insert(existing_deps, Rc::clone(dep), sheet_data);
+    let new_deps = insert(existing_deps, Arc::clone(dep));
 
     c.borrow_mut().dependencies = new_deps;
 }
 
 
 
-/// Removes all dependencies from cells that depend on the specified `cell1`.
+/// Unlinks a cell from every cell it reads, in both directions.
 ///
-/// This is typically used when a cell's formula is changed or cleared,
-/// and its dependents must be updated to reflect the removal of this dependency.
+/// This is used when a cell's formula is changed or replaced by a constant: each cell it read
+/// stops listing it among the cells to recalculate, and its own list of cells it reads is emptied.
+/// The cells reading this cell are left linked, as their formulas still refer to it.
 ///
 /// # Arguments
 ///
-/// * `cell1` - The cell whose references should be removed from its dependents.
-/// * `row` - The row index of `cell1`, used to locate the reference in other cells.
-/// * `col` - The column index of `cell1`.
+/// * `row` - The row index of the cell.
+/// * `col` - The column index of the cell.
 /// * `sheet_data` - A mutable reference to the spreadsheet data structure.
 ///
 /// # How It Works
 ///
-/// - Iteratively pops each dependent of `cell1`.
-/// - For each dependent cell, removes the reference to `cell1` from its `dependencies` AVL tree.
-/// - Ensures safe mutable access by using `take()` to temporarily extract values,
-///   and then restoring ownership.
-/// - Continues this process until no dependents remain.
+/// - Pops the cells it reads off its `dependents` stack one at a time, until the stack is empty.
+/// - For each of them, removes this cell from its `dependencies` AVL tree.
 ///
 pub fn delete_dependencies( row: usize, col: usize, sheet_data: &mut SheetData) {
-    let cell1 = &sheet_data.sheet[row][col];
-    loop {
-        let dependent_node = {
-            let mut cell_borrow = cell1.borrow_mut();
-            match cell_borrow.dependents.take() {
-                Some(node) => node,
-                None => break, // exit loop if no more dependents
-            }
-        };
-        let dependent_ref = dependent_node.borrow();
-        let mut dependent = dependent_ref.cell.borrow_mut();
-        dependent.dependencies = delete_node(dependent.dependencies.take(), row, col, sheet_data);
-
-        pop_dependent(&cell1); // now it's safe to mutably borrow again
+    let cell1 = sheet_data.sheet[row][col].clone();
+    while let Some(read) = pop_dependent(&cell1) {
+        let mut read = read.borrow_mut();
+        read.dependencies = delete_node(read.dependencies.take(), row, col);
     }
 }
 /// Performs a depth-first search (DFS) to detect if a dependency path exists from the
@@ -138,7 +124,8 @@ pub fn dfs(
     sheet_data: &SheetData,
 ) -> bool {
     // Calculate bit indices for the visited ARRAY
-    let index = current_row * unsafe { C } + current_col;
+    let cols = sheet_data.sheet[0].len();
+    let index = current_row * cols + current_col;
     let bit_index = index % 64;
     let vec_index = index / 64;
     
@@ -151,16 +138,16 @@ pub fn dfs(
     visited[vec_index] |= 1 << bit_index;
     
     // Direct check first
-    if Rc::ptr_eq(current, target) {
+    if Arc::ptr_eq(current, target) {
         return true;
     }
     
     // Target coordinates only need to be calculated once
-    let (target_row, target_col) = sheet_data.calculate_row_col(target).unwrap_or((0, 0));
+    let (target_row, target_col) = target.borrow().position();
     
     // Check if direct dependency exists (faster than traversal)
     let cur = current.borrow();
-    if find(&cur.dependencies, target_row, target_col, sheet_data).is_some() {
+    if find(&cur.dependencies, target_row, target_col).is_some() {
         return true;
     }
     
@@ -168,15 +155,15 @@ pub fn dfs(
     let mut stack = vec![cur.dependencies.clone()];
     while let Some(Some(node)) = stack.pop() {
         let dep_cell = &node.borrow().cell;
-        let (dep_row, dep_col) = sheet_data.calculate_row_col(dep_cell).unwrap_or((0, 0));
+        let (dep_row, dep_col) = dep_cell.borrow().position();
         
-        if Rc::ptr_eq(dep_cell, target) ||
+        if Arc::ptr_eq(dep_cell, target) ||
             (dep_row == target_row && dep_col == target_col) {
             return true;
         }
         
         // Check if dep_cell has been visited
-        let dep_index = dep_row * unsafe { C } + dep_col;
+        let dep_index = dep_row * cols + dep_col;
         let dep_bit_index = dep_index % 64;
         let dep_vec_index = dep_index / 64;
         
@@ -217,7 +204,7 @@ pub fn dfs(
 ///
 /// - Initializes a `visited` bit-vector to keep track of explored cells.
 /// - Calls [`dfs`] internally to perform a depth-first traversal through dependencies.
-/// - Uses the `R` and `C` global constants to calculate bit indices for visited tracking.
+/// - Sizes the `visited` bit-vector from the sheet, with one bit per cell.
 pub fn check_loop(
     start: &CellRef,
     target: &CellRef,
@@ -226,20 +213,20 @@ pub fn check_loop(
     sheet_data: &SheetData,
 ) -> bool {
     // Quick check for direct self-reference
-    if Rc::ptr_eq(start, target) {
+    if Arc::ptr_eq(start, target) {
         return true;
     }
     
     // Pre-calculate target position once
-    let (target_row, target_col) = sheet_data.calculate_row_col(target).unwrap_or((0, 0));
+    let (target_row, target_col) = target.borrow().position();
     
     // Check if target is directly in start's dependencies (fast path)
-    if find(&start.borrow().dependencies, target_row, target_col, sheet_data).is_some() {
+    if find(&start.borrow().dependencies, target_row, target_col).is_some() {
         return true;
     }
     
     // Full dependency check
-    let mut visited = vec![0u64; (unsafe { R * C }+63)/64];
+    let mut visited = vec![0u64; sheet_data.flat.len().div_ceil(64)];
     dfs(start, target, &mut visited, start_row, start_col, sheet_data)
 }
 /// Performs a depth-first search to check if any dependency of the current cell
@@ -255,7 +242,6 @@ pub fn check_loop(
 /// * `row1`, `col1` - The top-left corner of the target range.
 /// * `row2`, `col2` - The bottom-right corner of the target range.
 /// * `current_row`, `current_col` - The row and column of the current cell.
-/// * `sheet_data` - A reference to the spreadsheet structure for cell access.
 ///
 /// # Returns
 ///
@@ -278,24 +264,19 @@ pub fn dfs_range(
     current_row: usize,
     current_col: usize,
     // sheet: &mut Vec<Vec<CellRef>>,
-    sheet_data: &SheetData,
 ) -> bool {
     if current_row >= row1 && current_row <= row2 && current_col >= col1 && current_col <= col2 {
         return true;
     }
-    if !visited[current_row * unsafe { C } + current_col] {
-        visited[current_row * unsafe { C } + current_col] = true;
-        let cur = current.borrow();
+    let cur = current.borrow();
+    if !visited[cur.id] {
+        visited[cur.id] = true;
         let mut stack = vec![cur.dependencies.clone()];
         while let Some(Some(node)) = stack.pop() {
             let dep_cell = &node.borrow().cell;
-            // let dep_ptr = dep_cell.as_ptr() as usize - sheet[0][0].as_ptr() as usize;
-            // let dep_row = dep_ptr / std::mem::size_of::<RefCell<Cell>>() / unsafe { C };
-            // let dep_col = dep_ptr / std::mem::size_of::<RefCell<Cell>>() % unsafe { C };
-            let (dep_row , dep_col) = sheet_data.calculate_row_col(dep_cell).unwrap_or((0, 0));
-            // let dep_col = sheet_data.calculate_row_col(dep_cell).unwrap_or((0, 0)).1;
+            let (dep_row, dep_col) = dep_cell.borrow().position();
             if dfs_range(
-                dep_cell, visited, row1, col1, row2, col2, dep_row, dep_col, sheet_data,
+                dep_cell, visited, row1, col1, row2, col2, dep_row, dep_col,
             ) {
                 return true;
             }
@@ -338,7 +319,7 @@ pub fn check_loop_range(
     // sheet: &mut Vec<Vec<CellRef>>,
     sheet_data: &SheetData,
 ) -> bool {
-    let mut visited = vec![false; unsafe { R * C }];
+    let mut visited = vec![false; sheet_data.flat.len()];
     dfs_range(
         start,
         &mut visited,
@@ -348,82 +329,73 @@ pub fn check_loop_range(
         col2,
         start_row,
         start_col,
-        sheet_data,
     )
 }
-/// A utility function to perform depth-first traversal for topological sorting.
+/// Orders `cell` and every cell depending on it, directly or through other cells, topologically.
 ///
-/// This function marks the current cell as visited, traverses all of its
-/// dependencies recursively, and finally pushes the cell onto the stack. It ensures
-/// that all cells it depends on are added to the stack before itself.
+/// This function uses Kahn's algorithm over the graph of dependents (each cell's `dependencies`
+/// tree holds the cells that read it): a cell is only placed once every cell it reads from this
+/// set has been placed. The cells are pushed so that popping the stack returns `cell` first and
+/// each dependent after all of its inputs, e.g. for a diamond A1 → (B1, C1) → D1, D1 comes last.
 ///
 /// # Arguments
 ///
-/// * `cell` - The current cell to process.
+/// * `cell` - The cell to start from.
 /// * `visited` - A mutable boolean vector that tracks whether a cell has already been visited.
-/// * `sheet_data` - A reference to the full spreadsheet data structure.
+///   Cells already marked are left out, and every cell ordered here is marked.
 /// * `stack` - A mutable reference to the stack where sorted cells are pushed.
 ///
 /// # How It Works
 ///
-/// - Calculates the index of the current cell in the 2D spreadsheet.
-/// - If not visited:
-///     - Marks the cell as visited.
-///     - Recursively traverses all dependencies.
-///     - Pushes the current cell to the result stack after its dependencies.
+/// - Collects the cells reachable from `cell` and counts, for each, how many of them it reads.
+/// - Starting from `cell`, takes a cell whose count is zero, appends it to the order and decrements
+///   the count of the cells reading it.
+/// - Pushes the order onto the stack from the last cell to the first.
 pub fn topological_sort_util(
     cell: &CellRef,
     visited: &mut Vec<bool>,
-    sheet_data: &SheetData,
     stack: &mut StackLink,
 ) {
-    if let Some((row, col)) = sheet_data.calculate_row_col(cell) {
-        let index = row * unsafe { C } + col;
+    let index_of = |cell: &CellRef| cell.borrow().id;
 
-        // Skip if already visited
-        if visited[index] {
-            return;
+    // Skip if already visited
+    if visited[index_of(cell)] {
+        return;
+    }
+    visited[index_of(cell)] = true;
+
+    // Count the incoming edges of every cell reached from `cell`
+    let mut in_degree = HashMap::from([(index_of(cell), 0)]);
+    let mut reached = vec![cell.clone()];
+    while let Some(current) = reached.pop() {
+        for dep in direct_dependents(&current) {
+            let index = index_of(&dep);
+            if let Some(count) = in_degree.get_mut(&index) {
+                *count += 1;
+            } else if !visited[index] {
+                visited[index] = true;
+                in_degree.insert(index, 1);
+                reached.push(dep);
+            }
         }
-        
-        visited[index] = true;
+    }
 
-        // Use iterative approach instead of recursion for better performance
-        let mut dep_stack = vec![(cell.borrow().dependencies.clone(), false)];
-        
-        while let Some((node_link, processed)) = dep_stack.pop() {
-            if processed {
-                // Node was processed, add to result stack
-                if let Some(cell_node) = dep_stack.last() {
-                    if let Some(ref node_rc) = cell_node.0 {
-                        push(stack, Rc::clone(&node_rc.borrow().cell));
-                    }
-                }
-                continue;
-            }
-            
-            if let Some(node_rc) = node_link.clone() {
-                let node = node_rc.borrow();
-                
-                // Mark this node for later processing after its dependencies
-                dep_stack.push((node_link, true));
-                
-                // Process dependencies (right to left for stack order)
-                if let Some(right) = node.right.clone() {
-                    dep_stack.push((Some(right), false));
-                }
-                
-                // Process the cell itself
-                topological_sort_util(&node.cell, visited, sheet_data, stack);
-                
-                // Process left subtree
-                if let Some(left) = node.left.clone() {
-                    dep_stack.push((Some(left), false));
+    let mut queue = VecDeque::from([cell.clone()]);
+    let mut order = Vec::with_capacity(in_degree.len());
+    while let Some(current) = queue.pop_front() {
+        for dep in direct_dependents(&current) {
+            if let Some(count) = in_degree.get_mut(&index_of(&dep)).filter(|count| **count > 0) {
+                *count -= 1;
+                if *count == 0 {
+                    queue.push_back(dep);
                 }
             }
         }
-        
-        // Add the current cell to the stack
-        push(stack, Rc::clone(cell));
+        order.push(current);
+    }
+
+    for cell in order.into_iter().rev() {
+        push(stack, cell);
     }
 }
 
@@ -444,8 +416,81 @@ pub fn topological_sort_from_cell(
     stack: &mut StackLink,
 ) {
     // println!("Topological sort from cell");
-    let mut visited = vec![false; unsafe { R * C }];
-    topological_sort_util(start_cell, &mut visited, sheet_data, stack);
+    let mut visited = vec![false; sheet_data.flat.len()];
+    topological_sort_util(start_cell, &mut visited, stack);
+}
+/// Re-evaluates the cells depending on `cell`, directly or through other cells, after its value changed.
+///
+/// The dependents are taken in topological order, so each one is evaluated after the cells it reads.
+/// A dependent is only evaluated if one of the cells it reads changed value or status; when its own
+/// result is unchanged, the cells past it are not marked and keep their values. A cell going from
+/// `ERR` back to a value is a change of status, so fixing the cell that failed clears the `ERR` of
+/// every cell depending on it, however indirectly, without re-entering them.
+///
+/// # Arguments
+///
+/// * `cell` - The cell that was just updated.
+/// * `rows`, `cols` - The size of the sheet.
+/// * `sheet_data` - The spreadsheet data structure.
+pub fn recalculate_dependents(cell: &CellRef, rows: usize, cols: usize, sheet_data: &mut SheetData) {
+    let mut stack = None;
+    topological_sort_from_cell(cell, sheet_data, &mut stack);
+
+    // Remove the updated cell itself from the stack
+    pop(&mut stack);
+
+    let mut dirty = HashSet::new();
+    mark_dependents(cell, &mut dirty);
+    while let Some(dep_cell) = pop(&mut stack) {
+        let (id, r, c) = {
+            let dep = dep_cell.borrow();
+            (dep.id, dep.row, dep.col)
+        };
+        if !dirty.remove(&id) {
+            continue;
+        }
+        let (expr, before) = {
+            let dep = dep_cell.borrow();
+            (dep.expression.clone(), (dep.val, dep.status))
+        };
+        let mut res = 0;
+        match evaluate_expression(&expr, rows, cols, sheet_data, &mut res, &r, &c, 0) {
+            0 | 1 => {
+                let mut cell_mut = sheet_data.sheet[r][c].borrow_mut();
+                cell_mut.val = res;
+                cell_mut.status = 0;
+            },
+            -2 => sheet_data.sheet[r][c].borrow_mut().status = 1,
+            _ => {}
+        }
+        sheet_data.invalidate(r, c);
+        let after = {
+            let dep = dep_cell.borrow();
+            (dep.val, dep.status)
+        };
+        if after != before {
+            mark_dependents(&dep_cell, &mut dirty);
+        }
+    }
+}
+
+/// Adds the ids of the cells that read `cell` directly (its `dependencies` tree) to `dirty`.
+fn mark_dependents(cell: &CellRef, dirty: &mut HashSet<usize>) {
+    dirty.extend(direct_dependents(cell).iter().map(|dep| dep.borrow().id));
+}
+
+/// Returns the cells that read `cell` directly, i.e. the cells in its `dependencies` tree.
+fn direct_dependents(cell: &CellRef) -> Vec<CellRef> {
+    let mut cells = Vec::new();
+    let mut nodes = vec![cell.borrow().dependencies.clone()];
+    while let Some(node) = nodes.pop() {
+        let Some(node) = node else { continue };
+        let node = node.borrow();
+        cells.push(node.cell.clone());
+        nodes.push(node.left.clone());
+        nodes.push(node.right.clone());
+    }
+    cells
 }
 /// Handles scrolling logic for the spreadsheet view based on user input.
 ///
@@ -459,28 +504,29 @@ pub fn topological_sort_from_cell(
 ///     - `"s"`: Scroll down
 ///     - `"a"`: Scroll left
 ///     - `"d"`: Scroll right
+/// * `rows`, `cols` - The size of the sheet.
 ///
 /// # Behavior
 ///
 /// - Updates the global variables `START_ROW` and `START_COL` based on the direction.
-/// - Ensures values remain within the bounds of the spreadsheet defined by `R` and `C`.
+/// - Ensures values remain within the bounds of the spreadsheet, `rows` by `cols`.
 /// - Uses `saturating_sub` to safely handle potential underflows when scrolling near edges.
 ///
 /// # Safety
 ///
 /// This function uses `unsafe` to mutate static mutable variables, so it should be used
 /// with caution and under the assumption of single-threaded context.
-pub fn scroll(input: &str) -> i32 {
+pub fn scroll(input: &str, rows: usize, cols: usize) -> i32 {
     unsafe {
         match input {
             "w" if START_ROW >= 10 => START_ROW -= 10,
             "w" => START_ROW = 0,
-            "s" if START_ROW + 20 <= R - 1 => START_ROW += 10,
-            "s" => START_ROW = R.saturating_sub(10),
+            "s" if START_ROW + 20 < rows => START_ROW += 10,
+            "s" => START_ROW = rows.saturating_sub(10),
             "a" if START_COL >= 10 => START_COL -= 10,
             "a" => START_COL = 0,
-            "d" if START_COL + 20 <= C - 1 => START_COL += 10,
-            "d" => START_COL = C.saturating_sub(10),
+            "d" if START_COL + 20 < cols => START_COL += 10,
+            "d" => START_COL = cols.saturating_sub(10),
             _ => {}
         }
     }
@@ -511,6 +557,7 @@ pub fn sleep_seconds(seconds: u64) {
 /// # Examples
 ///
 /// ```
+/// # use Rust_lab::sheet::label_to_index;
 /// assert_eq!(label_to_index("A1"), Some((0, 0)));
 /// assert_eq!(label_to_index("AA10"), Some((9, 26)));
 /// assert_eq!(label_to_index("ZZZ999"), Some((998, 18277)));
@@ -589,6 +636,7 @@ pub fn label_to_index(label: &str) -> Option<(usize, usize)> {
 /// # Examples
 ///
 /// ```
+/// # use Rust_lab::sheet::col_label_to_index;
 /// assert_eq!(col_label_to_index("A"), Some(0));
 /// assert_eq!(col_label_to_index("Z"), Some(25));
 /// assert_eq!(col_label_to_index("AA"), Some(26));
@@ -623,6 +671,7 @@ pub fn col_label_to_index(label: &str) -> Option<usize> {
 /// # Examples
 ///
 /// ```
+/// # use Rust_lab::sheet::col_index_to_label;
 /// assert_eq!(col_index_to_label(0), "A");
 /// assert_eq!(col_index_to_label(25), "Z");
 /// assert_eq!(col_index_to_label(26), "AA");
@@ -668,10 +717,12 @@ pub fn col_index_to_label(mut index: usize) -> String {
 /// ...
 /// ```
 pub fn print_sheet(sheet: &Vec<Vec<CellRef>>) {
+    let rows = sheet.len();
+    let cols = sheet.first().map_or(0, Vec::len);
     unsafe {
         print!("\t");
         for col in START_COL..START_COL + 10 {
-            if col >= C {
+            if col >= cols {
                 break;
             }
             let label = col_index_to_label(col);
@@ -680,12 +731,12 @@ pub fn print_sheet(sheet: &Vec<Vec<CellRef>>) {
         println!("");
 
         for row in START_ROW..START_ROW + 10 {
-            if row >= R {
+            if row >= rows {
                 break;
             }
             print!("{}\t", row + 1);
             for col in START_COL..START_COL + 10 {
-                if col >= C {
+                if col >= cols {
                     break;
                 }
                 let cell = sheet[row][col].borrow();
@@ -718,7 +769,7 @@ pub fn print_sheet(sheet: &Vec<Vec<CellRef>>) {
 ///
 /// # Examples
 ///
-/// ```rust
+/// ```ignore
 /// assert_eq!(split_label_and_number("A1"), Some(("A".to_string(), "1".to_string())));
 /// assert_eq!(split_label_and_number("AB12"), Some(("AB".to_string(), "12".to_string())));
 /// assert_eq!(split_label_and_number("A1B"), None);
@@ -762,7 +813,8 @@ fn split_label_and_number(s: &str) -> Option<(String, String)> {
 /// Returns an integer status code:
 /// * `0`: Success
 /// * `-1`: Invalid expression
-/// * `-2`: Division by Zero error to set status to 1
+/// * `-2`: Division by Zero error to set status to 1; also given by an aggregate over an empty range
+///   or whose result does not fit in a cell
 /// * `-4`: Circular dependency detected
 ///
 /// # Functionality
@@ -790,7 +842,9 @@ fn split_label_and_number(s: &str) -> Option<(String, String)> {
 /// - Evaluates the expression recursively, handling binary operations.
 /// - Updates dependencies in the spreadsheet data structure.
 /// - Checks for circular references using a depth-first search.
-/// - Handles special cases like SUM, AVG, MAX, MIN, STDEV functions.
+/// - Handles special cases like SUM, AVG, MAX, MIN, STDEV functions. When recalculating
+///   (`call_value` 0), SUM and AVG read the per-column prefix sums of `SheetData` rather than
+///   every cell of the range.
 /// - Updates the result value and the cell's status accordingly.
 pub fn evaluate_expression(
     expr: &str,
@@ -905,7 +959,6 @@ pub fn evaluate_expression(
             add_dependency(
                 from_cell,
                 &(sheet_data.sheet)[*row][*col].clone(),
-                sheet_data,
             );
             push_dependent(
                 &(sheet_data.sheet)[*row][*col],
@@ -1020,14 +1073,14 @@ pub fn evaluate_expression(
             if row1 >= 0 {
                 // let dep_cell1 = (sheet_data.sheet)[row1 as usize][col1 as usize].clone();
                 let from_cell = &(sheet_data.sheet)[row1 as usize][col1 as usize].clone();
-                add_dependency(from_cell,to_cell, sheet_data);
+                add_dependency(from_cell,to_cell);
                 push_dependent(&(sheet_data.sheet)[*row][*col], &(sheet_data.sheet)[row1 as usize][col1 as usize]);
             }
 
             if row2 >= 0 && (col2 != col1 || row2 != row1) {
                 // let dep_cell2 = (sheet_data.sheet)[row2 as usize][col2 as usize].clone();
                 let from_cell = &(sheet_data.sheet)[row2 as usize][col2 as usize].clone();
-                add_dependency(from_cell,to_cell, sheet_data);
+                add_dependency(from_cell,to_cell);
                 push_dependent(&(sheet_data.sheet)[*row][*col], &(sheet_data.sheet)[row2 as usize][col2 as usize]);
             }
         }
@@ -1128,9 +1181,28 @@ pub fn evaluate_expression(
                 return -4; // Circular dependency detected
             }
 
+            // Recalculations read the cached column prefix sums instead of every cell of the range
+            if call_value == 0 && (func == "SUM" || func == "AVG") {
+                let (mut total, mut errors) = (0i64, 0u32);
+                for j in col1..=col2 {
+                    let (sum, count) = sheet_data.column_sum(j, row1 as usize, row2 as usize);
+                    total += sum;
+                    errors += count;
+                }
+                let value = if func == "AVG" { total.checked_div(i64::from(row2 - row1 + 1) * (col2 - col1 + 1) as i64) } else { Some(total) };
+                match value.and_then(|value| i32::try_from(value).ok()) {
+                    Some(value) => *result = value,
+                    None => return -2, // Empty range or a result outside the i32 range
+                }
+                if errors > 0 {
+                    return -2; // Error in dependents
+                }
+                return 0;
+            }
+
             // Handle SUM function
             if func == "SUM" {
-                *result = 0;
+                let mut total = 0i64;
                 if call_value == 1 {
                     delete_dependencies(
                         *row,
@@ -1146,14 +1218,13 @@ pub fn evaluate_expression(
                             if cell.status == 1 {
                                 count_status += 1;
                             }
-                            *result += cell.val;
+                            total += cell.val as i64;
                         }
                         let from_cell = &(sheet_data.sheet)[i as usize][j as usize].clone();
                         if call_value == 1 {
                             add_dependency(
                                 from_cell,
                                 to_cell,
-                                sheet_data,
                             );
                             push_dependent(
                                 &(sheet_data.sheet)[*row as usize][*col as usize],
@@ -1163,6 +1234,10 @@ pub fn evaluate_expression(
                     }
                 }
 
+                match i32::try_from(total) {
+                    Ok(value) => *result = value,
+                    Err(_) => return -2, // The sum does not fit in a cell
+                }
                 if count_status > 0 {
                     return -2; // Error in dependents
                 }
@@ -1171,8 +1246,8 @@ pub fn evaluate_expression(
 
             // Handle AVG function
             if func == "AVG" {
-                *result = 0;
-                let mut count = 0;
+                let mut total = 0i64;
+                let mut count = 0i64;
 
                 if call_value == 1 {
                     //let mut cell = sheet[*row as usize][*col as usize].borrow_mut();
@@ -1190,7 +1265,7 @@ pub fn evaluate_expression(
                             if cell.status == 1 {
                                 count_status += 1;
                             }
-                            *result += cell.val;
+                            total += cell.val as i64;
                             count += 1;
                         }
                         let from_cell = &(sheet_data.sheet)[i as usize][j as usize].clone();
@@ -1198,7 +1273,6 @@ pub fn evaluate_expression(
                             add_dependency(
                                 from_cell,
                                 to_cell,
-                                sheet_data,
                             );
                             push_dependent(
                                 &(sheet_data.sheet)[*row as usize][*col as usize],
@@ -1208,7 +1282,11 @@ pub fn evaluate_expression(
                     }
                 }
 
-                *result /= count;
+                // An empty range has no average
+                match total.checked_div(count).and_then(|value| i32::try_from(value).ok()) {
+                    Some(value) => *result = value,
+                    None => return -2,
+                }
 
                 if count_status > 0 {
                     return -2; // Error in dependents
@@ -1234,7 +1312,6 @@ pub fn evaluate_expression(
                             add_dependency(
                                 from_cell,
                                 to_cell,
-                                sheet_data,
                             );
                             push_dependent(
                                 &(sheet_data.sheet)[*row as usize][*col as usize],
@@ -1281,7 +1358,6 @@ pub fn evaluate_expression(
                             add_dependency(
                                 from_cell,
                                 to_cell,
-                                sheet_data,
                             );
                             push_dependent(
                                 &(sheet_data.sheet)[*row as usize][*col as usize],
@@ -1299,8 +1375,8 @@ pub fn evaluate_expression(
 
             // Handle STDEV function
             if func == "STDEV" {
-                let mut sum = 0;
-                let mut count = 0;
+                let mut sum = 0i64;
+                let mut count = 0i64;
                 if call_value == 1 {
                     delete_dependencies(
                         *row,
@@ -1316,7 +1392,7 @@ pub fn evaluate_expression(
                             if cell.status == 1 {
                                 count_status += 1;
                             }
-                            sum += cell.val;
+                            sum += cell.val as i64;
                             count += 1;
                         }
                         let from_cell = &(sheet_data.sheet)[i as usize][j as usize].clone();
@@ -1324,7 +1400,6 @@ pub fn evaluate_expression(
                             add_dependency(
                                 from_cell,
                                 to_cell,
-                                sheet_data,
                             );
                             push_dependent(
                                 &(sheet_data.sheet)[*row as usize][*col as usize],
@@ -1334,12 +1409,15 @@ pub fn evaluate_expression(
                     }
                 }
 
-                let mean: i32 = sum / count;
+                // An empty range has no standard deviation
+                let Some(mean) = sum.checked_div(count) else {
+                    return -2;
+                };
                 let mut variance: f64 = 0.0;
 
                 for i in row1..=row2 {
                     for j in col1..=col2 {
-                        variance += (((sheet_data.sheet)[i as usize][j as usize].borrow().val - mean).pow(2)) as f64;
+                        variance += (((sheet_data.sheet)[i as usize][j].borrow().val as i64 - mean) as f64).powi(2);
                     }
                 }
 
@@ -1418,7 +1496,6 @@ pub fn evaluate_expression(
             add_dependency(
                 &(sheet_data.sheet)[row1 as usize][col1].clone(),
                 &(sheet_data.sheet)[*row][*col].clone(),
-                sheet_data,
             );
             push_dependent(
                 &(sheet_data.sheet)[*row][*col],
@@ -1472,7 +1549,7 @@ pub fn execute_command(input: &str, rows: usize, cols: usize, sheet_data: &mut S
     // Quick check for common commands
     match input {
         "q" => return 1,
-        "w" | "s" | "a" | "d" => return scroll(input),
+        "w" | "s" | "a" | "d" => return scroll(input, rows, cols),
         "disable_output" => {
             unsafe { FLAG = 0; }
             return 0;
@@ -1541,36 +1618,9 @@ pub fn execute_command(input: &str, rows: usize, cols: usize, sheet_data: &mut S
                     cell_mut.expression = expr.trim().to_string();
                     cell_mut.status = 0;
                 }
+                sheet_data.invalidate(row, col);
                 
-                // Update dependents using topological sort
-                let mut stack = None;
-                topological_sort_from_cell(&cell, sheet_data, &mut stack);
-                
-                // Remove the current cell from stack since we just updated it
-                pop(&mut stack);
-                
-                // Process dependents in topological order
-                while let Some(dep_cell) = pop(&mut stack) {
-                    if let Some((r, c)) = sheet_data.calculate_row_col(&dep_cell) {
-                        // Avoid multiple borrows
-                        let expr = dep_cell.borrow().expression.clone();
-                
-                        let mut res = 0;
-                
-                        match evaluate_expression(&expr, rows, cols, sheet_data, &mut res, &r, &c, 0) {
-                            0 | 1 => {
-                                let mut cell_mut = sheet_data.sheet[r][c].borrow_mut();
-                                cell_mut.val = res;
-                                cell_mut.status = 0;
-                            },
-                            -2 => {
-                                sheet_data.sheet[r][c].borrow_mut().status = 1;
-                            },
-                            _ => {}
-                        }
-                    }
-                }
-                
+                recalculate_dependents(&cell, rows, cols, sheet_data);
                 
                 return 0;
             },
@@ -1585,31 +1635,9 @@ pub fn execute_command(input: &str, rows: usize, cols: usize, sheet_data: &mut S
                     cell_mut.expression = expr.trim().to_string();
                     cell_mut.status = 1;
                 }
+                sheet_data.invalidate(row, col);
                 
-                // Update dependents using topological sort
-                let mut stack = None;
-                topological_sort_from_cell(&cell, sheet_data, &mut stack);
-                
-                // Skip current cell
-                pop(&mut stack);
-                
-                // Process dependents
-                while let Some(dep_cell) = pop(&mut stack) {
-                    if let Some((r, c)) = sheet_data.calculate_row_col(&dep_cell) {
-                        let expr = dep_cell.borrow().expression.clone();
-                        let mut res = 0;
-                        
-                        match evaluate_expression(&expr, rows, cols, sheet_data, &mut res, &r, &c, 0) {
-                            0 | 1 => {
-                                let mut cell_mut = (sheet_data.sheet)[r][c].borrow_mut();
-                                cell_mut.val = res;
-                                cell_mut.status = 0;
-                            },
-                            -2 => (sheet_data.sheet)[r][c].borrow_mut().status = 1,
-                            _ => {}
-                        }
-                    }
-                }
+                recalculate_dependents(&cell, rows, cols, sheet_data);
                 return -2;
             },
             code => return code, // Return error codes directly
@@ -1619,38 +1647,120 @@ pub fn execute_command(input: &str, rows: usize, cols: usize, sheet_data: &mut S
     -1  // Invalid command
 }
 
-/// Entry point for the spreadsheet program.
+/// A job run by a [`SheetWorker`] on its sheet.
+type SheetJob = Box<dyn FnOnce(&mut SheetData) + Send>;
+
+/// A sheet recalculated on a thread of its own, so the thread reading commands is never held up.
+///
+/// `SheetData` is `Send`: the sheet is built by the caller and moved to the worker, which owns it from
+/// then on. Only jobs and their results cross over, and jobs run one at a time, in the order they were
+/// sent. The caller can poll the receiver of a job (e.g. with `try_recv`) and handle input meanwhile.
+pub struct SheetWorker {
+    rows: usize,
+    cols: usize,
+    jobs: Option<mpsc::Sender<SheetJob>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SheetWorker {
+    /// Starts a worker thread owning an empty sheet of `rows` by `cols` cells.
+    pub fn spawn(rows: usize, cols: usize) -> Self {
+        Self::with_sheet(SheetData::new(rows, cols))
+    }
+
+    /// Starts a worker thread owning `sheet_data`, e.g. a sheet filled in before.
+    pub fn with_sheet(mut sheet_data: SheetData) -> Self {
+        let (rows, cols) = (sheet_data.sheet.len(), sheet_data.sheet.first().map_or(0, Vec::len));
+        let (jobs, queue) = mpsc::channel::<SheetJob>();
+        let thread = thread::spawn(move || {
+            for job in queue {
+                job(&mut sheet_data);
+            }
+        });
+        SheetWorker { rows, cols, jobs: Some(jobs), thread: Some(thread) }
+    }
+
+    /// Queues `job` to run on the worker's sheet and returns at once.
+    ///
+    /// # Returns
+    /// A receiver for the job's result; it reports an error instead if the worker has stopped.
+    pub fn submit<T, F>(&self, job: F) -> mpsc::Receiver<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut SheetData) -> T + Send + 'static,
+    {
+        let (result, receiver) = mpsc::channel();
+        if let Some(jobs) = &self.jobs {
+            // A send fails only once the worker has stopped, and then the receiver reports it
+            let _ = jobs.send(Box::new(move |sheet_data: &mut SheetData| {
+                let _ = result.send(job(sheet_data));
+            }));
+        }
+        receiver
+    }
+
+    /// Queues a command for [`execute_command`] on the worker's sheet and returns at once.
+    ///
+    /// # Returns
+    /// A receiver for the command's status.
+    pub fn execute(&self, input: &str) -> mpsc::Receiver<i32> {
+        let (input, rows, cols) = (input.to_string(), self.rows, self.cols);
+        self.submit(move |sheet_data| execute_command(&input, rows, cols, sheet_data))
+    }
+}
+
+impl Drop for SheetWorker {
+    /// Lets the worker finish the jobs already sent, then waits for its thread to end.
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// How often (in milliseconds) `run` checks whether the command on the [`SheetWorker`] is done.
+const POLL_INTERVAL_MS: u64 = 50;
+/// How long (in milliseconds) a command runs before `run` says the sheet is being recalculated.
+const BUSY_NOTICE_MS: u64 = 200;
+
+/// Reads lines from standard input on a thread of their own, so `run` can keep polling the worker
+/// while it waits for the next command. The channel closes at the end of the input.
+fn read_lines() -> mpsc::Receiver<String> {
+    let (lines, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines().map_while(io::Result::ok) {
+            if lines.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Runs the basic spreadsheet program on the process's command-line arguments.
 ///
 /// This program initializes a spreadsheet with a specified number of rows and columns
-/// passed as command-line arguments. It supports both a default mode and a special `-vim` mode
-/// (triggering an extended version of the application).
+/// passed as command-line arguments. The Vim-style editor is `extended::main`, which the
+/// `Rust_lab` binary runs.
 ///
 /// # Command-Line Arguments
 /// - `<rows>`: Number of rows in the spreadsheet (1 ≤ rows ≤ 999).
 /// - `<columns>`: Number of columns in the spreadsheet (1 ≤ columns ≤ 18278).
-/// - `-vim`: Optional flag to run in extended mode (`extended::run_extended()`).
 ///
 /// # Behavior
 /// - Parses arguments and validates input sizes.
-/// - Initializes the spreadsheet data.
+/// - Initializes the spreadsheet data and moves it to a [`SheetWorker`] thread.
 /// - Displays the spreadsheet initially and after each successful command (if output is enabled).
-/// - Accepts commands in a loop via standard input.
-/// - Processes commands using `execute_command`.
+/// - Accepts commands in a loop via standard input, read on a thread of their own.
+/// - Processes commands using `execute_command` on the worker and polls for the result. While a command
+///   runs, it notes on the terminal that the sheet is being recalculated, queues the commands typed
+///   meanwhile, and quits at once on `q` typed at a terminal.
 /// - Displays execution time and command result status (`ok`, `Loop Detected!`, or `Invalid Input`).
 /// - Exits when `"q"` command is entered.
-
-fn main() {
+pub fn run() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() > 1 && args[1] == "-vim" {
-        // Call the extended version's main function
-        if let Err(err) = run_extended() {
-            eprintln!("Error in extended mode: {}", err);
-            std::process::exit(-1);
-        }
-        return;
-    }
-
     if args.len() != 3 {
         eprintln!("Usage: {} <No. of rows> <No. of columns>", args[0]);
         std::process::exit(-1);
@@ -1666,56 +1776,75 @@ fn main() {
         std::process::exit(-1);
     });
 
-    unsafe {
-        R = r;
-        C = c;
-    }
-
-    if r < 1 || r > 999 {
-        eprintln!("Invalid Input < 1<=R<=999 >");
+    if !(1..=MAX_ROWS).contains(&r) {
+        eprintln!("Invalid Input < 1<=R<={} >", MAX_ROWS);
         std::process::exit(-1);
     }
 
-    if c < 1 || c > 18278 {
-        eprintln!("Invalid Input < 1<=C<=18278 >");
+    if !(1..=MAX_COLS).contains(&c) {
+        eprintln!("Invalid Input < 1<=C<={} >", MAX_COLS);
         std::process::exit(-1);
     }
 
     let start_time = SystemTime::now();
-    let mut sheet_data = SheetData::new(r, c);
-    // create_sheet(&mut sheet);
-    print_sheet(&(sheet_data.sheet));
+    // The sheet lives on the worker, so a long recalculation never blocks this thread
+    let worker = SheetWorker::spawn(r, c);
+    let _ = worker.submit(|sheet_data| print_sheet(&(sheet_data.sheet))).recv();
 
     let elapsed = start_time.elapsed().unwrap().as_secs_f64();
     print!("[{:.2}] (ok) > ", elapsed);
     io::stdout().flush().unwrap();
 
-    let stdin = io::stdin();
-    let mut input = String::with_capacity(MAX_INPUT_LEN);
+    let lines = read_lines();
+    // Commands typed while another one was still running, in the order they were typed
+    let mut typed_ahead: VecDeque<String> = VecDeque::new();
 
     loop {
-        input.clear();
-        if stdin.read_line(&mut input).is_err() {
-            break;
-        }
-
-        input = input.trim_end().to_string();
-        let start = Instant::now();
-
-        let status = unsafe { execute_command(&input, R, C, &mut sheet_data) };
+        let input = match typed_ahead.pop_front() {
+            Some(input) => input,
+            None => match lines.recv() {
+                Ok(input) => input,
+                Err(_) => break,
+            },
+        };
+        let input = input.trim_end().to_string();
+        let started = Instant::now();
+
+        let result = worker.submit(move |sheet_data| {
+            let start = Instant::now();
+            let status = execute_command(&input, r, c, sheet_data);
+            let time_taken = start.elapsed().as_secs_f64();
+            if status != 1 && unsafe { FLAG } == 1 {
+                print_sheet(&(sheet_data.sheet));
+            }
+            (status, time_taken)
+        });
+
+        let mut noticed = false;
+        let (status, time_taken) = loop {
+            match result.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+                Ok(outcome) => break outcome,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if !noticed && started.elapsed() >= Duration::from_millis(BUSY_NOTICE_MS) && io::stderr().is_terminal() {
+                        eprintln!("Recalculating... (q to quit)");
+                        noticed = true;
+                    }
+                    // At a terminal, q quits without waiting; piped commands all run in order
+                    while let Ok(line) = lines.try_recv() {
+                        if line.trim_end() == "q" && io::stdin().is_terminal() {
+                            std::process::exit(0);
+                        }
+                        typed_ahead.push_back(line);
+                    }
+                }
+            }
+        };
 
         if status == 1 {
             break;
         }
 
-        let time_taken = start.elapsed().as_secs_f64();
-
-        unsafe {
-            if FLAG == 1 {
-                print_sheet(&(sheet_data.sheet));
-            }
-        }
-
         match status {
             0 | -2 => print!("[{:.8}] (ok) > ", time_taken),
             -4 => print!("[{:.2}] (Loop Detected!) > ", time_taken),
@@ -6,18 +6,14 @@
 //! `StackNode` elements, where each node holds a reference to a `Cell` and a link
 //! to the next node. It enables efficient tracking and manipulation of dependent cells
 //! during evaluation or updates.
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::Arc;
 use crate::cell::*;
 
-/// Type alias for shared, mutable reference to a `Cell`
-// pub type CellRef = Rc<RefCell<Cell>>;
-
 /// Type alias for a link in the stack
 /// 
-/// This alias represents a stack node as an `Option<Rc<RefCell<StackNode>>>`. Each node in the stack
+/// This alias represents a stack node as an `Option<Arc<Shared<StackNode>>>`. Each node in the stack
 /// contains a `CellRef` (reference to a `Cell`) and a reference to the next node in the stack.
-pub type StackLink = Option<Rc<RefCell<StackNode>>>;
+pub type StackLink = Option<Arc<Shared<StackNode>>>;
 
 /// Stack node structure
 /// 
@@ -39,7 +35,18 @@ impl StackNode {
     /// # Returns
     /// * A `StackLink` (a wrapped `StackNode`) containing the provided `cell` and `next` node.
     pub fn new(cell: CellRef, next: StackLink) -> StackLink {
-        Some(Rc::new(RefCell::new(StackNode { cell, next })))
+        Some(Arc::new(Shared::new(StackNode { cell, next })))
+    }
+}
+
+impl Drop for StackNode {
+    /// Unlinks the rest of the stack one node at a time, so dropping a long stack does not recurse
+    /// once per node. The walk stops at the first node another stack still points to.
+    fn drop(&mut self) {
+        let mut next = self.next.take();
+        while let Some(mut node) = next {
+            next = Arc::get_mut(&mut node).and_then(|node| node.get_mut().next.take());
+        }
     }
 }
 
@@ -0,0 +1,126 @@
+//! # WebAssembly bindings for the spreadsheet engine
+//!
+//! This module exposes the evaluation engine (`SheetData` and `execute_command`) to JavaScript
+//! through `wasm-bindgen`, so a web frontend can enter formulas and read computed values without
+//! the terminal editor. It is only built with the `wasm` feature, typically together with
+//! `--no-default-features` so the terminal, audio and PDF dependencies are left out:
+//!
+//! ```text
+//! wasm-pack build --target web -- --no-default-features --features wasm
+//! ```
+//!
+//! From JavaScript:
+//!
+//! ```text
+//! const sheet = new WasmSheet(10, 10);
+//! sheet.set_cell("A1", "5");
+//! sheet.set_cell("B1", "SUM(A1:A3)");
+//! sheet.get_value("B1"); // 5
+//! ```
+use crate::avl::SheetData;
+use crate::sheet::{col_index_to_label, execute_command, label_to_index, MAX_COLS, MAX_ROWS};
+use wasm_bindgen::prelude::*;
+
+/// A spreadsheet driven from JavaScript.
+#[wasm_bindgen]
+pub struct WasmSheet {
+    rows: usize,
+    cols: usize,
+    data: SheetData,
+}
+
+#[wasm_bindgen]
+impl WasmSheet {
+    /// Creates an empty sheet with all cells set to `0`.
+    ///
+    /// # Arguments
+    /// * `rows` - Number of rows (1 ≤ rows ≤ 999).
+    /// * `cols` - Number of columns (1 ≤ cols ≤ 18278).
+    #[wasm_bindgen(constructor)]
+    pub fn new(rows: usize, cols: usize) -> Result<WasmSheet, JsValue> {
+        if !(1..=MAX_ROWS).contains(&rows) || !(1..=MAX_COLS).contains(&cols) {
+            return Err(JsValue::from_str("Invalid sheet size"));
+        }
+        Ok(WasmSheet { rows, cols, data: SheetData::new(rows, cols) })
+    }
+
+    /// Assigns an expression to a cell (e.g. `set_cell("B2", "A1+10")`) and recalculates the cells
+    /// depending on it. A division by zero is not an error here: the cell is set and reads as `ERR`.
+    ///
+    /// # Arguments
+    /// * `addr` - The cell label, e.g. `"B2"`.
+    /// * `expression` - A number, a cell reference, an arithmetic expression or a function such as `SUM(A1:A3)`.
+    ///
+    /// # Returns
+    /// An error with `"Invalid Input"` or `"Loop Detected!"` if the expression was refused.
+    pub fn set_cell(&mut self, addr: &str, expression: &str) -> Result<(), JsValue> {
+        match execute_command(&format!("{}={}", addr, expression), self.rows, self.cols, &mut self.data) {
+            0 | -2 => Ok(()),
+            -4 => Err(JsValue::from_str("Loop Detected!")),
+            _ => Err(JsValue::from_str("Invalid Input")),
+        }
+    }
+
+    /// Returns the value of a cell, or `None` if the cell is `ERR` or the label is not in the sheet.
+    ///
+    /// # Arguments
+    /// * `addr` - The cell label, e.g. `"B2"`.
+    pub fn get_value(&self, addr: &str) -> Option<i32> {
+        let (row, col) = self.position(addr)?;
+        let cell = self.data.sheet[row][col].borrow();
+        (cell.status == 0).then_some(cell.val)
+    }
+
+    /// Returns whether a cell is `ERR` (e.g. after a division by zero).
+    ///
+    /// # Arguments
+    /// * `addr` - The cell label, e.g. `"B2"`.
+    pub fn is_error(&self, addr: &str) -> bool {
+        self.position(addr).is_some_and(|(row, col)| self.data.sheet[row][col].borrow().status != 0)
+    }
+
+    /// Returns the expression assigned to a cell, or an empty string if it has none.
+    ///
+    /// # Arguments
+    /// * `addr` - The cell label, e.g. `"B2"`.
+    pub fn get_expression(&self, addr: &str) -> String {
+        self.position(addr).map_or_else(String::new, |(row, col)| self.data.sheet[row][col].borrow().expression.clone())
+    }
+
+    /// Recalculates every cell that has an expression, in row-major order.
+    ///
+    /// # Returns
+    /// The number of cells that were recalculated.
+    pub fn recalc(&mut self) -> usize {
+        let mut count = 0;
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                let expression = self.data.sheet[row][col].borrow().expression.clone();
+                if expression.is_empty() {
+                    continue;
+                }
+                let label = format!("{}{}", col_index_to_label(col), row + 1);
+                execute_command(&format!("{}={}", label, expression), self.rows, self.cols, &mut self.data);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Returns the number of rows.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Returns the number of columns.
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+}
+
+impl WasmSheet {
+    /// Converts a cell label into a `(row, col)` inside this sheet.
+    fn position(&self, addr: &str) -> Option<(usize, usize)> {
+        label_to_index(addr).filter(|&(row, col)| row < self.rows && col < self.cols)
+    }
+}
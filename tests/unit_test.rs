@@ -1,5 +1,6 @@
-use prisha_rust_lab::*; // replace with your actual crate name
+use Rust_lab::*;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Instant;
 
 #[test]
@@ -16,13 +17,13 @@ fn test_large_tree() {
                 // Insert even columns
                 let row = (i + offset) % 10;
                 let col = j;
-                root = insert(root, sheet_data.get(row, col), &sheet_data);
+                root = insert(root, sheet_data.get(row, col));
             }
             for j in (1..10).step_by(2) {
                 // Insert odd columns
                 let row = (i + offset) % 10;
                 let col = j;
-                root = insert(root, sheet_data.get(row, col), &sheet_data);
+                root = insert(root, sheet_data.get(row, col));
             }
         }
     }
@@ -30,39 +31,64 @@ fn test_large_tree() {
     // Verify all cells are in the tree
     for i in 0..10 {
         for j in 0..10 {
-            assert!(find(&root, i, j, &sheet_data).is_some());
+            assert!(find(&root, i, j).is_some());
         }
     }
 
     // Delete half the nodes
     for i in 0..5 {
         for j in 0..10 {
-            root = delete_node(root, i, j, &sheet_data);
+            root = delete_node(root, i, j);
         }
     }
 
     // Verify deleted nodes are gone
     for i in 0..5 {
         for j in 0..10 {
-            assert!(find(&root, i, j, &sheet_data).is_none());
+            assert!(find(&root, i, j).is_none());
         }
     }
 
     // Verify remaining nodes are still there
     for i in 5..10 {
         for j in 0..10 {
-            assert!(find(&root, i, j, &sheet_data).is_some());
+            assert!(find(&root, i, j).is_some());
         }
     }
 }
 
 #[test]
-fn test_check_loop() {
-    // Make sure R and C are properly set before creating SheetData
-    unsafe {
-        R = 5;
-        C = 5;
+fn test_deep_dependency_structures() {
+    let sheet_data = SheetData::new(200, 200);
+
+    // Tens of thousands of dependencies in one tree, inserted in order
+    let mut root = None;
+    for row in 0..200 {
+        for col in 0..200 {
+            root = insert(root, sheet_data.get(row, col));
+        }
+    }
+    assert!(root.as_ref().unwrap().borrow().height <= 24);
+    assert!(find(&root, 123, 45).is_some());
+    for row in (0..200).step_by(2) {
+        for col in 0..200 {
+            root = delete_node(root, row, col);
+        }
     }
+    assert!(find(&root, 122, 45).is_none());
+    assert!(find(&root, 123, 45).is_some());
+    drop(root);
+
+    // A long dependents stack is dropped with its cell
+    let cell = Cell::new(0, "", 0);
+    for _ in 0..200_000 {
+        push_dependent(&cell, &sheet_data.get(0, 0));
+    }
+    drop(cell);
+}
+
+#[test]
+fn test_check_loop() {
 
     let sheet_data = &mut SheetData::new(5, 5);
     let a1 = &sheet_data.sheet[0][0].clone(); //A1
@@ -75,37 +101,33 @@ fn test_check_loop() {
     assert!(!check_loop(a1, b1, 0, 0, sheet_data));
 
     // Set up chain: a1 -> b1
-    add_dependency(&b1.clone(), &a1.clone(), sheet_data);
-    add_dependency(&c1.clone(), &a1.clone(), sheet_data);
+    add_dependency(&b1.clone(), &a1.clone());
+    add_dependency(&c1.clone(), &a1.clone());
 
     // Test if we can detect the direct dependency
     assert!(!check_loop(a1, b1, 0, 0, sheet_data));
     assert!(!check_loop(a1, c1, 0, 0, sheet_data));
 
     // Add b1 -> c1
-    add_dependency(&d1.clone(), &c1.clone(), sheet_data);
-    add_dependency(&e1.clone(), &c1.clone(), sheet_data);
+    add_dependency(&d1.clone(), &c1.clone());
+    add_dependency(&e1.clone(), &c1.clone());
 
     assert!(!check_loop(c1, d1, 0, 2, sheet_data));
     assert!(!check_loop(c1, e1, 0, 2, sheet_data));
 
-    add_dependency(&d1.clone(), &b1.clone(), sheet_data);
+    add_dependency(&d1.clone(), &b1.clone());
 
     // Check if adding c1 -> a1 would create a cycle
     // This checks for a path from c1 back to a1 (which exists through b1)
     assert!(!check_loop(b1, d1, 0, 1, sheet_data));
 
-    add_dependency(&e1.clone(), &a1.clone(), sheet_data);
+    add_dependency(&e1.clone(), &a1.clone());
     assert!(check_loop(e1, a1, 0, 4, sheet_data));
 }
 
 #[test]
 fn test_dfs() {
     // Set global dimensions
-    unsafe {
-        R = 5;
-        C = 5;
-    }
 
     let sheet_data = &mut SheetData::new(5, 5);
     let a1 = &sheet_data.sheet[0][0].clone();
@@ -117,13 +139,13 @@ fn test_dfs() {
     // Create dependency tree:
     // a1 -> b1 -> d1
     // a1 -> c1
-    add_dependency(&a1.clone(), &b1.clone(), sheet_data);
+    add_dependency(&a1.clone(), &b1.clone());
     push_dependent(&b1.clone(), &a1.clone());
 
-    add_dependency(&b1.clone(), &d1.clone(), sheet_data);
+    add_dependency(&b1.clone(), &d1.clone());
     push_dependent(&d1.clone(), &b1.clone());
 
-    add_dependency(&a1.clone(), &c1.clone(), sheet_data);
+    add_dependency(&a1.clone(), &c1.clone());
     push_dependent(&c1.clone(), &a1.clone());
 
     // Test direct paths
@@ -145,10 +167,6 @@ fn test_dfs() {
 #[test]
 fn test_circular_detection() {
     // Set global dimensions
-    unsafe {
-        R = 3;
-        C = 3;
-    }
 
     let sheet_data = &mut SheetData::new(3, 3);
     let a1 = &sheet_data.sheet[0][0].clone();
@@ -156,10 +174,10 @@ fn test_circular_detection() {
     let c1 = &sheet_data.sheet[0][2].clone();
 
     // Create a chain: a1 -> b1 -> c1
-    add_dependency(&a1.clone(), &b1.clone(), sheet_data);
+    add_dependency(&a1.clone(), &b1.clone());
     push_dependent(&b1.clone(), &a1.clone());
 
-    add_dependency(&b1.clone(), &c1.clone(), sheet_data);
+    add_dependency(&b1.clone(), &c1.clone());
     push_dependent(&c1.clone(), &b1.clone());
 
     // At this point, adding c1 -> a1 would create a cycle
@@ -199,7 +217,7 @@ fn test_circular_detection() {
 
 //     // Capture stdout to verify output
 //     let output = Arc::new(Mutex::new(Vec::new()));
-//     let output_clone = Arc::clone(&output);
+//     let output_clone = Rc::clone(&output);
 
 //     // Mock stdout with a closure
 //     let mock_print = move |s: &str| {
@@ -278,6 +296,47 @@ fn test_create_sheet() {
     assert_eq!(sheet_data_large.sheet[0].len(), 100);
 }
 
+#[test]
+fn test_cell_ids_locate_cells() {
+    let sheet_data = SheetData::new(4, 6);
+    assert_eq!(sheet_data.sheet[2][5].borrow().id, 17);
+    assert_eq!(sheet_data.sheet[2][5].borrow().position(), (2, 5));
+    assert_eq!(sheet_data.calculate_row_col(&sheet_data.sheet[2][5]), Some((2, 5)));
+
+    // A cell of another sheet with the same id is not found
+    let other = SheetData::new(4, 6);
+    assert_eq!(sheet_data.calculate_row_col(&other.sheet[2][5]), None);
+}
+
+#[test]
+fn test_cells_know_their_position() {
+    let mut data = SheetData::new(10, 10);
+    for (row, cells) in data.sheet.iter().enumerate() {
+        for (col, cell) in cells.iter().enumerate() {
+            assert_eq!(cell.borrow().position(), (row, col));
+        }
+    }
+
+    // The AVL tree orders and finds cells by the position stored on them
+    let mut root = None;
+    for (row, col) in [(7, 1), (0, 9), (3, 3), (7, 0), (0, 0)] {
+        root = insert(root, data.get(row, col));
+    }
+    let found = find(&root, 7, 0).unwrap();
+    assert!(Arc::ptr_eq(&found.borrow().cell, &data.get(7, 0)));
+    assert!(find(&root, 1, 7).is_none());
+
+    // Recalculation and loop checks walk the graph by those positions too
+    assert_eq!(execute_command("A1=2", 10, 10, &mut data), 0);
+    assert_eq!(execute_command("B2=A1*3", 10, 10, &mut data), 0);
+    assert_eq!(execute_command("C3=B2+A1", 10, 10, &mut data), 0);
+    assert_eq!(execute_command("D4=SUM(A1:C3)", 10, 10, &mut data), 0);
+    assert_eq!(execute_command("A1=1", 10, 10, &mut data), 0);
+    assert_eq!((data.sheet[1][1].borrow().val, data.sheet[2][2].borrow().val, data.sheet[3][3].borrow().val), (3, 4, 8));
+    assert_eq!(execute_command("A1=D4", 10, 10, &mut data), -4);
+    assert_eq!(data.sheet[0][0].borrow().val, 1);
+}
+
 #[test]
 fn test_label_to_index() {
     // Test valid labels
@@ -376,10 +435,6 @@ fn test_col_index_to_label() {
 #[test]
 fn test_execute_command() {
     let mut data = SheetData::new(10, 10);
-    unsafe {
-        R = 5;
-        C = 5;
-    }
 
     let mut status1 = execute_command("q", 5, 5, &mut data);
     assert_eq!(status1, 1);
@@ -443,10 +498,6 @@ fn test_execute_command() {
     let status9 = execute_command("scroll_to B2", 5, 5, &mut data);
     assert_eq!(status9, 0);
 
-    unsafe {
-        R = 10;
-        C = 10;
-    }
     let mut data2 = SheetData::new(10, 10);
     let mut result = 0;
     let row = 0;
@@ -456,6 +507,112 @@ fn test_execute_command() {
     print_sheet(&data2.sheet);
 }
 
+#[test]
+fn test_execute_command_recalculates_changed_dependents() {
+    let mut data = SheetData::new(5, 5);
+    for command in ["A2=5", "A1=1", "B1=MAX(A1:A2)", "C1=B1+1", "D1=SUM(A1:C1)", "E1=D1*2"] {
+        assert_eq!(execute_command(command, 5, 5, &mut data), 0);
+    }
+    assert_eq!(data.sheet[0][4].borrow().val, 24);
+
+    // B1 and C1 keep their values; D1 and E1 read A1 directly or through D1
+    assert_eq!(execute_command("A1=3", 5, 5, &mut data), 0);
+    assert_eq!(data.sheet[0][2].borrow().val, 6);
+    assert_eq!(data.sheet[0][3].borrow().val, 14);
+    assert_eq!(data.sheet[0][4].borrow().val, 28);
+
+    assert_eq!(execute_command("A2=0", 5, 5, &mut data), 0);
+    assert_eq!(data.sheet[0][2].borrow().val, 4);
+    assert_eq!(data.sheet[0][4].borrow().val, 20);
+}
+
+#[test]
+fn test_range_aggregates_follow_edits() {
+    let mut data = SheetData::new(5, 5);
+    for command in ["A1=1", "A2=2", "B3=3", "C1=SUM(A1:B4)", "C2=AVG(A1:B4)", "D1=SUM(A2:A3)"] {
+        assert_eq!(execute_command(command, 5, 5, &mut data), 0);
+    }
+    assert_eq!(data.sheet[0][2].borrow().val, 6);
+    assert_eq!(data.sheet[1][2].borrow().val, 0);
+
+    // Edits above, inside and below the rows already summed
+    assert_eq!(execute_command("A4=10", 5, 5, &mut data), 0);
+    assert_eq!(execute_command("A1=5", 5, 5, &mut data), 0);
+    assert_eq!(data.sheet[0][2].borrow().val, 20);
+    assert_eq!(data.sheet[1][2].borrow().val, 2);
+    assert_eq!(data.sheet[0][3].borrow().val, 2);
+
+    assert_eq!(execute_command("B2=1/0", 5, 5, &mut data), -2);
+    assert_eq!(data.sheet[0][2].borrow().status, 1);
+    assert_eq!(data.sheet[0][3].borrow().status, 0);
+    assert_eq!(execute_command("B2=4", 5, 5, &mut data), 0);
+    assert_eq!(data.sheet[0][2].borrow().status, 0);
+    assert_eq!(data.sheet[0][2].borrow().val, 24);
+    assert_eq!(data.sheet[1][2].borrow().val, 3);
+}
+
+#[test]
+fn test_aggregates_of_large_values() {
+    let mut data = SheetData::new(5, 5);
+    for command in ["A1=2000000000", "A2=2000000000", "B1=AVG(A1:A2)", "B3=STDEV(A1:A2)"] {
+        assert_eq!(execute_command(command, 5, 5, &mut data), 0);
+    }
+    assert_eq!(data.sheet[0][1].borrow().val, 2000000000);
+    assert_eq!(data.sheet[2][1].borrow().val, 0);
+    // A sum that does not fit in a cell is ERR rather than an overflow
+    assert_eq!(execute_command("B2=SUM(A1:A2)", 5, 5, &mut data), -2);
+    assert_eq!(data.sheet[1][1].borrow().status, 1);
+
+    assert_eq!(execute_command("A3=-2000000000", 5, 5, &mut data), 0);
+    assert_eq!(execute_command("B4=STDEV(A1:A3)", 5, 5, &mut data), 0);
+    assert_eq!(data.sheet[3][1].borrow().val, 1885618083);
+    assert_eq!(execute_command("A1=1", 5, 5, &mut data), 0);
+    assert_eq!(data.sheet[1][1].borrow().status, 0);
+    assert_eq!(data.sheet[1][1].borrow().val, 2000000001);
+    assert_eq!(data.sheet[0][1].borrow().val, 1000000000);
+}
+
+#[test]
+fn test_fixing_a_failed_cell_clears_its_dependents() {
+    let mut data = SheetData::new(5, 5);
+    // A2 divides by A1; the cells below read it through arithmetic, ranges and plain references
+    for command in ["A1=0", "A2=5/A1", "B1=A2+1", "B2=MAX(B1:B1)", "B3=B2*2", "B4=AVG(A2:B3)", "C1=B4"] {
+        execute_command(command, 5, 5, &mut data);
+    }
+    let failed = |data: &SheetData| [(1, 0), (0, 1), (1, 1), (2, 1), (3, 1), (0, 2)].map(|(r, c)| data.sheet[r][c].borrow().status);
+    assert_eq!(failed(&data), [1; 6]);
+
+    assert_eq!(execute_command("A1=5", 5, 5, &mut data), 0);
+    assert_eq!(failed(&data), [0; 6]);
+    assert_eq!(data.sheet[2][1].borrow().val, 4);
+    assert_eq!(data.sheet[0][2].borrow().val, 1);
+
+    // Failing again and fixing it again works the same way
+    assert_eq!(execute_command("A1=0", 5, 5, &mut data), 0);
+    assert_eq!(failed(&data), [1; 6]);
+    assert_eq!(execute_command("A2=3", 5, 5, &mut data), 0);
+    assert_eq!(failed(&data), [0; 6]);
+}
+
+#[test]
+fn test_overwriting_a_formula_unlinks_every_input() {
+    let mut data = SheetData::new(5, 5);
+    assert_eq!(execute_command("B1=A1+A2", 5, 5, &mut data), 0);
+    assert_eq!(execute_command("C1=SUM(A3:A4)", 5, 5, &mut data), 0);
+    assert_eq!(execute_command("B1=5", 5, 5, &mut data), 0);
+    assert_eq!(execute_command("C1=B1", 5, 5, &mut data), 0);
+    for r in 0..4 {
+        assert!(data.sheet[r][0].borrow().dependencies.is_none(), "A{}", r + 1);
+    }
+    assert!(data.sheet[0][1].borrow().dependents.is_none());
+
+    // None of the cells read before still counts as a way back to B1 or C1
+    for command in ["A1=B1", "A2=B1", "A3=C1", "A4=C1"] {
+        assert_eq!(execute_command(command, 5, 5, &mut data), 0, "{}", command);
+    }
+    assert_eq!(data.sheet[3][0].borrow().val, 5);
+}
+
 #[test]
 fn test_push_dependent() {
     let sheet_data = &mut SheetData::new(5, 5);
@@ -474,7 +631,7 @@ fn test_push_dependent() {
 
     // Check that the dependent is cell2
     let dep_node = dependents.as_ref().unwrap();
-    assert!(Rc::ptr_eq(&dep_node.borrow().cell, cell2));
+    assert!(Arc::ptr_eq(&dep_node.borrow().cell, cell2));
 }
 
 fn test_add_dependency() {
@@ -486,7 +643,7 @@ fn test_add_dependency() {
     assert!(cell1.borrow().dependencies.is_none());
 
     // Add cell2 as dependency of cell1
-    add_dependency(&cell1.clone(), &cell2.clone(), sheet_data);
+    add_dependency(&cell1.clone(), &cell2.clone());
 
     // Check that cell2 is now a dependency of cell1
     let dependencies = &cell1.borrow().dependencies;
@@ -494,7 +651,7 @@ fn test_add_dependency() {
 
     // Check that the dependency is cell2
     if let Some(dep_node) = dependencies {
-        assert!(Rc::ptr_eq(&dep_node.borrow().cell, cell2));
+        assert!(Arc::ptr_eq(&dep_node.borrow().cell, cell2));
     } else {
         panic!("Expected dependency not found");
     }
@@ -591,7 +748,7 @@ fn test_delete_dependencies() {
     let cell2 = &sheet_data.sheet[1][1].clone();
 
     // Set up dependency: cell1 depends on cell2
-    add_dependency(&cell1.clone(), &cell2.clone(), sheet_data);
+    add_dependency(&cell1.clone(), &cell2.clone());
     push_dependent(&cell2.clone(), &cell1.clone());
 
     // Verify dependency exists
@@ -668,26 +825,22 @@ fn test_dfs_range() {
     let c2 = &sheet_data.sheet[1][2].clone();
 
     // Set up chain: cell1 -> cell2 -> cell3
-    add_dependency(&a1, &b2.clone(), sheet_data);
+    add_dependency(a1, &b2.clone());
     push_dependent(&b2.clone(), &a1.clone());
 
-    add_dependency(&b1, &a1.clone(), sheet_data);
-    add_dependency(&c1, &a1.clone(), sheet_data);
-    add_dependency(&b2, &a1.clone(), sheet_data);
-    add_dependency(&c2, &a1.clone(), sheet_data);
+    add_dependency(b1, &a1.clone());
+    add_dependency(c1, &a1.clone());
+    add_dependency(b2, &a1.clone());
+    add_dependency(c2, &a1.clone());
     push_dependent(&a1.clone(), &b1.clone());
     push_dependent(&a1.clone(), &c1.clone());
     push_dependent(&a1.clone(), &b2.clone());
     push_dependent(&a1.clone(), &c2.clone());
 
-    unsafe {
-        R = 5;
-        C = 5;
-    }
 
     // Check if cell1 depends on cells in the range (1,1) to (2,2)
     let mut visited = vec![false; 5 * 5];
-    assert!(dfs_range(a1, &mut visited, 0, 1, 1, 2, 0, 0, sheet_data));
+    assert!(dfs_range(a1, &mut visited, 0, 1, 1, 2, 0, 0));
 }
 
 #[test]
@@ -700,20 +853,16 @@ fn test_check_loop_range() {
     let c2 = &sheet_data.sheet[1][2].clone();
 
     // Set up chain: cell1 -> cell2 -> cell3
-    add_dependency(&b1.clone(), &a1.clone(), sheet_data);
-    add_dependency(&c1.clone(), &a1.clone(), sheet_data);
-    add_dependency(&b2.clone(), &a1.clone(), sheet_data);
-    add_dependency(&c2.clone(), &a1.clone(), sheet_data);
+    add_dependency(&b1.clone(), &a1.clone());
+    add_dependency(&c1.clone(), &a1.clone());
+    add_dependency(&b2.clone(), &a1.clone());
+    add_dependency(&c2.clone(), &a1.clone());
 
-    unsafe {
-        R = 5;
-        C = 5;
-    }
 
     // Check if cell1 depends on cells in the range (1,1) to (2,2)
     assert!(!check_loop_range(a1, 0, 1, 1, 2, 0, 0, sheet_data));
 
-    add_dependency(&a1.clone(), &b2.clone(), sheet_data);
+    add_dependency(&a1.clone(), &b2.clone());
 
     // Check range outside dependencies
     assert!(check_loop_range(a1, 0, 1, 1, 2, 0, 0, sheet_data));
@@ -727,47 +876,71 @@ fn test_topological_sort_util() {
     let cell3 = &sheet_data.sheet[2][2].clone();
 
     // Set up chain: cell1 -> cell2 -> cell3
-    add_dependency(&cell1.clone(), &cell2.clone(), sheet_data);
+    add_dependency(&cell1.clone(), &cell2.clone());
     push_dependent(&cell2.clone(), &cell1.clone());
 
-    add_dependency(&cell2.clone(), &cell3.clone(), sheet_data);
+    add_dependency(&cell2.clone(), &cell3.clone());
     push_dependent(&cell3.clone(), &cell2.clone());
 
-    unsafe {
-        R = 5;
-        C = 5;
-    }
 
     let mut stack = None;
     let mut visited = vec![false; 5 * 5];
 
     // Run topological sort
-    topological_sort_util(cell1, &mut visited, sheet_data, &mut stack);
+    topological_sort_util(cell1, &mut visited, &mut stack);
 
     // Check if stack has elements in correct order
     // The top of the stack should be cell1, followed by cell2, then cell3
     let first = pop(&mut stack);
     assert!(first.is_some());
-    assert!(Rc::ptr_eq(&first.unwrap(), cell1));
+    assert!(Arc::ptr_eq(&first.unwrap(), cell1));
 
     let second = pop(&mut stack);
     assert!(second.is_some());
-    assert!(Rc::ptr_eq(&second.unwrap(), cell2));
+    assert!(Arc::ptr_eq(&second.unwrap(), cell2));
 
     let third = pop(&mut stack);
     assert!(third.is_some());
-    assert!(Rc::ptr_eq(&third.unwrap(), cell3));
+    assert!(Arc::ptr_eq(&third.unwrap(), cell3));
 
     // Stack should be empty now
     assert!(pop(&mut stack).is_none());
 }
 
 #[test]
-fn test_evaluate_expression() {
-    unsafe {
-        R = 10;
-        C = 10;
+fn test_topological_sort_diamond() {
+    let mut data = SheetData::new(5, 5);
+    // A1 feeds B1 and C1, which both feed D1; E1 reads A1 and D1
+    for command in ["A1=1", "B1=A1+1", "C1=A1*2", "D1=B1+C1", "E1=D1+A1"] {
+        assert_eq!(execute_command(command, 5, 5, &mut data), 0);
+    }
+
+    let mut stack = None;
+    topological_sort_from_cell(&data.sheet[0][0], &data, &mut stack);
+    let mut order = Vec::new();
+    while let Some(cell) = pop(&mut stack) {
+        order.push(cell.borrow().col);
     }
+    assert_eq!(order.len(), 5);
+    let position = |col: usize| order.iter().position(|&c| c == col).unwrap();
+    assert_eq!(position(0), 0);
+    assert!(position(3) > position(1) && position(3) > position(2));
+    assert_eq!(position(4), 4);
+
+    assert_eq!(execute_command("A1=5", 5, 5, &mut data), 0);
+    assert_eq!(data.sheet[0][3].borrow().val, 16);
+    assert_eq!(data.sheet[0][4].borrow().val, 21);
+
+    // Two diamonds stacked on each other: B2 and C2 read D1, D2 reads both
+    for command in ["B2=D1-1", "C2=D1+E1", "D2=B2*C2"] {
+        assert_eq!(execute_command(command, 5, 5, &mut data), 0);
+    }
+    assert_eq!(execute_command("A1=1", 5, 5, &mut data), 0);
+    assert_eq!(data.sheet[1][3].borrow().val, 3 * 9);
+}
+
+#[test]
+fn test_evaluate_expression() {
 
     let sheet_data = &mut SheetData::new(10, 10);
 
@@ -960,10 +1133,6 @@ fn test_evaluate_expression() {
 
 #[test]
 fn test_evaluate_wrong_expression() {
-    unsafe {
-        R = 10;
-        C = 10;
-    }
     let mut sheet_data = SheetData::new(10, 10);
     let mut result = 0;
     let row = 0;
@@ -1038,10 +1207,6 @@ fn test_evaluate_wrong_expression() {
 
     // Test circular dependency
     // Set up A1 to depend on B1, then try to make B1 depend on A1
-    unsafe {
-        R = 10;
-        C = 10;
-    }
     sheet_data = SheetData::new(10, 10); // Reset
     let row_a = 0;
     let col_a = 0;
@@ -1574,3 +1739,2491 @@ fn test_evaluate_wrong_expression() {
 
 //     assert!(count == 4);
 // }
+
+#[cfg(feature = "wasm")]
+#[test]
+fn test_wasm_sheet_sets_and_reads_cells() {
+    let mut sheet = wasm::WasmSheet::new(10, 10).ok().unwrap();
+    assert_eq!((sheet.rows(), sheet.cols()), (10, 10));
+    sheet.set_cell("A1", "5").ok().unwrap();
+    sheet.set_cell("A2", "3").ok().unwrap();
+    sheet.set_cell("B1", "SUM(A1:A3)").ok().unwrap();
+    assert_eq!(sheet.get_value("B1"), Some(8));
+    assert_eq!(sheet.get_expression("B1"), "SUM(A1:A3)");
+
+    // A division by zero sets the cell to ERR instead of failing
+    sheet.set_cell("C1", "A1/0").ok().unwrap();
+    assert!(sheet.is_error("C1"));
+    assert_eq!(sheet.get_value("C1"), None);
+    // Every cell with an expression is recalculated
+    assert_eq!(sheet.recalc(), 4);
+    assert_eq!(sheet.get_value("Z99"), None);
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn test_ffi_sets_evaluates_and_frees_a_sheet() {
+    use ffi::*;
+    use std::ffi::CString;
+    let c = |text: &str| CString::new(text).unwrap();
+    assert!(sheet_new(0, 5).is_null());
+    let sheet = sheet_new(5, 5);
+    let mut value = 0;
+    unsafe {
+        assert_eq!(sheet_set_cell(sheet, c("A1").as_ptr(), c("5").as_ptr()), SHEET_OK);
+        assert_eq!(sheet_set_cell(sheet, c("B1").as_ptr(), c("A1*2").as_ptr()), SHEET_OK);
+        assert_eq!(sheet_get_value(sheet, c("B1").as_ptr(), &mut value), SHEET_OK);
+        assert_eq!(value, 10);
+        assert_eq!(sheet_evaluate(sheet, c("SUM(A1:B1)").as_ptr(), &mut value), SHEET_OK);
+        assert_eq!(value, 15);
+
+        // Errors come back as status codes
+        assert_eq!(sheet_set_cell(sheet, c("A1").as_ptr(), c("B1").as_ptr()), SHEET_LOOP);
+        assert_eq!(sheet_set_cell(sheet, c("C1").as_ptr(), c("A1/0").as_ptr()), SHEET_ERR);
+        assert_eq!(sheet_get_value(sheet, c("C1").as_ptr(), &mut value), SHEET_ERR);
+        assert_eq!(sheet_get_value(sheet, c("F1").as_ptr(), &mut value), SHEET_INVALID);
+        assert_eq!(sheet_get_value(sheet, c("C1").as_ptr(), std::ptr::null_mut()), SHEET_INVALID);
+        assert_eq!(sheet_set_cell(sheet, std::ptr::null(), c("1").as_ptr()), SHEET_INVALID);
+
+        // Dependents are updated when a cell changes
+        assert_eq!(sheet_set_cell(sheet, c("A1").as_ptr(), c("7").as_ptr()), SHEET_OK);
+        assert_eq!(sheet_get_value(sheet, c("B1").as_ptr(), &mut value), SHEET_OK);
+        assert_eq!(value, 14);
+        sheet_free(sheet);
+        sheet_free(std::ptr::null_mut());
+    }
+}
+
+#[test]
+fn test_sheet_worker_recalculates_off_the_calling_thread() {
+    let worker = SheetWorker::spawn(5, 5);
+    assert_eq!(worker.execute("A1=2").recv(), Ok(0));
+    assert_eq!(worker.execute("B1=A1*3").recv(), Ok(0));
+
+    // The caller gets control back while the worker is still busy
+    let started = Instant::now();
+    let slow = worker.execute("C1=SLEEP(1)");
+    let update = worker.execute("A1=4");
+    assert!(started.elapsed() < std::time::Duration::from_millis(500));
+    assert!(slow.try_recv().is_err());
+
+    // Commands still run in the order they were sent
+    assert_eq!(slow.recv(), Ok(0));
+    assert_eq!(update.recv(), Ok(0));
+    assert_eq!(worker.submit(|sheet_data| sheet_data.sheet[0][1].borrow().val).recv(), Ok(12));
+}
+
+#[test]
+fn test_sheet_data_moves_to_another_thread() {
+    let mut sheet_data = SheetData::new(5, 5);
+    assert_eq!(execute_command("A1=3", 5, 5, &mut sheet_data), 0);
+    assert_eq!(execute_command("B1=A1+1", 5, 5, &mut sheet_data), 0);
+    // A filled-in sheet can be handed to a worker, which recalculates it there
+    let worker = SheetWorker::with_sheet(sheet_data);
+    assert_eq!(worker.execute("A1=10").recv(), Ok(0));
+    assert_eq!(worker.submit(|sheet_data| sheet_data.sheet[0][1].borrow().val).recv(), Ok(11));
+
+    // Like a RefCell, a conflicting borrow panics instead of waiting for ever
+    let cell = Cell::new(0, "", 0);
+    let _writing = cell.borrow_mut();
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cell.borrow().val)).is_err());
+}
+
+/// Builds an extended spreadsheet by running `commands` as a batch script.
+fn extended_sheet(name: &str, commands: &str) -> Spreadsheet {
+    let script = std::env::temp_dir().join(format!("rust_lab_{}.txt", name));
+    std::fs::write(&script, commands).unwrap();
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.run_batch(&script).unwrap();
+    sheet
+}
+
+#[test]
+fn test_slow_formula_times_out_without_holding_up_others() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "0").cell("B1", "=SLEEP(A1)")
+        .cell("C1", "=(A1+1)").cell("C2", "=(A1*2)").cell("C3", "=SUM(A1:A2)")
+        .build();
+    assert!(sheet.run_command("timeout 100"));
+    assert_eq!(sheet.status_message(), "EVALUATION TIMEOUT SET TO 100MS");
+
+    // The slow cell runs out of its own budget; the cheap cells after it still get theirs
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "5", false));
+    let cell = |addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().clone();
+    assert_eq!(cell("B1").display_value, "#TIMEOUT!");
+    assert_eq!(cell("B1").error.as_deref(), Some("EVALUATION TIMEOUT"));
+    assert_eq!(cell("C1").display_value, "6");
+    assert_eq!(cell("C2").display_value, "10");
+    assert_eq!(cell("C3").display_value, "5");
+    assert_eq!(cell("C3").error, None);
+}
+
+#[test]
+fn test_range_formulas_stop_at_their_deadline() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("A2", "2").cell("A3", "3").build();
+    assert!(sheet.run_command("timeout 0"));
+
+    // With no budget at all every formula reading a range gives up before its first row
+    for (addr, formula) in [("B1", "=SUM(A1:A3)"), ("B2", "=MAX(A1:A3)"), ("B3", "=STDEV(A1:A3)")] {
+        sheet.update_cell(&addr.parse().unwrap(), formula, false);
+        let cell = sheet.get_cell(&addr.parse().unwrap()).unwrap();
+        assert_eq!(cell.display_value, "#TIMEOUT!", "{}", formula);
+        assert_eq!(cell.error.as_deref(), Some("EVALUATION TIMEOUT"));
+    }
+
+    // Stale inputs count towards the budget of the formula reading them and stay stale when it runs out
+    assert!(sheet.run_command("timeout 2000"));
+    sheet.set_lazy_calc(true);
+    sheet.update_cell(&"C1".parse().unwrap(), "=(A1+1)", false);
+    sheet.run_command("timeout 0");
+    sheet.update_cell(&"A1".parse().unwrap(), "10", false);
+    sheet.update_cell(&"D1".parse().unwrap(), "=(C1*2)", false);
+    assert_eq!(sheet.get_cell(&"D1".parse().unwrap()).unwrap().display_value, "#TIMEOUT!");
+    assert_eq!(sheet.get_cell(&"C1".parse().unwrap()).unwrap().display_value, "2");
+
+    // With a budget again the stale input is evaluated first
+    sheet.run_command("timeout 2000");
+    sheet.update_cell(&"A1".parse().unwrap(), "10", false);
+    sheet.update_cell(&"D1".parse().unwrap(), "=(C1*2)", false);
+    assert_eq!(sheet.get_cell(&"C1".parse().unwrap()).unwrap().display_value, "11");
+    assert_eq!(sheet.get_cell(&"D1".parse().unwrap()).unwrap().display_value, "22");
+}
+
+#[test]
+fn test_watch_list_pins_cells_for_the_panel_and_completion() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    assert!(sheet.run_command("watch D7"));
+    assert_eq!(sheet.status_message(), "WATCHING D7");
+    sheet.run_command("watch K1");
+    assert_eq!(sheet.status_message(), "INVALID WATCH COMMAND");
+
+    // Watched cells are offered when completing a cell argument
+    sheet.send_keys(":unwatch D<Tab><Enter>");
+    assert_eq!(sheet.status_message(), "STOPPED WATCHING D7");
+    sheet.run_command("unwatch D7");
+    assert_eq!(sheet.status_message(), "CELL NOT WATCHED");
+
+    // Without an address the cell under the cursor is watched
+    sheet.run_command("watch");
+    assert_eq!(sheet.status_message(), "WATCHING A1");
+}
+
+#[test]
+fn test_alerts_fire_when_a_recalculation_makes_them_true() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "10").cell("B1", "=(A1*2)").build();
+    assert!(sheet.run_command("alert B1 > 100 \"budget exceeded\""));
+    assert_eq!(sheet.status_message(), "ALERT ADDED");
+    sheet.run_command("alerts");
+    assert_eq!(sheet.status_message(), "ALERTS: B1 > 100");
+    assert_eq!(sheet.alert_notice(), None);
+
+    // The rule is checked after the edit has propagated to B1
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "60", false));
+    assert_eq!(sheet.alert_notice(), Some("ALERT B1: budget exceeded"));
+    sheet.send_keys("l");
+    assert_eq!(sheet.alert_notice(), None);
+
+    // It only fires again once the condition has been false in between
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "70", false));
+    assert_eq!(sheet.alert_notice(), None);
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "1", false));
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "51", false));
+    assert_eq!(sheet.alert_notice(), Some("ALERT B1: budget exceeded"));
+
+    sheet.run_command("alert Z1 > 1");
+    assert_eq!(sheet.status_message(), "INVALID ALERT CELL");
+    sheet.run_command("alert clear");
+    assert_eq!(sheet.alert_notice(), None);
+    sheet.run_command("alerts");
+    assert_eq!(sheet.status_message(), "NO ALERTS");
+}
+
+#[test]
+fn test_refresh_links_rereads_changed_sources() {
+    let source = std::env::temp_dir().join("rust_lab_link_source.json");
+    Spreadsheet::builder().cell("A1", "7").build().save_json(&source, false).unwrap();
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.run_command("refresh links");
+    assert_eq!(sheet.status_message(), "NO LINKS TO REFRESH");
+
+    let link = format!("=LINK({},A1)", source.display());
+    assert!(sheet.update_cell(&"B1".parse().unwrap(), &link, false));
+    assert!(sheet.update_cell(&"C1".parse().unwrap(), "=(B1+1)", false));
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!(value(&sheet, "C1"), "8");
+
+    // Linked values are cached until the sources are refreshed
+    Spreadsheet::builder().cell("A1", "40").build().save_json(&source, false).unwrap();
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "1", false));
+    assert_eq!(value(&sheet, "B1"), "7");
+    sheet.run_command("refresh links");
+    assert_eq!(sheet.status_message(), format!("LINKS REFRESHED: 1 CHANGED ({})", source.display()));
+    assert_eq!(value(&sheet, "B1"), "40");
+    assert_eq!(value(&sheet, "C1"), "41");
+    sheet.run_command("refresh links");
+    assert_eq!(sheet.status_message(), "LINKS REFRESHED: NO CHANGES");
+}
+
+#[test]
+fn test_dependency_csv_lists_each_edge_in_column_order() {
+    let path = std::env::temp_dir().join("rust_lab_dependencies.csv");
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("B1", "2").cell("D2", "=(C1+A1)")
+        .cell("C1", "=SUM(A1:B1)").build();
+    sheet.run_command(&format!("saveas_depcsv {}", path.display()));
+    assert_eq!(sheet.status_message(), format!("DEPENDENCIES SAVED TO {}", path.display()));
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "cell,depends_on\nC1,A1\nC1,B1\nD2,A1\nD2,C1\n"
+    );
+
+    // With a range only the formulas inside it are exported
+    sheet.run_command(&format!("saveas_depcsv D1:D5 {}", path.display()));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "cell,depends_on\nD2,A1\nD2,C1\n");
+}
+
+#[test]
+fn test_replace_all_and_confirm_each() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "cat").cell("A2", "catalog").cell("A3", "5")
+        .cell("B1", "=(A3+1)").cell("C1", "x1").cell("C2", "x2").cell("C3", "x3").build();
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    sheet.run_command("replace cat dog");
+    assert_eq!(sheet.status_message(), "2 CELLS REPLACED");
+    assert_eq!((value(&sheet, "A1"), value(&sheet, "A2")), ("dog".to_string(), "dogalog".to_string()));
+
+    // Formulas are only searched with -f
+    sheet.run_command("replace A3 A4");
+    assert_eq!(sheet.status_message(), "NO MATCHES FOUND");
+    sheet.run_command("replace A3 A4 -f");
+    assert_eq!(sheet.get_cell(&"B1".parse().unwrap()).unwrap().formula.as_deref(), Some("(A4+1)"));
+    assert_eq!(value(&sheet, "B1"), "1");
+
+    // With -c each match is confirmed in turn, and the accepted ones undo as one step
+    sheet.run_command("replace x y C1:C3 -c");
+    assert_eq!(*sheet.mode(), Mode::Replace);
+    assert_eq!(sheet.status_message(), "REPLACE IN C1? (y/n/a/q)");
+    sheet.send_keys("n");
+    assert_eq!(sheet.status_message(), "REPLACE IN C2? (y/n/a/q)");
+    assert_eq!(sheet.cursor().to_string(), "C2");
+    sheet.send_keys("yq");
+    assert_eq!(sheet.status_message(), "1 CELLS REPLACED");
+    assert_eq!(*sheet.mode(), Mode::Normal);
+    assert_eq!([value(&sheet, "C1"), value(&sheet, "C2"), value(&sheet, "C3")], ["x1", "y2", "x3"]);
+    assert!(sheet.undo());
+    assert_eq!(value(&sheet, "C2"), "x2");
+    assert_eq!(value(&sheet, "B1"), "1");
+}
+
+#[test]
+fn test_status_bar_shows_file_changes_and_last_error() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    let (mode, info, error) = sheet.status_bar(60);
+    assert_eq!(mode, " NORMAL ");
+    assert_eq!(info.trim_end(), " [NO FILE] | 0 CELLS | A1");
+    assert_eq!(error, "");
+    assert_eq!(mode.len() + info.len(), 60);
+
+    // Saving names the file and clears the modified mark; the next edit sets it again
+    let path = std::env::temp_dir().join("rust_lab_status_bar.json");
+    sheet.update_cell(&"A1".parse().unwrap(), "5", false);
+    sheet.update_cell(&"B2".parse().unwrap(), "=(A1+1)", false);
+    sheet.run_command(&format!("saveas_json {}", path.display()));
+    let (_, info, _) = sheet.status_bar(200);
+    assert!(info.starts_with(&format!(" {} | 2 CELLS | A1 | FILE SAVED TO", path.display())), "{}", info);
+    sheet.update_cell(&"C3".parse().unwrap(), "x", false);
+    assert!(sheet.status_bar(200).1.starts_with(&format!(" {} [+] | 3 CELLS", path.display())));
+
+    // An error stays on the right after later messages replace it, and the message is not repeated
+    sheet.run_command("nonsense");
+    let (_, info, error) = sheet.status_bar(200);
+    assert_eq!(error, " ERR: INVALID COMMAND ");
+    assert!(!info.contains("INVALID COMMAND"));
+    sheet.run_command("i B2");
+    let (mode, info, error) = sheet.status_bar(200);
+    assert_eq!(mode, " INSERT ");
+    assert!(info.contains("| B2 | INSERTING"));
+    assert_eq!(error, " ERR: INVALID COMMAND ");
+    assert_eq!(mode.len() + info.len() + error.len(), 200);
+
+    // A narrow terminal cuts the information short before the error
+    let (mode, info, error) = sheet.status_bar(40);
+    assert_eq!(error, " ERR: INVALID COMMAND ");
+    assert!(info.ends_with('~'));
+    assert_eq!(mode.len() + info.len() + error.len(), 40);
+}
+
+#[test]
+fn test_find_with_a_regex() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "b12").cell("A2", "ab3").cell("B1", "b7x")
+        .cell("C4", "b4").build();
+    assert!(sheet.run_command(r"find /^b\d+$/"));
+    assert_eq!(sheet.status_message(), "2 MATCHES FOUND");
+    assert_eq!(*sheet.mode(), Mode::Find);
+    assert_eq!(sheet.cursor().to_string(), "A1");
+    sheet.send_keys("n");
+    assert_eq!(sheet.cursor().to_string(), "C4");
+
+    // Without slashes the query is still a plain substring
+    sheet.send_keys("<Esc>");
+    sheet.run_command("find b");
+    assert_eq!(sheet.status_message(), "4 MATCHES FOUND");
+    sheet.send_keys("<Esc>");
+    sheet.run_command("find /(/");
+    assert_eq!(sheet.status_message(), "INVALID REGEX");
+    assert_eq!(*sheet.mode(), Mode::Normal);
+}
+
+#[test]
+fn test_demo_mode_records_keys_as_a_replayable_script() {
+    let path = std::env::temp_dir().join("rust_lab_demo_script.txt");
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.send_keys(":demo on<Enter>");
+    assert_eq!(sheet.status_message(), "DEMO MODE ON");
+    sheet.send_keys("jl:mi B2:B2 5<Enter>");
+    sheet.send_keys(&format!(":demo save {}<Enter>", path.display()));
+    assert_eq!(sheet.status_message(), format!("DEMO SCRIPT SAVED TO {}", path.display()));
+
+    // The save command itself is left out of the script
+    let script = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(script, "jl:mi B2:B2 5<Enter>\n");
+    let mut replayed = Spreadsheet::new(10, 10);
+    replayed.send_keys(&script);
+    assert_eq!(replayed.cursor().to_string(), "B2");
+    assert_eq!(replayed.get_cell(&"B2".parse().unwrap()).unwrap().display_value, "5");
+
+    sheet.send_keys(&format!(":demo play {} 0<Enter>", path.display()));
+    assert_eq!(sheet.status_message(), "REPLAYING 14 KEYS");
+    sheet.send_keys(":demo off<Enter>");
+    assert_eq!(sheet.status_message(), "DEMO MODE OFF");
+}
+
+#[test]
+fn test_find_scope_covers_values_formulas_and_addresses() {
+    let mut sheet = Spreadsheet::builder().cell("B7", "4").cell("C1", "=(B7+1)").cell("D2", "=SUM(A1:B7)")
+        .cell("E5", "B7").build();
+    sheet.run_command("find -f B7");
+    assert_eq!(sheet.status_message(), "3 MATCHES FOUND");
+    assert_eq!(sheet.cursor().to_string(), "C1");
+    sheet.send_keys("n");
+    assert_eq!(sheet.cursor().to_string(), "D2");
+
+    // `t` cycles formulas -> addresses -> values and reruns the query
+    sheet.send_keys("t");
+    assert_eq!(sheet.status_message(), "SEARCHING ADDRESSES: 1 MATCHES FOUND");
+    assert_eq!(sheet.cursor().to_string(), "B7");
+    sheet.send_keys("t");
+    assert_eq!(sheet.status_message(), "SEARCHING VALUES: 1 MATCHES FOUND");
+    assert_eq!(sheet.cursor().to_string(), "E5");
+
+    sheet.send_keys("<Esc>");
+    sheet.run_command("find -a b7");
+    assert_eq!(sheet.cursor().to_string(), "B7");
+}
+
+#[test]
+fn test_matches_set_and_clear_update_every_find_match() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "todo").cell("B2", "todo").cell("C3", "todo")
+        .cell("D4", "keep").build();
+    sheet.run_command("lock C3");
+    sheet.run_command("matches set done");
+    assert_eq!(sheet.status_message(), "NO FIND MATCHES");
+
+    // `:` in Find mode keeps the matches for the command; locked cells are skipped
+    sheet.run_command("find todo");
+    sheet.send_keys(":matches set done<Enter>");
+    assert_eq!(sheet.status_message(), "2 OF 3 MATCHES UPDATED");
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!([value(&sheet, "A1"), value(&sheet, "B2"), value(&sheet, "C3")], ["done", "done", "todo"]);
+
+    // The whole update is a single undo step
+    assert!(sheet.undo());
+    assert_eq!([value(&sheet, "A1"), value(&sheet, "B2")], ["todo", "todo"]);
+
+    sheet.run_command("find -f todo");
+    sheet.run_command("matches clear");
+    assert_eq!(sheet.status_message(), "2 OF 3 MATCHES UPDATED");
+    assert_eq!([value(&sheet, "A1"), value(&sheet, "D4")], ["", "keep"]);
+}
+
+#[test]
+fn test_typing_only_redraws_the_input_line() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.send_keys(":i<Enter>");
+    assert!(sheet.needs_full_redraw());
+    for keys in ["4", "2", "<BS>"] {
+        sheet.send_keys(keys);
+        assert!(!sheet.needs_full_redraw(), "{} redrew the grid", keys);
+    }
+    // Committing the edit changes the grid
+    sheet.send_keys("<Enter>");
+    assert!(sheet.needs_full_redraw());
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "4");
+
+    sheet.send_keys(":");
+    for keys in ["w", "a", "<Tab>", "<Up>"] {
+        sheet.send_keys(keys);
+        assert!(!sheet.needs_full_redraw(), "{} redrew the grid", keys);
+    }
+    sheet.send_keys("<Esc>");
+    assert!(sheet.needs_full_redraw());
+}
+
+#[test]
+fn test_tick_sets_the_idle_refresh_rate() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    assert!(sheet.run_command("tick 250"));
+    assert_eq!(sheet.status_message(), "TICK RATE SET TO 250MS");
+    for cmd in ["tick 0", "tick", "tick fast", "ticks 50"] {
+        sheet.run_command(cmd);
+        assert_eq!(sheet.status_message(), "USAGE: tick <milliseconds>", "{}", cmd);
+    }
+}
+
+#[test]
+fn test_macros_record_and_replay_keys() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.send_keys("qa");
+    assert_eq!(sheet.status_message(), "RECORDING @a");
+    sheet.send_keys(":i<Enter>7<Enter>j:mi B1:B1 =SUM(A1:A10)<Enter>q");
+    assert_eq!(sheet.status_message(), "RECORDED @a");
+    assert_eq!(sheet.cursor().to_string(), "A2");
+
+    // Replaying runs the same edits, movements and commands from the new cursor position
+    sheet.send_keys("@a");
+    assert_eq!(sheet.cursor().to_string(), "A3");
+    sheet.send_keys("@@");
+    assert_eq!(sheet.cursor().to_string(), "A4");
+    let value = |addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!([value("A1"), value("A2"), value("A3"), value("B1")], ["7", "7", "7", "21"]);
+
+    sheet.send_keys("@b");
+    assert_eq!(sheet.status_message(), "REGISTER @b IS EMPTY");
+}
+
+#[test]
+fn test_up_and_down_recall_earlier_commands() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.send_keys(":mi A1:A1 1<Enter>:mi A2:A2 2<Enter>:mi A2:A2 2<Enter>");
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+
+    // A repeated command is stored once, so two steps back reach the first one
+    sheet.send_keys(":<Up><Up><BS>5<Enter>");
+    assert_eq!(value(&sheet, "A1"), "5");
+    sheet.send_keys(":<Up><Up><Down><BS>7<Enter>");
+    assert_eq!(value(&sheet, "A1"), "7");
+
+    // Stepping past the newest entry leaves an empty buffer to type into
+    sheet.send_keys(":<Up><Down>mi A3:A3 3<Enter>");
+    assert_eq!(value(&sheet, "A3"), "3");
+    assert_eq!(value(&sheet, "A2"), "2");
+}
+
+#[test]
+fn test_resuming_from_the_shell_repaints_the_whole_screen() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.send_keys(":mi A1:A1 4");
+    assert!(!sheet.needs_full_redraw());
+    sheet.redraw();
+    assert!(sheet.needs_full_redraw());
+
+    // The half-typed command survives the trip to the shell
+    sheet.send_keys("2<Enter>");
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "42");
+}
+
+#[test]
+fn test_recovery_file_keeps_the_sheet_of_a_crashed_session() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").build();
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "2", false));
+    let crashed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        sheet.update_cell(&"B1".parse().unwrap(), "=(A1*3)", false);
+        panic!("bug in the event loop");
+    }));
+    assert!(crashed.is_err());
+
+    let dir = std::env::temp_dir().join("rust_lab_recovery");
+    let recovery = sheet.save_recovery(&dir).unwrap();
+    assert_eq!(recovery, dir.join("recovery.json"));
+    let mut restored = Spreadsheet::new(10, 10);
+    restored.load_json(&recovery).unwrap();
+    assert_eq!(restored.get_cell(&"B1".parse().unwrap()).unwrap().display_value, "6");
+    // The undo history comes back too
+    assert!(restored.undo());
+    assert!(restored.undo());
+    assert_eq!(restored.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "1");
+}
+
+#[test]
+fn test_tab_completes_commands_files_and_cells() {
+    let dir = std::env::temp_dir().join("rust_lab_completion");
+    std::fs::create_dir_all(&dir).unwrap();
+    Spreadsheet::builder().cell("A1", "1").build().save_json(&dir.join("budget_2024.json"), false).unwrap();
+    Spreadsheet::builder().cell("A1", "2").build().save_json(&dir.join("budget_old.json"), false).unwrap();
+
+    // A single candidate is filled in; several are listed and cycled with repeated Tabs
+    let mut sheet = Spreadsheet::builder().cell("C3", "7").build();
+    sheet.send_keys(":loc<Tab> C<Tab><Enter>");
+    assert!(sheet.get_cell(&"C3".parse().unwrap()).unwrap().is_locked);
+    sheet.send_keys(&format!(":load {}/budget_<Tab>", dir.display()));
+    assert_eq!(sheet.status_message(), format!("{0}/budget_2024.json  {0}/budget_old.json", dir.display()));
+    sheet.send_keys("<Tab><Enter>");
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "2");
+
+    sheet.send_keys(":nosuch<Tab>");
+    assert_eq!(sheet.status_message(), "NO COMPLETIONS");
+}
+
+#[test]
+fn test_dot_repeats_the_last_change_at_the_cursor() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.send_keys(".");
+    assert_eq!(sheet.status_message(), "NOTHING TO REPEAT");
+
+    sheet.send_keys(":i<Enter>=(B1+1)<Enter>j.j.");
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!(sheet.get_cell(&"A3".parse().unwrap()).unwrap().formula.as_deref(), Some("(B1+1)"));
+    assert_eq!(value(&sheet, "A2"), "1");
+
+    // A multi-insert is repeated with the same size, starting at the cursor
+    sheet.send_keys(":mi C1:D2 5<Enter>ll.");
+    assert_eq!([value(&sheet, "C3"), value(&sheet, "D4"), value(&sheet, "E4"), value(&sheet, "C5")], ["5", "5", "0", "0"]);
+
+    // Locking is a change too
+    sheet.send_keys(":lock<Enter>h.");
+    assert!(sheet.get_cell(&"B3".parse().unwrap()).unwrap().is_locked);
+}
+
+#[test]
+fn test_command_line_opens_a_file_for_the_script() {
+    let dir = std::env::temp_dir();
+    let file = dir.join("rust_lab_cli_open.json");
+    let out = dir.join("rust_lab_cli_saved.json");
+    let script = dir.join("rust_lab_cli_script.txt");
+    Spreadsheet::builder().cell("A1", "5").build().save_json(&file, false).unwrap();
+    std::fs::write(&script, format!("B1=(A1*2)\nsaveas_json {}\n", out.display())).unwrap();
+    let run = |args: &[&std::ffi::OsStr]| std::process::Command::new(env!("CARGO_BIN_EXE_Rust_lab")).args(args).output().unwrap();
+
+    // The file is loaded before the script runs; --batch is another name for --script
+    let output = run(&[file.as_os_str(), "--batch".as_ref(), script.as_os_str()]);
+    assert_eq!(output.status.code(), Some(0));
+    let mut saved = Spreadsheet::new(10, 10);
+    saved.load_json(&out).unwrap();
+    assert_eq!(saved.get_cell(&"B1".parse().unwrap()).unwrap().display_value, "10");
+
+    // Read-only sheets refuse the script's edits
+    let output = run(&[file.as_os_str(), "--readonly".as_ref(), "--script".as_ref(), script.as_os_str()]);
+    assert_eq!(output.status.code(), Some(1));
+    // Sizes outside 1..=999 rows are rejected before anything runs
+    let output = run(&["--rows".as_ref(), "0".as_ref(), "--script".as_ref(), script.as_os_str()]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_date_formats_render_and_export_cells() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.run_command("fmt A1:A3 date %d.%m.%Y");
+    let cell = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().clone();
+
+    // Dates are stored as serial numbers, so arithmetic on them works
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "2024-04-03", false));
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "=(A1+30)", false));
+    assert_eq!(cell(&sheet, "A1").display_value, "45385");
+    assert_eq!(cell(&sheet, "A1").formatted_value(), "03.04.2024");
+    assert_eq!(cell(&sheet, "A2").formatted_value(), "03.05.2024");
+
+    // Other common spellings of a date are accepted as well; the cell's own order comes first
+    assert!(sheet.update_cell(&"A3".parse().unwrap(), "3 Apr 2024", false));
+    assert_eq!(cell(&sheet, "A3").display_value, "45385");
+    assert!(sheet.update_cell(&"A3".parse().unwrap(), "04/03/2024", false));
+    assert_eq!(cell(&sheet, "A3").formatted_value(), "04.03.2024");
+
+    let csv = std::env::temp_dir().join("rust_lab_dates.csv");
+    sheet.export_csv(&csv, sheet.parse_range("A1:A2")).unwrap();
+    assert_eq!(std::fs::read_to_string(&csv).unwrap().lines().collect::<Vec<_>>(), ["03.04.2024", "03.05.2024"]);
+
+    sheet.run_command("fmt A1:A3 date %Q");
+    assert_eq!(sheet.status_message(), "INVALID DATE PATTERN");
+    sheet.run_command("fmt A1:A3 clear");
+    assert_eq!(cell(&sheet, "A1").formatted_value(), "45385");
+}
+
+#[test]
+fn test_undo_history_is_unlimited_unless_capped() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    let a1: CellAddress = "A1".parse().unwrap();
+    for i in 1..=10 {
+        assert!(sheet.update_cell(&a1, &i.to_string(), false));
+    }
+    for _ in 0..10 {
+        assert!(sheet.undo());
+    }
+    assert!(!sheet.undo());
+    assert_eq!(sheet.get_cell(&a1).unwrap().display_value, "0");
+    for _ in 0..4 {
+        assert!(sheet.redo());
+    }
+    assert_eq!(sheet.get_cell(&a1).unwrap().display_value, "4");
+
+    // A new edit drops the undone actions
+    assert!(sheet.update_cell(&"B1".parse().unwrap(), "9", false));
+    assert!(!sheet.redo());
+
+    sheet.run_command("undolimit 2");
+    assert_eq!(sheet.status_message(), "UNDO LIMIT SET TO 2");
+    assert!(sheet.undo());
+    assert!(sheet.undo());
+    assert!(!sheet.undo());
+    assert_eq!(sheet.get_cell(&a1).unwrap().display_value, "3");
+    sheet.run_command("undolimit 0");
+    assert_eq!(sheet.status_message(), "UNDO HISTORY UNLIMITED");
+}
+
+#[test]
+fn test_csv_import_translates_excel_formulas() {
+    let csv = std::env::temp_dir().join("rust_lab_excel_formulas.csv");
+    std::fs::write(&csv, "2,3,\"=SUM($A$1,B1)\"\n=ROUND(2.5;0),=iferror(A1*B1;0),=VLOOKUP(A1;A1:B1;2)\n=ROUND(-2.5;0)\n").unwrap();
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.run_command(&format!("load {}", csv.display()));
+    assert_eq!(sheet.status_message(), "IMPORTED 6 CELLS, 1 FORMULAS NOT TRANSLATED: C2: UNSUPPORTED FUNCTION VLOOKUP");
+
+    let cell = |addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().clone();
+    assert_eq!(cell("C1").formula.as_deref(), Some("(A1+B1)"));
+    assert_eq!(cell("C1").display_value, "5");
+    // Excel rounds halves away from zero
+    assert_eq!((cell("A2").display_value, cell("A3").display_value), ("3".to_string(), "-3".to_string()));
+    assert_eq!(cell("B2").display_value, "6");
+    // The untranslated formula stays visible as text
+    assert_eq!(cell("C2").formula, None);
+    assert_eq!(cell("C2").display_value, "=VLOOKUP(A1;A1:B1;2)");
+}
+
+#[test]
+fn test_multi_cell_operations_undo_in_one_step() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "3").cell("A2", "1").cell("A3", "2").cell("B1", "=SUM(A1:A3)").build();
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+
+    sheet.run_command("mi C1:D3 7");
+    assert!(sheet.sort_range("A1:A3", true));
+    assert_eq!([value(&sheet, "A1"), value(&sheet, "A2"), value(&sheet, "A3")], ["1", "2", "3"]);
+
+    // Each command is undone and redone as a whole
+    assert!(sheet.undo());
+    assert_eq!([value(&sheet, "A1"), value(&sheet, "A2"), value(&sheet, "A3")], ["3", "1", "2"]);
+    assert_eq!(value(&sheet, "D3"), "7");
+    assert!(sheet.undo());
+    assert_eq!([value(&sheet, "C1"), value(&sheet, "D3")], ["0", "0"]);
+    assert!(sheet.redo());
+    assert_eq!([value(&sheet, "C1"), value(&sheet, "C3"), value(&sheet, "D1"), value(&sheet, "D3")], ["7", "7", "7", "7"]);
+    assert_eq!(value(&sheet, "B1"), "6");
+}
+
+#[test]
+fn test_saved_undo_history_continues_after_reopening() {
+    let dir = std::env::temp_dir();
+    let with_history = dir.join("rust_lab_undo_saved.json");
+    let without_history = dir.join("rust_lab_undo_not_saved.json");
+    let mut sheet = Spreadsheet::new(10, 10);
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "1", false));
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "2", false));
+    assert!(sheet.undo());
+    sheet.run_command(&format!("saveas_json {} -u", with_history.display()));
+    sheet.run_command(&format!("saveas_json {}", without_history.display()));
+
+    let mut reopened = Spreadsheet::new(10, 10);
+    reopened.load_json(&with_history).unwrap();
+    let a1 = |sheet: &Spreadsheet| sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!(a1(&reopened), "1");
+    // Both the undo and the redo stack come back
+    assert!(reopened.redo());
+    assert_eq!(a1(&reopened), "2");
+    assert!(reopened.undo());
+    assert!(reopened.undo());
+    assert_eq!(a1(&reopened), "0");
+
+    let mut plain = Spreadsheet::new(10, 10);
+    plain.load_json(&without_history).unwrap();
+    assert!(!plain.undo());
+    assert_eq!(a1(&plain), "1");
+}
+
+#[test]
+fn test_marks_remember_cells_to_jump_back_to() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.run_command("marks");
+    assert_eq!(sheet.status_message(), "NO MARKS");
+    sheet.send_keys("jjlma");
+    assert_eq!(sheet.status_message(), "MARK a SET AT B3");
+    sheet.send_keys("jjjl'a");
+    assert_eq!(sheet.status_message(), "JUMPED TO MARK a (B3)");
+    assert_eq!(sheet.cursor().to_string(), "B3");
+
+    // '' goes back to where the last jump started
+    sheet.send_keys("''");
+    assert_eq!(sheet.cursor().to_string(), "C6");
+    sheet.run_command("marks");
+    assert_eq!(sheet.status_message(), "MARKS: '=B3, a=B3");
+    sheet.send_keys("'b");
+    assert_eq!(sheet.status_message(), "MARK b NOT SET");
+    assert_eq!(sheet.cursor().to_string(), "C6");
+}
+
+#[test]
+fn test_named_registers_hold_separate_clipboards() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("A2", "2").cell("B1", "hello").build();
+    sheet.run_command("registers");
+    assert_eq!(sheet.status_message(), "NO REGISTERS");
+    sheet.run_command("yank A1:A2 a");
+    sheet.send_keys("l\"by");
+
+    // A named yank also fills the unnamed register used by a plain p
+    sheet.send_keys("llp");
+    sheet.send_keys("l\"ap");
+    assert_eq!(sheet.status_message(), "2 CELLS PASTED FROM \"a");
+    let value = |addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!([value("D1"), value("E1"), value("E2")], ["hello", "1", "2"]);
+
+    sheet.run_command("registers");
+    assert_eq!(sheet.status_message(), "REGISTERS: \"\" B1 (1x1) hello, \"a A1:A2 (2x1) 1, \"b B1 (1x1) hello");
+    sheet.run_command("put c");
+    assert_eq!(sheet.status_message(), "REGISTER \"c IS EMPTY");
+}
+
+#[test]
+fn test_workbook_import_reads_values_text_and_formulas() {
+    let ods = std::env::temp_dir().join("rust_lab_import.ods");
+    let mut source = Spreadsheet::builder().cell("A1", "4").cell("A2", "6").cell("B1", "=SUM(A1:A2)")
+        .cell("C1", "label").cell("D4", "9").build();
+    source.run_command(&format!("saveas_ods {}", ods.display()));
+
+    // Cells beyond a smaller sheet are skipped and reported
+    let mut sheet = Spreadsheet::new(3, 3);
+    sheet.run_command(&format!("load {}", ods.display()));
+    assert_eq!(sheet.status_message(), "IMPORTED 9 CELLS, 1 PROBLEMS: 7 CELLS OUTSIDE THE 3x3 SHEET SKIPPED");
+    let cell = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().clone();
+    assert_eq!(cell(&sheet, "B1").formula.as_deref(), Some("SUM(A1:A2)"));
+    assert_eq!(cell(&sheet, "C1").display_value, "label");
+
+    // Imported formulas are live
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "10", false));
+    assert_eq!(cell(&sheet, "B1").display_value, "14");
+
+    sheet.run_command(&format!("load {} Sheet1", ods.display()));
+    assert!(sheet.status_message().starts_with("IMPORTED 9 CELLS"));
+    sheet.run_command(&format!("load {} Totals", ods.display()));
+    assert_eq!(sheet.status_message(), "LOAD ERROR: NO SHEET NAMED Totals (SHEETS: Sheet1)");
+}
+
+#[test]
+fn test_html_export_styles_cells() {
+    let path = std::env::temp_dir().join("rust_lab_export.html");
+    let mut sheet = Spreadsheet::builder().cell("A1", "x<y").cell("A2", "2").cell("B1", "=(A2+1)").cell("C1", "9").build();
+    sheet.run_command("align A1 l");
+    sheet.run_command("lock B1");
+    sheet.run_command(&format!("saveas_html A1:B2 {}", path.display()));
+    let html = std::fs::read_to_string(&path).unwrap();
+
+    assert!(html.contains("<td style=\"border:1px solid #c8ccd2;padding:4px 8px;min-width:5ch;text-align:left\">x&lt;y</td>"));
+    // Formulas show in the tooltip; locked cells are shaded and marked with a lock
+    assert!(html.contains("text-align:center;color:#1a4f8b;background:#e4e4e4\" title=\"=(A2+1) (locked)\">&#128274; 3</td>"));
+    // Only the chosen range is exported
+    assert!(html.contains(">B</th>"));
+    assert!(!html.contains(">C</th>"));
+    assert!(!html.contains(">9</td>"));
+    assert_eq!(html.matches("<tr>").count(), 3);
+}
+
+#[test]
+fn test_latex_export_writes_an_escaped_booktabs_table() {
+    let path = std::env::temp_dir().join("rust_lab_export.tex");
+    let mut sheet = Spreadsheet::builder().cell("A1", "Item").cell("B1", "Cost_%").cell("A2", "a&b").cell("B2", "5")
+        .cell("A3", "x").cell("B3", "7").cell("C1", "left out").build();
+    sheet.run_command("align B2 r");
+    sheet.run_command("align B3 r");
+    sheet.run_command(&format!("saveas_tex {} A1:B3", path.display()));
+    assert_eq!(sheet.status_message(), format!("LATEX TABLE SAVED TO {}", path.display()));
+
+    // The column takes its most common alignment; other cells override it with \multicolumn
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), concat!(
+        "% Requires \\usepackage{booktabs}\n",
+        "\\begin{tabular}{cr}\n",
+        "\\toprule\n",
+        "Item & \\multicolumn{1}{c}{Cost\\_\\%} \\\\\n",
+        "\\midrule\n",
+        "a\\&b & 5 \\\\\n",
+        "x & 7 \\\\\n",
+        "\\bottomrule\n",
+        "\\end{tabular}\n",
+    ));
+}
+
+#[test]
+fn test_ods_export_keeps_representable_formulas() {
+    use std::io::Read;
+    let path = std::env::temp_dir().join("rust_lab_export.ods");
+    let mut sheet = Spreadsheet::builder().cell("A1", "4").cell("A2", "6").cell("B1", "=SUM(A1:A2)")
+        .cell("B2", "=(A1+C3)").cell("C1", "tag").build();
+    sheet.run_command(&format!("saveas_ods A1:B2 {}", path.display()));
+    // B2 refers to a cell outside the exported range
+    assert_eq!(sheet.status_message(), format!("ODS SAVED TO {} (1 FORMULAS SAVED AS VALUES)", path.display()));
+
+    let mut ods = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+    let mut read = |name: &str| {
+        let mut text = String::new();
+        ods.by_name(name).unwrap().read_to_string(&mut text).unwrap();
+        text
+    };
+    assert_eq!(read("mimetype"), "application/vnd.oasis.opendocument.spreadsheet");
+    let content = read("content.xml");
+    assert!(content.contains("<table:table-cell office:value-type=\"float\" office:value=\"10\" table:formula=\"of:=SUM([.A1:.A2])\"><text:p>10</text:p></table:table-cell>"));
+    assert!(content.contains("<table:table-cell office:value-type=\"float\" office:value=\"4\"><text:p>4</text:p></table:table-cell></table:table-row>"));
+    assert!(!content.contains("tag"));
+    assert_eq!(ods.by_index(0).unwrap().name(), "mimetype");
+}
+
+#[test]
+fn test_load_json_rebuilds_dependencies_and_recalculates() {
+    let path = std::env::temp_dir().join("rust_lab_stale_values.json");
+    // Stored results are stale: the formulas are evaluated again on load
+    std::fs::write(
+        &path,
+        r#"{"cells": {"A1": {"raw_value": "1", "display_value": "1"}, "A2": {"raw_value": "2", "display_value": "2"},
+            "B1": {"formula": "SUM(A1:A2)", "display_value": "999"}, "C1": {"formula": "(B1*2)", "display_value": "0"}}}"#,
+    )
+    .unwrap();
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.load_json(&path).unwrap();
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!([value(&sheet, "B1"), value(&sheet, "C1")], ["3", "6"]);
+
+    // Edits after loading reach every dependent
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "5", false));
+    assert_eq!([value(&sheet, "B1"), value(&sheet, "C1")], ["6", "12"]);
+    // and a loaded formula still takes part in cycle detection
+    assert!(!sheet.update_cell(&"A1".parse().unwrap(), "=(C1+1)", false));
+}
+
+#[test]
+fn test_old_save_formats_are_migrated_on_load() {
+    let dir = std::env::temp_dir();
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+
+    // Version 0 was a bare object of cells, version 1 had no version number and left out default fields
+    let v0 = dir.join("rust_lab_save_v0.json");
+    std::fs::write(&v0, r#"{"A1": {"raw_value": "4", "display_value": "4"}, "B1": {"formula": "(A1*2)"}}"#).unwrap();
+    let v1 = dir.join("rust_lab_save_v1.json");
+    std::fs::write(&v1, r#"{"cells": {"A1": {"raw_value": "7", "display_value": "7"}}, "undo": [], "redo": []}"#).unwrap();
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.load_json(&v0).unwrap();
+    assert_eq!([value(&sheet, "A1"), value(&sheet, "B1")], ["4", "8"]);
+    sheet.load_json(&v1).unwrap();
+    assert_eq!(value(&sheet, "A1"), "7");
+
+    // Saving writes the current version, and a file from a newer version is refused
+    let saved = dir.join("rust_lab_save_current.json");
+    sheet.save_json(&saved, false).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&saved).unwrap()).unwrap();
+    assert_eq!(json["version"], 2);
+    let newer = dir.join("rust_lab_save_newer.json");
+    std::fs::write(&newer, r#"{"version": 99, "cells": {}}"#).unwrap();
+    let err = sheet.load_json(&newer).unwrap_err();
+    assert!(err.to_string().contains("SAVE FORMAT VERSION 99 IS NEWER THAN SUPPORTED"), "{}", err);
+    assert_eq!(value(&sheet, "A1"), "7");
+}
+
+#[test]
+fn test_binary_save_round_trip() {
+    let dir = std::env::temp_dir();
+    let sheet = extended_sheet(
+        "bin_round_trip",
+        "mi A1:A1 2\nmi A2:A2 3\nmi B1:B1 =SUM(A1:A2)\nmi C1:C1 =sqrt(B1)\nmi D4:D4 hello\nlock C1\nalign A1 left\n",
+    );
+    let json = dir.join("rust_lab_round_trip.json");
+    let bin = dir.join("rust_lab_round_trip.bin");
+    sheet.save_json(&json, true).unwrap();
+    sheet.save_bin(&bin, true).unwrap();
+    assert!(std::fs::metadata(&bin).unwrap().len() < std::fs::metadata(&json).unwrap().len());
+
+    // Loading the binary file must give back exactly what the JSON file holds
+    let mut loaded = Spreadsheet::new(10, 10);
+    loaded.load_bin(&bin).unwrap();
+    let reloaded_json = dir.join("rust_lab_round_trip_reloaded.json");
+    loaded.save_json(&reloaded_json, true).unwrap();
+    let read = |path: &std::path::Path| -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    };
+    assert_eq!(read(&json), read(&reloaded_json));
+    assert_eq!(read(&json)["cells"]["B1"]["display_value"], "5");
+}
+
+#[test]
+fn test_binary_load_rejects_json() {
+    let dir = std::env::temp_dir();
+    let sheet = extended_sheet("bin_rejects_json", "mi A1:A1 7\n");
+    let json = dir.join("rust_lab_not_binary.json");
+    sheet.save_json(&json, false).unwrap();
+
+    let mut loaded = Spreadsheet::new(10, 10);
+    assert!(loaded.load_bin(&json).is_err());
+    assert!(loaded.load_json(&json).is_ok());
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_database_backed_sheet_writes_edits_through_and_pages_in_rows() {
+    let path = std::env::temp_dir().join("rust_lab_backed.db");
+    let _ = std::fs::remove_file(&path);
+    let mut sheet = Spreadsheet::new(300, 3);
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "5", false));
+    assert!(sheet.update_cell(&"A250".parse().unwrap(), "7", false));
+    assert!(sheet.update_cell(&"B1".parse().unwrap(), "=(A1+A250)", false));
+    assert!(sheet.update_cell(&"C280".parse().unwrap(), "9", false));
+    sheet.run_command(&format!("saveas_db {}", path.display()));
+    assert!(!sheet.status_message().contains("ERROR"), "{}", sheet.status_message());
+    // Edits after saving are written to the database as they are made
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "6", false));
+
+    let mut opened = Spreadsheet::new(10, 10);
+    opened.run_command(&format!("open_db {}", path.display()));
+    assert_eq!(opened.status_message(), format!("OPENED DATABASE {}", path.display()));
+    assert_eq!(opened.dimensions(), (300, 3));
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!([value(&opened, "A1"), value(&opened, "B1")], ["6", "13"]);
+    // Only the rows near the cursor are read up front; others come in when a command refers to them
+    assert!(opened.get_cell(&"C280".parse().unwrap()).unwrap().is_blank());
+    opened.run_command("bold C280");
+    assert_eq!(value(&opened, "C280"), "9");
+}
+
+#[test]
+fn test_exports_can_be_limited_to_a_range() {
+    let run = |sheet: &mut Spreadsheet, cmd: String| {
+        sheet.run_command(&cmd);
+        sheet.status_message().to_string()
+    };
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("B2", "2").cell("C2", "3").cell("B3", "4").cell("C3", "=(B3+1)").build();
+    let dir = std::env::temp_dir();
+    let csv = dir.join("rust_lab_range.csv");
+    run(&mut sheet, format!("saveas_csv B2:C3 {}", csv.display()));
+    assert_eq!(std::fs::read_to_string(&csv).unwrap().lines().collect::<Vec<_>>(), ["2,3", "4,5"]);
+    // The corners can be given in either order
+    run(&mut sheet, format!("saveas_csv C3:B2 {}", csv.display()));
+    assert_eq!(std::fs::read_to_string(&csv).unwrap().lines().collect::<Vec<_>>(), ["2,3", "4,5"]);
+
+    let json = dir.join("rust_lab_range.json");
+    run(&mut sheet, format!("saveas_json B2:B3 {}", json.display()));
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+    let mut keys: Vec<&String> = saved["cells"].as_object().unwrap().keys().collect();
+    keys.sort();
+    assert_eq!(keys, ["B2", "B3"]);
+
+    let status = run(&mut sheet, format!("saveas_csv A1:Z99 {}", csv.display()));
+    assert!(status.starts_with("ERROR:"), "{}", status);
+}
+
+/// Reads a PDF export as text, with its page contents decompressed (optimized builds compress them).
+fn pdf_contents(path: &std::path::Path) -> String {
+    let bytes = std::fs::read(path).unwrap();
+    let mut pdf = String::from_utf8_lossy(&bytes).into_owned();
+    let streams = regex::bytes::Regex::new(r"(?s-u)stream\r?\n(.*?)endstream").unwrap();
+    for caps in streams.captures_iter(&bytes) {
+        let mut contents = String::new();
+        if std::io::Read::read_to_string(&mut flate2::read::ZlibDecoder::new(&caps[1]), &mut contents).is_ok() {
+            pdf.push_str(&contents);
+        }
+    }
+    pdf
+}
+
+/// A text drawn on a PDF page: font, x, y and the text itself.
+type PdfText = (String, f32, f32, String);
+
+/// Reads back the page count, the page size in points and every text drawn of a PDF export.
+fn read_pdf(path: &std::path::Path) -> (usize, (f32, f32), Vec<PdfText>) {
+    let pdf = pdf_contents(path);
+    let count = regex::Regex::new(r"/Type\s*/Pages\s*/Count (\d+)").unwrap().captures(&pdf).unwrap()[1].parse().unwrap();
+    let page = regex::Regex::new(r"/MediaBox\s*\[0 0 ([0-9.]+) ([0-9.]+)\]").unwrap().captures(&pdf).unwrap();
+    let texts = regex::Regex::new(r"/(Helvetica\S*) [0-9.]+ Tf\s+([0-9.]+) ([0-9.]+) Td\s+<([0-9A-F]*)> Tj").unwrap()
+        .captures_iter(&pdf)
+        .map(|caps| {
+            let bytes: Vec<u8> = (0..caps[4].len()).step_by(2).map(|i| u8::from_str_radix(&caps[4][i..i + 2], 16).unwrap()).collect();
+            (caps[1].to_string(), caps[2].parse().unwrap(), caps[3].parse().unwrap(), String::from_utf8(bytes).unwrap())
+        })
+        .collect();
+    (count, (page[1].parse().unwrap(), page[2].parse().unwrap()), texts)
+}
+
+#[test]
+fn test_pdf_export_prints_every_column_with_real_widths() {
+    let path = std::env::temp_dir().join("rust_lab_wide.pdf");
+    let mut sheet = Spreadsheet::new(3, 16);
+    sheet.run_command("mi A1:P1 7");
+    let x_of = |texts: &[PdfText], text: &str| texts.iter().find(|t| t.3 == text).unwrap().1;
+
+    // Sixteen columns do not fit across a portrait page, so they go on to a second one
+    sheet.run_command(&format!("saveas_pdf {}", path.display()));
+    assert_eq!(sheet.status_message(), format!("PDF SAVED TO {}", path.display()));
+    let (pages, (width, height), texts) = read_pdf(&path);
+    assert_eq!(pages, 2);
+    assert!(width < height);
+    for col in ["A", "J", "K", "P"] {
+        assert!(texts.iter().any(|t| t.3 == col), "column {} is missing", col);
+    }
+    let narrow_gap = x_of(&texts, "C") - x_of(&texts, "B");
+    // The grid is drawn as stroked lines
+    assert!(regex::Regex::new(r"[0-9.]+ [0-9.]+ l\s+S").unwrap().is_match(&pdf_contents(&path)));
+
+    // They all fit across a landscape page
+    sheet.run_command(&format!("saveas_pdf {} landscape", path.display()));
+    let (pages, (width, height), _) = read_pdf(&path);
+    assert_eq!(pages, 1);
+    assert!(width > height);
+
+    // A wider cell widens its whole column
+    sheet.run_command("dim B1 (1,12)");
+    sheet.run_command(&format!("saveas_pdf {}", path.display()));
+    let (_, _, texts) = read_pdf(&path);
+    assert!(x_of(&texts, "C") - x_of(&texts, "B") > narrow_gap);
+}
+
+#[test]
+fn test_pdf_export_styles_cells_and_fits_long_values() {
+    let path = std::env::temp_dir().join("rust_lab_styled.pdf");
+    let mut sheet = Spreadsheet::new(3, 3);
+    sheet.run_command("mi A1:A1 abcdefghijkl");
+    sheet.run_command("mi B1:B1 5");
+    sheet.run_command("mi B2:B2 6");
+    sheet.run_command("align B1 l");
+    sheet.run_command("align B2 r");
+    let find = |texts: &[PdfText], text: &str| texts.iter().find(|t| t.3 == text).cloned().unwrap();
+
+    sheet.run_command(&format!("saveas_pdf {} size=12 title=\"Q3 Report\"", path.display()));
+    assert_eq!(sheet.status_message(), format!("PDF SAVED TO {}", path.display()));
+    let (_, _, texts) = read_pdf(&path);
+    // The title and the headers are bold, the values are not
+    assert_eq!(texts[0].0, "Helvetica-Bold");
+    assert_eq!(texts[0].3, "Q3 Report");
+    assert_eq!(find(&texts, "B").0, "Helvetica-Bold");
+    assert_eq!(find(&texts, "5").0, "Helvetica");
+    // The font size is applied to the table
+    assert!(pdf_contents(&path).contains("/Helvetica 12 Tf"));
+    // Alignment follows the cell, and a long value is cut off inside its column
+    assert!(find(&texts, "6").1 > find(&texts, "5").1);
+    assert!(texts.iter().any(|t| t.3 == "ab..."));
+
+    // With wrap, the value continues on the lines below instead
+    sheet.run_command(&format!("saveas_pdf {} wrap", path.display()));
+    let (_, _, texts) = read_pdf(&path);
+    let lines: Vec<&str> = texts.iter().filter(|t| t.0 == "Helvetica" && t.3.chars().all(|c| c.is_ascii_lowercase())).map(|t| t.3.as_str()).collect();
+    assert_eq!(lines, ["abcde", "fghij", "kl"]);
+    assert!(find(&texts, "fghij").2 < find(&texts, "abcde").2);
+}
+
+#[test]
+fn test_load_json_reports_invalid_cells() {
+    let path = std::env::temp_dir().join("rust_lab_invalid_cells.json");
+    std::fs::write(
+        &path,
+        r#"{"version": 2, "cells": {"A1": {"raw_value": "1", "display_value": "1", "width": 0}, "b2": {"raw_value": "2", "display_value": "2"}}}"#,
+    )
+    .unwrap();
+
+    let mut sheet = Spreadsheet::new(10, 10);
+    let message = sheet.load_json(&path).unwrap_err().to_string();
+    assert!(message.contains("A1 (LINE 1): width MUST BE A POSITIVE INTEGER"), "{}", message);
+    assert!(message.contains("b2 (LINE 1): ADDRESS MUST BE WRITTEN AS B2"), "{}", message);
+}
+
+#[test]
+fn test_change_log_keeps_revisions() {
+    let dir = std::env::temp_dir();
+    let path = dir.join("rust_lab_change_log.jsonl");
+    let _ = std::fs::remove_file(&path);
+    let sheet = extended_sheet("change_log", "mi A1:A1 5\nmi B1:B1 =SUM(A1:A1)\n");
+    assert_eq!(sheet.save_log(&path).unwrap(), (1, 2));
+    assert_eq!(sheet.save_log(&path).unwrap(), (1, 0));
+
+    let edited = extended_sheet("change_log_edit", "mi A1:A1 7\nmi B1:B1 =SUM(A1:A1)\n");
+    assert_eq!(edited.save_log(&path).unwrap(), (2, 2));
+
+    let mut loaded = Spreadsheet::new(10, 10);
+    let (revision, times) = loaded.load_log(&path, Some(1)).unwrap();
+    assert_eq!((revision, times.len()), (1, 2));
+    let first = dir.join("rust_lab_change_log_first.json");
+    loaded.save_json(&first, false).unwrap();
+    assert!(std::fs::read_to_string(&first).unwrap().contains(r#""raw_value": "5""#));
+    assert!(loaded.load_log(&path, Some(3)).is_err());
+}
+
+#[test]
+fn test_compressed_saves_round_trip() {
+    let dir = std::env::temp_dir();
+    let sheet = extended_sheet("compressed", "mi A1:A1 2\nmi B1:B1 =SUM(A1:A1)\nmi C2:C2 text\n");
+    let plain = dir.join("rust_lab_compressed.json");
+    sheet.save_json(&plain, false).unwrap();
+    let plain_size = std::fs::metadata(&plain).unwrap().len();
+    let read = |path: &std::path::Path| -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    };
+
+    for name in ["rust_lab_compressed.json.gz", "rust_lab_compressed.json.zst"] {
+        let path = dir.join(name);
+        sheet.save_json(&path, false).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < plain_size / 4, "{} is not compressed", name);
+
+        let mut loaded = Spreadsheet::new(10, 10);
+        loaded.load_json(&path).unwrap();
+        let reloaded = dir.join("rust_lab_compressed_reloaded.json");
+        loaded.save_json(&reloaded, false).unwrap();
+        assert_eq!(read(&reloaded), read(&plain));
+    }
+}
+
+#[test]
+fn test_sheets_keep_their_own_cells() {
+    let dir = std::env::temp_dir();
+    let mut sheet = extended_sheet(
+        "sheet_tabs",
+        "mi A1:A1 5\nsheet new Calc\nmi A1:A1 9\nsheet copy Calc_copy\nmi A1:A1 11\nsheet delete Calc\nsheet Sheet1\nsheet rename Inputs\n",
+    );
+    let inputs = dir.join("rust_lab_sheet_inputs.json");
+    sheet.save_json(&inputs, false).unwrap();
+    let read = |path: &std::path::Path| -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    };
+    assert_eq!(read(&inputs)["cells"]["A1"]["raw_value"], "5");
+
+    let copy = dir.join("rust_lab_sheet_copy.json");
+    let script = dir.join("rust_lab_sheet_switch.txt");
+    std::fs::write(&script, "sheet next\n").unwrap();
+    sheet.run_batch(&script).unwrap();
+    sheet.save_json(&copy, false).unwrap();
+    assert_eq!(read(&copy)["cells"]["A1"]["raw_value"], "11");
+}
+
+#[test]
+fn test_switching_sheets_restores_each_sheets_scroll() {
+    let mut sheet = Spreadsheet::new(100, 100);
+    sheet.resize(80, 24);
+    sheet.viewport();
+    // Scrolling by a page leaves the cursor on A1
+    sheet.send_keys("sd");
+    let scrolled = sheet.viewport();
+    assert_ne!((scrolled.0[0], scrolled.1[0]), (0, 0));
+    // A new sheet opens at its top left corner, not at the offset of the sheet before it
+    sheet.run_command("sheet new Other");
+    let (rows, cols) = sheet.viewport();
+    assert_eq!((rows[0], cols[0]), (0, 0));
+    sheet.run_command("sheet Sheet1");
+    assert_eq!(sheet.viewport(), scrolled);
+}
+
+#[test]
+fn test_config_file_sets_startup_defaults() {
+    let home = std::env::temp_dir().join("rust_lab_config_home");
+    std::fs::create_dir_all(&home).unwrap();
+    let out = home.join("saved.json");
+    let script = home.join("script.txt");
+    std::fs::write(&script, format!("A1=5\nsaveas_json {}\n", out.display())).unwrap();
+    let run = || std::process::Command::new(env!("CARGO_BIN_EXE_Rust_lab")).env("HOME", &home).arg("--script").arg(&script).output().unwrap();
+
+    std::fs::write(home.join(".hackersheetrc"), "rows = 20\ncols = 12\nwidth = 8\nalignment = \"left\"\n").unwrap();
+    assert_eq!(run().status.code(), Some(0));
+    let mut saved = Spreadsheet::new(10, 10);
+    saved.load_json(&out).unwrap();
+    assert_eq!(saved.dimensions(), (20, 12));
+    let cell = saved.get_cell(&"A1".parse().unwrap()).unwrap();
+    assert_eq!((cell.width, &cell.alignment), (8, &Alignment::Left));
+
+    // A broken config is reported and the built-in defaults are used
+    std::fs::write(home.join(".hackersheetrc"), "rows = 0\n").unwrap();
+    let output = run();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("Ignoring config file"));
+    saved.load_json(&out).unwrap();
+    assert_eq!(saved.dimensions(), (10, 10));
+    assert_eq!(saved.get_cell(&"A1".parse().unwrap()).unwrap().width, 5);
+}
+
+#[test]
+fn test_frozen_panes_stay_in_view_while_scrolling() {
+    let mut sheet = Spreadsheet::new(50, 50);
+    sheet.resize(80, 24);
+    sheet.run_command("freeze 2 1");
+    assert_eq!(sheet.status_message(), "FROZE 2 ROWS AND 1 COLUMNS");
+    sheet.send_keys("ssdd");
+    let (rows, cols) = sheet.viewport();
+    // The frozen rows and column come first, then the scrolled part of the grid
+    assert_eq!(rows[..2], [0, 1]);
+    assert!(rows[2] > 2 && rows.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", rows);
+    assert_eq!(cols[0], 0);
+    assert!(cols[1] > 1, "{:?}", cols);
+    sheet.send_keys("wwwwwwaaaaaa");
+
+    // At least one row and column of the view is left to scroll
+    sheet.run_command("freeze 17 0");
+    assert_eq!(sheet.status_message(), "FROZEN PANES MUST LEAVE A ROW AND COLUMN TO SCROLL");
+    sheet.run_command("freeze 0 0");
+    assert_eq!(sheet.status_message(), "PANES UNFROZEN");
+    assert_eq!(sheet.viewport().0[..2], [0, 1]);
+}
+
+#[test]
+fn test_cell_styles_are_saved_and_checked() {
+    let dir = std::env::temp_dir();
+    let sheet = extended_sheet("cell_styles", "color A1:B1 white on dark_blue\nbold A1\nitalic B1\nbold A1\nwrap A1:A2\n");
+    let path = dir.join("rust_lab_cell_styles.json");
+    sheet.save_json(&path, false).unwrap();
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["cells"]["B1"]["bg"], "dark_blue");
+    assert_eq!(saved["cells"]["B1"]["italic"], true);
+    assert_eq!(saved["cells"]["A1"]["bold"], false);
+    assert_eq!(saved["cells"]["A2"]["wrap"], true);
+
+    std::fs::write(&path, r#"{"version": 2, "cells": {"A1": {"raw_value": "1", "display_value": "1", "fg": "purple"}}}"#).unwrap();
+    let message = Spreadsheet::new(10, 10).load_json(&path).unwrap_err().to_string();
+    assert!(message.contains("fg MUST BE A COLOR NAME"), "{}", message);
+}
+
+#[test]
+fn test_grid_fills_the_terminal() {
+    let mut sheet = Spreadsheet::new(100, 100);
+    // Seven lines are taken by the headers and status lines; columns are five characters and a gap wide
+    sheet.resize(80, 24);
+    let (rows, cols) = sheet.viewport();
+    assert_eq!((rows.len(), cols.len()), (17, 12));
+    sheet.resize(120, 40);
+    let (rows, cols) = sheet.viewport();
+    assert_eq!((rows.len(), cols.len()), (33, 19));
+    // A tiny terminal still shows one cell
+    sheet.resize(10, 3);
+    let (rows, cols) = sheet.viewport();
+    assert_eq!((rows.len(), cols.len()), (1, 1));
+
+    // A small sheet does not fill a large terminal
+    let mut small = Spreadsheet::new(5, 3);
+    small.resize(120, 40);
+    assert_eq!(small.viewport(), (vec![0, 1, 2, 3, 4], vec![0, 1, 2]));
+}
+
+#[test]
+fn test_view_follows_the_cursor() {
+    let mut sheet = Spreadsheet::new(100, 100);
+    sheet.resize(80, 24);
+    sheet.run_command("j A1");
+    assert_eq!(sheet.viewport().0[0], 0);
+
+    // Jumps scroll the view so the cursor is on screen
+    sheet.run_command("j Z60");
+    let (rows, cols) = sheet.viewport();
+    assert!(rows.contains(&59) && cols.contains(&25), "{:?} {:?}", rows, cols);
+
+    // Jumping back up puts the cursor on the top row
+    sheet.run_command("j A10");
+    assert_eq!(sheet.viewport().0[0], 9);
+
+    // Stepping past the bottom scrolls by one row
+    sheet.run_command("j A1");
+    sheet.viewport();
+    sheet.run_command("j A17");
+    let before = sheet.viewport().0;
+    assert_eq!(before.last(), Some(&16));
+    sheet.send_keys("j");
+    assert_eq!(sheet.viewport().0, before.iter().map(|row| row + 1).collect::<Vec<_>>());
+
+    sheet.run_command("j A1");
+    assert_eq!(sheet.viewport().0[0], 0);
+}
+
+#[test]
+fn test_tall_rows_take_several_lines() {
+    let mut sheet = Spreadsheet::new(100, 10);
+    sheet.resize(80, 24);
+    assert_eq!(sheet.viewport().0.len(), 17);
+    // A cell four lines tall makes its whole row four lines tall, leaving room for fewer rows
+    sheet.run_command("dim B3 (4,5)");
+    assert_eq!(sheet.viewport().0.len(), 14);
+    // Only the cells in view count: scrolled past, the row no longer takes room
+    sheet.run_command("j A40");
+    assert_eq!(sheet.viewport().0.len(), 17);
+}
+
+#[test]
+fn test_wrapping_cells_break_long_text_over_their_height() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "the quick brown fox").build();
+    sheet.run_command("dim A1 (3,6)");
+    sheet.run_command("align A1 l");
+    let a1 = "A1".parse().unwrap();
+    // Without wrap the value is cut off on the first line
+    assert_eq!(sheet.cell_lines(&a1), ["the ..", "      ", "      "]);
+    // With wrap it breaks at spaces, and is cut off on the last line if it still does not fit
+    sheet.run_command("wrap A1");
+    assert_eq!(sheet.cell_lines(&a1), ["the   ", "quick ", "brow.."]);
+    sheet.run_command("dim A1 (4,6)");
+    assert_eq!(sheet.cell_lines(&a1), ["the   ", "quick ", "brown ", "fox   "]);
+    // Toggling again turns it off
+    sheet.run_command("wrap A1");
+    assert_eq!(sheet.cell_lines(&a1)[0], "the ..");
+}
+
+#[test]
+fn test_precedents_and_dependents_of_the_cursor_are_highlighted() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("B1", "=(A1+1)").cell("C1", "=(B1*2)").cell("D1", "=(A1+5)").build();
+    let set = |cells: &[&str]| cells.iter().map(|cell| cell.to_string()).collect::<std::collections::HashSet<_>>();
+    sheet.run_command("j C1");
+    assert_eq!(sheet.formula_links(), (set(&[]), set(&[])));
+
+    // Precedents are followed through other formulas
+    sheet.send_keys("[");
+    assert_eq!(sheet.status_message(), "SHOWING PRECEDENTS");
+    assert_eq!(sheet.formula_links(), (set(&["A1", "B1"]), set(&[])));
+    sheet.send_keys("]");
+    assert_eq!(sheet.status_message(), "SHOWING DEPENDENTS");
+    sheet.run_command("j A1");
+    assert_eq!(sheet.formula_links(), (set(&[]), set(&["B1", "C1", "D1"])));
+
+    sheet.send_keys("[]");
+    assert_eq!(sheet.status_message(), "DEPENDENTS HIDDEN");
+    assert_eq!(sheet.formula_links(), (set(&[]), set(&[])));
+}
+
+#[test]
+fn test_help_screen_lists_keys_commands_and_functions() {
+    let lines = help_lines();
+    let line_of = |heading: &str| lines.iter().position(|line| line == heading).unwrap();
+    assert!(line_of("KEYS") < line_of("COMMANDS") && line_of("COMMANDS") < line_of("FORMULA FUNCTIONS"));
+    // Entries come from the registries, with descriptions lined up
+    let freeze = lines.iter().find(|line| line.starts_with("  :freeze ")).unwrap();
+    assert_eq!(freeze.trim_end(), format!("  {:<34}{}", ":freeze <rows> [cols]", "Keep leading rows and columns on screen"));
+    assert!(lines.iter().any(|line| line.starts_with("  =SUM(A1:B5)") && line.ends_with("Sum of a range")));
+
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.run_command("help");
+    assert!(*sheet.mode() == Mode::Help);
+    // Keys scroll the help instead of moving the cursor, and q closes it
+    sheet.send_keys("jjq");
+    assert!(*sheet.mode() == Mode::Normal);
+    assert_eq!(sheet.cursor().to_string(), "A1");
+}
+
+#[test]
+fn test_grid_lines_and_zebra_stripes_toggle() {
+    let mut sheet = Spreadsheet::new(100, 100);
+    sheet.resize(80, 24);
+    assert_eq!(sheet.viewport().0.len(), 17);
+    sheet.run_command("grid");
+    assert_eq!(sheet.status_message(), "GRID LINES ON");
+    // Every row also takes the line above it, and one more line closes the grid
+    assert_eq!(sheet.viewport().0.len(), 8);
+    sheet.run_command("grid off");
+    assert_eq!(sheet.status_message(), "GRID LINES OFF");
+    assert_eq!(sheet.viewport().0.len(), 17);
+
+    sheet.run_command("zebra on");
+    assert_eq!(sheet.status_message(), "ZEBRA STRIPES ON");
+    sheet.run_command("zebra");
+    assert_eq!(sheet.status_message(), "ZEBRA STRIPES OFF");
+    sheet.run_command("zebra maybe");
+    assert_eq!(sheet.status_message(), "USAGE: zebra [on|off]");
+}
+
+#[test]
+fn test_progress_bar_fills_as_the_operation_goes() {
+    assert_eq!(progress_bar("SORTING", 0, 40_000, 0), "| SORTING [....................] 0%");
+    assert_eq!(progress_bar("SORTING", 10_000, 40_000, 1), "/ SORTING [#####...............] 25%");
+    // Overshooting the total counts as done
+    assert_eq!(progress_bar("IMPORTING", 50_000, 40_000, 6), "- IMPORTING [####################] 100%");
+    assert_eq!(progress_bar("INSERTING", 39_999, 40_000, 3), "\\ INSERTING [###################.] 99%");
+}
+
+#[test]
+fn test_sparklines_and_charts_scale_a_range() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("A2", "5").cell("A3", "3").cell("A4", "8").cell("B1", "=SPARK(A1:A4)")
+        .cell("C1", "north").cell("C2", "south").build();
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!(value(&sheet, "B1"), "▁▅▃█");
+    // The sparkline follows its range
+    assert!(sheet.update_cell(&"A4".parse().unwrap(), "1", false));
+    assert_eq!(value(&sheet, "B1"), "▁█▅▁");
+
+    // Bars start from zero; a line only marks the top of each bar, with the lowest on the bottom line
+    assert_eq!(chart_lines(ChartKind::Bar, &[2.0, 4.0], 2, 1), [" █", "██"]);
+    assert_eq!(chart_lines(ChartKind::Line, &[0.0, 2.0, 4.0], 2, 2), ["    ██", "▁▁██  "]);
+
+    sheet.run_command("chart pie A1:A4");
+    assert_eq!(sheet.status_message(), "USAGE: chart <bar|line> <range>");
+    sheet.run_command("chart bar C1:C2");
+    assert_eq!(sheet.status_message(), "NO NUMBERS TO CHART");
+}
+
+#[test]
+fn test_heatmap_blends_from_the_lowest_to_the_highest_number() {
+    use crossterm::style::Color;
+    let (low, high) = ((0, 0, 200), (200, 0, 0));
+    assert_eq!(heat_color(1.0, 1.0, 5.0, low, high), Color::Rgb { r: 0, g: 0, b: 200 });
+    assert_eq!(heat_color(5.0, 1.0, 5.0, low, high), Color::Rgb { r: 200, g: 0, b: 0 });
+    assert_eq!(heat_color(2.0, 1.0, 5.0, low, high), Color::Rgb { r: 50, g: 0, b: 150 });
+    // When every number is the same they all get the middle color
+    assert_eq!(heat_color(3.0, 3.0, 3.0, low, high), Color::Rgb { r: 100, g: 0, b: 100 });
+
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.run_command("heatmap");
+    assert_eq!(sheet.status_message(), "HEATMAP ON");
+    sheet.run_command("heatmap off");
+    assert_eq!(sheet.status_message(), "HEATMAP OFF");
+}
+
+#[test]
+fn test_grid_shows_every_column_that_fits() {
+    assert_eq!(
+        [0, 25, 26, 701, 702].map(CellAddress::col_to_letters),
+        ["A", "Z", "AA", "ZZ", "AAA"].map(String::from)
+    );
+
+    let mut sheet = Spreadsheet::new(5, 40);
+    // A wide terminal shows far more than ten columns of five characters and a gap
+    sheet.resize(200, 24);
+    assert_eq!(sheet.viewport().1, (0..32).collect::<Vec<_>>());
+    // Columns are as wide as their widest cell in view, which leaves room for fewer
+    sheet.run_command("dim B2 (1,20)");
+    assert_eq!(sheet.viewport().1.len(), 30);
+    // Columns past Z scroll into view like any other
+    sheet.run_command("j AN1");
+    assert_eq!(sheet.viewport().1.last(), Some(&39));
+}
+
+#[test]
+fn test_wide_characters_are_measured_by_display_width() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "日本語テキスト").cell("A2", "ab🎉").cell("A3", "naïve").build();
+    sheet.run_command("align A2 r");
+    let lines = |sheet: &Spreadsheet, addr: &str| sheet.cell_lines(&addr.parse().unwrap());
+    // Every line fills the five columns of the cell: CJK characters and emoji take two each
+    assert_eq!(lines(&sheet, "A1"), ["日.. "]);
+    assert_eq!(lines(&sheet, "A2"), [" ab🎉"]);
+    // Accented letters take one, so the word fits
+    assert_eq!(lines(&sheet, "A3"), ["naïve"]);
+}
+
+#[test]
+fn test_formula_errors_spread_and_clear() {
+    let dir = std::env::temp_dir();
+    let read = |path: &std::path::Path| -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    };
+    let sheet = extended_sheet("formula_errors", "mi A1:A1 -1\nmi B1:B1 =sqrt(A1)\nmi C1:C1 =SUM(B1:B1)\n");
+    let path = dir.join("rust_lab_formula_errors.json");
+    sheet.save_json(&path, false).unwrap();
+    let saved = read(&path);
+    assert_eq!(saved["cells"]["B1"]["error"], "UNDEFINED RESULT");
+    assert_eq!(saved["cells"]["C1"]["error"], "B1 HAS AN ERROR");
+
+    // A valid input recalculates the failed cells and clears their errors
+    let sheet = extended_sheet("formula_errors_fixed", "mi A1:A1 -1\nmi B1:B1 =sqrt(A1)\nmi C1:C1 =SUM(B1:B1)\nmi A1:A1 4\n");
+    sheet.save_json(&path, false).unwrap();
+    let saved = read(&path);
+    assert!(saved["cells"]["B1"]["error"].is_null());
+    assert!(saved["cells"]["C1"]["error"].is_null());
+    assert_eq!(saved["cells"]["C1"]["display_value"], "2");
+}
+
+#[test]
+fn test_extended_sheet_as_a_library() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    for (row, value) in ["3", "9", "5"].iter().enumerate() {
+        assert!(sheet.update_cell(&CellAddress::new(0, row), value, false));
+    }
+    let total: CellAddress = "B1".parse().unwrap();
+    assert!(sheet.update_cell(&total, "=SUM(A1:A3)", false));
+    assert_eq!(sheet.get_cell(&total).unwrap().display_value, "17");
+
+    assert!(sheet.sort_range("A1:A3", false));
+    assert_eq!(sheet.get_cell(&CellAddress::new(0, 0)).unwrap().display_value, "9");
+    assert!(sheet.find("5"));
+    assert_eq!(sheet.cursor().to_string(), "A2");
+
+    assert!(sheet.run_command("lock B1"));
+    assert!(!sheet.update_cell(&total, "1", false));
+    assert_eq!(sheet.status_message(), "ERROR: CELL B1 LOCKED");
+    assert!("B".parse::<CellAddress>().is_err());
+}
+
+#[test]
+fn test_each_sheet_keeps_its_own_view() {
+    let mut scrolled = Spreadsheet::new(100, 100);
+    let mut other = Spreadsheet::new(100, 100);
+    scrolled.resize(80, 24);
+    other.resize(80, 24);
+    scrolled.run_command("j Z60");
+    let (rows, cols) = scrolled.viewport();
+    assert!(rows.contains(&59) && cols.contains(&25), "{:?} {:?}", rows, cols);
+    // Scrolling one sheet leaves the others where they were
+    let (rows, cols) = other.viewport();
+    assert_eq!((rows[0], cols[0]), (0, 0));
+}
+
+#[test]
+fn test_builder_populates_sheet() {
+    let sheet = Spreadsheet::builder()
+        .rows(20)
+        .cols(5)
+        .cell("A1", "=SUM(B1:B3)")
+        .cell("B1", "4")
+        .cell("B3", "6")
+        .build();
+    assert_eq!(sheet.dimensions(), (20, 5));
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "10");
+
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").build();
+    assert!(!sheet.undo());
+
+    let built = Spreadsheet::builder().cols(3).cell("A1", "2").cell("E1", "1").try_build();
+    assert_eq!(built.err().as_deref(), Some("E1: INVALID CELL ADDRESS"));
+    assert!(Spreadsheet::builder().cell("A1", "=NOPE(B1)").try_build().is_err());
+}
+
+#[test]
+fn test_script_assignments_and_failures() {
+    let script = std::env::temp_dir().join("rust_lab_script_assignments.txt");
+    std::fs::write(&script, "A1=5\nA2=7\nB1=SUM(A1:A2)\nC1=\"hi\"\nB2=NOPE(A1)\nZ99=1\n").unwrap();
+    let mut sheet = Spreadsheet::new(10, 10);
+    assert_eq!(sheet.run_batch(&script).unwrap(), 2);
+    assert_eq!(sheet.get_cell(&"B1".parse().unwrap()).unwrap().display_value, "12");
+    assert_eq!(sheet.get_cell(&"C1".parse().unwrap()).unwrap().display_value, "hi");
+    assert_eq!(sheet.status_message(), "ERROR: CELL Z99 OUTSIDE THE SHEET");
+}
+
+#[test]
+fn test_script_output_holds_only_result_lines() {
+    let script = std::env::temp_dir().join("rust_lab_script_output.txt");
+    std::fs::write(&script, "A1=5\nA2=(A1+1)\nB1=SUM(A1:A2)\ndim A1 (2,8)\nlock B1\nB2=NOPE(A1)\n").unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_Rust_lab"))
+        .arg("--script").arg(&script)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // Every line is `<script line>: <status>`, with nothing else mixed in
+    assert!(!stdout.is_empty());
+    for line in stdout.lines() {
+        let (number, _) = line.split_once(": ").unwrap_or_else(|| panic!("unexpected output line {:?}", line));
+        assert!(number.parse::<usize>().is_ok(), "unexpected output line {:?}", line);
+    }
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("6: ERROR"));
+}
+
+#[test]
+fn test_evaluate_expression_against_sheet() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("A2", "2").cell("B1", "3").cell("B2", "4").cell("C1", "hi").build();
+    assert_eq!(evaluate(&sheet, "SUM(A1:B2)*2"), Ok(20.0));
+    assert_eq!(evaluate(&sheet, "=(A1 + B2) / 2 - MAX(A1:A2, 7)"), Ok(-4.5));
+    assert_eq!(evaluate(&sheet, "B2/(A2-2)").err().as_deref(), Some("DIVISION BY ZERO"));
+    assert_eq!(evaluate(&sheet, "C1+1").err().as_deref(), Some("C1 IS NOT A NUMBER"));
+    assert!(evaluate(&sheet, "SUM(A1:B2").is_err());
+    // Nothing is entered into the sheet
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "1");
+
+    sheet.run_command("eval SUM(A1:A2)+B1");
+    assert_eq!(sheet.status_message(), "SUM(A1:A2)+B1 = 6");
+}
+
+#[test]
+fn test_change_hooks_report_updates_and_recalculation() {
+    use std::cell::RefCell;
+    let changes = Rc::new(RefCell::new(Vec::new()));
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("A2", "2").cell("B1", "=SUM(A1:A2)").cell("C1", "=MAX(A1:A2)").build();
+    let seen = Rc::clone(&changes);
+    sheet.on_change(move |addr, old, new| seen.borrow_mut().push(format!("{} {}->{}", addr, old, new)));
+
+    sheet.update_cell(&"A1".parse().unwrap(), "0", false);
+    let mut after_update = changes.borrow().clone();
+    after_update.sort();
+    // C1 was recalculated but its value stayed 2
+    assert_eq!(after_update, ["A1 1->0", "B1 3->2"]);
+
+    changes.borrow_mut().clear();
+    sheet.undo();
+    changes.borrow_mut().sort();
+    assert_eq!(*changes.borrow(), ["A1 0->1", "B1 2->3"]);
+
+    // A rejected update changes nothing
+    changes.borrow_mut().clear();
+    sheet.update_cell(&"A1".parse().unwrap(), "=SUM(B1:B1)", false);
+    assert!(changes.borrow().is_empty());
+}
+
+#[test]
+fn test_large_loads_recalculate_in_batches() {
+    // 2700 formulas, more than one batch
+    let mut cells = serde_json::Map::new();
+    cells.insert("A1".to_string(), serde_json::json!({"raw_value": "1", "display_value": "1"}));
+    for row in 1..=900 {
+        for col in ["B", "C", "D"] {
+            cells.insert(format!("{}{}", col, row), serde_json::json!({"formula": "(A1+1)", "display_value": "0"}));
+        }
+    }
+    let path = std::env::temp_dir().join("rust_lab_chunked.json");
+    std::fs::write(&path, serde_json::json!({ "cells": cells }).to_string()).unwrap();
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.set_chunked_recalc(true);
+    sheet.load_json(&path).unwrap();
+    // Loading returns before the formulas are evaluated; each batch evaluates some of them
+    let stale = |sheet: &Spreadsheet| (1..=900).filter(|row| value(sheet, &format!("D{}", row)) != "2").count()
+        + (1..=900).filter(|row| value(sheet, &format!("B{}", row)) != "2").count();
+    let before = stale(&sheet);
+    assert!(before > 0);
+    assert!(sheet.recalculate_batch(1000));
+    assert!(stale(&sheet) < before);
+    while sheet.recalculate_batch(1000) {}
+    assert_eq!(stale(&sheet), 0);
+
+    // A command or switching batches off finishes a pending recalculation first
+    sheet.load_json(&path).unwrap();
+    sheet.run_command("j D900");
+    assert_eq!(value(&sheet, "D900"), "2");
+    sheet.load_json(&path).unwrap();
+    sheet.set_chunked_recalc(false);
+    assert_eq!(stale(&sheet), 0);
+}
+
+#[test]
+fn test_dependents_recalculate_once_in_order() {
+    // Every cell reads the two above it, so reaching each dependent once per path would take 2^40 updates
+    let mut builder = Spreadsheet::builder().rows(50).cell("A1", "1").cell("A2", "1");
+    for row in 3..=40 {
+        builder = builder.cell(&format!("A{}", row), &format!("=SUM(A{}:A{})", row - 2, row - 1));
+    }
+    let mut sheet = builder.cell("B1", "=MAX(A1:A2)").cell("C1", "=SUM(B1:B1)").build();
+    assert_eq!(sheet.get_cell(&"A40".parse().unwrap()).unwrap().display_value, "102334155");
+
+    sheet.update_cell(&"A1".parse().unwrap(), "0", false);
+    assert_eq!(sheet.get_cell(&"A40".parse().unwrap()).unwrap().display_value, "63245986");
+    // B1 kept its value, so C1 still reads it correctly
+    assert_eq!(sheet.get_cell(&"C1".parse().unwrap()).unwrap().display_value, "1");
+}
+
+#[test]
+fn test_large_sheet_stores_only_edited_cells() {
+    let mut sheet = Spreadsheet::new(999, 18278);
+    assert_eq!(sheet.get_cell(&CellAddress::new(18277, 998)).unwrap().display_value, "0");
+    assert!(sheet.get_cell(&CellAddress::new(18278, 0)).is_none());
+
+    assert!(sheet.update_cell(&"Z999".parse().unwrap(), "4", false));
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "=SUM(A2:A3)", false));
+    let path = std::env::temp_dir().join("rust_lab_sparse_cells.json");
+    sheet.save_json(&path, false).unwrap();
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["cells"].as_object().unwrap().len(), 2);
+    assert_eq!((saved["rows"].as_u64(), saved["cols"].as_u64()), (Some(999), Some(18278)));
+
+    // Undoing back to a blank cell drops it again
+    sheet.undo();
+    sheet.undo();
+    sheet.save_json(&path, false).unwrap();
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert!(saved["cells"].as_object().unwrap().is_empty());
+
+    let mut loaded = Spreadsheet::new(10, 10);
+    loaded.load_json(&path).unwrap();
+    assert_eq!(loaded.dimensions(), (999, 18278));
+}
+
+#[test]
+fn test_lazy_calculation_defers_dependents() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("B1", "=SUM(A1:A1)").cell("C1", "=SUM(B1:B1)").cell("D1", "=MAX(A1:A1)").build();
+    sheet.set_lazy_calc(true);
+    sheet.update_cell(&"A1".parse().unwrap(), "5", false);
+    assert_eq!(sheet.get_cell(&"C1".parse().unwrap()).unwrap().display_value, "1");
+
+    // A formula reading a stale cell evaluates it first
+    sheet.update_cell(&"E1".parse().unwrap(), "=SUM(C1:C1)", false);
+    assert_eq!(sheet.get_cell(&"E1".parse().unwrap()).unwrap().display_value, "5");
+    assert_eq!(sheet.get_cell(&"B1".parse().unwrap()).unwrap().display_value, "5");
+    assert_eq!(sheet.get_cell(&"D1".parse().unwrap()).unwrap().display_value, "1");
+
+    // Undo marks the dependents stale again, and leaving lazy mode evaluates them
+    sheet.undo();
+    sheet.undo();
+    sheet.set_lazy_calc(false);
+    for addr in ["B1", "C1", "D1"] {
+        assert_eq!(sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value, "1", "{}", addr);
+    }
+    assert!(sheet.run_command("calc lazy"));
+    assert_eq!(sheet.status_message(), "CALCULATION: LAZY");
+}
+
+#[test]
+fn test_frames_only_rewrite_changed_lines() {
+    let frame = |lines: &[&str]| lines.iter().map(|line| line.as_bytes().to_vec()).collect::<Vec<_>>();
+    let last = frame(&["  A  B", "1 5  0", "2 0  0"]);
+    assert_eq!(changed_lines(Some(&last), &frame(&["  A  B", "1 5  0", "2 0  0"]), 10), Vec::<usize>::new());
+    assert_eq!(changed_lines(Some(&last), &frame(&["  A  B", "1 6  0", "2 0  0"]), 10), [1]);
+    // A line the new frame no longer has is cleared
+    assert_eq!(changed_lines(Some(&last), &frame(&["  A  B", "1 5  0"]), 10), [2]);
+    // Without a last frame everything above the status bar is drawn
+    assert_eq!(changed_lines(None, &last, 5), [0, 1, 2, 3]);
+}
+
+#[test]
+fn test_frames_are_capped_and_idle_ticks_follow_the_tick_rate() {
+    use std::time::Duration;
+    // Keys that are already waiting are handled before a frame only drawn a moment ago is redrawn
+    assert!(frame_due(false, Duration::ZERO));
+    assert!(!frame_due(true, Duration::from_millis(5)));
+    assert!(frame_due(true, Duration::from_millis(16)));
+
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.run_command("tick 250");
+    assert_eq!(sheet.poll_timeout(), Duration::from_millis(250));
+    // Ticks never come faster than frames
+    sheet.run_command("tick 5");
+    assert_eq!(sheet.poll_timeout(), Duration::from_millis(16));
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_large_sheets_move_to_a_swap_database() {
+    let swap_files = || {
+        let prefix = format!("hackersheet-{}-", std::process::id());
+        std::fs::read_dir(std::env::temp_dir()).unwrap().filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .count()
+    };
+    let before = swap_files();
+    let mut sheet = Spreadsheet::new(300, 3);
+    sheet.set_swap_threshold(21);
+    assert!(sheet.update_cell(&"C280".parse().unwrap(), "9", false));
+    for row in 1..=20 {
+        assert!(sheet.update_cell(&format!("A{}", row).parse().unwrap(), &row.to_string(), false));
+    }
+    assert_eq!(swap_files(), before);
+    // Going over the threshold moves the cells out; formulas still see every value
+    assert!(sheet.update_cell(&"B1".parse().unwrap(), "=SUM(A1:A20)", false));
+    assert_eq!(swap_files(), before + 1);
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!(value(&sheet, "B1"), "210");
+    // Rows out of view are read back when a command refers to them
+    assert!(sheet.get_cell(&"C280".parse().unwrap()).unwrap().is_blank());
+    sheet.run_command("bold C280");
+    assert_eq!(value(&sheet, "C280"), "9");
+    drop(sheet);
+    assert_eq!(swap_files(), before);
+}
+
+#[test]
+fn test_cycle_is_rejected_before_editing() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("B1", "=(A1+1)").cell("C1", "=SUM(A1:B1)").build();
+    assert!(sheet.update_cell(&"D1".parse().unwrap(), "7", false));
+    assert!(sheet.undo());
+
+    assert!(!sheet.update_cell(&"A1".parse().unwrap(), "=MAX(C1:C1)", false));
+    assert_eq!(sheet.status_message(), "ERROR: CIRCULAR DEPENDENCY DETECTED WITH A1");
+    assert!(!sheet.update_cell(&"B1".parse().unwrap(), "=SUM(B1:B1)", false));
+    for (addr, value) in [("A1", "1"), ("B1", "2"), ("C1", "3")] {
+        assert_eq!(sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value, value, "{}", addr);
+    }
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().formula, None);
+
+    // The rejected edits left the redo history alone
+    assert!(sheet.redo());
+    assert_eq!(sheet.get_cell(&"D1".parse().unwrap()).unwrap().display_value, "7");
+}
+
+#[test]
+fn test_arithmetic_formulas_track_every_reference() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "2").cell("A2", "3").cell("B1", "=(A1+A2)").cell("C1", "=(A2*A2)").cell("D1", "=sqrt(B1)").build();
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "7", false));
+    for (addr, value) in [("B1", "9"), ("C1", "49"), ("D1", "3")] {
+        assert_eq!(sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value, value, "{}", addr);
+    }
+    // The right-hand operand is a dependency too, so this would read B1 through itself
+    assert!(!sheet.update_cell(&"A2".parse().unwrap(), "=(1+D1)", false));
+    assert_eq!(evaluate(&sheet, "A1 + A2*2").unwrap(), 16.0);
+    assert_eq!(evaluate(&sheet, "A1 A2").unwrap_err(), "UNEXPECTED 'A2'");
+}
+
+#[test]
+fn test_multi_letter_columns() {
+    assert_eq!("ab12".parse::<CellAddress>().unwrap().to_string(), "AB12");
+    assert_eq!("ZZZ1".parse::<CellAddress>().unwrap().col, 18277);
+    for bad in ["AAAA1", "A", "1A", "A1B", "A+1", "A0"] {
+        assert!(bad.parse::<CellAddress>().is_err(), "{}", bad);
+    }
+
+    let mut sheet = Spreadsheet::builder().cols(30).cell("AA1", "4").cell("AB1", "5").cell("AC1", "=SUM(AA1:AB1)").cell("AD1", "=(AA1*AB1)").build();
+    assert!(sheet.update_cell(&"AB1".parse().unwrap(), "6", false));
+    assert_eq!(sheet.get_cell(&"AC1".parse().unwrap()).unwrap().display_value, "10");
+    assert_eq!(sheet.get_cell(&"AD1".parse().unwrap()).unwrap().display_value, "24");
+
+    assert!(sheet.run_command("j AD10"));
+    assert_eq!(sheet.cursor().to_string(), "AD10");
+    assert!(sheet.run_command("find -a ab1"));
+    assert_eq!(sheet.status_message(), "1 MATCHES FOUND");
+    assert_eq!(sheet.cursor().to_string(), "AB1");
+}
+
+#[test]
+fn test_rejected_formula_does_not_block_later_edits() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("A2", "=(A1*2)").cell("A3", "=SUM(A1:A2)").cell("A4", "=(A3+A2)").build();
+    assert!(!sheet.update_cell(&"A1".parse().unwrap(), "=FOO(1)", false));
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "5", false), "{}", sheet.status_message());
+    for (addr, value) in [("A2", "10"), ("A3", "15"), ("A4", "25")] {
+        assert_eq!(sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value, value, "{}", addr);
+    }
+}
+
+#[test]
+fn test_locked_cells_in_bulk_edits() {
+    let values = |sheet: &Spreadsheet, addrs: &[&str]| -> Vec<String> {
+        addrs.iter().map(|addr| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone()).collect()
+    };
+    let mut sheet = Spreadsheet::builder().cell("A1", "4").cell("A2", "3").cell("B2", "x").cell("A3", "2").cell("A4", "1").build();
+    assert!(sheet.run_command("lock B2"));
+
+    // Skipping leaves the locked cell and its whole row where they are
+    assert!(sheet.sort_range("A1:A4", true));
+    assert_eq!(sheet.status_message(), "ROW SORT APPLIED, 1 LOCKED ROWS KEPT IN PLACE");
+    assert_eq!(values(&sheet, &["A1", "A2", "B2", "A3", "A4"]), ["1", "3", "x", "2", "4"]);
+    assert!(sheet.run_command("mi B1:B3 7"));
+    assert_eq!(sheet.status_message(), "MULTIPLE INSERTS, 1 LOCKED CELLS SKIPPED");
+    assert_eq!(values(&sheet, &["B1", "B2", "B3"]), ["7", "x", "7"]);
+
+    // Failing refuses the whole edit
+    assert!(sheet.run_command("locked fail"));
+    sheet.run_command("sort A1:A4 0");
+    assert_eq!(sheet.status_message(), "ERROR: 1 LOCKED CELLS IN ROWS 1-4, FIRST B2");
+    sheet.run_command("mi A2:C2 9");
+    assert_eq!(sheet.status_message(), "ERROR: 1 LOCKED CELLS IN A2:C2, FIRST B2");
+    assert_eq!(values(&sheet, &["A1", "A2", "B2", "A3", "A4", "C2"]), ["1", "3", "x", "2", "4", "0"]);
+    assert!(sheet.sort_range("A3:A4", false));
+    assert_eq!(values(&sheet, &["A3", "A4"]), ["4", "2"]);
+}
+
+#[test]
+fn test_aggregates_without_numbers() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "x").cell("A2", "y").cell("B1", "=MIN(A1:A2)").cell("B2", "=STDEV(A1:A2)").cell("B3", "=SUM(A1:A2)").build();
+    let error = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().error.clone();
+    assert_eq!(error(&sheet, "B1").as_deref(), Some("NO NUMBERS FOR MIN"));
+    assert_eq!(error(&sheet, "B2").as_deref(), Some("NO NUMBERS FOR STDEV"));
+    assert_eq!(error(&sheet, "B3"), None);
+    assert_eq!(sheet.get_cell(&"B3".parse().unwrap()).unwrap().display_value, "0");
+
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "4", false));
+    assert_eq!(error(&sheet, "B1"), None);
+    assert_eq!(sheet.get_cell(&"B1".parse().unwrap()).unwrap().display_value, "4");
+    assert_eq!(sheet.get_cell(&"B2".parse().unwrap()).unwrap().display_value, "0");
+}
+
+#[test]
+fn test_dependency_graph_follows_undo_and_sort() {
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    let mut sheet = Spreadsheet::builder().cell("A1", "2").cell("A2", "1").cell("B1", "=(A1*10)").build();
+    assert!(sheet.update_cell(&"B1".parse().unwrap(), "5", false));
+    assert!(sheet.undo());
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "3", false));
+    assert_eq!(value(&sheet, "B1"), "30");
+
+    // The formula moves to row 2 with its row, still reading A1
+    assert!(sheet.sort_range("A1:A2", true));
+    let path = std::env::temp_dir().join("rust_lab_graph_after_sort.csv");
+    assert!(sheet.run_command(&format!("saveas_depcsv {}", path.display())));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "cell,depends_on\nB2,A1\n");
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "4", false));
+    assert_eq!(value(&sheet, "B2"), "40");
+    assert_eq!(value(&sheet, "B1"), "0");
+}
+
+#[test]
+fn test_division_in_arithmetic_formulas() {
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().clone();
+    let mut sheet = Spreadsheet::builder().cell("A1", "9").cell("A2", "2").cell("B1", "=(A1/A2)").cell("B2", "=(B1*2)").build();
+    assert_eq!(value(&sheet, "B1").display_value, "4.5");
+
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "0", false));
+    assert_eq!(value(&sheet, "B1").error.as_deref(), Some("DIVISION BY ZERO"));
+    assert_eq!(value(&sheet, "B2").error.as_deref(), Some("B1 HAS AN ERROR"));
+    assert!(sheet.update_cell(&"C1".parse().unwrap(), "=(A1/0)", false));
+    assert_eq!(sheet.status_message(), "ERROR: DIVISION BY ZERO IN C1");
+
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "3", false));
+    assert_eq!(value(&sheet, "B2").display_value, "6");
+    assert_eq!(value(&sheet, "B2").error, None);
+}
+
+#[test]
+fn test_haunt_rules_follow_values_and_error_count() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").cell("A2", "1").build();
+    assert!(sheet.run_command("haunt when A1 > 5 weight 2"));
+    assert!(sheet.run_command("haunt when ERRORS >= 2"));
+    sheet.run_command("alerts");
+    assert_eq!(sheet.status_message(), "ALERTS: A1 > 5 (HAUNT x2), ERRORS >= 2 (HAUNT x1)");
+    assert!(sheet.haunt_cells().is_empty());
+
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "9", false));
+    assert_eq!(sheet.haunt_cells(), ["A1"]);
+
+    // One failed formula is not enough for the error rule; a second one names both cells
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "0", false));
+    assert!(sheet.update_cell(&"B1".parse().unwrap(), "=(A1/A2)", false));
+    assert_eq!(sheet.haunt_cells(), ["A1"]);
+    assert!(sheet.update_cell(&"B2".parse().unwrap(), "=(9/A2)", false));
+    assert_eq!(sheet.haunt_cells(), ["A1", "B1", "B2"]);
+
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "1", false));
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "3", false));
+    assert!(sheet.haunt_cells().is_empty());
+}
+
+#[test]
+fn test_recorded_formula_errors_count_as_error_cells() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "4").cell("A2", "0").build();
+    assert!(sheet.run_command("haunt when ERRORS > 0"));
+    assert!(sheet.haunt_cells().is_empty());
+
+    // The error lives in `Cell.error`; the value itself does not look like an error
+    assert!(sheet.update_cell(&"B1".parse().unwrap(), "=(A1/A2)", false));
+    let cell = sheet.get_cell(&"B1".parse().unwrap()).unwrap();
+    assert_eq!(cell.error.as_deref(), Some("DIVISION BY ZERO"));
+    assert!(!cell.display_value.starts_with('#'));
+    assert_eq!(sheet.haunt_cells(), ["B1"]);
+    assert!(sheet.update_cell(&"C1".parse().unwrap(), "=(B1+1)", false));
+    assert_eq!(sheet.haunt_cells(), ["B1", "C1"]);
+
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "2", false));
+    assert!(sheet.haunt_cells().is_empty());
+}
+
+#[test]
+fn test_chained_and_mixed_operators_in_arithmetic_formulas() {
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().clone();
+    let mut sheet = Spreadsheet::builder().cell("A1", "12").cell("A2", "2")
+        .cell("B1", "=(A1/2/3)").cell("B2", "=(1+2*3)").cell("B3", "=(A1-A2*4+6/A2)").cell("B4", "=(A1/A2-6)")
+        .build();
+    assert_eq!(value(&sheet, "B1").display_value, "2");
+    assert_eq!(value(&sheet, "B1").error, None);
+    assert_eq!(value(&sheet, "B2").display_value, "7");
+    assert_eq!(value(&sheet, "B3").display_value, "7");
+    assert_eq!(value(&sheet, "B4").display_value, "0");
+
+    // A zero anywhere in a chain of divisions is reported, and the cells follow their inputs
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "0", false));
+    assert_eq!(value(&sheet, "B3").error.as_deref(), Some("DIVISION BY ZERO"));
+    assert!(sheet.update_cell(&"A2".parse().unwrap(), "3", false));
+    assert_eq!(value(&sheet, "B3").display_value, "2");
+    assert_eq!(value(&sheet, "B4").display_value, "-2");
+}
+
+#[test]
+fn test_sheet_size_and_ranges_stay_in_bounds() {
+    let error = Spreadsheet::builder().rows(1200).try_build().err().unwrap();
+    assert_eq!(error, "SHEET SIZE 1200x10 OUT OF RANGE (1x1 TO 999x18278)");
+    assert_eq!(Spreadsheet::new(5000, 20000).dimensions(), (999, 18278));
+
+    // Ranges reaching past the last row or column are refused before any cell is walked
+    let mut sheet = Spreadsheet::builder().cell("A1", "3").build();
+    assert!(sheet.parse_range("A1:A11").is_none());
+    sheet.run_command("mi A1:ZZZ999 7");
+    assert_eq!(sheet.status_message(), "ERROR: RANGE A1:ZZZ999 OUTSIDE THE SHEET (A1:J10)");
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "3");
+    let path = std::env::temp_dir().join("rust_lab_outside_range.csv");
+    let _ = std::fs::remove_file(&path);
+    sheet.run_command(&format!("saveas_csv A1:K1 {}", path.display()));
+    assert_eq!(sheet.status_message(), "ERROR: RANGE A1:K1 OUTSIDE THE SHEET (A1:J10)");
+    assert!(!path.exists());
+
+    // A file with cells past the largest grid is refused and the sheet is left as it was
+    let path = std::env::temp_dir().join("rust_lab_jumbo.json");
+    std::fs::write(&path, r#"{"cells": {"A1200": {"raw_value": "1"}}}"#).unwrap();
+    assert!(sheet.load_json(&path).unwrap_err().to_string().contains("A1200 (LINE 1): ROW IS BEYOND THE LAST ROW (999)"));
+    std::fs::write(&path, r#"{"version": 2, "rows": 1200, "cols": 10, "cells": {}}"#).unwrap();
+    let error = sheet.load_json(&path).unwrap_err();
+    assert_eq!((error.kind(), error.to_string().as_str()), (std::io::ErrorKind::InvalidData, "SHEET SIZE 1200x10 OUT OF RANGE (1x1 TO 999x18278)"));
+    assert_eq!(sheet.dimensions(), (10, 10));
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "3");
+}
+
+#[test]
+fn test_shared_session_merges_edits_and_cursors() {
+    let value = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    let path = std::env::temp_dir().join("rust_lab_session.jsonl");
+    let _ = std::fs::remove_file(&path);
+    let mut alice = Spreadsheet::builder().cell("A1", "2").build();
+    assert!(!alice.share(&path, "alice").unwrap());
+    let mut bob = Spreadsheet::new(10, 10);
+    assert!(bob.share(&path, "bob").unwrap());
+    assert_eq!(value(&bob, "A1"), "2");
+
+    // Each client recalculates the formulas reading a cell the other edited
+    assert!(bob.update_cell(&"B1".parse().unwrap(), "=SUM(A1:A2)", false));
+    alice.sync_session();
+    assert!(alice.update_cell(&"A1".parse().unwrap(), "5", false));
+    bob.sync_session();
+    assert_eq!((value(&alice, "B1"), value(&bob, "B1")), ("5".to_string(), "5".to_string()));
+    assert!(alice.undo());
+    bob.sync_session();
+    assert_eq!(value(&bob, "B1"), "2");
+
+    // Both edit a cell before seeing the other's edit: the same one wins on both sides
+    assert!(alice.update_cell(&"C1".parse().unwrap(), "1", false));
+    assert!(bob.update_cell(&"C1".parse().unwrap(), "9", false));
+    alice.sync_session();
+    bob.sync_session();
+    assert_eq!((value(&alice, "C1"), value(&bob, "C1")), ("9".to_string(), "9".to_string()));
+
+    // Cursors are shown to the other clients until they leave
+    bob.run_command("j D4");
+    bob.sync_session();
+    alice.sync_session();
+    alice.run_command("who");
+    assert_eq!(alice.status_message(), "CLIENTS: bob AT D4");
+    assert!(bob.unshare());
+    alice.sync_session();
+    alice.run_command("who");
+    assert!(alice.status_message().starts_with("NO OTHER CLIENTS"));
+}
+
+#[cfg(feature = "websocket")]
+#[test]
+fn test_dashboard_mirrors_sheet_and_runs_commands() {
+    type Socket = tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<std::net::TcpStream>>;
+    fn next_event(socket: &mut Socket) -> serde_json::Value {
+        loop {
+            if let tungstenite::Message::Text(text) = socket.read().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+    fn send(socket: &mut Socket, text: &str) {
+        socket.send(tungstenite::Message::Text(text.to_string())).unwrap();
+    }
+    let mut sheet = Spreadsheet::builder().cell("A1", "2").cell("B1", "=SUM(A1:A2)").build();
+    let addr = sheet.serve("127.0.0.1:0").unwrap();
+    let dashboard = std::thread::spawn(move || {
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}", addr)).unwrap();
+        let snapshot = next_event(&mut socket);
+        assert_eq!((snapshot["type"].as_str(), snapshot["rows"].as_u64()), (Some("snapshot"), Some(10)));
+        assert_eq!(snapshot["cells"]["B1"]["display_value"], "2");
+
+        // A command answers with its status, then its changes follow
+        send(&mut socket, r#"{"command": "mi A1:A1 5"}"#);
+        let status = next_event(&mut socket);
+        assert_eq!((status["type"].as_str(), status["error"].as_bool()), (Some("status"), Some(false)));
+        let changes = next_event(&mut socket);
+        assert_eq!(changes["type"], "changes");
+        assert_eq!((changes["cells"]["A1"]["display_value"].as_str(), changes["cells"]["B1"]["display_value"].as_str()), (Some("5"), Some("5")));
+        // The dashboard has a cursor of its own
+        send(&mut socket, r#"{"command": "j C3"}"#);
+        assert_eq!(next_event(&mut socket)["cursor"], "C3");
+
+        // Quitting is left to the editor, and other messages are refused
+        send(&mut socket, r#"{"command": "q"}"#);
+        assert_eq!(next_event(&mut socket)["error"], true);
+        send(&mut socket, "sort A1:A3 1");
+        assert!(next_event(&mut socket)["message"].as_str().unwrap().starts_with("INVALID MESSAGE"));
+    });
+    while !dashboard.is_finished() {
+        sheet.sync_dashboard();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    dashboard.join().unwrap();
+    assert_eq!(sheet.get_cell(&"B1".parse().unwrap()).unwrap().display_value, "5");
+    assert_eq!(sheet.cursor().to_string(), "A1");
+    assert_eq!(sheet.history(&"A1".parse().unwrap()).last().unwrap().author.as_deref(), Some("dashboard 1"));
+    assert!(sheet.stop_serving());
+    sheet.run_command("serve off");
+    assert_eq!(sheet.status_message(), "THE DASHBOARD SERVER IS NOT RUNNING");
+}
+
+#[cfg(feature = "websocket")]
+#[test]
+fn test_dashboard_resumes_session_after_dropped_connection() {
+    type Socket = tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<std::net::TcpStream>>;
+    fn next_event(socket: &mut Socket) -> serde_json::Value {
+        loop {
+            if let tungstenite::Message::Text(text) = socket.read().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+    fn send(socket: &mut Socket, text: &str) {
+        socket.send(tungstenite::Message::Text(text.to_string())).unwrap();
+    }
+    let mut sheet = Spreadsheet::builder().cell("A1", "1").build();
+    let addr = sheet.serve("127.0.0.1:0").unwrap();
+    let dashboard = std::thread::spawn(move || {
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}", addr)).unwrap();
+        let token = next_event(&mut socket)["token"].as_str().unwrap().to_string();
+        send(&mut socket, r#"{"command": "j C3", "id": 1, "view": "B5"}"#);
+        assert_eq!(next_event(&mut socket)["id"], 1);
+        // The connection drops after a command went out, before its status came back
+        send(&mut socket, r#"{"command": "bold A1", "id": 2}"#);
+        drop(socket);
+
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}/?token={}", addr, token)).unwrap();
+        let snapshot = next_event(&mut socket);
+        assert_eq!(snapshot["token"], token.as_str());
+        assert_eq!((snapshot["client_cursor"].as_str(), snapshot["view"].as_str()), (Some("C3"), Some("B5")));
+        assert_eq!(snapshot["cells"]["A1"]["bold"], true);
+        // Resending the command gets its status without running it again
+        send(&mut socket, r#"{"command": "bold A1", "id": 2}"#);
+        let status = next_event(&mut socket);
+        assert_eq!((status["id"].as_u64(), status["error"].as_bool()), (Some(2), Some(false)));
+
+        // An unknown token starts a new session
+        let (mut other, _) = tungstenite::connect(format!("ws://{}/?token=nope", addr)).unwrap();
+        let snapshot = next_event(&mut other);
+        assert_ne!(snapshot["token"], token.as_str());
+        assert_eq!(snapshot["client_cursor"], "A1");
+    });
+    while !dashboard.is_finished() {
+        sheet.sync_dashboard();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    dashboard.join().unwrap();
+    assert!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().bold);
+}
+
+#[test]
+fn test_audit_log_keeps_each_cells_edit_trail() {
+    let path = std::env::temp_dir().join("rust_lab_audit.jsonl");
+    let session = std::env::temp_dir().join("rust_lab_audit_session.jsonl");
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&session);
+    let (a1, b1) = ("A1".parse().unwrap(), "B1".parse().unwrap());
+    let mut sheet = Spreadsheet::new(10, 10);
+    assert!(sheet.update_cell(&a1, "5", false));
+    assert!(sheet.update_cell(&b1, "=SUM(A1:A2)", false));
+    assert!(sheet.update_cell(&a1, "7", false));
+    // B1 was only recalculated by the second edit of A1
+    assert_eq!(sheet.history(&b1).len(), 1);
+    sheet.run_command(&format!("audit {}", path.display()));
+    assert!(sheet.status_message().ends_with("(0 EARLIER CHANGES)"));
+    assert!(sheet.undo());
+    let trail: Vec<(&str, &str)> = sheet.history(&a1).iter().map(|entry| (entry.old.as_str(), entry.new.as_str())).collect();
+    assert_eq!(trail, [("", "5"), ("5", "7"), ("7", "5")]);
+    sheet.run_command("history A1");
+    assert!(sheet.status_message().starts_with("HISTORY OF A1: "));
+    assert!(sheet.status_message().contains(" (EMPTY) -> 5; "));
+
+    // The audit file holds every change, and a later sheet reads them back
+    let mut later = Spreadsheet::new(10, 10);
+    assert_eq!(later.open_audit(&path).unwrap(), 4);
+    assert_eq!(later.history(&a1).len(), 3);
+
+    // In a shared session each change names the client it came from
+    assert!(!sheet.share(&session, "alice").unwrap());
+    let mut bob = Spreadsheet::new(10, 10);
+    assert!(bob.share(&session, "bob").unwrap());
+    assert!(bob.update_cell(&a1, "9", false));
+    sheet.sync_session();
+    let last = sheet.history(&a1).last().cloned().unwrap();
+    assert_eq!((last.old.as_str(), last.new.as_str(), last.author.as_deref()), ("5", "9", Some("bob")));
+    assert_eq!(bob.history(&a1).last().unwrap().author.as_deref(), Some("bob"));
+    sheet.run_command("audit off");
+    assert!(sheet.status_message().starts_with("STOPPED AUDITING"));
+}
+
+#[test]
+fn test_diff_and_three_way_merge_of_saved_sheets() {
+    let dir = std::env::temp_dir();
+    let (base, ours, theirs) = (dir.join("rust_lab_base.json"), dir.join("rust_lab_ours.json"), dir.join("rust_lab_theirs.json"));
+    let save = |path: &std::path::Path, cells: &[(&str, &str)]| {
+        let mut builder = Spreadsheet::builder();
+        for (addr, input) in cells {
+            builder = builder.cell(addr, input);
+        }
+        builder.build().save_json(path, false).unwrap();
+    };
+    save(&base, &[("A1", "1"), ("A2", "2"), ("B1", "=SUM(A1:A2)"), ("C1", "7")]);
+    save(&ours, &[("A1", "5"), ("A2", "2"), ("B1", "=SUM(A1:A2)"), ("C1", "8")]);
+    save(&theirs, &[("A1", "1"), ("A2", "9"), ("B1", "=SUM(A1:A2)"), ("C1", "6"), ("D4", "new")]);
+
+    // Only inputs count: B1 computes another value on each side but was not edited
+    let diffs: Vec<String> = diff_files(&base, &theirs).unwrap().iter().map(CellDiff::describe).collect();
+    assert_eq!(diffs, ["~ C1: 7 -> 6", "~ A2: 2 -> 9", "+ D4: new"]);
+    assert!(diff_files(&base, &base).unwrap().is_empty());
+
+    // Each side's own edits are kept, and C1, edited on both, stays as ours
+    let merged = dir.join("rust_lab_merged.json");
+    let conflicts = merge_files(&base, &ours, &theirs, &merged).unwrap();
+    assert_eq!(conflicts.iter().map(MergeConflict::describe).collect::<Vec<_>>(), ["! C1: 7 -> 8 (ours), 7 -> 6 (theirs)"]);
+    let mut sheet = Spreadsheet::new(10, 10);
+    sheet.load_json(&merged).unwrap();
+    let value = |addr: &str| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone();
+    assert_eq!([value("A1"), value("A2"), value("B1"), value("C1"), value("D4")], ["5", "9", "14", "8", "new"]);
+}
+
+#[test]
+fn test_text_save_is_sorted_minimal_and_round_trips() {
+    let dir = std::env::temp_dir();
+    let (first, second) = (dir.join("rust_lab_first.sheet"), dir.join("rust_lab_second.sheet"));
+    let mut sheet = Spreadsheet::builder().cell("B2", "hello").cell("A10", "3").cell("A2", "4").cell("C1", "=SUM(A2:A10)").build();
+    assert!(sheet.run_command("bold B2"));
+    sheet.save_text(&first).unwrap();
+    sheet.save_text(&second).unwrap();
+
+    // Cells come in row-major order with only their input and changed styles, the same on every save
+    let text = std::fs::read_to_string(&first).unwrap();
+    assert_eq!(text, std::fs::read_to_string(&second).unwrap());
+    let lines: Vec<&str> = text.lines().skip(1).collect();
+    assert_eq!(lines, ["size 10x10", "C1 {\"input\":\"=SUM(A2:A10)\"}", "A2 {\"input\":\"4\"}", "B2 {\"bold\":true,\"input\":\"hello\"}", "A10 {\"input\":\"3\"}"]);
+
+    // It loads like a JSON save, with formulas recalculated
+    let mut loaded = Spreadsheet::new(10, 10);
+    loaded.load_json(&first).unwrap();
+    let cell = |addr: &str| loaded.get_cell(&addr.parse().unwrap()).unwrap().clone();
+    assert_eq!(cell("C1").display_value, "7");
+    assert!(cell("B2").bold);
+    assert_eq!(cell("B2").display_value, "hello");
+    loaded.save_text(&second).unwrap();
+    assert_eq!(text, std::fs::read_to_string(&second).unwrap());
+}
+
+#[test]
+fn test_list_validation_limits_cell_values() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "Open").build();
+    assert!(sheet.run_command("validate A1:A3 list Open, In progress ,Done"));
+    assert_eq!(sheet.status_message(), "VALUES LIMITED TO Open, In progress, Done");
+    let a2 = "A2".parse().unwrap();
+    assert_eq!(sheet.get_cell(&a2).unwrap().choices.as_deref(), Some(&["Open".to_string(), "In progress".to_string(), "Done".to_string()][..]));
+
+    // Only listed values are accepted, and the cell can still be cleared
+    assert!(!sheet.update_cell(&a2, "Maybe", false));
+    assert_eq!(sheet.status_message(), "ERROR: A2 MUST BE ONE OF Open, In progress, Done");
+    assert!(sheet.update_cell(&a2, "In progress", false));
+    assert_eq!(sheet.get_cell(&a2).unwrap().display_value, "In progress");
+    assert!(sheet.update_cell(&a2, "", false));
+
+    // The list is saved with the cell
+    let path = std::env::temp_dir().join("rust_lab_choices.sheet");
+    sheet.save_text(&path).unwrap();
+    let mut loaded = Spreadsheet::new(10, 10);
+    loaded.load_json(&path).unwrap();
+    assert!(!loaded.update_cell(&"A3".parse().unwrap(), "Maybe", false));
+
+    assert!(sheet.run_command("validate A1:A3 off"));
+    assert!(sheet.update_cell(&a2, "Maybe", false));
+    assert!(sheet.run_command("validate A1 list"));
+    assert!(sheet.status_message().starts_with("USAGE"));
+}
+
+#[test]
+fn test_valid_edit_clears_a_refused_list_value_error() {
+    let mut sheet = Spreadsheet::builder().build();
+    assert!(sheet.run_command("validate A1 list Yes,No"));
+    let a1 = "A1".parse().unwrap();
+    assert!(!sheet.update_cell(&a1, "Maybe", false));
+    assert_eq!(sheet.status_message(), "ERROR: A1 MUST BE ONE OF Yes, No");
+    assert!(sheet.update_cell(&a1, "No", false));
+    assert_eq!(sheet.status_message(), "");
+}
+
+#[test]
+fn test_protected_ranges_refuse_edits_sorts_and_fills() {
+    let mut sheet = Spreadsheet::builder().cell("A1", "3").cell("A2", "1").cell("C5", "7").build();
+    assert!(sheet.run_command("protect B2:A1 s3cret"));
+    assert_eq!(sheet.status_message(), "PROTECTED A1:B2");
+    assert!(!sheet.update_cell(&"B2".parse().unwrap(), "9", false));
+    assert_eq!(sheet.status_message(), "ERROR: RANGE A1:B2 PROTECTED");
+    assert!(sheet.update_cell(&"C5".parse().unwrap(), "8", false));
+    sheet.run_command("mi B1:C3 4");
+    assert_eq!(sheet.status_message(), "ERROR: RANGE A1:B2 PROTECTED");
+    sheet.run_command("sort A1:A2 1");
+    assert_eq!(sheet.status_message(), "ERROR: RANGE A1:B2 PROTECTED");
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "3");
+
+    // The password is saved as a salted hash, and needed to lift the protection after reopening
+    let path = std::env::temp_dir().join("rust_lab_protected.json");
+    sheet.save_json(&path, false).unwrap();
+    assert!(!std::fs::read_to_string(&path).unwrap().contains("s3cret"));
+    let mut loaded = Spreadsheet::new(10, 10);
+    loaded.load_json(&path).unwrap();
+    loaded.run_command("protect sheet");
+    loaded.run_command("protect");
+    assert_eq!(loaded.status_message(), "PROTECTED: SHEET, A1:B2 (WITH PASSWORD)");
+    assert!(!loaded.update_cell(&"C5".parse().unwrap(), "1", false));
+    loaded.run_command("unprotect all");
+    assert_eq!(loaded.status_message(), "ERROR: PASSWORD REQUIRED");
+    loaded.run_command("unprotect all guess");
+    assert_eq!(loaded.status_message(), "ERROR: WRONG PASSWORD");
+    loaded.run_command("unprotect all s3cret");
+    assert_eq!(loaded.status_message(), "UNPROTECTED ALL");
+    assert!(loaded.update_cell(&"B2".parse().unwrap(), "9", false));
+}
+
+#[test]
+fn test_undo_and_redo_refuse_to_change_protected_cells() {
+    let mut sheet = Spreadsheet::builder().build();
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "1", false));
+    assert!(sheet.update_cell(&"A1".parse().unwrap(), "2", false));
+    sheet.run_command("protect A1:A2");
+    assert!(!sheet.undo());
+    assert_eq!(sheet.status_message(), "ERROR: RANGE A1:A2 PROTECTED");
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "2");
+
+    // The refused action is kept, and undone once the protection is lifted
+    sheet.run_command("unprotect A1:A2");
+    assert!(sheet.undo());
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "1");
+    sheet.run_command("protect sheet");
+    assert!(!sheet.redo());
+    assert_eq!(sheet.status_message(), "ERROR: SHEET PROTECTED");
+    assert_eq!(sheet.get_cell(&"A1".parse().unwrap()).unwrap().display_value, "1");
+}
+
+#[test]
+fn test_pivot_table_aggregates_groups_and_refreshes() {
+    let mut sheet = Spreadsheet::builder()
+        .cell("A1", "Region").cell("B1", "Year").cell("C1", "Sales")
+        .cell("A2", "North").cell("B2", "2024").cell("C2", "10")
+        .cell("A3", "South").cell("B3", "2023").cell("C3", "4")
+        .cell("A4", "North").cell("B4", "2023").cell("C4", "5")
+        .cell("A5", "North").cell("B5", "2024").cell("C5", "1")
+        .build();
+    assert!(sheet.run_command("pivot A1:C5 E1 rows=A cols=B values=SUM(C)"));
+    assert_eq!(sheet.status_message(), "PIVOT TABLE WRITTEN TO E1 (3x3)");
+    let text = |sheet: &Spreadsheet, addr: &str| sheet.get_cell(&addr.parse().unwrap()).map(|cell| cell.display_value.clone()).unwrap_or_default();
+    // Column by column: E holds the row labels
+    let grid = |sheet: &Spreadsheet| ["E", "F", "G"].map(|col| (1..=3).map(|row| text(sheet, &format!("{}{}", col, row))).collect::<Vec<_>>());
+    assert_eq!(grid(&sheet), [
+        vec!["SUM OF Sales", "North", "South"],
+        vec!["2023", "5", "4"],
+        vec!["2024", "11", ""],
+    ]);
+
+    // The table only changes on refresh, and a narrower table clears the cells it no longer uses
+    sheet.update_cell(&"C5".parse().unwrap(), "3", false);
+    assert_eq!(text(&sheet, "G2"), "11");
+    assert!(sheet.run_command("pivot refresh"));
+    assert_eq!(text(&sheet, "G2"), "13");
+    assert!(sheet.run_command("pivot A1:C5 E1 rows=A values=COUNT(C)"));
+    assert_eq!(grid(&sheet), [
+        vec!["Region", "North", "South"],
+        vec!["COUNT OF Sales", "3", "1"],
+        vec!["", "", ""],
+    ]);
+    sheet.run_command("pivot");
+    assert_eq!(sheet.status_message(), "PIVOTS: E1: COUNT(C) BY A FROM A1:C5");
+
+    // The definition is saved with the sheet, so it can be refreshed after reopening
+    let path = std::env::temp_dir().join("rust_lab_pivot.sheet");
+    sheet.save_text(&path).unwrap();
+    let mut loaded = Spreadsheet::new(10, 10);
+    loaded.load_json(&path).unwrap();
+    assert!(loaded.run_command("pivot refresh"));
+    assert_eq!(loaded.status_message(), "1 PIVOT TABLES REFRESHED");
+
+    sheet.run_command("pivot A1:C5 B2 rows=A values=SUM(C)");
+    assert_eq!(sheet.status_message(), "ERROR: PIVOT AT B2 WOULD OVERWRITE A1:C5");
+    sheet.run_command("pivot A1:C5 H1 rows=D values=SUM(C)");
+    assert_eq!(sheet.status_message(), "ERROR: COLUMN D IS NOT IN A1:C5");
+}
+
+#[test]
+fn test_row_groups_collapse_and_expand() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    assert!(sheet.run_command("group 3:5"));
+    assert_eq!(sheet.status_message(), "GROUPED ROWS 3:5");
+    sheet.run_command("group 4:8");
+    assert_eq!(sheet.status_message(), "ERROR: ROWS 4:8 OVERLAP THE GROUP 3:5");
+    sheet.run_command("group 2:6");
+    sheet.run_command("group");
+    assert_eq!(sheet.status_message(), "ROW GROUPS: 2:6, 3:5");
+
+    // Collapsing at the cursor folds the innermost group and moves the cursor below it
+    sheet.run_command("j A4");
+    sheet.run_command("collapse");
+    assert_eq!(sheet.status_message(), "COLLAPSED ROWS 3:5");
+    assert!((2..=4).all(|row| sheet.is_row_hidden(row)));
+    assert!(!sheet.is_row_hidden(1) && !sheet.is_row_hidden(5));
+    assert_eq!(sheet.cursor().to_string(), "A6");
+    sheet.run_command("expand");
+    assert_eq!(sheet.status_message(), "EXPANDED ROWS 3:5");
+    assert!(!sheet.is_row_hidden(3));
+
+    // Jumping into a folded row shows it again
+    sheet.run_command("collapse all");
+    assert!(sheet.is_row_hidden(1));
+    sheet.run_command("j B4");
+    assert!(!sheet.is_row_hidden(3) && !sheet.is_row_hidden(1));
+
+    // Groups and their state are saved with the sheet
+    sheet.run_command("collapse 2:6");
+    let path = std::env::temp_dir().join("rust_lab_row_groups.json");
+    sheet.save_json(&path, false).unwrap();
+    let mut loaded = Spreadsheet::new(10, 10);
+    loaded.load_json(&path).unwrap();
+    assert!(loaded.is_row_hidden(5) && !loaded.is_row_hidden(6));
+    loaded.run_command("ungroup all");
+    assert!(!loaded.is_row_hidden(5));
+    loaded.run_command("ungroup");
+    assert_eq!(loaded.status_message(), "NO ROW GROUP AT ROW 1");
+}
+
+#[test]
+fn test_sort_mode_range_keeps_other_columns() {
+    let values = |sheet: &Spreadsheet, addrs: &[&str]| -> Vec<String> {
+        addrs.iter().map(|addr| sheet.get_cell(&addr.parse().unwrap()).unwrap().display_value.clone()).collect()
+    };
+    let mut sheet = Spreadsheet::builder()
+        .cell("A1", "3").cell("B1", "c").cell("C1", "x")
+        .cell("A2", "1").cell("B2", "a").cell("C2", "y")
+        .cell("A3", "2").cell("B3", "b").cell("C3", "z")
+        .build();
+    assert!(sheet.run_command("sortmode range"));
+    assert_eq!(sheet.status_message(), "SORT MOVES: RANGE");
+    assert!(sheet.sort_range("A1:B3", true));
+    assert_eq!(sheet.status_message(), "RANGE SORT APPLIED");
+    assert_eq!(values(&sheet, &["A1", "A2", "A3", "B1", "B2", "B3"]), ["1", "2", "3", "a", "b", "c"]);
+    assert_eq!(values(&sheet, &["C1", "C2", "C3"]), ["x", "y", "z"]);
+
+    // A locked cell outside the range no longer pins its row
+    assert!(sheet.run_command("lock C1"));
+    assert!(sheet.run_command("sort A1:A3 0"));
+    assert_eq!(sheet.status_message(), "RANGE SORT APPLIED");
+    assert_eq!(values(&sheet, &["A1", "B1", "C1"]), ["3", "a", "x"]);
+
+    // Back in rows mode the whole rows move again
+    assert!(sheet.run_command("sortmode rows"));
+    assert!(sheet.run_command("unlock C1"));
+    assert!(sheet.sort_range("A1:A3", true));
+    assert_eq!(values(&sheet, &["A3", "B3", "C3"]), ["3", "a", "x"]);
+    sheet.run_command("sortmode cols");
+    assert_eq!(sheet.status_message(), "USAGE: sortmode [rows|range]");
+}
+
+#[test]
+fn test_cursor_never_rests_on_a_collapsed_row() {
+    let mut sheet = Spreadsheet::builder().cell("B4", "needle").build();
+    sheet.run_command("group 3:5");
+
+    // Sideways moves next to and below a collapsed group keep their row; vertical ones step over it
+    assert!(sheet.send_keys("jjj"));
+    assert_eq!(sheet.cursor().to_string(), "A4");
+    sheet.run_command("collapse");
+    assert_eq!(sheet.cursor().to_string(), "A6");
+    assert!(sheet.send_keys("llh"));
+    assert_eq!(sheet.cursor().to_string(), "B6");
+    assert!(sheet.send_keys("k"));
+    assert_eq!(sheet.cursor().to_string(), "B2");
+    assert!(sheet.send_keys("j"));
+    assert_eq!(sheet.cursor().to_string(), "B6");
+
+    // Marks, finds and column jumps never leave the cursor on a folded row
+    sheet.run_command("expand 3:5");
+    assert!(sheet.send_keys("kkma"));
+    sheet.run_command("collapse 3:5");
+    assert!(sheet.send_keys("'al"));
+    assert!(!sheet.is_row_hidden(3));
+    assert_eq!(sheet.cursor().to_string(), "C4");
+    sheet.run_command("collapse 3:5");
+    assert!(sheet.find("needle"));
+    assert!(!sheet.is_row_hidden(3));
+    assert_eq!(sheet.cursor().to_string(), "B4");
+    sheet.run_command("collapse 3:5");
+    sheet.run_command("group 8:10");
+    sheet.run_command("collapse 8:10");
+    sheet.run_command("jj");
+    assert_eq!(sheet.cursor().to_string(), "B7");
+    sheet.run_command("kk");
+    assert!(sheet.send_keys("hl"));
+    assert_eq!(sheet.cursor().to_string(), "B1");
+}